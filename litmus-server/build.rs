@@ -0,0 +1,10 @@
+fn main() {
+    // The extension module leaves Python to be linked by the interpreter
+    // that loads it, the unit tests embed an interpreter so link it here.
+    let config = pyo3_build_config::get();
+    if let (Some(dir), Some(name)) = (&config.lib_dir, &config.lib_name) {
+        println!("cargo:rustc-link-arg=-L{}", dir);
+        println!("cargo:rustc-link-arg=-Wl,-rpath,{}", dir);
+        println!("cargo:rustc-link-arg=-l{}", name);
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::metrics::ResponseStats;
+
+/// The size of the buffer lines are written to before being flushed.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The format access log lines are written in.
+#[derive(Copy, Clone)]
+pub enum AccessLogFormat {
+    /// The Common Log Format, the same as Apache's `common` format.
+    Common,
+
+    /// The Common Log Format followed by the referer and user agent, the
+    /// same as Apache's and nginx's `combined` format.
+    Combined,
+
+    /// A JSON object per line, this is the only format recording the time
+    /// taken to respond.
+    Json,
+}
+
+/// Writes a line per completed request to stdout or a file.
+///
+/// Lines are buffered and only written once the buffer is full or it's
+/// flushed, which happens each time the server checks for keep alive
+/// timeouts and when it shuts down, so logging costs little more than
+/// formatting the line.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<BufWriter<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    /// Creates an access log writing to stdout.
+    pub fn stdout(format: AccessLogFormat) -> Self {
+        Self::new(format, Box::new(io::stdout()))
+    }
+
+    /// Creates an access log appending to the file at the given path,
+    /// the file is created if it doesn't exist.
+    pub fn file(format: AccessLogFormat, path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(format, Box::new(file)))
+    }
+
+    fn new(format: AccessLogFormat, target: Box<dyn Write + Send>) -> Self {
+        Self {
+            format,
+            writer: Mutex::new(BufWriter::with_capacity(BUFFER_SIZE, target)),
+        }
+    }
+
+    /// Formats and buffers the line for the given request and the response
+    /// to it, which took `duration` to queue.
+    pub(crate) fn record(
+        &self,
+        entry: &AccessEntry,
+        response: &ResponseStats,
+        duration: Duration,
+    ) {
+        let mut line = String::with_capacity(256);
+        match self.format {
+            AccessLogFormat::Common => write_common(&mut line, entry, response),
+            AccessLogFormat::Combined => {
+                write_common(&mut line, entry, response);
+                line.push_str(" \"");
+                push_escaped(&mut line, entry.referer.as_deref().unwrap_or("-"));
+                line.push_str("\" \"");
+                push_escaped(&mut line, entry.user_agent.as_deref().unwrap_or("-"));
+                line.push('"');
+            },
+            AccessLogFormat::Json => write_json(&mut line, entry, response, duration),
+        }
+        line.push('\n');
+
+        // Each line is written in one go so lines are never split between
+        // flushes, keeping them whole when workers share a file.
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            warn!("failed to write to the access log: {}", e);
+        }
+    }
+
+    /// Writes any buffered lines.
+    pub(crate) fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.flush() {
+            warn!("failed to flush the access log: {}", e);
+        }
+    }
+}
+
+/// The details of a request recorded in the access log once its response
+/// has been queued.
+pub(crate) struct AccessEntry {
+    pub(crate) peer: SocketAddr,
+    pub(crate) method: String,
+    pub(crate) target: String,
+    pub(crate) version: &'static str,
+    pub(crate) referer: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) request_id: Option<Arc<str>>,
+}
+
+fn write_common(line: &mut String, entry: &AccessEntry, response: &ResponseStats) {
+    let _ = write!(
+        line,
+        "{} - - [{}] \"",
+        entry.peer.ip(),
+        Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+    );
+    push_escaped(line, &entry.method);
+    line.push(' ');
+    push_escaped(line, &entry.target);
+    let _ = write!(line, " {}\" {} ", entry.version, response.status);
+
+    if response.size == 0 {
+        line.push('-');
+    } else {
+        let _ = write!(line, "{}", response.size);
+    }
+}
+
+fn write_json(
+    line: &mut String,
+    entry: &AccessEntry,
+    response: &ResponseStats,
+    duration: Duration,
+) {
+    let _ = write!(
+        line,
+        "{{\"time\":\"{}\",\"peer\":\"{}\",\"method\":",
+        Local::now().to_rfc3339(),
+        entry.peer,
+    );
+    push_json_str(line, &entry.method);
+    line.push_str(",\"path\":");
+    push_json_str(line, &entry.target);
+    let _ = write!(
+        line,
+        ",\"version\":\"{}\",\"status\":{},\"size\":{},\"duration_ms\":{:.3}",
+        entry.version,
+        response.status,
+        response.size,
+        duration.as_secs_f64() * 1000.0,
+    );
+
+    line.push_str(",\"referer\":");
+    match entry.referer.as_deref() {
+        Some(referer) => push_json_str(line, referer),
+        None => line.push_str("null"),
+    }
+
+    line.push_str(",\"user_agent\":");
+    match entry.user_agent.as_deref() {
+        Some(user_agent) => push_json_str(line, user_agent),
+        None => line.push_str("null"),
+    }
+
+    line.push_str(",\"request_id\":");
+    match entry.request_id.as_deref() {
+        Some(request_id) => push_json_str(line, request_id),
+        None => line.push_str("null"),
+    }
+
+    line.push('}');
+}
+
+/// Pushes a value within a quoted field of the common log format, quotes,
+/// backslashes and control characters are escaped as `\xHH`.
+fn push_escaped(line: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '\x00'..='\x1f' | '\x7f' => {
+                let _ = write!(line, "\\x{:02X}", c as u32);
+            },
+            c => line.push(c),
+        }
+    }
+}
+
+/// Pushes a value as a JSON string.
+fn push_json_str(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            '\x00'..='\x1f' => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            },
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
@@ -0,0 +1,46 @@
+//! The endpoints served by the server itself in bench mode, these measure
+//! the most the server can do with no application in the way.
+
+/// The body of the plaintext endpoint.
+pub(crate) const PLAINTEXT_BODY: &[u8] = b"Hello, World!";
+
+/// The body of the JSON endpoint.
+pub(crate) const JSON_BODY: &[u8] = br#"{"message":"Hello, World!"}"#;
+
+/// The max size of a body sent back by the echo endpoint, the whole body
+/// is held in memory until it has been received.
+pub(crate) const MAX_ECHO_SIZE: usize = 1024 * 1024;
+
+/// An endpoint served in bench mode.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum BenchEndpoint {
+    /// `text/plain` hello world, served at any path not matched by another
+    /// endpoint.
+    Plaintext,
+
+    /// A small `application/json` object, served at `/json`.
+    Json,
+
+    /// Sends back the body of the request, served at `/echo`.
+    Echo,
+}
+
+impl BenchEndpoint {
+    /// The endpoint served at the given path.
+    pub(crate) fn from_path(path: &str) -> Self {
+        match path {
+            "/json" => Self::Json,
+            "/echo" => Self::Echo,
+            _ => Self::Plaintext,
+        }
+    }
+
+    /// The content type of the endpoint's response.
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Plaintext => "text/plain",
+            Self::Json => "application/json",
+            Self::Echo => "application/octet-stream",
+        }
+    }
+}
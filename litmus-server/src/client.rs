@@ -1,15 +1,27 @@
-use std::time::Instant;
+use std::time::Duration;
 
+use bytes::BytesMut;
+use pyo3::exceptions::PyRuntimeError;
 use pyo3::PyResult;
 
 use crate::event_loop::PreSetEventLoop;
-use crate::net::{SocketStatus, StreamHandle};
-use crate::protocols::{AutoProtocol, Protocols};
+use crate::hooks::{self, TraceEvent, TraceHook};
+use crate::migration::ConnectionSnapshot;
+use crate::net::{ProxyStatus, SocketStatus, StreamHandle};
+use crate::protocols::{AutoProtocol, SwitchStatus};
 use crate::server::CallbackHandler;
 use crate::settings::Settings;
-use crate::traits::{BufferHandler, PollHandler, Reusable, SocketState};
+use crate::traits::{
+    BufferHandler, CompletionHandler, Migratable, PollHandler, Reusable, SocketState,
+};
 use crate::transport::Transport;
 
+/// The error recorded when the connection is reset or aborted by the peer.
+const DISCONNECT_ERROR: &str = "connection reset by peer";
+
+/// The error recorded when the connection is closed by the write stall guard.
+const WRITE_STALL_ERROR: &str = "write buffer stalled";
+
 pub struct ClientHandler {
     event_loop: PreSetEventLoop,
     connection: StreamHandle,
@@ -19,8 +31,53 @@ pub struct ClientHandler {
 
     is_free: bool,
     is_idle: bool,
-    last_time: Instant,
-    idle_for: Instant,
+    last_time: Duration,
+    idle_for: Duration,
+
+    /// The last time any data was written to the socket or the write
+    /// buffer was seen empty.
+    last_write: Duration,
+
+    /// The last error that occurred on the connection, kept for
+    /// diagnostics until the client is rebound.
+    last_error: Option<String>,
+
+    /// If the PROXY protocol header of the connection is yet to be read.
+    awaiting_proxy_header: bool,
+
+    /// The part of the PROXY protocol header received so far.
+    proxy_header: Vec<u8>,
+
+    /// The result of a read done ahead of the next `poll_read`, its data
+    /// is already in the protocol's read buffer.
+    prefetched: Option<PyResult<SocketStatus>>,
+
+    /// The id of the connection given to trace hooks.
+    connection_id: u64,
+
+    /// When the connection was accepted.
+    accepted: Duration,
+
+    /// The number of bytes received and sent on the connection.
+    bytes_received: usize,
+    bytes_sent: usize,
+
+    /// If the connection is yet to be logged and reported closed to the
+    /// tracer.
+    traced_open: bool,
+
+    /// If the peer has shut down its side of the connection, the response
+    /// in progress is still written before the connection is closed.
+    peer_closed: bool,
+
+    /// If the last `poll_read()` stopped once it had used up its budget
+    /// for the wakeup rather than reading everything available.
+    read_yielded: bool,
+
+    /// If the write side of the connection has been shut down after its
+    /// last response, anything more the peer sends is discarded until it
+    /// closes its side, see `poll_linger()`.
+    lingering: bool,
 }
 
 impl Reusable for ClientHandler {
@@ -31,18 +88,21 @@ impl Reusable for ClientHandler {
         settings: Settings,
     ) -> PyResult<Self> {
         event_loop.add_reader()?;
+        let now = event_loop.now()?;
+        let connection_id = hooks::next_connection_id();
 
         let transport = Transport::new(
             connection.addr,
             connection.server,
             connection.tls,
+            connection_id,
             event_loop.clone(),
         );
 
-        let protocol =
-            AutoProtocol::new(settings.clone(), Protocols::H1, transport, callback);
+        let protocol = AutoProtocol::new(settings.clone(), transport, callback);
+        let awaiting_proxy_header = settings.proxy_protocol;
 
-        Ok(Self {
+        let mut handler = Self {
             event_loop,
             connection,
             settings,
@@ -50,97 +110,584 @@ impl Reusable for ClientHandler {
 
             is_free: false,
             is_idle: false,
-            last_time: Instant::now(),
-            idle_for: Instant::now(),
-        })
+            last_time: now,
+            idle_for: now,
+            last_write: now,
+            last_error: None,
+            awaiting_proxy_header,
+            proxy_header: Vec::new(),
+            prefetched: None,
+            connection_id,
+            accepted: now,
+            bytes_received: 0,
+            bytes_sent: 0,
+            traced_open: false,
+            peer_closed: false,
+            read_yielded: false,
+            lingering: false,
+        };
+
+        handler.trace_accepted();
+        if !handler.awaiting_proxy_header {
+            handler.select_protocol()?;
+        }
+
+        Ok(handler)
     }
 
-    fn set_connection(&mut self, connection: StreamHandle) -> PyResult<()> {
-        self.event_loop.set_fd(connection.fd());
+    fn rebind(&mut self, connection: StreamHandle, index: usize) -> PyResult<()> {
+        if !self.is_idle {
+            return Err(PyRuntimeError::new_err(
+                "cannot rebind a client whose previous connection is not idle",
+            ));
+        }
+
+        self.event_loop.rebind(connection.fd(), index)?;
         self.connection = connection;
+        self.connection_id = hooks::next_connection_id();
 
         let transport = Transport::new(
             self.connection.addr,
             self.connection.server,
             self.connection.tls,
+            self.connection_id,
             self.event_loop.clone(),
         );
         self.protocol.new_connection(transport);
         self.event_loop.add_reader()?;
 
+        self.is_free = false;
+        self.is_idle = false;
+        self.last_time = self.event_loop.now()?;
+        self.last_write = self.last_time;
+        self.last_error = None;
+        self.awaiting_proxy_header = self.settings.proxy_protocol;
+        self.proxy_header.clear();
+        self.prefetched = None;
+        self.accepted = self.last_time;
+        self.bytes_received = 0;
+        self.bytes_sent = 0;
+        self.peer_closed = false;
+        self.read_yielded = false;
+        self.lingering = false;
+        self.trace_accepted();
+        if !self.awaiting_proxy_header {
+            self.select_protocol()?;
+        }
+
         Ok(())
     }
 }
 
-impl PollHandler for ClientHandler {
-    fn poll_read(&mut self) -> PyResult<()> {
-        let buffer = self.protocol.read_buffer_acquire()?;
+impl ClientHandler {
+    /// Checks the write stall guard, if the write buffer is over the limit
+    /// and has made no progress within the timeout the connection should
+    /// be force-closed.
+    fn write_stalled(&mut self, now: Duration) -> bool {
+        let guard = match self.settings.write_stall {
+            Some(guard) => guard,
+            None => return false,
+        };
 
-        let len = match self.connection.read(buffer)? {
-            SocketStatus::WouldBlock => return Ok(()),
-            SocketStatus::Complete(len) => len,
-            SocketStatus::Disconnect => {
-                self.protocol.connection_lost()?;
+        let buffered = self.protocol.write_buffered();
+        if buffered == 0 {
+            self.last_write = now;
+            return false;
+        }
+
+        (buffered > guard.max_buffered)
+            & (now.saturating_sub(self.last_write) >= guard.timeout)
+    }
+
+    /// Selects the protocol as if it was negotiated using ALPN, in-memory
+    /// sockets have no TLS handshake to negotiate it with.
+    #[cfg(test)]
+    pub(crate) fn select_alpn(&mut self, protocol: &[u8]) {
+        self.protocol.select_alpn(Some(protocol));
+    }
+
+    /// The event loop binding of the connection, its generation changes
+    /// each time the client is rebound.
+    #[cfg(test)]
+    pub(crate) fn event_loop(&self) -> &PreSetEventLoop {
+        &self.event_loop
+    }
+
+    /// Serves the custom protocol of the listener the connection was
+    /// accepted on if it has one, in place of HTTP.
+    fn select_protocol(&mut self) -> PyResult<()> {
+        if let Some(factory) = self.connection.protocol() {
+            self.protocol.select_custom(factory.create())?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the writer registered while the connection has data pending
+    /// that was not written due to the socket blocking, e.g. TLS records.
+    fn flush_pending(&self) -> PyResult<()> {
+        if self.connection.has_pending_writes() {
+            self.event_loop.add_writer()?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the connection once its last response has been written.
+    ///
+    /// The write side is shut down first so the peer reads the whole
+    /// response, closing both sides while requests it sent are unread would
+    /// reset the connection and could discard the response. The connection
+    /// lingers until the peer closes its side or the keep alive timeout.
+    fn half_close(&mut self) -> PyResult<()> {
+        if self.peer_closed || !self.connection.shutdown_write() {
+            return self.event_loop.close_socket();
+        }
+
+        io_event!("write side shut down");
+        self.lingering = true;
+        self.last_time = self.event_loop.now()?;
+        self.event_loop.remove_writer()?;
+        self.event_loop.add_reader()
+    }
+
+    /// Ends a `poll_read()` which used up its budget before the socket
+    /// would block, the reader is re-armed so the rest is read on a later
+    /// wakeup once the other connections have had their turn.
+    fn yield_read(&mut self) -> PyResult<()> {
+        io_event!("read budget used up, yielding");
+        self.read_yielded = true;
+        self.event_loop.rearm_reader()?;
+        self.flush_pending()
+    }
+
+    /// Discards anything read from a lingering connection, closing it once
+    /// the peer closes its side.
+    fn poll_linger(&mut self) -> PyResult<()> {
+        for _ in 0..self.settings.max_reads_per_wakeup.max(1) {
+            if !self.event_loop.is_reading() {
+                break;
+            }
+
+            let buffer = self.protocol.read_buffer_acquire()?;
+            let status = self.connection.read(buffer);
+            buffer.clear();
+
+            match self.record_error(status)? {
+                SocketStatus::WouldBlock => {
+                    self.protocol.release_read_buffer();
+                    return Ok(());
+                },
+                SocketStatus::Complete(len) if len > 0 => {},
+                SocketStatus::Complete(_) | SocketStatus::Disconnect => {
+                    io_event!(reason = "eof", "connection lost");
+                    self.is_idle = true;
+                    self.idle_for = self.event_loop.now()?;
+                    return self.shutdown();
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles the peer disconnecting while writing to the socket.
+    fn on_write_disconnect(&mut self) -> PyResult<()> {
+        io_event!(reason = DISCONNECT_ERROR, "connection lost");
+        self.last_error = Some(DISCONNECT_ERROR.to_string());
+        self.protocol.connection_lost()?;
+        self.is_idle = true;
+        self.idle_for = self.event_loop.now()?;
+        self.shutdown()
+    }
+
+    /// Reads the PROXY protocol header of the connection, once complete
+    /// the protocol is handed a transport with the client's real address.
+    ///
+    /// Returns `true` if the connection is ready to be read from.
+    fn poll_proxy_header(&mut self) -> PyResult<bool> {
+        let status = self.connection.read_proxy_header(&mut self.proxy_header);
+        let status = self.record_error(status)?;
+
+        match status {
+            ProxyStatus::Incomplete => Ok(false),
+            ProxyStatus::Complete => {
+                self.awaiting_proxy_header = false;
+                self.proxy_header.clear();
+                self.last_time = self.event_loop.now()?;
+
+                let transport = Transport::new(
+                    self.connection.addr,
+                    self.connection.server,
+                    self.connection.tls,
+                    self.connection_id,
+                    self.event_loop.clone(),
+                );
+                self.protocol.new_connection(transport);
+                self.select_protocol()?;
+                Ok(true)
+            },
+            ProxyStatus::Invalid(reason) => {
+                io_event!(reason, "invalid proxy protocol header");
+                self.last_error =
+                    Some(format!("invalid proxy protocol header: {}", reason));
                 self.is_idle = true;
-                self.idle_for = Instant::now();
-                return self.shutdown();
+                self.idle_for = self.event_loop.now()?;
+                self.shutdown()?;
+                Ok(false)
+            },
+            ProxyStatus::Disconnect => {
+                io_event!(reason = DISCONNECT_ERROR, "connection lost");
+                self.last_error = Some(DISCONNECT_ERROR.to_string());
+                self.is_idle = true;
+                self.idle_for = self.event_loop.now()?;
+                self.shutdown()?;
+                Ok(false)
             },
+        }
+    }
+
+    /// Logs the connection being accepted and reports it to the tracer
+    /// if any.
+    fn trace_accepted(&mut self) {
+        debug!(
+            connection_id = self.connection_id,
+            peer:% = self.connection.addr;
+            "accepted connection from {}", self.connection.addr,
+        );
+
+        self.traced_open = true;
+        let tracer = match self.settings.tracer.as_ref() {
+            Some(tracer) => tracer,
+            None => return,
         };
 
-        // EOF
-        if len == 0 {
-            self.protocol.eof_received()?;
+        tracer.emit(TraceHook::ConnectionAccepted, || {
+            TraceEvent::new(
+                self.connection_id,
+                self.connection.addr,
+                self.connection.server,
+                self.accepted,
+                self.accepted,
+            )
+        });
+    }
+
+    /// Logs the connection being closed and reports it to the tracer if
+    /// any, only the first call once a connection is accepted does anything.
+    fn trace_closed(&mut self) -> PyResult<()> {
+        if !self.traced_open {
             return Ok(());
         }
+        self.traced_open = false;
 
-        self.protocol.read_buffer_filled(len)?;
-
-        self.last_time = Instant::now();
+        debug!(
+            connection_id = self.connection_id,
+            peer:% = self.connection.addr,
+            bytes_received = self.bytes_received,
+            bytes_sent = self.bytes_sent,
+            error = self.last_error.as_deref();
+            "closed connection from {}", self.connection.addr,
+        );
 
-        self.protocol.maybe_switch()?;
+        let now = self.event_loop.now()?;
+        if let Some(tracer) = self.settings.tracer.as_ref() {
+            tracer.emit(TraceHook::ConnectionClosed, || {
+                TraceEvent::new(
+                    self.connection_id,
+                    self.connection.addr,
+                    self.connection.server,
+                    now,
+                    self.accepted,
+                )
+                .with_bytes(self.bytes_received, self.bytes_sent)
+            });
+        }
 
         Ok(())
     }
 
+    /// Records the error of the given result, if any, as the last error
+    /// of the connection and logs it along with the connection.
+    fn record_error<T>(&mut self, result: PyResult<T>) -> PyResult<T> {
+        if let Err(e) = result.as_ref() {
+            warn!(
+                connection_id = self.connection_id,
+                peer:% = self.connection.addr;
+                "i/o error on connection from {}: {}", self.connection.addr, e,
+            );
+            self.last_error = Some(e.to_string());
+        }
+
+        result
+    }
+}
+
+impl PollHandler for ClientHandler {
+    fn poll_read(&mut self) -> PyResult<()> {
+        io_span!("poll_read", self.event_loop.fd(), self.event_loop.index());
+
+        if self.lingering {
+            return self.poll_linger();
+        }
+
+        if self.awaiting_proxy_header && !self.poll_proxy_header()? {
+            return Ok(());
+        }
+
+        // Drain as much as possible per wakeup while still capping the reads
+        // and bytes so a single busy connection cannot starve the others.
+        self.read_yielded = false;
+        let mut budget = self.settings.max_bytes_per_wakeup.unwrap_or(usize::MAX);
+        for _ in 0..self.settings.max_reads_per_wakeup.max(1) {
+            let status = match self.prefetched.take() {
+                Some(status) => status,
+                None => {
+                    let buffer = self.protocol.read_buffer_acquire()?;
+                    self.connection.read(buffer)
+                },
+            };
+            let status = self.record_error(status)?;
+            io_event!(?status, "read from socket");
+
+            let len = match status {
+                SocketStatus::WouldBlock => {
+                    // Idle connections don't hold onto a read buffer.
+                    self.protocol.release_read_buffer();
+                    return self.flush_pending();
+                },
+                SocketStatus::Complete(len) => len,
+                SocketStatus::Disconnect => {
+                    let error = self
+                        .connection
+                        .take_tls_error()
+                        .unwrap_or_else(|| DISCONNECT_ERROR.to_string());
+                    io_event!(reason = error.as_str(), "connection lost");
+                    self.last_error = Some(error);
+                    self.protocol.connection_lost()?;
+                    self.is_idle = true;
+                    self.idle_for = self.event_loop.now()?;
+                    return self.shutdown();
+                },
+            };
+
+            // EOF
+            if len == 0 {
+                // The peer may only have shut down its side, the response in
+                // progress is finished before the connection is closed.
+                if self.protocol.eof_received()? {
+                    io_event!("peer shut down its side");
+                    self.peer_closed = true;
+                    return self.flush_pending();
+                }
+
+                io_event!(reason = "eof", "connection lost");
+                self.is_idle = true;
+                self.idle_for = self.event_loop.now()?;
+                return self.shutdown();
+            }
+
+            self.settings.metrics.bytes_received(len);
+            self.bytes_received += len;
+
+            if self.connection.tls {
+                let connection = &self.connection;
+                self.protocol.select_alpn(connection.alpn_protocol());
+                self.protocol
+                    .set_peer_certificate(connection.peer_certificate());
+                self.protocol.set_tls_details(|| connection.tls_details());
+            }
+            self.protocol.read_buffer_filled(len)?;
+
+            self.last_time = self.event_loop.now()?;
+
+            if let SwitchStatus::SwitchTo(_protocol) = self.protocol.maybe_switch()? {
+                io_event!(protocol = ?_protocol, "switching protocol");
+            }
+
+            // Nothing more is read once the protocol has paused reading, e.g.
+            // after rejecting a request, the rest is left in the socket.
+            if !self.event_loop.is_reading() {
+                return self.flush_pending();
+            }
+
+            // The response is written before anything more is parsed.
+            if self.protocol.stops_reading() {
+                break;
+            }
+
+            budget = budget.saturating_sub(len);
+            if budget == 0 {
+                break;
+            }
+        }
+
+        self.yield_read()
+    }
+
+    fn prefetch(&mut self) {
+        if self.awaiting_proxy_header | self.lingering | self.prefetched.is_some() {
+            return;
+        }
+
+        let status = match self.protocol.read_buffer_acquire() {
+            Ok(buffer) => self.connection.read(buffer),
+            Err(e) => Err(e),
+        };
+        self.prefetched = Some(status);
+    }
+
     fn poll_write(&mut self) -> PyResult<()> {
-        let buffer = self.protocol.write_buffer_acquire()?;
+        io_span!("poll_write", self.event_loop.fd(), self.event_loop.index());
 
-        let len = match self.connection.write(buffer)? {
+        // Hold back partial segments while the response is being assembled
+        // so the headers and body go out as full segments.
+        if self.protocol.response_pending() {
+            self.connection.cork();
+        }
+
+        // A file being sent goes out before anything queued behind it.
+        if let Some(file) = self.protocol.pending_file() {
+            let status = self.connection.send_file(file);
+            let status = self.record_error(status)?;
+            io_event!(?status, "sent file to socket");
+
+            match status {
+                SocketStatus::WouldBlock => return Ok(()),
+                SocketStatus::Complete(len) => {
+                    self.last_write = self.event_loop.now()?;
+                    self.last_time = self.last_write;
+                    self.settings.metrics.bytes_sent(len);
+                    self.bytes_sent += len;
+                    self.protocol.file_sent(len)?;
+                },
+                SocketStatus::Disconnect => return self.on_write_disconnect(),
+            }
+
+            if self.protocol.pending_file().is_some() {
+                return Ok(());
+            }
+        }
+
+        // Large body chunks are written alongside the buffer in one go.
+        self.protocol.write_buffer_acquire()?;
+        let status = match self.protocol.write_queue() {
+            Some(chunks) => self.connection.write_vectored(chunks),
+            None => self.connection.write(self.protocol.write_buffer()),
+        };
+        let status = self.record_error(status)?;
+        io_event!(?status, "wrote to socket");
+
+        let len = match status {
             SocketStatus::WouldBlock => return Ok(()),
             SocketStatus::Complete(len) => len,
-            SocketStatus::Disconnect => {
-                self.protocol.connection_lost()?;
-                self.is_idle = true;
-                self.idle_for = Instant::now();
-                return self.shutdown();
-            },
+            SocketStatus::Disconnect => return self.on_write_disconnect(),
         };
 
+        if !self.protocol.response_pending() {
+            self.connection.uncork();
+        }
+
+        // A slow reader isn't idle while a response is still being written
+        // to it, only once it stops reading.
+        if len > 0 {
+            self.last_write = self.event_loop.now()?;
+            self.last_time = self.last_write;
+        }
+
+        self.settings.metrics.bytes_sent(len);
+        self.bytes_sent += len;
         self.protocol.write_buffer_drained(len)?;
 
-        Ok(())
+        if self.protocol.take_close() {
+            return self.half_close();
+        }
+
+        self.flush_pending()
     }
 
     fn poll_close(&mut self) -> PyResult<()> {
-        self.connection.close();
+        io_span!("poll_close", self.event_loop.fd(), self.event_loop.index());
+
+        // The socket's file descriptor is released straight away by a reset.
+        let aborting = self.event_loop.take_aborting();
+        self.event_loop.release()?;
+
+        if aborting {
+            io_event!(reason = "aborted", "connection lost");
+            self.connection.abort();
+        } else {
+            io_event!(reason = "closed", "connection lost");
+            self.connection.close();
+        }
+
         self.protocol.connection_lost()?;
-        Ok(())
+        self.is_idle = true;
+        self.idle_for = self.event_loop.now()?;
+        self.trace_closed()
     }
 
     fn poll_keep_alive(&mut self) -> PyResult<()> {
-        if self.last_time.elapsed() >= self.settings.keep_alive {
+        self.protocol.poll_timers()?;
+
+        let now = self.event_loop.now()?;
+        if self.write_stalled(now) {
+            io_span!(
+                "poll_keep_alive",
+                self.event_loop.fd(),
+                self.event_loop.index()
+            );
+            io_event!(reason = WRITE_STALL_ERROR, "connection lost");
+
+            self.last_error = Some(WRITE_STALL_ERROR.to_string());
+            self.connection.close();
+            self.is_idle = true;
+            self.idle_for = now;
+            return self.shutdown();
+        }
+
+        // A response in progress is covered by the response timeout, the
+        // connection is only idle once it completes.
+        if self.protocol.response_pending() {
+            self.last_time = now;
+            return Ok(());
+        }
+
+        let idle = now.saturating_sub(self.last_time);
+        if !self.protocol.is_long_lived() & (idle >= self.settings.keep_alive) {
+            io_span!(
+                "poll_keep_alive",
+                self.event_loop.fd(),
+                self.event_loop.index()
+            );
+            io_event!(reason = "keep alive timeout", "connection lost");
+
             self.connection.close();
             self.is_idle = true;
-            self.idle_for = Instant::now();
+            self.idle_for = now;
             return self.shutdown();
         }
         Ok(())
     }
 
+    fn poll_drain(&mut self) -> PyResult<()> {
+        if self.is_idle || !self.protocol.drain()? {
+            return Ok(());
+        }
+
+        self.connection.close();
+        self.is_idle = true;
+        self.idle_for = self.event_loop.now()?;
+        self.shutdown()
+    }
+
     fn shutdown(&mut self) -> PyResult<()> {
+        io_span!("shutdown", self.event_loop.fd(), self.event_loop.index());
+
+        self.event_loop.release()?;
         self.connection.close();
         self.protocol.connection_lost()?;
-        Ok(())
+        self.trace_closed()
     }
 
     fn is_idle(&self) -> bool {
@@ -154,4 +701,482 @@ impl PollHandler for ClientHandler {
     fn set_free(&mut self) {
         self.is_free = true;
     }
+
+    fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+impl CompletionHandler for ClientHandler {
+    fn poll_received(&mut self, data: Option<&[u8]>) -> PyResult<()> {
+        let socket = self.connection.completion().ok_or_else(|| {
+            PyRuntimeError::new_err("connection is not owned by the event loop")
+        })?;
+
+        match data {
+            Some(data) => socket.feed(data),
+            None => socket.feed_eof(),
+        }
+
+        // Like a readiness based event loop, the connection is only told it
+        // is readable while it's being read from. Each read consumes what
+        // was received so this stops once everything has been read, or the
+        // read budget is used up in which case the re-armed reader picks
+        // up the rest on the connection's next turn.
+        while !self.is_idle
+            & self.event_loop.is_reading()
+            & self
+                .connection
+                .completion()
+                .is_some_and(|s| s.is_readable())
+        {
+            self.poll_read()?;
+            if self.read_yielded {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_outbound(&mut self) -> PyResult<Option<BytesMut>> {
+        match self.connection.completion() {
+            Some(socket) => Ok(socket.take_outbound()),
+            None => Err(PyRuntimeError::new_err(
+                "connection is not owned by the event loop",
+            )),
+        }
+    }
+}
+
+impl Migratable for ClientHandler {
+    fn snapshot(&mut self) -> PyResult<ConnectionSnapshot> {
+        // The TLS session state cannot be handed off with the socket.
+        if self.connection.tls {
+            return Err(PyRuntimeError::new_err(
+                "connections cannot be migrated while TLS is enabled",
+            ));
+        }
+
+        let snapshot = self.protocol.snapshot()?;
+
+        // The socket must not be shutdown as it's shared with whatever
+        // process the connection is handed off to.
+        self.connection.release_ip_slot();
+        self.event_loop.release()?;
+        self.protocol.connection_lost()?;
+        self.is_idle = true;
+        self.idle_for = self.event_loop.now()?;
+        self.trace_closed()?;
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: ConnectionSnapshot) -> PyResult<()> {
+        // The header was already read by the process handing it off.
+        self.awaiting_proxy_header = false;
+        self.protocol.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind};
+    use std::time::Duration;
+
+    use pyo3::prelude::*;
+    use pyo3::types::PyModule;
+
+    use crate::clock::Clock;
+    use crate::event_loop::EventLoop;
+    use crate::migration::ConnectionSnapshot;
+    use crate::settings::WriteStallGuard;
+    use crate::testing::{self, TestClient};
+
+    use super::{DISCONNECT_ERROR, WRITE_STALL_ERROR};
+
+    /// An event loop whose time only moves when set by the test.
+    const FAKE_LOOP: &str = r#"
+class FakeLoop:
+    now = 0.0
+
+    def time(self):
+        return self.now
+"#;
+
+    #[test]
+    fn nothing_is_parsed_after_a_rejected_request() {
+        let mut client = TestClient::new(testing::settings());
+
+        // More than a single read so the rest is read in the same wakeup.
+        let mut data = b"BREW / HTTP/1.1\r\n\r\n".to_vec();
+        data.extend_from_slice(&[b'a'; 64 * 1024]);
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        client.send(&data);
+
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 1);
+        assert_eq!(client.requests(), 0);
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn nothing_is_parsed_after_an_oversized_head() {
+        let mut settings = testing::settings();
+        settings.max_header_size = 1024;
+        let mut client = TestClient::new(settings);
+
+        let mut data = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        data.extend_from_slice(&[b'a'; 64 * 1024]);
+        data.extend_from_slice(b"\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        client.send(&data);
+
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 1);
+        assert_eq!(client.requests(), 0);
+    }
+
+    #[test]
+    fn pipelined_requests_wait_on_the_queued_response() {
+        let mut client = TestClient::new(testing::settings());
+
+        let mut data = Vec::new();
+        for _ in 0..4 {
+            data.extend_from_slice(b"GET / HTTP/1.1\r\nX-Padding: ");
+            data.extend_from_slice(&[b'a'; 16 * 1024]);
+            data.extend_from_slice(b"\r\n\r\n");
+        }
+        client.send(&data);
+        assert_eq!(client.requests(), 1);
+
+        for i in 0..4 {
+            client.respond(i, 200, b"ok");
+        }
+        assert_eq!(client.requests(), 4);
+        assert_eq!(client.take_written().matches("HTTP/1.1 200 OK").count(), 4);
+    }
+
+    #[test]
+    fn queued_reads_are_drained_in_a_single_wakeup() {
+        let mut settings = testing::settings();
+        settings.max_reads_per_wakeup = 16;
+        let mut client = TestClient::new(settings);
+
+        // The head arrives over 8 reads with no response to wait on.
+        let head = format!("GET / HTTP/1.1\r\nx-padding: {}\r\n\r\n", "a".repeat(97));
+        assert_eq!(head.len(), 8 * 16);
+        client.set_read_limit(Some(16));
+        client.feed(head.as_bytes());
+
+        client.try_poll_read().unwrap();
+        assert_eq!(client.unread(), 0);
+        assert_eq!(client.requests(), 1);
+    }
+
+    #[test]
+    fn reads_stop_at_the_cap_per_wakeup() {
+        let mut settings = testing::settings();
+        settings.max_reads_per_wakeup = 2;
+        let mut client = TestClient::new(settings);
+
+        client.set_read_limit(Some(16));
+        client.feed(&[b'a'; 8 * 16]);
+
+        client.try_poll_read().unwrap();
+        assert_eq!(client.unread(), 6 * 16);
+        assert_eq!(client.requests(), 0);
+    }
+
+    #[test]
+    fn read_error_is_kept_as_the_last_error() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.respond(0, 200, b"ok");
+        assert_eq!(client.last_error(), None);
+
+        client.fail_next_read(io::Error::other("device on fire"));
+        assert!(client.try_poll_read().is_err());
+        let error = client.last_error().unwrap();
+        assert!(error.contains("device on fire"), "{}", error);
+    }
+
+    #[test]
+    fn last_error_is_cleared_once_the_client_is_reused() {
+        let mut client = TestClient::new(testing::settings());
+        client.fail_next_read(ErrorKind::ConnectionReset.into());
+        client.try_poll_read().unwrap();
+        client.run();
+        assert!(client.is_closed());
+        assert_eq!(client.last_error().as_deref(), Some(DISCONNECT_ERROR));
+
+        client.reconnect().unwrap();
+        assert_eq!(client.last_error(), None);
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(client.requests(), 1);
+    }
+
+    #[test]
+    fn response_is_finished_after_the_peer_half_closes() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.send_eof();
+        assert!(!client.is_closed());
+
+        client.respond(0, 200, b"ok");
+        assert!(client.take_written().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn connection_is_migrated_at_a_request_boundary() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET /a HTTP/1.1\r\n\r\n");
+        assert!(client.snapshot().is_err());
+
+        // The start of the next request is handed off along with it.
+        client.respond(0, 200, b"a");
+        client.send(b"GET /b HTTP/1.1\r\n");
+        let encoded = client.snapshot().unwrap().encode();
+        client.run();
+        assert!(client.is_closed());
+
+        let snapshot = ConnectionSnapshot::decode(&encoded).unwrap();
+        assert!(snapshot.keep_alive);
+        assert_eq!(snapshot.pending, b"GET /b HTTP/1.1\r\n");
+
+        let mut migrated = TestClient::new(testing::settings());
+        migrated.restore(snapshot).unwrap();
+        assert_eq!(migrated.requests(), 0);
+        migrated.send(b"\r\n");
+        assert_eq!(migrated.requests(), 1);
+        assert_eq!(migrated.scope::<String>(0, "path"), "/b");
+
+        migrated.respond(0, 200, b"b");
+        assert!(migrated.take_written().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!migrated.is_closed());
+    }
+
+    #[test]
+    fn requests_received_are_migrated() {
+        let mut settings = testing::settings();
+        settings.max_requests_per_connection = Some(2);
+        let mut client = TestClient::new(settings);
+        client.send(b"GET /a HTTP/1.1\r\n\r\n");
+        client.respond(0, 200, b"a");
+
+        let encoded = client.snapshot().unwrap().encode();
+        let snapshot = ConnectionSnapshot::decode(&encoded).unwrap();
+        assert_eq!(snapshot.requests_received, 1);
+
+        // The migrated connection's second request is still its last.
+        let mut settings = testing::settings();
+        settings.max_requests_per_connection = Some(2);
+        let mut migrated = TestClient::new(settings);
+        migrated.restore(snapshot).unwrap();
+        migrated.send(b"GET /b HTTP/1.1\r\n\r\n");
+        migrated.respond(0, 200, b"b");
+
+        let written = migrated.take_written();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.contains("connection: close\r\n"));
+        assert!(migrated.is_closed());
+    }
+
+    #[test]
+    fn snapshots_of_other_versions_are_rejected() {
+        let snapshot = ConnectionSnapshot {
+            keep_alive: true,
+            requests_received: 3,
+            pending: b"GET".to_vec(),
+        };
+        let mut encoded = snapshot.encode();
+        assert!(ConnectionSnapshot::decode(&encoded).is_ok());
+        assert!(ConnectionSnapshot::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        encoded[0] = 1;
+        assert!(ConnectionSnapshot::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn stalled_slow_reader_is_force_closed() {
+        let mut settings = testing::settings();
+        settings.write_stall = Some(WriteStallGuard {
+            timeout: Duration::from_secs(3),
+            max_buffered: 16 * 1024,
+        });
+        let (mut client, time) = TestClient::with_manual_time(settings);
+        client.set_write_limit(Some(1024));
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.respond(0, 200, &[b'a'; 64 * 1024]);
+
+        // Reading slowly is progress, even for longer than the keep alive.
+        for _ in 0..5 {
+            time.advance(Duration::from_secs(2));
+            client.take_written();
+            client.poll_timers();
+        }
+        assert!(!client.is_closed());
+        assert_eq!(client.last_error(), None);
+
+        time.advance(Duration::from_secs(3));
+        client.poll_timers();
+        assert!(client.is_closed());
+        assert_eq!(client.last_error().as_deref(), Some(WRITE_STALL_ERROR));
+    }
+
+    #[test]
+    fn idle_connection_is_closed_after_keep_alive() {
+        let (mut client, time) = TestClient::with_manual_time(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.respond(0, 200, b"ok");
+
+        time.advance(Duration::from_secs(4));
+        client.poll_timers();
+        assert!(!client.is_closed());
+
+        time.advance(Duration::from_secs(1));
+        client.poll_timers();
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn keep_alive_starts_once_the_response_completes() {
+        let (mut client, time) = TestClient::with_manual_time(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+
+        // The application taking a while isn't the connection being idle.
+        time.advance(Duration::from_secs(10));
+        client.poll_timers();
+        assert!(!client.is_closed());
+
+        client.respond(0, 200, b"ok");
+        time.advance(Duration::from_secs(4));
+        client.poll_timers();
+        assert!(!client.is_closed());
+
+        time.advance(Duration::from_secs(1));
+        client.poll_timers();
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn timeouts_follow_the_loop_clock() {
+        pyo3::prepare_freethreaded_python();
+        let fake_loop = Python::with_gil(|py| -> PyResult<PyObject> {
+            let module =
+                PyModule::from_code(py, FAKE_LOOP, "fake_loop.py", "fake_loop")?;
+            Ok(module.getattr("FakeLoop")?.call0()?.into())
+        })
+        .unwrap();
+        let set_time = |secs: f64| {
+            Python::with_gil(|py| fake_loop.as_ref(py).setattr("now", secs)).unwrap();
+        };
+
+        let loop_time = Python::with_gil(|py| fake_loop.getattr(py, "time")).unwrap();
+        let event_loop = EventLoop::noop_with_clock(Clock::new(Some(loop_time)));
+        let mut client = TestClient::with_event_loop(testing::settings(), event_loop);
+        set_time(1000.0);
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.respond(0, 200, b"ok");
+
+        // However long has passed in reality, only the loop's time counts.
+        set_time(1004.5);
+        client.poll_timers();
+        assert!(!client.is_closed());
+
+        set_time(1005.0);
+        client.poll_timers();
+        assert!(client.is_closed());
+    }
+
+    /// A TLS 1.2 client of a test client terminating TLS with the test
+    /// certificate.
+    #[cfg(feature = "tls")]
+    fn tls_client() -> (TestClient, rustls::ClientConnection) {
+        use std::convert::TryInto;
+        use std::fs::File;
+        use std::io::BufReader;
+        use std::sync::Arc;
+
+        use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+        use crate::net::TlsConfig;
+
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+        let cert = format!("{}/cert.pem", testdata);
+        let key = format!("{}/key.pem", testdata);
+        let mut settings = testing::settings();
+        settings.tls =
+            Some(TlsConfig::from_pem_files(&cert, &key, false, None, None).unwrap());
+
+        let ca = File::open(format!("{}/ca.pem", testdata)).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(
+            &rustls_pemfile::certs(&mut BufReader::new(ca)).unwrap(),
+        );
+        let config = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = "localhost".try_into().unwrap();
+        let tls = ClientConnection::new(Arc::new(config), name).unwrap();
+
+        (TestClient::new(settings), tls)
+    }
+
+    /// Exchanges the records pending on either side until the client has
+    /// nothing more to send.
+    #[cfg(feature = "tls")]
+    fn exchange(client: &mut TestClient, tls: &mut rustls::ClientConnection) {
+        while tls.wants_write() {
+            let mut records = Vec::new();
+            tls.write_tls(&mut records).unwrap();
+            client.send(&records);
+
+            let written = client.take_written_bytes();
+            if !written.is_empty() {
+                tls.read_tls(&mut written.as_slice()).unwrap();
+                tls.process_new_packets().unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_renegotiation_closes_the_connection() {
+        use std::io::Write;
+
+        use crate::net::RENEGOTIATION_ERROR;
+
+        let (mut client, mut tls) = tls_client();
+        exchange(&mut client, &mut tls);
+        assert!(!tls.is_handshaking());
+
+        tls.writer().write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        exchange(&mut client, &mut tls);
+        assert_eq!(client.requests(), 1);
+
+        // Renegotiating starts a new handshake record, its contents are
+        // encrypted so they're never looked at.
+        client.send(&[22, 3, 3, 0, 4, 1, 0, 0, 0]);
+        assert!(client.is_closed());
+        assert_eq!(client.last_error().as_deref(), Some(RENEGOTIATION_ERROR));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_error_is_kept_as_the_last_error() {
+        let (mut client, _tls) = tls_client();
+
+        // A record of a content type that doesn't exist.
+        client.send(&[99, 3, 1, 0, 1, 0]);
+        assert!(client.is_closed());
+        let error = client.last_error().unwrap();
+        assert!(error.starts_with("tls error: "), "{}", error);
+    }
 }
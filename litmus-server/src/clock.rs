@@ -0,0 +1,100 @@
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pyo3::prelude::*;
+
+use crate::traits::TimeSource;
+
+/// The clock used by the server to calculate timeouts.
+///
+/// By default the monotonic `Instant` clock is used, alternatively the
+/// clock can be driven by the Python event loop's `loop.time()` so that
+/// any timeouts line up with how the loop schedules its own callbacks.
+#[derive(Clone)]
+pub struct Clock {
+    source: Arc<dyn TimeSource + Send + Sync>,
+
+    /// When the clock was created and the time of the source then, used to
+    /// turn the clock's time into an `Instant`.
+    origin: Instant,
+    start: Duration,
+}
+
+impl Clock {
+    /// Creates a new clock using the given loop time callback if one
+    /// is provided, otherwise falls back to a monotonic clock.
+    pub fn new(loop_time: Option<PyObject>) -> Self {
+        match loop_time {
+            Some(cb) => Self::from_source(LoopTime(cb)),
+            None => Self::from_source(MonotonicTime(Instant::now())),
+        }
+    }
+
+    /// Creates a new clock driven by the given time source.
+    pub fn from_source(source: impl TimeSource + Send + Sync + 'static) -> Self {
+        Self {
+            origin: Instant::now(),
+            start: source.now().unwrap_or_default(),
+            source: Arc::new(source),
+        }
+    }
+
+    /// Gets the current time of the clock.
+    ///
+    /// The returned time is only meaningful when compared to another
+    /// time produced by the same clock.
+    pub fn now(&self) -> PyResult<Duration> {
+        self.source.now()
+    }
+
+    /// Gets the current time of the clock as an `Instant` for the APIs
+    /// which take one, this only moves as the clock's time does.
+    pub fn instant(&self) -> PyResult<Instant> {
+        Ok(self.origin + self.now()?.saturating_sub(self.start))
+    }
+}
+
+/// Time measured from the given origin using `Instant`.
+struct MonotonicTime(Instant);
+
+impl TimeSource for MonotonicTime {
+    fn now(&self) -> PyResult<Duration> {
+        Ok(self.0.elapsed())
+    }
+}
+
+/// Time taken from a Python callback returning the loop time in seconds
+/// as a float.
+struct LoopTime(PyObject);
+
+impl TimeSource for LoopTime {
+    fn now(&self) -> PyResult<Duration> {
+        Python::with_gil(|py| -> PyResult<Duration> {
+            let secs: f64 = self.0.call0(py)?.extract(py)?;
+            Ok(Duration::from_secs_f64(secs.max(0.0)))
+        })
+    }
+}
+
+/// A deterministic time source which only moves when advanced manually,
+/// allowing timeouts to be tested without sleeping.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct ManualTime(Arc<AtomicU64>);
+
+#[cfg(test)]
+impl ManualTime {
+    /// Moves the time forward by the given duration.
+    pub(crate) fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl TimeSource for ManualTime {
+    fn now(&self) -> PyResult<Duration> {
+        Ok(Duration::from_nanos(self.0.load(Ordering::Relaxed)))
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{self, Write};
+
+use brotli::CompressorWriter;
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::settings::{Compression, Encoding};
+
+/// The quality used for brotli, higher qualities are too slow to be
+/// applied on the fly.
+const BROTLI_QUALITY: u32 = 4;
+
+/// The brotli window size as a power of two.
+const BROTLI_WINDOW: u32 = 22;
+
+/// The size of brotli's internal buffer.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// The content types compressed besides any `text/*` type.
+const COMPRESSIBLE_TYPES: &[&[u8]] = &[
+    b"application/json",
+    b"application/javascript",
+    b"application/xml",
+    b"application/xhtml+xml",
+    b"application/rss+xml",
+    b"application/atom+xml",
+    b"application/wasm",
+    b"image/svg+xml",
+];
+
+/// Picks the coding to compress the response with from the request's
+/// `Accept-Encoding` header, `None` if the client accepts none of the
+/// server's codings.
+///
+/// The coding with the highest quality value wins, ties are broken using
+/// the server's order of preference.
+pub(crate) fn negotiate(compression: &Compression, accept: &[u8]) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let mut wildcard = None;
+    let mut listed = Vec::new();
+
+    for item in accept.split(|b| *b == b',') {
+        let mut parts = item.split(|b| *b == b';');
+        let name = parts.next().unwrap_or_default().trim_ascii();
+        let quality = parts
+            .filter_map(|p| p.trim_ascii().strip_prefix(b"q="))
+            .filter_map(|q| std::str::from_utf8(q).ok()?.parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if name == b"*" {
+            wildcard = Some(quality);
+            continue;
+        }
+
+        for encoding in compression.encodings.iter() {
+            if name.eq_ignore_ascii_case(encoding.as_str().as_bytes()) {
+                listed.push(*encoding);
+                best = pick(best, *encoding, quality, &compression.encodings);
+            }
+        }
+    }
+
+    // The wildcard applies to any coding not explicitly listed.
+    if let Some(quality) = wildcard {
+        for encoding in compression.encodings.iter() {
+            if !listed.contains(encoding) {
+                best = pick(best, *encoding, quality, &compression.encodings);
+            }
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Picks between the current best coding and a candidate, a quality of
+/// zero means the coding is not acceptable.
+fn pick(
+    best: Option<(Encoding, f32)>,
+    encoding: Encoding,
+    quality: f32,
+    preference: &[Encoding],
+) -> Option<(Encoding, f32)> {
+    if quality <= 0.0 {
+        return best;
+    }
+
+    let rank = |e: Encoding| preference.iter().position(|p| *p == e);
+    match best {
+        Some((current, q))
+            if (q > quality) | ((q == quality) & (rank(current) < rank(encoding))) =>
+        {
+            best
+        },
+        _ => Some((encoding, quality)),
+    }
+}
+
+/// If a response with the given `Content-Type` is worth compressing,
+/// already compressed formats such as images are left alone.
+pub(crate) fn is_compressible(content_type: &[u8]) -> bool {
+    let mime = content_type
+        .split(|b| *b == b';')
+        .next()
+        .unwrap_or_default()
+        .trim_ascii()
+        .to_ascii_lowercase();
+
+    mime.starts_with(b"text/")
+        | mime.ends_with(b"+json")
+        | mime.ends_with(b"+xml")
+        | COMPRESSIBLE_TYPES.contains(&mime.as_slice())
+}
+
+/// Compresses a response body as it is streamed.
+pub(crate) enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Creates a new encoder for the given coding and compression level,
+    /// the level only applies to gzip and deflate.
+    pub(crate) fn new(encoding: Encoding, level: u32) -> Self {
+        let level = flate2::Compression::new(level);
+        match encoding {
+            Encoding::Brotli => Self::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), level)),
+            Encoding::Deflate => Self::Deflate(ZlibEncoder::new(Vec::new(), level)),
+        }
+    }
+
+    /// Compresses a chunk of the body returning the compressed output.
+    ///
+    /// Each chunk is flushed so streamed responses reach the client as they
+    /// are sent rather than once the encoder's buffer fills up.
+    pub(crate) fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let out = match self {
+            Self::Brotli(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            },
+            Self::Gzip(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            },
+            Self::Deflate(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            },
+        };
+
+        Ok(std::mem::take(out))
+    }
+
+    /// Compresses the last chunk of the body and finishes the stream.
+    pub(crate) fn finish(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli(mut e) => {
+                e.write_all(data)?;
+                Ok(e.into_inner())
+            },
+            Self::Gzip(mut e) => {
+                e.write_all(data)?;
+                e.finish()
+            },
+            Self::Deflate(mut e) => {
+                e.write_all(data)?;
+                e.finish()
+            },
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+thread_local! {
+    static CACHED: RefCell<CachedDate> = RefCell::new(CachedDate::default());
+}
+
+/// The `date` header line of the last second a response was sent in.
+///
+/// Responses are built on the event loop's thread so the cache is kept
+/// per thread, avoiding any locking on the hot path.
+#[derive(Default)]
+struct CachedDate {
+    /// The second since the Unix epoch the line was formatted for.
+    second: u64,
+
+    /// The rendered header line including the line separator.
+    line: Vec<u8>,
+}
+
+/// Appends the `date` header line for the current time to the head of a
+/// response, the line is only formatted once per second.
+pub(crate) fn extend_date_header(out: &mut Vec<u8>) {
+    let now = SystemTime::now();
+    let second = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    CACHED.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        if cached.line.is_empty() | (cached.second != second) {
+            cached.second = second;
+            cached.line.clear();
+            let _ = write!(cached.line, "date: {}\r\n", httpdate::HttpDate::from(now));
+        }
+
+        out.extend_from_slice(&cached.line);
+    });
+}
@@ -1,8 +1,20 @@
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 use pyo3::prelude::*;
 
+use crate::clock::Clock;
+#[cfg(feature = "http3")]
+use crate::net::NO_FD;
+
+#[cfg(unix)]
+use crate::poller::{Poller, Token};
+#[cfg(feature = "http3")]
+use crate::quic::Signals;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+use crate::uring::Uring;
+
 type CheapPyObject = Arc<PyObject>;
 
 #[cfg(windows)]
@@ -13,11 +25,59 @@ pub type SocketFd = i32;
 
 #[derive(Clone)]
 pub struct EventLoop {
-    add_reader: CheapPyObject,
-    remove_reader: CheapPyObject,
-    add_writer: CheapPyObject,
-    remove_writer: CheapPyObject,
-    close_socket: CheapPyObject,
+    backend: Backend,
+    clock: Clock,
+
+    /// The QUIC driver's signals, the clients of HTTP/3 request streams
+    /// have no socket of their own and are registered with these instead.
+    #[cfg(feature = "http3")]
+    quic: Option<Arc<Signals>>,
+}
+
+/// What file descriptors are registered with.
+#[derive(Clone)]
+enum Backend {
+    /// The asyncio event loop, invoked through the Python callbacks.
+    Asyncio {
+        add_reader: CheapPyObject,
+        remove_reader: CheapPyObject,
+        add_writer: CheapPyObject,
+        remove_writer: CheapPyObject,
+        close_socket: CheapPyObject,
+
+        /// Applies a whole batch of registrations in one call if given,
+        /// otherwise each is applied with its own callback.
+        apply_registrations: Option<CheapPyObject>,
+
+        /// The registrations made while the server is handling an event,
+        /// applied once it's done with it.
+        batch: Arc<Mutex<RegistrationBatch>>,
+    },
+
+    /// The server's own poller, no Python is invoked when registering.
+    #[cfg(unix)]
+    Native(Arc<Poller>),
+
+    /// The server's io_uring driver, which owns the sockets it accepts.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    Uring(Arc<Uring>),
+
+    /// Nothing is registered and closing is left to the caller, used to
+    /// drive connections over in-memory sockets. The indexes of the sockets
+    /// asked to be closed are kept until taken by `take_closed()`.
+    Noop(Arc<Mutex<Vec<usize>>>),
+
+    /// The streams of a multiplexed connection, e.g. HTTP/2, which have no
+    /// socket of their own, see `EventLoop::multiplexed()`.
+    Multiplexed(Arc<Multiplexed>),
+}
+
+/// The connection the streams of a multiplexed event loop are carried by.
+struct Multiplexed {
+    connection: PreSetEventLoop,
+
+    /// The indexes of the streams asked to be closed.
+    closed: Mutex<Vec<usize>>,
 }
 
 impl EventLoop {
@@ -27,43 +87,405 @@ impl EventLoop {
         add_writer: PyObject,
         remove_writer: PyObject,
         close_socket: PyObject,
+        apply_registrations: Option<PyObject>,
+        clock: Clock,
     ) -> Self {
-        Self {
+        let backend = Backend::Asyncio {
             add_reader: Arc::from(add_reader),
             remove_reader: Arc::from(remove_reader),
             add_writer: Arc::from(add_writer),
             remove_writer: Arc::from(remove_writer),
             close_socket: Arc::from(close_socket),
+            apply_registrations: apply_registrations.map(Arc::from),
+            batch: Arc::default(),
+        };
+
+        Self {
+            backend,
+            clock,
+            #[cfg(feature = "http3")]
+            quic: None,
+        }
+    }
+
+    /// Creates an event loop registering file descriptors with the given
+    /// native poller.
+    #[cfg(unix)]
+    pub(crate) fn native(poller: Arc<Poller>, clock: Clock) -> Self {
+        Self {
+            backend: Backend::Native(poller),
+            clock,
+            #[cfg(feature = "http3")]
+            quic: None,
         }
     }
 
+    /// Creates an event loop registering file descriptors with the given
+    /// io_uring driver.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub(crate) fn uring(uring: Arc<Uring>, clock: Clock) -> Self {
+        Self {
+            backend: Backend::Uring(uring),
+            clock,
+            #[cfg(feature = "http3")]
+            quic: None,
+        }
+    }
+
+    /// Creates an event loop which does nothing when registering or
+    /// closing sockets, allowing a connection to be driven entirely by
+    /// hand e.g. over a `MemoryHandle`.
+    pub fn noop() -> Self {
+        Self::noop_with_clock(Clock::new(None))
+    }
+
+    /// Creates a no-op event loop measuring timeouts with the given clock,
+    /// e.g. one driven by hand.
+    pub fn noop_with_clock(clock: Clock) -> Self {
+        Self {
+            backend: Backend::Noop(Arc::default()),
+            clock,
+            #[cfg(feature = "http3")]
+            quic: None,
+        }
+    }
+
+    /// Creates an event loop for the streams multiplexed over the given
+    /// connection, registered with their index and `NO_FD`.
+    ///
+    /// Nothing is registered with the connection's event loop for a stream,
+    /// instead anything a stream registers or closes wakes the connection's
+    /// writer which then drives the stream. The indexes of the streams asked
+    /// to be closed are kept until taken by `take_closed()`.
+    pub(crate) fn multiplexed(connection: PreSetEventLoop) -> Self {
+        Self {
+            clock: connection.event_loop.clock.clone(),
+            backend: Backend::Multiplexed(Arc::new(Multiplexed {
+                connection,
+                closed: Mutex::default(),
+            })),
+            #[cfg(feature = "http3")]
+            quic: None,
+        }
+    }
+
+    /// Takes the indexes of the sockets a no-op or multiplexed event loop
+    /// was asked to close since this was last called, always empty for
+    /// other loops.
+    pub fn take_closed(&self) -> Vec<usize> {
+        match &self.backend {
+            Backend::Noop(closed) => std::mem::take(&mut *closed.lock().unwrap()),
+            Backend::Multiplexed(mux) => {
+                std::mem::take(&mut *mux.closed.lock().unwrap())
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Registers the clients of HTTP/3 request streams with the given QUIC
+    /// driver's signals rather than the backend.
+    #[cfg(feature = "http3")]
+    pub(crate) fn with_quic(mut self, signals: Arc<Signals>) -> Self {
+        self.quic = Some(signals);
+        self
+    }
+
+    /// The native poller if the event loop is backed by one.
+    #[cfg(unix)]
+    pub(crate) fn poller(&self) -> Option<&Arc<Poller>> {
+        match &self.backend {
+            Backend::Native(poller) => Some(poller),
+            _ => None,
+        }
+    }
+
+    /// The io_uring driver if the event loop is backed by one.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub(crate) fn ring(&self) -> Option<&Arc<Uring>> {
+        match &self.backend {
+            Backend::Uring(uring) => Some(uring),
+            _ => None,
+        }
+    }
+
+    /// The QUIC driver's signals if the socket is an HTTP/3 request stream,
+    /// these have no file descriptor of their own.
+    #[cfg(feature = "http3")]
+    #[inline]
+    fn quic(&self, fd: SocketFd) -> Option<&Signals> {
+        match self.quic.as_deref() {
+            Some(signals) if fd == NO_FD => Some(signals),
+            _ => None,
+        }
+    }
+
+    /// Gets the current time of the loop's clock.
+    pub fn now(&self) -> PyResult<Duration> {
+        self.clock.now()
+    }
+
+    /// The clock timeouts are measured with.
+    #[cfg(feature = "http3")]
+    pub(crate) fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Closes the socket of the client at the given index, unlike
+    /// `close_socket()` this knows of sockets owned by the QUIC driver.
+    #[cfg_attr(not(feature = "http3"), allow(unused_variables))]
+    pub(crate) fn close_client(&self, fd: SocketFd, index: usize) -> PyResult<()> {
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic(fd) {
+            quic.close_socket(index);
+            return Ok(());
+        }
+
+        self.close_socket(index)
+    }
+
     pub fn close_socket(&self, index: usize) -> PyResult<()> {
-        Python::with_gil(|py| -> PyResult<()> {
-            let _ = self.close_socket.call1(py, (index,))?;
-            Ok(())
-        })
+        match &self.backend {
+            Backend::Asyncio { close_socket, .. } => {
+                Python::with_gil(|py| -> PyResult<()> {
+                    let _ = close_socket.call1(py, (index,))?;
+                    Ok(())
+                })
+            },
+            #[cfg(unix)]
+            Backend::Native(poller) => Ok(poller.close_socket(index)?),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Backend::Uring(uring) => {
+                uring.close_socket(index);
+                Ok(())
+            },
+            Backend::Noop(closed) => {
+                closed.lock().unwrap().push(index);
+                Ok(())
+            },
+            Backend::Multiplexed(mux) => {
+                mux.closed.lock().unwrap().push(index);
+                mux.connection.add_writer()
+            },
+        }
     }
 
     /// Start monitoring the file descriptor for read availability
     /// and invokes a callback once the fd is available for reading.
     pub fn add_reader(&self, fd: SocketFd, index: usize) -> PyResult<()> {
-        self.invoke_add(&self.add_reader, fd, index)
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic(fd) {
+            quic.add_reader(index);
+            return Ok(());
+        }
+
+        match &self.backend {
+            Backend::Asyncio {
+                add_reader, batch, ..
+            } => {
+                if batch_registration(batch, Registration::AddReader, fd, index) {
+                    return Ok(());
+                }
+                self.invoke_add(add_reader, fd, index)
+            },
+            #[cfg(unix)]
+            Backend::Native(poller) => Ok(poller.add_reader(fd, Token::Client(index))?),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Backend::Uring(uring) => {
+                uring.add_reader(fd, index);
+                Ok(())
+            },
+            Backend::Noop(_) => Ok(()),
+            // Anything waiting to be read by the stream is fed to it once the
+            // connection's writer is polled.
+            Backend::Multiplexed(mux) => mux.connection.add_writer(),
+        }
     }
 
     /// Stop monitoring the file descriptor for read availability.
     pub fn remove_reader(&self, fd: SocketFd) -> PyResult<()> {
-        self.invoke_remove(&self.remove_reader, fd)
+        match &self.backend {
+            Backend::Asyncio {
+                remove_reader,
+                batch,
+                ..
+            } => {
+                if batch_registration(batch, Registration::RemoveReader, fd, 0) {
+                    return Ok(());
+                }
+                self.invoke_remove(remove_reader, fd)
+            },
+            #[cfg(unix)]
+            Backend::Native(poller) => Ok(poller.remove_reader(fd)?),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Backend::Uring(uring) => {
+                uring.remove_reader(fd);
+                Ok(())
+            },
+            Backend::Noop(_) | Backend::Multiplexed(_) => Ok(()),
+        }
     }
 
     /// Start monitoring the file descriptor for write availability
     /// and invokes a callback once the fd is available for writing.
     pub fn add_writer(&self, fd: SocketFd, index: usize) -> PyResult<()> {
-        self.invoke_add(&self.add_writer, fd, index)
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic(fd) {
+            quic.add_writer(index);
+            return Ok(());
+        }
+
+        match &self.backend {
+            Backend::Asyncio {
+                add_writer, batch, ..
+            } => {
+                if batch_registration(batch, Registration::AddWriter, fd, index) {
+                    return Ok(());
+                }
+                self.invoke_add(add_writer, fd, index)
+            },
+            #[cfg(unix)]
+            Backend::Native(poller) => Ok(poller.add_writer(fd, Token::Client(index))?),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Backend::Uring(uring) => {
+                uring.add_writer(fd, index);
+                Ok(())
+            },
+            Backend::Noop(_) => Ok(()),
+            Backend::Multiplexed(mux) => mux.connection.add_writer(),
+        }
     }
 
     /// Stop monitoring the file descriptor for write availability.
     pub fn remove_writer(&self, fd: SocketFd) -> PyResult<()> {
-        self.invoke_remove(&self.remove_writer, fd)
+        match &self.backend {
+            Backend::Asyncio {
+                remove_writer,
+                batch,
+                ..
+            } => {
+                if batch_registration(batch, Registration::RemoveWriter, fd, 0) {
+                    return Ok(());
+                }
+                self.invoke_remove(remove_writer, fd)
+            },
+            #[cfg(unix)]
+            Backend::Native(poller) => Ok(poller.remove_writer(fd)?),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            Backend::Uring(uring) => {
+                uring.remove_writer(fd);
+                Ok(())
+            },
+            Backend::Noop(_) | Backend::Multiplexed(_) => Ok(()),
+        }
+    }
+
+    /// Stop monitoring the client at the given index for read availability,
+    /// unlike `remove_reader()` this knows of sockets owned by the QUIC
+    /// driver.
+    #[cfg_attr(not(feature = "http3"), allow(unused_variables))]
+    pub(crate) fn remove_client_reader(
+        &self,
+        fd: SocketFd,
+        index: usize,
+    ) -> PyResult<()> {
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic(fd) {
+            quic.remove_reader(index);
+            return Ok(());
+        }
+
+        self.remove_reader(fd)
+    }
+
+    /// Stop monitoring the client at the given index for write availability,
+    /// unlike `remove_writer()` this knows of sockets owned by the QUIC
+    /// driver.
+    #[cfg_attr(not(feature = "http3"), allow(unused_variables))]
+    pub(crate) fn remove_client_writer(
+        &self,
+        fd: SocketFd,
+        index: usize,
+    ) -> PyResult<()> {
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic(fd) {
+            quic.remove_writer(index);
+            return Ok(());
+        }
+
+        self.remove_writer(fd)
+    }
+
+    /// Starts batching the registrations made with the asyncio backend
+    /// rather than invoking the event loop for each, until the matching
+    /// `end_batch()`. Batches can be nested, only the outermost applies
+    /// the registrations.
+    pub(crate) fn begin_batch(&self) {
+        if let Backend::Asyncio { batch, .. } = &self.backend {
+            batch.lock().expect("batch lock").depth += 1;
+        }
+    }
+
+    /// Ends a batch started with `begin_batch()`, applying every
+    /// registration made during it once the outermost batch ends.
+    ///
+    /// Registrations undone later in the batch are dropped, e.g. a reader
+    /// added and removed again while handling the same event never
+    /// reaches the event loop.
+    pub(crate) fn end_batch(&self) -> PyResult<()> {
+        let (callbacks, apply, batch) = match &self.backend {
+            Backend::Asyncio {
+                add_reader,
+                remove_reader,
+                add_writer,
+                remove_writer,
+                apply_registrations,
+                batch,
+                ..
+            } => (
+                [add_reader, remove_reader, add_writer, remove_writer],
+                apply_registrations,
+                batch,
+            ),
+            _ => return Ok(()),
+        };
+
+        let queued = {
+            let mut batch = batch.lock().expect("batch lock");
+            batch.depth = batch.depth.saturating_sub(1);
+            if batch.depth > 0 {
+                return Ok(());
+            }
+            coalesce(&mut batch.queued)
+        };
+
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        Python::with_gil(|py| -> PyResult<()> {
+            if let Some(apply) = apply {
+                let ops: Vec<(u8, SocketFd, usize)> = queued
+                    .iter()
+                    .map(|&(op, fd, index)| (op as u8, fd, index))
+                    .collect();
+                let _ = apply.call1(py, (ops,))?;
+                return Ok(());
+            }
+
+            for (op, fd, index) in queued {
+                let cb = callbacks[op as usize];
+                let _ = match op {
+                    Registration::AddReader | Registration::AddWriter => {
+                        cb.call1(py, (fd, index))?
+                    },
+                    Registration::RemoveReader | Registration::RemoveWriter => {
+                        cb.call1(py, (fd,))?
+                    },
+                };
+            }
+
+            Ok(())
+        })
     }
 
     fn invoke_remove(&self, cb: &PyObject, fd: SocketFd) -> PyResult<()> {
@@ -81,12 +503,136 @@ impl EventLoop {
     }
 }
 
+/// A change to what a socket is monitored for, the discriminant is the code
+/// it's given to `apply_registrations` as.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Registration {
+    AddReader = 0,
+    RemoveReader = 1,
+    AddWriter = 2,
+    RemoveWriter = 3,
+}
+
+impl Registration {
+    #[inline]
+    fn is_add(self) -> bool {
+        matches!(self, Self::AddReader | Self::AddWriter)
+    }
+
+    #[inline]
+    fn is_reader(self) -> bool {
+        matches!(self, Self::AddReader | Self::RemoveReader)
+    }
+}
+
+/// The registrations queued while the server handles an event.
+#[derive(Default)]
+struct RegistrationBatch {
+    /// How many batches have been started and not yet ended.
+    depth: usize,
+
+    /// The `(registration, fd, index)` of each registration in the order
+    /// they were made.
+    queued: Vec<(Registration, SocketFd, usize)>,
+}
+
+/// Queues the registration if a batch is in progress, returning if it was.
+fn batch_registration(
+    batch: &Mutex<RegistrationBatch>,
+    op: Registration,
+    fd: SocketFd,
+    index: usize,
+) -> bool {
+    let mut batch = batch.lock().expect("batch lock");
+    if batch.depth == 0 {
+        return false;
+    }
+
+    batch.queued.push((op, fd, index));
+    true
+}
+
+/// Reduces the queued registrations to those which change what the event
+/// loop monitors, only the last registration of a socket's reader or
+/// writer decides what it ends up as.
+///
+/// The first registration tells what the socket was before the batch, as
+/// `PreSetEventLoop` only adds a reader that isn't already added and so
+/// on, a remove following an add made in the same batch cancels out.
+fn coalesce(
+    queued: &mut Vec<(Registration, SocketFd, usize)>,
+) -> Vec<(Registration, SocketFd, usize)> {
+    // The position of the last registration of each socket's reader or
+    // writer and if it was already registered before the batch.
+    let mut last: HashMap<(SocketFd, bool), (usize, bool)> = HashMap::new();
+    let mut ops: Vec<Option<(Registration, SocketFd, usize)>> =
+        Vec::with_capacity(queued.len());
+
+    for (op, fd, index) in queued.drain(..) {
+        let key = (fd, op.is_reader());
+        let registered = match last.get(&key) {
+            Some(&(position, registered)) => {
+                ops[position] = None;
+                registered
+            },
+            None => !op.is_add(),
+        };
+
+        last.insert(key, (ops.len(), registered));
+        ops.push(Some((op, fd, index)));
+    }
+
+    for (position, registered) in last.into_values() {
+        let undone =
+            matches!(ops[position], Some((op, ..)) if !op.is_add() & !registered);
+        if undone {
+            ops[position] = None;
+        }
+    }
+
+    ops.into_iter().flatten().collect()
+}
+
+/// Where a `PreSetEventLoop`'s socket is in its life with the event loop.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SocketPhase {
+    /// The socket can be registered for read and write readiness.
+    Open,
+
+    /// Every registration was removed ahead of the socket being closed,
+    /// nothing can be registered until a new socket is bound.
+    Released,
+}
+
+/// The registration state of a socket shared by every clone of its
+/// `PreSetEventLoop`.
+struct Binding {
+    /// Bumped each time a new socket is bound, clones of the event loop
+    /// made for a previous socket are stale and can't touch the new one.
+    generation: u64,
+
+    phase: SocketPhase,
+
+    is_reading: bool,
+
+    is_writing: bool,
+
+    /// If the socket should be reset rather than closed gracefully once
+    /// its close is handled.
+    aborting: bool,
+}
+
 /// A wrapper around an EventLoop with a pre-set file descriptor and index.
 ///
 /// This helps abstract the set socket away from handlers that are designed
 /// to handle multiple different sockets.
 /// In theory in order to change a handler's socket it should only need
 /// to change the pre-set event loop.
+///
+/// Adding a reader or writer that's already added, or removing one that
+/// isn't, does nothing so the event loop never sees a socket registered
+/// twice. Clones handed out before the socket was rebound, e.g. with the
+/// transport of a previous connection, no longer affect it.
 #[derive(Clone)]
 pub struct PreSetEventLoop {
     /// The event loop to invoke.
@@ -98,79 +644,230 @@ pub struct PreSetEventLoop {
     /// The index location of the handler for the file descriptor.
     index: usize,
 
-    is_reading: Arc<AtomicBool>,
+    /// The generation of the binding this clone was made for.
+    generation: u64,
 
-    is_writing: Arc<AtomicBool>,
+    binding: Arc<Mutex<Binding>>,
 }
 
 impl PreSetEventLoop {
     pub fn new(event_loop: EventLoop, fd: SocketFd, index: usize) -> Self {
+        let binding = Binding {
+            generation: 0,
+            phase: SocketPhase::Open,
+            is_reading: false,
+            is_writing: false,
+            aborting: false,
+        };
+
         Self {
             event_loop,
             fd,
             index,
-            is_reading: Arc::new(AtomicBool::new(false)),
-            is_writing: Arc::new(AtomicBool::new(false)),
+            generation: 0,
+            binding: Arc::new(Mutex::new(binding)),
         }
     }
 
-    pub fn set_fd(&mut self, fd: SocketFd) {
-        self.is_reading.store(false, Ordering::Relaxed);
-        self.is_writing.store(false, Ordering::Relaxed);
+    /// Binds the event loop to a new socket, any registrations left on the
+    /// previous socket are removed first so they can't dangle once its
+    /// file descriptor is reused.
+    ///
+    /// Clones made before rebinding are stale from then on, anything they
+    /// try to register or close is ignored.
+    pub fn rebind(&mut self, fd: SocketFd, index: usize) -> PyResult<()> {
+        let (is_reading, is_writing) = {
+            let binding = self.lock();
+            (binding.is_reading, binding.is_writing)
+        };
+
+        debug_assert!(
+            !(is_reading | is_writing),
+            "fd {} rebound without being released",
+            self.fd,
+        );
+        if is_reading | is_writing {
+            debug!("fd {} rebound without being released", self.fd);
+            self.release()?;
+        }
+
+        self.generation = {
+            let mut binding = self.lock();
+            binding.generation += 1;
+            binding.phase = SocketPhase::Open;
+            binding.is_reading = false;
+            binding.is_writing = false;
+            binding.aborting = false;
+            binding.generation
+        };
         self.fd = fd;
+        self.index = index;
+
+        Ok(())
+    }
+
+    /// Removes the socket's reader and writer ahead of it being closed,
+    /// once released nothing can be registered until the event loop is
+    /// rebound.
+    pub fn release(&self) -> PyResult<()> {
+        if !self.is_current("release") {
+            return Ok(());
+        }
+
+        self.remove_reader()?;
+        self.remove_writer()?;
+        self.lock().phase = SocketPhase::Released;
+
+        Ok(())
+    }
+
+    /// The socket file descriptor the event loop is set to.
+    #[inline]
+    #[allow(unused)]
+    pub fn fd(&self) -> SocketFd {
+        self.fd
     }
 
+    /// The index of the handler the event loop is set to.
     #[inline]
-    fn is_reading(&self) -> bool {
-        self.is_reading.load(Ordering::Relaxed)
+    #[allow(unused)]
+    pub fn index(&self) -> usize {
+        self.index
     }
 
+    /// If the socket is being monitored for read readiness.
     #[inline]
-    fn is_writing(&self) -> bool {
-        self.is_writing.load(Ordering::Relaxed)
+    pub fn is_reading(&self) -> bool {
+        let binding = self.lock();
+        (binding.generation == self.generation) & binding.is_reading
+    }
+
+    /// If the socket is being monitored for write readiness.
+    #[inline]
+    pub fn is_writing(&self) -> bool {
+        let binding = self.lock();
+        (binding.generation == self.generation) & binding.is_writing
     }
 
     pub fn close_socket(&self) -> PyResult<()> {
-        self.event_loop.close_socket(self.index)
+        if !self.is_current("close") {
+            return Ok(());
+        }
+
+        self.event_loop.close_client(self.fd, self.index)
+    }
+
+    /// Closes the socket the same as `close_socket()` but resets the
+    /// connection instead of shutting it down gracefully.
+    pub fn abort_socket(&self) -> PyResult<()> {
+        if !self.is_current("abort") {
+            return Ok(());
+        }
+
+        self.lock().aborting = true;
+        self.event_loop.close_client(self.fd, self.index)
+    }
+
+    /// Takes whether the socket was aborted rather than closed.
+    pub fn take_aborting(&self) -> bool {
+        std::mem::take(&mut self.lock().aborting)
+    }
+
+    /// Gets the current time of the loop's clock.
+    pub fn now(&self) -> PyResult<Duration> {
+        self.event_loop.now()
     }
 
     /// Start monitoring the socket for read readiness.
     pub fn add_reader(&self) -> PyResult<()> {
-        if !self.is_reading() {
-            self.event_loop.add_reader(self.fd, self.index)?;
-            self.is_reading.store(true, Ordering::Relaxed);
+        if !self.can_register("add_reader") || self.lock().is_reading {
+            return Ok(());
         }
 
+        self.event_loop.add_reader(self.fd, self.index)?;
+        self.lock().is_reading = true;
+
         Ok(())
     }
 
+    /// Registers the reader again while the socket is already monitored,
+    /// for a connection that stopped reading with data possibly left.
+    ///
+    /// Readiness based backends keep reporting the socket as readable
+    /// anyway, completion based backends are told to let the connection
+    /// read what they've already received on their next turn.
+    pub fn rearm_reader(&self) -> PyResult<()> {
+        if !self.can_register("rearm_reader") || !self.lock().is_reading {
+            return Ok(());
+        }
+
+        self.event_loop.add_reader(self.fd, self.index)
+    }
+
     /// Stop monitoring the socket for read readiness.
     pub fn remove_reader(&self) -> PyResult<()> {
-        if self.is_reading() {
-            self.event_loop.remove_reader(self.fd)?;
-            self.is_reading.store(false, Ordering::Relaxed);
+        if !self.is_current("remove_reader") || !self.lock().is_reading {
+            return Ok(());
         }
 
+        self.event_loop.remove_client_reader(self.fd, self.index)?;
+        self.lock().is_reading = false;
+
         Ok(())
     }
 
     /// Start monitoring the socket for write readiness.
     pub fn add_writer(&self) -> PyResult<()> {
-        if !self.is_writing() {
-            self.event_loop.add_writer(self.fd, self.index)?;
-            self.is_writing.store(true, Ordering::Relaxed);
+        if !self.can_register("add_writer") || self.lock().is_writing {
+            return Ok(());
         }
 
+        self.event_loop.add_writer(self.fd, self.index)?;
+        self.lock().is_writing = true;
+
         Ok(())
     }
 
     /// Stops monitoring the socket for write readiness.
     pub fn remove_writer(&self) -> PyResult<()> {
-        if self.is_writing() {
-            self.event_loop.remove_writer(self.fd)?;
-            self.is_writing.store(false, Ordering::Relaxed);
+        if !self.is_current("remove_writer") || !self.lock().is_writing {
+            return Ok(());
         }
 
+        self.event_loop.remove_client_writer(self.fd, self.index)?;
+        self.lock().is_writing = false;
+
         Ok(())
     }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, Binding> {
+        self.binding.lock().expect("binding lock")
+    }
+
+    /// If this clone is still bound to the socket, logging the operation
+    /// being ignored if it's stale.
+    fn is_current(&self, op: &str) -> bool {
+        let current = self.lock().generation == self.generation;
+        if !current {
+            debug!("ignoring {} of fd {} from a stale event loop", op, self.fd);
+        }
+
+        current
+    }
+
+    /// If the socket can be registered, logging the registration being
+    /// ignored if the socket was released or this clone is stale.
+    fn can_register(&self, op: &str) -> bool {
+        if !self.is_current(op) {
+            return false;
+        }
+
+        let released = self.lock().phase == SocketPhase::Released;
+        if released {
+            debug!("ignoring {} of fd {} after it was released", op, self.fd);
+        }
+
+        !released
+    }
 }
@@ -0,0 +1,352 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// A range of addresses given in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Copy, Clone)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses a range, a bare address being a range of just itself.
+    fn parse(cidr: &str) -> Option<Self> {
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+
+        let addr: IpAddr = addr.trim().parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|&p| p <= max)?,
+            None => max,
+        };
+
+        Some(Self { addr, prefix })
+    }
+
+    /// If the address is in the range, IPv4-mapped IPv6 addresses are
+    /// treated as the IPv4 address they map.
+    fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            },
+            _ => false,
+        }
+    }
+}
+
+/// If the first `prefix` bits of the two addresses are the same.
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    let partial = prefix % 8;
+
+    if net[..full] != addr[..full] {
+        return false;
+    }
+
+    if partial == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - partial);
+    (net[full] & mask) == (addr[full] & mask)
+}
+
+/// The proxies trusted to report the client they forwarded a request on
+/// behalf of using the `Forwarded` or `X-Forwarded-For` and
+/// `X-Forwarded-Proto` headers.
+///
+/// The headers are only read from connections whose peer is trusted, the
+/// addresses they list are then followed from the nearest to the furthest
+/// until one isn't trusted, that address being the effective client.
+pub struct TrustedProxies {
+    ranges: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    /// Creates the trusted proxies from a list of addresses and CIDR ranges,
+    /// erroring with the first entry that isn't valid.
+    pub fn new(ranges: &[String]) -> Result<Self, String> {
+        let ranges = ranges
+            .iter()
+            .map(|range| Cidr::parse(range).ok_or_else(|| range.clone()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { ranges })
+    }
+
+    /// If the given address belongs to a trusted proxy.
+    pub(crate) fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(addr))
+    }
+
+    /// Resolves the effective client and scheme of a request received from
+    /// `peer` over `scheme`, both are returned as is if the peer isn't
+    /// trusted or the request wasn't forwarded.
+    ///
+    /// A `Forwarded` header takes precedence over `X-Forwarded-For`, the
+    /// port of the client is `0` unless given by `Forwarded`.
+    pub(crate) fn resolve(
+        &self,
+        peer: SocketAddr,
+        scheme: &'static str,
+        headers: &[httparse::Header],
+    ) -> (SocketAddr, &'static str) {
+        if !self.is_trusted(peer.ip()) {
+            return (peer, scheme);
+        }
+
+        let mut hops = forwarded_hops(headers);
+        if hops.is_empty() {
+            hops = x_forwarded_hops(headers);
+        }
+
+        let mut client = peer;
+        let mut scheme = scheme;
+        for hop in hops.iter().rev() {
+            // The hop was added by a trusted proxy so its scheme is too.
+            if let Some(proto) = hop.proto {
+                scheme = proto;
+            }
+
+            match hop.addr {
+                Some(addr) => client = addr,
+                None => break,
+            }
+
+            if !self.is_trusted(client.ip()) {
+                break;
+            }
+        }
+
+        (client, scheme)
+    }
+}
+
+/// A proxy's record of the client it forwarded a request on behalf of.
+struct Hop {
+    /// The address of the client, `None` if the proxy hid it or it isn't
+    /// valid.
+    addr: Option<SocketAddr>,
+
+    /// The scheme the client used if known.
+    proto: Option<&'static str>,
+}
+
+/// The values of every header with the given name joined into a single
+/// list, the same as if they had been sent as one header.
+fn header_list<'a>(headers: &'a [httparse::Header], name: &str) -> Vec<&'a str> {
+    headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(name))
+        .filter_map(|header| std::str::from_utf8(header.value).ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect()
+}
+
+/// Parses the hops of the RFC 7239 `Forwarded` header, e.g.
+/// `for=192.0.2.60;proto=https, for="[2001:db8::1]:4711"`.
+fn forwarded_hops(headers: &[httparse::Header]) -> Vec<Hop> {
+    header_list(headers, "forwarded")
+        .into_iter()
+        .filter(|element| !element.is_empty())
+        .map(|element| {
+            let mut hop = Hop {
+                addr: None,
+                proto: None,
+            };
+
+            for pair in element.split(';') {
+                let (name, value) = match pair.split_once('=') {
+                    Some((name, value)) => (name.trim(), unquote(value.trim())),
+                    None => continue,
+                };
+
+                if name.eq_ignore_ascii_case("for") {
+                    hop.addr = parse_node(value);
+                } else if name.eq_ignore_ascii_case("proto") {
+                    hop.proto = parse_scheme(value);
+                }
+            }
+
+            hop
+        })
+        .collect()
+}
+
+/// Parses the hops of the `X-Forwarded-For` header, each being given the
+/// scheme in the same position from the end of `X-Forwarded-Proto`.
+fn x_forwarded_hops(headers: &[httparse::Header]) -> Vec<Hop> {
+    let addrs = header_list(headers, "x-forwarded-for");
+    let protos = header_list(headers, "x-forwarded-proto");
+
+    addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            let from_end = addrs.len() - i;
+            let proto = protos
+                .len()
+                .checked_sub(from_end)
+                .and_then(|i| parse_scheme(protos[i]));
+
+            Hop {
+                addr: parse_node(addr),
+                proto,
+            }
+        })
+        .collect()
+}
+
+/// Removes the quotes around a quoted-string value if any.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Parses an address which may have a port, IPv6 addresses with a port
+/// being in brackets, e.g. `192.0.2.60`, `192.0.2.60:4711` or `[::1]:4711`.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+
+    let ip = node
+        .strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .unwrap_or(node);
+
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// Parses a forwarded scheme, anything other than http or https is ignored.
+fn parse_scheme(scheme: &str) -> Option<&'static str> {
+    if scheme.eq_ignore_ascii_case("https") {
+        Some("https")
+    } else if scheme.eq_ignore_ascii_case("http") {
+        Some("http")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.1:5000";
+
+    fn proxies(ranges: &[&str]) -> TrustedProxies {
+        let ranges: Vec<String> = ranges.iter().map(|r| r.to_string()).collect();
+        TrustedProxies::new(&ranges).unwrap()
+    }
+
+    fn resolve(
+        proxies: &TrustedProxies,
+        headers: &[(&str, &str)],
+    ) -> (SocketAddr, &'static str) {
+        let headers: Vec<httparse::Header> = headers
+            .iter()
+            .map(|(name, value)| httparse::Header {
+                name,
+                value: value.as_bytes(),
+            })
+            .collect();
+        proxies.resolve(PEER.parse().unwrap(), "http", &headers)
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_match_by_prefix() {
+        let proxies =
+            proxies(&["10.0.0.0/8", "192.168.1.1", "fd00::/12", "172.16.0.0/12"]);
+        assert!(proxies.is_trusted("10.255.0.1".parse().unwrap()));
+        assert!(proxies.is_trusted("192.168.1.1".parse().unwrap()));
+        assert!(!proxies.is_trusted("192.168.1.2".parse().unwrap()));
+        assert!(proxies.is_trusted("fd0f::1".parse().unwrap()));
+        assert!(!proxies.is_trusted("fd10::1".parse().unwrap()));
+        assert!(proxies.is_trusted("172.31.0.1".parse().unwrap()));
+        assert!(!proxies.is_trusted("172.32.0.1".parse().unwrap()));
+
+        // IPv4-mapped addresses match the IPv4 ranges.
+        assert!(proxies.is_trusted("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        for range in ["10.0.0.0/33", "::/129", "10.0.0", "proxy"] {
+            let err = TrustedProxies::new(&[range.to_string()]).err();
+            assert_eq!(err.as_deref(), Some(range));
+        }
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let proxies = proxies(&["192.168.0.0/16"]);
+        let headers = [("forwarded", "for=203.0.113.7;proto=https")];
+        assert_eq!(resolve(&proxies, &headers), (addr(PEER), "http"));
+    }
+
+    #[test]
+    fn forwarded_is_followed_until_an_untrusted_hop() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = [
+            (
+                "Forwarded",
+                "for=198.51.100.1, for=\"[2001:db8::1]:4711\";proto=https",
+            ),
+            ("forwarded", "for=10.0.0.2;proto=http"),
+        ];
+        assert_eq!(
+            resolve(&proxies, &headers),
+            (addr("[2001:db8::1]:4711"), "https")
+        );
+    }
+
+    #[test]
+    fn hidden_client_stops_at_the_proxy() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = [("forwarded", "for=203.0.113.7, for=unknown, for=10.0.0.2")];
+        assert_eq!(resolve(&proxies, &headers), (addr("10.0.0.2:0"), "http"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = [
+            ("x-forwarded-for", "198.51.100.1"),
+            ("forwarded", "for=203.0.113.7"),
+        ];
+        assert_eq!(resolve(&proxies, &headers), (addr("203.0.113.7:0"), "http"));
+    }
+
+    #[test]
+    fn x_forwarded_proto_is_matched_from_the_end() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = [
+            ("x-forwarded-for", "203.0.113.7, 10.0.0.3"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "https, ftp"),
+        ];
+        assert_eq!(
+            resolve(&proxies, &headers),
+            (addr("203.0.113.7:0"), "https")
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use pyo3::prelude::*;
+
+/// The id handed to the next accepted connection.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocates the id of a newly accepted connection, ids are unique for the
+/// lifetime of the process.
+pub(crate) fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The points in the life of a connection and its requests a tracer can
+/// hook into.
+#[derive(Copy, Clone)]
+pub(crate) enum TraceHook {
+    ConnectionAccepted,
+    RequestParsed,
+    ResponseStarted,
+    ResponseComplete,
+    ConnectionClosed,
+}
+
+impl TraceHook {
+    const ALL: [TraceHook; 5] = [
+        TraceHook::ConnectionAccepted,
+        TraceHook::RequestParsed,
+        TraceHook::ResponseStarted,
+        TraceHook::ResponseComplete,
+        TraceHook::ConnectionClosed,
+    ];
+
+    /// The name of the hook as given to the callbacks.
+    fn name(self) -> &'static str {
+        match self {
+            TraceHook::ConnectionAccepted => "connection_accepted",
+            TraceHook::RequestParsed => "request_parsed",
+            TraceHook::ResponseStarted => "response_started",
+            TraceHook::ResponseComplete => "response_complete",
+            TraceHook::ConnectionClosed => "connection_closed",
+        }
+    }
+}
+
+/// Invokes the callbacks of a trace config at each point in the life of a
+/// connection and its requests, letting APM tools instrument the server.
+///
+/// The config is any object with an `on_<hook>` attribute for the hooks it
+/// wants, e.g. `on_request_parsed`, each being a callable invoked with a
+/// `TraceEvent`. Hooks which are missing, `None` or empty lists are skipped
+/// entirely so they cost nothing.
+pub struct Tracer {
+    callbacks: [Option<PyObject>; 5],
+}
+
+impl Tracer {
+    /// Creates a tracer from the hooks of the given trace config, the
+    /// hooks are looked up once so the config can't be changed afterwards.
+    pub fn new(config: &PyAny) -> PyResult<Self> {
+        let mut callbacks = [None, None, None, None, None];
+
+        for (hook, slot) in TraceHook::ALL.iter().zip(callbacks.iter_mut()) {
+            let attr = format!("on_{}", hook.name());
+            if !config.hasattr(attr.as_str())? {
+                continue;
+            }
+
+            let callback = config.getattr(attr.as_str())?;
+            let empty = callback.is_none() || callback.len().is_ok_and(|len| len == 0);
+            if !empty {
+                *slot = Some(callback.into());
+            }
+        }
+
+        Ok(Self { callbacks })
+    }
+
+    /// If anything is hooked into the given point.
+    pub(crate) fn is_hooked(&self, hook: TraceHook) -> bool {
+        self.callbacks[hook as usize].is_some()
+    }
+
+    /// Invokes the callback of the given hook with the event made by
+    /// `make_event`, which is only called if the hook is in use.
+    ///
+    /// Errors raised by the callback are logged and otherwise silenced so
+    /// a tracer can't affect the connection.
+    pub(crate) fn emit(&self, hook: TraceHook, make_event: impl FnOnce() -> TraceEvent) {
+        let callback = match self.callbacks[hook as usize].as_ref() {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        let mut event = make_event();
+        event.hook = hook.name();
+
+        Python::with_gil(|py| {
+            let result =
+                Py::new(py, event).and_then(|event| callback.call1(py, (event,)));
+            if let Err(e) = result {
+                error!("trace hook {} raised an exception: {}", hook.name(), e);
+            }
+        });
+    }
+}
+
+/// The request in progress on a connection, kept to trace it through to
+/// its response.
+pub(crate) struct RequestTrace {
+    pub(crate) method: String,
+
+    /// The request target excluding any query string.
+    pub(crate) path: String,
+
+    /// When the request was parsed.
+    pub(crate) started: Duration,
+
+    /// The number of bytes of the request received, its head and any of
+    /// its body.
+    pub(crate) received: usize,
+
+    /// The number of bytes the connection had queued to be written when
+    /// the request was parsed.
+    pub(crate) queued_before: usize,
+}
+
+/// The details of a point in the life of a connection or a request given
+/// to the hooks of a trace config.
+///
+/// Times are in seconds by the event loop's clock, the byte counts are for
+/// the connection as a whole on connection events and for the request and
+/// its response on request events.
+#[pyclass(name = "TraceEvent")]
+pub struct TraceEvent {
+    hook: &'static str,
+    connection_id: u64,
+    client: SocketAddr,
+    server: SocketAddr,
+    time: Duration,
+    started: Duration,
+    bytes_received: usize,
+    bytes_sent: usize,
+    method: Option<String>,
+    path: Option<String>,
+    status: Option<u16>,
+}
+
+impl TraceEvent {
+    /// Creates the event of a connection accepted at `started`, or a
+    /// request parsed at `started`.
+    pub(crate) fn new(
+        connection_id: u64,
+        client: SocketAddr,
+        server: SocketAddr,
+        time: Duration,
+        started: Duration,
+    ) -> Self {
+        Self {
+            hook: "",
+            connection_id,
+            client,
+            server,
+            time,
+            started,
+            bytes_received: 0,
+            bytes_sent: 0,
+            method: None,
+            path: None,
+            status: None,
+        }
+    }
+
+    /// Sets the bytes received and sent.
+    pub(crate) fn with_bytes(mut self, received: usize, sent: usize) -> Self {
+        self.bytes_received = received;
+        self.bytes_sent = sent;
+        self
+    }
+
+    /// Sets the request the event is about along with the status of its
+    /// response once started.
+    pub(crate) fn with_request(
+        mut self,
+        method: &str,
+        path: &str,
+        status: Option<u16>,
+    ) -> Self {
+        self.method = Some(method.to_string());
+        self.path = Some(path.to_string());
+        self.status = status;
+        self
+    }
+}
+
+#[pymethods]
+impl TraceEvent {
+    /// The name of the hook, e.g. `"request_parsed"`.
+    #[getter]
+    fn hook(&self) -> &'static str {
+        self.hook
+    }
+
+    /// The id of the connection, unique for the lifetime of the process.
+    #[getter]
+    fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// The `(host, port)` of the client.
+    #[getter]
+    fn client(&self) -> (String, u16) {
+        (self.client.ip().to_string(), self.client.port())
+    }
+
+    /// The `(host, port)` of the listener the connection was accepted on.
+    #[getter]
+    fn server(&self) -> (String, u16) {
+        (self.server.ip().to_string(), self.server.port())
+    }
+
+    /// When the event happened.
+    #[getter]
+    fn time(&self) -> f64 {
+        self.time.as_secs_f64()
+    }
+
+    /// The seconds since the connection was accepted on connection events,
+    /// or since the request was parsed on request events.
+    #[getter]
+    fn elapsed(&self) -> f64 {
+        self.time.saturating_sub(self.started).as_secs_f64()
+    }
+
+    /// The number of bytes received so far.
+    #[getter]
+    fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    /// The number of bytes sent so far, on request events this counts what
+    /// has been queued to be written.
+    #[getter]
+    fn bytes_sent(&self) -> usize {
+        self.bytes_sent
+    }
+
+    /// The request method, `None` on connection events.
+    #[getter]
+    fn method(&self) -> Option<&str> {
+        self.method.as_deref()
+    }
+
+    /// The request target excluding any query string, `None` on connection
+    /// events.
+    #[getter]
+    fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// The status of the response, `None` until it has started.
+    #[getter]
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+}
@@ -0,0 +1,31 @@
+//! Optional structured tracing of the client I/O lifecycle.
+//!
+//! Spans and events are only emitted with the `tracing` feature enabled,
+//! otherwise the macros expand to nothing so the hot path is left untouched.
+
+/// Enters a span for the rest of the current block tagged with the
+/// client's file descriptor and index.
+#[cfg(feature = "tracing")]
+macro_rules! io_span {
+    ($name:literal, $fd:expr, $index:expr) => {
+        let _span = tracing::trace_span!($name, fd = $fd, index = $index).entered();
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! io_span {
+    ($name:literal, $fd:expr, $index:expr) => {};
+}
+
+/// Emits an event within the current span.
+#[cfg(feature = "tracing")]
+macro_rules! io_event {
+    ($($arg:tt)+) => {
+        tracing::trace!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! io_event {
+    ($($arg:tt)+) => {};
+}
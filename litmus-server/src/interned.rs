@@ -0,0 +1,110 @@
+use pyo3::once_cell::GILOnceCell;
+use pyo3::types::{PyBytes, PyString};
+use pyo3::{Py, Python};
+
+/// The lowercase names of the headers most requests are sent with, roughly
+/// in order of how often they're seen so lookups end early.
+const COMMON_HEADERS: &[&str] = &[
+    "host",
+    "user-agent",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "connection",
+    "cookie",
+    "content-type",
+    "content-length",
+    "cache-control",
+    "referer",
+    "origin",
+    "authorization",
+    "upgrade-insecure-requests",
+    "sec-fetch-site",
+    "sec-fetch-mode",
+    "sec-fetch-dest",
+    "sec-fetch-user",
+    "sec-ch-ua",
+    "sec-ch-ua-mobile",
+    "sec-ch-ua-platform",
+    "if-none-match",
+    "if-modified-since",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-real-ip",
+    "x-request-id",
+    "forwarded",
+    "pragma",
+    "priority",
+    "dnt",
+    "te",
+    "transfer-encoding",
+    "expect",
+    "range",
+    "if-range",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-protocol",
+    "via",
+    "traceparent",
+];
+
+/// The request methods defined by HTTP/1.1 and RFC 5789.
+const METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+static HEADER_NAMES: GILOnceCell<Vec<Py<PyBytes>>> = GILOnceCell::new();
+static METHOD_NAMES: GILOnceCell<Vec<Py<PyString>>> = GILOnceCell::new();
+
+/// Gets the header name lowercased as a `bytes` object.
+///
+/// Common names are created once and shared between every request, both
+/// saving the allocation and lowercasing of the name on each request and
+/// the churn of short lived Python objects. Any other name is lowercased
+/// straight into a new object.
+pub(crate) fn header_name<'p>(py: Python<'p>, name: &str) -> &'p PyBytes {
+    let index = COMMON_HEADERS
+        .iter()
+        .position(|common| common.eq_ignore_ascii_case(name));
+
+    if let Some(index) = index {
+        let names = HEADER_NAMES.get_or_init(py, || {
+            COMMON_HEADERS
+                .iter()
+                .map(|name| Py::from(PyBytes::new(py, name.as_bytes())))
+                .collect()
+        });
+
+        return names[index].as_ref(py);
+    }
+
+    // This only fails if the object can't be allocated, in which case
+    // `PyBytes::new` would panic as well.
+    PyBytes::new_with(py, name.len(), |buffer| {
+        buffer.copy_from_slice(name.as_bytes());
+        buffer.make_ascii_lowercase();
+        Ok(())
+    })
+    .expect("failed to allocate a bytes object")
+}
+
+/// Gets the request method as a `str` object, the standard methods are
+/// created once and shared between every request.
+pub(crate) fn method<'p>(py: Python<'p>, method: &str) -> &'p PyString {
+    match METHODS.iter().position(|&known| known == method) {
+        Some(index) => {
+            let methods = METHOD_NAMES.get_or_init(py, || {
+                METHODS
+                    .iter()
+                    .map(|method| Py::from(PyString::new(py, method)))
+                    .collect()
+            });
+
+            methods[index].as_ref(py)
+        },
+        None => PyString::new(py, method),
+    }
+}
@@ -1,14 +1,47 @@
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+mod instrument;
+
+mod access_log;
+mod bench;
 mod client;
+mod clock;
+mod compression;
+mod date;
 mod event_loop;
+mod forwarded;
+mod hooks;
+mod interned;
+pub mod lsgi;
 mod manager;
+mod metrics;
+mod migration;
+pub mod multipart;
 mod net;
+pub mod plugin;
+#[cfg(unix)]
+mod poller;
+mod pool;
 mod protocols;
-mod lsgi;
+#[cfg(feature = "http3")]
+mod quic;
+mod range;
+mod rate_limit;
+mod request_id;
 pub mod responders;
+mod router;
 pub mod server;
 pub mod settings;
+mod static_files;
+#[cfg(test)]
+mod testing;
 mod traits;
 mod transport;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
+
+pub use event_loop::{EventLoop, PreSetEventLoop};
+pub use hooks::TraceEvent;
+pub use net::{MemoryHandle, SocketStatus, StreamHandle};
@@ -1,14 +1,20 @@
-use pyo3::types::PyBytes;
-use pyo3::Py;
+use std::sync::Arc;
 
-/// A set of headers.
-///
-/// Each header is a (name, value) pair.
-type Headers<'a> = Vec<(&'a str, Py<PyBytes>)>;
+use pyo3::class::PyMappingProtocol;
+use pyo3::exceptions::PyKeyError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList, PyString};
+
+use crate::interned;
+use crate::net::TlsDetails;
 
 /// A simple tuple containing the ip string and port
 type SocketDetails = (String, u16);
 
+/// The DER of the client's certificate along with the `(name, value)`
+/// attributes of its subject.
+type PeerCertificate = Option<(Py<PyBytes>, Vec<(String, String)>)>;
+
 /// The type of the scope call
 pub const SCOPE_TYPE: &str = "http";
 
@@ -22,57 +28,372 @@ pub const HTTP_10: &str = "1.0";
 pub const HTTP_11: &str = "1.1";
 
 /// The HTTP/2 specification
-pub const _HTTP_2: &str = "2";
-
-/// The LSGI (Litmus Server Gateway Interface) scope that contains all state of the server and
-/// request.
-pub type LSGIScope<'a> = (
-    // type
-    //
-    // The type of scope, for a request this is "http"
-    &'static str,
-    // http_version
-    //
-    // One of "1.0", "1.1" or "2", representing HTTP/1, HTTP/1.1, HTTP/2.
-    &'static str,
-    // method
-    //
-    // The HTTP method name, in uppercase.
-    &'a str,
-    // scheme
-    //
-    // URL scheme portion, either http or https.
-    &'static str,
-    // path
-    //
-    // HTTP request target excluding any query string,
-    // with percent-encoded sequences and UTF-8 byte sequences
-    // decoded into characters.
-    &'a str,
-    // query_string
-    //
-    // URL portion after the ?, percent-encoded.
-    &'a str,
-    // root_path
-    //
-    // The root path this application is mounted at
-    &'a str,
-    // headers
-    //
-    // iterable of `(name, value)` two-item iterables, where name
-    // is the header name, and value is the header value.
-    // Order of header values must be preserved from the original
-    // HTTP request.
-    Headers<'a>,
-    // client
-    //
-    // A two-item iterable of (host, port), where host is the remote
-    // host’s IPv4 or IPv6 address, and port is the remote port
-    // as an u16.
-    SocketDetails,
-    // server
-    //
-    // A two-item iterable of (host, port), where host is the
-    // listening address for this server.
-    SocketDetails,
-);
+pub const HTTP_2: &str = "2";
+
+/// The keys the scope can be indexed by, matching its attributes.
+const SCOPE_KEYS: &[&str] = &[
+    "type",
+    "http_version",
+    "method",
+    "scheme",
+    "path",
+    "raw_path",
+    "query",
+    "query_string",
+    "root_path",
+    "headers",
+    "client",
+    "server",
+    "client_cert",
+    "request_id",
+    "connection",
+];
+
+/// The LSGI (Litmus Server Gateway Interface) scope that contains all state
+/// of the server and request.
+///
+/// The scope is built once per request, its values are available both as
+/// attributes, e.g. `scope.path`, and by indexing it like a dict, e.g.
+/// `scope["path"]`.
+#[pyclass(name = "Scope")]
+pub struct Scope {
+    http_version: &'static str,
+    method: Py<PyString>,
+    scheme: &'static str,
+    path: String,
+    raw_path: Py<PyBytes>,
+    query: String,
+    root_path: &'static str,
+    headers: Py<PyList>,
+    client: SocketDetails,
+    server: SocketDetails,
+    client_cert: PeerCertificate,
+    request_id: Option<Arc<str>>,
+    connection: Py<ConnectionInfo>,
+}
+
+impl Scope {
+    /// Creates the scope of a request.
+    ///
+    /// The path is percent-decoded while the query is kept as it was sent,
+    /// header names are lowercased. The method and common header names are
+    /// shared between requests, see `interned`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        py: Python,
+        http_version: &'static str,
+        method: &str,
+        scheme: &'static str,
+        raw_path: &str,
+        query: &str,
+        headers: &[httparse::Header],
+        client: SocketDetails,
+        server: SocketDetails,
+        client_cert: PeerCertificate,
+        request_id: Option<Arc<str>>,
+        connection: Py<ConnectionInfo>,
+    ) -> Self {
+        let list = PyList::empty(py);
+        for header in headers {
+            let pair = (
+                interned::header_name(py, header.name),
+                PyBytes::new(py, header.value),
+            );
+            let _ = list.append(pair);
+        }
+
+        Self {
+            http_version,
+            method: Py::from(interned::method(py, method)),
+            scheme,
+            path: percent_decode(raw_path),
+            raw_path: Py::from(PyBytes::new(py, raw_path.as_bytes())),
+            query: query.to_string(),
+            root_path: TEMP_ROOT_PATH,
+            headers: Py::from(list),
+            client,
+            server,
+            client_cert,
+            request_id,
+            connection,
+        }
+    }
+
+    /// The value of the given key, `None` if there's no such key.
+    fn value(&self, py: Python, key: &str) -> Option<PyObject> {
+        let value = match key {
+            "type" => self.scope_type().into_py(py),
+            "http_version" => self.http_version().into_py(py),
+            "method" => self.method(py).into_py(py),
+            "scheme" => self.scheme().into_py(py),
+            "path" => self.path().into_py(py),
+            "raw_path" => self.raw_path(py).into_py(py),
+            "query" => self.query().into_py(py),
+            "query_string" => self.query_string(py).into_py(py),
+            "root_path" => self.root_path().into_py(py),
+            "headers" => self.headers(py).into_py(py),
+            "client" => self.client().into_py(py),
+            "server" => self.server().into_py(py),
+            "client_cert" => self.client_cert(py),
+            "request_id" => self.request_id().into_py(py),
+            "connection" => self.connection(py).into_py(py),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+}
+
+#[pymethods]
+impl Scope {
+    /// The type of scope, for a request this is `"http"`.
+    #[getter(type)]
+    fn scope_type(&self) -> &'static str {
+        SCOPE_TYPE
+    }
+
+    /// One of `"1.0"` or `"1.1"`.
+    #[getter]
+    fn http_version(&self) -> &'static str {
+        self.http_version
+    }
+
+    /// The HTTP method name, in uppercase.
+    #[getter]
+    fn method(&self, py: Python) -> Py<PyString> {
+        self.method.clone_ref(py)
+    }
+
+    /// URL scheme portion, either `"http"` or `"https"`.
+    #[getter]
+    fn scheme(&self) -> &'static str {
+        self.scheme
+    }
+
+    /// The request target excluding any query string, with percent-encoded
+    /// sequences decoded and invalid UTF-8 replaced.
+    #[getter]
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The request target excluding any query string as it was sent.
+    #[getter]
+    fn raw_path(&self, py: Python) -> Py<PyBytes> {
+        self.raw_path.clone_ref(py)
+    }
+
+    /// URL portion after the `?`, percent-encoded.
+    #[getter]
+    fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// URL portion after the `?` as bytes, percent-encoded.
+    #[getter]
+    fn query_string<'p>(&self, py: Python<'p>) -> &'p PyBytes {
+        PyBytes::new(py, self.query.as_bytes())
+    }
+
+    /// The root path this application is mounted at.
+    #[getter]
+    fn root_path(&self) -> &'static str {
+        self.root_path
+    }
+
+    /// A list of `(name, value)` byte string pairs with lowercase names in
+    /// the order they were sent.
+    #[getter]
+    fn headers(&self, py: Python) -> Py<PyList> {
+        self.headers.clone_ref(py)
+    }
+
+    /// The `(host, port)` of the client.
+    #[getter]
+    fn client(&self) -> SocketDetails {
+        self.client.clone()
+    }
+
+    /// The `(host, port)` of the listener the request was received on.
+    #[getter]
+    fn server(&self) -> SocketDetails {
+        self.server.clone()
+    }
+
+    /// The certificate the client verified itself with using mutual TLS as
+    /// a tuple of its DER and its subject's `(name, value)` attributes,
+    /// `None` without TLS or if the client didn't give one.
+    #[getter]
+    fn client_cert(&self, py: Python) -> PyObject {
+        match self.client_cert.as_ref() {
+            Some((der, subject)) => (der.clone_ref(py), subject.clone()).into_py(py),
+            None => py.None(),
+        }
+    }
+
+    /// The id the request was given if request ids are enabled, also sent
+    /// back in the `X-Request-ID` response header.
+    #[getter]
+    fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// The connection the request was received on.
+    #[getter]
+    fn connection(&self, py: Python) -> Py<ConnectionInfo> {
+        self.connection.clone_ref(py)
+    }
+
+    /// The keys the scope can be indexed by.
+    fn keys(&self) -> Vec<&'static str> {
+        SCOPE_KEYS.to_vec()
+    }
+
+    /// Gets the value of the given key, or the default if there's no such
+    /// key.
+    #[args(default = "None")]
+    fn get(&self, py: Python, key: &str, default: Option<PyObject>) -> PyObject {
+        self.value(py, key).or(default).unwrap_or_else(|| py.None())
+    }
+}
+
+#[pyproto]
+impl PyMappingProtocol for Scope {
+    fn __getitem__(&self, key: &str) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            self.value(py, key)
+                .ok_or_else(|| PyKeyError::new_err(key.to_string()))
+        })
+    }
+
+    fn __len__(&self) -> usize {
+        SCOPE_KEYS.len()
+    }
+}
+
+/// The details of the connection a request was received on, shared by every
+/// request made over the same connection.
+///
+/// Unlike the scope's `client` and `server` the addresses are those of the
+/// socket itself, they aren't replaced with those given by a trusted proxy.
+#[pyclass(name = "ConnectionInfo")]
+pub struct ConnectionInfo {
+    connection_id: u64,
+    local: SocketDetails,
+    remote: SocketDetails,
+    tls: Option<TlsDetails>,
+    peer_cert: PeerCertificate,
+}
+
+impl ConnectionInfo {
+    /// Creates the details of a connection, `tls` is `None` unless the
+    /// connection is encrypted.
+    pub(crate) fn new(
+        connection_id: u64,
+        local: SocketDetails,
+        remote: SocketDetails,
+        tls: Option<TlsDetails>,
+        peer_cert: PeerCertificate,
+    ) -> Self {
+        Self {
+            connection_id,
+            local,
+            remote,
+            tls,
+            peer_cert,
+        }
+    }
+}
+
+#[pymethods]
+impl ConnectionInfo {
+    /// The id of the connection, unique within the worker and the same as
+    /// the one given to trace hooks.
+    #[getter]
+    fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// The `(host, port)` the connection was accepted on.
+    #[getter]
+    fn local(&self) -> SocketDetails {
+        self.local.clone()
+    }
+
+    /// The `(host, port)` of the peer of the socket.
+    #[getter]
+    fn remote(&self) -> SocketDetails {
+        self.remote.clone()
+    }
+
+    /// If the connection is encrypted with TLS.
+    #[getter]
+    fn tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// The negotiated TLS version, e.g. `"TLSv1.3"`, `None` without TLS.
+    #[getter]
+    fn tls_version(&self) -> Option<&'static str> {
+        self.tls.as_ref().and_then(|tls| tls.version)
+    }
+
+    /// The IANA name of the negotiated cipher suite, e.g.
+    /// `"TLS_AES_128_GCM_SHA256"`, `None` without TLS.
+    #[getter]
+    fn cipher(&self) -> Option<&str> {
+        self.tls.as_ref().and_then(|tls| tls.cipher.as_deref())
+    }
+
+    /// The protocol negotiated using ALPN, e.g. `"http/1.1"`, `None`
+    /// without TLS or if the client didn't offer any protocols.
+    #[getter]
+    fn alpn_protocol(&self) -> Option<String> {
+        self.tls
+            .as_ref()
+            .and_then(|tls| tls.alpn_protocol.as_deref())
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned())
+    }
+
+    /// The certificate the client verified itself with using mutual TLS as
+    /// a tuple of its DER and its subject's `(name, value)` attributes,
+    /// the same as the scope's `client_cert`.
+    #[getter]
+    fn peer_cert(&self, py: Python) -> PyObject {
+        match self.peer_cert.as_ref() {
+            Some((der, subject)) => (der.clone_ref(py), subject.clone()).into_py(py),
+            None => py.None(),
+        }
+    }
+}
+
+/// Decodes the percent-encoded sequences of a path, sequences that aren't
+/// valid are left as they are.
+pub(crate) fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    if !bytes.contains(&b'%') {
+        return path.to_string();
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| (bytes[i] == b'%') & hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
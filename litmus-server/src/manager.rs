@@ -1,12 +1,17 @@
+use bytes::BytesMut;
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
 use slab::Slab;
 
 use crate::event_loop::{EventLoop, PreSetEventLoop};
+use crate::migration::ConnectionSnapshot;
 use crate::net::StreamHandle;
+use crate::pool::{ClientPool, PoolStats};
 use crate::server::CallbackHandler;
 use crate::settings::Settings;
-use crate::traits::{PollHandler, RawPollHandler, Reusable};
+use crate::traits::{
+    CompletionHandler, Migratable, PollHandler, RawPollHandler, Reusable,
+};
 
 const MAX_QUEUE_SIZE: usize = 512;
 
@@ -23,7 +28,9 @@ macro_rules! get_or_reject {
     }};
 }
 
-pub(crate) struct ClientManager<C: Reusable + PollHandler> {
+pub(crate) struct ClientManager<
+    C: Reusable + PollHandler + Migratable + CompletionHandler,
+> {
     /// The Python callback
     callback: CallbackHandler,
 
@@ -35,9 +42,12 @@ pub(crate) struct ClientManager<C: Reusable + PollHandler> {
 
     /// The server configuration settings.
     settings: Settings,
+
+    /// The idle clients ready to be recycled for new connections.
+    pool: ClientPool<C>,
 }
 
-impl<C: Reusable + PollHandler> ClientManager<C> {
+impl<C: Reusable + PollHandler + Migratable + CompletionHandler> ClientManager<C> {
     pub(crate) fn new(
         callback: CallbackHandler,
         event_loop: EventLoop,
@@ -45,31 +55,177 @@ impl<C: Reusable + PollHandler> ClientManager<C> {
     ) -> Self {
         Self {
             clients: Slab::with_capacity(MAX_QUEUE_SIZE),
+            pool: ClientPool::new(
+                settings.min_pooled_clients,
+                settings.max_pooled_clients,
+            ),
             callback,
             event_loop,
             settings,
         }
     }
 
-    pub(crate) fn handle_connection(&mut self, conn: StreamHandle) -> PyResult<()> {
+    /// Binds the new connection to a client returning the index of the
+    /// client.
+    pub(crate) fn handle_connection(&mut self, conn: StreamHandle) -> PyResult<usize> {
         let index = self.clients.insert(None);
         debug!(
             "creating new index {} for new connection: {:?}",
             index, conn.addr
         );
-        let el = PreSetEventLoop::new(self.event_loop.clone(), conn.fd(), index);
-        let handle = C::new(self.callback.clone(), el, conn, self.settings.clone())?;
-        self.clients[index].replace(handle);
+        io_event!(fd = conn.fd(), index, addr = %conn.addr, "accepted connection");
 
-        Ok(())
+        if let Err(e) = conn.set_options(&self.settings.socket_options) {
+            warn!("failed to set socket options on {:?}: {}", conn.addr, e);
+        }
+
+        let handle = match self.pool.acquire() {
+            Some(mut handle) => handle.rebind(conn, index).map(|_| handle),
+            None => {
+                self.pool.record_created();
+                let el = PreSetEventLoop::new(self.event_loop.clone(), conn.fd(), index);
+                C::new(self.callback.clone(), el, conn, self.settings.clone())
+            },
+        };
+
+        match handle {
+            Ok(handle) => {
+                self.clients[index].replace(handle);
+                self.settings.metrics.connection_opened();
+                Ok(index)
+            },
+            Err(e) => {
+                self.clients.remove(index);
+                Err(e)
+            },
+        }
+    }
+
+    /// Takes a snapshot of the client at the given index detaching it
+    /// from its connection.
+    pub(crate) fn snapshot(&mut self, index: usize) -> PyResult<ConnectionSnapshot> {
+        let handle = get_or_reject!(&mut self.clients, index)?;
+        handle.snapshot()
+    }
+
+    /// Binds a migrated connection to a client and restores its state.
+    pub(crate) fn restore(
+        &mut self,
+        conn: StreamHandle,
+        snapshot: ConnectionSnapshot,
+    ) -> PyResult<usize> {
+        let index = self.handle_connection(conn)?;
+        let handle = get_or_reject!(&mut self.clients, index)?;
+        handle.restore(snapshot)?;
+        Ok(index)
+    }
+
+    /// Hands the client at the given index data received by the event
+    /// loop, `None` marking the EOF.
+    pub(crate) fn poll_received(
+        &mut self,
+        index: usize,
+        data: Option<&[u8]>,
+    ) -> PyResult<()> {
+        let handle = get_or_reject!(&mut self.clients, index)?;
+        handle.poll_received(data)
+    }
+
+    /// Takes the data the client at the given index has queued to be sent
+    /// by the event loop.
+    pub(crate) fn take_outbound(&mut self, index: usize) -> PyResult<Option<BytesMut>> {
+        let handle = get_or_reject!(&mut self.clients, index)?;
+        handle.take_outbound()
+    }
+
+    /// Reads ahead from the sockets of the clients at the given indexes,
+    /// which must be sorted, spread over up to `threads` threads.
+    ///
+    /// Nothing is called into Python so this can be run with the GIL
+    /// released, the clients handle what was read on their next poll.
+    pub(crate) fn prefetch(&mut self, indexes: &[usize], threads: usize)
+    where
+        C: Send,
+    {
+        let mut clients: Vec<&mut C> = self
+            .clients
+            .iter_mut()
+            .filter(|(index, _)| indexes.binary_search(index).is_ok())
+            .filter_map(|(_, client)| client.as_mut())
+            .collect();
+
+        if clients.is_empty() {
+            return;
+        }
+
+        let per_thread = clients.len().div_ceil(threads.max(1));
+        let result = crossbeam::scope(|scope| {
+            let mut chunks = clients.chunks_mut(per_thread);
+
+            // The current thread takes the first share rather than waiting.
+            let first = chunks.next();
+            for chunk in chunks {
+                scope.spawn(move |_| chunk.iter_mut().for_each(|c| c.prefetch()));
+            }
+
+            if let Some(chunk) = first {
+                chunk.iter_mut().for_each(|c| c.prefetch());
+            }
+        });
+
+        if let Err(e) = result {
+            std::panic::resume_unwind(e);
+        }
     }
 
     pub(crate) fn len_clients(&self) -> usize {
         self.clients.len()
     }
+
+    /// The number of clients with an open connection.
+    pub(crate) fn len_active(&self) -> usize {
+        self.clients
+            .iter()
+            .filter(|(_, c)| c.as_ref().map(|c| !c.is_idle()).unwrap_or(false))
+            .count()
+    }
+
+    /// Starts draining every connection, idle keep-alive connections are
+    /// closed immediately and the rest once their current response is done.
+    pub(crate) fn drain(&mut self) -> PyResult<()> {
+        for (_, client) in self.clients.iter_mut() {
+            if let Some(client) = client.as_mut() {
+                client.poll_drain()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn len_pooled(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// A snapshot of the client pool's counters.
+    pub(crate) fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Gets the last error of the client at the given index.
+    pub(crate) fn last_error(&self, index: usize) -> PyResult<Option<String>> {
+        match self.clients.get(index).and_then(|c| c.as_ref()) {
+            Some(client) => Ok(client.last_error().map(String::from)),
+            None => Err(PyRuntimeError::new_err(format!(
+                "client does not exist with index {}",
+                index
+            ))),
+        }
+    }
 }
 
-impl<C: Reusable + PollHandler> RawPollHandler for ClientManager<C> {
+impl<C: Reusable + PollHandler + Migratable + CompletionHandler> RawPollHandler
+    for ClientManager<C>
+{
     /// Invokes a read event on a given handler.
     ///
     /// Invoked by the python event loop when the file descriptor is ready to be
@@ -103,11 +259,14 @@ impl<C: Reusable + PollHandler> RawPollHandler for ClientManager<C> {
     fn poll_keep_alive(&mut self) -> PyResult<()> {
         let mut remove = Vec::new();
         for (id, client) in self.clients.iter_mut() {
-            if client.is_none() {
-                remove.push(id);
-            }
+            let client = match client.as_mut() {
+                Some(client) => client,
+                None => {
+                    remove.push(id);
+                    continue;
+                },
+            };
 
-            let client = client.as_mut().unwrap();
             if !client.is_idle() {
                 client.poll_keep_alive()?;
             } else if !client.is_free() {
@@ -116,8 +275,26 @@ impl<C: Reusable + PollHandler> RawPollHandler for ClientManager<C> {
             }
         }
 
+        // Idle clients are recycled rather than dropped.
         for id in remove {
-            self.clients.remove(id);
+            if let Some(client) = self.clients.remove(id) {
+                self.pool.release(client);
+            }
+        }
+
+        self.pool.trim();
+        self.settings.buffers.trim();
+        let now = self.event_loop.now()?;
+        if let Some(limiter) = self.settings.ip_limiter.as_ref() {
+            limiter.prune(now);
+        }
+        self.settings
+            .metrics
+            .set_active_connections(self.len_active());
+        self.settings.metrics.tick(now);
+
+        if let Some(log) = self.settings.access_log.as_ref() {
+            log.flush();
         }
 
         Ok(())
@@ -131,6 +308,10 @@ impl<C: Reusable + PollHandler> RawPollHandler for ClientManager<C> {
             };
         }
 
+        if let Some(log) = self.settings.access_log.as_ref() {
+            log.flush();
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,331 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The upper bounds in seconds of the request latency histogram buckets,
+/// the same as the Prometheus client libraries' defaults.
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The lowest status code counted, anything outside `100..600` is ignored.
+const MIN_STATUS: u16 = 100;
+const MAX_STATUS: u16 = 600;
+
+/// The counters of a server, updated as connections are accepted and
+/// requests are answered.
+///
+/// Each worker has its own metrics, these are plain relaxed atomics so
+/// they cost next to nothing to keep up to date.
+pub struct Metrics {
+    connections_total: AtomicU64,
+    active_connections: AtomicU64,
+    requests_total: AtomicU64,
+
+    /// The number of responses with each status code, indexed from
+    /// `MIN_STATUS`.
+    statuses: Vec<AtomicU64>,
+
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+
+    /// The number of requests whose latency fell in each bucket, the
+    /// last bucket counting anything above the largest bound.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+
+    /// The request rate measured over the last tick.
+    rate: Mutex<RequestRate>,
+}
+
+struct RequestRate {
+    /// The clock time of the last tick, `None` before the first.
+    last_tick: Option<Duration>,
+    last_total: u64,
+    per_second: f64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections_total: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            requests_total: AtomicU64::new(0),
+            statuses: (MIN_STATUS..MAX_STATUS)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            latency_buckets: Default::default(),
+            latency_sum_micros: AtomicU64::new(0),
+            rate: Mutex::new(RequestRate {
+                last_tick: None,
+                last_total: 0,
+                per_second: 0.0,
+            }),
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the number of connections currently open, this is refreshed
+    /// each keep alive check rather than tracked as connections close.
+    pub(crate) fn set_active_connections(&self, active: usize) {
+        self.active_connections
+            .store(active as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bytes_received(&self, amount: usize) {
+        self.bytes_received
+            .fetch_add(amount as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bytes_sent(&self, amount: usize) {
+        self.bytes_sent.fetch_add(amount as u64, Ordering::Relaxed);
+    }
+
+    /// Records a response with the given status which took `latency` to
+    /// queue from when the request was dispatched.
+    pub(crate) fn record_response(&self, status: u16, latency: Duration) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+
+        if (MIN_STATUS..MAX_STATUS).contains(&status) {
+            let index = (status - MIN_STATUS) as usize;
+            self.statuses[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Updates the request rate with the requests answered since the last
+    /// tick, called periodically with the time of the event loop's clock.
+    pub(crate) fn tick(&self, now: Duration) {
+        let total = self.requests_total.load(Ordering::Relaxed);
+        let mut rate = self.rate.lock().unwrap();
+
+        if let Some(last_tick) = rate.last_tick {
+            let elapsed = now.saturating_sub(last_tick).as_secs_f64();
+            if elapsed > 0.0 {
+                rate.per_second = (total - rate.last_total) as f64 / elapsed;
+            }
+        }
+
+        rate.last_tick = Some(now);
+        rate.last_total = total;
+    }
+
+    /// Takes a snapshot of the current values.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let statuses = self
+            .statuses
+            .iter()
+            .enumerate()
+            .map(|(index, count)| {
+                (index as u16 + MIN_STATUS, count.load(Ordering::Relaxed))
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+
+        // The buckets are cumulative as with Prometheus histograms.
+        let mut cumulative = 0;
+        let mut latency_buckets = Vec::with_capacity(LATENCY_BUCKETS.len());
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.latency_buckets.iter()) {
+            cumulative += count.load(Ordering::Relaxed);
+            latency_buckets.push((*bound, cumulative));
+        }
+
+        MetricsSnapshot {
+            connections_total: self.connections_total.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            requests_per_second: self.rate.lock().unwrap().per_second,
+            statuses,
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            latency_buckets,
+            latency_count: self
+                .latency_buckets
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .sum(),
+            latency_sum: self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        }
+    }
+}
+
+/// The values of the metrics at a point in time.
+pub struct MetricsSnapshot {
+    pub connections_total: u64,
+    pub active_connections: u64,
+    pub requests_total: u64,
+    pub requests_per_second: f64,
+
+    /// The number of responses with each status code that has been sent.
+    pub statuses: Vec<(u16, u64)>,
+
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+
+    /// The upper bound of each latency bucket in seconds along with the
+    /// number of requests which took at most that long.
+    pub latency_buckets: Vec<(f64, u64)>,
+    pub latency_count: u64,
+
+    /// The total latency of every request in seconds.
+    pub latency_sum: f64,
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(2048);
+
+        write_metric(
+            &mut out,
+            "litmus_connections_active",
+            "gauge",
+            "The number of open connections as of the last keep alive check.",
+            self.active_connections,
+        );
+        write_metric(
+            &mut out,
+            "litmus_connections_total",
+            "counter",
+            "The number of connections accepted.",
+            self.connections_total,
+        );
+        write_metric(
+            &mut out,
+            "litmus_requests_total",
+            "counter",
+            "The number of requests answered.",
+            self.requests_total,
+        );
+        write_metric(
+            &mut out,
+            "litmus_requests_per_second",
+            "gauge",
+            "The rate of requests answered over the last keep alive interval.",
+            self.requests_per_second,
+        );
+
+        write_header(
+            &mut out,
+            "litmus_responses_total",
+            "counter",
+            "The number of responses sent by status code.",
+        );
+        for (status, count) in self.statuses.iter() {
+            let _ = writeln!(
+                out,
+                "litmus_responses_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "litmus_received_bytes_total",
+            "counter",
+            "The number of bytes read from connections.",
+            self.bytes_received,
+        );
+        write_metric(
+            &mut out,
+            "litmus_sent_bytes_total",
+            "counter",
+            "The number of bytes written to connections.",
+            self.bytes_sent,
+        );
+
+        let name = "litmus_request_duration_seconds";
+        write_header(
+            &mut out,
+            name,
+            "histogram",
+            "The time taken to respond to requests.",
+        );
+        for (bound, count) in self.latency_buckets.iter() {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.latency_count);
+        let _ = writeln!(out, "{}_sum {}", name, self.latency_sum);
+        let _ = writeln!(out, "{}_count {}", name, self.latency_count);
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    write_header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// The progress of the response to the current request, used to record
+/// it in the metrics and access log once complete.
+pub(crate) struct ResponseStats {
+    /// When the request was dispatched by the event loop's clock.
+    pub(crate) started: Duration,
+
+    /// The status of the response, `0` until the response head is seen.
+    pub(crate) status: u16,
+
+    /// The number of bytes of the response after the head.
+    pub(crate) size: usize,
+}
+
+impl ResponseStats {
+    pub(crate) fn new(started: Duration) -> Self {
+        Self {
+            started,
+            status: 0,
+            size: 0,
+        }
+    }
+
+    /// Records a chunk of the response, the status is read from the head
+    /// which is expected to be at the start of the first chunk.
+    pub(crate) fn observe(&mut self, chunk: &[u8]) {
+        if self.status != 0 {
+            self.size += chunk.len();
+            return;
+        }
+
+        // `HTTP/1.1 200 ...`
+        self.status = chunk
+            .get(9..12)
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+
+        let head_len = chunk
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|pos| pos + 4)
+            .unwrap_or(chunk.len());
+        self.size += chunk.len() - head_len;
+    }
+}
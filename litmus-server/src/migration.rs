@@ -0,0 +1,72 @@
+use std::convert::TryInto;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::PyResult;
+
+/// The version of the snapshot format, bumped on any incompatible change.
+const SNAPSHOT_VERSION: u8 = 2;
+
+/// The size of the fixed header: version, keep_alive, requests received
+/// and pending length.
+const HEADER_SIZE: usize = 1 + 1 + 4 + 4;
+
+/// The essential state of a connection taken at a request boundary so
+/// that it can be migrated to another process along with its socket.
+pub struct ConnectionSnapshot {
+    /// If the connection should be kept alive after the next response.
+    pub keep_alive: bool,
+
+    /// The number of requests received on the connection so far, so the
+    /// max requests per connection still applies once migrated.
+    pub requests_received: usize,
+
+    /// Any data read from the socket that is yet to be handled,
+    /// e.g. pipelined requests.
+    pub pending: Vec<u8>,
+}
+
+impl ConnectionSnapshot {
+    /// Encodes the snapshot into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE + self.pending.len());
+        out.push(SNAPSHOT_VERSION);
+        out.push(self.keep_alive as u8);
+        out.extend_from_slice(&(self.requests_received as u32).to_be_bytes());
+        out.extend_from_slice(&(self.pending.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.pending);
+        out
+    }
+
+    /// Decodes a snapshot previously produced by `encode`.
+    ///
+    /// This raises a `ValueError` if the snapshot is malformed or was
+    /// produced by an incompatible version.
+    pub fn decode(data: &[u8]) -> PyResult<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(PyValueError::new_err("snapshot is truncated"));
+        }
+
+        if data[0] != SNAPSHOT_VERSION {
+            return Err(PyValueError::new_err(format!(
+                "unsupported snapshot version {}",
+                data[0]
+            )));
+        }
+
+        let keep_alive = data[1] != 0;
+        let requests_received =
+            u32::from_be_bytes(data[2..6].try_into().unwrap()) as usize;
+        let len = u32::from_be_bytes(data[6..HEADER_SIZE].try_into().unwrap()) as usize;
+
+        let pending = &data[HEADER_SIZE..];
+        if pending.len() != len {
+            return Err(PyValueError::new_err("snapshot is truncated"));
+        }
+
+        Ok(Self {
+            keep_alive,
+            requests_received,
+            pending: pending.to_vec(),
+        })
+    }
+}
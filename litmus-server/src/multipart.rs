@@ -0,0 +1,618 @@
+use bytes::{Buf, BytesMut};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::lsgi::percent_decode;
+
+/// The max size of the headers of a single part.
+const MAX_HEADERS_SIZE: usize = 16 * 1024;
+
+/// The max number of headers of a single part.
+const MAX_HEADERS_COUNT: usize = 32;
+
+/// The max length of a boundary, see RFC 2046 section 5.1.1.
+const MAX_BOUNDARY_LEN: usize = 70;
+
+/// Where the parser is within the body.
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Skipping anything ahead of the first boundary.
+    Preamble,
+
+    /// Just after a boundary, either the last boundary or the start of
+    /// the next part follows.
+    Boundary,
+
+    /// Reading the headers of a part.
+    Headers,
+
+    /// Reading the content of a part.
+    Content,
+
+    /// The last boundary has been read, anything after it is ignored.
+    Done,
+}
+
+/// A part of a `multipart/form-data` body.
+#[pyclass(name = "MultipartPart")]
+pub struct Part {
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl Part {
+    fn new(headers: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+        let mut part = Self {
+            headers,
+            name: None,
+            filename: None,
+            content_type: None,
+        };
+
+        for (name, value) in part.headers.iter() {
+            let value = String::from_utf8_lossy(value);
+            match name.as_slice() {
+                b"content-disposition" => {
+                    let (name, filename) = parse_disposition(&value);
+                    part.name = name;
+                    part.filename = filename;
+                },
+                b"content-type" => part.content_type = Some(value.trim().to_string()),
+                _ => {},
+            }
+        }
+
+        part
+    }
+}
+
+#[pymethods]
+impl Part {
+    /// The `(name, value)` headers of the part, the names are lowercased.
+    #[getter]
+    fn headers(&self, py: Python) -> Vec<(Py<PyBytes>, Py<PyBytes>)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    Py::from(PyBytes::new(py, name)),
+                    Py::from(PyBytes::new(py, value)),
+                )
+            })
+            .collect()
+    }
+
+    /// The name of the form field from the `Content-Disposition` header.
+    #[getter]
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The name of the uploaded file from the `Content-Disposition`
+    /// header, `filename*` is preferred over `filename` if both are given.
+    #[getter]
+    fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The `Content-Type` of the part if given, RFC 7578 defaults it to
+    /// `text/plain`.
+    #[getter]
+    fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+/// A streaming parser of `multipart/form-data` request bodies.
+///
+/// The body is fed to the parser as it's received, each call returning
+/// what could be parsed from it so far, a `MultipartPart` as each part
+/// starts followed by chunks of its content as `bytes`. Only a partial
+/// boundary or headers are held onto between calls, the content itself is
+/// never buffered.
+///
+/// Args:
+///     content_type:
+///         The `Content-Type` header of the request giving the boundary.
+///     max_parts:
+///         The max number of parts in the body.
+///     max_part_size:
+///         The max size of the content of a single part in bytes.
+///
+/// Raises:
+///     ValueError:
+///         If the content type isn't `multipart/form-data` with a valid
+///         boundary, or while feeding the body if it's malformed or
+///         exceeds the limits.
+#[pyclass(name = "MultipartParser")]
+pub struct MultipartParser {
+    /// The boundary delimiter preceded by a CRLF.
+    delimiter: Vec<u8>,
+
+    state: State,
+    buffer: BytesMut,
+
+    max_parts: Option<usize>,
+    max_part_size: Option<usize>,
+
+    parts: usize,
+    part_size: usize,
+}
+
+impl MultipartParser {
+    /// The delimiter without the CRLF, the first boundary may start the
+    /// body without one.
+    fn dash_boundary(&self) -> &[u8] {
+        &self.delimiter[2..]
+    }
+
+    /// Parses as much of the buffer as possible, pushing anything parsed
+    /// onto the given list.
+    fn parse(&mut self, py: Python, out: &mut Vec<PyObject>) -> PyResult<()> {
+        loop {
+            match self.state {
+                State::Preamble => {
+                    let dash_boundary_len = self.dash_boundary().len();
+                    let found = if self.buffer.starts_with(self.dash_boundary()) {
+                        Some(0)
+                    } else {
+                        find(&self.buffer, &self.delimiter).map(|i| i + 2)
+                    };
+
+                    match found {
+                        Some(i) => {
+                            self.buffer.advance(i + dash_boundary_len);
+                            self.state = State::Boundary;
+                        },
+                        None => {
+                            // Anything that can't be the start of the
+                            // delimiter is discarded.
+                            let keep = self.delimiter.len() - 1;
+                            let len = self.buffer.len().saturating_sub(keep);
+                            self.buffer.advance(len);
+                            return Ok(());
+                        },
+                    }
+                },
+                State::Boundary => {
+                    if self.buffer.len() < 2 {
+                        return Ok(());
+                    }
+
+                    if self.buffer.starts_with(b"--") {
+                        self.buffer.clear();
+                        self.state = State::Done;
+                        return Ok(());
+                    }
+
+                    // Transport padding may follow the boundary.
+                    let padding = self
+                        .buffer
+                        .iter()
+                        .take_while(|&&b| (b == b' ') | (b == b'\t'))
+                        .count();
+                    if self.buffer.len() < padding + 2 {
+                        return Ok(());
+                    }
+
+                    if &self.buffer[padding..padding + 2] != b"\r\n" {
+                        return Err(PyValueError::new_err(
+                            "invalid multipart body: malformed boundary",
+                        ));
+                    }
+
+                    self.buffer.advance(padding + 2);
+                    self.state = State::Headers;
+                },
+                State::Headers => {
+                    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS_COUNT];
+                    let (len, part) =
+                        match httparse::parse_headers(&self.buffer, &mut headers) {
+                            Ok(httparse::Status::Complete((len, headers))) => {
+                                let headers = headers
+                                    .iter()
+                                    .map(|h| {
+                                        (
+                                            h.name.to_ascii_lowercase().into_bytes(),
+                                            h.value.to_vec(),
+                                        )
+                                    })
+                                    .collect();
+                                (len, Part::new(headers))
+                            },
+                            Ok(httparse::Status::Partial) => {
+                                if self.buffer.len() > MAX_HEADERS_SIZE {
+                                    return Err(PyValueError::new_err(
+                                        "invalid multipart body: part headers too large",
+                                    ));
+                                }
+
+                                return Ok(());
+                            },
+                            Err(_) => {
+                                return Err(PyValueError::new_err(
+                                    "invalid multipart body: malformed part headers",
+                                ))
+                            },
+                        };
+
+                    self.parts += 1;
+                    if self.max_parts.is_some_and(|max| self.parts > max) {
+                        return Err(PyValueError::new_err(
+                            "multipart body has too many parts",
+                        ));
+                    }
+
+                    self.buffer.advance(len);
+                    self.part_size = 0;
+                    self.state = State::Content;
+                    out.push(Py::new(py, part)?.into_py(py));
+                },
+                State::Content => {
+                    let (len, end) = match find(&self.buffer, &self.delimiter) {
+                        Some(i) => (i, true),
+                        None => {
+                            // The end may hold the start of the delimiter.
+                            let keep = self.delimiter.len() - 1;
+                            (self.buffer.len().saturating_sub(keep), false)
+                        },
+                    };
+
+                    self.part_size += len;
+                    if self.max_part_size.is_some_and(|max| self.part_size > max) {
+                        return Err(PyValueError::new_err(
+                            "multipart part is larger than the max part size",
+                        ));
+                    }
+
+                    if len > 0 {
+                        let content = self.buffer.split_to(len);
+                        out.push(PyBytes::new(py, &content).into_py(py));
+                    }
+
+                    if !end {
+                        return Ok(());
+                    }
+
+                    self.buffer.advance(self.delimiter.len());
+                    self.state = State::Boundary;
+                },
+                State::Done => {
+                    self.buffer.clear();
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl MultipartParser {
+    #[new]
+    #[args(max_parts = "None", max_part_size = "None")]
+    fn new(
+        content_type: &str,
+        max_parts: Option<usize>,
+        max_part_size: Option<usize>,
+    ) -> PyResult<Self> {
+        let boundary = parse_boundary(content_type).ok_or_else(|| {
+            PyValueError::new_err("expected multipart/form-data with a valid boundary")
+        })?;
+
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Ok(Self {
+            delimiter,
+            state: State::Preamble,
+            buffer: BytesMut::new(),
+            max_parts,
+            max_part_size,
+            parts: 0,
+            part_size: 0,
+        })
+    }
+
+    /// Feeds the next chunk of the body to the parser.
+    ///
+    /// Returns:
+    ///     A list of what could be parsed, a `MultipartPart` as each part
+    ///     starts followed by any of its content as `bytes`.
+    fn feed(&mut self, py: Python, data: &[u8]) -> PyResult<Vec<PyObject>> {
+        let mut out = Vec::new();
+        if self.state == State::Done {
+            return Ok(out);
+        }
+
+        self.buffer.extend_from_slice(data);
+        self.parse(py, &mut out)?;
+        Ok(out)
+    }
+
+    /// Checks the body was complete once all of it has been fed.
+    ///
+    /// Raises:
+    ///     ValueError:
+    ///         If the body ended before the last boundary.
+    fn finish(&self) -> PyResult<()> {
+        if self.state != State::Done {
+            return Err(PyValueError::new_err(
+                "invalid multipart body: body ended before the last boundary",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// If the last boundary has been parsed, anything fed after it is
+    /// ignored.
+    #[getter]
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+}
+
+/// Gets the boundary of a `multipart/form-data` content type.
+fn parse_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    let boundary = params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("boundary") {
+            Some(unquote(value.trim()))
+        } else {
+            None
+        }
+    })?;
+
+    let valid = !boundary.is_empty()
+        & (boundary.len() <= MAX_BOUNDARY_LEN)
+        & !boundary.ends_with(' ')
+        & boundary.bytes().all(|b| b.is_ascii_graphic() | (b == b' '));
+    if valid {
+        Some(boundary)
+    } else {
+        None
+    }
+}
+
+/// Gets the field name and filename given by a `Content-Disposition`
+/// header.
+fn parse_disposition(value: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+    let mut filename_ext = None;
+
+    for param in split_params(value).into_iter().skip(1) {
+        let (key, value) = match param.split_once('=') {
+            Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+
+        match key.as_str() {
+            "name" => name = Some(unquote(value)),
+            "filename" => filename = Some(unquote(value)),
+            // RFC 5987, e.g. `UTF-8''na%C3%AFve.txt`.
+            "filename*" => {
+                filename_ext = value.splitn(3, '\'').nth(2).map(percent_decode);
+            },
+            _ => {},
+        }
+    }
+
+    (name, filename_ext.or(filename))
+}
+
+/// Splits the parameters of a header value on semicolons outside of
+/// quoted strings.
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+
+    params.push(&value[start..]);
+    params
+}
+
+/// Removes the quotes of a quoted string along with any escapes within
+/// it, other values are left as they are.
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return value.to_string(),
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut escaped = false;
+    for c in inner.chars() {
+        if !escaped & (c == '\\') {
+            escaped = true;
+            continue;
+        }
+
+        escaped = false;
+        unquoted.push(c);
+    }
+
+    unquoted
+}
+
+/// Finds the first occurrence of the needle in the haystack.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let first = *needle.first()?;
+    let mut offset = 0;
+
+    while haystack.len() - offset >= needle.len() {
+        let i = haystack[offset..=haystack.len() - needle.len()]
+            .iter()
+            .position(|&b| b == first)?;
+
+        let start = offset + i;
+        if haystack[start..].starts_with(needle) {
+            return Some(start);
+        }
+        offset = start + 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"b0und\"";
+
+    const BODY: &[u8] = b"preamble\r\n--b0und\r\n\
+        content-disposition: form-data; name=\"text\"\r\n\r\n\
+        hello\r\n--b0und  \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+        content-type: text/plain\r\n\r\n\
+        line one\r\nline two\r\n--b0und--\r\nepilogue";
+
+    /// The name, filename and content of each part parsed.
+    type Parsed = Vec<(Option<String>, Option<String>, Vec<u8>)>;
+
+    fn new_parser(
+        max_parts: Option<usize>,
+        max_part_size: Option<usize>,
+    ) -> MultipartParser {
+        pyo3::prepare_freethreaded_python();
+        MultipartParser::new(CONTENT_TYPE, max_parts, max_part_size).unwrap()
+    }
+
+    /// Feeds each chunk to the parser collecting the parts parsed.
+    fn feed(parser: &mut MultipartParser, chunks: &[&[u8]]) -> PyResult<Parsed> {
+        Python::with_gil(|py| {
+            let mut parsed: Parsed = Vec::new();
+            for chunk in chunks {
+                for item in parser.feed(py, chunk)? {
+                    if let Ok(part) = item.extract::<PyRef<Part>>(py) {
+                        parsed.push((
+                            part.name.clone(),
+                            part.filename.clone(),
+                            Vec::new(),
+                        ));
+                    } else {
+                        let content: &[u8] = item.extract(py)?;
+                        parsed.last_mut().unwrap().2.extend_from_slice(content);
+                    }
+                }
+            }
+            Ok(parsed)
+        })
+    }
+
+    fn expected() -> Parsed {
+        vec![
+            (Some("text".into()), None, b"hello".to_vec()),
+            (
+                Some("file".into()),
+                Some("a.txt".into()),
+                b"line one\r\nline two".to_vec(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn preamble_and_epilogue_are_ignored() {
+        let mut parser = new_parser(None, None);
+        assert_eq!(feed(&mut parser, &[BODY]).unwrap(), expected());
+        assert!(parser.is_done());
+        parser.finish().unwrap();
+    }
+
+    #[test]
+    fn boundary_split_across_feeds() {
+        for split in 1..BODY.len() {
+            let mut parser = new_parser(None, None);
+            let (first, second) = BODY.split_at(split);
+            assert_eq!(feed(&mut parser, &[first, second]).unwrap(), expected());
+            parser.finish().unwrap();
+        }
+
+        let mut parser = new_parser(None, None);
+        let bytes: Vec<&[u8]> = BODY.chunks(1).collect();
+        assert_eq!(feed(&mut parser, &bytes).unwrap(), expected());
+    }
+
+    #[test]
+    fn body_ending_early_is_an_error() {
+        let mut parser = new_parser(None, None);
+        feed(&mut parser, &[&BODY[..BODY.len() - 20]]).unwrap();
+        assert!(!parser.is_done());
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    fn extended_filename_is_preferred() {
+        let value = "form-data; name=\"f\"; filename=\"a.txt\"; \
+            filename*=UTF-8''na%C3%AFve%20file.txt";
+        assert_eq!(
+            parse_disposition(value),
+            (Some("f".into()), Some("naïve file.txt".into()))
+        );
+    }
+
+    #[test]
+    fn quoted_params_keep_semicolons() {
+        let value = r#"form-data; name="a;b"; filename="c\"d.txt""#;
+        assert_eq!(
+            parse_disposition(value),
+            (Some("a;b".into()), Some("c\"d.txt".into()))
+        );
+    }
+
+    #[test]
+    fn too_many_parts_is_an_error() {
+        let mut parser = new_parser(Some(2), None);
+        assert_eq!(feed(&mut parser, &[BODY]).unwrap(), expected());
+
+        let mut parser = new_parser(Some(1), None);
+        assert!(feed(&mut parser, &[BODY]).is_err());
+    }
+
+    #[test]
+    fn part_over_the_max_size_is_an_error() {
+        let mut parser = new_parser(None, Some(18));
+        assert_eq!(feed(&mut parser, &[BODY]).unwrap(), expected());
+
+        let mut parser = new_parser(None, Some(17));
+        assert!(feed(&mut parser, &[BODY]).is_err());
+    }
+
+    #[test]
+    fn invalid_boundaries_are_rejected() {
+        assert_eq!(
+            parse_boundary("multipart/form-data; boundary=abc"),
+            Some("abc".into())
+        );
+        assert_eq!(parse_boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(parse_boundary("multipart/form-data; boundary=\"\""), None);
+        assert_eq!(parse_boundary("multipart/form-data; boundary=\"a \""), None);
+
+        let long = format!("multipart/form-data; boundary={}", "a".repeat(71));
+        assert_eq!(parse_boundary(&long), None);
+    }
+}
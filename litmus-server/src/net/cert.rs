@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_VERSION: u8 = 0xa0;
+
+const TAG_UTF8_STRING: u8 = 0x0c;
+const TAG_PRINTABLE_STRING: u8 = 0x13;
+const TAG_T61_STRING: u8 = 0x14;
+const TAG_IA5_STRING: u8 = 0x16;
+const TAG_BMP_STRING: u8 = 0x1e;
+
+/// The short names of the common subject attributes, others are given
+/// as their dotted OID.
+const ATTRIBUTE_NAMES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x05], "serialNumber"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "STREET"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (
+        &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01],
+        "emailAddress",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01],
+        "UID",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19],
+        "DC",
+    ),
+];
+
+/// The certificate a client verified itself with during the TLS handshake.
+#[derive(Clone)]
+pub struct PeerCertificate {
+    /// The DER encoded certificate.
+    pub der: Vec<u8>,
+
+    /// The `(name, value)` attributes of the certificate's subject in the
+    /// order they're encoded, e.g. `("CN", "alice")`.
+    pub subject: Vec<(String, String)>,
+}
+
+impl PeerCertificate {
+    /// Reads the subject of the DER encoded certificate, the certificate
+    /// has already been verified so a subject that fails to parse is left
+    /// empty rather than rejecting it.
+    pub fn from_der(der: &[u8]) -> Self {
+        Self {
+            der: der.to_vec(),
+            subject: parse_subject(der).unwrap_or_default(),
+        }
+    }
+}
+
+fn parse_subject(der: &[u8]) -> Option<Vec<(String, String)>> {
+    let cert = Der(der).expect(TAG_SEQUENCE)?;
+    let mut tbs = Der(Der(cert).expect(TAG_SEQUENCE)?);
+
+    if tbs.peek() == Some(TAG_VERSION) {
+        tbs.read()?;
+    }
+
+    tbs.expect(TAG_INTEGER)?; // serialNumber
+    tbs.expect(TAG_SEQUENCE)?; // signature
+    tbs.expect(TAG_SEQUENCE)?; // issuer
+    tbs.expect(TAG_SEQUENCE)?; // validity
+    let mut names = Der(tbs.expect(TAG_SEQUENCE)?);
+
+    let mut subject = Vec::new();
+    while !names.is_empty() {
+        let mut set = Der(names.expect(TAG_SET)?);
+        while !set.is_empty() {
+            let mut attribute = Der(set.expect(TAG_SEQUENCE)?);
+            let oid = attribute.expect(TAG_OID)?;
+            let (tag, value) = attribute.read()?;
+            subject.push((attribute_name(oid), decode_string(tag, value)));
+        }
+    }
+
+    Some(subject)
+}
+
+fn attribute_name(oid: &[u8]) -> String {
+    if let Some((_, name)) = ATTRIBUTE_NAMES.iter().find(|(known, _)| *known == oid) {
+        return name.to_string();
+    }
+
+    let mut name = String::new();
+    let mut arc: u64 = 0;
+    for byte in oid {
+        arc = (arc << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 != 0 {
+            continue;
+        }
+
+        // The first two arcs are encoded together.
+        if name.is_empty() {
+            let first = (arc / 40).min(2);
+            let _ = write!(name, "{}.{}", first, arc - first * 40);
+        } else {
+            let _ = write!(name, ".{}", arc);
+        }
+        arc = 0;
+    }
+
+    name
+}
+
+/// Decodes the directory string, string types that aren't known are
+/// given as hex.
+fn decode_string(tag: u8, value: &[u8]) -> String {
+    match tag {
+        TAG_UTF8_STRING | TAG_PRINTABLE_STRING | TAG_IA5_STRING => {
+            String::from_utf8_lossy(value).into_owned()
+        },
+        TAG_T61_STRING => value.iter().map(|&b| b as char).collect(),
+        TAG_BMP_STRING => {
+            let units = value
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        },
+        _ => value.iter().fold(String::from("#"), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        }),
+    }
+}
+
+/// A reader over DER encoded values.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    /// Reads the next value returning its tag and contents.
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+
+        let len = if first & 0x80 == 0 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if (n == 0) | (n > 4) | (rest.len() < n) {
+                return None;
+            }
+
+            let len = rest[..n].iter().fold(0, |len, &b| (len << 8) | b as usize);
+            rest = &rest[n..];
+            len
+        };
+
+        if rest.len() < len {
+            return None;
+        }
+
+        let (value, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some((tag, value))
+    }
+
+    /// Reads the next value if it has the given tag.
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (found, value) if found == tag => Some(value),
+            _ => None,
+        }
+    }
+}
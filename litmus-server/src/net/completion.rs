@@ -0,0 +1,218 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem::ManuallyDrop;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(windows)]
+use std::os::windows::io::FromRawSocket;
+use std::sync::Arc;
+
+use bytes::{Buf, BytesMut};
+use pyo3::prelude::*;
+
+use crate::event_loop::SocketFd;
+
+/// The max number of bytes queued to be sent before writes block, at
+/// which point the connection waits for the event loop to take them.
+const MAX_OUTBOUND: usize = 256 * 1024;
+
+/// A socket whose I/O is performed by a completion based event loop, e.g.
+/// asyncio's proactor using overlapped I/O on Windows.
+///
+/// The socket itself is owned by the event loop, data it receives is fed
+/// into the inbound buffer to be read by the connection and anything the
+/// connection writes is queued in the outbound buffer until the event loop
+/// takes it to be sent.
+pub struct CompletionSocket {
+    fd: SocketFd,
+    inbound: BytesMut,
+    outbound: BytesMut,
+
+    /// If the event loop has received the EOF.
+    eof: bool,
+
+    /// If the connection has shut the socket down, once everything queued
+    /// has been sent the event loop should close the socket.
+    shutdown: bool,
+
+    /// Invoked once the socket is shut down so the event loop can finish
+    /// sending and close it.
+    on_shutdown: ShutdownHook,
+}
+
+/// Tells whatever owns a completion socket that it has been shut down.
+pub type ShutdownHook = Arc<dyn Fn() + Send + Sync>;
+
+impl CompletionSocket {
+    pub fn new(fd: SocketFd, on_shutdown: PyObject) -> Self {
+        let on_shutdown = move || {
+            Python::with_gil(|py| {
+                if let Err(e) = on_shutdown.call0(py) {
+                    error!(
+                        "completion socket shutdown callback raised an exception: {}",
+                        e
+                    );
+                }
+            })
+        };
+
+        Self::with_hook(fd, Arc::new(on_shutdown))
+    }
+
+    /// Creates a socket whose owner is told it has been shut down by the
+    /// given hook rather than a Python callback.
+    pub fn with_hook(fd: SocketFd, on_shutdown: ShutdownHook) -> Self {
+        Self {
+            fd,
+            inbound: BytesMut::new(),
+            outbound: BytesMut::new(),
+            eof: false,
+            shutdown: false,
+            on_shutdown,
+        }
+    }
+
+    /// Turns away the connection with the given file descriptor by writing
+    /// the response straight to the socket, the socket is left open for
+    /// the event loop to close.
+    ///
+    /// This is best effort, the response is dropped if it can't be written
+    /// straight away.
+    pub fn reject(fd: SocketFd, response: &[u8]) {
+        let mut stream = borrow_stream(fd);
+        let _ = stream.write(response);
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+
+    /// The file descriptor of the socket owned by the event loop.
+    pub fn fd(&self) -> SocketFd {
+        self.fd
+    }
+
+    /// Queues data received by the event loop to be read.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.inbound.extend_from_slice(data);
+    }
+
+    /// Marks the EOF as received, reads return `0` once the inbound
+    /// buffer is empty.
+    pub fn feed_eof(&mut self) {
+        self.eof = true;
+    }
+
+    /// If a read would not block, the equivalent of a readiness based
+    /// event loop reporting the socket as readable.
+    pub fn is_readable(&self) -> bool {
+        !self.inbound.is_empty() | self.eof
+    }
+
+    /// Takes the data queued to be sent, `None` once the socket has been
+    /// shut down and everything queued has been taken.
+    pub fn take_outbound(&mut self) -> Option<BytesMut> {
+        if self.shutdown & self.outbound.is_empty() {
+            return None;
+        }
+
+        Some(self.outbound.split())
+    }
+
+    pub fn shutdown(&mut self, _how: Shutdown) -> io::Result<()> {
+        if self.shutdown {
+            return Ok(());
+        }
+        self.shutdown = true;
+
+        (self.on_shutdown)();
+        Ok(())
+    }
+
+    /// Reads data from the inbound buffer without removing it.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.inbound.is_empty() {
+            return self.empty_read();
+        }
+
+        let len = buf.len().min(self.inbound.len());
+        buf[..len].copy_from_slice(&self.inbound[..len]);
+        Ok(len)
+    }
+
+    fn empty_read(&self) -> io::Result<usize> {
+        if self.eof {
+            Ok(0)
+        } else {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+impl Read for CompletionSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.peek(buf)?;
+        self.inbound.advance(len);
+        Ok(len)
+    }
+}
+
+impl Write for CompletionSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.shutdown {
+            return Err(ErrorKind::ConnectionAborted.into());
+        }
+
+        let available = MAX_OUTBOUND.saturating_sub(self.outbound.len());
+        if available == 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let len = buf.len().min(available);
+        self.outbound.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let mut written = 0;
+        for buf in bufs {
+            match self.write(buf) {
+                Ok(n) => {
+                    written += n;
+                    if n < buf.len() {
+                        break;
+                    }
+                },
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Borrows the socket with the given file descriptor as a tcp stream
+/// without taking ownership of it.
+pub(super) fn borrow_stream(fd: SocketFd) -> ManuallyDrop<TcpStream> {
+    #[cfg(unix)]
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    #[cfg(windows)]
+    let stream = unsafe { TcpStream::from_raw_socket(fd) };
+
+    ManuallyDrop::new(stream)
+}
+
+/// Gets the client's and server's addresses of the socket with the given
+/// file descriptor, these are unspecified if the socket isn't tcp.
+pub fn addresses(fd: SocketFd) -> (SocketAddr, SocketAddr) {
+    let stream = borrow_stream(fd);
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+
+    (
+        stream.peer_addr().unwrap_or(unspecified),
+        stream.local_addr().unwrap_or(unspecified),
+    )
+}
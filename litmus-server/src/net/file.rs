@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+/// The max amount of a file read per write when falling back to reading
+/// the file and writing it to the socket.
+const FALLBACK_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A region of a file to be written to a socket.
+pub struct FileBody {
+    file: File,
+
+    /// The offset in the file to write from next.
+    offset: u64,
+
+    /// The amount of bytes of the region left to write.
+    remaining: u64,
+}
+
+impl FileBody {
+    /// Creates a new body writing `count` bytes of the file starting at
+    /// `offset`, or up to the end of the file if no count is given.
+    pub fn new(file: File, offset: u64, count: Option<u64>) -> io::Result<Self> {
+        let len = file.metadata()?.len().saturating_sub(offset);
+        let remaining = count.map(|c| c.min(len)).unwrap_or(len);
+
+        Ok(Self {
+            file,
+            offset,
+            remaining,
+        })
+    }
+
+    /// The amount of bytes of the region left to write.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Advances the region past the given amount of written bytes.
+    pub(crate) fn advance(&mut self, amount: usize) {
+        self.offset += amount as u64;
+        self.remaining -= amount as u64;
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn fd(&self) -> i32 {
+        use std::os::unix::io::AsRawFd;
+
+        self.file.as_raw_fd()
+    }
+
+    /// The current offset of the region in the file.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads the next chunk of the region to be written.
+    pub(crate) fn read_chunk(&self) -> io::Result<Vec<u8>> {
+        let len = (self.remaining as usize).min(FALLBACK_CHUNK_SIZE);
+        let mut buffer = vec![0; len];
+
+        #[cfg(unix)]
+        let n = self.file.read_at(&mut buffer, self.offset)?;
+
+        #[cfg(windows)]
+        let n = self.file.seek_read(&mut buffer, self.offset)?;
+
+        buffer.truncate(n);
+        Ok(buffer)
+    }
+
+    /// Reads the rest of the region into memory, used when the body has
+    /// to be transformed before it's written, e.g. compressed.
+    pub(crate) fn read_to_end(mut self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.remaining as usize);
+        while self.remaining > 0 {
+            let chunk = self.read_chunk()?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            self.advance(chunk.len());
+            out.extend(chunk);
+        }
+
+        Ok(out)
+    }
+}
@@ -1,13 +1,23 @@
-use std::io::ErrorKind;
+#[cfg(unix)]
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
 use std::net::{SocketAddr, TcpListener};
 #[cfg(unix)]
-use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
 #[cfg(windows)]
-use std::os::windows::io::AsRawSocket;
+use std::os::windows::io::{AsRawSocket, FromRawSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use pyo3::exceptions::PyValueError;
 use pyo3::{PyErr, PyResult};
 
 use crate::net::StreamHandle;
+use crate::plugin::ProtocolFactory;
 
 /// Represents the state of the socket that is accepting connections.
 pub enum Status<T> {
@@ -20,57 +30,469 @@ pub enum Status<T> {
     ShouldPause,
 }
 
-/// A non-blocking tcp listener, this is just a wrapper over the
-/// `std::net::TcpListener` just with non_blocking set to true and
+/// The prefix of a bind address signalling the rest is the path of a
+/// unix domain socket, e.g. `unix:/run/litmus.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// The first file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// The underlying listening socket.
+enum Listener {
+    Tcp(TcpListener),
+
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A non-blocking tcp or unix domain socket listener, this is just a
+/// wrapper over the std listeners just with non_blocking set to true and
 /// a custom `net::NoneBlockingListener.accept()` method implemented for
 /// use with Python.
 pub struct NoneBlockingListener {
-    /// The base TcpListener that is held internally, this should be
+    /// The base listener that is held internally, this should be
     /// set as non-blocking.
-    listener: TcpListener,
+    listener: Listener,
 
+    /// The address the listener is bound to, unspecified for unix domain
+    /// sockets.
     pub addr: SocketAddr,
+
+    /// The path of the unix domain socket if any.
+    path: Option<PathBuf>,
+
+    /// If the socket file should be removed once the listener is dropped,
+    /// only set if this listener created it.
+    cleanup: bool,
+
+    /// The custom protocol served on connections accepted by the listener
+    /// in place of HTTP.
+    protocol: Option<Arc<dyn ProtocolFactory>>,
 }
 
 impl NoneBlockingListener {
     /// Attempts to bind to a given addresses and returns `Self`
-    pub fn bind(addr: &str) -> PyResult<Self> {
-        let listener = TcpListener::bind(addr)?;
+    ///
+    /// If `reuse_port` is set the socket is bound with `SO_REUSEPORT`
+    /// allowing several workers to bind to the same address with the OS
+    /// balancing connections between them.
+    ///
+    /// If `v6_only` is set an IPv6 address only accepts IPv6 connections,
+    /// allowing an IPv4 listener to be bound to the same port alongside it.
+    ///
+    /// Addresses starting with `unix:` bind a unix domain socket to the
+    /// path following it, with the socket file's permissions set to `mode`
+    /// if given.
+    pub fn bind(
+        addr: &str,
+        reuse_port: bool,
+        v6_only: bool,
+        mode: Option<u32>,
+    ) -> PyResult<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            if reuse_port {
+                return Err(PyValueError::new_err(
+                    "SO_REUSEPORT cannot be used with unix domain sockets",
+                ));
+            }
+
+            return Self::bind_unix(PathBuf::from(path), mode);
+        }
+
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| PyValueError::new_err(format!("invalid address {:?}", addr)))?;
+
+        let v6_only = v6_only & addr.is_ipv6();
+        let listener = if reuse_port | v6_only {
+            bind_with(addr, reuse_port, v6_only)?
+        } else {
+            TcpListener::bind(addr)?
+        };
         listener.set_nonblocking(true).expect("set non-blocking");
 
         Ok(Self {
-            listener,
-            addr: addr.parse().expect("invalid addr"),
+            listener: Listener::Tcp(listener),
+            addr,
+            path: None,
+            cleanup: false,
+            protocol: None,
+        })
+    }
+
+    /// Binds a unix domain socket to the given path, replacing any stale
+    /// socket file left behind by a previous server.
+    #[cfg(unix)]
+    fn bind_unix(path: PathBuf, mode: Option<u32>) -> PyResult<Self> {
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true).expect("set non-blocking");
+
+        if let Some(mode) = mode {
+            fs::set_permissions(&path, Permissions::from_mode(mode))?;
+        }
+
+        Ok(Self {
+            listener: Listener::Unix(listener),
+            addr: unspecified_addr(),
+            path: Some(path),
+            cleanup: true,
+            protocol: None,
+        })
+    }
+
+    /// Creates a new listener adopting the already bound and listening
+    /// socket with the given file descriptor, e.g. one inherited from a
+    /// parent process.
+    ///
+    /// # Safety
+    /// The file descriptor must be an open, listening tcp socket which is
+    /// not owned by anything else.
+    #[cfg(unix)]
+    pub unsafe fn from_fd(fd: i32) -> PyResult<Self> {
+        if !is_unix_socket(fd)? {
+            return Self::adopt(TcpListener::from_raw_fd(fd));
+        }
+
+        let listener = UnixListener::from_raw_fd(fd);
+        listener.set_nonblocking(true)?;
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(|p| p.to_path_buf());
+
+        Ok(Self {
+            listener: Listener::Unix(listener),
+            addr: unspecified_addr(),
+            path,
+            cleanup: false,
+            protocol: None,
+        })
+    }
+
+    /// Creates a new listener adopting the already bound and listening
+    /// socket with the given file descriptor, e.g. one inherited from a
+    /// parent process.
+    ///
+    /// # Safety
+    /// The file descriptor must be an open, listening tcp socket which is
+    /// not owned by anything else.
+    #[cfg(windows)]
+    pub unsafe fn from_fd(fd: u64) -> PyResult<Self> {
+        Self::adopt(TcpListener::from_raw_socket(fd))
+    }
+
+    fn adopt(listener: TcpListener) -> PyResult<Self> {
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+
+        Ok(Self {
+            listener: Listener::Tcp(listener),
+            addr,
+            path: None,
+            cleanup: false,
+            protocol: None,
         })
     }
 
+    /// Takes the listeners passed to this process by systemd socket
+    /// activation, as described by the `LISTEN_PID` and `LISTEN_FDS`
+    /// environment variables, this is empty if none were passed.
+    ///
+    /// The variables are removed so the listeners aren't taken twice or
+    /// mistaken as their own by child processes.
+    #[cfg(unix)]
+    pub fn systemd_listeners() -> PyResult<Vec<Self>> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        // The sockets are meant for another process, e.g. our parent.
+        let (pid, count) = match (pid, count) {
+            (Some(pid), Some(count)) => (pid, count),
+            _ => return Ok(Vec::new()),
+        };
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(Vec::new());
+        }
+
+        let count: i32 = count.parse().map_err(|_| {
+            PyValueError::new_err(format!("invalid LISTEN_FDS {:?}", count))
+        })?;
+
+        let mut listeners = Vec::new();
+        for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+            if !is_listening_stream(fd)? {
+                return Err(PyValueError::new_err(format!(
+                    "file descriptor {} passed by systemd is not a listening stream socket",
+                    fd
+                )));
+            }
+
+            // systemd passes the sockets inheritable, they're ours now.
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            listeners.push(unsafe { Self::from_fd(fd)? });
+        }
+
+        Ok(listeners)
+    }
+
+    /// Systemd socket activation is only supported on unix, there are never
+    /// any listeners to take elsewhere.
+    #[cfg(windows)]
+    pub fn systemd_listeners() -> PyResult<Vec<Self>> {
+        Ok(Vec::new())
+    }
+
+    /// Serves the given custom protocol on connections accepted by the
+    /// listener in place of HTTP.
+    pub fn with_protocol(mut self, protocol: Arc<dyn ProtocolFactory>) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// The custom protocol served on connections accepted by the listener
+    /// if any.
+    pub fn protocol(&self) -> Option<&Arc<dyn ProtocolFactory>> {
+        self.protocol.as_ref()
+    }
+
+    /// A human readable description of where the listener is bound.
+    pub fn location(&self) -> String {
+        match self.path.as_ref() {
+            Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+            None => format!("http://{}", self.addr),
+        }
+    }
+
     /// Accepts a single client from the socket without blocking, returning a
     /// `net::Status` describing if the fd listener should be paused or the
     /// client itself if has been accepted successfully.
     pub fn accept(&self) -> PyResult<Status<StreamHandle>> {
-        let (stream, addr) = match self.listener.accept() {
-            Ok(pair) => pair,
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                return Ok(Status::ShouldPause)
-            },
-            Err(e) => return Err(PyErr::from(e)),
+        let result = match &self.listener {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, addr)| {
+                stream.set_nonblocking(true).expect("set non-blocking");
+                StreamHandle::new(stream, addr, self.addr)
+            }),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| {
+                stream.set_nonblocking(true).expect("set non-blocking");
+                StreamHandle::new_unix(stream, self.addr)
+            }),
         };
 
-        stream.set_nonblocking(true).expect("set non-blocking");
+        match result {
+            Ok(handle) => {
+                let handle = handle.with_protocol(self.protocol.clone());
+                Ok(Status::Successful(handle))
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Status::ShouldPause),
+            Err(e) => Err(PyErr::from(e)),
+        }
+    }
 
-        let handle = StreamHandle::new(stream, addr, self.addr);
-        Ok(Status::Successful(handle))
+    /// Leaves the unix domain socket file in place once the listener is
+    /// dropped, as it's been handed off to another process still using it.
+    pub fn disown(&mut self) {
+        self.cleanup = false;
     }
 
     /// Returns the raw file descriptor of the socket.
     #[cfg(windows)]
     pub fn fd(&self) -> u64 {
-        self.listener.as_raw_socket()
+        match &self.listener {
+            Listener::Tcp(listener) => listener.as_raw_socket(),
+        }
     }
 
     /// Returns the raw file descriptor of the socket.
     #[cfg(unix)]
     pub fn fd(&self) -> i32 {
-        self.listener.as_raw_fd()
+        match &self.listener {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
     }
 }
+
+impl Drop for NoneBlockingListener {
+    fn drop(&mut self) {
+        if !self.cleanup {
+            return;
+        }
+
+        if let Some(path) = self.path.as_ref() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("failed to remove socket file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+/// If the socket with the given file descriptor is a unix domain socket.
+#[cfg(unix)]
+fn is_unix_socket(fd: i32) -> io::Result<bool> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let res = unsafe {
+        libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(storage.ss_family as libc::c_int == libc::AF_UNIX)
+}
+
+/// If the socket with the given file descriptor is a stream socket which
+/// is listening for connections.
+#[cfg(unix)]
+fn is_listening_stream(fd: i32) -> io::Result<bool> {
+    let option = |name| {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(value)
+    };
+
+    Ok((option(libc::SO_TYPE)? == libc::SOCK_STREAM)
+        & (option(libc::SO_ACCEPTCONN)? != 0))
+}
+
+/// Binds a listener to the given address with `SO_REUSEADDR` set before
+/// binding, along with `SO_REUSEPORT` if `reuse_port` is set and
+/// `IPV6_V6ONLY` if `v6_only` is set.
+#[cfg(unix)]
+fn bind_with(
+    addr: SocketAddr,
+    reuse_port: bool,
+    v6_only: bool,
+) -> io::Result<TcpListener> {
+    use std::mem;
+
+    let (domain, storage, len) = unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        match addr {
+            SocketAddr::V4(v4) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = v4.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+                (libc::AF_INET, storage, mem::size_of::<libc::sockaddr_in>())
+            },
+            SocketAddr::V6(v6) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = v6.port().to_be();
+                sin6.sin6_addr.s6_addr = v6.ip().octets();
+                sin6.sin6_flowinfo = v6.flowinfo();
+                sin6.sin6_scope_id = v6.scope_id();
+                (
+                    libc::AF_INET6,
+                    storage,
+                    mem::size_of::<libc::sockaddr_in6>(),
+                )
+            },
+        }
+    };
+
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Owning the fd straight away makes sure it's closed on any errors.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    let mut opts = vec![(libc::SOL_SOCKET, libc::SO_REUSEADDR)];
+    if reuse_port {
+        opts.push((libc::SOL_SOCKET, libc::SO_REUSEPORT));
+    }
+    if v6_only {
+        opts.push((libc::IPPROTO_IPV6, libc::IPV6_V6ONLY));
+    }
+
+    let enabled: libc::c_int = 1;
+    for (level, opt) in opts {
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                opt,
+                &enabled as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let res = unsafe {
+        libc::bind(
+            fd,
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::listen(fd, 1024) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+/// `SO_REUSEPORT` is not available, listeners should instead be bound once
+/// and inherited by each worker.
+///
+/// IPv6 sockets are always bound with `IPV6_V6ONLY` on Windows.
+#[cfg(not(unix))]
+fn bind_with(
+    addr: SocketAddr,
+    reuse_port: bool,
+    _v6_only: bool,
+) -> io::Result<TcpListener> {
+    if reuse_port {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+
+    TcpListener::bind(addr)
+}
@@ -0,0 +1,207 @@
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bytes::{Buf, BytesMut};
+
+use crate::event_loop::SocketFd;
+
+/// The file descriptor reported for in-memory sockets, these are never
+/// registered with a real event loop so this only needs to be invalid.
+#[cfg(unix)]
+pub const NO_FD: SocketFd = -1;
+
+/// The file descriptor reported for in-memory sockets, these are never
+/// registered with a real event loop so this only needs to be invalid.
+#[cfg(windows)]
+pub const NO_FD: SocketFd = SocketFd::MAX;
+
+/// An in-memory socket with no OS resources behind it, used to drive a
+/// connection's protocol entirely from Rust.
+///
+/// The handle is cheap to clone and every clone shares the same buffers,
+/// one clone is wrapped in a `StreamHandle` via `StreamHandle::from_memory`
+/// giving the connection the same read / write interface as a tcp stream,
+/// the other is kept to feed it data and take whatever it responds with.
+#[derive(Clone, Default)]
+pub struct MemoryHandle {
+    inner: Arc<Mutex<Buffers>>,
+}
+
+#[derive(Default)]
+struct Buffers {
+    inbound: BytesMut,
+    outbound: BytesMut,
+
+    /// If the EOF has been fed.
+    eof: bool,
+
+    /// The error the next read fails with if any.
+    read_error: Option<io::Error>,
+
+    /// The max number of bytes returned by a single read, `None` to
+    /// return all that's buffered.
+    read_limit: Option<usize>,
+
+    /// If the connection has shut the socket down.
+    shutdown: bool,
+
+    /// The max number of bytes held in the outbound buffer before writes
+    /// block, `None` to never block.
+    write_limit: Option<usize>,
+}
+
+impl MemoryHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues data to be read by the connection.
+    pub fn feed(&self, data: &[u8]) {
+        self.buffers().inbound.extend_from_slice(data);
+    }
+
+    /// Marks the EOF as received, reads return `0` once the inbound
+    /// buffer is empty.
+    pub fn feed_eof(&self) {
+        self.buffers().eof = true;
+    }
+
+    /// Makes the next read fail with the given error, as if the socket
+    /// had errored.
+    pub fn fail_next_read(&self, error: io::Error) {
+        self.buffers().read_error = Some(error);
+    }
+
+    /// Limits how many bytes a single read returns, `None` removes the
+    /// limit.
+    ///
+    /// This allows data to arrive over several reads the same way it would
+    /// with a socket receiving it in separate segments.
+    pub fn set_read_limit(&self, limit: Option<usize>) {
+        self.buffers().read_limit = limit;
+    }
+
+    /// The number of bytes fed which the connection is yet to read.
+    pub fn unread(&self) -> usize {
+        self.buffers().inbound.len()
+    }
+
+    /// Takes everything the connection has written so far.
+    pub fn take_written(&self) -> BytesMut {
+        self.buffers().outbound.split()
+    }
+
+    /// If the connection has shut the socket down.
+    pub fn is_shutdown(&self) -> bool {
+        self.buffers().shutdown
+    }
+
+    /// Limits how many bytes can be written before writes block until
+    /// they're taken, `None` removes the limit.
+    ///
+    /// This allows the connection's back pressure handling to be driven
+    /// the same way a full socket send buffer would.
+    pub fn set_write_limit(&self, limit: Option<usize>) {
+        self.buffers().write_limit = limit;
+    }
+
+    /// If a write would not block, i.e. the outbound buffer is under the
+    /// write limit.
+    pub fn is_writable(&self) -> bool {
+        let buffers = self.buffers();
+        match buffers.write_limit {
+            Some(limit) => buffers.shutdown | (buffers.outbound.len() < limit),
+            None => true,
+        }
+    }
+
+    pub fn shutdown(&mut self, _how: Shutdown) -> io::Result<()> {
+        self.buffers().shutdown = true;
+        Ok(())
+    }
+
+    /// Reads data from the inbound buffer without removing it.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let buffers = self.buffers();
+        if buffers.inbound.is_empty() {
+            return if buffers.eof {
+                Ok(0)
+            } else {
+                Err(ErrorKind::WouldBlock.into())
+            };
+        }
+
+        let limit = buffers.read_limit.unwrap_or(usize::MAX);
+        let len = buf.len().min(buffers.inbound.len()).min(limit);
+        buf[..len].copy_from_slice(&buffers.inbound[..len]);
+        Ok(len)
+    }
+
+    fn buffers(&self) -> MutexGuard<'_, Buffers> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl Read for MemoryHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(error) = self.buffers().read_error.take() {
+            return Err(error);
+        }
+
+        let len = self.peek(buf)?;
+        self.buffers().inbound.advance(len);
+        Ok(len)
+    }
+}
+
+impl Write for MemoryHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffers = self.buffers();
+        if buffers.shutdown {
+            return Err(ErrorKind::ConnectionAborted.into());
+        }
+
+        // Like a socket, writing nothing never blocks.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let available = match buffers.write_limit {
+            Some(limit) => limit.saturating_sub(buffers.outbound.len()),
+            None => buf.len(),
+        };
+
+        if available == 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let len = buf.len().min(available);
+        buffers.outbound.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Writes the buffers in order until the write limit is reached, so a
+    /// vectored write can stop part way through any of them.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut written = 0;
+        for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+            let len = match self.write(buf) {
+                Ok(len) => len,
+                Err(e) if (e.kind() == ErrorKind::WouldBlock) & (written > 0) => break,
+                Err(e) => return Err(e),
+            };
+
+            written += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
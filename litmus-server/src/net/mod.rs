@@ -1,5 +1,27 @@
+mod cert;
+mod completion;
+mod file;
 mod listener;
+mod memory;
+mod options;
+mod proxy;
+mod socket;
 mod stream;
+#[cfg(feature = "tls")]
+mod tls;
 
+pub use cert::PeerCertificate;
+pub use completion::CompletionSocket;
+#[cfg(any(all(target_os = "linux", feature = "io_uring"), feature = "http3"))]
+pub use completion::ShutdownHook;
+pub use file::FileBody;
 pub use listener::{NoneBlockingListener, Status};
-pub use stream::{SocketStatus, StreamHandle};
+pub use memory::MemoryHandle;
+pub(crate) use memory::NO_FD;
+pub use options::{SocketOptions, TcpKeepalive};
+pub use proxy::ProxyStatus;
+pub use stream::{SocketStatus, StreamHandle, TlsDetails};
+#[cfg(all(test, feature = "tls"))]
+pub(crate) use tls::RENEGOTIATION_ERROR;
+#[cfg(feature = "tls")]
+pub use tls::{ClientAuthPolicy, SniConfig, TlsConfig};
@@ -0,0 +1,171 @@
+use std::io;
+#[cfg(unix)]
+use std::mem;
+use std::time::Duration;
+
+use crate::event_loop::SocketFd;
+
+/// The TCP keepalive probes sent on an idle connection.
+#[derive(Copy, Clone)]
+pub struct TcpKeepalive {
+    /// How long the connection is idle before the first probe is sent.
+    pub idle: Duration,
+
+    /// How long to wait between unanswered probes.
+    pub interval: Duration,
+
+    /// The number of unanswered probes before the connection is dropped.
+    pub count: u32,
+}
+
+/// The options set on each accepted tcp socket, anything left unset keeps
+/// the OS default.
+///
+/// On Windows only `nodelay` is applied.
+#[derive(Copy, Clone, Default)]
+pub struct SocketOptions {
+    /// Sets `TCP_NODELAY` disabling Nagle's algorithm, small writes are
+    /// sent straight away rather than waiting to be coalesced.
+    pub nodelay: bool,
+
+    /// Enables `SO_KEEPALIVE`, the idle time, interval and count are only
+    /// set on Linux, Android, FreeBSD and macOS.
+    pub keepalive: Option<TcpKeepalive>,
+
+    /// The size of the socket's receive buffer set by `SO_RCVBUF`.
+    pub recv_buffer_size: Option<usize>,
+
+    /// The size of the socket's send buffer set by `SO_SNDBUF`.
+    pub send_buffer_size: Option<usize>,
+
+    /// How long closing the socket waits for unsent data set by
+    /// `SO_LINGER`, zero resets the connection instead.
+    pub linger: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Sets the options on the tcp socket with the given file descriptor.
+    #[cfg(unix)]
+    pub fn apply(&self, fd: SocketFd) -> io::Result<()> {
+        if self.nodelay {
+            set_opt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, 1 as libc::c_int)?;
+        }
+
+        if let Some(keepalive) = self.keepalive.as_ref() {
+            set_opt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1 as libc::c_int)?;
+            set_keepalive_timing(fd, keepalive)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            set_opt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, to_c_int(size as u64))?;
+        }
+
+        if let Some(size) = self.send_buffer_size {
+            set_opt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, to_c_int(size as u64))?;
+        }
+
+        if let Some(linger) = self.linger {
+            let value = libc::linger {
+                l_onoff: 1,
+                l_linger: to_c_int(linger.as_secs()),
+            };
+            set_opt(fd, libc::SOL_SOCKET, libc::SO_LINGER, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the options on the tcp socket with the given file descriptor.
+    #[cfg(windows)]
+    pub fn apply(&self, fd: SocketFd) -> io::Result<()> {
+        if self.nodelay {
+            super::completion::borrow_stream(fd).set_nodelay(true)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+    target_os = "ios",
+))]
+fn set_keepalive_timing(fd: SocketFd, keepalive: &TcpKeepalive) -> io::Result<()> {
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let idle = libc::TCP_KEEPALIVE;
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let idle = libc::TCP_KEEPIDLE;
+
+    let secs = |d: Duration| to_c_int(d.as_secs().max(1));
+    set_opt(fd, libc::IPPROTO_TCP, idle, secs(keepalive.idle))?;
+    set_opt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        secs(keepalive.interval),
+    )?;
+    set_opt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPCNT,
+        to_c_int(keepalive.count as u64),
+    )
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "ios",
+    ))
+))]
+fn set_keepalive_timing(_fd: SocketFd, _keepalive: &TcpKeepalive) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets `SO_LINGER` to zero so closing the socket resets the connection
+/// rather than shutting it down gracefully.
+#[cfg(unix)]
+pub(crate) fn set_reset_on_close(fd: SocketFd) -> io::Result<()> {
+    let value = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    set_opt(fd, libc::SOL_SOCKET, libc::SO_LINGER, value)
+}
+
+#[cfg(unix)]
+fn to_c_int(value: u64) -> libc::c_int {
+    value.min(libc::c_int::MAX as u64) as libc::c_int
+}
+
+#[cfg(unix)]
+fn set_opt<T>(
+    fd: SocketFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
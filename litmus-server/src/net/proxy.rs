@@ -0,0 +1,303 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+/// The signature starting a v2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+/// The prefix starting a v1 header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// The max length of a v1 header including the trailing `\r\n`.
+const V1_MAX_LEN: usize = 107;
+
+/// The length of the fixed part of a v2 header.
+const V2_HEADER_LEN: usize = 16;
+
+/// The max length of a header, v2 headers carrying more TLVs than fit in
+/// this are rejected.
+pub(crate) const MAX_HEADER_LEN: usize = 4096;
+
+/// The outcome of reading the PROXY protocol header of a connection.
+pub enum ProxyStatus {
+    /// The header has been read.
+    Complete,
+
+    /// More data is needed to complete the header.
+    Incomplete,
+
+    /// The connection did not start with a valid header.
+    Invalid(&'static str),
+
+    /// The connection was closed before the header was complete.
+    Disconnect,
+}
+
+/// A parsed PROXY protocol header.
+pub(crate) enum ProxyHeader {
+    /// The header is incomplete.
+    Partial,
+
+    /// A complete header of the given length along with the source and
+    /// destination addresses of the proxied connection, these are `None`
+    /// if the proxy did not relay a connection on behalf of a client,
+    /// e.g. health checks.
+    Complete {
+        len: usize,
+        addrs: Option<(SocketAddr, SocketAddr)>,
+    },
+}
+
+/// Parses a v1 or v2 PROXY protocol header from the start of the buffer.
+pub(crate) fn parse(buffer: &[u8]) -> Result<ProxyHeader, &'static str> {
+    if is_prefixed_by(buffer, V2_SIGNATURE) {
+        parse_v2(buffer)
+    } else if is_prefixed_by(buffer, V1_PREFIX) {
+        parse_v1(buffer)
+    } else {
+        Err("missing signature")
+    }
+}
+
+/// If the buffer starts with the given prefix or could do once more data
+/// has been received.
+fn is_prefixed_by(buffer: &[u8], prefix: &[u8]) -> bool {
+    let len = buffer.len().min(prefix.len());
+    buffer[..len] == prefix[..len]
+}
+
+/// Parses the human readable v1 header, e.g.
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+fn parse_v1(buffer: &[u8]) -> Result<ProxyHeader, &'static str> {
+    let search = &buffer[..buffer.len().min(V1_MAX_LEN)];
+    let end = match search.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buffer.len() < V1_MAX_LEN => return Ok(ProxyHeader::Partial),
+        None => return Err("v1 header too long"),
+    };
+
+    let line = str::from_utf8(&buffer[V1_PREFIX.len()..end])
+        .map_err(|_| "v1 header is not ASCII")?;
+    let mut parts = line.split(' ');
+
+    let addrs = match parts.next() {
+        Some("UNKNOWN") => None,
+        Some("TCP4") | Some("TCP6") => {
+            let mut next = || parts.next().ok_or("v1 header is missing fields");
+            let src_ip = next()?.parse::<IpAddr>();
+            let dst_ip = next()?.parse::<IpAddr>();
+            let src_port = next()?.parse::<u16>();
+            let dst_port = next()?.parse::<u16>();
+
+            match (src_ip, dst_ip, src_port, dst_port) {
+                (Ok(src_ip), Ok(dst_ip), Ok(src_port), Ok(dst_port)) => Some((
+                    SocketAddr::new(src_ip, src_port),
+                    SocketAddr::new(dst_ip, dst_port),
+                )),
+                _ => return Err("v1 header has an invalid address"),
+            }
+        },
+        _ => return Err("v1 header has an unknown protocol"),
+    };
+
+    Ok(ProxyHeader::Complete {
+        len: end + 2,
+        addrs,
+    })
+}
+
+/// Parses the binary v2 header.
+fn parse_v2(buffer: &[u8]) -> Result<ProxyHeader, &'static str> {
+    if buffer.len() < V2_HEADER_LEN {
+        return Ok(ProxyHeader::Partial);
+    }
+
+    let version = buffer[12] >> 4;
+    let command = buffer[12] & 0x0F;
+    let family = buffer[13];
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+
+    if version != 2 {
+        return Err("v2 header has an unsupported version");
+    }
+
+    if len > MAX_HEADER_LEN {
+        return Err("v2 header too long");
+    }
+
+    if buffer.len() < len {
+        return Ok(ProxyHeader::Partial);
+    }
+
+    let body = &buffer[V2_HEADER_LEN..len];
+    let addrs = match (command, family) {
+        // LOCAL, the connection was made by the proxy itself.
+        (0x0, _) => None,
+
+        // PROXY over TCP or UDP on IPv4.
+        (0x1, 0x11) | (0x1, 0x12) => {
+            if body.len() < 12 {
+                return Err("v2 header has a truncated address");
+            }
+
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            Some((
+                SocketAddr::new(ip(&body[0..4]), port(&body[8..10])),
+                SocketAddr::new(ip(&body[4..8]), port(&body[10..12])),
+            ))
+        },
+
+        // PROXY over TCP or UDP on IPv6.
+        (0x1, 0x21) | (0x1, 0x22) => {
+            if body.len() < 36 {
+                return Err("v2 header has a truncated address");
+            }
+
+            let ip = |b: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(b);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Some((
+                SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+            ))
+        },
+
+        // Unix sockets and unspecified families have no usable address.
+        (0x1, _) => None,
+
+        _ => return Err("v2 header has an unknown command"),
+    };
+
+    Ok(ProxyHeader::Complete { len, addrs })
+}
+
+fn port(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Addrs = Option<(SocketAddr, SocketAddr)>;
+
+    /// The length and addresses of a header expected to be complete.
+    fn complete(buffer: &[u8]) -> (usize, Addrs) {
+        match parse(buffer) {
+            Ok(ProxyHeader::Complete { len, addrs }) => (len, addrs),
+            Ok(ProxyHeader::Partial) => panic!("header is partial"),
+            Err(e) => panic!("header is invalid: {}", e),
+        }
+    }
+
+    fn is_partial(buffer: &[u8]) -> bool {
+        matches!(parse(buffer), Ok(ProxyHeader::Partial))
+    }
+
+    fn addrs(src: &str, dst: &str) -> Addrs {
+        Some((src.parse().unwrap(), dst.parse().unwrap()))
+    }
+
+    /// A v2 header with the given command, family and body.
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[test]
+    fn v1_addresses_are_parsed() {
+        let header = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /";
+        assert_eq!(
+            complete(header),
+            (header.len() - 5, addrs("192.0.2.1:56324", "192.0.2.2:443"))
+        );
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            complete(header),
+            (
+                header.len(),
+                addrs("[2001:db8::1]:56324", "[2001:db8::2]:443")
+            )
+        );
+
+        assert_eq!(complete(b"PROXY UNKNOWN\r\n"), (15, None));
+    }
+
+    #[test]
+    fn v1_partial_headers_wait_for_more() {
+        let header = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        for len in 1..header.len() {
+            assert!(is_partial(&header[..len]), "{}", len);
+        }
+    }
+
+    #[test]
+    fn v1_invalid_headers_are_rejected() {
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 host 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 1 70000\r\n").is_err());
+        assert!(parse(&[b'P'; V1_MAX_LEN]).is_err());
+    }
+
+    #[test]
+    fn v2_addresses_are_parsed() {
+        let body = [192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB];
+        let header = v2(0x1, 0x11, &body);
+        assert_eq!(
+            complete(&header),
+            (28, addrs("192.0.2.1:56324", "192.0.2.2:443"))
+        );
+
+        let mut body = Ipv6Addr::LOCALHOST.octets().to_vec();
+        body.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        body.extend_from_slice(&[0xDC, 0x04, 0x01, 0xBB]);
+        let header = v2(0x1, 0x21, &body);
+        assert_eq!(complete(&header), (52, addrs("[::1]:56324", "[::]:443")));
+    }
+
+    #[test]
+    fn v2_tlvs_are_skipped() {
+        let mut body = vec![192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB];
+        body.extend_from_slice(&[0x04, 0x00, 0x03, b'a', b'b', b'c']);
+        let mut header = v2(0x1, 0x11, &body);
+        header.extend_from_slice(b"GET /");
+        assert_eq!(
+            complete(&header),
+            (34, addrs("192.0.2.1:56324", "192.0.2.2:443"))
+        );
+    }
+
+    #[test]
+    fn v2_local_and_unix_have_no_addresses() {
+        assert_eq!(complete(&v2(0x0, 0x00, &[])), (16, None));
+        assert_eq!(complete(&v2(0x1, 0x31, &[0; 216])), (232, None));
+    }
+
+    #[test]
+    fn v2_partial_headers_wait_for_more() {
+        let header = v2(0x1, 0x11, &[0; 12]);
+        for len in 1..header.len() {
+            assert!(is_partial(&header[..len]), "{}", len);
+        }
+    }
+
+    #[test]
+    fn v2_invalid_headers_are_rejected() {
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse(&header).is_err());
+
+        assert!(parse(&v2(0x2, 0x11, &[0; 12])).is_err());
+        assert!(parse(&v2(0x1, 0x11, &[0; 8])).is_err());
+        assert!(parse(&v2(0x1, 0x21, &[0; 12])).is_err());
+        assert!(parse(&v2(0x1, 0x11, &[0; MAX_HEADER_LEN])).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+
+use super::completion::CompletionSocket;
+use super::memory::{MemoryHandle, NO_FD};
+
+/// A connected socket, either a tcp stream, a unix domain socket stream,
+/// a socket whose I/O is performed by a completion based event loop or an
+/// in-memory socket.
+pub enum Socket {
+    Tcp(TcpStream),
+
+    #[cfg(unix)]
+    Unix(UnixStream),
+
+    Completion(CompletionSocket),
+
+    Memory(MemoryHandle),
+}
+
+impl Socket {
+    /// If the socket is a tcp stream, tcp specific options such as corking
+    /// do not apply to anything else.
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, Self::Tcp(_))
+    }
+
+    /// The completion socket if the socket's I/O is performed by the
+    /// event loop.
+    pub fn as_completion(&mut self) -> Option<&mut CompletionSocket> {
+        match self {
+            Self::Completion(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// If the socket is backed by a real file descriptor which can be
+    /// handed to the OS directly, e.g. for `sendfile`.
+    pub fn has_os_socket(&self) -> bool {
+        !matches!(self, Self::Completion(_) | Self::Memory(_))
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(s) => s.shutdown(how),
+            Self::Completion(s) => s.shutdown(how),
+            Self::Memory(s) => s.shutdown(how),
+        }
+    }
+
+    /// Reads data from the socket without removing it from the socket's
+    /// receive queue.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.peek(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => {
+                let res = unsafe {
+                    libc::recv(
+                        s.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        libc::MSG_PEEK,
+                    )
+                };
+
+                if res < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(res as usize)
+                }
+            },
+            Self::Completion(s) => s.peek(buf),
+            Self::Memory(s) => s.peek(buf),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => s.read(buf),
+            Self::Completion(s) => s.read(buf),
+            Self::Memory(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => s.write(buf),
+            Self::Completion(s) => s.write(buf),
+            Self::Memory(s) => s.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write_vectored(bufs),
+            #[cfg(unix)]
+            Self::Unix(s) => s.write_vectored(bufs),
+            Self::Completion(s) => s.write_vectored(bufs),
+            Self::Memory(s) => s.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Self::Unix(s) => s.flush(),
+            Self::Completion(s) => s.flush(),
+            Self::Memory(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(s) => s.as_raw_fd(),
+            Self::Unix(s) => s.as_raw_fd(),
+            Self::Completion(s) => s.fd(),
+            Self::Memory(_) => NO_FD,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for Socket {
+    fn as_raw_socket(&self) -> RawSocket {
+        match self {
+            Self::Tcp(s) => s.as_raw_socket(),
+            Self::Completion(s) => s.fd(),
+            Self::Memory(_) => NO_FD,
+        }
+    }
+}
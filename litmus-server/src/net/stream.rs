@@ -1,44 +1,306 @@
-use std::io::{ErrorKind, Read, Write};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, IoSlice, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
 #[cfg(unix)]
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 #[cfg(windows)]
-use std::os::windows::io::AsRawSocket;
+use std::os::windows::io::{AsRawSocket, FromRawSocket};
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use pyo3::{PyErr, PyObject, PyResult};
+#[cfg(feature = "tls")]
+use rustls::ServerConnection;
+
+use super::completion::{self, CompletionSocket};
+use super::file::FileBody;
+use super::memory::{MemoryHandle, NO_FD};
+#[cfg(unix)]
+use super::options::set_reset_on_close;
+use super::options::SocketOptions;
+use super::proxy::{self, ProxyHeader, ProxyStatus};
+use super::socket::Socket;
+#[cfg(feature = "tls")]
+use super::tls::{self, RecordTracker, TlsConfig};
+use crate::event_loop::SocketFd;
+use crate::plugin::ProtocolFactory;
+use crate::rate_limit::IpSlot;
+
+/// The max number of buffers offered to a single vectored write, this is
+/// kept well below `IOV_MAX` which is as low as 1024 on some platforms.
+const MAX_WRITE_SLICES: usize = 64;
+
+/// What was negotiated during a connection's TLS handshake.
+#[derive(Clone, Debug, Default)]
+pub struct TlsDetails {
+    /// The TLS version, e.g. `"TLSv1.3"`.
+    pub version: Option<&'static str>,
 
-use bytes::{BufMut, BytesMut};
-use pyo3::{PyErr, PyResult};
+    /// The IANA name of the cipher suite, e.g. `"TLS_AES_128_GCM_SHA256"`.
+    pub cipher: Option<String>,
 
+    /// The protocol negotiated using ALPN, e.g. `b"h2"`.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
 pub enum SocketStatus {
     Complete(usize),
     WouldBlock,
     Disconnect,
 }
 
-/// A struct that wraps a given TcpStream and SocketAddr and produces a
+/// A struct that wraps a given stream and SocketAddr and produces a
 /// contain for interactions that are os agnostic.
 pub struct StreamHandle {
-    /// The internal `std::net::TcpStream` instance that should be set
-    /// to be non-blocking.
-    stream: TcpStream,
+    /// The internal tcp or unix stream that should be set to be
+    /// non-blocking.
+    stream: Socket,
 
     /// The remote's given socket addr as given by the tcp listener upon
     /// accepting the client / connection.
+    ///
+    /// Unix domain socket peers have no address so this is unspecified.
     pub addr: SocketAddr,
 
     pub server: SocketAddr,
 
     pub tls: bool,
+
+    /// If the socket is currently corked.
+    corked: bool,
+
+    /// If the write side of the connection has been shut down.
+    write_shutdown: bool,
+
+    /// The connection's slot in the per-address connection limit if any,
+    /// released once the connection is closed.
+    ip_slot: Option<IpSlot>,
+
+    /// The custom protocol of the listener the connection was accepted on,
+    /// served in place of HTTP.
+    protocol: Option<Arc<dyn ProtocolFactory>>,
+
+    /// The TLS session if the connection is encrypted.
+    #[cfg(feature = "tls")]
+    session: Option<Box<ServerConnection>>,
+
+    /// The records received over the TLS session.
+    #[cfg(feature = "tls")]
+    records: RecordTracker,
+
+    /// Why the TLS session closed the connection if it did.
+    #[cfg(feature = "tls")]
+    tls_error: Option<String>,
 }
 
 impl StreamHandle {
     /// Create a new tcp handle wrapping the given stream and addr.
     pub fn new(stream: TcpStream, addr: SocketAddr, server: SocketAddr) -> Self {
+        Self::from_socket(Socket::Tcp(stream), addr, server)
+    }
+
+    /// Create a new handle wrapping the given unix domain socket stream, the
+    /// client's and server's addresses are left unspecified.
+    #[cfg(unix)]
+    pub fn new_unix(stream: UnixStream, server: SocketAddr) -> Self {
+        Self::from_socket(Socket::Unix(stream), server, server)
+    }
+
+    fn from_socket(stream: Socket, addr: SocketAddr, server: SocketAddr) -> Self {
         Self {
             stream,
             addr,
             server,
             tls: false,
+            corked: false,
+            write_shutdown: false,
+            ip_slot: None,
+            protocol: None,
+            #[cfg(feature = "tls")]
+            session: None,
+            #[cfg(feature = "tls")]
+            records: RecordTracker::default(),
+            #[cfg(feature = "tls")]
+            tls_error: None,
+        }
+    }
+
+    /// Serves the given custom protocol on the connection in place of HTTP.
+    pub(crate) fn with_protocol(
+        mut self,
+        protocol: Option<Arc<dyn ProtocolFactory>>,
+    ) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// The custom protocol served on the connection if any.
+    pub(crate) fn protocol(&self) -> Option<&Arc<dyn ProtocolFactory>> {
+        self.protocol.as_ref()
+    }
+
+    /// Terminates TLS on the connection using the given config, all reads
+    /// and writes go through the TLS session from then on.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: &TlsConfig) -> PyResult<Self> {
+        self.session = Some(Box::new(config.new_session()?));
+        self.tls = true;
+        Ok(self)
+    }
+
+    /// The protocol negotiated using ALPN during the TLS handshake, `None`
+    /// if the connection isn't encrypted, the handshake is yet to complete
+    /// or the client didn't offer any protocols.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_ref() {
+            return session.alpn_protocol();
+        }
+
+        None
+    }
+
+    /// What was negotiated during the TLS handshake, `None` if the
+    /// connection isn't encrypted or the handshake is yet to complete.
+    pub fn tls_details(&self) -> Option<TlsDetails> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_ref() {
+            return tls::details(session);
+        }
+
+        None
+    }
+
+    /// The DER of the certificate the client verified itself with during
+    /// the TLS handshake, `None` if the connection isn't encrypted, the
+    /// handshake is yet to complete or the client didn't give one.
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_ref() {
+            return session
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.0.as_slice());
+        }
+
+        None
+    }
+
+    /// Takes the reason the TLS session closed the connection, `None` if
+    /// the connection isn't encrypted or the session didn't close it.
+    pub fn take_tls_error(&mut self) -> Option<String> {
+        #[cfg(feature = "tls")]
+        if let Some(error) = self.tls_error.take() {
+            return Some(error);
+        }
+
+        None
+    }
+
+    /// If there is encrypted data waiting to be written to the socket
+    /// which was not written due to the socket blocking.
+    pub fn has_pending_writes(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_ref() {
+            return session.wants_write();
         }
+
+        false
+    }
+
+    /// Creates a new tcp handle adopting the already connected socket with
+    /// the given file descriptor, e.g. one handed off from another process.
+    ///
+    /// # Safety
+    /// The file descriptor must be an open, connected tcp socket which is
+    /// not owned by anything else.
+    #[cfg(unix)]
+    pub unsafe fn from_fd(fd: i32) -> PyResult<Self> {
+        Self::adopt(TcpStream::from_raw_fd(fd))
+    }
+
+    /// Creates a new tcp handle adopting the already connected socket with
+    /// the given file descriptor, e.g. one handed off from another process.
+    ///
+    /// # Safety
+    /// The file descriptor must be an open, connected tcp socket which is
+    /// not owned by anything else.
+    #[cfg(windows)]
+    pub unsafe fn from_fd(fd: u64) -> PyResult<Self> {
+        Self::adopt(TcpStream::from_raw_socket(fd))
+    }
+
+    /// Creates a new handle for a socket owned by a completion based event
+    /// loop, the event loop performs all I/O with the socket feeding the
+    /// handle any data it receives and sending anything the handle queues.
+    ///
+    /// `on_shutdown` is invoked once the connection shuts the socket down.
+    pub fn from_completion(fd: SocketFd, on_shutdown: PyObject) -> Self {
+        let (addr, server) = completion::addresses(fd);
+        let socket = CompletionSocket::new(fd, on_shutdown);
+        Self::from_socket(Socket::Completion(socket), addr, server)
+    }
+
+    /// Creates a new handle for a socket owned by the io_uring driver, which
+    /// performs all I/O with the socket in the same way as a completion
+    /// based event loop.
+    ///
+    /// `on_shutdown` is invoked once the connection shuts the socket down.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub(crate) fn from_ring(
+        fd: SocketFd,
+        on_shutdown: completion::ShutdownHook,
+    ) -> Self {
+        let (addr, server) = completion::addresses(fd);
+        let socket = CompletionSocket::with_hook(fd, on_shutdown);
+        Self::from_socket(Socket::Completion(socket), addr, server)
+    }
+
+    /// Creates a new handle for an HTTP/3 request stream of a connection
+    /// accepted by the QUIC driver, the driver translates what's received
+    /// on the stream into HTTP/1.1 and feeds it to the handle in the same
+    /// way as a completion based event loop.
+    ///
+    /// The stream has no file descriptor of its own and is always
+    /// encrypted. `on_shutdown` is invoked once the connection shuts the
+    /// socket down.
+    #[cfg(feature = "http3")]
+    pub(crate) fn from_quic(
+        addr: SocketAddr,
+        server: SocketAddr,
+        on_shutdown: completion::ShutdownHook,
+    ) -> Self {
+        let socket = CompletionSocket::with_hook(NO_FD, on_shutdown);
+        let mut handle = Self::from_socket(Socket::Completion(socket), addr, server);
+        handle.tls = true;
+        handle
+    }
+
+    /// The completion socket if the handle's I/O is performed by a
+    /// completion based event loop.
+    pub fn completion(&mut self) -> Option<&mut CompletionSocket> {
+        self.stream.as_completion()
+    }
+
+    /// Creates a handle for an in-memory socket, the connection reads
+    /// whatever is fed into the given memory handle and writes into it.
+    pub fn from_memory(
+        socket: MemoryHandle,
+        addr: SocketAddr,
+        server: SocketAddr,
+    ) -> Self {
+        Self::from_socket(Socket::Memory(socket), addr, server)
+    }
+
+    fn adopt(stream: TcpStream) -> PyResult<Self> {
+        stream.set_nonblocking(true)?;
+        let addr = stream.peer_addr()?;
+        let server = stream.local_addr()?;
+
+        Ok(Self::new(stream, addr, server))
     }
 
     /// Returns the raw file descriptor of the socket.
@@ -57,6 +319,17 @@ impl StreamHandle {
     /// a result with the number of bytes read if the operation is a success.
     #[timed::timed(duration(printer = "trace!"))]
     pub fn read(&mut self, buffer: &mut BytesMut) -> PyResult<SocketStatus> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_mut() {
+            return tls::read(
+                &mut self.stream,
+                session,
+                &mut self.records,
+                &mut self.tls_error,
+                buffer,
+            );
+        }
+
         let data = buffer.chunk_mut();
         let mut slice =
             unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr(), data.len()) };
@@ -87,6 +360,11 @@ impl StreamHandle {
     /// is a success.
     #[timed::timed(duration(printer = "trace!"))]
     pub fn write(&mut self, buffer: &mut BytesMut) -> PyResult<SocketStatus> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_mut() {
+            return tls::write(&mut self.stream, session, buffer);
+        }
+
         let len = match self.stream.write(buffer) {
             Ok(n) => n,
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
@@ -106,7 +384,434 @@ impl StreamHandle {
         Ok(SocketStatus::Complete(len))
     }
 
+    /// Writes the supplied buffers to the socket in a single vectored write
+    /// returning a result with the number of bytes written to the socket if
+    /// the operation is a success.
+    ///
+    /// Any buffers that were fully written are removed from the queue and
+    /// the first partially written buffer is advanced past the written
+    /// bytes, ready to be re-offered on the next write.
+    #[timed::timed(duration(printer = "trace!"))]
+    pub fn write_vectored(
+        &mut self,
+        buffers: &mut VecDeque<Bytes>,
+    ) -> PyResult<SocketStatus> {
+        let slices: Vec<IoSlice> = buffers
+            .iter()
+            .take(MAX_WRITE_SLICES)
+            .map(|b| IoSlice::new(b))
+            .collect();
+
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_mut() {
+            let status = tls::write_vectored(&mut self.stream, session, &slices)?;
+            if let SocketStatus::Complete(len) = status {
+                advance_buffers(buffers, len);
+            }
+            return Ok(status);
+        }
+
+        let len = match self.stream.write_vectored(&slices) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                return Ok(SocketStatus::WouldBlock)
+            },
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
+                return Ok(SocketStatus::Disconnect)
+            },
+            Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => {
+                return Ok(SocketStatus::Disconnect)
+            },
+            Err(e) => return Err(PyErr::from(e)),
+        };
+
+        advance_buffers(buffers, len);
+
+        Ok(SocketStatus::Complete(len))
+    }
+
+    /// Writes as much of the file region to the socket as possible,
+    /// returning a result with the number of bytes written if the
+    /// operation is a success.
+    ///
+    /// This uses `sendfile(2)` where available so the file is copied
+    /// directly to the socket, otherwise or if the connection is encrypted
+    /// the file is read and written to the socket in chunks.
+    pub fn send_file(&mut self, file: &mut FileBody) -> PyResult<SocketStatus> {
+        // Completion and in-memory sockets have no OS socket to send to, the
+        // file must go through their outbound buffer to keep it in order
+        // with everything else written.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if !self.tls & self.stream.has_os_socket() {
+            if let Some(status) = self.sendfile(file)? {
+                return Ok(status);
+            }
+        }
+
+        let chunk = file.read_chunk()?;
+        if chunk.is_empty() {
+            warn!("file was truncated while being sent, closing connection");
+            return Ok(SocketStatus::Disconnect);
+        }
+
+        let mut buffer = BytesMut::from(chunk.as_slice());
+        let status = self.write(&mut buffer)?;
+        if let SocketStatus::Complete(len) = status {
+            file.advance(len);
+        }
+
+        Ok(status)
+    }
+
+    /// Writes the file region using `sendfile(2)`, returning `None` if it
+    /// is not supported for the socket and the fallback should be used.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn sendfile(&mut self, file: &mut FileBody) -> PyResult<Option<SocketStatus>> {
+        // The most Linux will transfer in a single call.
+        const MAX_SENDFILE: u64 = 0x7fff_f000;
+
+        let mut offset = file.offset() as libc::off_t;
+        let count = file.remaining().min(MAX_SENDFILE) as usize;
+        let res = unsafe { libc::sendfile(self.fd(), file.fd(), &mut offset, count) };
+
+        if res < 0 {
+            let e = std::io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EAGAIN) => Ok(Some(SocketStatus::WouldBlock)),
+                Some(libc::ECONNRESET) | Some(libc::EPIPE) => {
+                    Ok(Some(SocketStatus::Disconnect))
+                },
+                Some(libc::EINVAL) | Some(libc::ENOSYS) => Ok(None),
+                _ => Err(PyErr::from(e)),
+            };
+        }
+
+        if (res == 0) & (count > 0) {
+            warn!("file was truncated while being sent, closing connection");
+            return Ok(Some(SocketStatus::Disconnect));
+        }
+
+        file.advance(res as usize);
+        Ok(Some(SocketStatus::Complete(res as usize)))
+    }
+
+    /// Reads the PROXY protocol header the connection starts with, setting
+    /// the client and server addresses to the ones it gives.
+    ///
+    /// The header is read off the socket directly, bypassing any TLS
+    /// session, and nothing past its end is consumed. Any part of the
+    /// header received so far is accumulated in `pending` until the
+    /// header is complete.
+    pub fn read_proxy_header(&mut self, pending: &mut Vec<u8>) -> PyResult<ProxyStatus> {
+        let mut peeked = [0; proxy::MAX_HEADER_LEN];
+        let available = proxy::MAX_HEADER_LEN - pending.len();
+
+        let len = match self.stream.peek(&mut peeked[..available]) {
+            Ok(0) => return Ok(ProxyStatus::Disconnect),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                return Ok(ProxyStatus::Incomplete)
+            },
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
+                return Ok(ProxyStatus::Disconnect)
+            },
+            Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => {
+                return Ok(ProxyStatus::Disconnect)
+            },
+            Err(e) => return Err(PyErr::from(e)),
+        };
+
+        let start = pending.len();
+        pending.extend_from_slice(&peeked[..len]);
+
+        let header = match proxy::parse(pending) {
+            Ok(header) => header,
+            Err(reason) => return Ok(ProxyStatus::Invalid(reason)),
+        };
+
+        // Only the bytes belonging to the header are taken off the socket,
+        // everything after it is left for the protocol.
+        let (consume, status) = match header {
+            ProxyHeader::Partial => (len, ProxyStatus::Incomplete),
+            ProxyHeader::Complete { len, addrs } => {
+                if let Some((addr, server)) = addrs {
+                    self.addr = addr;
+                    self.server = server;
+                }
+
+                (len - start, ProxyStatus::Complete)
+            },
+        };
+
+        self.stream.read_exact(&mut peeked[..consume])?;
+
+        Ok(status)
+    }
+
+    /// Turns the connection away with the given response and closes it.
+    ///
+    /// This is best effort, the socket is never waited on so the response
+    /// is dropped if it can't be written straight away. Any request already
+    /// received is discarded first so the peer isn't sent a reset before
+    /// it has read the response.
+    pub fn reject(mut self, response: &[u8]) {
+        let mut discard = [0; 4096];
+        let _ = self.stream.read(&mut discard);
+        let _ = self.stream.write(response);
+        let _ = self.stream.shutdown(Shutdown::Write);
+    }
+
     pub fn close(&mut self) {
+        self.ip_slot = None;
+
+        // The close notify was already sent if the write side is shut down.
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_mut() {
+            if !self.write_shutdown {
+                tls::close(&mut self.stream, session);
+            }
+        }
+
         let _ = self.stream.shutdown(Shutdown::Both);
     }
+
+    /// Resets the connection, the socket is closed straight away with
+    /// `SO_LINGER` set to zero so the peer is sent a RST and anything not
+    /// yet sent is discarded. TLS sessions aren't sent a close notify.
+    ///
+    /// Sockets which can't be reset are closed as normal, the socket's
+    /// listeners must be removed first as its file descriptor is released.
+    pub fn abort(&mut self) {
+        #[cfg(unix)]
+        if self.stream.is_tcp() && set_reset_on_close(self.fd()).is_ok() {
+            self.ip_slot = None;
+            self.write_shutdown = true;
+
+            // Dropping the stream closes it, a placeholder which has seen
+            // the EOF and is shut down takes its place.
+            let mut placeholder = MemoryHandle::new();
+            placeholder.feed_eof();
+            let _ = placeholder.shutdown(Shutdown::Both);
+            self.stream = Socket::Memory(placeholder);
+            return;
+        }
+
+        self.close();
+    }
+
+    /// Shuts down the write side of the connection once its last response
+    /// has been written, leaving the read side open so the peer sees the
+    /// end of the response before the connection is closed.
+    ///
+    /// Returns `false` if the socket can't be half closed, sockets without
+    /// an OS socket of their own are closed by their owner instead.
+    pub fn shutdown_write(&mut self) -> bool {
+        if !self.stream.has_os_socket() {
+            return false;
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_mut() {
+            tls::close(&mut self.stream, session);
+        }
+
+        self.write_shutdown = true;
+        let _ = self.stream.shutdown(Shutdown::Write);
+        true
+    }
+
+    /// Counts the connection against its address in the per-address
+    /// connection limit until the connection is closed.
+    pub(crate) fn set_ip_slot(&mut self, slot: Option<IpSlot>) {
+        self.ip_slot = slot;
+    }
+
+    /// Stops counting the connection against its address, e.g. once it's
+    /// handed off to another process without being closed.
+    pub(crate) fn release_ip_slot(&mut self) {
+        self.ip_slot = None;
+    }
+
+    /// Sets the given options on the socket, these only apply to tcp
+    /// sockets so anything else is left as is.
+    pub fn set_options(&self, options: &SocketOptions) -> std::io::Result<()> {
+        match &self.stream {
+            // Sockets owned by the QUIC driver are streams of a connection.
+            Socket::Completion(socket) if socket.fd() == NO_FD => Ok(()),
+            Socket::Tcp(_) | Socket::Completion(_) => options.apply(self.fd()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Corks the socket, any writes are accumulated and sent as full
+    /// segments until the socket is uncorked.
+    ///
+    /// This maps to `TCP_CORK` on Linux and `TCP_NOPUSH` on BSD / macOS,
+    /// on any other platform this is a no-op.
+    pub fn cork(&mut self) {
+        if !self.corked & self.stream.is_tcp() {
+            self.set_cork(true);
+            self.corked = true;
+        }
+    }
+
+    /// Uncorks the socket, flushing any partially accumulated segments.
+    pub fn uncork(&mut self) {
+        if self.corked {
+            self.set_cork(false);
+            self.corked = false;
+        }
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    ))]
+    fn set_cork(&self, enabled: bool) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let opt = libc::TCP_CORK;
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let opt = libc::TCP_NOPUSH;
+
+        let value = enabled as libc::c_int;
+        let res = unsafe {
+            libc::setsockopt(
+                self.fd(),
+                libc::IPPROTO_TCP,
+                opt,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        // Corking is purely an optimisation so failing is not fatal.
+        if res != 0 {
+            debug!(
+                "failed to set cork on socket {:?}: {}",
+                self.addr,
+                std::io::Error::last_os_error(),
+            );
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    )))]
+    fn set_cork(&self, _enabled: bool) {}
+}
+
+/// Advances the queue of buffers by the given amount of written bytes.
+///
+/// Fully drained buffers are popped from the front of the queue and the
+/// remaining offset is applied to the next buffer.
+fn advance_buffers(buffers: &mut VecDeque<Bytes>, mut amount: usize) {
+    while amount > 0 {
+        let front = match buffers.front_mut() {
+            Some(front) => front,
+            None => return,
+        };
+
+        if front.len() > amount {
+            front.advance(amount);
+            return;
+        }
+
+        amount -= front.len();
+        buffers.pop_front();
+    }
+
+    // Skip over any empty buffers so they are not re-offered.
+    while buffers.front().map(|b| b.is_empty()).unwrap_or(false) {
+        buffers.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use bytes::Bytes;
+
+    use super::{SocketStatus, StreamHandle};
+    use crate::net::MemoryHandle;
+
+    /// Reads back if `TCP_CORK` is set on the socket.
+    #[cfg(target_os = "linux")]
+    fn is_corked(handle: &StreamHandle) -> bool {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                handle.fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_CORK,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+        value != 0
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cork_sets_tcp_cork_until_uncorked() {
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let _peer = TcpStream::connect(server).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+
+        let mut handle = StreamHandle::new(stream, addr, server);
+        assert!(!is_corked(&handle));
+
+        handle.cork();
+        assert!(is_corked(&handle));
+        handle.cork();
+        assert!(is_corked(&handle));
+
+        handle.uncork();
+        assert!(!is_corked(&handle));
+    }
+
+    #[test]
+    fn partial_vectored_write_re_offers_the_rest() {
+        let peer = MemoryHandle::new();
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let server = "127.0.0.1:8080".parse().unwrap();
+        let mut handle = StreamHandle::from_memory(peer.clone(), addr, server);
+
+        let mut buffers: VecDeque<Bytes> = ["aaaaaaaaaa", "bbbbbbbbbb", "cccccccccc"]
+            .iter()
+            .map(|b| Bytes::from_static(b.as_bytes()))
+            .collect();
+
+        // Only one and a half buffers fit.
+        peer.set_write_limit(Some(15));
+        let status = handle.write_vectored(&mut buffers).unwrap();
+        assert!(matches!(status, SocketStatus::Complete(15)));
+        assert_eq!(buffers, ["bbbbb", "cccccccccc"]);
+        assert_eq!(&peer.take_written()[..], b"aaaaaaaaaabbbbb");
+
+        peer.set_write_limit(None);
+        let status = handle.write_vectored(&mut buffers).unwrap();
+        assert!(matches!(status, SocketStatus::Complete(15)));
+        assert!(buffers.is_empty());
+        assert_eq!(&peer.take_written()[..], b"bbbbbcccccccccc");
+    }
 }
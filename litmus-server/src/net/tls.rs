@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, IoSlice, Read, Write};
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+use pyo3::exceptions::PyValueError;
+use pyo3::types::PyBytes;
+use pyo3::{PyErr, PyObject, PyResult, Python};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    Certificate, PrivateKey, ProtocolVersion, RootCertStore, ServerConfig,
+    ServerConnection,
+};
+
+use super::socket::Socket;
+use super::{SocketStatus, TlsDetails};
+#[cfg(feature = "http3")]
+use crate::protocols::ALPN_H3;
+use crate::protocols::{ALPN_H2, ALPN_HTTP_11};
+
+/// The reason a connection is closed with when the client attempts to
+/// renegotiate the session.
+pub(crate) const RENEGOTIATION_ERROR: &str = "tls renegotiation refused";
+
+/// The size of a TLS record's header.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// The content type of a record carrying handshake messages.
+const HANDSHAKE_RECORD: u8 = 22;
+
+/// If clients must present a certificate signed by the client CA.
+#[derive(Copy, Clone, Debug)]
+pub enum ClientAuthPolicy {
+    /// Clients without a valid certificate fail the handshake.
+    Required,
+
+    /// Clients can connect without a certificate, but any certificate
+    /// given must be valid.
+    Optional,
+}
+
+/// The certificates picked by the server name clients ask for using SNI,
+/// clients asking for any other name are given the default certificate.
+#[derive(Default)]
+pub struct SniConfig {
+    /// The `(cert_path, key_path)` PEM files served for each hostname,
+    /// `*.example.com` matches any single label below `example.com`.
+    pub certs: HashMap<String, (String, String)>,
+
+    /// Called with the server name, or `None`, at the start of every
+    /// handshake returning the `(cert_pem, key_pem)` to serve or `None`
+    /// to fall back to `certs`.
+    pub callback: Option<PyObject>,
+}
+
+/// The TLS configuration shared by every connection accepted by the server.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Loads the certificate chain and private key from the given PEM files.
+    ///
+    /// Clients can negotiate HTTP/1.1 using ALPN, and HTTP/2 as well if
+    /// `http2` is set.
+    ///
+    /// If `client_ca` is given clients are asked for a certificate which
+    /// is verified against the CA certificates in the PEM file.
+    ///
+    /// If `sni` is given the certificate is picked by the server name of
+    /// each handshake, the given certificate being the default.
+    pub fn from_pem_files(
+        cert_path: &str,
+        key_path: &str,
+        http2: bool,
+        client_ca: Option<(&str, ClientAuthPolicy)>,
+        sni: Option<SniConfig>,
+    ) -> PyResult<Self> {
+        let certs = read_certs(cert_path)?
+            .into_iter()
+            .map(Certificate)
+            .collect::<Vec<_>>();
+        let key = read_key_file(key_path)?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some((ca_path, policy)) => {
+                let mut roots = RootCertStore::empty();
+                let (_, invalid) =
+                    roots.add_parsable_certificates(&read_certs(ca_path)?);
+                if invalid > 0 {
+                    return Err(PyValueError::new_err(format!(
+                        "invalid client CA certificate in {:?}",
+                        ca_path
+                    )));
+                }
+
+                builder.with_client_cert_verifier(match policy {
+                    ClientAuthPolicy::Required => {
+                        AllowAnyAuthenticatedClient::new(roots)
+                    },
+                    ClientAuthPolicy::Optional => {
+                        AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                    },
+                })
+            },
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = match sni {
+            Some(sni) => {
+                let default = Arc::new(certified_key(certs, &key)?);
+                builder.with_cert_resolver(Arc::new(SniResolver::new(sni, default)?))
+            },
+            None => builder.with_single_cert(certs, key).map_err(|e| {
+                PyValueError::new_err(format!("invalid certificate: {}", e))
+            })?,
+        };
+
+        // The first protocol the client also offers is picked.
+        config.alpn_protocols = if http2 {
+            vec![ALPN_H2.to_vec(), ALPN_HTTP_11.to_vec()]
+        } else {
+            vec![ALPN_HTTP_11.to_vec()]
+        };
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Creates the TLS session for a newly accepted connection.
+    pub(crate) fn new_session(&self) -> PyResult<ServerConnection> {
+        ServerConnection::new(self.config.clone())
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// The config used for the TLS handshake of QUIC connections, which
+    /// only offers HTTP/3 using ALPN.
+    #[cfg(feature = "http3")]
+    pub(crate) fn quic_config(&self) -> Arc<ServerConfig> {
+        let mut config = (*self.config).clone();
+        config.alpn_protocols = vec![ALPN_H3.to_vec()];
+        Arc::new(config)
+    }
+}
+
+/// Picks the certificate of each handshake by the server name the client
+/// asked for.
+struct SniResolver {
+    callback: Option<PyObject>,
+
+    /// The certificates of exact hostnames.
+    exact: HashMap<String, Arc<CertifiedKey>>,
+
+    /// The certificates of wildcard hostnames keyed by the parent domain,
+    /// e.g. `example.com` for `*.example.com`.
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    fn new(sni: SniConfig, default: Arc<CertifiedKey>) -> PyResult<Self> {
+        let mut exact = HashMap::new();
+        let mut wildcard = HashMap::new();
+
+        for (pattern, (cert_path, key_path)) in sni.certs {
+            let certs = read_certs(&cert_path)?
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            let key = Arc::new(certified_key(certs, &read_key_file(&key_path)?)?);
+
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(parent) if !parent.is_empty() && !parent.contains('*') => {
+                    wildcard.insert(parent.to_string(), key);
+                },
+                _ if !pattern.is_empty() && !pattern.contains('*') => {
+                    exact.insert(pattern, key);
+                },
+                _ => {
+                    return Err(PyValueError::new_err(format!(
+                        "invalid tls sni hostname {:?}, expected a hostname or \
+                        a wildcard such as '*.example.com'",
+                        pattern
+                    )))
+                },
+            }
+        }
+
+        Ok(Self {
+            callback: sni.callback,
+            exact,
+            wildcard,
+            default,
+        })
+    }
+
+    /// Asks the callback for the certificate of the server name.
+    fn resolve_with_callback(
+        callback: &PyObject,
+        name: Option<&str>,
+    ) -> PyResult<Option<CertifiedKey>> {
+        let pem = Python::with_gil(|py| -> PyResult<_> {
+            let result = callback.call1(py, (name,))?;
+            if result.is_none(py) {
+                return Ok(None);
+            }
+
+            let (cert, key): (&PyBytes, &PyBytes) = result.extract(py)?;
+            Ok(Some((cert.as_bytes().to_vec(), key.as_bytes().to_vec())))
+        })?;
+
+        let (cert, key) = match pem {
+            Some(pem) => pem,
+            None => return Ok(None),
+        };
+
+        let certs = rustls_pemfile::certs(&mut &cert[..])?;
+        if certs.is_empty() {
+            return Err(PyValueError::new_err(
+                "no certificates found in the tls sni callback's certificate",
+            ));
+        }
+
+        let key = read_key(&mut &key[..])?.ok_or_else(|| {
+            PyValueError::new_err("no private key found in the tls sni callback's key")
+        })?;
+        let certs = certs.into_iter().map(Certificate).collect();
+        Ok(Some(certified_key(certs, &key)?))
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().map(|n| n.to_ascii_lowercase());
+
+        // A failing callback fails the handshake rather than handing out a
+        // certificate the client didn't ask for.
+        if let Some(callback) = self.callback.as_ref() {
+            match Self::resolve_with_callback(callback, name.as_deref()) {
+                Ok(Some(key)) => return Some(Arc::new(key)),
+                Ok(None) => {},
+                Err(e) => {
+                    Python::with_gil(|py| e.print(py));
+                    return None;
+                },
+            }
+        }
+
+        let name = match name {
+            Some(name) => name,
+            None => return Some(self.default.clone()),
+        };
+
+        if let Some(key) = self.exact.get(&name) {
+            return Some(key.clone());
+        }
+
+        let wildcard = name
+            .split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent));
+        Some(wildcard.unwrap_or(&self.default).clone())
+    }
+}
+
+/// Pairs the certificate chain with its private key ready to be served.
+fn certified_key(certs: Vec<Certificate>, key: &PrivateKey) -> PyResult<CertifiedKey> {
+    let key = rustls::sign::any_supported_type(key)
+        .map_err(|e| PyValueError::new_err(format!("invalid private key: {}", e)))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Reads the DER of every certificate in the PEM file, at least one
+/// certificate must be found.
+fn read_certs(path: &str) -> PyResult<Vec<Vec<u8>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "no certificates found in {:?}",
+            path
+        )));
+    }
+
+    Ok(certs)
+}
+
+/// Reads the private key in the PEM file.
+fn read_key_file(path: &str) -> PyResult<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_key(&mut reader)?.ok_or_else(|| {
+        PyValueError::new_err(format!("no private key found in {:?}", path))
+    })
+}
+
+/// Reads the first private key in the PEM if there is one.
+fn read_key(reader: &mut dyn BufRead) -> PyResult<Option<PrivateKey>> {
+    loop {
+        match rustls_pemfile::read_one(reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => {
+                return Ok(Some(PrivateKey(key)))
+            },
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Follows the records sent by the client to spot a renegotiation attempt.
+///
+/// rustls refuses to renegotiate a TLS 1.2 session by sending a warning
+/// alert and ignoring the new handshake, which leaves the client waiting
+/// on a handshake that never happens. Once the session is established
+/// the client has no reason to send another handshake record so one is
+/// taken as an attempt to renegotiate.
+#[derive(Default)]
+pub(crate) struct RecordTracker {
+    /// The header of the current record received so far.
+    header: [u8; RECORD_HEADER_LEN],
+    header_len: usize,
+
+    /// The bytes of the current record's body yet to be received.
+    body_remaining: usize,
+}
+
+impl RecordTracker {
+    /// Follows the records in the data received, returning if a handshake
+    /// record was started.
+    fn track(&mut self, mut data: &[u8]) -> bool {
+        let mut handshake = false;
+        while !data.is_empty() {
+            if self.body_remaining > 0 {
+                let len = self.body_remaining.min(data.len());
+                self.body_remaining -= len;
+                data = &data[len..];
+                continue;
+            }
+
+            let len = (RECORD_HEADER_LEN - self.header_len).min(data.len());
+            self.header[self.header_len..][..len].copy_from_slice(&data[..len]);
+            self.header_len += len;
+            data = &data[len..];
+
+            if self.header_len == RECORD_HEADER_LEN {
+                handshake |= self.header[0] == HANDSHAKE_RECORD;
+                self.body_remaining =
+                    u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                self.header_len = 0;
+            }
+        }
+
+        handshake
+    }
+}
+
+/// Reads from the socket, passing the data read to the record tracker.
+struct TrackedReader<'a> {
+    stream: &'a mut Socket,
+    records: &'a mut RecordTracker,
+
+    /// If a handshake record was started by the data read.
+    handshake: bool,
+}
+
+impl Read for TrackedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        self.handshake |= self.records.track(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Reads any available ciphertext from the socket and the resulting
+/// plaintext into the buffer.
+///
+/// This returns `WouldBlock` while the handshake is in progress or no
+/// complete record has been received yet.
+///
+/// If the session fails, or the client attempts to renegotiate it, the
+/// connection is closed with the reason set as the error.
+pub(crate) fn read(
+    stream: &mut Socket,
+    session: &mut ServerConnection,
+    records: &mut RecordTracker,
+    error: &mut Option<String>,
+    buffer: &mut BytesMut,
+) -> PyResult<SocketStatus> {
+    let established = !session.is_handshaking();
+    let mut reader = TrackedReader {
+        stream,
+        records,
+        handshake: false,
+    };
+
+    let result = session.read_tls(&mut reader);
+    let renegotiating = established & reader.handshake;
+    let eof = match result {
+        Ok(n) => n == 0,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
+            return Ok(SocketStatus::Disconnect)
+        },
+        Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => {
+            return Ok(SocketStatus::Disconnect)
+        },
+        Err(e) => return Err(PyErr::from(e)),
+    };
+
+    if renegotiating {
+        debug!("tls renegotiation attempted, closing connection");
+        *error = Some(RENEGOTIATION_ERROR.to_string());
+        close(stream, session);
+        return Ok(SocketStatus::Disconnect);
+    }
+
+    if let Err(e) = session.process_new_packets() {
+        debug!("tls error, closing connection: {}", e);
+        *error = Some(format!("tls error: {}", e));
+
+        // Attempt to send the alert describing the error.
+        let _ = session.write_tls(stream);
+        return Ok(SocketStatus::Disconnect);
+    }
+
+    // The handshake produces data to send even while reading.
+    if let SocketStatus::Disconnect = flush(stream, session)? {
+        return Ok(SocketStatus::Disconnect);
+    }
+
+    let data = buffer.chunk_mut();
+    let slice = unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr(), data.len()) };
+
+    let len = match session.reader().read(slice) {
+        Ok(n) => n,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            return if eof {
+                Ok(SocketStatus::Complete(0))
+            } else {
+                Ok(SocketStatus::WouldBlock)
+            };
+        },
+        // The peer closed the connection without a close_notify.
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+        Err(e) => return Err(PyErr::from(e)),
+    };
+
+    unsafe {
+        buffer.advance_mut(len);
+    }
+
+    Ok(SocketStatus::Complete(len))
+}
+
+/// Encrypts the data from the supplied buffer and writes as much of
+/// the resulting ciphertext to the socket as possible, returning the
+/// number of plaintext bytes consumed.
+pub(crate) fn write(
+    stream: &mut Socket,
+    session: &mut ServerConnection,
+    buffer: &mut BytesMut,
+) -> PyResult<SocketStatus> {
+    // Anything still pending from the last write goes first so the
+    // amount buffered by the session stays bounded.
+    if let status @ (SocketStatus::WouldBlock | SocketStatus::Disconnect) =
+        flush(stream, session)?
+    {
+        return Ok(status);
+    }
+
+    let len = session.writer().write(buffer)?;
+    let _ = buffer.split_to(len);
+
+    if let SocketStatus::Disconnect = flush(stream, session)? {
+        return Ok(SocketStatus::Disconnect);
+    }
+
+    Ok(SocketStatus::Complete(len))
+}
+
+/// Encrypts the data from the supplied slices the same as `write`.
+pub(crate) fn write_vectored(
+    stream: &mut Socket,
+    session: &mut ServerConnection,
+    slices: &[IoSlice],
+) -> PyResult<SocketStatus> {
+    if let status @ (SocketStatus::WouldBlock | SocketStatus::Disconnect) =
+        flush(stream, session)?
+    {
+        return Ok(status);
+    }
+
+    let len = session.writer().write_vectored(slices)?;
+
+    if let SocketStatus::Disconnect = flush(stream, session)? {
+        return Ok(SocketStatus::Disconnect);
+    }
+
+    Ok(SocketStatus::Complete(len))
+}
+
+/// What was negotiated by the session's handshake, `None` until the
+/// handshake has completed.
+pub(crate) fn details(session: &ServerConnection) -> Option<TlsDetails> {
+    if session.is_handshaking() {
+        return None;
+    }
+
+    let version = session
+        .protocol_version()
+        .and_then(|version| match version {
+            ProtocolVersion::TLSv1_2 => Some("TLSv1.2"),
+            ProtocolVersion::TLSv1_3 => Some("TLSv1.3"),
+            other => other.as_str(),
+        });
+
+    // rustls marks the TLS 1.3 suites, which don't name the key exchange,
+    // apart from those of TLS 1.2.
+    let cipher = session
+        .negotiated_cipher_suite()
+        .and_then(|suite| suite.suite().as_str())
+        .map(|name| match name.strip_prefix("TLS13_") {
+            Some(name) => format!("TLS_{}", name),
+            None => name.to_string(),
+        });
+
+    Some(TlsDetails {
+        version,
+        cipher,
+        alpn_protocol: session.alpn_protocol().map(<[u8]>::to_vec),
+    })
+}
+
+/// Sends the close_notify alert to the peer, this is best effort.
+pub(crate) fn close(stream: &mut Socket, session: &mut ServerConnection) {
+    session.send_close_notify();
+    let _ = flush(stream, session);
+}
+
+/// Writes any pending ciphertext to the socket.
+fn flush(stream: &mut Socket, session: &mut ServerConnection) -> PyResult<SocketStatus> {
+    let mut total = 0;
+    while session.wants_write() {
+        match session.write_tls(stream) {
+            Ok(n) => total += n,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                return Ok(SocketStatus::WouldBlock)
+            },
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
+                return Ok(SocketStatus::Disconnect)
+            },
+            Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => {
+                return Ok(SocketStatus::Disconnect)
+            },
+            Err(e) => return Err(PyErr::from(e)),
+        }
+    }
+
+    Ok(SocketStatus::Complete(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecordTracker;
+
+    #[test]
+    fn records_split_across_reads_are_tracked() {
+        let mut records = RecordTracker::default();
+
+        // An application data record with a handshake type byte in its body.
+        assert!(!records.track(&[23, 3, 3]));
+        assert!(!records.track(&[0, 3, 22]));
+        assert!(!records.track(&[22, 22]));
+
+        // A handshake record whose header is split.
+        assert!(!records.track(&[23, 3, 3, 0, 0, 22, 3]));
+        assert!(records.track(&[3, 0, 1]));
+        assert!(!records.track(&[1, 23, 3, 3, 0, 0]));
+    }
+}
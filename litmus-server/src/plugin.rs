@@ -0,0 +1,341 @@
+//! Custom wire protocols served on a listener in place of HTTP, e.g.
+//! Redis' RESP or a bespoke RPC protocol.
+//!
+//! A protocol plugs into the same machinery as the built in protocols, the
+//! client reads from and writes to the socket and the protocol is only
+//! handed what was read and asked for what to write. Each listener bound
+//! with a `ProtocolFactory` creates a protocol for every connection it
+//! accepts, see `Server::connect()`.
+//!
+//! Protocols written in Python are adapted by `PyProtocolFactory`, the
+//! Python object follows `asyncio.Protocol` with a `ProtocolTransport`
+//! given to `connection_made()`.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::traits::BaseTransport;
+use crate::transport::Transport;
+
+/// A wire protocol spoken over a single connection.
+///
+/// Every method is called on the event loop's thread.
+pub trait Protocol: Send {
+    /// The connection has been accepted, the handle stays valid until
+    /// `connection_lost()` is called.
+    fn connection_made(&mut self, conn: Connection) -> PyResult<()>;
+
+    /// Data has been read from the socket, anything left in the buffer is
+    /// handed over again along with whatever is read next.
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()>;
+
+    /// Fills the buffer with anything to be written to the socket, called
+    /// while the writer is registered with `Connection::resume_writing()`.
+    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()>;
+
+    /// The peer has shut down its side of the connection, returning `true`
+    /// keeps the connection half open until the protocol closes it.
+    fn eof_received(&mut self) -> PyResult<bool> {
+        Ok(false)
+    }
+
+    /// The connection has been closed.
+    fn connection_lost(&mut self) -> PyResult<()>;
+
+    /// If the connection should be closed once everything written has been
+    /// flushed to the socket.
+    fn is_closing(&self) -> bool {
+        false
+    }
+
+    /// If the connection is exempt from the keep alive timeout while idle.
+    fn is_long_lived(&self) -> bool {
+        false
+    }
+
+    /// Starts draining the connection ahead of the server shutting down,
+    /// returning if the connection can be closed immediately.
+    fn drain(&mut self) -> PyResult<bool> {
+        Ok(true)
+    }
+}
+
+/// Creates the protocol of each connection accepted on a listener.
+pub trait ProtocolFactory: Send + Sync {
+    fn create(&self) -> Box<dyn Protocol>;
+}
+
+/// A protocol's handle on its connection.
+#[derive(Clone)]
+pub struct Connection {
+    transport: Transport,
+}
+
+impl Connection {
+    pub(crate) fn new(transport: Transport) -> Self {
+        Self { transport }
+    }
+
+    /// The address of the peer.
+    pub fn client(&self) -> SocketAddr {
+        self.transport.client
+    }
+
+    /// The address of the listener the connection was accepted on.
+    pub fn server(&self) -> SocketAddr {
+        self.transport.server
+    }
+
+    /// If the connection is encrypted with TLS.
+    pub fn tls(&self) -> bool {
+        self.transport.tls
+    }
+
+    /// Closes the connection immediately, anything not yet written is
+    /// discarded, see `Protocol::is_closing()` to close once flushed.
+    pub fn close(&self) -> PyResult<()> {
+        self.transport.close()
+    }
+
+    /// Resets the connection with a TCP RST, anything not yet written is
+    /// discarded and the peer sees the connection fail rather than end.
+    pub fn abort(&self) -> PyResult<()> {
+        self.transport.abort()
+    }
+
+    /// Stops handing the protocol anything read until reading is resumed.
+    pub fn pause_reading(&self) -> PyResult<()> {
+        self.transport.pause_reading()
+    }
+
+    pub fn resume_reading(&self) -> PyResult<()> {
+        self.transport.resume_reading()
+    }
+
+    /// Asks the protocol for something to write once the socket is
+    /// writable, the writer is removed once nothing is left to write.
+    pub fn resume_writing(&self) -> PyResult<()> {
+        self.transport.resume_writing()
+    }
+}
+
+/// Adapts a Python protocol factory, e.g. a subclass of
+/// `litmus.Protocol`, the factory is called with no arguments for every
+/// connection.
+///
+/// Exceptions raised by the protocol are printed and the connection is
+/// closed.
+pub struct PyProtocolFactory {
+    factory: Arc<PyObject>,
+}
+
+impl PyProtocolFactory {
+    pub fn new(factory: PyObject) -> Self {
+        Self {
+            factory: Arc::new(factory),
+        }
+    }
+}
+
+impl ProtocolFactory for PyProtocolFactory {
+    fn create(&self) -> Box<dyn Protocol> {
+        Box::new(PyProtocol {
+            factory: self.factory.clone(),
+            protocol: None,
+            state: Arc::new(TransportState::default()),
+        })
+    }
+}
+
+/// What a Python protocol has written and if it's closing, shared with
+/// its transport.
+#[derive(Default)]
+struct TransportState {
+    outgoing: Mutex<BytesMut>,
+    closing: AtomicBool,
+    closed: AtomicBool,
+}
+
+struct PyProtocol {
+    factory: Arc<PyObject>,
+
+    /// The Python protocol once the connection has been made.
+    protocol: Option<(PyObject, Connection)>,
+
+    state: Arc<TransportState>,
+}
+
+impl PyProtocol {
+    /// Calls a method of the Python protocol, closing the connection if it
+    /// raises an exception.
+    fn call(
+        &self,
+        py: Python,
+        name: &str,
+        args: impl IntoPy<Py<pyo3::types::PyTuple>>,
+    ) -> PyResult<Option<PyObject>> {
+        let (protocol, conn) = match self.protocol.as_ref() {
+            Some(protocol) => protocol,
+            None => return Ok(None),
+        };
+
+        match protocol.call_method1(py, name, args) {
+            Ok(result) => Ok(Some(result)),
+            Err(e) => {
+                e.print(py);
+                conn.close()?;
+                Ok(None)
+            },
+        }
+    }
+}
+
+impl Protocol for PyProtocol {
+    fn connection_made(&mut self, conn: Connection) -> PyResult<()> {
+        self.state = Arc::new(TransportState::default());
+
+        Python::with_gil(|py| {
+            let protocol = match self.factory.call0(py) {
+                Ok(protocol) => protocol,
+                Err(e) => {
+                    e.print(py);
+                    return conn.close();
+                },
+            };
+
+            let transport = Py::new(
+                py,
+                ProtocolTransport {
+                    conn: conn.clone(),
+                    state: self.state.clone(),
+                },
+            )?;
+
+            self.protocol = Some((protocol, conn));
+            self.call(py, "connection_made", (transport,))?;
+            Ok(())
+        })
+    }
+
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        Python::with_gil(|py| {
+            let data = PyBytes::new(py, buffer);
+            buffer.clear();
+            self.call(py, "data_received", (data,))?;
+            Ok(())
+        })
+    }
+
+    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        let mut outgoing = self.state.outgoing.lock().unwrap();
+        buffer.extend_from_slice(&outgoing);
+        outgoing.clear();
+        Ok(())
+    }
+
+    fn eof_received(&mut self) -> PyResult<bool> {
+        Python::with_gil(|py| match self.call(py, "eof_received", ())? {
+            Some(keep_open) => keep_open.is_true(py),
+            None => Ok(false),
+        })
+    }
+
+    fn connection_lost(&mut self) -> PyResult<()> {
+        self.state.closed.store(true, Ordering::Relaxed);
+        Python::with_gil(|py| {
+            self.call(py, "connection_lost", ())?;
+            self.protocol = None;
+            Ok(())
+        })
+    }
+
+    fn is_closing(&self) -> bool {
+        self.state.closing.load(Ordering::Relaxed)
+    }
+}
+
+/// The transport handed to a Python protocol's `connection_made()`.
+#[pyclass]
+pub struct ProtocolTransport {
+    conn: Connection,
+    state: Arc<TransportState>,
+}
+
+#[pymethods]
+impl ProtocolTransport {
+    /// Queues the data to be written to the socket, this is ignored once
+    /// the transport is closing.
+    fn write(&self, data: &[u8]) -> PyResult<()> {
+        if self.state.closing.load(Ordering::Relaxed)
+            | self.state.closed.load(Ordering::Relaxed)
+        {
+            return Ok(());
+        }
+
+        self.state.outgoing.lock().unwrap().extend_from_slice(data);
+        self.conn.resume_writing()
+    }
+
+    /// Closes the connection once everything written has been flushed.
+    fn close(&self) -> PyResult<()> {
+        if self.state.closing.swap(true, Ordering::Relaxed)
+            | self.state.closed.load(Ordering::Relaxed)
+        {
+            return Ok(());
+        }
+
+        // The writer closes the connection once it finds nothing to write.
+        self.conn.resume_writing()
+    }
+
+    /// Resets the connection immediately discarding anything not written.
+    fn abort(&self) -> PyResult<()> {
+        if self.state.closed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        self.state.closing.store(true, Ordering::Relaxed);
+        self.conn.abort()
+    }
+
+    /// If the transport is closing or has closed.
+    fn is_closing(&self) -> bool {
+        self.state.closing.load(Ordering::Relaxed)
+            | self.state.closed.load(Ordering::Relaxed)
+    }
+
+    fn pause_reading(&self) -> PyResult<()> {
+        if self.state.closed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        self.conn.pause_reading()
+    }
+
+    fn resume_reading(&self) -> PyResult<()> {
+        if self.state.closed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        self.conn.resume_reading()
+    }
+
+    /// The `(host, port)` of the client.
+    #[getter]
+    fn client(&self) -> (String, u16) {
+        let client = self.conn.client();
+        (client.ip().to_string(), client.port())
+    }
+
+    /// The `(host, port)` of the listener the connection was accepted on.
+    #[getter]
+    fn server(&self) -> (String, u16) {
+        let server = self.conn.server();
+        (server.ip().to_string(), server.port())
+    }
+}
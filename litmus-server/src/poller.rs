@@ -0,0 +1,507 @@
+//! A native readiness poller used in place of the asyncio event loop.
+//!
+//! Sockets are registered with an epoll (Linux) or kqueue (BSD / macOS)
+//! instance owned by the server, so registering and removing readers and
+//! writers is a plain syscall rather than a call into Python holding the GIL.
+//! The poller's own file descriptor is watched by the asyncio event loop,
+//! once it becomes readable the ready events are dispatched in a single
+//! batch.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::event_loop::SocketFd;
+
+/// The max number of events handled each time the poller is woken up,
+/// any remaining events are picked up on the next wakeup.
+const MAX_EVENTS: usize = 1024;
+
+/// What a registered file descriptor belongs to.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Token {
+    /// The listener at the given index.
+    Listener(usize),
+
+    /// The client at the given index.
+    Client(usize),
+}
+
+/// The readiness a file descriptor is registered for.
+#[derive(Copy, Clone)]
+struct Interest {
+    token: Token,
+    read: bool,
+    write: bool,
+}
+
+/// A file descriptor that is ready to be read from or written to.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Event {
+    pub(crate) fd: SocketFd,
+    pub(crate) token: Token,
+    pub(crate) readable: bool,
+    pub(crate) writable: bool,
+}
+
+pub(crate) struct Poller {
+    selector: sys::Selector,
+
+    /// The readiness each file descriptor is registered for.
+    interests: Mutex<HashMap<SocketFd, Interest>>,
+
+    /// The clients whose socket should be closed on the next wakeup.
+    closing: Mutex<Vec<usize>>,
+
+    /// Written to in order to wake up the poller, e.g. to close a socket.
+    waker: UnixStream,
+
+    /// The end of the waker registered with the selector.
+    wake_receiver: UnixStream,
+}
+
+impl Poller {
+    /// Creates a new poller, this fails on platforms without epoll or
+    /// kqueue.
+    pub(crate) fn new() -> io::Result<Self> {
+        let selector = sys::Selector::new()?;
+
+        let (waker, wake_receiver) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        wake_receiver.set_nonblocking(true)?;
+        selector.register(wake_receiver.as_raw_fd(), (false, false), (true, false))?;
+
+        Ok(Self {
+            selector,
+            interests: Mutex::new(HashMap::new()),
+            closing: Mutex::new(Vec::new()),
+            waker,
+            wake_receiver,
+        })
+    }
+
+    /// The file descriptor of the poller, this becomes readable once any
+    /// registered file descriptor is ready.
+    pub(crate) fn fd(&self) -> SocketFd {
+        self.selector.fd()
+    }
+
+    /// Start monitoring the file descriptor for read readiness.
+    pub(crate) fn add_reader(&self, fd: SocketFd, token: Token) -> io::Result<()> {
+        self.update(fd, Some(token), |interest| interest.read = true)
+    }
+
+    /// Stop monitoring the file descriptor for read readiness.
+    pub(crate) fn remove_reader(&self, fd: SocketFd) -> io::Result<()> {
+        self.update(fd, None, |interest| interest.read = false)
+    }
+
+    /// Start monitoring the file descriptor for write readiness.
+    pub(crate) fn add_writer(&self, fd: SocketFd, token: Token) -> io::Result<()> {
+        self.update(fd, Some(token), |interest| interest.write = true)
+    }
+
+    /// Stop monitoring the file descriptor for write readiness.
+    pub(crate) fn remove_writer(&self, fd: SocketFd) -> io::Result<()> {
+        self.update(fd, None, |interest| interest.write = false)
+    }
+
+    /// If the file descriptor is still registered by the given token for
+    /// read and write readiness respectively.
+    ///
+    /// Handling one event can remove the listeners of another event in the
+    /// same batch, these events are stale and should be skipped.
+    pub(crate) fn interest(&self, fd: SocketFd, token: Token) -> (bool, bool) {
+        match self.interests().get(&fd) {
+            Some(interest) if interest.token == token => (interest.read, interest.write),
+            _ => (false, false),
+        }
+    }
+
+    /// Schedules the socket of the client at the given index to be closed
+    /// on the next wakeup.
+    pub(crate) fn close_socket(&self, index: usize) -> io::Result<()> {
+        self.closing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(index);
+        self.wake()
+    }
+
+    /// Takes the clients whose socket is scheduled to be closed.
+    pub(crate) fn take_closing(&self) -> Vec<usize> {
+        std::mem::take(&mut *self.closing.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Gets the events which are ready without blocking.
+    pub(crate) fn poll(&self, events: &mut Vec<Event>) -> io::Result<()> {
+        events.clear();
+
+        let mut ready = Vec::with_capacity(MAX_EVENTS);
+        self.selector.select(&mut ready)?;
+
+        let interests = self.interests();
+        for (fd, readable, writable) in ready {
+            if fd == self.wake_receiver.as_raw_fd() {
+                self.clear_wakeups()?;
+                continue;
+            }
+
+            if let Some(interest) = interests.get(&fd) {
+                events.push(Event {
+                    fd,
+                    token: interest.token,
+                    readable,
+                    writable,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn interests(&self) -> MutexGuard<'_, HashMap<SocketFd, Interest>> {
+        self.interests.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Updates the readiness the file descriptor is registered for, the
+    /// token is only needed if the file descriptor may not be registered.
+    fn update(
+        &self,
+        fd: SocketFd,
+        token: Option<Token>,
+        apply: impl FnOnce(&mut Interest),
+    ) -> io::Result<()> {
+        let mut interests = self.interests();
+
+        let previous = interests.get(&fd).copied();
+        let mut interest = match (previous, token) {
+            (Some(interest), Some(token)) => Interest { token, ..interest },
+            (Some(interest), None) => interest,
+            (None, Some(token)) => Interest {
+                token,
+                read: false,
+                write: false,
+            },
+            (None, None) => return Ok(()),
+        };
+        apply(&mut interest);
+
+        let before = previous
+            .map(|i| (i.read, i.write))
+            .unwrap_or((false, false));
+        let after = (interest.read, interest.write);
+        self.selector.register(fd, before, after)?;
+
+        if interest.read | interest.write {
+            interests.insert(fd, interest);
+        } else {
+            interests.remove(&fd);
+        }
+
+        Ok(())
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        match (&self.waker).write(&[1]) {
+            // The poller is already due to wake up.
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            other => other.map(|_| ()),
+        }
+    }
+
+    fn clear_wakeups(&self) -> io::Result<()> {
+        let mut buffer = [0; 64];
+        loop {
+            match (&self.wake_receiver).read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    use super::MAX_EVENTS;
+    use crate::event_loop::SocketFd;
+
+    pub(super) struct Selector {
+        epoll: OwnedFd,
+    }
+
+    impl Selector {
+        pub(super) fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let epoll = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(Self { epoll })
+        }
+
+        pub(super) fn fd(&self) -> SocketFd {
+            self.epoll.as_raw_fd()
+        }
+
+        /// Changes the readiness the file descriptor is registered for
+        /// from `before` to `after`, given as `(read, write)`.
+        pub(super) fn register(
+            &self,
+            fd: SocketFd,
+            before: (bool, bool),
+            after: (bool, bool),
+        ) -> io::Result<()> {
+            let op = match (before != (false, false), after != (false, false)) {
+                (false, false) => return Ok(()),
+                (false, true) => libc::EPOLL_CTL_ADD,
+                (true, true) => libc::EPOLL_CTL_MOD,
+                (true, false) => libc::EPOLL_CTL_DEL,
+            };
+
+            let mut flags = 0;
+            if after.0 {
+                flags |= libc::EPOLLIN;
+            }
+            if after.1 {
+                flags |= libc::EPOLLOUT;
+            }
+
+            let mut event = libc::epoll_event {
+                events: flags as u32,
+                u64: fd as u64,
+            };
+
+            let res = unsafe { libc::epoll_ctl(self.fd(), op, fd, &mut event) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        }
+
+        /// Gets the file descriptors which are ready without blocking as
+        /// `(fd, readable, writable)`.
+        pub(super) fn select(
+            &self,
+            ready: &mut Vec<(SocketFd, bool, bool)>,
+        ) -> io::Result<()> {
+            let mut events: Vec<libc::epoll_event> = Vec::with_capacity(MAX_EVENTS);
+
+            let n = unsafe {
+                libc::epoll_wait(self.fd(), events.as_mut_ptr(), MAX_EVENTS as i32, 0)
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(()),
+                    _ => Err(e),
+                };
+            }
+
+            unsafe { events.set_len(n as usize) };
+
+            // Errors and hang ups wake up both sides as asyncio does, the
+            // following read or write then reports them.
+            for event in events {
+                let flags = event.events as i32;
+                ready.push((
+                    event.u64 as SocketFd,
+                    flags & !libc::EPOLLOUT != 0,
+                    flags & !libc::EPOLLIN != 0,
+                ));
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+))]
+mod sys {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::{mem, ptr};
+
+    use super::MAX_EVENTS;
+    use crate::event_loop::SocketFd;
+
+    pub(super) struct Selector {
+        kqueue: OwnedFd,
+    }
+
+    impl Selector {
+        pub(super) fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::kqueue() };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let kqueue = unsafe { OwnedFd::from_raw_fd(fd) };
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { kqueue })
+        }
+
+        pub(super) fn fd(&self) -> SocketFd {
+            self.kqueue.as_raw_fd()
+        }
+
+        /// Changes the readiness the file descriptor is registered for
+        /// from `before` to `after`, given as `(read, write)`.
+        pub(super) fn register(
+            &self,
+            fd: SocketFd,
+            before: (bool, bool),
+            after: (bool, bool),
+        ) -> io::Result<()> {
+            let filters = [
+                (libc::EVFILT_READ, before.0, after.0),
+                (libc::EVFILT_WRITE, before.1, after.1),
+            ];
+
+            let mut changes = Vec::with_capacity(filters.len());
+            for (filter, was_set, set) in filters.iter().copied() {
+                if was_set == set {
+                    continue;
+                }
+
+                let mut change: libc::kevent = unsafe { mem::zeroed() };
+                change.ident = fd as _;
+                change.filter = filter;
+                change.flags = if set { libc::EV_ADD } else { libc::EV_DELETE };
+                changes.push(change);
+            }
+
+            if changes.is_empty() {
+                return Ok(());
+            }
+
+            let res = unsafe {
+                libc::kevent(
+                    self.fd(),
+                    changes.as_ptr(),
+                    changes.len() as _,
+                    ptr::null_mut(),
+                    0,
+                    ptr::null(),
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        }
+
+        /// Gets the file descriptors which are ready without blocking as
+        /// `(fd, readable, writable)`.
+        pub(super) fn select(
+            &self,
+            ready: &mut Vec<(SocketFd, bool, bool)>,
+        ) -> io::Result<()> {
+            let mut events: Vec<libc::kevent> = Vec::with_capacity(MAX_EVENTS);
+            let timeout = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+
+            let n = unsafe {
+                libc::kevent(
+                    self.fd(),
+                    ptr::null(),
+                    0,
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as _,
+                    &timeout,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(()),
+                    _ => Err(e),
+                };
+            }
+
+            unsafe { events.set_len(n as usize) };
+
+            for event in events {
+                if event.flags & libc::EV_ERROR != 0 {
+                    continue;
+                }
+
+                ready.push((
+                    event.ident as SocketFd,
+                    event.filter == libc::EVFILT_READ,
+                    event.filter == libc::EVFILT_WRITE,
+                ));
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Neither epoll nor kqueue is available, a selector can never be created.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+)))]
+mod sys {
+    use std::convert::Infallible;
+    use std::io;
+
+    use crate::event_loop::SocketFd;
+
+    pub(super) struct Selector(Infallible);
+
+    impl Selector {
+        pub(super) fn new() -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the native backend is not supported on this platform",
+            ))
+        }
+
+        pub(super) fn fd(&self) -> SocketFd {
+            match self.0 {}
+        }
+
+        pub(super) fn register(
+            &self,
+            _fd: SocketFd,
+            _before: (bool, bool),
+            _after: (bool, bool),
+        ) -> io::Result<()> {
+            match self.0 {}
+        }
+
+        pub(super) fn select(
+            &self,
+            _ready: &mut Vec<(SocketFd, bool, bool)>,
+        ) -> io::Result<()> {
+            match self.0 {}
+        }
+    }
+}
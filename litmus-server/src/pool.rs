@@ -0,0 +1,364 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+
+use bytes::BytesMut;
+
+use crate::traits::Reusable;
+
+/// A bounded free-list of idle clients.
+///
+/// Idle clients are returned to the pool rather than dropped so that new
+/// connections can be bound to an existing client, re-using its protocol
+/// state and buffers instead of allocating fresh ones. The most recently
+/// released client is reused first as its memory is the most likely to
+/// still be in cache.
+/// Once the pool reaches its max size any surplus clients are dropped so
+/// memory does not grow with the peak number of concurrent connections,
+/// on top of this any clients which went unused since the last trim are
+/// dropped by `trim` down to the pool's min size.
+pub(crate) struct ClientPool<C: Reusable> {
+    /// The idle clients ready to be rebound, the most recently released
+    /// last.
+    free: Vec<C>,
+
+    /// The number of idle clients kept when trimming.
+    min_size: usize,
+
+    /// The max number of idle clients to hold onto.
+    max_size: usize,
+
+    /// The fewest idle clients held since the last trim, this many were
+    /// never needed and can be dropped.
+    low: usize,
+
+    /// The number of clients created and the number of connections bound
+    /// to a pooled client rather than a new one.
+    created: usize,
+    reused: usize,
+}
+
+/// A snapshot of the client pool's counters.
+pub(crate) struct PoolStats {
+    pub(crate) created: usize,
+    pub(crate) reused: usize,
+    pub(crate) pooled: usize,
+}
+
+impl<C: Reusable> ClientPool<C> {
+    /// Creates a new empty pool holding at least `min_size` and at most
+    /// `max_size` clients, room is reserved for `min_size` clients up front.
+    pub(crate) fn new(min_size: usize, max_size: usize) -> Self {
+        let min_size = min_size.min(max_size);
+        Self {
+            free: Vec::with_capacity(min_size),
+            min_size,
+            max_size,
+            low: 0,
+            created: 0,
+            reused: 0,
+        }
+    }
+
+    /// Takes the most recently released idle client out of the pool if one
+    /// is available.
+    pub(crate) fn acquire(&mut self) -> Option<C> {
+        let client = self.free.pop()?;
+        self.low = self.low.min(self.free.len());
+        self.reused += 1;
+        Some(client)
+    }
+
+    /// Counts a client created as the pool had none to reuse.
+    pub(crate) fn record_created(&mut self) {
+        self.created += 1;
+    }
+
+    /// Returns an idle client to the pool, the client is dropped if the
+    /// pool is already full.
+    pub(crate) fn release(&mut self, client: C) {
+        if self.free.len() < self.max_size {
+            self.free.push(client);
+        }
+    }
+
+    /// Drops the idle clients which went unused since the last trim, the
+    /// least recently released first, keeping at least the min size.
+    pub(crate) fn trim(&mut self) {
+        let surplus = self.low.min(self.free.len().saturating_sub(self.min_size));
+        if surplus > 0 {
+            self.free.drain(..surplus);
+            self.free.shrink_to(self.min_size.max(self.free.len()));
+        }
+
+        self.low = self.free.len();
+    }
+
+    /// The number of idle clients currently held by the pool.
+    pub(crate) fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    /// A snapshot of the pool's counters.
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            created: self.created,
+            reused: self.reused,
+            pooled: self.free.len(),
+        }
+    }
+}
+
+/// The capacities of the buffers held by the buffer pool, smallest first.
+const BUFFER_CLASSES: [usize; 3] = [8 * 1024, 32 * 1024, 128 * 1024];
+
+/// The number of shards the buffer pool is split into.
+const BUFFER_SHARDS: usize = 8;
+
+/// The shard given to the next thread to use a buffer pool.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The shard of the buffer pool the current thread uses first, threads
+    /// are given the shards in turn.
+    static HOME_SHARD: usize =
+        NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % BUFFER_SHARDS;
+}
+
+/// A pool of read and write buffers shared by the clients of a server.
+///
+/// Connections only hold onto buffers while they have data to read or
+/// write, returning them to the pool once empty so that idle keep-alive
+/// connections don't each pin their own buffers. Buffers are pooled by
+/// size class, a buffer is acquired from the smallest class fitting the
+/// requested size and released to the largest class it can serve.
+///
+/// The pool is split into shards each behind its own lock so the event
+/// loop and io threads don't contend over a single one. Each thread only
+/// waits on its own shard, falling back to whichever other shards are
+/// free to acquire from or release to so memory is balanced between the
+/// threads.
+///
+/// Each class holds at most `max_size` buffers across the shards, on top
+/// of this any buffers which went unused since the last trim are dropped
+/// by `trim` so the pool follows the number of busy connections back down
+/// after a spike.
+pub struct BufferPool {
+    shards: [Mutex<Shard>; BUFFER_SHARDS],
+
+    /// The max number of free buffers held per size class by each shard.
+    shard_size: usize,
+
+    /// The number of times a thread had to wait on its own shard.
+    #[cfg(test)]
+    contended: AtomicUsize,
+
+    /// The number of buffers allocated as none were free.
+    #[cfg(test)]
+    allocated: AtomicUsize,
+}
+
+/// The size classes of one of the buffer pool's shards.
+type Shard = [SizeClass; BUFFER_CLASSES.len()];
+
+#[derive(Default)]
+struct SizeClass {
+    /// The free buffers ready to be acquired.
+    free: Vec<BytesMut>,
+
+    /// The fewest free buffers held since the last trim, this many were
+    /// never needed and can be dropped.
+    low: usize,
+}
+
+impl SizeClass {
+    fn pop(&mut self) -> Option<BytesMut> {
+        let buffer = self.free.pop()?;
+        self.low = self.low.min(self.free.len());
+        Some(buffer)
+    }
+}
+
+impl BufferPool {
+    /// Creates a new empty pool holding at most `max_size` buffers for
+    /// each size class.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            shards: Default::default(),
+            shard_size: max_size.div_ceil(BUFFER_SHARDS),
+            #[cfg(test)]
+            contended: AtomicUsize::new(0),
+            #[cfg(test)]
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a buffer with a capacity of at least `size` out of the pool,
+    /// allocating a new one if none are free.
+    pub(crate) fn acquire(&self, size: usize) -> BytesMut {
+        let index = BUFFER_CLASSES
+            .iter()
+            .position(|&capacity| capacity >= size)
+            .unwrap_or(BUFFER_CLASSES.len() - 1);
+
+        let home = HOME_SHARD.with(|shard| *shard);
+        if let Some(buffer) = self.lock_home(home)[index].pop() {
+            return buffer;
+        }
+
+        for shard in self.other_shards(home) {
+            if let Some(buffer) = shard.and_then(|mut shard| shard[index].pop()) {
+                return buffer;
+            }
+        }
+
+        #[cfg(test)]
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        BytesMut::with_capacity(BUFFER_CLASSES[index].max(size))
+    }
+
+    /// Returns an empty buffer to the pool, the buffer is dropped if it
+    /// still holds data, its class is full or it has grown well beyond
+    /// its class.
+    pub(crate) fn release(&self, mut buffer: BytesMut) {
+        if !buffer.is_empty() {
+            return;
+        }
+
+        // Reclaims the space before the buffer's start left by anything
+        // already read from or written out of it.
+        let _ = BUFFER_CLASSES.iter().rev().any(|&c| buffer.try_reclaim(c));
+
+        let capacity = buffer.capacity();
+        let index = match BUFFER_CLASSES.iter().rposition(|&c| c <= capacity) {
+            Some(index) if capacity <= BUFFER_CLASSES[index] * 2 => index,
+            _ => return,
+        };
+
+        let home = HOME_SHARD.with(|shard| *shard);
+        let mut shard = self.lock_home(home);
+        if shard[index].free.len() < self.shard_size {
+            shard[index].free.push(buffer);
+            return;
+        }
+        drop(shard);
+
+        for mut shard in self.other_shards(home).flatten() {
+            if shard[index].free.len() < self.shard_size {
+                shard[index].free.push(buffer);
+                return;
+            }
+        }
+    }
+
+    /// Locks the given thread's own shard, waiting on it if it's busy.
+    fn lock_home(&self, home: usize) -> MutexGuard<'_, Shard> {
+        match self.shards[home].try_lock() {
+            Ok(shard) => shard,
+            Err(TryLockError::WouldBlock) => {
+                #[cfg(test)]
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.shards[home].lock().unwrap()
+            },
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+
+    /// Tries to lock each of the shards other than the given thread's own
+    /// in turn, `None` for any that are busy.
+    fn other_shards(
+        &self,
+        home: usize,
+    ) -> impl Iterator<Item = Option<MutexGuard<'_, Shard>>> {
+        (1..BUFFER_SHARDS).map(move |offset| {
+            self.shards[(home + offset) % BUFFER_SHARDS].try_lock().ok()
+        })
+    }
+
+    /// Drops the free buffers which went unused since the last trim.
+    pub(crate) fn trim(&self) {
+        for shard in self.shards.iter() {
+            let mut classes = shard.lock().unwrap();
+            for class in classes.iter_mut() {
+                let unused = class.low.min(class.free.len());
+                class.free.truncate(class.free.len() - unused);
+                class.free.shrink_to_fit();
+                class.low = class.free.len();
+            }
+        }
+    }
+
+    /// The number of free buffers currently held by the pool.
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let classes = shard.lock().unwrap();
+                classes.iter().map(|class| class.free.len()).sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::{BufferPool, HOME_SHARD};
+
+    #[test]
+    fn threads_sharing_the_pool_do_not_contend() {
+        let pool = Arc::new(BufferPool::new(64));
+        let barrier = Arc::new(Barrier::new(3));
+
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let pool = pool.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    // Each thread's first buffer may be taken from another's
+                    // shard, only the buffers recycled after are counted.
+                    pool.release(pool.acquire(1024));
+                    barrier.wait();
+                    barrier.wait();
+
+                    for _ in 0..10_000 {
+                        pool.release(pool.acquire(1024));
+                    }
+                    HOME_SHARD.with(|shard| *shard)
+                })
+            })
+            .collect();
+
+        barrier.wait();
+        let contended = pool.contended.load(Ordering::Relaxed);
+        barrier.wait();
+
+        let homes: Vec<usize> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_ne!(homes[0], homes[1]);
+        assert_eq!(pool.contended.load(Ordering::Relaxed), contended);
+
+        let allocated = pool.allocated.load(Ordering::Relaxed);
+        assert!(allocated <= 2, "{} buffers allocated", allocated);
+        assert_eq!(pool.len(), allocated);
+    }
+
+    #[test]
+    fn free_buffers_are_shared_between_shards() {
+        let pool = BufferPool::new(16);
+
+        // More buffers than a single shard holds are kept by the others.
+        let buffers: Vec<_> = (0..10).map(|_| pool.acquire(1024)).collect();
+        buffers.into_iter().for_each(|buffer| pool.release(buffer));
+        assert_eq!(pool.len(), 10);
+
+        let buffers: Vec<_> = (0..20).map(|_| pool.acquire(1024)).collect();
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 20);
+        assert_eq!(pool.len(), 0);
+
+        // The pool as a whole holds at most its max size per class.
+        buffers.into_iter().for_each(|buffer| pool.release(buffer));
+        assert_eq!(pool.len(), 16);
+    }
+}
@@ -1,35 +1,80 @@
-#![allow(deprecated)]
+use std::collections::VecDeque;
+use std::iter;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
 
-use std::{mem, str};
-
-use bytes::BytesMut;
-use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use bytes::{Buf, Bytes, BytesMut};
+use headers::{Header as _, SecWebsocketAccept, SecWebsocketKey};
+use http::header::{
+    ACCEPT_ENCODING, ALLOW, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, EXPECT, REFERER,
+    SEC_WEBSOCKET_KEY, TE, TRANSFER_ENCODING, UPGRADE, USER_AGENT,
+};
 use http::uri::Uri;
-use httparse::{parse_chunk_size, Header, Request, Status};
+use http::StatusCode;
+use httparse::{parse_chunk_size, parse_headers, Header, Request, Status, EMPTY_HEADER};
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::types::PyBytes;
-use pyo3::{Py, PyResult, Python};
+use pyo3::{Py, PyObject, PyResult, Python};
 
-use crate::protocols::selector::SwitchStatus;
+use crate::access_log::AccessEntry;
+use crate::bench::{BenchEndpoint, JSON_BODY, MAX_ECHO_SIZE, PLAINTEXT_BODY};
+use crate::compression;
+use crate::hooks::{RequestTrace, TraceEvent, TraceHook};
 use crate::lsgi;
-use crate::responders::{ReceiverFactory, SenderFactory};
+use crate::metrics::ResponseStats;
+use crate::net::{FileBody, PeerCertificate, TlsDetails};
+use crate::protocols::h2::{self, H2cUpgrade};
+use crate::protocols::selector::{Protocols, SwitchStatus};
+use crate::range::RangeRequest;
+use crate::rate_limit::TokenBucket;
+use crate::request_id;
+use crate::responders::{
+    has_token, invoke_callback, Body, EventStream, ExpectContinue, ReceiverFactory,
+    RequestConnection, SenderFactory, WebSocketFactory,
+};
 use crate::server::CallbackHandler;
-use crate::settings::Settings;
+use crate::settings::{
+    Encoding, ExpectContinuePolicy, PipelinedUpgradePolicy, RateLimitPolicy, Settings,
+    MAX_HEADERS_LIMIT,
+};
+use crate::static_files::{StaticBody, StaticResponse};
 use crate::traits::{BaseTransport, ProtocolBuffers};
 use crate::transport::Transport;
 
-macro_rules! conv_err {
-    ( $e:expr ) => {
-        $e.map_err(|e| PyRuntimeError::new_err(format!("{}", e)))
-    };
-}
-
-/// The max headers allowed in a single request.
-const MAX_HEADERS: usize = 100;
-
 /// The minimum amount the buffer needs to be filled by before a body is sent.
 const MIN_BUFF_SIZE: usize = 64 * 1024;
 
+/// The min size of a body chunk for it to be written from its own buffer
+/// rather than being copied into the write buffer.
+const MIN_VECTORED_SIZE: usize = 16 * 1024;
+
+/// The max length of the request target.
+const MAX_TARGET_SIZE: usize = 8 * 1024;
+
+/// The max number of pipelined requests queued behind the response in
+/// progress before reading from the connection is paused.
+const MAX_PIPELINED_REQUESTS: usize = 16;
+
+/// The header a request id is read from and sent back in.
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// An empty comment framed as a chunk, sent on a quiet event stream to
+/// stop it from being timed out by proxies and clients.
+const EVENT_STREAM_HEARTBEAT: &[u8] = b"3\r\n:\n\n\r\n";
+
+/// The content type of the metrics served at the metrics path.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The header carrying the HTTP/2 settings of a request upgrading to h2c.
+const HTTP2_SETTINGS: &str = "http2-settings";
+
+/// The request methods the server will accept.
+const KNOWN_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
 /// A more reasonable buffer allocation size, this will stop re-allocating
 /// if they go above the MIN_BUFF_SIZE
 const FORGIVING_BUFFER_SIZE: usize = 128 * 1024;
@@ -42,7 +87,6 @@ pub struct H1Protocol {
     maybe_transport: Option<Transport>,
 
     /// The server configuration used to construct a ASGI scope.
-    #[allow(unused)]
     settings: Settings,
 
     /// The python callback handler.
@@ -61,15 +105,160 @@ pub struct H1Protocol {
     /// If the request uses chunked encoding for it's body.
     chunked_encoding: bool,
 
+    /// The bytes of the current chunk's data yet to be received.
+    chunk_remaining: usize,
+
+    /// If the `\r\n` ending the current chunk's data is yet to be received.
+    chunk_suffix: bool,
+
+    /// The size of the chunked body received so far, checked against the
+    /// max body size as chunks arrive.
+    chunked_size: usize,
+
     /// If the server should close the connection after the response is
     /// complete.
     keep_alive: bool,
+
+    /// If the connection is closed once everything queued has been
+    /// written, set once the last response on the connection is queued.
+    close_after_write: bool,
+
+    /// The number of requests received on the connection, checked against
+    /// the max requests per connection.
+    requests_received: usize,
+
+    /// The number of `HTTP2-Settings` headers sent with the current
+    /// request, an upgrade to h2c needs exactly one.
+    http2_settings: usize,
+
+    /// The settings decoded from the `HTTP2-Settings` header of the current
+    /// request, `None` if it's missing or invalid.
+    h2c_settings: Option<Vec<u8>>,
+
+    /// The request the connection was upgraded to h2c with while waiting to
+    /// be switched over to HTTP/2, it's then served on the first stream.
+    h2c_upgrade: Option<H2cUpgrade>,
+
+    /// The HTTP version requests are given to the application as if the
+    /// protocol is serving a stream multiplexed over another connection,
+    /// see `new_stream()`.
+    stream_version: Option<&'static str>,
+
+    /// The certificate the client verified itself with during the TLS
+    /// handshake, if any.
+    peer_cert: Option<PeerCertificate>,
+
+    /// What was negotiated during the TLS handshake, `None` without TLS.
+    tls_details: Option<TlsDetails>,
+
+    /// The details of the connection given to each request's scope, made
+    /// with the first request.
+    connection_info: Option<Py<lsgi::ConnectionInfo>>,
+
+    /// If the client has signalled it accepts trailer fields on a chunked
+    /// response via the `TE: trailers` header.
+    accepts_trailers: bool,
+
+    /// The coding negotiated from the `Accept-Encoding` header of the
+    /// current request if compression is enabled.
+    encoding: Option<Encoding>,
+
+    /// If the client is waiting on a `100 Continue` before sending the
+    /// body of the current request.
+    expects_continue: bool,
+
+    /// The `100 Continue` owed to the current request if any.
+    interim: Option<ExpectContinue>,
+
+    /// The body of the current request received so far if it's being
+    /// echoed back by the bench mode's echo endpoint.
+    echo: Option<BytesMut>,
+
+    /// The event stream the response to the current request can be
+    /// started as.
+    event_stream: Option<EventStream>,
+
+    /// If a heartbeat is to be written on the event stream once everything
+    /// queued by the application has been.
+    heartbeat_due: bool,
+
+    /// The last time the application made progress on the response of
+    /// the current request, `None` if no response is outstanding.
+    response_activity: Option<Duration>,
+
+    /// The total number of bytes added to the write buffer.
+    bytes_queued: usize,
+
+    /// The total number of bytes drained from the write buffer.
+    bytes_drained: usize,
+
+    /// The callbacks waiting on their chunk being written to the socket
+    /// along with the value `bytes_drained` must reach for them to fire.
+    write_callbacks: VecDeque<(usize, PyObject)>,
+
+    /// The request rate limiter of the connection if a limit is set.
+    rate_limiter: Option<TokenBucket>,
+
+    /// If reading has been paused due to exceeding the rate limit.
+    throttled: bool,
+
+    /// A file being written directly to the socket along with the framing
+    /// to write after it and if more body is expected, nothing else is
+    /// written until it has been sent.
+    file: Option<(FileBody, Vec<u8>, bool)>,
+
+    /// If reading has been paused until the application receives the
+    /// body chunks already waiting for it.
+    body_paused: bool,
+
+    /// The heads of bodyless requests pipelined behind the response in
+    /// progress, dispatched in order once the responses before them are
+    /// complete.
+    pipelined: VecDeque<BytesMut>,
+
+    /// If reading has been paused until the pipelined requests already
+    /// received have been dispatched.
+    pipeline_paused: bool,
+
+    /// The protocol the client has asked to upgrade to on the current
+    /// request, this is cleared once the response is complete.
+    upgrade: Option<String>,
+
+    /// When the first bytes of a request head that is yet to be complete
+    /// were received.
+    head_started: Option<Duration>,
+
+    /// The last time any of the body of the current request was received,
+    /// `None` if no body is outstanding.
+    body_activity: Option<Duration>,
+
+    /// The `Sec-WebSocket-Key` of the current request if any.
+    websocket_key: Option<headers::HeaderValue>,
+
+    /// Set if the current request can be upgraded to a websocket.
+    websocket: Option<WebSocketFactory>,
+
+    /// The message callback and factory of a websocket accepted by the
+    /// application, waiting on the response to be written.
+    accepted_websocket: Option<(PyObject, WebSocketFactory)>,
+
+    /// The progress of the response to the current request, recorded in
+    /// the metrics once it has been queued.
+    response_stats: Option<ResponseStats>,
+
+    /// The access log entry of the current request if access logging is
+    /// enabled, recorded along with the response.
+    access: Option<AccessEntry>,
+
+    /// The current request if a tracer is set, traced through to its
+    /// response.
+    request_trace: Option<RequestTrace>,
 }
 
 impl H1Protocol {
     /// Create a new H1Protocol instance.
     pub(crate) fn new(settings: Settings, callback: CallbackHandler) -> Self {
-        let sender = SenderFactory::new();
+        let sender = SenderFactory::new(callback.clone(), settings.clone());
         let receiver = ReceiverFactory::new();
 
         Self {
@@ -82,7 +271,45 @@ impl H1Protocol {
 
             expected_content_length: 0,
             chunked_encoding: false,
+            chunk_remaining: 0,
+            chunk_suffix: false,
+            chunked_size: 0,
             keep_alive: true,
+            close_after_write: false,
+            requests_received: 0,
+            http2_settings: 0,
+            h2c_settings: None,
+            h2c_upgrade: None,
+            stream_version: None,
+            peer_cert: None,
+            tls_details: None,
+            connection_info: None,
+            accepts_trailers: false,
+            encoding: None,
+            expects_continue: false,
+            interim: None,
+            echo: None,
+            event_stream: None,
+            heartbeat_due: false,
+            response_activity: None,
+            bytes_queued: 0,
+            bytes_drained: 0,
+            write_callbacks: VecDeque::new(),
+            rate_limiter: None,
+            throttled: false,
+            file: None,
+            body_paused: false,
+            pipelined: VecDeque::new(),
+            pipeline_paused: false,
+            upgrade: None,
+            head_started: None,
+            body_activity: None,
+            websocket_key: None,
+            websocket: None,
+            accepted_websocket: None,
+            response_stats: None,
+            access: None,
+            request_trace: None,
         }
     }
 
@@ -110,12 +337,29 @@ impl H1Protocol {
     /// socket.
     pub fn new_connection(&mut self, transport: Transport) {
         self.reset_state();
+        self.stream_version = None;
         self.maybe_transport = Some(transport);
     }
 
+    /// Called when the protocol is in charge of a single stream multiplexed
+    /// over a connection, e.g. with HTTP/2, its request having been
+    /// translated to HTTP/1.1 and given to the application as the given
+    /// HTTP version.
+    pub(crate) fn new_stream(&mut self, transport: Transport, version: &'static str) {
+        self.new_connection(transport);
+        self.stream_version = Some(version);
+    }
+
     /// Called when the connection is lost from the protocol in order to
     /// properly reset state.
     pub fn lost_connection(&mut self) -> PyResult<()> {
+        // An event stream would otherwise go on forever, the application
+        // is woken if it's waiting to send so it finds out.
+        if let Some(stream) = self.event_stream.take() {
+            stream.close();
+        }
+        self.sender.close();
+
         Ok(())
     }
 
@@ -124,222 +368,2708 @@ impl H1Protocol {
     fn reset_state(&mut self) {
         self.expected_content_length = 0;
         self.chunked_encoding = false;
+        self.chunk_remaining = 0;
+        self.chunk_suffix = false;
+        self.chunked_size = 0;
+        self.close_after_write = false;
+        self.requests_received = 0;
+        self.http2_settings = 0;
+        self.h2c_settings = None;
+        self.h2c_upgrade = None;
+        self.peer_cert = None;
+        self.tls_details = None;
+        self.connection_info = None;
+        self.accepts_trailers = false;
+        self.encoding = None;
+        self.expects_continue = false;
+        self.interim = None;
+        self.echo = None;
+        self.event_stream = None;
+        self.heartbeat_due = false;
+        self.set_response_activity(None);
+        self.bytes_queued = 0;
+        self.bytes_drained = 0;
+        self.write_callbacks.clear();
+        self.rate_limiter = self.settings.rate_limit.map(|limit| {
+            TokenBucket::new(limit.requests_per_second, limit.burst as f64)
+        });
+        self.throttled = false;
+        self.file = None;
+        self.body_paused = false;
+        self.pipelined.clear();
+        self.pipeline_paused = false;
+        self.upgrade = None;
+        self.head_started = None;
+        self.body_activity = None;
+        self.websocket_key = None;
+        self.websocket = None;
+        self.accepted_websocket = None;
+        self.response_stats = None;
+        self.access = None;
+        self.request_trace = None;
 
-        self.sender = SenderFactory::new();
+        self.sender = SenderFactory::new(self.callback.clone(), self.settings.clone());
         self.receiver = ReceiverFactory::new();
     }
 
     /// Determines what the protocol should be switched to if it is
     /// necessary called just after reading has completed to allow
     /// for upgrading.
+    ///
+    /// An upgrade can only happen once any prior responses have been
+    /// written, websockets are switched to by `take_websocket` once the
+    /// accepting response is drained and any other upgrade is abandoned
+    /// once the response completes.
+    ///
+    /// An upgrade to h2c is switched to straight away, the selector moves
+    /// the `101 Switching Protocols` ahead of anything HTTP/2 writes and
+    /// takes the upgraded request with `take_h2c_upgrade`.
     pub(crate) fn maybe_switch(&mut self) -> PyResult<SwitchStatus> {
-        // ignore for now
+        if self.h2c_upgrade.is_some() {
+            return Ok(SwitchStatus::SwitchTo(Protocols::H2));
+        }
+
         Ok(SwitchStatus::NoSwitch)
     }
-}
 
-impl ProtocolBuffers for H1Protocol {
-    /// Parses data received from the given buffer and separates the content
-    /// accordingly.
-    ///
-    /// This callback is invoked just after the auto protocol's buffer
-    /// has been acquired and filled. It's not guaranteed to be filled
-    /// fully but it is guaranteed to have at least been filled up with
-    /// one or more bytes of data.
-    ///
-    /// Upon no data being read signalling a EOF the eof_received callback is
-    /// invoked and handled instead.
-    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        if self.expected_content_length == 0 {
-            self.parser_request(buffer)?;
+    /// Takes the request the connection was upgraded to h2c with.
+    pub(crate) fn take_h2c_upgrade(&mut self) -> Option<H2cUpgrade> {
+        self.h2c_upgrade.take()
+    }
+
+    /// Records the certificate the client verified itself with, the
+    /// certificate can't change so it's only read once per connection.
+    pub(crate) fn set_peer_certificate(&mut self, der: Option<&[u8]>) {
+        if self.peer_cert.is_none() {
+            self.peer_cert = der.map(PeerCertificate::from_der);
         }
+    }
 
-        if self.chunked_encoding {
-            self.parse_chunked_body(buffer)?;
-        } else if self.expected_content_length > 0 {
-            self.parse_body(buffer)?;
-        } else {
-            let _ = self.receiver.send((false, BytesMut::with_capacity(0)));
+    /// Records what was negotiated during the TLS handshake, this can't
+    /// change so it's only read once per connection.
+    pub(crate) fn set_tls_details(
+        &mut self,
+        details: impl FnOnce() -> Option<TlsDetails>,
+    ) {
+        if self.tls_details.is_none() {
+            self.tls_details = details();
         }
+    }
 
-        self.transport()?.resume_writing()?;
-        Ok(())
+    /// Takes the websocket accepted by the application along with its
+    /// message callback, called once the write buffer has been drained.
+    pub(crate) fn take_websocket(&mut self) -> Option<(PyObject, WebSocketFactory)> {
+        self.accepted_websocket.take()
     }
 
-    /// Fills the passed buffer with any messages enqueued to be sent.
-    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        while let Ok((more_body, keep_alive, buff)) = self.sender.recv() {
-            self.keep_alive = keep_alive;
-            buffer.extend(buff);
+    /// Called once data has been drained from the write buffer to the
+    /// socket, invoking any write callbacks whose chunk has been fully
+    /// written.
+    ///
+    /// Callbacks are expected not to raise, if they do the error is
+    /// ignored the same as with wakers.
+    pub(crate) fn write_drained(&mut self, amount: usize) {
+        self.bytes_drained += amount;
 
-            if !more_body & !self.keep_alive {
-                // This will schedule the closure using call_soon.
-                self.transport()?.close()?;
+        let ready = self
+            .write_callbacks
+            .iter()
+            .take_while(|(offset, _)| *offset <= self.bytes_drained)
+            .count();
+
+        if ready == 0 {
+            return;
+        }
+
+        Python::with_gil(|py| {
+            for (_, cb) in self.write_callbacks.drain(..ready) {
+                invoke_callback(py, &cb, "write");
             }
+        });
+    }
+
+    /// If the protocol is between requests, with no request body left
+    /// to read and no response left to write.
+    pub(crate) fn at_request_boundary(&self) -> bool {
+        (self.expected_content_length == 0)
+            & !self.chunked_encoding
+            & self.response_activity.is_none()
+            & self.write_callbacks.is_empty()
+            & self.upgrade.is_none()
+            & self.file.is_none()
+            & self.pipelined.is_empty()
+    }
+
+    /// If more of the response is waiting to be written to the socket than
+    /// the write high water mark allows.
+    fn above_high_water(&self) -> bool {
+        let buffered = self.bytes_queued.saturating_sub(self.bytes_drained);
+        buffered > self.settings.write_high_water
+    }
+
+    /// If the application has queued chunks which are yet to be taken, the
+    /// writer has to keep going until they have been.
+    pub(crate) fn response_queued(&self) -> bool {
+        !self.sender.is_empty()
+    }
+
+    /// If the connection is closed once everything queued has been written.
+    pub(crate) fn is_closing(&self) -> bool {
+        self.close_after_write
+    }
+
+    /// If the application is yet to complete the response to the current
+    /// request, including writing any file it sent.
+    fn response_in_progress(&self) -> bool {
+        self.response_activity.is_some() | self.file.is_some()
+    }
+
+    /// Records the last time the application made progress on the response,
+    /// shared with the request's sender so the application can tell how
+    /// long it has left before the response timeout.
+    fn set_response_activity(&mut self, activity: Option<Duration>) {
+        self.response_activity = activity;
+        self.sender.set_activity(activity);
+    }
+
+    /// Resumes reading unless it's still paused for another reason.
+    fn maybe_resume_reading(&self) -> PyResult<()> {
+        if !self.throttled & !self.body_paused & !self.pipeline_paused {
+            self.transport()?.resume_reading()?;
         }
 
         Ok(())
     }
-}
-
-impl H1Protocol {
-    fn parser_request(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        // This should be fine as it is guaranteed to be initialised
-        // before we use it, just waiting for the ability to use
-        // MaybeUninit, till then here we are.
-        let mut headers: [Header<'_>; MAX_HEADERS] = unsafe { mem::uninitialized() };
 
-        let body = buffer.clone();
+    /// Dispatches the next pipelined request once the response before it
+    /// is complete, queueing anything left in the buffer behind it.
+    pub(crate) fn poll_pipeline(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if self.response_in_progress()
+            | self.chunked_encoding
+            | (self.expected_content_length > 0)
+            | self.upgrade.is_some()
+        {
+            return Ok(());
+        }
 
-        let mut request = Request::new(&mut headers);
-        let status = conv_err!(request.parse(&body))?;
+        // The connection is closed once the response is written.
+        if !self.keep_alive {
+            self.pipelined.clear();
+            return Ok(());
+        }
 
-        let len = if status.is_partial() {
+        if !self.pipelined.is_empty() && self.throttle_next_request()? {
             return Ok(());
-        } else {
-            status.unwrap()
-        };
+        }
 
-        let _ = buffer.split_to(len);
+        if let Some(mut head) = self.pipelined.pop_front() {
+            self.parser_request(&mut head)?;
+            let _ = self.receiver.send((false, BytesMut::with_capacity(0)));
+            self.transport()?.resume_writing()?;
+
+            // Nothing behind a request closing the connection is served.
+            if !self.keep_alive {
+                self.pipelined.clear();
+                buffer.clear();
+                return Ok(());
+            }
+        }
 
-        self.on_request_parse(&mut request)?;
+        if self.pipeline_paused {
+            self.pipeline_paused = false;
+            self.maybe_resume_reading()?;
+            self.data_received(buffer)?;
+        }
 
         Ok(())
     }
 
-    fn parse_chunked_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        if let Some((more_body, data)) = self.drain_body_chunks(buffer)? {
-            let _ = self.receiver.send((more_body, data));
+    /// Queues the heads of bodyless requests pipelined behind the response
+    /// in progress.
+    ///
+    /// Queueing stops at a request with a body or an incomplete or invalid
+    /// head, these are left in the buffer and handled once the queue ahead
+    /// of them has been dispatched. Reading is paused while anything is left
+    /// in the buffer or the queue is full.
+    fn queue_pipelined(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        while !buffer.is_empty() & (self.pipelined.len() < MAX_PIPELINED_REQUESTS) {
+            match bodyless_head_len(buffer) {
+                Some(len) => self.pipelined.push_back(buffer.split_to(len)),
+                None => break,
+            }
+        }
+
+        let full = self.pipelined.len() >= MAX_PIPELINED_REQUESTS;
+        if (!buffer.is_empty() | full) & !self.pipeline_paused {
+            self.pipeline_paused = true;
+            self.transport()?.pause_reading()?;
         }
 
         Ok(())
     }
 
-    fn drain_body_chunks(
+    /// The file waiting to be written to the socket if any.
+    pub(crate) fn pending_file(&mut self) -> Option<&mut FileBody> {
+        self.file.as_mut().map(|(file, _, _)| file)
+    }
+
+    /// Called once some of the pending file has been written to the
+    /// socket, once it has been fully written anything queued after it
+    /// can be written.
+    pub(crate) fn file_sent(
         &mut self,
+        amount: usize,
         buffer: &mut BytesMut,
-    ) -> PyResult<Option<(bool, BytesMut)>> {
-        let mut temp_buff = BytesMut::with_capacity(FORGIVING_BUFFER_SIZE);
-        loop {
-            let res = conv_err!(parse_chunk_size(&buffer))?;
-            let (start, len) = match res {
-                Status::Complete(info) => info,
-                Status::Partial => {
-                    return if temp_buff.len() <= MIN_BUFF_SIZE {
-                        Ok(None)
-                    } else {
-                        Ok(Some((true, temp_buff)))
-                    }
-                },
-            };
+    ) -> PyResult<()> {
+        self.write_drained(amount);
 
-            if len == 0 {
-                let _ = buffer.split_to(start + 4);
-                return Ok(Some((false, temp_buff)));
-            }
+        let done = self
+            .file
+            .as_ref()
+            .map(|(file, _, _)| file.remaining() == 0)
+            .unwrap_or(false);
+
+        if done {
+            let (_, suffix, more_body) = self.file.take().unwrap();
+            buffer.extend(suffix);
+            self.on_body_queued(more_body)?;
+        }
+
+        Ok(())
+    }
 
-            let _ = buffer.split_to(start);
-            let body = buffer.split_to(len as usize);
-            let _ = buffer.split_to(2); // remove \r\n suffix
+    /// Updates the state of the response once a payload has been added to
+    /// the write buffer.
+    fn on_body_queued(&mut self, more_body: bool) -> PyResult<()> {
+        // The rest of a request body the application gave up on, e.g. after
+        // failing, would have to be read before the next request so the
+        // connection isn't reused.
+        if !more_body & (self.chunked_encoding | (self.expected_content_length > 0)) {
+            self.keep_alive = false;
+        }
 
-            temp_buff.extend(body);
+        if !more_body {
+            self.upgrade = None;
+            self.finish_response()?;
 
-            if temp_buff.len() >= MIN_BUFF_SIZE {
-                return Ok(Some((true, temp_buff)));
+            if let Some(ws) = self.websocket.take() {
+                if let Some(on_message) = ws.take_accepted() {
+                    self.accepted_websocket = Some((on_message, ws));
+                }
             }
         }
+
+        // The connection is only closed once the response has been fully
+        // written, nothing more is read in the meantime.
+        if !more_body & !self.keep_alive {
+            self.close_after_write = true;
+            self.transport()?.pause_reading()?;
+        }
+
+        Ok(())
     }
 
-    fn parse_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        let (more_body, data) = if buffer.len() >= self.expected_content_length {
-            let res = buffer.split_to(self.expected_content_length);
-            (false, Some(res))
-        } else if buffer.len() >= MIN_BUFF_SIZE {
-            let res = buffer.clone();
-            buffer.clear();
-            self.expected_content_length -= res.len();
-            (true, Some(res))
-        } else {
-            (true, None)
+    /// Called once everything queued has been written to the socket,
+    /// returning `true` if the connection's last response has been sent
+    /// and it should be closed.
+    pub(crate) fn write_flushed(&mut self) -> bool {
+        std::mem::take(&mut self.close_after_write)
+    }
+
+    /// Handles the peer shutting down its side of the connection, returning
+    /// `true` if the connection is kept half open until the response in
+    /// progress has been written.
+    ///
+    /// A request whose body or upgrade is yet to be completed can't be
+    /// answered, nor can anything pipelined behind the current request.
+    pub(crate) fn eof_received(&mut self, write_pending: bool) -> PyResult<bool> {
+        let incomplete = self.chunked_encoding
+            | (self.expected_content_length > 0)
+            | self.upgrade.is_some();
+        let responding = self.response_in_progress()
+            | self.response_queued()
+            | self.close_after_write
+            | write_pending;
+        if incomplete | !responding {
+            return Ok(false);
+        }
+
+        self.keep_alive = false;
+        self.pipelined.clear();
+        if !self.response_in_progress() & !self.response_queued() {
+            self.close_after_write = true;
+        }
+
+        self.transport()?.pause_reading()?;
+        Ok(true)
+    }
+
+    /// Starts tracking the response to the current request for the metrics
+    /// and the access log if it's enabled.
+    fn start_response(
+        &mut self,
+        method: &str,
+        target: &str,
+        version: &'static str,
+        headers: &[Header],
+    ) -> PyResult<()> {
+        let now = self.transport()?.now()?;
+        let (peer, _) = self.forwarded_client(headers)?;
+        self.response_stats = Some(ResponseStats::new(now));
+
+        let request_id = self.request_id(headers)?;
+        self.sender.set_request_id(request_id.clone());
+
+        if self.settings.tracer.is_some() {
+            self.request_trace = Some(RequestTrace {
+                method: method.to_string(),
+                path: target.split('?').next().unwrap_or(target).to_string(),
+                started: now,
+                received: 0,
+                queued_before: self.bytes_queued,
+            });
+        }
+
+        if self.settings.access_log.is_none() {
+            return Ok(());
+        }
+
+        let find = |name| {
+            headers
+                .iter()
+                .find(|h| h.name == name)
+                .map(|h| String::from_utf8_lossy(h.value).into_owned())
         };
 
-        if let Some(data) = data {
-            let _ = self.receiver.send((more_body, data));
+        self.access = Some(AccessEntry {
+            peer,
+            method: method.to_string(),
+            target: target.to_string(),
+            version,
+            referer: find(REFERER),
+            user_agent: find(USER_AGENT),
+            request_id,
+        });
+
+        Ok(())
+    }
+
+    /// Gets the id of a request if request ids are enabled, an id sent by
+    /// a trusted proxy is kept so the request can be followed across both.
+    fn request_id(&self, headers: &[Header]) -> PyResult<Option<Arc<str>>> {
+        let format = match self.settings.request_id {
+            Some(format) => format,
+            None => return Ok(None),
+        };
+
+        let client = self.transport()?.client;
+        let forwarded = self
+            .settings
+            .trusted_proxies
+            .as_ref()
+            .filter(|proxies| proxies.is_trusted(client.ip()))
+            .and_then(|_| {
+                headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(X_REQUEST_ID))
+            })
+            .filter(|h| request_id::is_valid_forwarded_id(h.value))
+            .and_then(|h| str::from_utf8(h.value).ok());
+
+        let id = match forwarded {
+            Some(id) => Arc::from(id),
+            None => Arc::from(format.generate()),
+        };
+
+        Ok(Some(id))
+    }
+
+    /// Records a chunk of the response in its stats, reporting the start
+    /// of the response to the tracer once its head is seen.
+    fn observe_response(&mut self, chunk: &[u8]) -> PyResult<()> {
+        let mut started = false;
+        if let Some(stats) = self.response_stats.as_mut() {
+            let head = stats.status == 0;
+            stats.observe(chunk);
+            started = head & (stats.status != 0);
+        }
+
+        if started {
+            self.trace_request(TraceHook::ResponseStarted)?;
         }
 
         Ok(())
     }
 
-    /// Turns all the headers into Python type objects and invokes the
-    /// python callback.
-    fn on_request_parse(&mut self, request: &mut Request) -> PyResult<()> {
-        let method = request.method.expect("Method was None at complete parse");
-        let path = request.path.expect("Path was None at complete parse");
-        let version = request.version.expect("Version was None at complete parse");
+    /// Records the response to the current request in the metrics and the
+    /// access log now it has been queued.
+    fn finish_response(&mut self) -> PyResult<()> {
+        if self.response_stats.is_some() {
+            self.trace_request(TraceHook::ResponseComplete)?;
+            self.request_trace = None;
+        }
 
-        let version = if version == 0 {
-            self.keep_alive = false;
-            lsgi::HTTP_10
-        } else if version == 1 {
-            self.keep_alive = true;
-            lsgi::HTTP_11
-        } else {
-            unreachable!()
+        let stats = match self.response_stats.take() {
+            Some(stats) => stats,
+            None => return Ok(()),
         };
 
-        let uri = path.parse::<Uri>().expect("failed to parse http url");
+        let duration = self.transport()?.now()?.saturating_sub(stats.started);
+        self.settings
+            .metrics
+            .record_response(stats.status, duration);
 
-        let headers_new = Python::with_gil(|py| {
-            let mut parsed_vec = Vec::with_capacity(request.headers.len());
-            for header in request.headers.iter() {
-                self.check_header(&header);
+        if let (Some(log), Some(entry)) =
+            (self.settings.access_log.as_ref(), self.access.take())
+        {
+            log.record(&entry, &stats, duration);
+        }
 
-                let bytes_body = PyBytes::new(py, header.value.as_ref());
+        Ok(())
+    }
 
-                let converted2: Py<PyBytes> = Py::from(bytes_body);
-                parsed_vec.push((header.name, converted2))
-            }
+    /// Sends the response to a request for a static file.
+    fn send_static_file(&self, response: StaticResponse, keep_alive: bool) {
+        let headers: Vec<(&str, &str)> = response
+            .headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
 
-            parsed_vec
-        });
+        match response.body {
+            StaticBody::File(len, file) => self.sender.send_file_response(
+                response.status,
+                &headers,
+                len,
+                file,
+                keep_alive,
+            ),
+            StaticBody::Text(body) => self.sender.send_static_response(
+                response.status,
+                &headers,
+                body,
+                keep_alive,
+            ),
+            StaticBody::Empty => {
+                self.sender
+                    .send_empty_response(response.status, &headers, keep_alive)
+            },
+        }
+    }
 
+    /// The effective client and scheme of a request, as reported by any
+    /// trusted proxy it was forwarded by.
+    fn forwarded_client(
+        &self,
+        headers: &[Header],
+    ) -> PyResult<(SocketAddr, &'static str)> {
         let transport = self.transport()?;
-        let server = (transport.server.ip().to_string(), transport.server.port());
-        let client = (transport.client.ip().to_string(), transport.client.port());
-        let schema = if transport.tls { "https" } else { "http" };
+        let scheme = if transport.tls { "https" } else { "http" };
 
-        let scope: lsgi::LSGIScope = (
-            lsgi::SCOPE_TYPE,
-            version,
-            method,
-            schema,
-            uri.path(),
-            uri.query().unwrap_or(""),
-            lsgi::TEMP_ROOT_PATH,
-            headers_new,
-            server,
-            client,
-        );
+        Ok(match self.settings.trusted_proxies.as_ref() {
+            Some(proxies) => proxies.resolve(transport.client, scheme, headers),
+            None => (transport.client, scheme),
+        })
+    }
+
+    /// The details of the connection given to each request's scope, made
+    /// once the first request is received.
+    fn connection_info(&mut self) -> PyResult<Py<lsgi::ConnectionInfo>> {
+        if let Some(info) = self.connection_info.as_ref() {
+            return Ok(Python::with_gil(|py| info.clone_ref(py)));
+        }
+
+        let transport = self.transport()?;
+        let info = Python::with_gil(|py| {
+            let peer_cert = self.peer_cert.as_ref().map(|cert| {
+                (Py::from(PyBytes::new(py, &cert.der)), cert.subject.clone())
+            });
+
+            let info = lsgi::ConnectionInfo::new(
+                transport.connection_id,
+                (transport.server.ip().to_string(), transport.server.port()),
+                (transport.client.ip().to_string(), transport.client.port()),
+                self.tls_details.clone(),
+                peer_cert,
+            );
+            Py::new(py, info)
+        })?;
+
+        self.connection_info = Some(Python::with_gil(|py| info.clone_ref(py)));
+        Ok(info)
+    }
+
+    /// Reports a point in the life of the current request to the tracer if
+    /// anything is hooked into it.
+    fn trace_request(&self, hook: TraceHook) -> PyResult<()> {
+        let (tracer, trace) = match (&self.settings.tracer, &self.request_trace) {
+            (Some(tracer), Some(trace)) if tracer.is_hooked(hook) => (tracer, trace),
+            _ => return Ok(()),
+        };
+
+        let transport = self.transport()?;
+        let now = transport.now()?;
+        let status = self
+            .response_stats
+            .as_ref()
+            .map(|stats| stats.status)
+            .filter(|status| *status != 0);
+        let sent = self.bytes_queued.saturating_sub(trace.queued_before);
 
-        let sender = self.sender.make_handle();
-        let receiver = self.receiver.make_handle();
-        self.callback.invoke((scope, sender, receiver))?;
+        tracer.emit(hook, || {
+            TraceEvent::new(
+                transport.connection_id,
+                transport.client,
+                transport.server,
+                now,
+                trace.started,
+            )
+            .with_bytes(trace.received, sent)
+            .with_request(&trace.method, &trace.path, status)
+        });
 
         Ok(())
     }
 
-    /// Checks a given header to see if it is to do with the request's
-    /// body size and type, e.g. Chunked encoding.
-    fn check_header(&mut self, header: &Header) {
-        if header.name == CONTENT_LENGTH {
-            self.expected_content_length = str::from_utf8(header.value)
-                .map(|v| v.parse::<usize>().unwrap_or(0))
-                .unwrap_or(0)
-        } else if header.name == TRANSFER_ENCODING {
-            let lowered = header.value.to_ascii_lowercase();
-            self.chunked_encoding = str::from_utf8(lowered.as_ref())
-                .map(|v| v.contains("chunked"))
-                .unwrap_or(false)
+    /// If the connection is to be kept alive after the current response.
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Sets if the connection is to be kept alive, used when restoring
+    /// a migrated connection.
+    pub(crate) fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// The number of requests received on the connection so far.
+    pub(crate) fn requests_received(&self) -> usize {
+        self.requests_received
+    }
+
+    /// Sets the number of requests received on the connection, used when
+    /// restoring a migrated connection.
+    pub(crate) fn set_requests_received(&mut self, requests_received: usize) {
+        self.requests_received = requests_received;
+    }
+
+    /// If a response is currently being assembled for the application.
+    pub(crate) fn response_pending(&self) -> bool {
+        self.response_activity.is_some()
+    }
+
+    /// If the response in progress is an open event stream.
+    pub(crate) fn is_event_stream(&self) -> bool {
+        self.response_activity.is_some()
+            && self.event_stream.as_ref().is_some_and(|s| s.is_open())
+    }
+
+    /// Polls the protocol's timers.
+    pub(crate) fn poll_timers(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        self.poll_response_timeout()?;
+        self.poll_header_timeout()?;
+        self.poll_body_timeout()?;
+        self.poll_event_stream()?;
+        self.poll_throttle(buffer)
+    }
+
+    /// Schedules a heartbeat on an open event stream that has been quiet
+    /// for the heartbeat interval.
+    fn poll_event_stream(&mut self) -> PyResult<()> {
+        let (interval, last) =
+            match (self.settings.event_stream_heartbeat, self.response_activity) {
+                (Some(interval), Some(last)) => (interval, last),
+                _ => return Ok(()),
+            };
+
+        if !self.is_event_stream() {
+            return Ok(());
+        }
+
+        if self.transport()?.now()?.saturating_sub(last) < interval {
+            return Ok(());
         }
+
+        self.heartbeat_due = true;
+        self.transport()?.resume_writing()
+    }
+
+    /// Closes the connection with a `408 Request Timeout` if the client is
+    /// taking too long to send a request head, e.g. slowloris attacks
+    /// trickling bytes to hold the connection open.
+    fn poll_header_timeout(&mut self) -> PyResult<()> {
+        let (timeout, started) = match (self.settings.header_timeout, self.head_started)
+        {
+            (Some(timeout), Some(started)) => (timeout, started),
+            _ => return Ok(()),
+        };
+
+        let transport = self.transport()?;
+        if transport.now()?.saturating_sub(started) < timeout {
+            return Ok(());
+        }
+
+        debug!(
+            connection_id = transport.connection_id,
+            peer:% = transport.client;
+            "client {} failed to send a request head within {:?}, closing connection",
+            transport.client, timeout,
+        );
+        transport.pause_reading()?;
+        transport.resume_writing()?;
+
+        self.head_started = None;
+        self.sender
+            .send_empty_response(StatusCode::REQUEST_TIMEOUT, &[], false);
+
+        Ok(())
+    }
+
+    /// Aborts the current request with a `408 Request Timeout` if the client
+    /// has stopped sending its body.
+    ///
+    /// Time spent waiting on the server, for the application to take the
+    /// body or ask for it with a `100 Continue` or for the rate limit, is
+    /// not held against the client.
+    fn poll_body_timeout(&mut self) -> PyResult<()> {
+        let (timeout, last) = match (self.settings.body_timeout, self.body_activity) {
+            (Some(timeout), Some(last)) => (timeout, last),
+            _ => return Ok(()),
+        };
+
+        if !self.chunked_encoding & (self.expected_content_length == 0) {
+            self.body_activity = None;
+            return Ok(());
+        }
+
+        let now = self.transport()?.now()?;
+        if self.body_paused | self.throttled | self.interim.is_some() {
+            self.body_activity = Some(now);
+            return Ok(());
+        }
+
+        if now.saturating_sub(last) < timeout {
+            return Ok(());
+        }
+
+        let transport = self.transport()?;
+        debug!(
+            connection_id = transport.connection_id,
+            peer:% = transport.client;
+            "client {} failed to send any of the request body within {:?}, \
+            aborting request",
+            transport.client,
+            timeout,
+        );
+        self.abort_request(StatusCode::REQUEST_TIMEOUT)
+    }
+
+    /// Resumes reading from a throttled connection once it is back within
+    /// the rate limit, dispatching the requests held back in the meantime.
+    fn poll_throttle(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if !self.throttled {
+            return Ok(());
+        }
+
+        let now = self.transport()?.now()?;
+        let has_token = match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter.has_token(now),
+            None => true,
+        };
+
+        if !has_token {
+            return Ok(());
+        }
+
+        self.throttled = false;
+        self.maybe_resume_reading()?;
+        if self.pipelined.is_empty() {
+            self.data_received(buffer)
+        } else {
+            self.poll_pipeline(buffer)
+        }
+    }
+
+    /// If the next request has to wait for the rate limit to allow it,
+    /// in which case it's left unparsed and reading is paused until it
+    /// does.
+    fn throttle_next_request(&mut self) -> PyResult<bool> {
+        if self.throttled {
+            return Ok(true);
+        }
+
+        let policy = self.settings.rate_limit.map(|limit| limit.policy);
+        if !matches!(policy, Some(RateLimitPolicy::Throttle)) {
+            return Ok(false);
+        }
+
+        let now = self.transport()?.now()?;
+        let has_token = match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter.has_token(now),
+            None => true,
+        };
+
+        if !has_token {
+            self.throttled = true;
+            self.transport()?.pause_reading()?;
+        }
+
+        Ok(!has_token)
+    }
+
+    /// Applies the rate limit to a new request, returning if the request
+    /// should be dispatched to the application.
+    ///
+    /// A throttled request is only parsed once a token is available so
+    /// only rejecting requests can turn one away.
+    fn check_rate_limit(&mut self, buffer: &mut BytesMut) -> PyResult<bool> {
+        let policy = match self.settings.rate_limit {
+            Some(limit) => limit.policy,
+            None => return Ok(true),
+        };
+
+        let now = self.transport()?.now()?;
+        let allowed = match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter.try_acquire(now),
+            None => true,
+        };
+
+        if allowed | matches!(policy, RateLimitPolicy::Throttle) {
+            return Ok(true);
+        }
+
+        self.upgrade = None;
+        self.reject_request(
+            buffer,
+            StatusCode::TOO_MANY_REQUESTS,
+            "request rate limit exceeded",
+        )?;
+        Ok(false)
+    }
+
+    /// Aborts the current request with a `503 Service Unavailable` if the
+    /// application has not progressed the outstanding response within the
+    /// configured response timeout.
+    fn poll_response_timeout(&mut self) -> PyResult<()> {
+        let (timeout, last) =
+            match (self.settings.response_timeout, self.response_activity) {
+                (Some(timeout), Some(last)) => (timeout, last),
+                _ => return Ok(()),
+            };
+
+        // Event streams are expected to go quiet between events.
+        if self.is_event_stream() {
+            return Ok(());
+        }
+
+        // The application may be waiting on the rest of the body, which is
+        // covered by the body timeout instead.
+        let transport = self.transport()?;
+        if self.chunked_encoding | (self.expected_content_length > 0) {
+            self.set_response_activity(Some(transport.now()?));
+            return Ok(());
+        }
+
+        if transport.now()?.saturating_sub(last) < timeout {
+            return Ok(());
+        }
+
+        warn!(
+            connection_id = transport.connection_id,
+            peer:% = transport.client;
+            "application failed to complete the response for {} within {:?}, \
+            aborting request",
+            transport.client, timeout,
+        );
+        self.abort_request(StatusCode::SERVICE_UNAVAILABLE)
+    }
+
+    /// Aborts the request in progress once one of its timeouts expires.
+    ///
+    /// If the application has yet to start the response the client is sent
+    /// the given status instead, the application's handles are detached so
+    /// nothing it sends afterwards is written. Otherwise the response can't
+    /// be salvaged and the connection is closed straight away.
+    fn abort_request(&mut self, status: StatusCode) -> PyResult<()> {
+        self.expected_content_length = 0;
+        self.chunked_encoding = false;
+        self.chunk_remaining = 0;
+        self.chunk_suffix = false;
+        self.body_activity = None;
+        self.echo = None;
+        self.set_response_activity(None);
+        self.keep_alive = false;
+        self.transport()?.pause_reading()?;
+
+        // The response may have already been sent in full, the rest of the
+        // body is then only being read to be discarded.
+        let started = self.response_stats.as_ref().is_none_or(|s| s.status != 0);
+        if started | self.file.is_some() {
+            return self.transport()?.close();
+        }
+
+        self.sender.close();
+        self.sender = SenderFactory::new(self.callback.clone(), self.settings.clone());
+        self.receiver = ReceiverFactory::new();
+        self.sender.send_empty_response(status, &[], false);
+
+        self.transport()?.resume_writing()
+    }
+
+    /// Starts draining the connection, returning if it can be closed
+    /// immediately as no request is in flight, otherwise it's closed once
+    /// the current response completes.
+    pub(crate) fn drain(&mut self) -> bool {
+        self.keep_alive = false;
+        self.at_request_boundary()
+    }
+}
+
+impl ProtocolBuffers for H1Protocol {
+    /// Parses data received from the given buffer and separates the content
+    /// accordingly.
+    ///
+    /// This callback is invoked just after the auto protocol's buffer
+    /// has been acquired and filled. It's not guaranteed to be filled
+    /// fully but it is guaranteed to have at least been filled up with
+    /// one or more bytes of data.
+    ///
+    /// Upon no data being read signalling a EOF the eof_received callback is
+    /// invoked and handled instead.
+    ///
+    /// Every complete request in the buffer is handled, requests pipelined
+    /// behind a response in progress are queued so the responses are
+    /// written in the order the requests were received.
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        loop {
+            // Anything after an upgrade to h2c is replayed into HTTP/2.
+            if self.h2c_upgrade.is_some() {
+                break;
+            }
+
+            // A connection cannot serve any more HTTP requests after upgrading.
+            if self.upgrade.is_some() && (self.expected_content_length == 0) {
+                return self.on_pipelined_after_upgrade(buffer);
+            }
+
+            if (self.expected_content_length == 0) & !self.chunked_encoding {
+                if buffer.is_empty() {
+                    break;
+                }
+
+                if self.response_in_progress() {
+                    self.queue_pipelined(buffer)?;
+                    break;
+                }
+
+                if self.throttle_next_request()? {
+                    break;
+                }
+
+                // Nothing more can be done until the rest of the head arrives.
+                let len = buffer.len();
+                self.parser_request(buffer)?;
+                if buffer.len() == len {
+                    break;
+                }
+            }
+
+            if self.chunked_encoding {
+                self.parse_chunked_body(buffer)?;
+            } else if self.expected_content_length > 0 {
+                self.parse_body(buffer)?;
+            } else {
+                let _ = self.receiver.send((false, BytesMut::with_capacity(0)));
+            }
+
+            // The rest of the body is yet to arrive or is held back.
+            if self.chunked_encoding | (self.expected_content_length > 0) {
+                self.body_activity = Some(self.transport()?.now()?);
+                break;
+            }
+        }
+
+        self.transport()?.resume_writing()?;
+        Ok(())
+    }
+
+    /// Fills the passed buffer with any messages enqueued to be sent.
+    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        self.fill_write_queue(buffer, None)
+    }
+}
+
+impl H1Protocol {
+    /// Fills the passed buffer with any messages enqueued to be sent.
+    ///
+    /// If a chunk queue is given any large body chunks are queued behind
+    /// the contents of the buffer, which is moved to the queue, so they can
+    /// be sent with a single vectored write instead of being copied. The
+    /// buffer is always written after everything in the queue.
+    pub(crate) fn fill_write_queue(
+        &mut self,
+        buffer: &mut BytesMut,
+        mut chunks: Option<&mut VecDeque<Bytes>>,
+    ) -> PyResult<()> {
+        // The interim response always goes ahead of the final response.
+        if let Some(interim) = self.interim.as_ref().and_then(|c| c.take_requested()) {
+            self.bytes_queued += interim.len();
+            buffer.extend_from_slice(interim);
+            self.interim = None;
+        }
+
+        // Anything queued behind a file waits until it has been sent, the
+        // rest stays queued while too much is waiting on the socket so the
+        // application is told to wait rather than buffering without bound.
+        while self.file.is_none() & !self.above_high_water() {
+            let (more_body, keep_alive, body, on_written) = match self.sender.recv() {
+                Ok(payload) => payload,
+                Err(_) => break,
+            };
+
+            let activity = if more_body {
+                Some(self.transport()?.now()?)
+            } else {
+                None
+            };
+            self.set_response_activity(activity);
+
+            // Either side can ask for the connection to be closed, connections
+            // are also closed after their current response while draining.
+            self.keep_alive &= keep_alive & !self.settings.is_draining();
+
+            match body {
+                // Nothing about the response is known from an interim one.
+                Body::Interim(buff) => {
+                    self.bytes_queued += buff.len();
+                    buffer.extend(buff);
+                },
+                Body::Bytes(buff) => {
+                    self.bytes_queued += buff.len();
+                    self.observe_response(&buff)?;
+
+                    match chunks.as_deref_mut() {
+                        // Large chunks are written alongside the buffer rather
+                        // than being copied into it.
+                        Some(chunks) if buff.len() >= MIN_VECTORED_SIZE => {
+                            if !buffer.is_empty() {
+                                chunks.push_back(buffer.split().freeze());
+                            }
+                            chunks.push_back(Bytes::from(buff));
+                        },
+                        _ => buffer.extend(buff),
+                    }
+                },
+                Body::File {
+                    file,
+                    prefix,
+                    suffix,
+                } => {
+                    let len = prefix.len() + file.remaining() as usize + suffix.len();
+                    self.bytes_queued += len;
+
+                    // Responses sent by the server start with their head.
+                    self.observe_response(&prefix)?;
+                    if let Some(stats) = self.response_stats.as_mut() {
+                        stats.size += len - prefix.len();
+                    }
+
+                    buffer.extend(prefix);
+                    self.file = Some((file, suffix, more_body));
+                },
+            }
+
+            if let Some(cb) = on_written {
+                self.write_callbacks.push_back((self.bytes_queued, cb));
+            }
+
+            // The response is only complete once the file has been sent.
+            if self.file.is_none() {
+                self.on_body_queued(more_body)?;
+            }
+        }
+
+        // Written after everything queued so it never splits an event.
+        if self.heartbeat_due & self.file.is_none() {
+            self.heartbeat_due = false;
+
+            if self.is_event_stream() {
+                self.bytes_queued += EVENT_STREAM_HEARTBEAT.len();
+                if let Some(stats) = self.response_stats.as_mut() {
+                    stats.size += EVENT_STREAM_HEARTBEAT.len();
+                }
+
+                buffer.extend_from_slice(EVENT_STREAM_HEARTBEAT);
+                self.set_response_activity(Some(self.transport()?.now()?));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parser_request(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS_LIMIT];
+        let max_headers = self.settings.max_headers_count.min(MAX_HEADERS_LIMIT);
+
+        let body = buffer.clone();
+
+        let mut request = Request::new(&mut headers[..max_headers]);
+        let status = match request.parse(&body) {
+            Ok(status) => status,
+            Err(httparse::Error::TooManyHeaders) => {
+                return self.reject_request(
+                    buffer,
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "too many request headers",
+                )
+            },
+            Err(e) => return self.reject_bad_request(buffer, &e.to_string()),
+        };
+
+        let len = if status.is_partial() {
+            // Stop the head from being buffered forever, this also stops
+            // junk that will never be a valid request line.
+            if buffer.len() > self.settings.max_header_size {
+                return self.reject_request(
+                    buffer,
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "request head too large",
+                );
+            }
+
+            if self.head_started.is_none() {
+                self.head_started = Some(self.transport()?.now()?);
+            }
+
+            return Ok(());
+        } else {
+            status.unwrap()
+        };
+
+        self.head_started = None;
+
+        if len > self.settings.max_header_size {
+            return self.reject_request(
+                buffer,
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "request head too large",
+            );
+        }
+
+        if let Err(reason) = validate_request_line(&request) {
+            return self.reject_bad_request(buffer, reason);
+        }
+
+        if self.settings.strict_parsing {
+            if let Err(reason) = validate_strict(&body[..len], &request) {
+                return self.reject_bad_request(buffer, reason);
+            }
+        }
+
+        let _ = buffer.split_to(len);
+
+        self.on_request_parse(buffer, &mut request, len)?;
+
+        if self.upgrade.is_some() && (self.expected_content_length == 0) {
+            return self.on_pipelined_after_upgrade(buffer);
+        }
+
+        Ok(())
+    }
+
+    /// If the current request asks to upgrade to h2c and the upgrade can
+    /// be accepted.
+    ///
+    /// Only the first request on a connection is upgraded so nothing can
+    /// be pipelined ahead of it, and only if it has no body as the body
+    /// would have to be read before switching. Any other request asking
+    /// for h2c is served over HTTP/1.1 as if it never asked.
+    fn accepts_h2c(&self, is_http_10: bool) -> bool {
+        self.settings.http2
+            & self.stream_version.is_none()
+            & !is_http_10
+            & (self.requests_received == 1)
+            & (self.http2_settings == 1)
+            & self.h2c_settings.is_some()
+            & (self.expected_content_length == 0)
+            & !self.chunked_encoding
+            & self
+                .upgrade
+                .as_deref()
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("h2c"))
+    }
+
+    /// Accepts an upgrade to h2c, reading is paused until the connection
+    /// has been switched over to HTTP/2 which then serves the request on
+    /// its first stream.
+    ///
+    /// The request's headers become the fields of the stream, less those
+    /// only meant for this connection or the upgrade.
+    fn upgrade_h2c(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> PyResult<()> {
+        let mut fields = vec![
+            (b":method".to_vec(), method.as_bytes().to_vec()),
+            (b":scheme".to_vec(), b"http".to_vec()),
+            (b":path".to_vec(), path.as_bytes().to_vec()),
+        ];
+        let mut regular = Vec::with_capacity(headers.len());
+        for header in headers.iter() {
+            let name = header.name.to_ascii_lowercase();
+            match name.as_str() {
+                "host" => fields.push((b":authority".to_vec(), header.value.to_vec())),
+                "connection" | "keep-alive" | "proxy-connection" | "te"
+                | "transfer-encoding" | "upgrade" | HTTP2_SETTINGS => {},
+                _ => regular.push((name.into_bytes(), header.value.to_vec())),
+            }
+        }
+        if self.accepts_trailers {
+            regular.push((TE.as_str().as_bytes().to_vec(), b"trailers".to_vec()));
+        }
+        fields.extend(regular);
+
+        self.upgrade = None;
+        self.h2c_upgrade = Some(H2cUpgrade {
+            fields,
+            settings: self.h2c_settings.take().unwrap_or_default(),
+        });
+        self.transport()?.pause_reading()?;
+
+        let headers = [(CONNECTION.as_str(), "Upgrade"), (UPGRADE.as_str(), "h2c")];
+        self.sender
+            .send_empty_response(StatusCode::SWITCHING_PROTOCOLS, &headers, true);
+
+        Ok(())
+    }
+
+    /// Handles any requests pipelined behind a request asking to upgrade
+    /// the connection according to the configured policy.
+    fn on_pipelined_after_upgrade(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        match self.settings.pipelined_upgrade {
+            PipelinedUpgradePolicy::Discard => {
+                debug!("discarding {} bytes pipelined after upgrade", buffer.len());
+                buffer.clear();
+                Ok(())
+            },
+            PipelinedUpgradePolicy::Reject => {
+                self.reject_bad_request(buffer, "request pipelined after upgrade")
+            },
+        }
+    }
+
+    /// Responds with a `400 Bad Request` and closes the connection,
+    /// discarding anything left in the buffer.
+    fn reject_bad_request(
+        &mut self,
+        buffer: &mut BytesMut,
+        reason: &str,
+    ) -> PyResult<()> {
+        self.reject_request(buffer, StatusCode::BAD_REQUEST, reason)
+    }
+
+    /// Responds with the given error status and closes the connection,
+    /// discarding anything left in the buffer.
+    fn reject_request(
+        &mut self,
+        buffer: &mut BytesMut,
+        status: StatusCode,
+        reason: &str,
+    ) -> PyResult<()> {
+        let transport = self.transport()?;
+        debug!(
+            connection_id = transport.connection_id,
+            peer:% = transport.client,
+            status = status.as_u16();
+            "rejecting request with {}: {}", status, reason,
+        );
+
+        self.stop_reading(buffer)?;
+
+        // The request line may not have been parsed.
+        if self.response_stats.is_none() {
+            self.start_response("-", "-", "-", &[])?;
+        }
+        self.sender.send_empty_response(status, &[], false);
+
+        Ok(())
+    }
+
+    /// Stops reading from a connection that is closed once the current
+    /// response is written, discarding anything left in the buffer.
+    fn stop_reading(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        buffer.clear();
+        self.pipelined.clear();
+        self.keep_alive = false;
+        self.expected_content_length = 0;
+        self.chunked_encoding = false;
+        self.chunk_remaining = 0;
+        self.chunk_suffix = false;
+        self.transport()?.pause_reading()
+    }
+
+    /// If the connection can be reused after answering the current request
+    /// without the application, reading is stopped if it can't.
+    ///
+    /// The body is never read so the connection can't be reused if the
+    /// request has one.
+    fn reuse_without_body(&mut self, buffer: &mut BytesMut) -> PyResult<bool> {
+        let has_body = (self.expected_content_length > 0) | self.chunked_encoding;
+        if !self.keep_alive | has_body {
+            self.stop_reading(buffer)?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Resets the connection once a chunked body grows past the max body
+    /// size.
+    ///
+    /// Unlike a body with a `Content-Length` the size isn't known until
+    /// the application is already handling the request and may have
+    /// started responding, so there is no way to send a `413` instead.
+    fn reject_chunked_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        let transport = self.transport()?;
+        debug!(
+            connection_id = transport.connection_id,
+            peer:% = transport.client;
+            "client {} sent a chunked body larger than the max body size, \
+            resetting connection",
+            transport.client,
+        );
+
+        buffer.clear();
+        self.chunked_encoding = false;
+        self.chunk_remaining = 0;
+        self.chunk_suffix = false;
+        self.transport()?.abort()
+    }
+
+    /// Rejects a request whose chunked body can't be decoded with the
+    /// given error status, closing the connection.
+    ///
+    /// The application is already handling the request so it's aborted the
+    /// same as when it times out, if the response has already started the
+    /// connection is closed without the error status.
+    fn reject_chunked_framing(
+        &mut self,
+        buffer: &mut BytesMut,
+        status: StatusCode,
+        reason: &str,
+    ) -> PyResult<()> {
+        let transport = self.transport()?;
+        debug!(
+            connection_id = transport.connection_id,
+            peer:% = transport.client,
+            status = status.as_u16();
+            "rejecting chunked body with {}: {}", status, reason,
+        );
+
+        buffer.clear();
+        self.abort_request(status)
+    }
+
+    /// Pauses reading if the application has yet to receive the body
+    /// chunks already sent to it, returning if the body is paused.
+    fn body_backpressure(&mut self) -> PyResult<bool> {
+        if !self.receiver.is_full() {
+            return Ok(false);
+        }
+
+        if !self.body_paused {
+            self.body_paused = true;
+            self.receiver.pause();
+            self.transport()?.pause_reading()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Continues reading a paused body once the application has received
+    /// the pending chunks, parsing anything left in the buffer.
+    pub(crate) fn resume_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if !self.body_paused | self.receiver.is_full() {
+            return Ok(());
+        }
+
+        self.body_paused = false;
+        self.maybe_resume_reading()?;
+
+        if self.chunked_encoding {
+            self.parse_chunked_body(buffer)
+        } else if self.expected_content_length > 0 {
+            self.parse_body(buffer)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts the body received towards the current request's trace.
+    fn trace_body_received(&mut self, len: usize) {
+        if let Some(trace) = self.request_trace.as_mut() {
+            trace.received += len;
+        }
+    }
+
+    fn parse_chunked_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if self.body_backpressure()? {
+            return Ok(());
+        }
+
+        if let Some((more_body, data)) = self.drain_body_chunks(buffer)? {
+            self.trace_body_received(data.len());
+            self.receive_body(more_body, data);
+        }
+
+        Ok(())
+    }
+
+    /// Decodes as many chunks as are available in the buffer, returning
+    /// the decoded data if any.
+    ///
+    /// Chunks are decoded incrementally so large chunks are passed on as
+    /// they arrive rather than buffered in their entirety.
+    fn drain_body_chunks(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> PyResult<Option<(bool, BytesMut)>> {
+        let mut temp_buff = BytesMut::with_capacity(FORGIVING_BUFFER_SIZE);
+        loop {
+            if self.chunk_remaining > 0 {
+                let n = self.chunk_remaining.min(buffer.len());
+                temp_buff.extend_from_slice(&buffer.split_to(n));
+                self.chunk_remaining -= n;
+
+                if self.chunk_remaining > 0 {
+                    break;
+                }
+            }
+
+            if self.chunk_suffix {
+                if buffer.len() < 2 {
+                    break;
+                }
+
+                if &buffer[..2] != b"\r\n" {
+                    self.reject_chunked_framing(
+                        buffer,
+                        StatusCode::BAD_REQUEST,
+                        "invalid chunk terminator",
+                    )?;
+                    return Ok(None);
+                }
+
+                buffer.advance(2);
+                self.chunk_suffix = false;
+            }
+
+            if temp_buff.len() >= MIN_BUFF_SIZE {
+                return Ok(Some((true, temp_buff)));
+            }
+
+            let (start, len) = match parse_chunk_size(buffer) {
+                Ok(Status::Complete(info)) => info,
+                Ok(Status::Partial) => break,
+                Err(_) => {
+                    self.reject_chunked_framing(
+                        buffer,
+                        StatusCode::BAD_REQUEST,
+                        "invalid chunk size",
+                    )?;
+                    return Ok(None);
+                },
+            };
+
+            if len == 0 {
+                // Any trailers sent after the last chunk are handed to the
+                // application alongside it.
+                let mut trailers = [EMPTY_HEADER; MAX_HEADERS_LIMIT];
+                let max_trailers =
+                    self.settings.max_headers_count.min(MAX_HEADERS_LIMIT);
+                let res =
+                    match parse_headers(&buffer[start..], &mut trailers[..max_trailers])
+                    {
+                        Ok(res) => res,
+                        Err(httparse::Error::TooManyHeaders) => {
+                            self.reject_chunked_framing(
+                                buffer,
+                                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                                "too many trailers",
+                            )?;
+                            return Ok(None);
+                        },
+                        Err(_) => {
+                            self.reject_chunked_framing(
+                                buffer,
+                                StatusCode::BAD_REQUEST,
+                                "invalid trailers",
+                            )?;
+                            return Ok(None);
+                        },
+                    };
+
+                if let Status::Complete((end, parsed)) = res {
+                    if !parsed.is_empty() {
+                        self.receiver.set_trailers(
+                            parsed
+                                .iter()
+                                .map(|h| (h.name.to_string(), h.value.to_vec()))
+                                .collect(),
+                        );
+                    }
+
+                    buffer.advance(start + end);
+                    self.chunked_encoding = false;
+                    self.expected_content_length = 0;
+                    return Ok(Some((false, temp_buff)));
+                }
+
+                // Trailers are held to the same limit as the request head.
+                if buffer.len() - start > self.settings.max_header_size {
+                    self.reject_chunked_framing(
+                        buffer,
+                        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                        "trailers too large",
+                    )?;
+                    return Ok(None);
+                }
+
+                break;
+            }
+
+            self.chunked_size = self.chunked_size.saturating_add(len as usize);
+            if self.exceeds_max_body_size(self.chunked_size) {
+                self.reject_chunked_body(buffer)?;
+                return Ok(None);
+            }
+
+            buffer.advance(start);
+            self.chunk_remaining = len as usize;
+            self.chunk_suffix = true;
+        }
+
+        if temp_buff.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some((true, temp_buff)))
+        }
+    }
+
+    fn exceeds_max_body_size(&self, size: usize) -> bool {
+        if self.echo.is_some() & (size > MAX_ECHO_SIZE) {
+            return true;
+        }
+
+        self.settings.max_body_size.is_some_and(|max| size > max)
+    }
+
+    /// Passes a chunk of the request body on to the application, or adds
+    /// it to the body being echoed back which is sent once complete.
+    fn receive_body(&mut self, more_body: bool, data: BytesMut) {
+        let echo = match self.echo.as_mut() {
+            Some(echo) => echo,
+            None => {
+                let _ = self.receiver.send((more_body, data));
+                return;
+            },
+        };
+
+        echo.extend_from_slice(&data);
+        if !more_body {
+            let body = self.echo.take().unwrap_or_default();
+            self.send_bench_response(BenchEndpoint::Echo, &body, self.keep_alive);
+        }
+    }
+
+    /// Answers the current request from the bench mode's endpoints without
+    /// invoking the application.
+    ///
+    /// The echo endpoint buffers the body until it has been received in
+    /// full, any other endpoint responds straight away.
+    fn respond_bench(
+        &mut self,
+        buffer: &mut BytesMut,
+        endpoint: BenchEndpoint,
+        is_http_10: bool,
+    ) -> PyResult<()> {
+        let has_body = (self.expected_content_length > 0) | self.chunked_encoding;
+        if (endpoint == BenchEndpoint::Echo) & has_body {
+            if self.expected_content_length > MAX_ECHO_SIZE {
+                return self.reject_request(
+                    buffer,
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "request body too large to echo",
+                );
+            }
+
+            // HTTP/1.0 clients don't understand interim responses.
+            self.interim = None;
+            if self.expects_continue & !is_http_10 {
+                let interim = ExpectContinue::new(self.transport()?.clone());
+                interim.request()?;
+                self.interim = Some(interim);
+            }
+
+            self.echo = Some(BytesMut::new());
+            return Ok(());
+        }
+
+        let body = match endpoint {
+            BenchEndpoint::Plaintext => PLAINTEXT_BODY,
+            BenchEndpoint::Json => JSON_BODY,
+            BenchEndpoint::Echo => b"",
+        };
+
+        let keep_alive = self.reuse_without_body(buffer)?;
+        self.send_bench_response(endpoint, body, keep_alive);
+        Ok(())
+    }
+
+    fn send_bench_response(
+        &self,
+        endpoint: BenchEndpoint,
+        body: &[u8],
+        keep_alive: bool,
+    ) {
+        let headers = [(CONTENT_TYPE.as_str(), endpoint.content_type())];
+        self.sender
+            .send_static_response(StatusCode::OK, &headers, body, keep_alive);
+    }
+
+    fn parse_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if self.body_backpressure()? {
+            return Ok(());
+        }
+
+        let (more_body, data) = if buffer.len() >= self.expected_content_length {
+            let res = buffer.split_to(self.expected_content_length);
+            self.expected_content_length = 0;
+            (false, Some(res))
+        } else if buffer.len() >= MIN_BUFF_SIZE {
+            let res = buffer.clone();
+            buffer.clear();
+            self.expected_content_length -= res.len();
+            (true, Some(res))
+        } else {
+            (true, None)
+        };
+
+        if let Some(data) = data {
+            self.trace_body_received(data.len());
+            self.receive_body(more_body, data);
+        }
+
+        Ok(())
+    }
+
+    /// Turns all the headers into Python type objects and invokes the
+    /// python callback, `head_len` being the size of the request's head.
+    fn on_request_parse(
+        &mut self,
+        buffer: &mut BytesMut,
+        request: &mut Request,
+        head_len: usize,
+    ) -> PyResult<()> {
+        let method = request.method.expect("Method was None at complete parse");
+        let path = request.path.expect("Path was None at complete parse");
+        let version = request.version.expect("Version was None at complete parse");
+
+        // HTTP/1.0 connections are closed after the response unless the
+        // client explicitly asks to keep them alive.
+        let is_http_10 = version == 0;
+        let version = if is_http_10 {
+            self.keep_alive = false;
+            lsgi::HTTP_10
+        } else {
+            self.keep_alive = true;
+            lsgi::HTTP_11
+        };
+
+        // A stream is given to the application as the version it was
+        // received with rather than the HTTP/1.1 it was translated to.
+        let version = self.stream_version.unwrap_or(version);
+
+        // Already validated by `validate_request_line`.
+        let uri = path.parse::<Uri>().unwrap_or_default();
+
+        let http_version = match version {
+            lsgi::HTTP_10 => "HTTP/1.0",
+            lsgi::HTTP_2 => "HTTP/2",
+            _ => "HTTP/1.1",
+        };
+        self.start_response(method, path, http_version, request.headers)?;
+
+        if let Some(trace) = self.request_trace.as_mut() {
+            trace.received = head_len;
+        }
+        self.trace_request(TraceHook::RequestParsed)?;
+
+        // TE, Expect, Upgrade and Accept-Encoding only apply to the current
+        // request.
+        self.accepts_trailers = false;
+        self.encoding = None;
+        self.expects_continue = false;
+        self.upgrade = None;
+        self.http2_settings = 0;
+        self.h2c_settings = None;
+        self.websocket_key = None;
+        self.chunked_size = 0;
+
+        for header in request.headers.iter() {
+            self.check_header(header);
+        }
+
+        match body_framing(request.headers) {
+            Ok(BodyFraming::Length(len)) => self.expected_content_length = len,
+            Ok(BodyFraming::Chunked) => self.chunked_encoding = true,
+            Err((status, reason)) => {
+                self.upgrade = None;
+                return self.reject_request(buffer, status, reason);
+            },
+        }
+
+        // The connection is recycled once it has served enough requests.
+        self.requests_received += 1;
+        if let Some(max) = self.settings.max_requests_per_connection {
+            if self.requests_received >= max {
+                self.keep_alive = false;
+            }
+        }
+
+        self.sender.set_request_connection(RequestConnection {
+            http_10: is_http_10,
+            keep_alive: self.keep_alive,
+            accepts_trailers: self.accepts_trailers,
+        });
+
+        // Rejected before the application sees the request so a client
+        // expecting a `100 Continue` never sends the body.
+        if self.exceeds_max_body_size(self.expected_content_length) {
+            self.upgrade = None;
+            return self.reject_request(
+                buffer,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body too large",
+            );
+        }
+
+        if !self.check_rate_limit(buffer)? {
+            return Ok(());
+        }
+
+        if let Some(metrics_path) = self.settings.metrics_path.as_deref() {
+            if (method == "GET") & (uri.path() == metrics_path) {
+                let keep_alive = self.reuse_without_body(buffer)?;
+                let body = self.settings.metrics.snapshot().render();
+                let headers = [(CONTENT_TYPE.as_str(), METRICS_CONTENT_TYPE)];
+                self.sender.send_static_response(
+                    StatusCode::OK,
+                    &headers,
+                    body.as_bytes(),
+                    keep_alive,
+                );
+                return Ok(());
+            }
+        }
+
+        if self.settings.bench {
+            let endpoint = BenchEndpoint::from_path(uri.path());
+            return self.respond_bench(buffer, endpoint, is_http_10);
+        }
+
+        if self.settings.maintenance.applies_to(uri.path()) {
+            let keep_alive = self.reuse_without_body(buffer)?;
+            let maintenance = &self.settings.maintenance;
+            let headers = [(CONTENT_TYPE.as_str(), "text/plain")];
+            self.sender.send_static_response(
+                maintenance.status,
+                &headers,
+                &maintenance.body,
+                keep_alive,
+            );
+            return Ok(());
+        }
+
+        if let Some(static_files) = self.settings.static_files.as_ref() {
+            if let Some(response) =
+                static_files.respond(method, uri.path(), request.headers)
+            {
+                let keep_alive = self.reuse_without_body(buffer)?;
+                self.send_static_file(response, keep_alive);
+                return Ok(());
+            }
+        }
+
+        let allow = match self.settings.auto_options.as_ref() {
+            Some(router) if method == "OPTIONS" => router.allow(uri.path()),
+            _ => None,
+        };
+        if let Some(allow) = allow {
+            let keep_alive = self.reuse_without_body(buffer)?;
+            let headers = [(ALLOW.as_str(), allow.as_str())];
+            self.sender.send_empty_response(
+                StatusCode::NO_CONTENT,
+                &headers,
+                keep_alive,
+            );
+            return Ok(());
+        }
+
+        if self.accepts_h2c(is_http_10) {
+            return self.upgrade_h2c(method, path, request.headers);
+        }
+
+        let connection = self.connection_info()?;
+        let transport = self.transport()?;
+        let (client, schema) = self.forwarded_client(request.headers)?;
+        let server = (transport.server.ip().to_string(), transport.server.port());
+        let client = (client.ip().to_string(), client.port());
+        let scope = Python::with_gil(|py| {
+            let peer_cert = self.peer_cert.as_ref().map(|cert| {
+                (Py::from(PyBytes::new(py, &cert.der)), cert.subject.clone())
+            });
+
+            lsgi::Scope::new(
+                py,
+                version,
+                method,
+                schema,
+                uri.path(),
+                uri.query().unwrap_or(""),
+                request.headers,
+                client,
+                server,
+                peer_cert,
+                self.sender.request_id().cloned(),
+                connection,
+            )
+        });
+
+        self.set_response_activity(Some(transport.now()?));
+
+        self.websocket = self.websocket_factory()?;
+
+        let mut sender = self.sender.make_handle(self.transport()?.clone());
+        if let Some(ws) = self.websocket.as_ref() {
+            sender.set_websocket(ws.make_acceptor());
+        }
+
+        if let Some(range) = RangeRequest::from_headers(request.headers) {
+            sender.set_range(range);
+        }
+
+        let event_stream = EventStream::new();
+        sender.set_event_stream(event_stream.clone());
+        self.event_stream = Some(event_stream);
+
+        // Compressed bodies are chunked which HTTP/1.0 clients don't
+        // understand, and a HEAD response has no body to compress.
+        if let Some(encoding) = self.encoding {
+            if (version != lsgi::HTTP_10) & (method != "HEAD") {
+                sender.set_encoding(encoding);
+            }
+        }
+
+        // Each request gets its own channel so nothing left unreceived by
+        // a previous request's handler holds up this one.
+        self.receiver = ReceiverFactory::new();
+        let mut receiver = self.receiver.make_handle(self.transport()?.clone());
+
+        // HTTP/1.0 clients don't understand interim responses.
+        let has_body = (self.expected_content_length > 0) | self.chunked_encoding;
+        self.interim = None;
+        if self.expects_continue & has_body & !is_http_10 {
+            let interim = ExpectContinue::new(self.transport()?.clone());
+            match self.settings.expect_continue {
+                ExpectContinuePolicy::Auto => interim.request()?,
+                ExpectContinuePolicy::OnReceive => {
+                    sender.set_expect_continue(interim.clone());
+                    receiver.set_expect_continue(interim.clone());
+                },
+            }
+            self.interim = Some(interim);
+        }
+
+        if let Err(e) = self.callback.invoke((scope, sender, receiver)) {
+            self.sender.send_error(e);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the websocket factory for the current request if it asked
+    /// to upgrade to a websocket.
+    fn websocket_factory(&mut self) -> PyResult<Option<WebSocketFactory>> {
+        let is_websocket = self
+            .upgrade
+            .as_deref()
+            .map(|v| v.eq_ignore_ascii_case("websocket"))
+            .unwrap_or(false);
+
+        let key = match self.websocket_key.take() {
+            Some(key) if is_websocket => key,
+            _ => return Ok(None),
+        };
+
+        let key = match SecWebsocketKey::decode(&mut iter::once(&key)) {
+            Ok(key) => key,
+            Err(_) => return Ok(None),
+        };
+
+        let mut values = Vec::with_capacity(1);
+        SecWebsocketAccept::from(key).encode(&mut values);
+        let accept_key = values[0].to_str().unwrap_or_default().to_string();
+
+        Ok(Some(WebSocketFactory::new(
+            self.transport()?.clone(),
+            accept_key,
+            self.settings.max_buffered_chunks,
+        )))
+    }
+
+    /// Checks a given header to see if it changes how the request is
+    /// handled, the framing of the body is checked by `body_framing()`.
+    fn check_header(&mut self, header: &Header) {
+        if header.name == ACCEPT_ENCODING {
+            if let Some(compression) = self.settings.compression.as_ref() {
+                self.encoding = compression::negotiate(compression, header.value);
+            }
+        } else if header.name == CONNECTION {
+            // `close` wins if both are listed.
+            if has_token(header.value, b"close") {
+                self.keep_alive = false;
+            } else if has_token(header.value, b"keep-alive") {
+                self.keep_alive = true;
+            }
+        } else if header.name == UPGRADE {
+            self.upgrade = str::from_utf8(header.value).ok().map(String::from);
+        } else if header.name == SEC_WEBSOCKET_KEY {
+            self.websocket_key = headers::HeaderValue::from_bytes(header.value).ok();
+        } else if header.name == EXPECT {
+            self.expects_continue = header.value.eq_ignore_ascii_case(b"100-continue");
+        } else if header.name.eq_ignore_ascii_case(HTTP2_SETTINGS) {
+            self.http2_settings += 1;
+            self.h2c_settings = h2::decode_settings_header(header.value);
+        } else if header.name == TE {
+            // The codings are ignored as the server never applies a
+            // transfer-coding other than chunked, only trailers matter.
+            self.accepts_trailers |= header
+                .value
+                .split(|b| *b == b',')
+                .map(|v| v.split(|b| *b == b';').next().unwrap_or(v))
+                .any(|v| v.trim_ascii().eq_ignore_ascii_case(b"trailers"));
+        }
+    }
+}
+
+/// How the body of a request is framed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BodyFraming {
+    /// The body is the given length, `0` if the request has no body.
+    Length(usize),
+
+    /// The body is sent in chunks until a zero length chunk.
+    Chunked,
+}
+
+/// Works out how the body of a request is framed from its headers, returning
+/// the error status and reason if it's ambiguous or unsupported.
+///
+/// A lenient proxy in front of the server could frame these requests
+/// differently, allowing a request to be smuggled past it, so they're
+/// rejected whether or not `strict_parsing` is set.
+fn body_framing(headers: &[Header]) -> Result<BodyFraming, (StatusCode, &'static str)> {
+    let bad_request = |reason| Err((StatusCode::BAD_REQUEST, reason));
+
+    let mut length = None;
+    let mut has_transfer_encoding = false;
+    let mut chunked = false;
+    let mut unsupported = false;
+    for header in headers {
+        if header.name == CONTENT_LENGTH {
+            // Repeated values are allowed as long as they all agree.
+            for value in header.value.split(|b| *b == b',') {
+                let value = match parse_content_length(value.trim_ascii()) {
+                    Some(value) => value,
+                    None => return bad_request("invalid content-length"),
+                };
+
+                if matches!(length, Some(len) if len != value) {
+                    return bad_request("conflicting content-length");
+                }
+                length = Some(value);
+            }
+        } else if header.name == TRANSFER_ENCODING {
+            has_transfer_encoding = true;
+
+            for coding in header.value.split(|b| *b == b',') {
+                let coding = coding.split(|b| *b == b';').next().unwrap_or(coding);
+                let coding = coding.trim_ascii();
+                if coding.is_empty() {
+                    continue;
+                }
+
+                // Chunked must be the last coding applied and only once.
+                if chunked {
+                    return bad_request("chunked is not the final transfer-coding");
+                }
+
+                if coding.eq_ignore_ascii_case(b"chunked") {
+                    chunked = true;
+                } else {
+                    unsupported = true;
+                }
+            }
+        }
+    }
+
+    if !has_transfer_encoding {
+        return Ok(BodyFraming::Length(length.unwrap_or(0)));
+    }
+
+    if length.is_some() {
+        return bad_request("content-length with transfer-encoding");
+    }
+
+    if !chunked {
+        return bad_request("chunked is not the final transfer-coding");
+    }
+
+    // Only chunked is decoded, the body can't be passed on otherwise.
+    if unsupported {
+        return Err((StatusCode::NOT_IMPLEMENTED, "unsupported transfer-coding"));
+    }
+
+    Ok(BodyFraming::Chunked)
+}
+
+/// Parses the value of a `Content-Length` header, which must be digits
+/// only without a sign and fit in a `usize`.
+fn parse_content_length(value: &[u8]) -> Option<usize> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// The length of the request head at the start of the buffer if it's
+/// complete and the request has no body.
+///
+/// Upgrade requests are treated as having a body so nothing is queued
+/// behind them.
+fn bodyless_head_len(buffer: &[u8]) -> Option<usize> {
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS_LIMIT];
+    let mut request = Request::new(&mut headers);
+    let len = match request.parse(buffer) {
+        Ok(Status::Complete(len)) => len,
+        _ => return None,
+    };
+
+    let has_body = request.headers.iter().any(|h| {
+        let name = h.name.as_bytes();
+        (name.eq_ignore_ascii_case(b"content-length") & (h.value.trim_ascii() != b"0"))
+            | name.eq_ignore_ascii_case(b"transfer-encoding")
+            | name.eq_ignore_ascii_case(b"upgrade")
+    });
+
+    if has_body {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// Validates the request line of a fully parsed request returning the
+/// reason it's invalid if so.
+fn validate_request_line(request: &Request) -> Result<(), &'static str> {
+    let method = request.method.unwrap_or("");
+    if !KNOWN_METHODS.contains(&method) {
+        return Err("unsupported method");
+    }
+
+    let path = request.path.unwrap_or("");
+    if path.len() > MAX_TARGET_SIZE {
+        return Err("request target too large");
+    }
+
+    if !path.is_ascii() || path.parse::<Uri>().is_err() {
+        return Err("invalid request target");
+    }
+
+    match request.version {
+        Some(0) | Some(1) => Ok(()),
+        _ => Err("unsupported http version"),
+    }
+}
+
+/// Validates a fully parsed request head under `strict_parsing` returning
+/// the reason it's invalid if so.
+///
+/// These are requests with a head other servers could read differently,
+/// the body framing is always checked by `body_framing()`.
+fn validate_strict(head: &[u8], request: &Request) -> Result<(), &'static str> {
+    let mut lines = head.split(|b| *b == b'\n');
+
+    // The head ends with a line ending so the last split is always empty.
+    lines.next_back();
+
+    for (i, line) in lines.enumerate() {
+        if line.last() != Some(&b'\r') {
+            return Err("bare LF line ending");
+        }
+
+        if (i > 0) & matches!(line.first(), Some(b' ') | Some(b'\t')) {
+            return Err("obsolete line folding");
+        }
+    }
+
+    for header in request.headers.iter() {
+        if !header.name.bytes().all(is_tchar) {
+            return Err("invalid header name");
+        }
+    }
+
+    Ok(())
+}
+
+/// If the byte is allowed in a header name, see RFC 9110 section 5.6.2.
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() | b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pyo3::exceptions::PyBlockingIOError;
+    use pyo3::prelude::*;
+    use pyo3::types::PyModule;
+
+    use crate::settings::{
+        Maintenance, PipelinedUpgradePolicy, RateLimit, RateLimitPolicy, Router,
+        ServerSettings,
+    };
+    use crate::testing::{self, TestClient};
+
+    /// A callback counting how many times it's been called.
+    const COUNTER: &str = r#"
+class Counter:
+    calls = 0
+
+    def __call__(self):
+        self.calls += 1
+"#;
+
+    fn counter() -> PyObject {
+        Python::with_gil(|py| -> PyResult<PyObject> {
+            let module = PyModule::from_code(py, COUNTER, "counter.py", "counter")?;
+            Ok(module.getattr("Counter")?.call0()?.into())
+        })
+        .unwrap()
+    }
+
+    fn calls(counter: &PyObject) -> usize {
+        Python::with_gil(|py| counter.getattr(py, "calls")?.extract(py)).unwrap()
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+
+        // The second request waits on the response to the first.
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "path"), "/a");
+
+        client.respond(0, 200, b"a");
+        assert_eq!(client.requests(), 2);
+        assert_eq!(client.scope::<String>(1, "path"), "/b");
+
+        client.respond(1, 200, b"b");
+        let written = client.take_written();
+        let a = written.find("\r\n\r\na").unwrap();
+        let b = written.find("\r\n\r\nb").unwrap();
+        assert!(a < b);
+        assert_eq!(written.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn chunked_body_is_decoded_with_trailers() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n",
+        );
+        assert_eq!(client.receive(0), (b"hello".to_vec(), true));
+
+        client.send(b"6;ext=1\r\n world\r\n0\r\nChecksum: abc\r\n\r\n");
+        assert_eq!(client.receive(0), (b" world".to_vec(), false));
+        assert_eq!(
+            client.trailers(0),
+            vec![("Checksum".to_string(), b"abc".to_vec())],
+        );
+
+        client.respond(0, 200, b"ok");
+        assert!(client.take_written().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn content_length_body_is_received() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello");
+
+        // Small bodies are passed on once complete.
+        assert_eq!(client.receive(0), (Vec::new(), true));
+
+        // Anything after the body is the next request.
+        client.send(b"worldGET /next HTTP/1.1\r\n\r\n");
+        assert_eq!(client.receive(0), (b"helloworld".to_vec(), false));
+
+        client.respond(0, 200, b"ok");
+        assert_eq!(client.requests(), 2);
+        assert_eq!(client.scope::<String>(1, "path"), "/next");
+    }
+
+    #[test]
+    fn unknown_method_is_rejected() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"BREW /pot HTTP/1.1\r\n\r\n");
+
+        assert_eq!(client.requests(), 0);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn malformed_request_line_is_rejected() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET /\x01 HTTP/1.1\r\n\r\n");
+
+        assert_eq!(client.requests(), 0);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn unknown_method_split_across_reads_is_rejected() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"BREW /po");
+        assert_eq!(client.take_written(), "");
+        assert!(!client.is_closed());
+
+        client.send(b"t HTTP/1.1\r\n\r\n");
+        assert_eq!(client.requests(), 0);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn malformed_request_line_split_across_reads_is_rejected() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET /a");
+        assert_eq!(client.take_written(), "");
+        assert!(!client.is_closed());
+
+        client.send(b"\x01 HTTP/1.1\r\n\r\n");
+        assert_eq!(client.requests(), 0);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn http_10_connection_is_closed_after_the_response() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.0\r\n\r\n");
+
+        client.respond(0, 200, b"ok");
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.contains("connection: close\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn http_10_keep_alive_is_honoured() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+
+        client.respond(0, 200, b"ok");
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.contains("connection: keep-alive\r\n"));
+        assert!(!client.is_closed());
+
+        client.send(b"GET /next HTTP/1.0\r\n\r\n");
+        assert_eq!(client.scope::<String>(1, "path"), "/next");
+        client.respond(1, 200, b"ok");
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn oversized_head_is_rejected() {
+        let mut settings = testing::settings();
+        settings.max_header_size = 64;
+        let mut client = TestClient::new(settings);
+        client.send(b"GET / HTTP/1.1\r\nX-Padding: ");
+        client.send(&[b'a'; 128]);
+
+        assert_eq!(client.requests(), 0);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn oversized_body_is_rejected() {
+        let mut settings = testing::settings();
+        settings.max_body_size = Some(4);
+        let mut client = TestClient::new(settings);
+        client.send(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+
+        assert_eq!(client.requests(), 0);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(client.is_closed());
+    }
+
+    /// Sends the given request to a new connection, returning what was
+    /// written back after checking the connection was closed.
+    fn rejected(request: &[u8]) -> String {
+        let mut client = TestClient::new(testing::settings());
+        client.send(request);
+        assert!(client.is_closed());
+        client.take_written()
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        let requests: [&[u8]; 3] = [
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabcd\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\x01\r\n\r\n",
+        ];
+
+        for request in requests {
+            let written = rejected(request);
+            assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        }
+    }
+
+    #[test]
+    fn oversized_trailers_are_rejected() {
+        let mut settings = testing::settings();
+        settings.max_header_size = 64;
+        let mut client = TestClient::new(settings);
+        client
+            .send(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX-Pad: ");
+        client.send(&[b'a'; 128]);
+
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn ambiguous_framing_is_rejected() {
+        let requests: [&[u8]; 7] = [
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+        ];
+
+        for request in requests {
+            let written = rejected(request);
+            assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        }
+    }
+
+    #[test]
+    fn unsupported_transfer_coding_is_rejected() {
+        let written =
+            rejected(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert!(written.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+    }
+
+    #[test]
+    fn matching_content_lengths_are_accepted() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3, 3\r\n\r\nabc",
+        );
+        assert_eq!(client.receive(0), (b"abc".to_vec(), false));
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn strict_parsing_rejects_ambiguous_heads() {
+        let requests: [&[u8]; 3] = [
+            b"GET / HTTP/1.1\nHost: a\n\n",
+            b"GET / HTTP/1.1\r\nX-Folded: a\r\n b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX(Name): a\r\n\r\n",
+        ];
+
+        for request in requests {
+            let mut settings = testing::settings();
+            settings.strict_parsing = true;
+            let mut client = TestClient::new(settings);
+            client.send(request);
+
+            assert_eq!(client.requests(), 0);
+            assert!(client
+                .take_written()
+                .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+            assert!(client.is_closed());
+        }
+    }
+
+    #[test]
+    fn bare_lf_is_accepted_without_strict_parsing() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.1\nHost: a\n\n");
+        assert_eq!(client.requests(), 1);
+    }
+
+    #[test]
+    fn stalled_application_is_answered_after_response_timeout() {
+        let (mut client, time) = TestClient::with_manual_time(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+
+        time.advance(Duration::from_secs(29));
+        client.poll_timers();
+        assert!(client.take_written().is_empty());
+
+        time.advance(Duration::from_secs(1));
+        client.poll_timers();
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn stalled_response_is_closed_after_response_timeout() {
+        let (mut client, time) = TestClient::with_manual_time(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.start_response(0, 200, 2);
+
+        time.advance(Duration::from_secs(29));
+        client.poll_timers();
+        assert!(!client.is_closed());
+
+        // The head may already be out so no other status can be sent.
+        time.advance(Duration::from_secs(1));
+        client.poll_timers();
+        assert!(client.is_closed());
+        assert!(!client.take_written().contains("503"));
+    }
+
+    /// Sends a chunked response to the first request with a `Checksum`
+    /// trailer, returning what was written.
+    fn respond_with_trailers(client: &mut TestClient) -> String {
+        let trailers: Vec<&[u8]> = vec![b"checksum"];
+        client.call(
+            0,
+            "send_start",
+            (200, Vec::<(&[u8], &[u8])>::new(), trailers),
+        );
+        client.call(0, "send_body", (true, b"hello".as_ref()));
+        client.call(0, "send_body", (false, b"".as_ref()));
+        let trailers: Vec<(&[u8], &[u8])> = vec![(b"checksum", b"abc")];
+        client.call(0, "send_trailers", (trailers,));
+        client.take_written()
+    }
+
+    #[test]
+    fn trailers_are_sent_when_accepted() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\nTE: trailers\r\n\r\n");
+
+        let written = respond_with_trailers(&mut client);
+        assert!(written.contains("\r\ntrailer: checksum\r\n"));
+        assert!(written.ends_with("0\r\nchecksum: abc\r\n\r\n"));
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn trailers_are_skipped_unless_accepted() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\nTE: gzip\r\n\r\n");
+
+        let written = respond_with_trailers(&mut client);
+        assert!(!written.contains("trailer"));
+        assert!(!written.contains("checksum"));
+        assert!(written.ends_with("0\r\n\r\n"));
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn write_callback_fires_once_the_chunk_is_flushed() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.start_response(0, 200, 1000);
+        client.take_written();
+
+        client.set_write_limit(Some(100));
+        let on_written = counter();
+        let body = vec![b'a'; 1000];
+        let args = Python::with_gil(|py| (false, body, on_written.clone_ref(py)));
+        client.call(0, "send_body", args);
+
+        // Each time the socket is drained only another 100 bytes are written.
+        let mut written = client.take_written();
+        while written.len() < 1000 {
+            assert_eq!(calls(&on_written), 0, "{} bytes written", written.len());
+            client.run();
+            written.push_str(&client.take_written());
+        }
+
+        client.run();
+        assert_eq!(written.len(), 1000);
+        assert_eq!(calls(&on_written), 1);
+    }
+
+    #[test]
+    fn application_waits_once_the_chunk_cap_is_reached() {
+        let mut settings = testing::settings();
+        settings.max_buffered_chunks = 4;
+        let mut client = TestClient::new(settings);
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        client.start_response(0, 200, 8);
+        client.take_written();
+
+        // Nothing is taken off the queue while the socket can't be written to.
+        client.set_write_limit(Some(0));
+        for _ in 0..4 {
+            client.call(0, "send_body", (true, b"a".as_ref()));
+        }
+        let error = client
+            .try_call(0, "send_body", (true, b"a".as_ref()))
+            .unwrap_err();
+        let blocked = Python::with_gil(|py| error.is_instance::<PyBlockingIOError>(py));
+        assert!(blocked);
+
+        client.set_write_limit(None);
+        client.run();
+        assert_eq!(client.take_written(), "aaaa");
+        for _ in 0..3 {
+            client.call(0, "send_body", (true, b"a".as_ref()));
+        }
+        client.call(0, "send_body", (false, b"a".as_ref()));
+        assert_eq!(client.take_written(), "aaaa");
+    }
+
+    /// A websocket upgrade with a request pipelined before and after it.
+    const PIPELINED_UPGRADE: &[u8] = b"GET /a HTTP/1.1\r\n\r\n\
+        GET /ws HTTP/1.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n\
+        GET /c HTTP/1.1\r\n\r\n";
+
+    #[test]
+    fn upgrade_waits_on_prior_responses_and_discards_later_requests() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(PIPELINED_UPGRADE);
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "path"), "/a");
+
+        client.respond(0, 200, b"a");
+        assert_eq!(client.requests(), 2);
+        assert_eq!(client.scope::<String>(1, "path"), "/ws");
+
+        client.call(1, "accept_websocket", (counter(),));
+        let written = client.take_written();
+        let switched = written
+            .find("HTTP/1.1 101 Switching Protocols\r\n")
+            .unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written[..switched].ends_with("\r\n\r\na"));
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 2);
+
+        // The request pipelined after the upgrade is never served.
+        assert_eq!(client.requests(), 2);
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn request_pipelined_after_an_upgrade_can_be_rejected() {
+        let mut settings = testing::settings();
+        settings.pipelined_upgrade = PipelinedUpgradePolicy::Reject;
+        let mut client = TestClient::new(settings);
+        client.send(PIPELINED_UPGRADE);
+        client.respond(0, 200, b"a");
+
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        assert!(written.contains("\r\n\r\naHTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 2);
+        assert_eq!(client.requests(), 2);
+        assert_eq!(client.scope::<String>(1, "path"), "/ws");
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn maintenance_mode_answers_all_but_excluded_paths() {
+        let mut settings = testing::settings();
+        let exclude = vec!["/health".to_string()];
+        settings.maintenance = Maintenance::new(503, b"down".to_vec(), exclude).unwrap();
+        let mut client = TestClient::new(settings);
+
+        client.settings().maintenance.set_enabled(true);
+        client.send(b"GET /users HTTP/1.1\r\n\r\n");
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(written.ends_with("\r\n\r\ndown"));
+        assert_eq!(client.requests(), 0);
+
+        client.send(b"GET /health/ready HTTP/1.1\r\n\r\n");
+        assert_eq!(client.requests(), 1);
+        client.respond(0, 200, b"ok");
+        assert!(client.take_written().starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Turning it off takes effect for the next request.
+        client.settings().maintenance.set_enabled(false);
+        client.send(b"GET /users HTTP/1.1\r\n\r\n");
+        assert_eq!(client.requests(), 2);
+        assert!(!client.is_closed());
+    }
+
+    /// Three requests pipelined in a single read.
+    const PIPELINED: &[u8] = b"GET /a HTTP/1.1\r\n\r\n\
+        GET /b HTTP/1.1\r\n\r\n\
+        GET /c HTTP/1.1\r\n\r\n";
+
+    fn rate_limited(policy: RateLimitPolicy) -> ServerSettings {
+        let mut settings = testing::settings();
+        settings.rate_limit = Some(RateLimit {
+            requests_per_second: 1.0,
+            burst: 1,
+            policy,
+        });
+        settings
+    }
+
+    #[test]
+    fn maintenance_response_to_a_request_with_a_body_closes_the_connection() {
+        let mut settings = testing::settings();
+        settings.maintenance = Maintenance::new(503, b"down".to_vec(), vec![]).unwrap();
+        let mut client = TestClient::new(settings);
+
+        // The body is never read so nothing pipelined behind it is answered.
+        client.settings().maintenance.set_enabled(true);
+        client.send(
+            b"POST /x HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello\
+            GET /y HTTP/1.1\r\n\r\n",
+        );
+        let written = client.take_written();
+        assert_eq!(
+            written
+                .matches("HTTP/1.1 503 Service Unavailable\r\n")
+                .count(),
+            1
+        );
+        assert!(written.contains("\r\nconnection: close\r\n"));
+        assert_eq!(client.requests(), 0);
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn pipelined_requests_over_the_rate_limit_are_throttled() {
+        let settings = rate_limited(RateLimitPolicy::Throttle);
+        let (mut client, time) = TestClient::with_manual_time(settings);
+
+        client.send(PIPELINED);
+        assert_eq!(client.requests(), 1);
+        client.respond(0, 200, b"a");
+        assert_eq!(client.requests(), 1);
+
+        // Each request waits on the token it needs.
+        time.advance(Duration::from_millis(500));
+        client.poll_timers();
+        assert_eq!(client.requests(), 1);
+
+        time.advance(Duration::from_millis(500));
+        client.poll_timers();
+        assert_eq!(client.requests(), 2);
+        assert_eq!(client.scope::<String>(1, "path"), "/b");
+        client.respond(1, 200, b"b");
+        assert_eq!(client.requests(), 2);
+
+        time.advance(Duration::from_secs(1));
+        client.poll_timers();
+        assert_eq!(client.requests(), 3);
+        assert_eq!(client.scope::<String>(2, "path"), "/c");
+        client.respond(2, 200, b"c");
+        assert_eq!(
+            client.take_written().matches("HTTP/1.1 200 OK\r\n").count(),
+            3
+        );
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn pipelined_requests_over_the_rate_limit_are_rejected() {
+        let settings = rate_limited(RateLimitPolicy::Reject);
+        let (mut client, _time) = TestClient::with_manual_time(settings);
+
+        client.send(PIPELINED);
+        assert_eq!(client.requests(), 1);
+        client.respond(0, 200, b"a");
+
+        // Nothing after the rejected request is answered.
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(
+            written
+                .matches("HTTP/1.1 429 Too Many Requests\r\n")
+                .count(),
+            1
+        );
+        assert_eq!(client.requests(), 1);
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn options_are_answered_with_the_routes_methods() {
+        let routes = vec![("/users/{id}".to_string(), vec!["GET".to_string()])];
+        let mut settings = testing::settings();
+        settings.auto_options = Some(Router::new(routes.into_iter().collect()).unwrap());
+        let mut client = TestClient::new(settings);
+
+        client.send(b"OPTIONS /users/1 HTTP/1.1\r\n\r\n");
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(written.contains("\r\nallow: GET, HEAD, OPTIONS\r\n"));
+        assert_eq!(client.requests(), 0);
+
+        // Paths without a route are left to the application.
+        client.send(b"OPTIONS /posts HTTP/1.1\r\n\r\n");
+        assert_eq!(client.requests(), 1);
+        assert!(!client.is_closed());
     }
 }
@@ -0,0 +1,1698 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use bytes::{Buf, BufMut, BytesMut};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::{PyErr, PyResult};
+
+use super::hpack::{self, Decoder, Field};
+use super::translate::{ResponseSink, TranslateError, Translator};
+use super::H1Protocol;
+use crate::event_loop::{EventLoop, PreSetEventLoop};
+use crate::lsgi;
+use crate::net::NO_FD;
+use crate::server::CallbackHandler;
+use crate::settings::Settings;
+use crate::traits::{BaseTransport, ProtocolBuffers};
+use crate::transport::Transport;
+
+/// The connection preface sent by clients using HTTP/2 with prior knowledge.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The size of every frame header.
+const FRAME_HEADER_SIZE: usize = 9;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_PRIORITY: u8 = 0x2;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// The error code telling the client to retry the request using HTTP/1.1.
+const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// The size of every flow control window until changed by the peer.
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+
+/// The largest a flow control window can grow to.
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// The largest frame payload the server accepts, the peer can allow larger
+/// frames to be sent to it.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+/// The largest frame size a peer can allow.
+const MAX_FRAME_SIZE_LIMIT: u32 = (1 << 24) - 1;
+
+/// The most streams a client can have open at once.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// The translated request of a stream buffered before its window stops
+/// being replenished.
+///
+/// This is above what the H1 protocol holds back before handing a body
+/// to the application so a stream never waits on a body its window
+/// stops the client from sending.
+const MAX_STREAM_INBOUND: usize = 128 * 1024;
+
+/// The response body queued on a stream before no more is taken from its
+/// H1 protocol until some has been sent.
+const MAX_STREAM_OUTBOUND: usize = 64 * 1024;
+
+/// The result of checking the start of a connection for the HTTP/2 preface.
+pub(crate) enum Preface {
+    /// The full preface has been received.
+    Found,
+
+    /// The data so far matches the preface but more is needed to tell.
+    Partial,
+
+    /// The connection is not using HTTP/2 with prior knowledge.
+    Missing,
+}
+
+/// Checks if the given data received at the start of a connection is
+/// the HTTP/2 connection preface.
+pub(crate) fn sniff_preface(data: &[u8]) -> Preface {
+    if data.len() >= PREFACE.len() {
+        if data.starts_with(PREFACE) {
+            Preface::Found
+        } else {
+            Preface::Missing
+        }
+    } else if PREFACE.starts_with(data) {
+        Preface::Partial
+    } else {
+        Preface::Missing
+    }
+}
+
+/// Decodes the value of an `HTTP2-Settings` header, the payload of a
+/// `SETTINGS` frame encoded as base64url, `None` if it's invalid.
+pub(crate) fn decode_settings_header(value: &[u8]) -> Option<Vec<u8>> {
+    let value = value.trim_ascii();
+    let value = match value.iter().position(|&b| b == b'=') {
+        Some(pos) if value[pos..].iter().all(|&b| b == b'=') => &value[..pos],
+        Some(_) => return None,
+        None => value,
+    };
+
+    let mut payload = Vec::with_capacity(value.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &b in value {
+        let sextet = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+
+        bits = (bits << 6) | sextet as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            payload.push((bits >> count) as u8);
+        }
+    }
+
+    // A single leftover character can't encode a whole byte.
+    if (count >= 6) | !payload.len().is_multiple_of(6) {
+        return None;
+    }
+
+    Some(payload)
+}
+
+/// The request a connection was upgraded to h2c with, served on the
+/// first stream once the connection has switched over to HTTP/2.
+pub(crate) struct H2cUpgrade {
+    /// The request's fields as if it was sent over HTTP/2.
+    pub(crate) fields: Vec<Field>,
+
+    /// The settings sent in the `HTTP2-Settings` header.
+    pub(crate) settings: Vec<u8>,
+}
+
+/// Why a frame can't be handled, along with the error code sent to the
+/// peer.
+#[derive(Debug)]
+enum H2Error {
+    /// Only the stream the frame was sent on is reset.
+    Stream(u32),
+
+    /// The whole connection is closed with a `GOAWAY`.
+    Connection(u32),
+
+    /// Serving the stream raised.
+    Python(PyErr),
+}
+
+impl From<TranslateError> for H2Error {
+    fn from(e: TranslateError) -> Self {
+        match e {
+            TranslateError::Malformed => Self::Stream(PROTOCOL_ERROR),
+            TranslateError::Rejected => Self::Stream(REFUSED_STREAM),
+            TranslateError::Internal => Self::Stream(INTERNAL_ERROR),
+        }
+    }
+}
+
+impl From<PyErr> for H2Error {
+    fn from(e: PyErr) -> Self {
+        Self::Python(e)
+    }
+}
+
+/// A frame queued to be sent on a stream, sent in order as flow control
+/// allows.
+enum Outbound {
+    /// An encoded field section, along with if it's the trailers ending
+    /// the stream.
+    Fields(BytesMut, bool),
+
+    /// Some of the response body.
+    Data(BytesMut),
+
+    /// The end of the stream, unless the trailers already ended it.
+    End,
+
+    /// Resets the stream with the given error code.
+    Reset(u32),
+}
+
+/// The frames a translated response is written to.
+struct Frames<'a>(&'a mut VecDeque<Outbound>);
+
+impl ResponseSink for Frames<'_> {
+    fn fields(&mut self, fields: &[(&[u8], &[u8])], trailers: bool) {
+        let mut block = BytesMut::new();
+        hpack::encode(fields.iter().copied(), &mut block);
+        self.0.push_back(Outbound::Fields(block, trailers));
+    }
+
+    fn data(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        match self.0.back_mut() {
+            Some(Outbound::Data(queued)) => queued.extend_from_slice(data),
+            _ => self.0.push_back(Outbound::Data(BytesMut::from(data))),
+        }
+    }
+}
+
+/// A request stream, served by an H1 protocol of its own as if it were
+/// a connection.
+struct Stream {
+    h1: H1Protocol,
+
+    /// The stream's registrations with the connection, see
+    /// `EventLoop::multiplexed()`.
+    handle: PreSetEventLoop,
+
+    translator: Translator,
+
+    /// The request translated to HTTP/1.1 yet to be read by the H1
+    /// protocol.
+    inbound: BytesMut,
+
+    /// If the client has ended its side of the stream.
+    remote_closed: bool,
+
+    /// The request body received which is yet to be given back to the
+    /// client's window.
+    unacked: i64,
+
+    /// How much more of the request body the client can send.
+    recv_window: i64,
+
+    /// How much more of the response body can be sent.
+    send_window: i64,
+
+    /// The frames waiting to be sent.
+    outbound: VecDeque<Outbound>,
+
+    /// If a frame ending the stream has been sent.
+    end_sent: bool,
+
+    /// If the H1 protocol is done with the stream, it's removed once the
+    /// frames left have been sent.
+    finished: bool,
+}
+
+impl Stream {
+    /// Feeds the H1 protocol the request received so far if it's reading.
+    fn feed(&mut self) -> PyResult<()> {
+        if self.handle.is_reading() & !self.inbound.is_empty() {
+            self.h1.data_received(&mut self.inbound)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives the request body received back to the client's window as
+    /// long as the H1 protocol is keeping up with it.
+    fn replenish_window(&mut self, id: u32, control: &mut BytesMut) {
+        if (self.unacked > 0)
+            & !self.remote_closed
+            & (self.inbound.len() < MAX_STREAM_INBOUND)
+        {
+            write_window_update(control, id, self.unacked as u32);
+            self.recv_window += self.unacked;
+            self.unacked = 0;
+        }
+    }
+
+    /// The amount of the response body waiting to be sent.
+    fn queued(&self) -> usize {
+        self.outbound
+            .iter()
+            .map(|out| match out {
+                Outbound::Data(data) => data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// If the H1 protocol has more of the response to be taken once the
+    /// queue has room.
+    fn has_more(&mut self) -> bool {
+        !self.finished & (self.h1.response_queued() | self.h1.pending_file().is_some())
+    }
+
+    /// Ends the stream once the H1 protocol is done with it.
+    ///
+    /// The stream is reset if the response is incomplete, or once it has
+    /// been sent if the request is yet to be received in full as it will
+    /// never be read.
+    fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        if self.handle.take_aborting() | !self.translator.finish() {
+            self.outbound.clear();
+            self.outbound.push_back(Outbound::Reset(INTERNAL_ERROR));
+            return;
+        }
+
+        self.outbound.push_back(Outbound::End);
+        if !self.remote_closed {
+            self.outbound.push_back(Outbound::Reset(NO_ERROR));
+        }
+    }
+
+    /// Releases the stream once it's been removed.
+    fn close(&mut self) -> PyResult<()> {
+        self.h1.lost_connection()?;
+        self.handle.release()
+    }
+}
+
+/// The HTTP/2 protocol handler.
+///
+/// Each request stream is translated to HTTP/1.1 and served by an H1
+/// protocol of its own, see `protocols::translate`, which is registered
+/// with an event loop multiplexing its stream over the connection.
+///
+/// Unless HTTP/2 is enabled in the settings this only speaks enough of
+/// the framing layer to turn the client away cleanly, sending an empty
+/// `SETTINGS` frame followed by a `GOAWAY` with the `HTTP_1_1_REQUIRED`
+/// error so the client retries using HTTP/1.1.
+pub(crate) struct H2Protocol {
+    maybe_transport: Option<Transport>,
+    settings: Settings,
+    callback: CallbackHandler,
+
+    /// The event loop the streams of the connection are registered with.
+    streams_loop: Option<EventLoop>,
+
+    /// If the client has sent its preface.
+    preface_received: bool,
+
+    streams: BTreeMap<u32, Stream>,
+
+    /// The highest stream id the client has opened, new streams must use
+    /// a higher one.
+    last_stream: u32,
+
+    decoder: Decoder,
+
+    /// The stream a field section is being received on, along with if it
+    /// ends the stream and the block so far, until the block is ended by
+    /// a `CONTINUATION` frame.
+    field_block: Option<(u32, bool, BytesMut)>,
+
+    /// The frames of the connection, and resets of streams, written ahead
+    /// of the frames of any stream.
+    control: BytesMut,
+
+    /// How much more of the response bodies can be sent on the connection.
+    send_window: i64,
+
+    /// The window of each new stream set by the client.
+    initial_window: i64,
+
+    /// The largest frame payload the client accepts.
+    max_frame_size: usize,
+
+    /// The error code of the `GOAWAY` sent if any, no new streams are
+    /// accepted once it's set.
+    goaway: Option<u32>,
+
+    /// If the client has sent a `GOAWAY`.
+    peer_goaway: bool,
+}
+
+impl H2Protocol {
+    pub(crate) fn new(settings: Settings, callback: CallbackHandler) -> Self {
+        Self {
+            maybe_transport: None,
+            settings,
+            callback,
+            streams_loop: None,
+            preface_received: false,
+            streams: BTreeMap::new(),
+            last_stream: 0,
+            decoder: Decoder::new(),
+            field_block: None,
+            control: BytesMut::new(),
+            send_window: DEFAULT_WINDOW_SIZE,
+            initial_window: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            goaway: None,
+            peer_goaway: false,
+        }
+    }
+
+    /// Get the set transport or raise an error.
+    #[inline]
+    fn transport(&self) -> PyResult<&Transport> {
+        self.maybe_transport.as_ref().ok_or_else(|| {
+            PyRuntimeError::new_err("transport was None upon being called")
+        })
+    }
+
+    /// Called when the protocol is in charge of a new socket / handle.
+    pub(crate) fn new_connection(&mut self, transport: Transport) {
+        self.streams_loop = Some(transport.multiplexed_loop());
+        self.maybe_transport = Some(transport);
+        self.preface_received = false;
+        self.streams.clear();
+        self.last_stream = 0;
+        self.decoder = Decoder::new();
+        self.field_block = None;
+        self.control.clear();
+        self.send_window = DEFAULT_WINDOW_SIZE;
+        self.initial_window = DEFAULT_WINDOW_SIZE;
+        self.max_frame_size = DEFAULT_MAX_FRAME_SIZE;
+        self.goaway = None;
+        self.peer_goaway = false;
+
+        if self.settings.http2 {
+            let max_header_list = self.settings.max_header_size as u32;
+            write_frame_header(&mut self.control, 12, FRAME_SETTINGS, 0, 0);
+            self.control.put_u16(SETTINGS_MAX_CONCURRENT_STREAMS);
+            self.control.put_u32(MAX_CONCURRENT_STREAMS as u32);
+            self.control.put_u16(SETTINGS_MAX_HEADER_LIST_SIZE);
+            self.control.put_u32(max_header_list);
+        }
+    }
+
+    /// Called when the connection is lost from the protocol in order to
+    /// properly reset state, every stream is lost along with it.
+    pub(crate) fn lost_connection(&mut self) -> PyResult<()> {
+        self.control.clear();
+        for (_, mut stream) in std::mem::take(&mut self.streams) {
+            stream.close()?;
+        }
+
+        Ok(())
+    }
+
+    /// If anything is waiting to be written, either frames or a stream
+    /// with more of its response.
+    pub(crate) fn wants_write(&self) -> bool {
+        let window = self.send_window > 0;
+        !self.control.is_empty()
+            | (self.preface_received
+                & self.streams.values().any(|stream| {
+                    let sendable = match stream.outbound.front() {
+                        Some(Outbound::Data(_)) => window & (stream.send_window > 0),
+                        Some(_) => true,
+                        None => stream.finished,
+                    };
+                    sendable | stream.handle.is_writing()
+                }))
+    }
+
+    /// If any streams are open, the connection is not idle until they
+    /// have been answered.
+    pub(crate) fn is_long_lived(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    /// If the connection should be closed now everything queued has been
+    /// written.
+    ///
+    /// A connection error closes it straight away, otherwise it's closed
+    /// once the streams left after either side sent a `GOAWAY` are done.
+    pub(crate) fn is_closing(&self) -> bool {
+        if !self.control.is_empty() {
+            return false;
+        }
+
+        match self.goaway {
+            Some(NO_ERROR) => self.streams.is_empty(),
+            Some(_) => true,
+            None => self.peer_goaway & self.streams.is_empty(),
+        }
+    }
+
+    /// Polls the timers of every stream.
+    pub(crate) fn poll_timers(&mut self) -> PyResult<()> {
+        for stream in self.streams.values_mut() {
+            stream.h1.poll_timers(&mut stream.inbound)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts draining the connection, no new streams are accepted and
+    /// the connection is closed once the streams already open are done.
+    pub(crate) fn drain(&mut self) -> PyResult<()> {
+        if self.goaway.is_none() {
+            self.goaway = Some(NO_ERROR);
+            write_goaway(&mut self.control, self.last_stream, NO_ERROR);
+        }
+
+        self.transport()?.resume_writing()
+    }
+
+    /// Turns the client away when HTTP/2 is not enabled.
+    fn turn_away(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        // Everything after the preface is ignored as the connection is
+        // about to be closed.
+        buffer.clear();
+
+        if self.goaway.is_none() {
+            self.goaway = Some(HTTP_1_1_REQUIRED);
+            write_frame_header(&mut self.control, 0, FRAME_SETTINGS, 0, 0);
+            write_goaway(&mut self.control, 0, HTTP_1_1_REQUIRED);
+
+            let transport = self.transport()?;
+            transport.pause_reading()?;
+            transport.resume_writing()?;
+        }
+
+        Ok(())
+    }
+
+    /// Serves the request a connection was upgraded to h2c with on the
+    /// first stream, which is half closed as the request had no body.
+    ///
+    /// The client's settings from the upgrade apply straight away, they're
+    /// not acknowledged.
+    pub(crate) fn upgrade(&mut self, upgrade: H2cUpgrade) -> PyResult<()> {
+        let result = self
+            .apply_settings(&upgrade.settings)
+            .and_then(|()| self.open_stream(1, upgrade.fields, true));
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(H2Error::Stream(code)) => self.reset_stream(1, code),
+            Err(H2Error::Connection(code)) => self.connection_error(code),
+            Err(H2Error::Python(e)) => Err(e),
+        }
+    }
+
+    /// Closes the connection with a `GOAWAY` carrying the error code, every
+    /// stream is reset along with it.
+    fn connection_error(&mut self, code: u32) -> PyResult<()> {
+        self.goaway = Some(code);
+        self.control.clear();
+        write_goaway(&mut self.control, self.last_stream, code);
+
+        for (_, mut stream) in std::mem::take(&mut self.streams) {
+            stream.close()?;
+        }
+
+        self.transport()?.pause_reading()
+    }
+
+    /// Resets the stream with the error code, the stream is forgotten
+    /// straight away.
+    fn reset_stream(&mut self, id: u32, code: u32) -> PyResult<()> {
+        if let Some(mut stream) = self.streams.remove(&id) {
+            stream.close()?;
+        }
+
+        write_frame_header(&mut self.control, 4, FRAME_RST_STREAM, 0, id);
+        self.control.put_u32(code);
+
+        Ok(())
+    }
+
+    /// Handles a frame, the frame header has already been checked.
+    fn on_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        id: u32,
+        payload: BytesMut,
+    ) -> Result<(), H2Error> {
+        // Nothing can be sent between the frames of a field section.
+        if let Some((block_id, _, _)) = self.field_block.as_ref() {
+            if (kind != FRAME_CONTINUATION) | (id != *block_id) {
+                return Err(H2Error::Connection(PROTOCOL_ERROR));
+            }
+        }
+
+        match kind {
+            FRAME_DATA => self.on_data(flags, id, payload),
+            FRAME_HEADERS => self.on_headers(flags, id, payload),
+            FRAME_PRIORITY => {
+                if id == 0 {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 5 {
+                    return Err(H2Error::Stream(FRAME_SIZE_ERROR));
+                }
+                Ok(())
+            },
+            FRAME_RST_STREAM => {
+                if (id == 0) | (id > self.last_stream) {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 4 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if let Some(mut stream) = self.streams.remove(&id) {
+                    stream.close()?;
+                }
+                Ok(())
+            },
+            FRAME_SETTINGS => self.on_settings(flags, id, payload),
+            FRAME_PUSH_PROMISE => Err(H2Error::Connection(PROTOCOL_ERROR)),
+            FRAME_PING => {
+                if id != 0 {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 8 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if flags & FLAG_ACK == 0 {
+                    write_frame_header(&mut self.control, 8, FRAME_PING, FLAG_ACK, 0);
+                    self.control.extend_from_slice(&payload);
+                }
+                Ok(())
+            },
+            FRAME_GOAWAY => {
+                if id != 0 {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() < 8 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+                }
+                self.peer_goaway = true;
+                Ok(())
+            },
+            FRAME_WINDOW_UPDATE => self.on_window_update(id, payload),
+            FRAME_CONTINUATION => {
+                let (block_id, end_stream, mut block) = match self.field_block.take() {
+                    Some(field_block) => field_block,
+                    None => return Err(H2Error::Connection(PROTOCOL_ERROR)),
+                };
+
+                block.extend_from_slice(&payload);
+                if block.len() > self.settings.max_header_size {
+                    return Err(H2Error::Connection(ENHANCE_YOUR_CALM));
+                }
+
+                if flags & FLAG_END_HEADERS == 0 {
+                    self.field_block = Some((block_id, end_stream, block));
+                    return Ok(());
+                }
+
+                self.on_field_block(id, end_stream, &block)
+            },
+            // Unknown frames are ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, flags: u8, id: u32, payload: BytesMut) -> Result<(), H2Error> {
+        if id == 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR));
+        }
+
+        // The connection's window is given back straight away, each
+        // stream's window bounds what it buffers.
+        let len = payload.len() as i64;
+        if len > 0 {
+            write_window_update(&mut self.control, 0, len as u32);
+        }
+
+        let data = strip_padding(flags, payload)?;
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None if id > self.last_stream => {
+                return Err(H2Error::Connection(PROTOCOL_ERROR))
+            },
+            // The stream has been closed, anything already in flight is
+            // ignored.
+            None => return Ok(()),
+        };
+
+        if stream.remote_closed {
+            return Err(H2Error::Stream(STREAM_CLOSED));
+        }
+
+        stream.recv_window -= len;
+        if stream.recv_window < 0 {
+            return Err(H2Error::Stream(FLOW_CONTROL_ERROR));
+        }
+        stream.unacked += len;
+
+        stream.translator.recv_body(&data, &mut stream.inbound)?;
+        if flags & FLAG_END_STREAM != 0 {
+            stream.translator.recv_fin(&mut stream.inbound)?;
+            stream.remote_closed = true;
+        }
+
+        stream.feed()?;
+        stream.replenish_window(id, &mut self.control);
+        Ok(())
+    }
+
+    fn on_headers(
+        &mut self,
+        flags: u8,
+        id: u32,
+        payload: BytesMut,
+    ) -> Result<(), H2Error> {
+        if id == 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR));
+        }
+
+        let mut block = strip_padding(flags, payload)?;
+        if flags & FLAG_PRIORITY != 0 {
+            if block.len() < 5 {
+                return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+            }
+            block.advance(5);
+        }
+
+        if block.len() > self.settings.max_header_size {
+            return Err(H2Error::Connection(ENHANCE_YOUR_CALM));
+        }
+
+        let end_stream = flags & FLAG_END_STREAM != 0;
+        if flags & FLAG_END_HEADERS == 0 {
+            self.field_block = Some((id, end_stream, block));
+            return Ok(());
+        }
+
+        self.on_field_block(id, end_stream, &block)
+    }
+
+    /// Handles a complete field section, opening a new stream or ending
+    /// the request of an open stream with its trailers.
+    ///
+    /// Every field section is decoded, even those of streams that are
+    /// refused, as each one can change the decoder's dynamic table.
+    fn on_field_block(
+        &mut self,
+        id: u32,
+        end_stream: bool,
+        block: &[u8],
+    ) -> Result<(), H2Error> {
+        let fields = self
+            .decoder
+            .decode(block)
+            .map_err(|_| H2Error::Connection(COMPRESSION_ERROR))?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.remote_closed {
+                return Err(H2Error::Stream(STREAM_CLOSED));
+            }
+
+            // Trailers have to end the stream and can't have pseudo-headers.
+            let pseudo = fields.iter().any(|(name, _)| name.starts_with(b":"));
+            if !end_stream | pseudo {
+                return Err(H2Error::Stream(PROTOCOL_ERROR));
+            }
+
+            stream
+                .translator
+                .recv_trailers(&fields, &mut stream.inbound)?;
+            stream.translator.recv_fin(&mut stream.inbound)?;
+            stream.remote_closed = true;
+            stream.feed()?;
+            return Ok(());
+        }
+
+        // The stream has been closed.
+        if id <= self.last_stream {
+            return Ok(());
+        }
+
+        // Only servers can open even numbered streams.
+        if id.is_multiple_of(2) {
+            return Err(H2Error::Connection(PROTOCOL_ERROR));
+        }
+
+        self.open_stream(id, fields, end_stream)
+    }
+
+    /// Opens a new stream with the fields of its request, passing the
+    /// request on to a H1 protocol of its own.
+    fn open_stream(
+        &mut self,
+        id: u32,
+        fields: Vec<Field>,
+        end_stream: bool,
+    ) -> Result<(), H2Error> {
+        self.last_stream = id;
+
+        let refused = self.goaway.is_some()
+            | self.peer_goaway
+            | self.settings.is_draining()
+            | (self.streams.len() >= MAX_CONCURRENT_STREAMS);
+        if refused {
+            return Err(H2Error::Stream(REFUSED_STREAM));
+        }
+
+        let mut translator = Translator::new();
+        let mut inbound = BytesMut::new();
+        translator.recv_head(&fields, !end_stream, &mut inbound)?;
+        if end_stream {
+            translator.recv_fin(&mut inbound)?;
+        }
+
+        let streams_loop = match self.streams_loop.as_ref() {
+            Some(streams_loop) => streams_loop.clone(),
+            None => return Err(H2Error::Connection(INTERNAL_ERROR)),
+        };
+        let handle = PreSetEventLoop::new(streams_loop, NO_FD, id as usize);
+        let transport = self.transport()?.for_stream(handle.clone());
+
+        let mut h1 = H1Protocol::new(self.settings.clone(), self.callback.clone());
+        h1.new_stream(transport, lsgi::HTTP_2);
+
+        let stream = self.streams.entry(id).or_insert(Stream {
+            h1,
+            handle,
+            translator,
+            inbound,
+            remote_closed: end_stream,
+            unacked: 0,
+            recv_window: DEFAULT_WINDOW_SIZE,
+            send_window: self.initial_window,
+            outbound: VecDeque::new(),
+            end_sent: false,
+            finished: false,
+        });
+
+        stream.handle.add_reader()?;
+        stream.feed()?;
+        Ok(())
+    }
+
+    fn on_settings(
+        &mut self,
+        flags: u8,
+        id: u32,
+        payload: BytesMut,
+    ) -> Result<(), H2Error> {
+        if id != 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR));
+        }
+
+        if flags & FLAG_ACK != 0 {
+            if !payload.is_empty() {
+                return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+            }
+            return Ok(());
+        }
+
+        if !payload.len().is_multiple_of(6) {
+            return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+        }
+
+        self.apply_settings(&payload)?;
+        write_frame_header(&mut self.control, 0, FRAME_SETTINGS, FLAG_ACK, 0);
+        Ok(())
+    }
+
+    /// Applies the client's settings, the payload is a whole number of
+    /// settings.
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), H2Error> {
+        for setting in payload.chunks(6) {
+            let value =
+                u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR))
+                },
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(H2Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+
+                    // The change applies to the windows of the open streams.
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(H2Error::Connection(FLOW_CONTROL_ERROR));
+                        }
+                    }
+                    self.initial_window = value;
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=MAX_FRAME_SIZE_LIMIT)
+                        .contains(&value)
+                    {
+                        return Err(H2Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.max_frame_size = value as usize;
+                },
+                // Responses are encoded without the dynamic table so its
+                // size doesn't matter, nothing is ever pushed either.
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, id: u32, payload: BytesMut) -> Result<(), H2Error> {
+        if payload.len() != 4 {
+            return Err(H2Error::Connection(FRAME_SIZE_ERROR));
+        }
+
+        let increment =
+            (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                & 0x7fff_ffff) as i64;
+
+        if id == 0 {
+            if increment == 0 {
+                return Err(H2Error::Connection(PROTOCOL_ERROR));
+            }
+
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(H2Error::Connection(FLOW_CONTROL_ERROR));
+            }
+            return Ok(());
+        }
+
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None if id > self.last_stream => {
+                return Err(H2Error::Connection(PROTOCOL_ERROR))
+            },
+            None => return Ok(()),
+        };
+
+        if increment == 0 {
+            return Err(H2Error::Stream(PROTOCOL_ERROR));
+        }
+
+        stream.send_window += increment;
+        if stream.send_window > MAX_WINDOW_SIZE {
+            return Err(H2Error::Stream(FLOW_CONTROL_ERROR));
+        }
+
+        Ok(())
+    }
+
+    /// Drives the H1 protocol of a stream that's ready to write, feeding it
+    /// any more of the request and translating what it has written of
+    /// the response into frames.
+    fn poll_stream(&mut self, id: u32) -> Result<(), H2Error> {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        if stream.finished | !stream.handle.is_writing() {
+            return Ok(());
+        }
+
+        stream.handle.remove_writer()?;
+        stream.h1.resume_body(&mut stream.inbound)?;
+        stream.replenish_window(id, &mut self.control);
+
+        let mut response = BytesMut::new();
+        let mut file_sent = 0;
+        if stream.queued() < MAX_STREAM_OUTBOUND {
+            stream.h1.fill_write_queue(&mut response, None)?;
+
+            while stream.queued() + response.len() < MAX_STREAM_OUTBOUND {
+                let file = match stream.h1.pending_file() {
+                    Some(file) => file,
+                    None => break,
+                };
+
+                let chunk = file.read_chunk().map_err(PyErr::from)?;
+                if chunk.is_empty() {
+                    // The file was truncated after the response started.
+                    return Err(H2Error::Stream(INTERNAL_ERROR));
+                }
+                file.advance(chunk.len());
+                response.extend_from_slice(&chunk);
+                file_sent += chunk.len();
+                stream.h1.file_sent(chunk.len(), &mut response)?;
+            }
+        }
+
+        let translated = stream
+            .translator
+            .send(&response, &mut Frames(&mut stream.outbound));
+        stream.h1.write_drained(response.len() - file_sent);
+        translated?;
+
+        let responding =
+            stream.h1.pending_file().is_some() | stream.h1.response_queued();
+        if !responding && stream.h1.write_flushed() {
+            stream.finish();
+        }
+
+        Ok(())
+    }
+
+    /// Writes the frames of the connection then those of each stream as
+    /// far as flow control allows, removing the streams that are done.
+    fn write_frames(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        buffer.extend_from_slice(&self.control);
+        self.control.clear();
+
+        // A stream opened by an upgrade to h2c waits on the client's
+        // preface, which follows the `101 Switching Protocols`.
+        if !self.preface_received {
+            return Ok(());
+        }
+
+        let mut done = Vec::new();
+        for (&id, stream) in self.streams.iter_mut() {
+            while let Some(out) = stream.outbound.front_mut() {
+                match out {
+                    Outbound::Fields(block, end_stream) => {
+                        write_fields(
+                            buffer,
+                            id,
+                            block,
+                            *end_stream,
+                            self.max_frame_size,
+                        );
+                        stream.end_sent |= *end_stream;
+                    },
+                    Outbound::Data(data) => {
+                        let window = self.send_window.min(stream.send_window);
+                        if window <= 0 {
+                            break;
+                        }
+
+                        let len =
+                            data.len().min(window as usize).min(self.max_frame_size);
+                        write_frame_header(buffer, len, FRAME_DATA, 0, id);
+                        buffer.extend_from_slice(&data.split_to(len));
+                        self.send_window -= len as i64;
+                        stream.send_window -= len as i64;
+
+                        if !data.is_empty() {
+                            continue;
+                        }
+                    },
+                    Outbound::End => {
+                        if !stream.end_sent {
+                            write_frame_header(
+                                buffer,
+                                0,
+                                FRAME_DATA,
+                                FLAG_END_STREAM,
+                                id,
+                            );
+                            stream.end_sent = true;
+                        }
+                    },
+                    Outbound::Reset(code) => {
+                        write_frame_header(buffer, 4, FRAME_RST_STREAM, 0, id);
+                        buffer.put_u32(*code);
+                        stream.outbound.clear();
+                        break;
+                    },
+                }
+
+                stream.outbound.pop_front();
+            }
+
+            if stream.finished & stream.outbound.is_empty() {
+                done.push(id);
+            } else if stream.has_more() & (stream.queued() < MAX_STREAM_OUTBOUND) {
+                stream.handle.add_writer()?;
+            }
+        }
+
+        for id in done {
+            if let Some(mut stream) = self.streams.remove(&id) {
+                stream.close()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtocolBuffers for H2Protocol {
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        if !self.settings.http2 {
+            return self.turn_away(buffer);
+        }
+
+        // Nothing more is read after a connection error.
+        if matches!(self.goaway, Some(code) if code != NO_ERROR) {
+            buffer.clear();
+            return Ok(());
+        }
+
+        if !self.preface_received {
+            match sniff_preface(buffer) {
+                Preface::Partial => return Ok(()),
+                Preface::Missing => {
+                    buffer.clear();
+                    return self.connection_error(PROTOCOL_ERROR);
+                },
+                Preface::Found => {
+                    buffer.advance(PREFACE.len());
+                    self.preface_received = true;
+                },
+            }
+        }
+
+        while buffer.len() >= FRAME_HEADER_SIZE {
+            let len = ((buffer[0] as usize) << 16)
+                | ((buffer[1] as usize) << 8)
+                | buffer[2] as usize;
+            if len > DEFAULT_MAX_FRAME_SIZE {
+                buffer.clear();
+                self.connection_error(FRAME_SIZE_ERROR)?;
+                break;
+            }
+
+            if buffer.len() < FRAME_HEADER_SIZE + len {
+                break;
+            }
+
+            let mut payload = buffer.split_to(FRAME_HEADER_SIZE + len);
+            let header = payload.split_to(FRAME_HEADER_SIZE);
+            let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff;
+
+            match self.on_frame(header[3], header[4], id, payload) {
+                Ok(()) => {},
+                Err(H2Error::Stream(code)) => self.reset_stream(id, code)?,
+                Err(H2Error::Connection(code)) => {
+                    buffer.clear();
+                    self.connection_error(code)?;
+                    break;
+                },
+                Err(H2Error::Python(e)) => return Err(e),
+            }
+        }
+
+        self.transport()?.resume_writing()
+    }
+
+    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        let ids: Vec<u32> = self.streams.keys().copied().collect();
+        for id in ids {
+            match self.poll_stream(id) {
+                Ok(()) => {},
+                Err(H2Error::Stream(code)) => self.reset_stream(id, code)?,
+                Err(H2Error::Connection(code)) => self.connection_error(code)?,
+                Err(H2Error::Python(e)) => return Err(e),
+            }
+        }
+
+        // Streams whose H1 protocol closed its side are done, the response
+        // is reset unless it was already complete.
+        if let Some(streams_loop) = self.streams_loop.as_ref() {
+            for index in streams_loop.take_closed() {
+                if let Some(stream) = self.streams.get_mut(&(index as u32)) {
+                    stream.finish();
+                }
+            }
+        }
+
+        self.write_frames(buffer)
+    }
+}
+
+/// Writes a frame header.
+fn write_frame_header(
+    buffer: &mut BytesMut,
+    length: usize,
+    kind: u8,
+    flags: u8,
+    id: u32,
+) {
+    buffer.put_uint(length as u64, 3);
+    buffer.put_u8(kind);
+    buffer.put_u8(flags);
+    buffer.put_u32(id);
+}
+
+/// Writes a `GOAWAY` frame with the last stream id that was processed.
+fn write_goaway(buffer: &mut BytesMut, last_stream: u32, code: u32) {
+    write_frame_header(buffer, 8, FRAME_GOAWAY, 0, 0);
+    buffer.put_u32(last_stream);
+    buffer.put_u32(code);
+}
+
+fn write_window_update(buffer: &mut BytesMut, id: u32, increment: u32) {
+    write_frame_header(buffer, 4, FRAME_WINDOW_UPDATE, 0, id);
+    buffer.put_u32(increment);
+}
+
+/// Writes a field section as a `HEADERS` frame followed by as many
+/// `CONTINUATION` frames as the max frame size needs.
+fn write_fields(
+    buffer: &mut BytesMut,
+    id: u32,
+    block: &[u8],
+    end_stream: bool,
+    max_frame_size: usize,
+) {
+    let mut kind = FRAME_HEADERS;
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+    let mut rest = block;
+
+    loop {
+        let (fragment, tail) = rest.split_at(rest.len().min(max_frame_size));
+        rest = tail;
+        if rest.is_empty() {
+            flags |= FLAG_END_HEADERS;
+        }
+
+        write_frame_header(buffer, fragment.len(), kind, flags, id);
+        buffer.extend_from_slice(fragment);
+
+        if rest.is_empty() {
+            break;
+        }
+
+        kind = FRAME_CONTINUATION;
+        flags = 0;
+    }
+}
+
+/// Removes the padding of a padded frame.
+fn strip_padding(flags: u8, mut payload: BytesMut) -> Result<BytesMut, H2Error> {
+    if flags & FLAG_PADDED == 0 {
+        return Ok(payload);
+    }
+
+    let padding = match payload.first() {
+        Some(&padding) if (padding as usize) < payload.len() => padding as usize,
+        _ => return Err(H2Error::Connection(PROTOCOL_ERROR)),
+    };
+
+    payload.advance(1);
+    payload.truncate(payload.len() - padding);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+
+    use super::*;
+    use crate::protocols::{ALPN_H2, ALPN_HTTP_11};
+    use crate::testing::{self, TestClient};
+
+    type Frame = (u8, u8, u32, Vec<u8>);
+
+    fn client() -> TestClient {
+        let mut settings = testing::settings();
+        settings.http2 = true;
+
+        let mut client = TestClient::new(settings);
+        let mut start = PREFACE.to_vec();
+        start.extend(frame(FRAME_SETTINGS, 0, 0, &[]));
+        client.send(&start);
+
+        // The server's settings then its ack of the client's.
+        let frames = frames(&client.take_written_bytes());
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].0, frames[0].1), (FRAME_SETTINGS, 0));
+        assert_eq!((frames[1].0, frames[1].1), (FRAME_SETTINGS, FLAG_ACK));
+        client
+    }
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        write_frame_header(&mut buffer, payload.len(), kind, flags, id);
+        buffer.put_slice(payload);
+        buffer.to_vec()
+    }
+
+    fn headers(id: u32, fields: &[(&str, &str)], end_stream: bool) -> Vec<u8> {
+        let mut block = BytesMut::new();
+        hpack::encode(
+            fields.iter().map(|(n, v)| (n.as_bytes(), v.as_bytes())),
+            &mut block,
+        );
+
+        let flags = FLAG_END_HEADERS | if end_stream { FLAG_END_STREAM } else { 0 };
+        frame(FRAME_HEADERS, flags, id, &block)
+    }
+
+    fn get(id: u32, path: &str) -> Vec<u8> {
+        let fields = [(":method", "GET"), (":scheme", "http"), (":path", path)];
+        headers(id, &fields, true)
+    }
+
+    fn frames(mut data: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let len = u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize;
+            let id = u32::from_be_bytes([data[5], data[6], data[7], data[8]]);
+            let payload = data[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+            frames.push((data[3], data[4], id, payload));
+            data = &data[FRAME_HEADER_SIZE + len..];
+        }
+        frames
+    }
+
+    /// The frames of a response, without any window updates.
+    fn response(client: &TestClient) -> Vec<Frame> {
+        frames(&client.take_written_bytes())
+            .into_iter()
+            .filter(|(kind, _, _, _)| *kind != FRAME_WINDOW_UPDATE)
+            .collect()
+    }
+
+    fn decode(decoder: &mut Decoder, block: &[u8]) -> Vec<(String, String)> {
+        decoder
+            .decode(block)
+            .unwrap()
+            .into_iter()
+            .map(|(n, v)| (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn prior_knowledge_request_is_served() {
+        let mut client = client();
+        client.send(&get(1, "/hello?a=1"));
+
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "http_version"), "2");
+        assert_eq!(client.scope::<String>(0, "method"), "GET");
+        assert_eq!(client.scope::<String>(0, "path"), "/hello");
+        assert_eq!(client.scope::<Vec<u8>>(0, "query_string"), b"a=1");
+
+        client.respond(0, 200, b"hello");
+        let frames = response(&client);
+        assert_eq!(frames.len(), 3, "{:?}", frames);
+
+        let (kind, flags, id, block) = &frames[0];
+        assert_eq!((*kind, *flags, *id), (FRAME_HEADERS, FLAG_END_HEADERS, 1));
+        let fields = decode(&mut Decoder::new(), block);
+        assert_eq!(fields[0], (":status".into(), "200".into()));
+        assert!(fields.contains(&("content-length".into(), "5".into())));
+        assert!(!fields.iter().any(|(name, _)| name == "connection"));
+
+        assert_eq!(frames[1], (FRAME_DATA, 0, 1, b"hello".to_vec()));
+        assert_eq!(frames[2], (FRAME_DATA, FLAG_END_STREAM, 1, Vec::new()));
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn request_body_is_received() {
+        let mut client = client();
+        let fields = [
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", "/upload"),
+            ("content-length", "11"),
+        ];
+        client.send(&headers(1, &fields, false));
+        client.send(&frame(FRAME_DATA, 0, 1, b"hello "));
+        client.send(&frame(FRAME_DATA, FLAG_END_STREAM, 1, b"world"));
+
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.receive(0), (b"hello world".to_vec(), false));
+
+        // The body is given back to both windows.
+        let updates: Vec<Frame> = frames(&client.take_written_bytes())
+            .into_iter()
+            .filter(|(kind, _, id, _)| (*kind == FRAME_WINDOW_UPDATE) & (*id == 0))
+            .collect();
+        assert_eq!(updates.len(), 2);
+    }
+
+    #[test]
+    fn streams_are_multiplexed() {
+        let mut client = client();
+        client.send(&get(1, "/slow"));
+        client.send(&get(3, "/fast"));
+        assert_eq!(client.requests(), 2);
+
+        client.respond(1, 200, b"fast");
+        let frames = response(&client);
+        assert!(frames.iter().all(|(_, _, id, _)| *id == 3));
+        assert_eq!(frames.last().unwrap().1, FLAG_END_STREAM);
+
+        client.respond(0, 200, b"slow");
+        let frames = response(&client);
+        assert!(frames.iter().all(|(_, _, id, _)| *id == 1));
+        assert_eq!(frames[1].3, b"slow");
+    }
+
+    #[test]
+    fn header_blocks_share_the_dynamic_table() {
+        let mut client = client();
+
+        // A literal added to the dynamic table, then the same field by index.
+        let mut first = BytesMut::new();
+        hpack::encode([(&b":method"[..], &b"GET"[..])], &mut first);
+        hpack::encode([(&b":scheme"[..], &b"http"[..])], &mut first);
+        hpack::encode([(&b":path"[..], &b"/"[..])], &mut first);
+        first.put_u8(0x40);
+        first.put_u8(8);
+        first.put_slice(b"x-tenant");
+        first.put_u8(4);
+        first.put_slice(b"acme");
+        client.send(&frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            1,
+            &first,
+        ));
+
+        let mut second = first.split_to(3);
+        second.put_u8(0x80 | 62);
+        client.send(&frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            3,
+            &second,
+        ));
+
+        assert_eq!(client.requests(), 2);
+        for index in 0..2 {
+            let headers: Vec<(Vec<u8>, Vec<u8>)> = client.scope(index, "headers");
+            assert!(headers.contains(&(b"x-tenant".to_vec(), b"acme".to_vec())));
+        }
+    }
+
+    #[test]
+    fn continuation_frames_are_joined() {
+        let mut client = client();
+        let mut request = get(1, "/split");
+        let block = request.split_off(FRAME_HEADER_SIZE);
+
+        client.send(&frame(FRAME_HEADERS, FLAG_END_STREAM, 1, &block[..2]));
+        assert_eq!(client.requests(), 0);
+        client.send(&frame(FRAME_CONTINUATION, FLAG_END_HEADERS, 1, &block[2..]));
+
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "path"), "/split");
+    }
+
+    #[test]
+    fn malformed_request_resets_the_stream() {
+        let mut client = client();
+        let fields = [(":method", "GET"), (":path", "/")];
+        client.send(&headers(1, &fields, true));
+
+        assert_eq!(client.requests(), 0);
+        let frames = response(&client);
+        assert_eq!(
+            frames,
+            vec![(
+                FRAME_RST_STREAM,
+                0,
+                1,
+                PROTOCOL_ERROR.to_be_bytes().to_vec()
+            )]
+        );
+
+        // The connection is still usable.
+        client.send(&get(3, "/"));
+        assert_eq!(client.requests(), 1);
+    }
+
+    #[test]
+    fn te_other_than_trailers_resets_the_stream() {
+        let mut client = client();
+        let fields = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            ("te", "gzip"),
+        ];
+        client.send(&headers(1, &fields, true));
+
+        assert_eq!(client.requests(), 0);
+        let frames = response(&client);
+        let code = PROTOCOL_ERROR.to_be_bytes().to_vec();
+        assert_eq!(frames, vec![(FRAME_RST_STREAM, 0, 1, code)]);
+    }
+
+    #[test]
+    fn te_trailers_passes_response_trailers_through() {
+        let mut client = client();
+        let fields = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            ("te", "trailers"),
+        ];
+        client.send(&headers(1, &fields, true));
+        assert_eq!(client.requests(), 1);
+
+        let trailers: Vec<&[u8]> = vec![b"checksum"];
+        client.call(
+            0,
+            "send_start",
+            (200, Vec::<(&[u8], &[u8])>::new(), trailers),
+        );
+        client.call(0, "send_body", (true, b"hello".as_ref()));
+        client.call(0, "send_body", (false, b"".as_ref()));
+        let trailers: Vec<(&[u8], &[u8])> = vec![(b"checksum", b"abc")];
+        client.call(0, "send_trailers", (trailers,));
+
+        // The trailers end the stream in place of an empty DATA frame.
+        let frames = response(&client);
+        assert_eq!(frames.len(), 3, "{:?}", frames);
+        assert_eq!(frames[1], (FRAME_DATA, 0, 1, b"hello".to_vec()));
+
+        let (kind, flags, id, block) = &frames[2];
+        let end = FLAG_END_HEADERS | FLAG_END_STREAM;
+        assert_eq!((*kind, *flags, *id), (FRAME_HEADERS, end, 1));
+        let fields = decode(&mut Decoder::new(), block);
+        assert_eq!(fields, vec![("checksum".into(), "abc".into())]);
+    }
+
+    #[test]
+    fn ping_is_acknowledged() {
+        let mut client = client();
+        client.send(&frame(FRAME_PING, 0, 0, b"12345678"));
+
+        let frames = response(&client);
+        assert_eq!(
+            frames,
+            vec![(FRAME_PING, FLAG_ACK, 0, b"12345678".to_vec())]
+        );
+    }
+
+    #[test]
+    fn response_waits_on_the_flow_control_window() {
+        let mut client = client();
+        client.send(&frame(FRAME_SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 3]));
+        client.take_written_bytes();
+
+        client.send(&get(1, "/"));
+        client.respond(0, 200, b"hello");
+        let frames = response(&client);
+        assert_eq!(frames[1], (FRAME_DATA, 0, 1, b"hel".to_vec()));
+        assert_eq!(frames.len(), 2);
+
+        client.send(&frame(FRAME_WINDOW_UPDATE, 0, 1, &10u32.to_be_bytes()));
+        let frames = response(&client);
+        assert_eq!(frames[0], (FRAME_DATA, 0, 1, b"lo".to_vec()));
+        assert_eq!(frames[1], (FRAME_DATA, FLAG_END_STREAM, 1, Vec::new()));
+    }
+
+    #[test]
+    fn connection_error_sends_goaway() {
+        let mut client = client();
+        client.send(&get(2, "/"));
+
+        let frames = response(&client);
+        let mut payload = 0u32.to_be_bytes().to_vec();
+        payload.extend(PROTOCOL_ERROR.to_be_bytes());
+        assert_eq!(frames, vec![(FRAME_GOAWAY, 0, 0, payload)]);
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn alpn_h2_is_served() {
+        let mut settings = testing::settings();
+        settings.http2 = true;
+        let mut client = TestClient::new(settings);
+        client.select_alpn(ALPN_H2);
+
+        let mut data = PREFACE.to_vec();
+        data.extend(get(1, "/"));
+        client.send(&data);
+
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "http_version"), "2");
+    }
+
+    #[test]
+    fn alpn_http_11_is_not_sniffed() {
+        let mut settings = testing::settings();
+        settings.http2 = true;
+        let mut client = TestClient::new(settings);
+        client.select_alpn(ALPN_HTTP_11);
+
+        client.send(PREFACE);
+        assert!(client
+            .take_written()
+            .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    const H2C_UPGRADE: &[u8] = b"GET /up?a=1 HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Connection: Upgrade, HTTP2-Settings\r\n\
+        Upgrade: h2c\r\n\
+        HTTP2-Settings: AAMAAABkAAQAAP__\r\n\
+        Accept: */*\r\n\r\n";
+
+    #[test]
+    fn h2c_upgrade_request_is_served_on_the_first_stream() {
+        let mut settings = testing::settings();
+        settings.http2 = true;
+        let mut client = TestClient::new(settings);
+        client.send(H2C_UPGRADE);
+
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "http_version"), "2");
+        assert_eq!(client.scope::<String>(0, "path"), "/up");
+        assert_eq!(client.scope::<Vec<u8>>(0, "query_string"), b"a=1");
+
+        let switching = b"HTTP/1.1 101 Switching Protocols\r\n";
+        let written = client.take_written_bytes();
+        assert!(written.starts_with(switching));
+        let head_end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let settings = frames(&written[head_end + 4..]);
+        assert_eq!(settings.len(), 1, "{:?}", settings);
+        assert_eq!((settings[0].0, settings[0].1), (FRAME_SETTINGS, 0));
+
+        // The response waits on the client's preface.
+        client.respond(0, 200, b"up");
+        assert!(client.take_written_bytes().is_empty());
+
+        let mut start = PREFACE.to_vec();
+        start.extend(frame(FRAME_SETTINGS, 0, 0, &[]));
+        client.send(&start);
+
+        // Only the settings frame is acked, those of the upgrade are not.
+        let frames = response(&client);
+        assert_eq!(frames.len(), 4, "{:?}", frames);
+        assert_eq!(frames[0], (FRAME_SETTINGS, FLAG_ACK, 0, Vec::new()));
+        assert_eq!((frames[1].0, frames[1].2), (FRAME_HEADERS, 1));
+        let fields = decode(&mut Decoder::new(), &frames[1].3);
+        assert_eq!(fields[0], (":status".into(), "200".into()));
+        assert_eq!(frames[2], (FRAME_DATA, 0, 1, b"up".to_vec()));
+        assert_eq!(frames[3], (FRAME_DATA, FLAG_END_STREAM, 1, Vec::new()));
+
+        // The upgrade took the first stream.
+        client.send(&get(3, "/next"));
+        assert_eq!(client.requests(), 2);
+        assert_eq!(client.scope::<String>(1, "path"), "/next");
+    }
+
+    #[test]
+    fn h2c_upgrade_with_invalid_settings_is_served_over_http_11() {
+        let mut settings = testing::settings();
+        settings.http2 = true;
+        let mut client = TestClient::new(settings);
+        let request = String::from_utf8(H2C_UPGRADE.to_vec()).unwrap();
+        client.send(request.replace("AAMAAABkAAQAAP__", "AAMA!").as_bytes());
+
+        assert_eq!(client.requests(), 1);
+        assert_eq!(client.scope::<String>(0, "http_version"), "1.1");
+        client.respond(0, 200, b"ok");
+        assert!(client.take_written().starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn settings_header_is_decoded() {
+        let settings = [0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 0xff, 0xff];
+        assert_eq!(
+            decode_settings_header(b"AAMAAABkAAQAAP__").unwrap(),
+            settings
+        );
+        assert_eq!(
+            decode_settings_header(b" AAMAAABkAAQAAP__= ").unwrap(),
+            settings
+        );
+        assert_eq!(decode_settings_header(b"").unwrap(), Vec::<u8>::new());
+        assert!(decode_settings_header(b"AAMAAABkAAQAAP/+").is_none());
+        assert!(decode_settings_header(b"AAMA").is_none());
+        assert!(decode_settings_header(b"AAMAAABkA=AQAAP__").is_none());
+    }
+
+    #[test]
+    fn client_is_turned_away_unless_enabled() {
+        let mut client = TestClient::new(testing::settings());
+        client.send(PREFACE);
+
+        let frames = frames(&client.take_written_bytes());
+        let mut payload = 0u32.to_be_bytes().to_vec();
+        payload.extend(HTTP_1_1_REQUIRED.to_be_bytes());
+        assert_eq!(frames[0], (FRAME_SETTINGS, 0, 0, Vec::new()));
+        assert_eq!(frames[1], (FRAME_GOAWAY, 0, 0, payload));
+        assert!(client.is_closed());
+    }
+}
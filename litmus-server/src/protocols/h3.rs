@@ -0,0 +1,435 @@
+//! HTTP/3 framing over the streams of a QUIC connection, see RFC 9114.
+//!
+//! Rather than a protocol of its own each request stream is translated to
+//! an HTTP/1.1 request and handed to the same H1 protocol as any other
+//! connection, see `protocols::translate`. The HTTP/1.1 response written
+//! back is then translated into frames on the stream.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use super::qpack;
+use super::translate::{Field, ResponseSink, TranslateError, Translator};
+
+const FRAME_DATA: u64 = 0x0;
+const FRAME_HEADERS: u64 = 0x1;
+const FRAME_CANCEL_PUSH: u64 = 0x3;
+const FRAME_SETTINGS: u64 = 0x4;
+const FRAME_PUSH_PROMISE: u64 = 0x5;
+const FRAME_GOAWAY: u64 = 0x7;
+const FRAME_MAX_PUSH_ID: u64 = 0xd;
+
+/// The frame types of HTTP/2 which have no HTTP/3 equivalent, these are
+/// an error if received.
+const RESERVED_FRAMES: [u64; 4] = [0x2, 0x6, 0x8, 0x9];
+
+const STREAM_CONTROL: u64 = 0x0;
+const STREAM_PUSH: u64 = 0x1;
+const STREAM_QPACK_ENCODER: u64 = 0x2;
+const STREAM_QPACK_DECODER: u64 = 0x3;
+
+/// The setting ids of HTTP/2 which have no HTTP/3 equivalent, these are
+/// an error if received.
+const RESERVED_SETTINGS: [u64; 4] = [0x2, 0x3, 0x4, 0x5];
+
+const SETTINGS_MAX_FIELD_SECTION_SIZE: u64 = 0x6;
+
+pub(crate) const H3_NO_ERROR: u64 = 0x100;
+pub(crate) const H3_INTERNAL_ERROR: u64 = 0x102;
+pub(crate) const H3_STREAM_CREATION_ERROR: u64 = 0x103;
+pub(crate) const H3_CLOSED_CRITICAL_STREAM: u64 = 0x104;
+const H3_FRAME_UNEXPECTED: u64 = 0x105;
+const H3_FRAME_ERROR: u64 = 0x106;
+const H3_EXCESSIVE_LOAD: u64 = 0x107;
+const H3_SETTINGS_ERROR: u64 = 0x109;
+const H3_MISSING_SETTINGS: u64 = 0x10a;
+pub(crate) const H3_REQUEST_REJECTED: u64 = 0x10b;
+pub(crate) const H3_REQUEST_CANCELLED: u64 = 0x10c;
+const H3_REQUEST_INCOMPLETE: u64 = 0x10d;
+const H3_MESSAGE_ERROR: u64 = 0x10e;
+
+/// An error handling a stream, either resetting just the stream or
+/// closing the whole connection with the error code.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum H3Error {
+    Stream(u64),
+    Connection(u64),
+}
+
+impl From<TranslateError> for H3Error {
+    fn from(e: TranslateError) -> Self {
+        match e {
+            TranslateError::Malformed => Self::Stream(H3_MESSAGE_ERROR),
+            TranslateError::Rejected => Self::Stream(H3_REQUEST_REJECTED),
+            TranslateError::Internal => Self::Stream(H3_INTERNAL_ERROR),
+        }
+    }
+}
+
+/// Writes a variable-length integer, see RFC 9000 section 16.
+pub(crate) fn encode_varint(out: &mut BytesMut, value: u64) {
+    if value < (1 << 6) {
+        out.put_u8(value as u8);
+    } else if value < (1 << 14) {
+        out.put_u16(0x4000 | value as u16);
+    } else if value < (1 << 30) {
+        out.put_u32(0x8000_0000 | value as u32);
+    } else {
+        out.put_u64(0xc000_0000_0000_0000 | value);
+    }
+}
+
+/// Reads a variable-length integer returning it along with its length,
+/// `None` if the buffer doesn't hold all of it.
+pub(crate) fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    if buf.len() < len {
+        return None;
+    }
+
+    let mut value = (first & 0x3f) as u64;
+    for byte in &buf[1..len] {
+        value = (value << 8) | *byte as u64;
+    }
+
+    Some((value, len))
+}
+
+/// Reads the type and payload length of the frame at the start of the
+/// buffer along with the length of the frame header.
+fn decode_frame_header(buf: &[u8]) -> Option<(u64, u64, usize)> {
+    let (kind, kind_len) = decode_varint(buf)?;
+    let (len, len_len) = decode_varint(&buf[kind_len..])?;
+    Some((kind, len, kind_len + len_len))
+}
+
+fn encode_frame(out: &mut BytesMut, kind: u64, payload: &[u8]) {
+    encode_varint(out, kind);
+    encode_varint(out, payload.len() as u64);
+    out.put_slice(payload);
+}
+
+/// The start of the server's control stream, its type followed by the
+/// server's settings.
+pub(crate) fn control_stream(max_field_section_size: usize) -> BytesMut {
+    let mut settings = BytesMut::new();
+    encode_varint(&mut settings, SETTINGS_MAX_FIELD_SECTION_SIZE);
+    encode_varint(&mut settings, max_field_section_size as u64);
+
+    let mut out = BytesMut::new();
+    encode_varint(&mut out, STREAM_CONTROL);
+    encode_frame(&mut out, FRAME_SETTINGS, &settings);
+    out
+}
+
+/// A `GOAWAY` frame telling the client no request stream from the given
+/// id onwards will be processed.
+pub(crate) fn goaway(stream_id: u64) -> BytesMut {
+    let mut id = BytesMut::new();
+    encode_varint(&mut id, stream_id);
+
+    let mut out = BytesMut::new();
+    encode_frame(&mut out, FRAME_GOAWAY, &id);
+    out
+}
+
+/// A unidirectional stream opened by the client.
+pub(crate) enum UniStream {
+    /// The stream's type is yet to be received.
+    Pending(BytesMut),
+
+    /// The client's control stream.
+    Control(ControlStream),
+
+    /// A QPACK encoder or decoder stream, without a dynamic table there is
+    /// nothing sent on these which needs acting on.
+    Ignored,
+}
+
+impl UniStream {
+    pub(crate) fn new() -> Self {
+        Self::Pending(BytesMut::new())
+    }
+
+    /// Handles data received on the stream, returning `false` if the
+    /// stream is of an unknown type and should no longer be read.
+    ///
+    /// `has_control` is set once the client's control stream is opened,
+    /// only one can be opened per connection.
+    pub(crate) fn recv(
+        &mut self,
+        data: &[u8],
+        has_control: &mut bool,
+    ) -> Result<bool, H3Error> {
+        let pending = match self {
+            Self::Pending(pending) => pending,
+            Self::Control(control) => return control.recv(data).map(|_| true),
+            Self::Ignored => return Ok(true),
+        };
+
+        pending.extend_from_slice(data);
+        let (kind, len) = match decode_varint(pending) {
+            Some(kind) => kind,
+            None => return Ok(true),
+        };
+        let rest = pending.split_off(len);
+
+        match kind {
+            STREAM_CONTROL if *has_control => {
+                Err(H3Error::Connection(H3_STREAM_CREATION_ERROR))
+            },
+            STREAM_CONTROL => {
+                *has_control = true;
+                let mut control = ControlStream::default();
+                control.recv(&rest)?;
+                *self = Self::Control(control);
+                Ok(true)
+            },
+            // Clients can't push.
+            STREAM_PUSH => Err(H3Error::Connection(H3_STREAM_CREATION_ERROR)),
+            STREAM_QPACK_ENCODER | STREAM_QPACK_DECODER => {
+                *self = Self::Ignored;
+                Ok(true)
+            },
+            _ => {
+                *self = Self::Ignored;
+                Ok(false)
+            },
+        }
+    }
+
+    /// Handles the stream being finished by the client, closing a control
+    /// stream is an error.
+    pub(crate) fn finished(&self) -> Result<(), H3Error> {
+        match self {
+            Self::Control(_) => Err(H3Error::Connection(H3_CLOSED_CRITICAL_STREAM)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The client's control stream, the only frames acted on are the client's
+/// settings which must come first.
+#[derive(Default)]
+pub(crate) struct ControlStream {
+    inbound: BytesMut,
+    has_settings: bool,
+}
+
+impl ControlStream {
+    fn recv(&mut self, data: &[u8]) -> Result<(), H3Error> {
+        self.inbound.extend_from_slice(data);
+
+        while let Some((kind, len, header_len)) = decode_frame_header(&self.inbound) {
+            let frame_len = header_len + len as usize;
+            if self.inbound.len() < frame_len {
+                // Nothing acted on is large, anything else is a flood.
+                if len > u16::MAX as u64 {
+                    return Err(H3Error::Connection(H3_EXCESSIVE_LOAD));
+                }
+                break;
+            }
+
+            let payload = self.inbound.split_to(frame_len).split_off(header_len);
+            if !self.has_settings & (kind != FRAME_SETTINGS) {
+                return Err(H3Error::Connection(H3_MISSING_SETTINGS));
+            }
+
+            match kind {
+                FRAME_SETTINGS if self.has_settings => {
+                    return Err(H3Error::Connection(H3_FRAME_UNEXPECTED))
+                },
+                FRAME_SETTINGS => {
+                    self.has_settings = true;
+                    check_settings(&payload)?;
+                },
+                FRAME_DATA | FRAME_HEADERS | FRAME_PUSH_PROMISE => {
+                    return Err(H3Error::Connection(H3_FRAME_UNEXPECTED))
+                },
+                kind if RESERVED_FRAMES.contains(&kind) => {
+                    return Err(H3Error::Connection(H3_FRAME_UNEXPECTED))
+                },
+                // Nothing is ever pushed, the client's `GOAWAY` and
+                // `MAX_PUSH_ID` only concern pushes.
+                FRAME_GOAWAY | FRAME_MAX_PUSH_ID | FRAME_CANCEL_PUSH => {},
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the client's settings are valid, none of them change how the
+/// server behaves.
+fn check_settings(mut payload: &[u8]) -> Result<(), H3Error> {
+    while !payload.is_empty() {
+        let (id, id_len) =
+            decode_varint(payload).ok_or(H3Error::Connection(H3_FRAME_ERROR))?;
+        let (_, value_len) = decode_varint(&payload[id_len..])
+            .ok_or(H3Error::Connection(H3_FRAME_ERROR))?;
+
+        if RESERVED_SETTINGS.contains(&id) {
+            return Err(H3Error::Connection(H3_SETTINGS_ERROR));
+        }
+        payload = &payload[id_len + value_len..];
+    }
+
+    Ok(())
+}
+
+/// Writes the parts of a translated response as frames.
+struct Frames<'a>(&'a mut BytesMut);
+
+impl ResponseSink for Frames<'_> {
+    fn fields(&mut self, fields: &[(&[u8], &[u8])], _trailers: bool) {
+        let mut block = BytesMut::new();
+        qpack::encode(fields.iter().copied(), &mut block);
+        encode_frame(self.0, FRAME_HEADERS, &block);
+    }
+
+    fn data(&mut self, data: &[u8]) {
+        encode_frame(self.0, FRAME_DATA, data);
+    }
+}
+
+/// Translates a request stream to and from HTTP/1.1, see the module docs.
+pub(crate) struct RequestStream {
+    /// Data received which is yet to make up a whole frame.
+    inbound: BytesMut,
+
+    /// The bytes of the current `DATA` frame yet to be received, or of an
+    /// unknown frame which is skipped.
+    frame_remaining: u64,
+    skipping: bool,
+
+    /// The max size of a field section received.
+    max_field_section_size: usize,
+
+    translator: Translator,
+}
+
+impl RequestStream {
+    pub(crate) fn new(max_field_section_size: usize) -> Self {
+        Self {
+            inbound: BytesMut::new(),
+            frame_remaining: 0,
+            skipping: false,
+            max_field_section_size,
+            translator: Translator::new(),
+        }
+    }
+
+    /// Handles data received on the stream, `fin` marking the end of the
+    /// request, and writes the request translated so far into `out`.
+    pub(crate) fn recv(
+        &mut self,
+        data: &[u8],
+        fin: bool,
+        out: &mut BytesMut,
+    ) -> Result<(), H3Error> {
+        self.inbound.extend_from_slice(data);
+
+        loop {
+            if self.frame_remaining > 0 {
+                if self.inbound.is_empty() {
+                    break;
+                }
+
+                let len = self.inbound.len().min(self.frame_remaining as usize);
+                let chunk = self.inbound.split_to(len);
+                self.frame_remaining -= len as u64;
+                if !self.skipping {
+                    self.translator.recv_body(&chunk, out)?;
+                }
+                continue;
+            }
+
+            let (kind, len, header_len) = match decode_frame_header(&self.inbound) {
+                Some(header) => header,
+                None => break,
+            };
+
+            match kind {
+                FRAME_DATA => {
+                    if !self.translator.has_head() | self.translator.has_trailers() {
+                        return Err(H3Error::Connection(H3_FRAME_UNEXPECTED));
+                    }
+                    self.inbound.advance(header_len);
+                    self.frame_remaining = len;
+                    self.skipping = false;
+                },
+                FRAME_HEADERS => {
+                    if len > self.max_field_section_size as u64 {
+                        return Err(H3Error::Stream(H3_EXCESSIVE_LOAD));
+                    }
+
+                    let frame_len = header_len + len as usize;
+                    if self.inbound.len() < frame_len {
+                        break;
+                    }
+                    let block = self.inbound.split_to(frame_len).split_off(header_len);
+                    let fields: Vec<Field> = qpack::decode(&block).map_err(|_| {
+                        H3Error::Connection(qpack::QPACK_DECOMPRESSION_FAILED)
+                    })?;
+
+                    if !self.translator.has_head() {
+                        let has_body = !fin | !self.inbound.is_empty();
+                        self.translator.recv_head(&fields, has_body, out)?;
+                    } else if !self.translator.has_trailers() {
+                        self.translator.recv_trailers(&fields, out)?;
+                    } else {
+                        return Err(H3Error::Connection(H3_FRAME_UNEXPECTED));
+                    }
+                },
+                FRAME_CANCEL_PUSH | FRAME_SETTINGS | FRAME_PUSH_PROMISE
+                | FRAME_GOAWAY | FRAME_MAX_PUSH_ID => {
+                    return Err(H3Error::Connection(H3_FRAME_UNEXPECTED))
+                },
+                kind if RESERVED_FRAMES.contains(&kind) => {
+                    return Err(H3Error::Connection(H3_FRAME_UNEXPECTED))
+                },
+                _ => {
+                    self.inbound.advance(header_len);
+                    self.frame_remaining = len;
+                    self.skipping = true;
+                },
+            }
+        }
+
+        if fin {
+            self.recv_fin(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn recv_fin(&mut self, out: &mut BytesMut) -> Result<(), H3Error> {
+        if (self.frame_remaining > 0) | !self.inbound.is_empty() {
+            return Err(H3Error::Connection(H3_FRAME_ERROR));
+        }
+
+        if !self.translator.has_head() {
+            return Err(H3Error::Stream(H3_REQUEST_INCOMPLETE));
+        }
+
+        Ok(self.translator.recv_fin(out)?)
+    }
+
+    /// Handles the HTTP/1.1 response written by the client, writing the
+    /// frames translated so far into `out`.
+    pub(crate) fn send(
+        &mut self,
+        data: &[u8],
+        out: &mut BytesMut,
+    ) -> Result<(), H3Error> {
+        Ok(self.translator.send(data, &mut Frames(out))?)
+    }
+
+    /// Ends the response as the client has shut its connection down,
+    /// returning `true` if the response is complete and the stream can be
+    /// finished rather than reset.
+    pub(crate) fn finish(&mut self) -> bool {
+        self.translator.finish()
+    }
+}
@@ -0,0 +1,426 @@
+//! HPACK field section compression for HTTP/2, see RFC 7541.
+//!
+//! Field sections received are decoded with the dynamic table the client
+//! maintains, those sent are only encoded with the static table and
+//! literals so the client's table is never written to. The integer, string
+//! and Huffman coding are shared with QPACK.
+
+use std::collections::VecDeque;
+
+use bytes::{BufMut, BytesMut};
+
+/// The size of the dynamic table until the server's settings say otherwise.
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// The size each entry of the dynamic table is counted as on top of its
+/// name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A field section which can't be decoded.
+#[derive(Debug)]
+pub(crate) struct DecodeError;
+
+/// A decoded field's name and value.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+/// Decodes the field sections received on a connection, keeping the
+/// dynamic table in step with the client's encoder.
+pub(crate) struct Decoder {
+    /// The dynamic table with the newest entry first.
+    table: VecDeque<Field>,
+
+    /// The size of every entry in the table.
+    size: usize,
+
+    /// The max size of the table as last set by the client.
+    max_size: usize,
+
+    /// The max size the client can set the table to.
+    limit: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+            limit: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    /// Decodes a field section into its name and value pairs.
+    ///
+    /// Every field section has to be decoded in the order it was received,
+    /// even those of requests which are refused, or the table falls out of
+    /// step with the client's.
+    pub(crate) fn decode(&mut self, mut buf: &[u8]) -> Result<Vec<Field>, DecodeError> {
+        let mut fields = Vec::new();
+        while let Some(&first) = buf.first() {
+            if first & 0x80 != 0 {
+                // Indexed field.
+                let (index, rest) = decode_int(buf, 7)?;
+                fields.push(self.field(index)?);
+                buf = rest;
+            } else if first & 0x40 != 0 {
+                // Literal field with incremental indexing.
+                let (field, rest) = self.literal(buf, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+                buf = rest;
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update, only allowed before any field.
+                let (size, rest) = decode_int(buf, 5)?;
+                if !fields.is_empty() | (size > self.limit as u64) {
+                    return Err(DecodeError);
+                }
+                self.max_size = size as usize;
+                self.evict();
+                buf = rest;
+            } else {
+                // Literal field without indexing or never indexed.
+                let (field, rest) = self.literal(buf, 4)?;
+                fields.push(field);
+                buf = rest;
+            }
+        }
+
+        Ok(fields)
+    }
+
+    /// Decodes a literal field whose name index has the given prefix, an
+    /// index of 0 is followed by the name as a string.
+    fn literal<'b>(
+        &self,
+        buf: &'b [u8],
+        prefix: u8,
+    ) -> Result<(Field, &'b [u8]), DecodeError> {
+        let (index, rest) = decode_int(buf, prefix)?;
+        let (name, rest) = match index {
+            0 => decode_string(rest, 7)?,
+            index => (self.field(index)?.0, rest),
+        };
+        let (value, rest) = decode_string(rest, 7)?;
+
+        Ok(((name, value), rest))
+    }
+
+    /// The field at the given index, the static table comes first followed
+    /// by the dynamic table.
+    fn field(&self, index: u64) -> Result<Field, DecodeError> {
+        let index = index as usize;
+        if index == 0 {
+            return Err(DecodeError);
+        }
+
+        match STATIC_TABLE.get(index - 1) {
+            Some((name, value)) => Ok((name.to_vec(), value.to_vec())),
+            None => self
+                .table
+                .get(index - 1 - STATIC_TABLE.len())
+                .cloned()
+                .ok_or(DecodeError),
+        }
+    }
+
+    /// Adds a field to the dynamic table, evicting the oldest entries to
+    /// make room. A field larger than the table empties it.
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        if size > self.max_size {
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+
+        self.size += size;
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => {
+                    self.size -= name.len() + value.len() + ENTRY_OVERHEAD
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes the given fields as a field section, names must already be
+/// lowercase.
+pub(crate) fn encode<'a>(
+    fields: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+    out: &mut BytesMut,
+) {
+    for (name, value) in fields {
+        match find_field(&STATIC_TABLE, name, value) {
+            Some((index, true)) => encode_int(out, 0x80, 7, index + 1),
+            Some((index, false)) => {
+                encode_int(out, 0x00, 4, index + 1);
+                encode_int(out, 0x00, 7, value.len() as u64);
+                out.put_slice(value);
+            },
+            None => {
+                out.put_u8(0x00);
+                encode_int(out, 0x00, 7, name.len() as u64);
+                out.put_slice(name);
+                encode_int(out, 0x00, 7, value.len() as u64);
+                out.put_slice(value);
+            },
+        }
+    }
+}
+
+/// Finds the entry of a static table for the field, preferring an entry
+/// with the same value. The flag is set if the value matches as well.
+pub(super) fn find_field(
+    table: &[(&[u8], &[u8])],
+    name: &[u8],
+    value: &[u8],
+) -> Option<(u64, bool)> {
+    let mut by_name = None;
+    for (index, (n, v)) in table.iter().enumerate() {
+        if *n != name {
+            continue;
+        }
+
+        if *v == value {
+            return Some((index as u64, true));
+        }
+        by_name.get_or_insert((index as u64, false));
+    }
+
+    by_name
+}
+
+/// Decodes a prefixed integer, see RFC 7541 section 5.1.
+pub(super) fn decode_int(buf: &[u8], prefix: u8) -> Result<(u64, &[u8]), DecodeError> {
+    let mask = (1u16 << prefix) as u64 - 1;
+    let (&first, mut rest) = buf.split_first().ok_or(DecodeError)?;
+
+    let mut value = first as u64 & mask;
+    if value < mask {
+        return Ok((value, rest));
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, next) = rest.split_first().ok_or(DecodeError)?;
+        rest = next;
+
+        if shift > 56 {
+            return Err(DecodeError);
+        }
+        value += ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok((value, rest));
+        }
+    }
+}
+
+/// Encodes a prefixed integer with the given flags in the bits above the
+/// prefix.
+pub(super) fn encode_int(out: &mut BytesMut, flags: u8, prefix: u8, mut value: u64) {
+    let mask = (1u16 << prefix) as u64 - 1;
+    if value < mask {
+        out.put_u8(flags | value as u8);
+        return;
+    }
+
+    out.put_u8(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        out.put_u8((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.put_u8(value as u8);
+}
+
+/// Decodes a string literal whose length has the given prefix, the bit
+/// above it marks the string as Huffman encoded.
+pub(super) fn decode_string(
+    buf: &[u8],
+    prefix: u8,
+) -> Result<(Vec<u8>, &[u8]), DecodeError> {
+    let huffman = matches!(buf.first(), Some(b) if b & (1 << prefix) != 0);
+    let (len, rest) = decode_int(buf, prefix)?;
+    if (rest.len() as u64) < len {
+        return Err(DecodeError);
+    }
+
+    let (data, rest) = rest.split_at(len as usize);
+    let data = if huffman {
+        huffman_decode(data)?
+    } else {
+        data.to_vec()
+    };
+
+    Ok((data, rest))
+}
+
+/// Decodes a string with the Huffman code of RFC 7541 appendix B.
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 2);
+    let mut code = 0u32;
+    let mut len = 0u8;
+
+    for byte in data {
+        for shift in (0..8).rev() {
+            code = (code << 1) | ((byte >> shift) & 1) as u32;
+            len += 1;
+
+            match huffman_symbol(code, len) {
+                // The EOS symbol must never be encoded.
+                Some(256) => return Err(DecodeError),
+                Some(symbol) => {
+                    out.push(symbol as u8);
+                    code = 0;
+                    len = 0;
+                },
+                None if len >= 30 => return Err(DecodeError),
+                None => {},
+            }
+        }
+    }
+
+    // Padding is shorter than a byte and made up of the EOS code's
+    // most significant bits, which are all ones.
+    if (len > 7) | (code != (1 << len) - 1) {
+        return Err(DecodeError);
+    }
+
+    Ok(out)
+}
+
+/// The symbol with the given code, the codes are canonical so the codes of
+/// each length are consecutive.
+fn huffman_symbol(code: u32, len: u8) -> Option<u16> {
+    for &(length, first, offset, count) in HUFFMAN_LENGTHS.iter() {
+        if length < len {
+            continue;
+        }
+
+        if (length > len) | (code < first) | (code - first >= count as u32) {
+            return None;
+        }
+
+        return Some(HUFFMAN_SYMBOLS[(offset + (code - first) as u16) as usize]);
+    }
+
+    None
+}
+
+/// The static table of RFC 7541 appendix A, indexed from 1.
+const STATIC_TABLE: [(&[u8], &[u8]); 61] = [
+    (b":authority", b""),
+    (b":method", b"GET"),
+    (b":method", b"POST"),
+    (b":path", b"/"),
+    (b":path", b"/index.html"),
+    (b":scheme", b"http"),
+    (b":scheme", b"https"),
+    (b":status", b"200"),
+    (b":status", b"204"),
+    (b":status", b"206"),
+    (b":status", b"304"),
+    (b":status", b"400"),
+    (b":status", b"404"),
+    (b":status", b"500"),
+    (b"accept-charset", b""),
+    (b"accept-encoding", b"gzip, deflate"),
+    (b"accept-language", b""),
+    (b"accept-ranges", b""),
+    (b"accept", b""),
+    (b"access-control-allow-origin", b""),
+    (b"age", b""),
+    (b"allow", b""),
+    (b"authorization", b""),
+    (b"cache-control", b""),
+    (b"content-disposition", b""),
+    (b"content-encoding", b""),
+    (b"content-language", b""),
+    (b"content-length", b""),
+    (b"content-location", b""),
+    (b"content-range", b""),
+    (b"content-type", b""),
+    (b"cookie", b""),
+    (b"date", b""),
+    (b"etag", b""),
+    (b"expect", b""),
+    (b"expires", b""),
+    (b"from", b""),
+    (b"host", b""),
+    (b"if-match", b""),
+    (b"if-modified-since", b""),
+    (b"if-none-match", b""),
+    (b"if-range", b""),
+    (b"if-unmodified-since", b""),
+    (b"last-modified", b""),
+    (b"link", b""),
+    (b"location", b""),
+    (b"max-forwards", b""),
+    (b"proxy-authenticate", b""),
+    (b"proxy-authorization", b""),
+    (b"range", b""),
+    (b"referer", b""),
+    (b"refresh", b""),
+    (b"retry-after", b""),
+    (b"server", b""),
+    (b"set-cookie", b""),
+    (b"strict-transport-security", b""),
+    (b"transfer-encoding", b""),
+    (b"user-agent", b""),
+    (b"vary", b""),
+    (b"via", b""),
+    (b"www-authenticate", b""),
+];
+
+/// The length, first code, offset into `HUFFMAN_SYMBOLS` and number of
+/// codes of each length used by the Huffman code of RFC 7541 appendix B.
+const HUFFMAN_LENGTHS: [(u8, u32, u16, u16); 21] = [
+    (5, 0x0, 0, 10),
+    (6, 0x14, 10, 26),
+    (7, 0x5c, 36, 32),
+    (8, 0xf8, 68, 6),
+    (10, 0x3f8, 74, 5),
+    (11, 0x7fa, 79, 3),
+    (12, 0xffa, 82, 2),
+    (13, 0x1ff8, 84, 6),
+    (14, 0x3ffc, 90, 2),
+    (15, 0x7ffc, 92, 3),
+    (19, 0x7fff0, 95, 3),
+    (20, 0xfffe6, 98, 8),
+    (21, 0x1fffdc, 106, 13),
+    (22, 0x3fffd2, 119, 26),
+    (23, 0x7fffd8, 145, 29),
+    (24, 0xffffea, 174, 12),
+    (25, 0x1ffffec, 186, 4),
+    (26, 0x3ffffe0, 190, 15),
+    (27, 0x7ffffde, 205, 19),
+    (28, 0xfffffe2, 224, 29),
+    (30, 0x3ffffffc, 253, 4),
+];
+
+/// The symbols of the Huffman code ordered by their code.
+const HUFFMAN_SYMBOLS: [u16; 257] = [
+    48, 49, 50, 97, 99, 101, 105, 111, 115, 116, 32, 37, 45, 46, 47, 51, 52, 53, 54, 55,
+    56, 57, 61, 65, 95, 98, 100, 102, 103, 104, 108, 109, 110, 112, 114, 117, 58, 66,
+    67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87,
+    89, 106, 107, 113, 118, 119, 120, 121, 122, 38, 42, 44, 59, 88, 90, 33, 34, 40, 41,
+    63, 39, 43, 124, 35, 62, 0, 36, 64, 91, 93, 126, 94, 125, 60, 96, 123, 92, 195, 208,
+    128, 130, 131, 162, 184, 194, 224, 226, 153, 161, 167, 172, 176, 177, 179, 209, 216,
+    217, 227, 229, 230, 129, 132, 133, 134, 136, 146, 154, 156, 160, 163, 164, 169, 170,
+    173, 178, 181, 185, 186, 187, 189, 190, 196, 198, 228, 232, 233, 1, 135, 137, 138,
+    139, 140, 141, 143, 147, 149, 150, 151, 152, 155, 157, 158, 165, 166, 168, 174, 175,
+    180, 182, 183, 188, 191, 197, 231, 239, 9, 142, 144, 145, 148, 159, 171, 206, 215,
+    225, 236, 237, 199, 207, 234, 235, 192, 193, 200, 201, 202, 205, 210, 213, 218, 219,
+    238, 240, 242, 243, 255, 203, 204, 211, 212, 214, 221, 222, 223, 241, 244, 245, 246,
+    247, 248, 250, 251, 252, 253, 254, 2, 3, 4, 5, 6, 7, 8, 11, 12, 14, 15, 16, 17, 18,
+    19, 20, 21, 23, 24, 25, 26, 27, 28, 29, 30, 31, 127, 220, 249, 10, 13, 22, 256,
+];
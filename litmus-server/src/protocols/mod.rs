@@ -1,5 +1,25 @@
 mod h1;
+mod h2;
+#[cfg(feature = "http3")]
+pub(crate) mod h3;
+mod hpack;
+#[cfg(feature = "http3")]
+mod qpack;
 mod selector;
+mod translate;
+mod ws;
 
 pub(crate) use h1::H1Protocol;
-pub(crate) use selector::{AutoProtocol, Protocols};
+pub(crate) use h2::H2Protocol;
+pub(crate) use selector::{AutoProtocol, SwitchStatus};
+pub(crate) use ws::WsProtocol;
+
+/// The ALPN protocol id of HTTP/2 over TLS.
+pub(crate) const ALPN_H2: &[u8] = b"h2";
+
+/// The ALPN protocol id of HTTP/1.1.
+pub(crate) const ALPN_HTTP_11: &[u8] = b"http/1.1";
+
+/// The ALPN protocol id of HTTP/3 over QUIC.
+#[cfg(feature = "http3")]
+pub(crate) const ALPN_H3: &[u8] = b"h3";
@@ -0,0 +1,203 @@
+//! QPACK field section compression for HTTP/3, see RFC 9204.
+//!
+//! Peers are never allowed a dynamic table, the server advertises a table
+//! capacity of 0, so field sections are only encoded with the static table
+//! and literals. Any reference to the dynamic table fails to decode.
+//!
+//! The integer, string and Huffman coding are those of HPACK.
+
+use bytes::{BufMut, BytesMut};
+
+use super::hpack::{decode_int, decode_string, encode_int, find_field};
+pub(crate) use super::hpack::{DecodeError, Field};
+
+/// The error code of a field section which can't be decoded.
+pub(crate) const QPACK_DECOMPRESSION_FAILED: u64 = 0x200;
+
+/// Decodes a field section into its name and value pairs.
+pub(crate) fn decode(buf: &[u8]) -> Result<Vec<Field>, DecodeError> {
+    // The field section prefix, both are 0 without a dynamic table.
+    let (required_insert_count, buf) = decode_int(buf, 8)?;
+    if required_insert_count != 0 {
+        return Err(DecodeError);
+    }
+    let (_, mut buf) = decode_int(buf, 7)?;
+
+    let mut fields = Vec::new();
+    while let Some(&first) = buf.first() {
+        let (name, value, rest) = if first & 0x80 != 0 {
+            // Indexed field line.
+            if first & 0x40 == 0 {
+                return Err(DecodeError);
+            }
+            let (index, rest) = decode_int(buf, 6)?;
+            let (name, value) = static_field(index)?;
+            (name.to_vec(), value.to_vec(), rest)
+        } else if first & 0x40 != 0 {
+            // Literal field line with name reference.
+            if first & 0x10 == 0 {
+                return Err(DecodeError);
+            }
+            let (index, rest) = decode_int(buf, 4)?;
+            let (name, _) = static_field(index)?;
+            let (value, rest) = decode_string(rest, 7)?;
+            (name.to_vec(), value, rest)
+        } else if first & 0x20 != 0 {
+            // Literal field line with literal name.
+            let (name, rest) = decode_string(buf, 3)?;
+            let (value, rest) = decode_string(rest, 7)?;
+            (name, value, rest)
+        } else {
+            // Post-base references are always into the dynamic table.
+            return Err(DecodeError);
+        };
+
+        fields.push((name, value));
+        buf = rest;
+    }
+
+    Ok(fields)
+}
+
+/// Encodes the given fields as a field section, names must already be
+/// lowercase.
+pub(crate) fn encode<'a>(
+    fields: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+    out: &mut BytesMut,
+) {
+    // A required insert count and base of 0.
+    out.put_slice(&[0, 0]);
+
+    for (name, value) in fields {
+        match find_field(&STATIC_TABLE, name, value) {
+            Some((index, true)) => encode_int(out, 0xc0, 6, index),
+            Some((index, false)) => {
+                encode_int(out, 0x50, 4, index);
+                encode_int(out, 0x00, 7, value.len() as u64);
+                out.put_slice(value);
+            },
+            None => {
+                encode_int(out, 0x20, 3, name.len() as u64);
+                out.put_slice(name);
+                encode_int(out, 0x00, 7, value.len() as u64);
+                out.put_slice(value);
+            },
+        }
+    }
+}
+
+fn static_field(index: u64) -> Result<(&'static [u8], &'static [u8]), DecodeError> {
+    STATIC_TABLE.get(index as usize).copied().ok_or(DecodeError)
+}
+
+/// The static table of RFC 9204 appendix A.
+const STATIC_TABLE: [(&[u8], &[u8]); 99] = [
+    (b":authority", b""),
+    (b":path", b"/"),
+    (b"age", b"0"),
+    (b"content-disposition", b""),
+    (b"content-length", b"0"),
+    (b"cookie", b""),
+    (b"date", b""),
+    (b"etag", b""),
+    (b"if-modified-since", b""),
+    (b"if-none-match", b""),
+    (b"last-modified", b""),
+    (b"link", b""),
+    (b"location", b""),
+    (b"referer", b""),
+    (b"set-cookie", b""),
+    (b":method", b"CONNECT"),
+    (b":method", b"DELETE"),
+    (b":method", b"GET"),
+    (b":method", b"HEAD"),
+    (b":method", b"OPTIONS"),
+    (b":method", b"POST"),
+    (b":method", b"PUT"),
+    (b":scheme", b"http"),
+    (b":scheme", b"https"),
+    (b":status", b"103"),
+    (b":status", b"200"),
+    (b":status", b"304"),
+    (b":status", b"404"),
+    (b":status", b"503"),
+    (b"accept", b"*/*"),
+    (b"accept", b"application/dns-message"),
+    (b"accept-encoding", b"gzip, deflate, br"),
+    (b"accept-ranges", b"bytes"),
+    (b"access-control-allow-headers", b"cache-control"),
+    (b"access-control-allow-headers", b"content-type"),
+    (b"access-control-allow-origin", b"*"),
+    (b"cache-control", b"max-age=0"),
+    (b"cache-control", b"max-age=2592000"),
+    (b"cache-control", b"max-age=604800"),
+    (b"cache-control", b"no-cache"),
+    (b"cache-control", b"no-store"),
+    (b"cache-control", b"public, max-age=31536000"),
+    (b"content-encoding", b"br"),
+    (b"content-encoding", b"gzip"),
+    (b"content-type", b"application/dns-message"),
+    (b"content-type", b"application/javascript"),
+    (b"content-type", b"application/json"),
+    (b"content-type", b"application/x-www-form-urlencoded"),
+    (b"content-type", b"image/gif"),
+    (b"content-type", b"image/jpeg"),
+    (b"content-type", b"image/png"),
+    (b"content-type", b"text/css"),
+    (b"content-type", b"text/html; charset=utf-8"),
+    (b"content-type", b"text/plain"),
+    (b"content-type", b"text/plain;charset=utf-8"),
+    (b"range", b"bytes=0-"),
+    (b"strict-transport-security", b"max-age=31536000"),
+    (
+        b"strict-transport-security",
+        b"max-age=31536000; includesubdomains",
+    ),
+    (
+        b"strict-transport-security",
+        b"max-age=31536000; includesubdomains; preload",
+    ),
+    (b"vary", b"accept-encoding"),
+    (b"vary", b"origin"),
+    (b"x-content-type-options", b"nosniff"),
+    (b"x-xss-protection", b"1; mode=block"),
+    (b":status", b"100"),
+    (b":status", b"204"),
+    (b":status", b"206"),
+    (b":status", b"302"),
+    (b":status", b"400"),
+    (b":status", b"403"),
+    (b":status", b"421"),
+    (b":status", b"425"),
+    (b":status", b"500"),
+    (b"accept-language", b""),
+    (b"access-control-allow-credentials", b"FALSE"),
+    (b"access-control-allow-credentials", b"TRUE"),
+    (b"access-control-allow-headers", b"*"),
+    (b"access-control-allow-methods", b"get"),
+    (b"access-control-allow-methods", b"get, post, options"),
+    (b"access-control-allow-methods", b"options"),
+    (b"access-control-expose-headers", b"content-length"),
+    (b"access-control-request-headers", b"content-type"),
+    (b"access-control-request-method", b"get"),
+    (b"access-control-request-method", b"post"),
+    (b"alt-svc", b"clear"),
+    (b"authorization", b""),
+    (
+        b"content-security-policy",
+        b"script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    (b"early-data", b"1"),
+    (b"expect-ct", b""),
+    (b"forwarded", b""),
+    (b"if-range", b""),
+    (b"origin", b""),
+    (b"purpose", b"prefetch"),
+    (b"server", b""),
+    (b"timing-allow-origin", b"*"),
+    (b"upgrade-insecure-requests", b"1"),
+    (b"user-agent", b""),
+    (b"x-forwarded-for", b""),
+    (b"x-frame-options", b"deny"),
+    (b"x-frame-options", b"sameorigin"),
+];
@@ -1,7 +1,14 @@
-use bytes::BytesMut;
+use std::collections::VecDeque;
+
+use bytes::{Bytes, BytesMut};
+use pyo3::exceptions::PyRuntimeError;
 use pyo3::PyResult;
 
-use super::H1Protocol;
+use super::h2::{self, Preface};
+use super::{H1Protocol, H2Protocol, WsProtocol, ALPN_H2, ALPN_HTTP_11};
+use crate::migration::ConnectionSnapshot;
+use crate::net::{FileBody, TlsDetails};
+use crate::plugin::{Connection, Protocol};
 use crate::server::CallbackHandler;
 use crate::settings::Settings;
 use crate::traits::{BaseTransport, BufferHandler, ProtocolBuffers, SocketState};
@@ -9,14 +16,14 @@ use crate::transport::Transport;
 
 const BUFFER_SIZE: usize = 32 * 1024;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub(crate) enum Protocols {
     H1,
-    // H2,
-    // WS,
+    H2,
+    WS,
+    Custom,
 }
 
-#[allow(unused)]
 pub(crate) enum SwitchStatus {
     SwitchTo(Protocols),
     NoSwitch,
@@ -24,30 +31,57 @@ pub(crate) enum SwitchStatus {
 
 pub(crate) struct AutoProtocol {
     transport: Transport,
+    settings: Settings,
 
     selected: Protocols,
     h1: H1Protocol,
+    h2: H2Protocol,
+    ws: WsProtocol,
+
+    /// The custom protocol served in place of HTTP, see `select_custom()`.
+    custom: Option<Box<dyn Protocol>>,
+
+    /// If the protocol of the connection has been decided, either by ALPN
+    /// or by checking the start of the connection for the HTTP/2
+    /// connection preface.
+    sniffed: bool,
 
     writer_buffer: BytesMut,
     reader_buffer: BytesMut,
+
+    /// The large body chunks queued to be written ahead of the write
+    /// buffer, these are sent together using a vectored write.
+    writer_chunks: VecDeque<Bytes>,
+
+    /// If the last response on the connection has been written and the
+    /// connection should be closed, see `take_close()`.
+    close_pending: bool,
 }
 
 impl AutoProtocol {
     pub(crate) fn new(
         settings: Settings,
-        selected: Protocols,
         transport: Transport,
         callback: CallbackHandler,
     ) -> Self {
-        let mut h1 = H1Protocol::new(settings, callback);
+        let ws = WsProtocol::new(callback.clone());
+        let h2 = H2Protocol::new(settings.clone(), callback.clone());
+        let mut h1 = H1Protocol::new(settings.clone(), callback);
         h1.new_connection(transport.clone());
 
         Self {
-            selected,
+            selected: Protocols::H1,
             transport,
+            settings,
             h1,
-            writer_buffer: BytesMut::with_capacity(BUFFER_SIZE),
-            reader_buffer: BytesMut::with_capacity(BUFFER_SIZE),
+            h2,
+            ws,
+            custom: None,
+            sniffed: false,
+            writer_buffer: BytesMut::new(),
+            reader_buffer: BytesMut::new(),
+            writer_chunks: VecDeque::new(),
+            close_pending: false,
         }
     }
 }
@@ -56,8 +90,283 @@ impl AutoProtocol {
     /// Allows the chance to switch protocol just after reading has
     /// finished.
     pub(crate) fn maybe_switch(&mut self) -> PyResult<SwitchStatus> {
+        let status = match self.selected {
+            Protocols::H1 => self.h1.maybe_switch()?,
+            Protocols::H2 | Protocols::WS | Protocols::Custom => SwitchStatus::NoSwitch,
+        };
+
+        if let SwitchStatus::SwitchTo(Protocols::H2) = status {
+            self.switch_h2c()?;
+        }
+
+        Ok(status)
+    }
+
+    /// Switches a connection upgraded to h2c over to HTTP/2.
+    ///
+    /// The `101 Switching Protocols` is moved into the write buffer so
+    /// it's written ahead of anything HTTP/2 writes and the upgrade request
+    /// is served on the first stream, then anything read after it, e.g.
+    /// the client's connection preface, is replayed into HTTP/2.
+    fn switch_h2c(&mut self) -> PyResult<()> {
+        if self.writer_buffer.capacity() == 0 {
+            self.writer_buffer = self.settings.buffers.acquire(BUFFER_SIZE);
+        }
+        self.h1
+            .fill_write_queue(&mut self.writer_buffer, Some(&mut self.writer_chunks))?;
+
+        self.selected = Protocols::H2;
+        self.h2.new_connection(self.transport.clone());
+        if let Some(upgrade) = self.h1.take_h2c_upgrade() {
+            self.h2.upgrade(upgrade)?;
+        }
+        self.transport.resume_writing()?;
+
+        if self.reader_buffer.is_empty() {
+            self.transport.resume_reading()
+        } else {
+            self.h2.data_received(&mut self.reader_buffer)
+        }
+    }
+
+    /// Selects the protocol negotiated using ALPN, taking the place of
+    /// sniffing for the HTTP/2 preface.
+    ///
+    /// This is called before each read is handled as the protocol is only
+    /// known once the TLS handshake completes, it does nothing once the
+    /// protocol has been decided or if none was negotiated.
+    pub(crate) fn select_alpn(&mut self, protocol: Option<&[u8]>) {
+        if self.sniffed {
+            return;
+        }
+
+        match protocol {
+            Some(ALPN_H2) => {
+                self.selected = Protocols::H2;
+                self.h2.new_connection(self.transport.clone());
+                self.sniffed = true;
+            },
+            Some(ALPN_HTTP_11) => self.sniffed = true,
+            _ => {},
+        }
+    }
+
+    /// Serves the given custom protocol on the connection in place of
+    /// HTTP, called once the connection is ready to be read from.
+    pub(crate) fn select_custom(&mut self, protocol: Box<dyn Protocol>) -> PyResult<()> {
+        self.selected = Protocols::Custom;
+        self.sniffed = true;
+
+        let conn = Connection::new(self.transport.clone());
+        self.custom.insert(protocol).connection_made(conn)
+    }
+
+    /// Records the certificate the client verified itself with during the
+    /// TLS handshake, called alongside `select_alpn`.
+    pub(crate) fn set_peer_certificate(&mut self, der: Option<&[u8]>) {
+        self.h1.set_peer_certificate(der);
+    }
+
+    /// Records what was negotiated during the TLS handshake, `details` is
+    /// only called until it gives them.
+    pub(crate) fn set_tls_details(
+        &mut self,
+        details: impl FnOnce() -> Option<TlsDetails>,
+    ) {
+        self.h1.set_tls_details(details);
+    }
+
+    /// If the selected protocol has a response queued to be written or is
+    /// closing the connection once everything queued has been written,
+    /// anything more received is left unread until then.
+    pub(crate) fn stops_reading(&self) -> bool {
         match self.selected {
-            Protocols::H1 => self.h1.maybe_switch(),
+            Protocols::H1 => self.h1.response_queued() | self.h1.is_closing(),
+            Protocols::H2 | Protocols::WS => false,
+            Protocols::Custom => self.custom.as_deref().is_some_and(|c| c.is_closing()),
+        }
+    }
+
+    /// If the selected protocol is part way through writing a response.
+    pub(crate) fn response_pending(&self) -> bool {
+        match self.selected {
+            Protocols::H1 => self.h1.response_pending(),
+            Protocols::H2 | Protocols::WS | Protocols::Custom => false,
+        }
+    }
+
+    /// If the connection is long lived and should not be closed for
+    /// being idle, e.g. websockets and event streams.
+    pub(crate) fn is_long_lived(&self) -> bool {
+        match self.selected {
+            Protocols::H1 => self.h1.is_event_stream(),
+            Protocols::H2 => self.h2.is_long_lived(),
+            Protocols::WS => true,
+            Protocols::Custom => self.custom.as_ref().is_some_and(|p| p.is_long_lived()),
+        }
+    }
+
+    /// The number of bytes waiting to be written to the socket.
+    pub(crate) fn write_buffered(&self) -> usize {
+        let chunked: usize = self.writer_chunks.iter().map(|c| c.len()).sum();
+        chunked + self.writer_buffer.len()
+    }
+
+    /// The chunks to write using a single vectored write, `None` if only
+    /// the write buffer has anything to write.
+    ///
+    /// The contents of the write buffer are moved to the back of the queue
+    /// so everything is written in order.
+    pub(crate) fn write_queue(&mut self) -> Option<&mut VecDeque<Bytes>> {
+        if self.writer_chunks.is_empty() {
+            return None;
+        }
+
+        if !self.writer_buffer.is_empty() {
+            self.writer_chunks
+                .push_back(self.writer_buffer.split().freeze());
+        }
+
+        Some(&mut self.writer_chunks)
+    }
+
+    /// The write buffer without filling it.
+    pub(crate) fn write_buffer(&mut self) -> &mut BytesMut {
+        &mut self.writer_buffer
+    }
+
+    /// Takes a snapshot of the protocol state at a request boundary.
+    pub(crate) fn snapshot(&self) -> PyResult<ConnectionSnapshot> {
+        let (at_boundary, keep_alive, requests_received) = match self.selected {
+            Protocols::H1 => (
+                self.h1.at_request_boundary(),
+                self.h1.keep_alive(),
+                self.h1.requests_received(),
+            ),
+            Protocols::H2 | Protocols::WS | Protocols::Custom => (false, false, 0),
+        };
+
+        if !at_boundary || (self.write_buffered() != 0) {
+            return Err(PyRuntimeError::new_err(
+                "connection can only be snapshot at a request boundary",
+            ));
+        }
+
+        Ok(ConnectionSnapshot {
+            keep_alive,
+            requests_received,
+            pending: self.reader_buffer.to_vec(),
+        })
+    }
+
+    /// Restores the protocol state from a snapshot, handling any pending
+    /// data as if it had just been read.
+    pub(crate) fn restore(&mut self, snapshot: ConnectionSnapshot) -> PyResult<()> {
+        match self.selected {
+            Protocols::H1 => {
+                self.h1.set_keep_alive(snapshot.keep_alive);
+                self.h1.set_requests_received(snapshot.requests_received);
+            },
+            Protocols::H2 | Protocols::WS | Protocols::Custom => {},
+        }
+
+        if snapshot.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.reader_buffer.extend_from_slice(&snapshot.pending);
+        self.read_buffer_filled(snapshot.pending.len())
+    }
+
+    /// Returns the read buffer to the buffer pool if it's empty, it's
+    /// acquired again once there is something to read.
+    pub(crate) fn release_read_buffer(&mut self) {
+        if self.reader_buffer.is_empty() & (self.reader_buffer.capacity() > 0) {
+            let buffer = std::mem::take(&mut self.reader_buffer);
+            self.settings.buffers.release(buffer);
+        }
+    }
+
+    /// Returns the write buffer to the buffer pool if it's empty, it's
+    /// acquired again once there is something to write.
+    fn release_write_buffer(&mut self) {
+        if self.writer_buffer.is_empty() & (self.writer_buffer.capacity() > 0) {
+            let buffer = std::mem::take(&mut self.writer_buffer);
+            self.settings.buffers.release(buffer);
+        }
+    }
+
+    /// Switches to the websocket protocol if the application accepted
+    /// an upgrade and the response has been written.
+    fn maybe_switch_websocket(&mut self) {
+        if let Protocols::H1 = self.selected {
+            if let Some((on_message, outgoing)) = self.h1.take_websocket() {
+                self.selected = Protocols::WS;
+                self.ws
+                    .new_connection(self.transport.clone(), on_message, outgoing);
+            }
+        }
+    }
+
+    /// The file waiting to be written to the socket once the write buffer
+    /// has been drained, if any.
+    pub(crate) fn pending_file(&mut self) -> Option<&mut FileBody> {
+        if self.write_buffered() != 0 {
+            return None;
+        }
+
+        match self.selected {
+            Protocols::H1 => self.h1.pending_file(),
+            Protocols::H2 | Protocols::WS | Protocols::Custom => None,
+        }
+    }
+
+    /// Called once some of the pending file has been written to the socket.
+    pub(crate) fn file_sent(&mut self, amount: usize) -> PyResult<()> {
+        match self.selected {
+            Protocols::H1 => self.h1.file_sent(amount, &mut self.writer_buffer),
+            Protocols::H2 | Protocols::WS | Protocols::Custom => Ok(()),
+        }
+    }
+
+    /// Polls any timers of the selected protocol, e.g. the response
+    /// timeout and rate limiting.
+    pub(crate) fn poll_timers(&mut self) -> PyResult<()> {
+        match self.selected {
+            Protocols::H1 => self.h1.poll_timers(&mut self.reader_buffer),
+            Protocols::H2 => self.h2.poll_timers(),
+            Protocols::WS | Protocols::Custom => Ok(()),
+        }
+    }
+
+    /// If the last response on the connection has been written so the
+    /// connection should be closed, clearing the flag.
+    pub(crate) fn take_close(&mut self) -> bool {
+        std::mem::take(&mut self.close_pending)
+    }
+
+    /// Starts draining the connection ahead of the server shutting down,
+    /// returning if the connection can be closed immediately.
+    pub(crate) fn drain(&mut self) -> PyResult<bool> {
+        let idle = self.reader_buffer.is_empty() & (self.write_buffered() == 0);
+
+        match self.selected {
+            Protocols::H1 => Ok(self.h1.drain() & idle),
+            // The connection is closed once the GOAWAY has been written and
+            // the streams already open are done.
+            Protocols::H2 => {
+                self.h2.drain()?;
+                Ok(false)
+            },
+            // The client is asked to close once the close frame is written.
+            Protocols::WS => {
+                self.ws.drain()?;
+                Ok(false)
+            },
+            Protocols::Custom => match self.custom.as_deref_mut() {
+                Some(custom) => Ok(custom.drain()? & idle),
+                None => Ok(idle),
+            },
         }
     }
 
@@ -72,10 +381,11 @@ impl AutoProtocol {
 impl SocketState for AutoProtocol {
     fn new_connection(&mut self, transport: Transport) {
         self.transport = transport;
-
-        match self.selected {
-            Protocols::H1 => self.h1.new_connection(self.transport.clone()),
-        }
+        self.selected = Protocols::H1;
+        self.sniffed = false;
+        self.close_pending = false;
+        self.custom = None;
+        self.h1.new_connection(self.transport.clone());
     }
 
     fn connection_lost(&mut self) -> PyResult<()> {
@@ -83,32 +393,107 @@ impl SocketState for AutoProtocol {
         self.transport.pause_writing()?;
         self.reader_buffer.clear();
         self.writer_buffer.clear();
+        self.writer_chunks.clear();
+        self.release_read_buffer();
+        self.release_write_buffer();
         match self.selected {
             Protocols::H1 => self.h1.lost_connection(),
+            Protocols::H2 => self.h2.lost_connection(),
+            Protocols::WS => self.ws.lost_connection(),
+            // The protocol is dropped so it's only told once.
+            Protocols::Custom => match self.custom.take() {
+                Some(mut custom) => custom.connection_lost(),
+                None => Ok(()),
+            },
         }
     }
 
     /// The EOF has been sent by the socket.
-    fn eof_received(&mut self) -> PyResult<()> {
-        self.connection_lost()
+    ///
+    /// Only HTTP/1 connections and custom protocols are kept half open, the
+    /// peer closing an HTTP/2 or websocket connection ends every stream on
+    /// it.
+    fn eof_received(&mut self) -> PyResult<bool> {
+        let write_pending = self.write_buffered() != 0;
+        let half_open = match self.selected {
+            Protocols::H1 => self.h1.eof_received(write_pending)?,
+            Protocols::H2 | Protocols::WS => false,
+            Protocols::Custom => match self.custom.as_deref_mut() {
+                Some(custom) => custom.eof_received()?,
+                None => false,
+            },
+        };
+
+        if !half_open {
+            self.connection_lost()?;
+            return Ok(false);
+        }
+
+        // Nothing more is parsed so anything left unread is discarded.
+        self.reader_buffer.clear();
+        self.release_read_buffer();
+        Ok(true)
     }
 }
 
 impl BufferHandler for AutoProtocol {
     fn read_buffer_acquire(&mut self) -> PyResult<&mut BytesMut> {
+        if self.reader_buffer.capacity() == 0 {
+            self.reader_buffer = self.settings.buffers.acquire(BUFFER_SIZE);
+        }
+
         Ok(&mut self.reader_buffer)
     }
 
     fn read_buffer_filled(&mut self, _amount: usize) -> PyResult<()> {
+        if !self.sniffed {
+            match h2::sniff_preface(&self.reader_buffer) {
+                Preface::Partial => return Ok(()),
+                Preface::Found => {
+                    self.selected = Protocols::H2;
+                    self.h2.new_connection(self.transport.clone());
+                },
+                Preface::Missing => {},
+            }
+
+            self.sniffed = true;
+        }
+
         match self.selected {
             Protocols::H1 => self.h1.data_received(&mut self.reader_buffer),
+            Protocols::H2 => self.h2.data_received(&mut self.reader_buffer),
+            Protocols::WS => self.ws.data_received(&mut self.reader_buffer),
+            Protocols::Custom => match self.custom.as_deref_mut() {
+                Some(custom) => custom.data_received(&mut self.reader_buffer),
+                None => Ok(()),
+            },
         }
     }
 
     fn write_buffer_acquire(&mut self) -> PyResult<&mut BytesMut> {
+        if self.writer_buffer.capacity() == 0 {
+            self.writer_buffer = self.settings.buffers.acquire(BUFFER_SIZE);
+        }
+
         match self.selected {
             Protocols::H1 => {
-                self.h1.fill_write_buffer(&mut self.writer_buffer)?;
+                self.h1.resume_body(&mut self.reader_buffer)?;
+                self.h1.fill_write_queue(
+                    &mut self.writer_buffer,
+                    Some(&mut self.writer_chunks),
+                )?;
+                self.h1.poll_pipeline(&mut self.reader_buffer)?;
+            },
+            Protocols::H2 => {
+                self.h2.fill_write_buffer(&mut self.writer_buffer)?;
+            },
+            Protocols::WS => {
+                self.ws.fill_write_buffer(&mut self.writer_buffer)?;
+            },
+            Protocols::Custom => {
+                if let Some(custom) = self.custom.as_deref_mut() {
+                    custom.fill_write_buffer(&mut self.writer_buffer)?;
+                }
             },
         };
 
@@ -116,10 +501,44 @@ impl BufferHandler for AutoProtocol {
     }
 
     fn write_buffer_drained(&mut self, amount: usize) -> PyResult<()> {
-        if (amount == 0) | (self.writer_buffer.len() == 0) {
+        match self.selected {
+            Protocols::H1 => self.h1.write_drained(amount),
+            Protocols::H2 | Protocols::WS | Protocols::Custom => {},
+        }
+
+        let buffered = self.write_buffered();
+        if buffered == 0 {
+            self.maybe_switch_websocket();
+            self.release_write_buffer();
+        }
+
+        // Writing continues while a file is waiting to be sent or chunks were
+        // left queued by the high water mark, or while HTTP/2 streams have
+        // more to send.
+        let write_pending = match self.selected {
+            Protocols::H1 => {
+                self.h1.pending_file().is_some() | self.h1.response_queued()
+            },
+            Protocols::H2 => self.h2.wants_write(),
+            Protocols::WS | Protocols::Custom => false,
+        };
+
+        if ((amount == 0) | (buffered == 0)) & !write_pending {
             self.pause_writing()?;
         }
 
+        if (buffered == 0) & !write_pending {
+            match self.selected {
+                Protocols::H1 => self.close_pending |= self.h1.write_flushed(),
+                Protocols::H2 => self.close_pending |= self.h2.is_closing(),
+                Protocols::Custom => {
+                    self.close_pending |=
+                        self.custom.as_ref().is_some_and(|p| p.is_closing())
+                },
+                Protocols::WS => {},
+            }
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,548 @@
+//! Translation of the requests of a multiplexed protocol to and from
+//! HTTP/1.1.
+//!
+//! Rather than a protocol of their own the streams of HTTP/2 and HTTP/3 are
+//! each translated to an HTTP/1.1 request, with `connection: close`, and
+//! handed to an H1 protocol as if they were a connection of their own. The
+//! HTTP/1.1 response written back is then translated into the fields and
+//! body the stream's frames are made from.
+
+use bytes::{Buf, BufMut, BytesMut};
+use httparse::{parse_chunk_size, parse_headers, Response, Status, EMPTY_HEADER};
+
+pub(crate) use super::hpack::Field;
+use crate::settings::MAX_HEADERS_LIMIT;
+
+/// The request headers which only apply to a single HTTP/1.1 connection,
+/// a request with any of these is malformed.
+const CONNECTION_HEADERS: [&[u8]; 5] = [
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+/// Why a request or response can't be translated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TranslateError {
+    /// The request is malformed.
+    Malformed,
+
+    /// The request is well formed but can't be served, e.g. `CONNECT`.
+    Rejected,
+
+    /// The response written by the H1 protocol can't be translated.
+    Internal,
+}
+
+/// What the parts of a translated response are written to, e.g. the
+/// frames of a stream.
+pub(crate) trait ResponseSink {
+    /// Writes the fields of a response head, starting with `:status`, or
+    /// of the trailers ending the response. Names are always lowercase.
+    fn fields(&mut self, fields: &[(&[u8], &[u8])], trailers: bool);
+
+    /// Writes some of the response's body.
+    fn data(&mut self, data: &[u8]);
+}
+
+/// How the body of the request is framed in HTTP/1.1.
+#[derive(Copy, Clone)]
+enum RequestBody {
+    /// The request has no body.
+    Empty,
+
+    /// The request gave a `content-length`, the bytes yet to be received.
+    Length(u64),
+
+    /// The length isn't known so the body is sent chunked.
+    Chunked,
+}
+
+/// How the body of the response is framed in HTTP/1.1.
+#[derive(Copy, Clone)]
+enum ResponseBody {
+    /// The head of the response is yet to be received in full.
+    Head,
+
+    /// The response has a `content-length`, the bytes yet to be received.
+    Length(u64),
+
+    /// The response is chunked, the size line of the next chunk is yet to
+    /// be received.
+    ChunkSize,
+
+    /// The bytes of the current chunk yet to be received.
+    ChunkData(u64),
+
+    /// The `\r\n` ending a chunk is yet to be received.
+    ChunkEnd,
+
+    /// The trailers after the last chunk are yet to be received.
+    Trailers,
+
+    /// The body ends once the connection is shut down.
+    UntilClose,
+
+    /// The response is complete.
+    Done,
+}
+
+/// Translates the request of a stream to HTTP/1.1 and the response written
+/// back from it, see the module docs.
+pub(crate) struct Translator {
+    /// How the request's body is framed, `None` until the head is received.
+    request: Option<RequestBody>,
+
+    /// If the request's trailers have been received, nothing can follow.
+    has_trailers: bool,
+
+    /// If the request is a `HEAD` request, the response has no body.
+    head: bool,
+
+    /// The response written by the H1 protocol which is yet to be
+    /// translated.
+    outbound: BytesMut,
+
+    response: ResponseBody,
+}
+
+impl Translator {
+    pub(crate) fn new() -> Self {
+        Self {
+            request: None,
+            has_trailers: false,
+            head: false,
+            outbound: BytesMut::new(),
+            response: ResponseBody::Head,
+        }
+    }
+
+    /// If the head of the request has been received.
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    pub(crate) fn has_head(&self) -> bool {
+        self.request.is_some()
+    }
+
+    /// If the trailers of the request have been received.
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    pub(crate) fn has_trailers(&self) -> bool {
+        self.has_trailers
+    }
+
+    /// If the whole response has been translated.
+    pub(crate) fn is_complete(&self) -> bool {
+        matches!(self.response, ResponseBody::Done)
+    }
+
+    /// Writes the request line and headers translated from the request's
+    /// field section, `has_body` is set unless the stream has already
+    /// ended.
+    pub(crate) fn recv_head(
+        &mut self,
+        fields: &[Field],
+        has_body: bool,
+        out: &mut BytesMut,
+    ) -> Result<(), TranslateError> {
+        let malformed = TranslateError::Malformed;
+
+        let mut method = None;
+        let mut scheme = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut host = None;
+        let mut content_length = None;
+        let mut cookies = Vec::new();
+        let mut headers = Vec::with_capacity(fields.len());
+
+        for (name, value) in fields.iter() {
+            if let Some(pseudo) = name.strip_prefix(b":") {
+                let slot = match pseudo {
+                    _ if !headers.is_empty() => return Err(malformed),
+                    b"method" => &mut method,
+                    b"scheme" => &mut scheme,
+                    b"path" => &mut path,
+                    b"authority" => &mut authority,
+                    _ => return Err(malformed),
+                };
+                if slot.replace(value.as_slice()).is_some() {
+                    return Err(malformed);
+                }
+                continue;
+            }
+
+            if !is_token(name) | name.iter().any(u8::is_ascii_uppercase) {
+                return Err(malformed);
+            }
+            if !is_field_value(value) {
+                return Err(malformed);
+            }
+
+            match name.as_slice() {
+                name if CONNECTION_HEADERS.contains(&name) => return Err(malformed),
+                b"te" if value.as_slice() != b"trailers" => return Err(malformed),
+                b"cookie" => cookies.push(value.as_slice()),
+                b"host" => host = Some(value.as_slice()),
+                b"content-length" => {
+                    let len = std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|len| len.parse::<u64>().ok())
+                        .ok_or(malformed)?;
+                    if matches!(content_length.replace(len), Some(prev) if prev != len) {
+                        return Err(malformed);
+                    }
+                },
+                _ => headers.push((name.as_slice(), value.as_slice())),
+            }
+        }
+
+        let method = method.ok_or(malformed)?;
+        if method == b"CONNECT" {
+            return Err(TranslateError::Rejected);
+        }
+
+        let path = path.filter(|path| !path.is_empty()).ok_or(malformed)?;
+        if scheme.is_none() | !is_token(method) | !is_request_target(path) {
+            return Err(malformed);
+        }
+
+        out.put_slice(method);
+        out.put_u8(b' ');
+        out.put_slice(path);
+        out.put_slice(b" HTTP/1.1\r\n");
+
+        if let Some(host) = authority.or(host) {
+            if !is_field_value(host) {
+                return Err(malformed);
+            }
+            put_header(out, b"host", host);
+        }
+
+        for (name, value) in headers {
+            put_header(out, name, value);
+        }
+
+        if !cookies.is_empty() {
+            put_header(out, b"cookie", &cookies.join(&b"; "[..]));
+        }
+
+        let body = match content_length {
+            Some(len) => {
+                put_header(out, b"content-length", len.to_string().as_bytes());
+                RequestBody::Length(len)
+            },
+            None if has_body => {
+                put_header(out, b"transfer-encoding", b"chunked");
+                RequestBody::Chunked
+            },
+            None => RequestBody::Empty,
+        };
+
+        // Each stream is a connection of its own to the H1 protocol, which
+        // then shuts it down once the response is complete.
+        put_header(out, b"connection", b"close");
+        out.put_slice(b"\r\n");
+
+        self.request = Some(body);
+        self.head = method == b"HEAD";
+
+        Ok(())
+    }
+
+    /// Writes some of the request's body.
+    pub(crate) fn recv_body(
+        &mut self,
+        data: &[u8],
+        out: &mut BytesMut,
+    ) -> Result<(), TranslateError> {
+        match self.request {
+            Some(RequestBody::Length(remaining)) => {
+                if data.len() as u64 > remaining {
+                    return Err(TranslateError::Malformed);
+                }
+                self.request = Some(RequestBody::Length(remaining - data.len() as u64));
+                out.put_slice(data);
+            },
+            Some(RequestBody::Chunked) => {
+                out.put_slice(format!("{:x}\r\n", data.len()).as_bytes());
+                out.put_slice(data);
+                out.put_slice(b"\r\n");
+            },
+            _ => return Err(TranslateError::Malformed),
+        }
+
+        Ok(())
+    }
+
+    /// Writes the trailers of a chunked body, they're dropped otherwise as
+    /// HTTP/1.1 can't carry them.
+    pub(crate) fn recv_trailers(
+        &mut self,
+        fields: &[Field],
+        out: &mut BytesMut,
+    ) -> Result<(), TranslateError> {
+        self.has_trailers = true;
+
+        for (name, value) in fields.iter() {
+            if !is_token(name) | !is_field_value(value) {
+                return Err(TranslateError::Malformed);
+            }
+        }
+
+        match self.request {
+            Some(RequestBody::Chunked) => {
+                out.put_slice(b"0\r\n");
+                for (name, value) in fields.iter() {
+                    put_header(out, name, value);
+                }
+                out.put_slice(b"\r\n");
+            },
+            Some(RequestBody::Length(remaining)) if remaining > 0 => {
+                return Err(TranslateError::Malformed)
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Ends the request once the stream has ended, the body must be
+    /// complete.
+    pub(crate) fn recv_fin(&mut self, out: &mut BytesMut) -> Result<(), TranslateError> {
+        match self.request {
+            None => Err(TranslateError::Malformed),
+            Some(RequestBody::Length(remaining)) if remaining > 0 => {
+                Err(TranslateError::Malformed)
+            },
+            Some(RequestBody::Chunked) if !self.has_trailers => {
+                out.put_slice(b"0\r\n\r\n");
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles the HTTP/1.1 response written by the H1 protocol, writing
+    /// the parts translated so far to the sink.
+    pub(crate) fn send(
+        &mut self,
+        data: &[u8],
+        sink: &mut impl ResponseSink,
+    ) -> Result<(), TranslateError> {
+        self.outbound.extend_from_slice(data);
+
+        loop {
+            match self.response {
+                ResponseBody::Head => {
+                    if !self.send_head(sink)? {
+                        break;
+                    }
+                },
+                ResponseBody::Length(remaining) => {
+                    let len = self.outbound.len().min(remaining as usize);
+                    if len == 0 {
+                        break;
+                    }
+
+                    sink.data(&self.outbound.split_to(len));
+                    self.response = match remaining - len as u64 {
+                        0 => ResponseBody::Done,
+                        remaining => ResponseBody::Length(remaining),
+                    };
+                },
+                ResponseBody::ChunkSize => {
+                    let (len, size) = match parse_chunk_size(&self.outbound) {
+                        Ok(Status::Complete(chunk)) => chunk,
+                        Ok(Status::Partial) => break,
+                        Err(_) => return Err(TranslateError::Internal),
+                    };
+                    self.outbound.advance(len);
+                    self.response = match size {
+                        0 => ResponseBody::Trailers,
+                        size => ResponseBody::ChunkData(size),
+                    };
+                },
+                ResponseBody::ChunkData(remaining) => {
+                    let len = self.outbound.len().min(remaining as usize);
+                    if len == 0 {
+                        break;
+                    }
+
+                    sink.data(&self.outbound.split_to(len));
+                    self.response = match remaining - len as u64 {
+                        0 => ResponseBody::ChunkEnd,
+                        remaining => ResponseBody::ChunkData(remaining),
+                    };
+                },
+                ResponseBody::ChunkEnd => {
+                    if self.outbound.len() < 2 {
+                        break;
+                    }
+                    self.outbound.advance(2);
+                    self.response = ResponseBody::ChunkSize;
+                },
+                ResponseBody::Trailers => {
+                    if !self.send_trailers(sink)? {
+                        break;
+                    }
+                    self.response = ResponseBody::Done;
+                },
+                ResponseBody::UntilClose => {
+                    if self.outbound.is_empty() {
+                        break;
+                    }
+                    sink.data(&self.outbound.split());
+                },
+                ResponseBody::Done => {
+                    self.outbound.clear();
+                    break;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Translates the head of a response once received in full, returning
+    /// `false` if more is needed.
+    fn send_head(
+        &mut self,
+        sink: &mut impl ResponseSink,
+    ) -> Result<bool, TranslateError> {
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS_LIMIT];
+        let mut response = Response::new(&mut headers);
+        let len = match response.parse(&self.outbound) {
+            Ok(Status::Complete(len)) => len,
+            Ok(Status::Partial) => return Ok(false),
+            Err(_) => return Err(TranslateError::Internal),
+        };
+
+        let code = response.code.unwrap_or(500);
+        let status = code.to_string();
+        let mut content_length = None;
+        let mut chunked = false;
+
+        let mut names = Vec::with_capacity(response.headers.len());
+        for header in response.headers.iter() {
+            let name = header.name.to_ascii_lowercase();
+            match name.as_str() {
+                "content-length" => {
+                    content_length = std::str::from_utf8(header.value)
+                        .ok()
+                        .and_then(|len| len.trim().parse::<u64>().ok());
+                },
+                "transfer-encoding" => {
+                    chunked = header.value.eq_ignore_ascii_case(b"chunked");
+                    continue;
+                },
+                "connection" | "keep-alive" | "proxy-connection" | "upgrade" => continue,
+                _ => {},
+            }
+            names.push((name, header.value));
+        }
+
+        let fields: Vec<(&[u8], &[u8])> =
+            std::iter::once((&b":status"[..], status.as_bytes()))
+                .chain(names.iter().map(|(name, value)| (name.as_bytes(), *value)))
+                .collect();
+        sink.fields(&fields, false);
+        self.outbound.advance(len);
+
+        // Informational responses are followed by another head, switching
+        // protocols has no equivalent.
+        if code == 101 {
+            return Err(TranslateError::Internal);
+        }
+        if (100..200).contains(&code) {
+            return Ok(true);
+        }
+
+        self.response = if self.head | (code == 204) | (code == 304) {
+            ResponseBody::Done
+        } else if chunked {
+            ResponseBody::ChunkSize
+        } else {
+            match content_length {
+                Some(0) => ResponseBody::Done,
+                Some(len) => ResponseBody::Length(len),
+                None => ResponseBody::UntilClose,
+            }
+        };
+
+        Ok(true)
+    }
+
+    /// Translates the trailers ending a chunked response once received in
+    /// full, returning `false` if more is needed.
+    fn send_trailers(
+        &mut self,
+        sink: &mut impl ResponseSink,
+    ) -> Result<bool, TranslateError> {
+        if self.outbound.starts_with(b"\r\n") {
+            self.outbound.advance(2);
+            return Ok(true);
+        }
+
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS_LIMIT];
+        let (len, trailers) = match parse_headers(&self.outbound, &mut headers) {
+            Ok(Status::Complete(trailers)) => trailers,
+            Ok(Status::Partial) => return Ok(false),
+            Err(_) => return Err(TranslateError::Internal),
+        };
+
+        let names: Vec<(String, &[u8])> = trailers
+            .iter()
+            .map(|header| (header.name.to_ascii_lowercase(), header.value))
+            .collect();
+
+        let fields: Vec<(&[u8], &[u8])> = names
+            .iter()
+            .map(|(name, value)| (name.as_bytes(), *value))
+            .collect();
+        sink.fields(&fields, true);
+        self.outbound.advance(len);
+
+        Ok(true)
+    }
+
+    /// Ends the response as the H1 protocol has shut its connection down,
+    /// returning `true` if the response is complete and the stream can be
+    /// finished rather than reset.
+    pub(crate) fn finish(&mut self) -> bool {
+        if let ResponseBody::UntilClose = self.response {
+            self.response = ResponseBody::Done;
+        }
+
+        self.is_complete()
+    }
+}
+
+fn put_header(out: &mut BytesMut, name: &[u8], value: &[u8]) {
+    out.put_slice(name);
+    out.put_slice(b": ");
+    out.put_slice(value);
+    out.put_slice(b"\r\n");
+}
+
+/// If the bytes are a valid token, see RFC 9110 section 5.6.2.
+fn is_token(value: &[u8]) -> bool {
+    !value.is_empty()
+        && value
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// If the bytes are a valid field value, as HPACK and QPACK can carry any
+/// bytes anything which would break the framing of HTTP/1.1 is rejected.
+fn is_field_value(value: &[u8]) -> bool {
+    !value
+        .iter()
+        .any(|&b| (b == b'\r') | (b == b'\n') | (b == 0))
+}
+
+/// If the bytes are a valid request target, they must not contain any
+/// whitespace or control characters.
+fn is_request_target(value: &[u8]) -> bool {
+    value.iter().all(|&b| b.is_ascii_graphic() | (b >= 0x80))
+}
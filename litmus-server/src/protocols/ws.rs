@@ -0,0 +1,611 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::str;
+
+use bytes::{Buf, BufMut, BytesMut};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::types::{PyBytes, PyString};
+use pyo3::{PyObject, PyResult, Python};
+
+use crate::responders::{Outgoing, WebSocketFactory, CLOSE_ABNORMAL};
+use crate::server::CallbackHandler;
+use crate::traits::{BaseTransport, ProtocolBuffers};
+use crate::transport::Transport;
+
+/// The max size of a single message after reassembling any fragments.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The max size of the payload of a control frame.
+const MAX_CONTROL_SIZE: usize = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_NO_STATUS: u16 = 1005;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// A single parsed frame with the payload unmasked.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Attempts to parse a single frame from the start of the buffer.
+///
+/// Returns `Ok(None)` if more data is needed or the close code to fail
+/// the connection with if the frame is invalid.
+fn parse_frame(buffer: &mut BytesMut) -> Result<Option<Frame>, u16> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = (buffer[0] & 0x80) != 0;
+    let rsv = buffer[0] & 0x70;
+    let opcode = buffer[0] & 0x0F;
+    let masked = (buffer[1] & 0x80) != 0;
+
+    // No extensions are negotiated and clients must always mask.
+    if (rsv != 0) | !masked {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+
+    let (len, offset) = match buffer[1] & 0x7F {
+        126 if buffer.len() >= 4 => {
+            (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4)
+        },
+        127 if buffer.len() >= 10 => {
+            (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10)
+        },
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+
+    let is_control = (opcode & 0x08) != 0;
+    if is_control & (!fin | (len > MAX_CONTROL_SIZE as u64)) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+
+    let len = len as usize;
+    if buffer.len() < offset + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = [
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ];
+    buffer.advance(offset + 4);
+
+    let mut payload = buffer.split_to(len).to_vec();
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+/// Writes a single unmasked frame to the buffer.
+fn write_frame(buffer: &mut BytesMut, opcode: u8, payload: &[u8]) {
+    buffer.reserve(payload.len() + 10);
+    buffer.put_u8(0x80 | opcode);
+
+    if payload.len() < 126 {
+        buffer.put_u8(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        buffer.put_u8(126);
+        buffer.put_u16(payload.len() as u16);
+    } else {
+        buffer.put_u8(127);
+        buffer.put_u64(payload.len() as u64);
+    }
+
+    buffer.put_slice(payload);
+}
+
+/// If the close code can be sent by a peer, codes below 1000, the
+/// reserved codes such as `1005 No Status` that are never sent in a frame
+/// and the unassigned codes below 3000 are all invalid.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// Builds the payload of a close frame.
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(reason.len() + 2);
+    payload.extend_from_slice(&code.to_be_bytes());
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+/// The websocket protocol handler, switched to from H1 once the
+/// application accepts an upgrade.
+pub(crate) struct WsProtocol {
+    maybe_transport: Option<Transport>,
+    callback: CallbackHandler,
+
+    /// The application callback invoked with each message.
+    on_message: Option<PyObject>,
+
+    /// The messages queued by the application.
+    outgoing: Option<WebSocketFactory>,
+
+    /// The opcode and payload of a fragmented message being reassembled.
+    fragments: Option<(u8, Vec<u8>)>,
+
+    /// Control frames waiting to be written ahead of any messages.
+    control: VecDeque<(u8, Vec<u8>)>,
+
+    /// If a close frame has been queued to be written.
+    close_sent: bool,
+
+    /// If a close frame has been received or the connection failed.
+    close_received: bool,
+
+    /// The code the connection was closed with, given to the application
+    /// once the connection is lost.
+    close_code: u16,
+}
+
+impl WsProtocol {
+    pub(crate) fn new(callback: CallbackHandler) -> Self {
+        Self {
+            maybe_transport: None,
+            callback,
+            on_message: None,
+            outgoing: None,
+            fragments: None,
+            control: VecDeque::new(),
+            close_sent: false,
+            close_received: false,
+            close_code: CLOSE_ABNORMAL,
+        }
+    }
+
+    /// Get the set transport or raise an error.
+    #[inline]
+    fn transport(&self) -> PyResult<&Transport> {
+        self.maybe_transport.as_ref().ok_or_else(|| {
+            PyRuntimeError::new_err("transport was None upon being called")
+        })
+    }
+
+    /// Called when the protocol takes over a connection that has been
+    /// upgraded with the application's message callback and queue.
+    pub(crate) fn new_connection(
+        &mut self,
+        transport: Transport,
+        on_message: PyObject,
+        outgoing: WebSocketFactory,
+    ) {
+        self.maybe_transport = Some(transport);
+        self.on_message = Some(on_message);
+        self.outgoing = Some(outgoing);
+        self.fragments = None;
+        self.control.clear();
+        self.close_sent = false;
+        self.close_received = false;
+        self.close_code = CLOSE_ABNORMAL;
+    }
+
+    /// Called when the connection is lost from the protocol, the
+    /// application is told the connection has closed.
+    pub(crate) fn lost_connection(&mut self) -> PyResult<()> {
+        if let Some(outgoing) = self.outgoing.take() {
+            outgoing.set_close_code(self.close_code);
+        }
+        self.fragments = None;
+        self.control.clear();
+
+        if let Some(cb) = self.on_message.take() {
+            Python::with_gil(|py| {
+                if let Err(e) = cb.call1(py, (py.None(),)) {
+                    self.callback.report_error(py, e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Starts the closing handshake with the `1001 Going Away` code as the
+    /// server is shutting down.
+    pub(crate) fn drain(&mut self) -> PyResult<()> {
+        self.queue_close(CLOSE_GOING_AWAY);
+        self.transport()?.resume_writing()
+    }
+
+    /// Fails the connection sending a close frame with the given code and
+    /// ignoring anything else sent by the client.
+    fn fail(&mut self, buffer: &mut BytesMut, code: u16) -> PyResult<()> {
+        debug!("failing websocket connection with code {}", code);
+
+        buffer.clear();
+        self.close_received = true;
+        self.close_code = code;
+        self.queue_close(code);
+
+        let transport = self.transport()?;
+        transport.pause_reading()?;
+        transport.resume_writing()
+    }
+
+    /// Queues a close frame if one has not already been sent.
+    fn queue_close(&mut self, code: u16) {
+        if !self.close_sent {
+            self.close_sent = true;
+            self.control.push_back((OP_CLOSE, close_payload(code, "")));
+        }
+    }
+
+    /// Handles a single frame returning the close code if the connection
+    /// should be failed.
+    fn on_frame(&mut self, frame: Frame) -> Result<(), u16> {
+        match frame.opcode {
+            OP_TEXT | OP_BINARY => {
+                if self.fragments.is_some() {
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                }
+
+                if frame.fin {
+                    self.on_message(frame.opcode, frame.payload)
+                } else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                    Ok(())
+                }
+            },
+            OP_CONTINUATION => {
+                let (opcode, mut payload) = match self.fragments.take() {
+                    Some(fragments) => fragments,
+                    None => return Err(CLOSE_PROTOCOL_ERROR),
+                };
+
+                if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(CLOSE_TOO_BIG);
+                }
+                payload.extend(frame.payload);
+
+                if frame.fin {
+                    self.on_message(opcode, payload)
+                } else {
+                    self.fragments = Some((opcode, payload));
+                    Ok(())
+                }
+            },
+            OP_PING => {
+                // Nothing can follow a close frame, including pongs.
+                if !self.close_sent {
+                    self.control.push_back((OP_PONG, frame.payload));
+                }
+                Ok(())
+            },
+            OP_PONG => Ok(()),
+            OP_CLOSE => {
+                let code = match frame.payload.len() {
+                    0 => CLOSE_NO_STATUS,
+                    1 => return Err(CLOSE_PROTOCOL_ERROR),
+                    _ => {
+                        let code =
+                            u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        if !is_valid_close_code(code) {
+                            return Err(CLOSE_PROTOCOL_ERROR);
+                        }
+                        if str::from_utf8(&frame.payload[2..]).is_err() {
+                            return Err(CLOSE_INVALID_DATA);
+                        }
+                        code
+                    },
+                };
+
+                self.close_received = true;
+                self.close_code = code;
+
+                // A close frame without a code is answered with a normal close.
+                let reply = if code == CLOSE_NO_STATUS {
+                    CLOSE_NORMAL
+                } else {
+                    code
+                };
+                self.queue_close(reply);
+                Ok(())
+            },
+            _ => Err(CLOSE_PROTOCOL_ERROR),
+        }
+    }
+
+    /// Passes a complete message to the application.
+    fn on_message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), u16> {
+        let cb = match self.on_message.as_ref() {
+            Some(cb) => cb,
+            None => return Ok(()),
+        };
+
+        Python::with_gil(|py| {
+            let message: PyObject = if opcode == OP_TEXT {
+                match str::from_utf8(&payload) {
+                    Ok(text) => PyString::new(py, text).into(),
+                    Err(_) => return Err(CLOSE_INVALID_DATA),
+                }
+            } else {
+                PyBytes::new(py, &payload).into()
+            };
+
+            if let Err(e) = cb.call1(py, (message,)) {
+                self.callback.report_error(py, e);
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl ProtocolBuffers for WsProtocol {
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        while !self.close_received {
+            let result = parse_frame(buffer).and_then(|frame| match frame {
+                Some(frame) => self.on_frame(frame).map(|_| true),
+                None => Ok(false),
+            });
+
+            match result {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(code) => return self.fail(buffer, code),
+            }
+        }
+
+        if self.close_received {
+            buffer.clear();
+        }
+
+        if !self.control.is_empty() {
+            self.transport()?.resume_writing()?;
+        }
+
+        Ok(())
+    }
+
+    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        // Control frames go first so pongs aren't held up behind messages,
+        // a close frame is always the last frame queued.
+        while let Some((opcode, payload)) = self.control.pop_front() {
+            write_frame(buffer, opcode, &payload);
+        }
+
+        while let Some(Ok(message)) = self.outgoing.as_ref().map(|o| o.recv()) {
+            if self.close_sent {
+                break;
+            }
+
+            match message {
+                Outgoing::Text(text) => write_frame(buffer, OP_TEXT, text.as_bytes()),
+                Outgoing::Binary(data) => write_frame(buffer, OP_BINARY, &data),
+                Outgoing::Close(code, reason) => {
+                    self.close_sent = true;
+                    write_frame(buffer, OP_CLOSE, &close_payload(code, &reason));
+                },
+            }
+        }
+
+        // The handshake is complete once both sides have sent a close.
+        if self.close_sent & self.close_received {
+            // This will schedule the closure using call_soon.
+            self.transport()?.close()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use pyo3::prelude::*;
+    use pyo3::types::PyList;
+
+    use super::*;
+    use crate::testing::{self, TestClient};
+
+    const UPGRADE: &[u8] = b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\
+        Upgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    /// Frames the payload as a client would, masked and with the shortest
+    /// length form.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        frame
+    }
+
+    fn parse(data: &[u8]) -> Result<Option<Frame>, u16> {
+        parse_frame(&mut BytesMut::from(data))
+    }
+
+    /// Upgrades the connection accepting the websocket with a message
+    /// callback appending to the returned list.
+    fn accepted() -> (TestClient, PyObject) {
+        let mut client = TestClient::new(testing::settings());
+        client.send(UPGRADE);
+
+        let (messages, on_message) = Python::with_gil(|py| {
+            let messages = PyList::empty(py);
+            let append: PyObject = messages.getattr("append").unwrap().into();
+            (PyObject::from(messages), append)
+        });
+        client.call(0, "accept_websocket", (on_message,));
+
+        let written = client.take_written();
+        assert!(written.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(written.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        (client, messages)
+    }
+
+    fn messages(messages: &PyObject) -> Vec<String> {
+        Python::with_gil(|py| messages.extract(py)).unwrap()
+    }
+
+    #[test]
+    fn frames_are_unmasked() {
+        let frame = parse(&client_frame(0x81, b"hello")).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"hello");
+    }
+
+    #[test]
+    fn partial_frames_are_left_in_the_buffer() {
+        let data = client_frame(0x82, &[7; 300]);
+        for len in [1, 3, 7, data.len() - 1] {
+            let mut buffer = BytesMut::from(&data[..len]);
+            assert!(parse_frame(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), len);
+        }
+
+        let mut buffer = BytesMut::from(&data[..]);
+        buffer.extend_from_slice(&client_frame(0x89, b""));
+        assert_eq!(parse_frame(&mut buffer).unwrap().unwrap().payload, [7; 300]);
+        assert_eq!(parse_frame(&mut buffer).unwrap().unwrap().opcode, OP_PING);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn unmasked_frames_are_rejected() {
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_TEXT, b"hello");
+        assert_eq!(parse(&buffer).err(), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn extended_lengths_are_parsed() {
+        let medium = vec![1; 126];
+        let data = client_frame(0x82, &medium);
+        assert_eq!(data[1], 0x80 | 126);
+        assert_eq!(parse(&data).unwrap().unwrap().payload, medium);
+
+        let large = vec![2; u16::MAX as usize + 1];
+        let data = client_frame(0x82, &large);
+        assert_eq!(data[1], 0x80 | 127);
+        assert_eq!(parse(&data).unwrap().unwrap().payload, large);
+    }
+
+    #[test]
+    fn extended_lengths_are_written() {
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_BINARY, &[0; 125]);
+        assert_eq!(buffer[..2], [0x82, 125]);
+
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_BINARY, &[0; 126]);
+        assert_eq!(buffer[..4], [0x82, 126, 0, 126]);
+        assert_eq!(buffer.len(), 4 + 126);
+
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_BINARY, &[0; 65536]);
+        assert_eq!(buffer[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(buffer.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn messages_over_the_max_size_are_rejected() {
+        let mut header = vec![0x82, 0x80 | 127];
+        header.extend_from_slice(&(MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes());
+        assert_eq!(parse(&header).err(), Some(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn oversized_control_frames_are_rejected() {
+        let data = client_frame(0x89, &[0; MAX_CONTROL_SIZE + 1]);
+        assert_eq!(parse(&data).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        let data = client_frame(0x89, &[0; MAX_CONTROL_SIZE]);
+        assert!(parse(&data).unwrap().is_some());
+
+        // Control frames can't be fragmented either.
+        let data = client_frame(0x09, b"ping");
+        assert_eq!(parse(&data).err(), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn ping_between_fragments_is_answered() {
+        let (mut client, received) = accepted();
+
+        let mut data = client_frame(0x01, b"hel");
+        data.extend(client_frame(0x89, b"ping"));
+        data.extend(client_frame(0x80, b"lo"));
+        client.send(&data);
+
+        assert_eq!(client.take_written_bytes(), b"\x8a\x04ping");
+        assert_eq!(messages(&received), ["hello"]);
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn close_handshake_echoes_the_code() {
+        let (mut client, _) = accepted();
+
+        client.send(&client_frame(0x88, b"\x03\xe8bye"));
+        assert_eq!(client.take_written_bytes(), b"\x88\x02\x03\xe8");
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn close_without_a_code_is_answered_with_a_normal_close() {
+        let (mut client, _) = accepted();
+
+        client.send(&client_frame(0x88, b""));
+        assert_eq!(client.take_written_bytes(), b"\x88\x02\x03\xe8");
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn close_with_an_invalid_code_fails_the_connection() {
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 2000, 5000] {
+            let (mut client, _) = accepted();
+
+            client.send(&client_frame(0x88, &code.to_be_bytes()));
+            assert_eq!(client.take_written_bytes(), b"\x88\x02\x03\xea", "{}", code);
+            assert!(client.is_closed());
+        }
+    }
+
+    #[test]
+    fn close_with_an_invalid_reason_fails_the_connection() {
+        let (mut client, _) = accepted();
+
+        client.send(&client_frame(0x88, b"\x03\xe8\xff\xfe"));
+        assert_eq!(client.take_written_bytes(), b"\x88\x02\x03\xef");
+        assert!(client.is_closed());
+    }
+}
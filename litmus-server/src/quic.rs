@@ -0,0 +1,1212 @@
+//! An experimental HTTP/3 listener serving requests over QUIC alongside
+//! the server's tcp listeners.
+//!
+//! QUIC itself is implemented by `quinn-proto` which performs no I/O of its
+//! own, datagrams received on the listeners' UDP sockets are handed to the
+//! connections and whatever the connections queue is sent. Each request
+//! stream is bound to a client of its own as if it were a connection, the
+//! stream is translated to HTTP/1.1 on the way in and the client's response
+//! translated back to HTTP/3 on the way out, see `protocols::h3`.
+//!
+//! The clients of request streams have no socket to register with the
+//! event loop so they register with the driver's signals instead. The UDP
+//! sockets are watched by the asyncio event loop, once one is readable, a
+//! connection's timer expires or a client has something to do
+//! `Server::poll_quic()` handles everything in a single batch.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+use pyo3::prelude::*;
+use quinn_proto::{
+    Connection as QuicConnection, ConnectionHandle, DatagramEvent, Dir, Endpoint,
+    EndpointConfig, Event, ReadError, ServerConfig, StreamEvent, StreamId,
+    TransportConfig, VarInt, WriteError,
+};
+use slab::Slab;
+
+use crate::client::ClientHandler;
+use crate::clock::Clock;
+use crate::event_loop::SocketFd;
+use crate::manager::ClientManager;
+use crate::net::{ShutdownHook, StreamHandle, TlsConfig};
+use crate::protocols::h3::{
+    self, H3Error, RequestStream, UniStream, H3_INTERNAL_ERROR, H3_NO_ERROR,
+    H3_REQUEST_CANCELLED, H3_REQUEST_REJECTED, H3_STREAM_CREATION_ERROR,
+};
+use crate::settings::Settings;
+use crate::traits::RawPollHandler;
+
+/// The most datagrams received from a single socket per poll so a busy
+/// listener can't starve everything else.
+const MAX_DATAGRAMS: usize = 256;
+
+/// The largest UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// The most read from a request stream at once.
+const MAX_READ: usize = 64 * 1024;
+
+/// The max number of requests a client can have in flight on one
+/// connection at once.
+const MAX_REQUESTS: u32 = 100;
+
+/// The max number of unidirectional streams a client can open, its
+/// control and QPACK streams along with a few of unknown types.
+const MAX_UNI_STREAMS: u32 = 8;
+
+/// What the client of a request stream is waiting on.
+#[derive(Default)]
+struct Interest {
+    /// If the client wants to be told the stream is readable or writable.
+    reading: bool,
+    writing: bool,
+
+    /// If the client should read whatever it was fed while it wasn't
+    /// reading, as it has started reading again.
+    pending_read: bool,
+}
+
+#[derive(Default)]
+struct SignalState {
+    clients: HashMap<usize, Interest>,
+
+    /// The clients with something to do, see `Quic::pump()`.
+    dirty: Vec<usize>,
+
+    /// The clients whose socket should be closed on the next wakeup.
+    closing: Vec<usize>,
+
+    /// The request streams whose client has shut the socket down.
+    shutdown: Vec<usize>,
+
+    /// If the driver is polling, wakeups are unnecessary as anything to do
+    /// is handled before it's done.
+    polling: bool,
+
+    /// If the event loop has been woken since the driver last polled.
+    woken: bool,
+
+    /// Schedules the driver to be polled.
+    wake: Option<Arc<PyObject>>,
+}
+
+/// What the clients of request streams register with in place of the event
+/// loop, see `EventLoop::with_quic()`.
+pub(crate) struct Signals {
+    state: Mutex<SignalState>,
+}
+
+impl Signals {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(SignalState::default()),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SignalState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Schedules the driver to be polled unless it's polling or already
+    /// scheduled, the callback is invoked once the lock is released.
+    fn wake(&self, mut state: MutexGuard<'_, SignalState>) {
+        if state.polling | state.woken {
+            return;
+        }
+
+        let wake = match state.wake.clone() {
+            Some(wake) => wake,
+            None => return,
+        };
+        state.woken = true;
+        drop(state);
+
+        Python::with_gil(|py| {
+            if let Err(e) = wake.call0(py) {
+                error!("QUIC wake callback raised an exception: {}", e);
+            }
+        })
+    }
+
+    /// Start telling the client at the given index once its stream is
+    /// readable.
+    pub(crate) fn add_reader(&self, index: usize) {
+        let mut state = self.state();
+        let interest = state.clients.entry(index).or_default();
+        interest.reading = true;
+        interest.pending_read = true;
+        state.dirty.push(index);
+        self.wake(state);
+    }
+
+    /// Stop telling the client at the given index once its stream is
+    /// readable.
+    pub(crate) fn remove_reader(&self, index: usize) {
+        if let Some(interest) = self.state().clients.get_mut(&index) {
+            interest.reading = false;
+        }
+    }
+
+    /// Start telling the client at the given index once everything it
+    /// queued has been written to its stream.
+    pub(crate) fn add_writer(&self, index: usize) {
+        let mut state = self.state();
+        state.clients.entry(index).or_default().writing = true;
+        state.dirty.push(index);
+        self.wake(state);
+    }
+
+    /// Stop telling the client at the given index once everything it
+    /// queued has been written to its stream.
+    pub(crate) fn remove_writer(&self, index: usize) {
+        if let Some(interest) = self.state().clients.get_mut(&index) {
+            interest.writing = false;
+        }
+    }
+
+    /// Schedules the client at the given index to be closed on the next
+    /// wakeup.
+    pub(crate) fn close_socket(&self, index: usize) {
+        let mut state = self.state();
+        state.closing.push(index);
+        self.wake(state);
+    }
+
+    /// The hook telling the driver the client of the request stream with
+    /// the given token has shut its socket down.
+    fn shutdown_hook(self: &Arc<Self>, token: usize) -> ShutdownHook {
+        let signals = Arc::downgrade(self);
+        Arc::new(move || {
+            if let Some(signals) = signals.upgrade() {
+                let mut state = signals.state();
+                state.shutdown.push(token);
+                signals.wake(state);
+            }
+        })
+    }
+
+    fn mark_dirty(&self, index: usize) {
+        self.state().dirty.push(index);
+    }
+
+    fn is_reading(&self, index: usize) -> bool {
+        self.state().clients.get(&index).is_some_and(|i| i.reading)
+    }
+
+    fn is_writing(&self, index: usize) -> bool {
+        self.state().clients.get(&index).is_some_and(|i| i.writing)
+    }
+
+    /// If the client should read what it was fed while it wasn't reading,
+    /// clearing the flag.
+    fn take_pending_read(&self, index: usize) -> bool {
+        match self.state().clients.get_mut(&index) {
+            Some(interest) => mem::take(&mut interest.pending_read) & interest.reading,
+            None => false,
+        }
+    }
+
+    /// Forgets the client at the given index once detached from its stream.
+    fn release(&self, index: usize) {
+        self.state().clients.remove(&index);
+    }
+
+    fn start_polling(&self) {
+        let mut state = self.state();
+        state.polling = true;
+        state.woken = false;
+    }
+
+    /// Wakes the event loop again if anything was left to do while polling.
+    fn finish_polling(&self) {
+        let mut state = self.state();
+        state.polling = false;
+
+        let pending = !state.dirty.is_empty()
+            | !state.closing.is_empty()
+            | !state.shutdown.is_empty();
+        if pending {
+            self.wake(state);
+        }
+    }
+
+    fn take_dirty(&self) -> Vec<usize> {
+        let mut dirty = mem::take(&mut self.state().dirty);
+        dirty.sort_unstable();
+        dirty.dedup();
+        dirty
+    }
+
+    fn take_closing(&self) -> Vec<usize> {
+        mem::take(&mut self.state().closing)
+    }
+
+    fn take_shutdown(&self) -> Vec<usize> {
+        mem::take(&mut self.state().shutdown)
+    }
+}
+
+/// A UDP socket HTTP/3 is served on along with its QUIC endpoint.
+struct Listener {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    endpoint: Endpoint,
+
+    /// The connections accepted on the socket by their handle.
+    connections: HashMap<ConnectionHandle, usize>,
+}
+
+/// A QUIC connection along with the state of HTTP/3 on it.
+struct Connection {
+    listener: usize,
+    handle: ConnectionHandle,
+    conn: QuicConnection,
+
+    /// The server's control stream once opened and the frames yet to be
+    /// written to it.
+    control: Option<StreamId>,
+    control_pending: BytesMut,
+
+    /// If the client has opened its control stream.
+    has_peer_control: bool,
+
+    /// The unidirectional streams opened by the client.
+    uni: HashMap<StreamId, UniStream>,
+
+    /// The tokens of the request streams by their id.
+    requests: HashMap<StreamId, usize>,
+
+    /// The id after the last request stream accepted, given in `GOAWAY`.
+    next_request: u64,
+
+    /// If `GOAWAY` has been sent, any later requests are rejected.
+    goaway: bool,
+}
+
+/// A request stream bound to a client.
+struct Request {
+    /// The connection of the stream, `None` once the connection is lost.
+    connection: Option<usize>,
+    id: StreamId,
+    h3: RequestStream,
+
+    /// The frames translated from the client's response which are yet to
+    /// be written to the stream.
+    pending: BytesMut,
+
+    /// The index of the client bound to the stream.
+    index: Option<usize>,
+
+    /// If the whole request has been received or the stream was reset.
+    recv_done: bool,
+
+    /// If the stream has been reset, anything the client queues from then
+    /// on is discarded.
+    send_done: bool,
+}
+
+/// What was read from a request stream.
+enum Received {
+    /// The request translated to HTTP/1.1, this may be empty.
+    Data(BytesMut),
+
+    /// The stream was reset or the request was malformed, the client
+    /// should be closed.
+    Cancelled,
+}
+
+/// Serves HTTP/3 on a set of UDP sockets, see the module docs.
+pub(crate) struct Quic {
+    listeners: Vec<Listener>,
+    connections: Slab<Connection>,
+    requests: Slab<Request>,
+
+    /// The tokens of the request streams by the index of their client.
+    bound: HashMap<usize, usize>,
+
+    signals: Arc<Signals>,
+    settings: Settings,
+
+    /// The clock of the event loop the driver is polled by.
+    clock: Clock,
+
+    /// If the server is draining, no new connections or requests are
+    /// accepted and connections are closed once their requests complete.
+    draining: bool,
+
+    buffer: Vec<u8>,
+}
+
+impl Quic {
+    /// Binds a UDP socket to each of the given addresses, the handshake is
+    /// performed with the given TLS config offering only HTTP/3.
+    pub(crate) fn bind(
+        binders: &[&str],
+        tls: &TlsConfig,
+        settings: Settings,
+    ) -> io::Result<Self> {
+        let idle_timeout = settings.keep_alive.as_millis().min(u32::MAX as u128);
+        let mut transport = TransportConfig::default();
+        transport
+            .max_concurrent_bidi_streams(VarInt::from_u32(MAX_REQUESTS))
+            .max_concurrent_uni_streams(VarInt::from_u32(MAX_UNI_STREAMS))
+            .max_idle_timeout(Some(VarInt::from_u32(idle_timeout as u32).into()));
+
+        let mut config = ServerConfig::with_crypto(tls.quic_config());
+        config.transport = Arc::new(transport);
+        let config = Arc::new(config);
+
+        let mut listeners = Vec::with_capacity(binders.len());
+        for bind in binders {
+            info!("binding HTTP/3 to {}", bind);
+            let socket = UdpSocket::bind(bind)?;
+            socket.set_nonblocking(true)?;
+
+            listeners.push(Listener {
+                addr: socket.local_addr()?,
+                socket,
+                endpoint: Endpoint::new(
+                    Arc::new(EndpointConfig::default()),
+                    Some(config.clone()),
+                ),
+                connections: HashMap::new(),
+            });
+        }
+
+        Ok(Self {
+            listeners,
+            connections: Slab::new(),
+            requests: Slab::new(),
+            bound: HashMap::new(),
+            signals: Arc::new(Signals::new()),
+            settings,
+            clock: Clock::new(None),
+            draining: false,
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+
+    /// The signals the clients of request streams register with.
+    pub(crate) fn signals(&self) -> Arc<Signals> {
+        self.signals.clone()
+    }
+
+    /// Sets the clock timers are measured with, this should be the clock
+    /// of the event loop the driver is polled by.
+    pub(crate) fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Sets the callback scheduling the driver to be polled, see
+    /// `Server::init_quic()`.
+    pub(crate) fn set_wake(&self, wake: Option<PyObject>) {
+        self.signals.state().wake = wake.map(Arc::new);
+    }
+
+    /// The file descriptors of the UDP sockets.
+    pub(crate) fn fds(&self) -> Vec<SocketFd> {
+        #[cfg(unix)]
+        return self
+            .listeners
+            .iter()
+            .map(|l| l.socket.as_raw_fd())
+            .collect();
+
+        #[cfg(windows)]
+        return self
+            .listeners
+            .iter()
+            .map(|l| l.socket.as_raw_socket())
+            .collect();
+    }
+
+    /// The number of connections which are yet to be closed.
+    pub(crate) fn len_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Receives on every socket, handles any expired timers and drives
+    /// every request stream with something to do, returning the time until
+    /// the next timer expires.
+    ///
+    /// An error handling one client is logged rather than returned so the
+    /// rest still get handled.
+    pub(crate) fn poll(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+    ) -> PyResult<Option<Duration>> {
+        let now = self.clock.instant()?;
+        self.signals.start_polling();
+
+        self.receive(now);
+        for (_, connection) in self.connections.iter_mut() {
+            if connection.conn.poll_timeout().is_some_and(|t| t <= now) {
+                connection.conn.handle_timeout(now);
+            }
+        }
+
+        let keys: Vec<usize> = self.connections.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            self.process(manager, key, now);
+        }
+
+        for index in self.signals.take_closing() {
+            if let Err(e) = manager.poll_close(index) {
+                error!("failed to close client {}: {}", index, e);
+            }
+        }
+
+        for token in self.signals.take_shutdown() {
+            match self.requests.get(token).map(|r| r.index) {
+                Some(Some(index)) => self.signals.mark_dirty(index),
+                // The client shut down before being bound to the stream.
+                Some(None) => self.finish_request(token, now),
+                None => {},
+            }
+        }
+
+        for index in self.signals.take_dirty() {
+            if let Err(e) = self.pump(manager, index, now) {
+                error!("failed to drive client {}: {}", index, e);
+            }
+        }
+
+        if self.draining {
+            self.poll_drain(now);
+        }
+
+        self.transmit(now);
+        self.remove_drained();
+        self.signals.finish_polling();
+
+        let timeout = self
+            .connections
+            .iter_mut()
+            .filter_map(|(_, connection)| connection.conn.poll_timeout())
+            .min()
+            .map(|timeout| timeout.saturating_duration_since(now));
+
+        Ok(timeout)
+    }
+
+    /// Stops accepting connections and requests, telling every client with
+    /// `GOAWAY`, connections are closed once their requests complete.
+    pub(crate) fn drain(&mut self) -> PyResult<()> {
+        self.draining = true;
+        for listener in self.listeners.iter_mut() {
+            listener.endpoint.reject_new_connections();
+        }
+
+        let now = self.clock.instant()?;
+        self.poll_drain(now);
+        self.transmit(now);
+
+        Ok(())
+    }
+
+    /// Closes every connection and stops waking the event loop.
+    pub(crate) fn shutdown(&mut self) -> PyResult<()> {
+        self.set_wake(None);
+
+        let now = self.clock.instant()?;
+        for (_, connection) in self.connections.iter_mut() {
+            connection
+                .conn
+                .close(now, varint(H3_NO_ERROR), Bytes::new());
+        }
+
+        self.transmit(now);
+
+        Ok(())
+    }
+
+    fn receive(&mut self, now: Instant) {
+        for index in 0..self.listeners.len() {
+            for _ in 0..MAX_DATAGRAMS {
+                let listener = &mut self.listeners[index];
+                let (len, remote) = match listener.socket.recv_from(&mut self.buffer) {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("failed to receive on {}: {}", listener.addr, e);
+                        break;
+                    },
+                };
+
+                let data = BytesMut::from(&self.buffer[..len]);
+                match listener.endpoint.handle(now, remote, None, None, data) {
+                    Some((handle, DatagramEvent::NewConnection(conn))) => {
+                        debug!("accepted HTTP/3 connection from {}", remote);
+                        let key = self.connections.insert(Connection {
+                            listener: index,
+                            handle,
+                            conn,
+                            control: None,
+                            control_pending: BytesMut::new(),
+                            has_peer_control: false,
+                            uni: HashMap::new(),
+                            requests: HashMap::new(),
+                            next_request: 0,
+                            goaway: false,
+                        });
+                        listener.connections.insert(handle, key);
+                    },
+                    Some((handle, DatagramEvent::ConnectionEvent(event))) => {
+                        if let Some(key) = listener.connections.get(&handle) {
+                            self.connections[*key].conn.handle_event(event);
+                        }
+                    },
+                    None => {},
+                }
+            }
+        }
+    }
+
+    /// Handles the events of the connection with the given key.
+    fn process(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        key: usize,
+        now: Instant,
+    ) {
+        self.poll_endpoint_events(key);
+
+        let mut events = Vec::new();
+        while let Some(event) = self.connections[key].conn.poll() {
+            events.push(event);
+        }
+
+        for event in events {
+            let result = match event {
+                Event::Connected => {
+                    self.open_control(key, now);
+                    Ok(())
+                },
+                Event::ConnectionLost { reason } => {
+                    debug!("HTTP/3 connection lost: {}", reason);
+                    self.connection_lost(manager, key)
+                },
+                Event::Stream(StreamEvent::Opened { dir }) => {
+                    self.accept_streams(manager, key, dir, now)
+                },
+                Event::Stream(StreamEvent::Readable { id }) => {
+                    self.readable(key, id, now);
+                    Ok(())
+                },
+                Event::Stream(StreamEvent::Writable { id }) => {
+                    self.writable(key, id, now);
+                    Ok(())
+                },
+                Event::Stream(StreamEvent::Stopped { id, .. }) => {
+                    self.stopped(manager, key, id)
+                },
+                _ => Ok(()),
+            };
+
+            if let Err(e) = result {
+                error!("failed to handle HTTP/3 event: {}", e);
+            }
+        }
+    }
+
+    fn poll_endpoint_events(&mut self, key: usize) {
+        let connection = &mut self.connections[key];
+        let endpoint = &mut self.listeners[connection.listener].endpoint;
+        while let Some(event) = connection.conn.poll_endpoint_events() {
+            if let Some(event) = endpoint.handle_event(connection.handle, event) {
+                connection.conn.handle_event(event);
+            }
+        }
+    }
+
+    /// Opens the server's control stream once the handshake completes.
+    fn open_control(&mut self, key: usize, now: Instant) {
+        let connection = &mut self.connections[key];
+        if let Some(id) = connection.conn.streams().open(Dir::Uni) {
+            connection.control = Some(id);
+            connection.control_pending =
+                h3::control_stream(self.settings.max_header_size);
+            flush_control(connection, now);
+        }
+    }
+
+    fn connection_lost(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        key: usize,
+    ) -> PyResult<()> {
+        let tokens: Vec<usize> =
+            self.connections[key].requests.values().copied().collect();
+        for token in tokens {
+            let request = &mut self.requests[token];
+            request.connection = None;
+            request.recv_done = true;
+            request.send_done = true;
+            request.pending.clear();
+
+            if let Some(index) = request.index {
+                manager.poll_close(index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accepts every new stream the client opened in the given direction.
+    fn accept_streams(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        key: usize,
+        dir: Dir,
+        now: Instant,
+    ) -> PyResult<()> {
+        while let Some(id) = self.connections[key].conn.streams().accept(dir) {
+            match dir {
+                Dir::Uni => {
+                    self.connections[key].uni.insert(id, UniStream::new());
+                    self.read_uni(key, id, now);
+                },
+                Dir::Bi => self.accept_request(manager, key, id, now)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Binds a new request stream to a client, unless the server is
+    /// draining or the connection limit has been reached.
+    fn accept_request(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        key: usize,
+        id: StreamId,
+        now: Instant,
+    ) -> PyResult<()> {
+        let connection = &mut self.connections[key];
+        connection.next_request = connection.next_request.max(id.0 + 4);
+
+        let limited = self
+            .settings
+            .connection_limit
+            .is_some_and(|limit| manager.len_active() >= limit.max);
+        if connection.goaway | self.draining | limited {
+            debug!(
+                "rejecting HTTP/3 request from {}",
+                connection.conn.remote_address()
+            );
+            reset_stream(&mut connection.conn, id, H3_REQUEST_REJECTED);
+            return Ok(());
+        }
+
+        let listener = &self.listeners[connection.listener];
+        let addr = connection.conn.remote_address();
+        let server = match connection.conn.local_ip() {
+            Some(ip) => SocketAddr::new(ip, listener.addr.port()),
+            None => listener.addr,
+        };
+
+        let token = self.requests.insert(Request {
+            connection: Some(key),
+            id,
+            h3: RequestStream::new(self.settings.max_header_size),
+            pending: BytesMut::new(),
+            index: None,
+            recv_done: false,
+            send_done: false,
+        });
+        connection.requests.insert(id, token);
+
+        let hook = self.signals.shutdown_hook(token);
+        let index = match manager
+            .handle_connection(StreamHandle::from_quic(addr, server, hook))
+        {
+            Ok(index) => index,
+            Err(e) => {
+                reset_stream(&mut connection.conn, id, H3_INTERNAL_ERROR);
+                connection.requests.remove(&id);
+                self.requests.remove(token);
+                return Err(e);
+            },
+        };
+
+        self.requests[token].index = Some(index);
+        if let Some(previous) = self.bound.insert(index, token) {
+            // The client was reused before its previous stream was
+            // finished, which can no longer be told apart from this one.
+            self.requests[previous].index = None;
+            self.finish_request(previous, now);
+        }
+
+        Ok(())
+    }
+
+    /// Reads whatever is available on one of the client's unidirectional
+    /// streams.
+    fn read_uni(&mut self, key: usize, id: StreamId, now: Instant) {
+        let Connection {
+            conn,
+            uni,
+            has_peer_control,
+            ..
+        } = &mut self.connections[key];
+        let stream = match uni.get_mut(&id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut recv = conn.recv_stream(id);
+        let mut chunks = match recv.read(true) {
+            Ok(chunks) => chunks,
+            Err(_) => return,
+        };
+
+        let mut result = Ok(true);
+        let mut finished = false;
+        loop {
+            match chunks.next(MAX_READ) {
+                Ok(Some(chunk)) => {
+                    result = stream.recv(&chunk.bytes, has_peer_control);
+                    if !matches!(result, Ok(true)) {
+                        break;
+                    }
+                },
+                Ok(None) | Err(ReadError::Reset(_)) => {
+                    finished = true;
+                    break;
+                },
+                Err(ReadError::Blocked) => break,
+            }
+        }
+        let _ = chunks.finalize();
+
+        let result = match result {
+            Ok(true) if finished => stream.finished(),
+            Ok(true) => Ok(()),
+            // Streams of unknown types are ignored.
+            Ok(false) => {
+                let _ = conn.recv_stream(id).stop(varint(H3_STREAM_CREATION_ERROR));
+                uni.remove(&id);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        };
+
+        if finished {
+            uni.remove(&id);
+        }
+
+        if let Err(H3Error::Connection(code) | H3Error::Stream(code)) = result {
+            conn.close(now, varint(code), Bytes::new());
+        }
+    }
+
+    fn readable(&mut self, key: usize, id: StreamId, now: Instant) {
+        let connection = &self.connections[key];
+        if connection.uni.contains_key(&id) {
+            return self.read_uni(key, id, now);
+        }
+
+        let index = connection
+            .requests
+            .get(&id)
+            .and_then(|token| self.requests[*token].index);
+        if let Some(index) = index {
+            self.signals.mark_dirty(index);
+        }
+    }
+
+    fn writable(&mut self, key: usize, id: StreamId, now: Instant) {
+        let connection = &mut self.connections[key];
+        if connection.control == Some(id) {
+            return flush_control(connection, now);
+        }
+
+        if let Some(token) = connection.requests.get(&id).copied() {
+            self.flush(token);
+            if let Some(index) = self.requests[token].index {
+                self.signals.mark_dirty(index);
+            }
+        }
+    }
+
+    /// Handles the client no longer reading the response on a stream, the
+    /// request is cancelled.
+    fn stopped(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        key: usize,
+        id: StreamId,
+    ) -> PyResult<()> {
+        let connection = &mut self.connections[key];
+        if connection.control == Some(id) {
+            warn!("HTTP/3 client stopped reading the control stream");
+            return Ok(());
+        }
+
+        let token = match connection.requests.get(&id) {
+            Some(token) => *token,
+            None => return Ok(()),
+        };
+
+        let _ = connection
+            .conn
+            .send_stream(id)
+            .reset(varint(H3_REQUEST_CANCELLED));
+        let request = &mut self.requests[token];
+        request.send_done = true;
+        request.pending.clear();
+
+        match request.index {
+            Some(index) => manager.poll_close(index),
+            None => Ok(()),
+        }
+    }
+
+    /// Drives the client at the given index the same way as a completion
+    /// based event loop, writing anything the client queued to its stream
+    /// and telling it it can read or write.
+    fn pump(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        index: usize,
+        now: Instant,
+    ) -> PyResult<()> {
+        let token = match self.bound.get(&index) {
+            Some(token) => *token,
+            None => return Ok(()),
+        };
+
+        loop {
+            // The client's response is only taken once everything before it
+            // has been written, so the client is held back by flow control.
+            self.flush(token);
+            let blocked = !self.requests[token].pending.is_empty();
+            if !blocked && self.take_response(manager, token, index, now)? {
+                continue;
+            }
+            if !self.bound.contains_key(&index) {
+                return Ok(());
+            }
+
+            let pending_read = self.signals.take_pending_read(index);
+            if self.signals.is_reading(index) {
+                match self.read_request(token, now) {
+                    Received::Data(data) if !data.is_empty() | pending_read => {
+                        manager.poll_received(index, Some(&data))?;
+                        continue;
+                    },
+                    Received::Data(_) => {},
+                    Received::Cancelled => {
+                        manager.poll_close(index)?;
+                        continue;
+                    },
+                }
+            }
+
+            // Everything queued has been written so the stream is writable,
+            // the client is polled again on the next wakeup if it still
+            // wants to write without having queued anything.
+            if !blocked & self.signals.is_writing(index) {
+                manager.poll_write(index)?;
+                if self.take_response(manager, token, index, now)? {
+                    continue;
+                }
+                if !self.bound.contains_key(&index) {
+                    return Ok(());
+                }
+                if self.signals.is_writing(index) {
+                    self.signals.mark_dirty(index);
+                }
+            }
+
+            break;
+        }
+
+        Ok(())
+    }
+
+    /// Takes what the client queued translating it to frames on its stream,
+    /// returning `true` if there was anything. The stream is finished once
+    /// the client has shut its socket down.
+    fn take_response(
+        &mut self,
+        manager: &mut ClientManager<ClientHandler>,
+        token: usize,
+        index: usize,
+        now: Instant,
+    ) -> PyResult<bool> {
+        let data = match manager.take_outbound(index)? {
+            Some(data) => data,
+            None => {
+                self.finish_request(token, now);
+                return Ok(false);
+            },
+        };
+
+        if data.is_empty() {
+            return Ok(false);
+        }
+
+        let request = &mut self.requests[token];
+        if !request.send_done {
+            if let Err(H3Error::Connection(code) | H3Error::Stream(code)) =
+                request.h3.send(&data, &mut request.pending)
+            {
+                self.reset_request(token, code);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Reads whatever is available on the request stream, translated to
+    /// HTTP/1.1.
+    fn read_request(&mut self, token: usize, now: Instant) -> Received {
+        let request = &mut self.requests[token];
+        let connection = match request.connection {
+            Some(key) if !request.recv_done => &mut self.connections[key],
+            _ => return Received::Data(BytesMut::new()),
+        };
+
+        let mut data = BytesMut::new();
+        let mut fin = false;
+        let mut recv = connection.conn.recv_stream(request.id);
+        match recv.read(true) {
+            Ok(mut chunks) => {
+                let mut reset = false;
+                while data.len() < MAX_READ {
+                    match chunks.next(MAX_READ - data.len()) {
+                        Ok(Some(chunk)) => data.extend_from_slice(&chunk.bytes),
+                        Ok(None) => {
+                            fin = true;
+                            break;
+                        },
+                        Err(ReadError::Blocked) => break,
+                        Err(ReadError::Reset(_)) => {
+                            reset = true;
+                            break;
+                        },
+                    }
+                }
+                let _ = chunks.finalize();
+
+                if reset {
+                    request.recv_done = true;
+                    return Received::Cancelled;
+                }
+            },
+            Err(_) => {
+                request.recv_done = true;
+                return Received::Cancelled;
+            },
+        }
+
+        let mut out = BytesMut::new();
+        match request.h3.recv(&data, fin, &mut out) {
+            Ok(()) => {
+                request.recv_done = fin;
+                Received::Data(out)
+            },
+            Err(H3Error::Stream(code)) => {
+                request.recv_done = true;
+                self.reset_request(token, code);
+                Received::Cancelled
+            },
+            Err(H3Error::Connection(code)) => {
+                request.recv_done = true;
+                connection.conn.close(now, varint(code), Bytes::new());
+                Received::Cancelled
+            },
+        }
+    }
+
+    /// Writes as much of the translated response to the stream as flow
+    /// control allows.
+    fn flush(&mut self, token: usize) {
+        let request = &mut self.requests[token];
+        let connection = match request.connection {
+            Some(key) if !request.send_done => &mut self.connections[key],
+            _ => return request.pending.clear(),
+        };
+
+        if !write_stream(&mut connection.conn, request.id, &mut request.pending) {
+            self.reset_request(token, H3_REQUEST_CANCELLED);
+        }
+    }
+
+    /// Resets both directions of the request stream with the given error.
+    fn reset_request(&mut self, token: usize, code: u64) {
+        let request = &mut self.requests[token];
+        request.send_done = true;
+        request.pending.clear();
+
+        if let Some(key) = request.connection {
+            reset_stream(&mut self.connections[key].conn, request.id, code);
+        }
+    }
+
+    /// Finishes the request stream once its client has shut its socket
+    /// down, detaching the client as it may be reused once closed.
+    fn finish_request(&mut self, token: usize, now: Instant) {
+        let mut request = self.requests.remove(token);
+        if let Some(index) = request.index {
+            self.bound.remove(&index);
+            self.signals.release(index);
+        }
+
+        let connection = match request.connection {
+            Some(key) => &mut self.connections[key],
+            None => return,
+        };
+        connection.requests.remove(&request.id);
+
+        // A response which ends with the connection is complete, anything
+        // else cut short is reset so the client doesn't take it as whole.
+        if !request.send_done {
+            if request.h3.finish() {
+                let _ = connection.conn.send_stream(request.id).finish();
+            } else {
+                let code = varint(H3_INTERNAL_ERROR);
+                let _ = connection.conn.send_stream(request.id).reset(code);
+            }
+        }
+
+        // The response is complete so the rest of the request isn't needed.
+        if !request.recv_done {
+            let _ = connection
+                .conn
+                .recv_stream(request.id)
+                .stop(varint(H3_NO_ERROR));
+        }
+
+        if self.draining & connection.requests.is_empty() {
+            connection
+                .conn
+                .close(now, varint(H3_NO_ERROR), Bytes::new());
+        }
+    }
+
+    /// Sends `GOAWAY` on every connection, closing those without any
+    /// requests in flight.
+    fn poll_drain(&mut self, now: Instant) {
+        for (_, connection) in self.connections.iter_mut() {
+            if !connection.goaway & connection.control.is_some() {
+                connection.goaway = true;
+                let goaway = h3::goaway(connection.next_request);
+                connection.control_pending.extend_from_slice(&goaway);
+                flush_control(connection, now);
+            }
+
+            if connection.requests.is_empty() & !connection.conn.is_closed() {
+                connection
+                    .conn
+                    .close(now, varint(H3_NO_ERROR), Bytes::new());
+            }
+        }
+    }
+
+    /// Sends every datagram the connections and endpoints have queued, any
+    /// which would block are dropped and left to QUIC to resend.
+    fn transmit(&mut self, now: Instant) {
+        let keys: Vec<usize> = self.connections.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            self.poll_endpoint_events(key);
+
+            let connection = &mut self.connections[key];
+            let listener = &self.listeners[connection.listener];
+            while let Some(transmit) = connection.conn.poll_transmit(now, 1) {
+                send(&listener.socket, &transmit.contents, transmit.destination);
+            }
+        }
+
+        for listener in self.listeners.iter_mut() {
+            while let Some(transmit) = listener.endpoint.poll_transmit() {
+                send(&listener.socket, &transmit.contents, transmit.destination);
+            }
+        }
+    }
+
+    /// Removes every connection which has been closed and has no more
+    /// packets to send or receive.
+    fn remove_drained(&mut self) {
+        let drained: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.conn.is_drained())
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in drained {
+            let connection = self.connections.remove(key);
+            self.listeners[connection.listener]
+                .connections
+                .remove(&connection.handle);
+
+            for token in connection.requests.values() {
+                if let Some(request) = self.requests.get_mut(*token) {
+                    request.connection = None;
+                }
+            }
+        }
+    }
+}
+
+/// Writes whatever is pending on the server's control stream, closing the
+/// connection if the client stopped it.
+fn flush_control(connection: &mut Connection, now: Instant) {
+    let id = match connection.control {
+        Some(id) => id,
+        None => return,
+    };
+
+    if !write_stream(&mut connection.conn, id, &mut connection.control_pending) {
+        let code = varint(h3::H3_CLOSED_CRITICAL_STREAM);
+        connection.conn.close(now, code, Bytes::new());
+    }
+}
+
+/// Writes as much of the buffer to the stream as flow control allows,
+/// returning `false` if the client stopped the stream.
+fn write_stream(
+    conn: &mut QuicConnection,
+    id: StreamId,
+    pending: &mut BytesMut,
+) -> bool {
+    while !pending.is_empty() {
+        match conn.send_stream(id).write(pending) {
+            Ok(0) | Err(WriteError::Blocked) => break,
+            Ok(len) => pending.advance(len),
+            Err(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Resets both directions of a request stream with the given error.
+fn reset_stream(conn: &mut QuicConnection, id: StreamId, code: u64) {
+    let _ = conn.send_stream(id).reset(varint(code));
+    let _ = conn.recv_stream(id).stop(varint(code));
+}
+
+fn send(socket: &UdpSocket, data: &[u8], addr: SocketAddr) {
+    if let Err(e) = socket.send_to(data, addr) {
+        if e.kind() != ErrorKind::WouldBlock {
+            debug!("failed to send datagram to {}: {}", addr, e);
+        }
+    }
+}
+
+fn varint(code: u64) -> VarInt {
+    VarInt::from_u64(code).unwrap_or(VarInt::MAX)
+}
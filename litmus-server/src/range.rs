@@ -0,0 +1,230 @@
+use std::time::UNIX_EPOCH;
+
+use http::header::{IF_RANGE, RANGE};
+use httparse::Header;
+
+/// A byte range of a body requested by a `Range` header.
+#[derive(Copy, Clone)]
+pub(crate) enum ByteRange {
+    /// The inclusive range of bytes to send.
+    Satisfiable(u64, u64),
+
+    /// The range is outside of the body.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// The value of the `Content-Range` header describing the range of a
+    /// body of the given length.
+    pub(crate) fn content_range(&self, len: u64) -> String {
+        match self {
+            Self::Satisfiable(start, end) => format!("bytes {}-{}/{}", start, end, len),
+            Self::Unsatisfiable => format!("bytes */{}", len),
+        }
+    }
+}
+
+/// The `Range` and `If-Range` headers of a request.
+#[derive(Clone)]
+pub(crate) struct RangeRequest {
+    range: String,
+    if_range: Option<String>,
+}
+
+impl RangeRequest {
+    /// The range requested by the given headers, `None` if there's no
+    /// `Range` header.
+    pub(crate) fn from_headers(headers: &[Header]) -> Option<Self> {
+        let find = |name: &str| {
+            headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .and_then(|h| std::str::from_utf8(h.value).ok())
+                .map(|value| value.trim().to_string())
+        };
+
+        Some(Self {
+            range: find(RANGE.as_str())?,
+            if_range: find(IF_RANGE.as_str()),
+        })
+    }
+
+    /// The range to send of a body of the given length, `None` if the
+    /// whole body should be sent.
+    ///
+    /// The range is ignored if it isn't a single byte range, or if an
+    /// `If-Range` doesn't match the body's strong `etag` or its
+    /// `last_modified` time in seconds since the epoch.
+    pub(crate) fn resolve(
+        &self,
+        len: u64,
+        etag: Option<&str>,
+        last_modified: Option<u64>,
+    ) -> Option<ByteRange> {
+        let current = match self.if_range.as_deref() {
+            Some(tag) if tag.starts_with('"') => {
+                etag.is_some_and(|etag| !etag.starts_with("W/") & (tag == etag))
+            },
+            Some(date) => last_modified.is_some() & (parse_date(date) == last_modified),
+            None => true,
+        };
+
+        if current {
+            parse_range(&self.range, len)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses an HTTP date into seconds since the epoch.
+pub(crate) fn parse_date(date: &str) -> Option<u64> {
+    httpdate::parse_http_date(date.trim())
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since| since.as_secs())
+}
+
+/// Parses a `Range` header, only a single byte range is supported so
+/// anything else, e.g. multiple ranges, is `None` and the whole body
+/// is sent instead.
+fn parse_range(range: &str, len: u64) -> Option<ByteRange> {
+    let (unit, range) = range.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") | range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    // `bytes=-500` is the last 500 bytes.
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if (suffix == 0) | (len == 0) {
+            return Some(ByteRange::Unsatisfiable);
+        }
+
+        return Some(ByteRange::Satisfiable(len.saturating_sub(suffix), len - 1));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() {
+        u64::MAX
+    } else {
+        end.parse().ok()?
+    };
+
+    if end < start {
+        return None;
+    }
+
+    if start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+
+    Some(ByteRange::Satisfiable(start, end.min(len - 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"abc\"";
+    const MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const MODIFIED_SECS: u64 = 784111777;
+
+    /// The `Content-Range` of the range resolved from the given headers
+    /// for a body of the given length, `None` if the whole body is sent.
+    fn resolve(headers: &[(&str, &str)], len: u64) -> Option<String> {
+        let headers: Vec<Header> = headers
+            .iter()
+            .map(|(name, value)| Header {
+                name,
+                value: value.as_bytes(),
+            })
+            .collect();
+        RangeRequest::from_headers(&headers)?
+            .resolve(len, Some(ETAG), Some(MODIFIED_SECS))
+            .map(|range| range.content_range(len))
+    }
+
+    fn range(range: &str, len: u64) -> Option<String> {
+        resolve(&[("range", range)], len)
+    }
+
+    #[test]
+    fn single_ranges_are_satisfied() {
+        assert_eq!(
+            range("bytes=0-99", 1000).as_deref(),
+            Some("bytes 0-99/1000")
+        );
+        assert_eq!(
+            range("bytes=500-", 1000).as_deref(),
+            Some("bytes 500-999/1000")
+        );
+        assert_eq!(
+            range("bytes=-100", 1000).as_deref(),
+            Some("bytes 900-999/1000")
+        );
+        assert_eq!(
+            range("bytes=-5000", 1000).as_deref(),
+            Some("bytes 0-999/1000")
+        );
+        assert_eq!(
+            range("Bytes = 990-5000", 1000).as_deref(),
+            Some("bytes 990-999/1000")
+        );
+    }
+
+    #[test]
+    fn ranges_outside_the_body_are_unsatisfiable() {
+        assert_eq!(range("bytes=1000-", 1000).as_deref(), Some("bytes */1000"));
+        assert_eq!(range("bytes=-0", 1000).as_deref(), Some("bytes */1000"));
+        assert_eq!(range("bytes=-10", 0).as_deref(), Some("bytes */0"));
+    }
+
+    #[test]
+    fn unsupported_ranges_send_the_whole_body() {
+        for value in [
+            "bytes=0-1,5-6",
+            "items=0-1",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes",
+        ] {
+            assert_eq!(range(value, 1000), None, "{}", value);
+        }
+        assert_eq!(resolve(&[("if-range", ETAG)], 1000), None);
+    }
+
+    #[test]
+    fn if_range_must_match_a_strong_etag() {
+        let matched = resolve(&[("range", "bytes=0-9"), ("if-range", ETAG)], 100);
+        assert_eq!(matched.as_deref(), Some("bytes 0-9/100"));
+
+        let stale = resolve(&[("range", "bytes=0-9"), ("if-range", "\"old\"")], 100);
+        assert_eq!(stale, None);
+
+        // A weak etag never matches, even when it's the same tag.
+        let request = RangeRequest {
+            range: "bytes=0-9".into(),
+            if_range: Some("W/\"abc\"".into()),
+        };
+        assert!(request.resolve(100, Some("W/\"abc\""), None).is_none());
+    }
+
+    #[test]
+    fn if_range_must_match_the_last_modified_date() {
+        let matched = resolve(&[("range", "bytes=0-9"), ("if-range", MODIFIED)], 100);
+        assert_eq!(matched.as_deref(), Some("bytes 0-9/100"));
+
+        let headers = [
+            ("range", "bytes=0-9"),
+            ("if-range", "Mon, 07 Nov 1994 08:49:37 GMT"),
+        ];
+        assert_eq!(resolve(&headers, 100), None);
+        assert_eq!(parse_date(MODIFIED), Some(MODIFIED_SECS));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::settings::IpLimit;
+
+/// A token bucket used to limit the rate of requests on a connection.
+///
+/// The bucket starts full and refills continuously at the given rate, each
+/// request takes a single token from the bucket.
+pub(crate) struct TokenBucket {
+    /// The number of tokens added to the bucket per second.
+    rate: f64,
+
+    /// The max number of tokens the bucket can hold.
+    capacity: f64,
+
+    /// The number of tokens currently in the bucket.
+    tokens: f64,
+
+    /// The last time the bucket was refilled.
+    last: Option<Duration>,
+}
+
+impl TokenBucket {
+    /// Creates a new full bucket.
+    pub(crate) fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: None,
+        }
+    }
+
+    /// Refills the bucket with the tokens accumulated since the last refill.
+    fn refill(&mut self, now: Duration) {
+        if let Some(last) = self.last {
+            let elapsed = now.saturating_sub(last).as_secs_f64();
+            self.tokens = (self.tokens + (elapsed * self.rate)).min(self.capacity);
+        }
+
+        self.last = Some(now);
+    }
+
+    /// Attempts to take a token from the bucket returning if one was taken.
+    pub(crate) fn try_acquire(&mut self, now: Duration) -> bool {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks if a token is available without taking it.
+    pub(crate) fn has_token(&mut self, now: Duration) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    /// Checks if the bucket has refilled completely.
+    fn is_full(&mut self, now: Duration) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// The connections of a single client address tracked by the `IpLimiter`.
+struct ClientState {
+    /// The new connections the address is allowed to open if limited.
+    bucket: Option<TokenBucket>,
+
+    /// The number of open connections from the address.
+    open: usize,
+}
+
+/// Limits the connections accepted from each client address, both the
+/// rate new connections are accepted at and the number open at once.
+///
+/// Addresses are those of the socket's peer, unix domain sockets have
+/// no address and aren't limited.
+pub struct IpLimiter {
+    limit: IpLimit,
+    clients: Mutex<HashMap<IpAddr, ClientState>>,
+}
+
+impl IpLimiter {
+    pub fn new(limit: IpLimit) -> Self {
+        Self {
+            limit,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The limit applied to each address.
+    pub(crate) fn limit(&self) -> &IpLimit {
+        &self.limit
+    }
+
+    /// Attempts to admit a new connection from the given address, the
+    /// returned slot counts the connection as open until it's dropped.
+    ///
+    /// Errors with the reason the connection was turned away if the
+    /// address is over either limit, the slot is `None` if the address
+    /// isn't limited.
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        now: Duration,
+    ) -> Result<Option<IpSlot>, &'static str> {
+        let ip = match client_ip(addr) {
+            Some(ip) => ip,
+            None => return Ok(None),
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(ip).or_insert_with(|| ClientState {
+            bucket: self
+                .limit
+                .connections_per_second
+                .map(|rate| TokenBucket::new(rate, self.limit.burst as f64)),
+            open: 0,
+        });
+
+        if let Some(max) = self.limit.max_connections {
+            if client.open >= max {
+                return Err("too many open connections");
+            }
+        }
+
+        if let Some(bucket) = client.bucket.as_mut() {
+            if !bucket.try_acquire(now) {
+                return Err("connection rate exceeded");
+            }
+        }
+
+        client.open += 1;
+        Ok(Some(IpSlot {
+            limiter: self.clone(),
+            ip,
+        }))
+    }
+
+    /// Counts a connection from the given address as open regardless of
+    /// the limits, e.g. one migrated from another process.
+    pub(crate) fn register(self: &Arc<Self>, addr: SocketAddr) -> Option<IpSlot> {
+        let ip = client_ip(addr)?;
+
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(ip).or_insert_with(|| ClientState {
+            bucket: None,
+            open: 0,
+        });
+        client.open += 1;
+
+        Some(IpSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// Forgets the addresses with no open connections whose rate limit
+    /// has fully recovered, these would be created afresh anyway.
+    pub(crate) fn prune(&self, now: Duration) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, client| {
+            let recovered = client.bucket.as_mut().is_none_or(|b| b.is_full(now));
+            (client.open > 0) | !recovered
+        });
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&ip) {
+            client.open = client.open.saturating_sub(1);
+        }
+    }
+}
+
+/// An open connection counted against its address by the `IpLimiter`,
+/// the connection stops counting once the slot is dropped.
+pub(crate) struct IpSlot {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+/// The address connections from the given peer are limited by, IPv4
+/// addresses mapped to IPv6 by dual stack sockets count as the IPv4
+/// address, `None` for the unspecified address of a unix domain socket.
+fn client_ip(addr: SocketAddr) -> Option<IpAddr> {
+    let ip = match addr.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr.ip()),
+        ip => ip,
+    };
+
+    if ip.is_unspecified() {
+        None
+    } else {
+        Some(ip)
+    }
+}
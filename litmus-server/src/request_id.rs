@@ -0,0 +1,108 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The alphabet of Crockford's base32 which ULIDs are encoded in.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The mask of the 80 random bits of a ULID.
+const ULID_RANDOM_MASK: u128 = (1 << 80) - 1;
+
+/// The max length of a request id passed on by a trusted proxy, longer ids
+/// are replaced with one of the server's own.
+pub(crate) const MAX_FORWARDED_ID_LEN: usize = 128;
+
+thread_local! {
+    /// The millisecond and random bits of the last ULID generated on this
+    /// thread, ids generated in the same millisecond increment the random
+    /// bits so they still sort in the order they were generated.
+    static LAST_ULID: Cell<(u64, u128)> = const { Cell::new((0, 0)) };
+}
+
+/// How the id given to each request is generated.
+#[derive(Copy, Clone, Debug)]
+pub enum RequestIdFormat {
+    /// A ULID, 26 characters sorting by the time they were generated.
+    Ulid,
+
+    /// A random version 4 UUID in its hyphenated form.
+    Uuid,
+}
+
+impl RequestIdFormat {
+    /// Generates a new id.
+    pub(crate) fn generate(self) -> String {
+        match self {
+            Self::Ulid => generate_ulid(),
+            Self::Uuid => generate_uuid(),
+        }
+    }
+}
+
+/// If an id sent with a request can be passed on as it is, it must be
+/// short and printable ASCII as it's echoed in the response and logged.
+pub(crate) fn is_valid_forwarded_id(id: &[u8]) -> bool {
+    !id.is_empty()
+        && (id.len() <= MAX_FORWARDED_ID_LEN)
+        && id.iter().all(|b| b.is_ascii_graphic())
+}
+
+fn generate_ulid() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+
+    let random = LAST_ULID.with(|last| {
+        let (last_millis, last_random) = last.get();
+        let random = if (millis == last_millis) & (last_random < ULID_RANDOM_MASK) {
+            last_random + 1
+        } else {
+            u128::from_be_bytes(random_bytes()) & ULID_RANDOM_MASK
+        };
+
+        last.set((millis, random));
+        random
+    });
+
+    // 48 bits of the time followed by 80 random bits, 5 bits per character.
+    let value = ((millis as u128 & ((1 << 48) - 1)) << 80) | random;
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+fn generate_uuid() -> String {
+    let mut bytes = random_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40; // Version 4
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+
+    let mut out = String::with_capacity(36);
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            out.push('-');
+        }
+        let _ = write!(out, "{:02x}", byte);
+    }
+
+    out
+}
+
+/// Fills 16 bytes from the OS's random source.
+///
+/// This only fails if the OS has no source of randomness at all, the time
+/// is used instead as an id only needs to be unique enough to correlate
+/// a request's logs.
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        bytes = nanos.to_be_bytes();
+    }
+
+    bytes
+}
@@ -2,21 +2,66 @@ use std::sync::Arc;
 
 use crossbeam::queue::SegQueue;
 use pyo3::types::PyBytes;
-use pyo3::{Py, PyObject};
+use pyo3::{Py, PyObject, Python};
+
+use crate::net::FileBody;
 
 mod receiver;
 mod sender;
+mod websocket;
+mod writer;
 
 pub use receiver::{DataReceiver, ReceiverFactory};
+pub(crate) use sender::{has_token, EventStream, ExpectContinue, RequestConnection};
 pub use sender::{DataSender, SenderFactory};
+pub use websocket::WebSocket;
+pub(crate) use websocket::{
+    Outgoing, WebSocketAcceptor, WebSocketFactory, CLOSE_ABNORMAL,
+};
+pub use writer::ResponseWriter;
 
 /// The payload that gets sent to the receiver half of the channel.
 ///
-/// Types equate to: more_body, keep_alive, body.
-pub type SenderPayload = (bool, bool, Vec<u8>);
+/// Types equate to: more_body, keep_alive, body, on_written.
+pub type SenderPayload = (bool, bool, Body, Option<PyObject>);
+
+/// The body of a payload sent by the application.
+pub enum Body {
+    Bytes(Vec<u8>),
+
+    /// An informational response written ahead of the final response,
+    /// e.g. `103 Early Hints`.
+    Interim(Vec<u8>),
+
+    /// A region of a file written directly to the socket, with any framing
+    /// to write before and after it.
+    File {
+        file: FileBody,
+        prefix: Vec<u8>,
+        suffix: Vec<u8>,
+    },
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
 
 /// The payload that gets sent to the receiver half of the channel.
 pub type ReceiverPayload = (bool, Py<PyBytes>);
 
+/// The `(name, value)` trailer headers of a request body.
+pub type Trailers = Vec<(String, Vec<u8>)>;
+
 /// The queue of Python waiters to be woken up on a given event.
 pub(crate) type WakerQueue = Arc<SegQueue<PyObject>>;
+
+/// Invokes a callback given by the application whose result doesn't
+/// matter to the server, e.g. a waker, anything it raises is logged
+/// rather than passed on to whatever happened to trigger it.
+pub(crate) fn invoke_callback(py: Python, callback: &PyObject, kind: &str) {
+    if let Err(e) = callback.call0(py) {
+        error!("{} callback raised an exception: {}", kind, e);
+    }
+}
@@ -1,4 +1,5 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bytes::BytesMut;
 use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError, TrySendError};
@@ -7,7 +8,13 @@ use pyo3::exceptions::{PyBlockingIOError, PyRuntimeError};
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 
-use super::{ReceiverPayload, WakerQueue};
+use super::{ExpectContinue, ReceiverPayload, Trailers, WakerQueue};
+use crate::traits::BaseTransport;
+use crate::transport::Transport;
+
+/// The max amount of body chunks waiting to be received by the application
+/// before the protocol stops reading from the socket.
+const MAX_PENDING_CHUNKS: usize = 2;
 
 /// The callable class that handling communication back to the server protocol.
 #[pyclass]
@@ -18,12 +25,43 @@ pub struct DataReceiver {
     /// A queue of waiting events to invoke before the body
     /// can be read from the receiver again.
     waiter_queue: WakerQueue,
+
+    /// The transport used to wake the protocol once a chunk is received.
+    transport: Transport,
+
+    /// If the protocol has stopped reading until a chunk is received.
+    paused: Arc<AtomicBool>,
+
+    /// Set if the request is waiting on a `100 Continue` before sending
+    /// its body, this is requested when the body is first received.
+    expect_continue: Option<ExpectContinue>,
+
+    /// The trailer headers sent after the last chunk of the body.
+    trailers: Arc<Mutex<Trailers>>,
 }
 
 impl DataReceiver {
     /// Create a new handler with the given sender.
-    pub fn new(rx: Receiver<ReceiverPayload>, waiter_queue: WakerQueue) -> Self {
-        Self { rx, waiter_queue }
+    pub fn new(
+        rx: Receiver<ReceiverPayload>,
+        waiter_queue: WakerQueue,
+        transport: Transport,
+        paused: Arc<AtomicBool>,
+        trailers: Arc<Mutex<Trailers>>,
+    ) -> Self {
+        Self {
+            rx,
+            waiter_queue,
+            transport,
+            paused,
+            expect_continue: None,
+            trailers,
+        }
+    }
+
+    /// Marks the request as waiting on a `100 Continue`.
+    pub(crate) fn set_expect_continue(&mut self, expect_continue: ExpectContinue) {
+        self.expect_continue = Some(expect_continue);
     }
 }
 
@@ -34,6 +72,12 @@ impl DataReceiver {
     /// Invoked by python passing more_body which represents if there
     /// is any more body to expect or not, and the body itself.
     ///
+    /// The body is streamed, the server stops reading from the socket while
+    /// chunks are waiting to be received and resumes once they have been.
+    ///
+    /// If the client is waiting on a `100 Continue` before sending the body
+    /// it is sent on the first call.
+    ///
     /// Returns:
     ///     A tuple containing a boolean and a set of bytes, the boolean signals
     ///     if there is more data to be read from the socket or not and the
@@ -43,7 +87,6 @@ impl DataReceiver {
     ///     RuntimeError:
     ///         If the channel the receiver uses to communicate with the main
     ///         socket handler is closed.
-    ///
     ///     BlockingIOError:
     ///         The receiver is empty and would block waiting for data to be
     ///         sent to the receiver. In the event that this error is raised
@@ -51,10 +94,20 @@ impl DataReceiver {
     ///         data is available.
     #[call]
     fn __call__(&self) -> PyResult<(bool, Py<PyBytes>)> {
+        if let Some(c) = self.expect_continue.as_ref() {
+            c.request()?;
+        }
+
         let resp = self.rx.try_recv();
 
         return match resp {
-            Ok(values) => Ok(values),
+            Ok(values) => {
+                if self.paused.swap(false, Ordering::Relaxed) {
+                    self.transport.resume_writing()?;
+                }
+
+                Ok(values)
+            },
             Err(TryRecvError::Disconnected) => Err(PyRuntimeError::new_err(
                 "receiving channel was unexpectedly closed.",
             )),
@@ -80,6 +133,24 @@ impl DataReceiver {
     fn subscribe(&self, waker: PyObject) {
         self.waiter_queue.push(waker);
     }
+
+    /// Gets the trailer headers sent after the last chunk of a chunked
+    /// request body.
+    ///
+    /// Trailers are only available once the last chunk of the body has
+    /// been received, until then or if the client sent none this is empty.
+    ///
+    /// Returns:
+    ///     A list of `(name, value)` tuples in the same form as the
+    ///     request's headers.
+    fn trailers(&self, py: Python) -> Vec<(String, Py<PyBytes>)> {
+        self.trailers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| (name.clone(), Py::from(PyBytes::new(py, value))))
+            .collect()
+    }
 }
 
 /// A factory / manager for receiver handles sending data from the server
@@ -94,24 +165,57 @@ pub struct ReceiverFactory {
     /// A queue of waiting events to invoke before the body
     /// can be read from the receiver again.
     waiter_queue: WakerQueue,
+
+    /// If the protocol has stopped reading until a chunk is received.
+    paused: Arc<AtomicBool>,
+
+    /// The trailer headers sent after the last chunk of the body.
+    trailers: Arc<Mutex<Trailers>>,
 }
 
 impl ReceiverFactory {
     /// Constructs a new factory.
     pub fn new() -> Self {
-        let (tx, rx) = bounded(2);
+        let (tx, rx) = bounded(MAX_PENDING_CHUNKS);
         let queue = Arc::new(SegQueue::new());
 
         Self {
             receiver_tx: tx,
             receiver_rx: rx,
             waiter_queue: queue,
+            paused: Arc::new(AtomicBool::new(false)),
+            trailers: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
-    /// Makes a new sending handle with the given factory channels and queue.
-    pub fn make_handle(&self) -> DataReceiver {
-        DataReceiver::new(self.receiver_rx.clone(), self.waiter_queue.clone())
+    /// Makes a new receiving handle with the given factory channels and
+    /// queue, the transport is woken once a paused body can be read again.
+    pub fn make_handle(&self, transport: Transport) -> DataReceiver {
+        DataReceiver::new(
+            self.receiver_rx.clone(),
+            self.waiter_queue.clone(),
+            transport,
+            self.paused.clone(),
+            self.trailers.clone(),
+        )
+    }
+
+    /// If the application has yet to receive the max amount of pending
+    /// chunks, no more should be sent until it has.
+    pub fn is_full(&self) -> bool {
+        self.receiver_tx.is_full()
+    }
+
+    /// Sets the trailer headers received after the last chunk of the body,
+    /// these must be set before the last chunk is sent.
+    pub fn set_trailers(&self, trailers: Trailers) {
+        *self.trailers.lock().unwrap() = trailers;
+    }
+
+    /// Marks the protocol as paused, the transport's writer is woken once
+    /// the application receives a chunk.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
     }
 
     /// Sends the given payload to the handler channel.
@@ -1,15 +1,54 @@
+use std::borrow::Cow;
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError, TrySendError};
 use crossbeam::queue::SegQueue;
-use pyo3::exceptions::PyBlockingIOError;
+use pyo3::exceptions::{
+    PyBlockingIOError, PyConnectionResetError, PyRuntimeError, PyValueError,
+};
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
-use super::{SenderPayload, WakerQueue};
+use super::{
+    invoke_callback, Body, SenderPayload, WakerQueue, WebSocket, WebSocketAcceptor,
+};
+use crate::compression::{self, Encoder};
+use crate::date;
+use crate::net::FileBody;
+use crate::range::{self, ByteRange, RangeRequest};
+use crate::server::CallbackHandler;
+use crate::settings::{Encoding, ServerSettings, Settings};
+use crate::traits::BaseTransport;
+use crate::transport::Transport;
 
 const HEADER_SEPARATOR: &[u8] = ": ".as_bytes();
 const LINE_SEPARATOR: &[u8] = "\r\n".as_bytes();
-const SERVER_HEADER: &[u8] = "server: Pyre".as_bytes();
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const CHUNKED_HEADER: &[u8] = "transfer-encoding: chunked".as_bytes();
+const TRAILER_HEADER: &[u8] = "trailer".as_bytes();
+const CONNECTION_CLOSE_HEADER: &[u8] = "connection: close".as_bytes();
+const CONNECTION_KEEP_ALIVE_HEADER: &[u8] = "connection: keep-alive".as_bytes();
+const CONNECTION_UPGRADE_HEADER: &[u8] = "connection: Upgrade".as_bytes();
+const WEBSOCKET_STATUS_LINE: &[u8] = "HTTP/1.1 101 Switching Protocols".as_bytes();
+const WEBSOCKET_UPGRADE_HEADER: &[u8] = "upgrade: websocket".as_bytes();
+const VARY_HEADER: &[u8] = "vary: accept-encoding".as_bytes();
+const LAST_CHUNK: &[u8] = "0\r\n".as_bytes();
+const CONTINUE_RESPONSE: &[u8] = "HTTP/1.1 100 Continue\r\n\r\n".as_bytes();
+const CONTINUE_WAITING: u8 = 0;
+const CONTINUE_REQUESTED: u8 = 1;
+const CONTINUE_SETTLED: u8 = 2;
+const CONTENT_TYPE_HEADER: &[u8] = "content-type".as_bytes();
+const CACHE_CONTROL_HEADER: &[u8] = "cache-control".as_bytes();
+const EVENT_STREAM_CONTENT_TYPE: &[u8] = "text/event-stream".as_bytes();
+const STREAM_PENDING: u8 = 0;
+const STREAM_OPEN: u8 = 1;
+const STREAM_CLOSED: u8 = 2;
 
 /// The callable class that handling communication back to the server protocol.
 #[pyclass]
@@ -22,16 +61,87 @@ pub struct DataSender {
     /// can be written to again.
     waiter_queue: WakerQueue,
 
-    /// If the response is using chunked encoding or not or not set.
+    /// If the response is using chunked encoding or not or not set,
+    /// `Some(false)` if the body is instead ended by closing the
+    /// connection as HTTP/1.0 clients don't understand chunked encoding.
     chunked_encoding: Option<bool>,
 
     /// If the client has defined a given content length of the body.
     expected_content_length: usize,
+
+    /// If the start of the response has been sent.
+    started: bool,
+
+    /// If the response has advertised trailers which are yet to be sent,
+    /// this is only ever set for chunked responses.
+    expects_trailers: bool,
+
+    /// If the response has been aborted due to an application error,
+    /// anything sent after this point is ignored.
+    errored: bool,
+
+    /// The callback handler used to report application errors.
+    callback: CallbackHandler,
+
+    /// The coding negotiated with the client to compress the response
+    /// with if it's eligible.
+    encoding: Option<Encoding>,
+
+    /// The encoder compressing the response body if it's being compressed.
+    encoder: Option<Encoder>,
+
+    /// The server settings.
+    settings: Settings,
+
+    /// Set if the request asked to upgrade to a websocket.
+    websocket: Option<WebSocketAcceptor>,
+
+    /// Set if the request is waiting on a `100 Continue` before sending
+    /// its body.
+    expect_continue: Option<ExpectContinue>,
+
+    /// Set if the response can be started as an event stream.
+    event_stream: Option<EventStream>,
+
+    /// Set if the request asked for a range of the response body.
+    range: Option<RangeRequest>,
+
+    /// How the request asked for the connection to be handled.
+    connection: RequestConnection,
+
+    /// The id of the request sent back with the response if any.
+    request_id: Option<Arc<str>>,
+
+    /// The transport used to wake the writer once anything is queued.
+    transport: Transport,
+
+    /// Set once the connection has been lost or the response aborted.
+    closed: Arc<AtomicBool>,
+
+    /// The callbacks invoked once the connection is lost or the response
+    /// aborted.
+    disconnect_waiters: WakerQueue,
+
+    /// The last time the application made progress on the response, see
+    /// `SenderFactory::set_activity()`.
+    activity: Arc<AtomicU64>,
 }
 
 impl DataSender {
     /// Create a new handler with the given sender.
-    pub fn new(tx: Sender<SenderPayload>, waiter_queue: WakerQueue) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        tx: Sender<SenderPayload>,
+        waiter_queue: WakerQueue,
+        callback: CallbackHandler,
+        settings: Settings,
+        transport: Transport,
+        connection: RequestConnection,
+        request_id: Option<Arc<str>>,
+        closed: Arc<AtomicBool>,
+        disconnect_waiters: WakerQueue,
+        activity: Arc<AtomicU64>,
+    ) -> Self {
         let chunked_encoding = None; // We expect nothing yet.
         let expected_content_length: usize = 0; // We expect nothing yet.
 
@@ -40,14 +150,193 @@ impl DataSender {
             waiter_queue,
             chunked_encoding,
             expected_content_length,
+            started: false,
+            expects_trailers: false,
+            errored: false,
+            callback,
+            encoding: None,
+            encoder: None,
+            settings,
+            websocket: None,
+            expect_continue: None,
+            event_stream: None,
+            range: None,
+            connection,
+            request_id,
+            transport,
+            closed,
+            disconnect_waiters,
+            activity,
         }
     }
+
+    /// Queues the payload to be written waking the writer.
+    ///
+    /// This raises a `BlockingIoError` if the queue is full, if the
+    /// connection has been dropped the payload is ignored.
+    fn queue(&self, payload: SenderPayload) -> PyResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+
+        match self.tx.try_send(payload) {
+            Ok(()) => self.transport.resume_writing(),
+            Err(TrySendError::Full(_)) => Err(PyBlockingIOError::new_err(())),
+            Err(TrySendError::Disconnected(_)) => Ok(()),
+        }
+    }
+
+    /// Sets the coding negotiated with the client, the response is only
+    /// compressed if it's eligible once started.
+    pub(crate) fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = Some(encoding);
+    }
+
+    /// Decides if the response is compressed given its status and headers.
+    ///
+    /// Returns if the response is eligible for compression at all, in which
+    /// case it varies on `Accept-Encoding`, along with the coding to compress
+    /// it with if the client accepts one.
+    fn select_encoding(
+        &self,
+        status: http::StatusCode,
+        resp_headers: &[(&[u8], &[u8])],
+    ) -> (bool, Option<Encoding>) {
+        let compression = match self.settings.compression.as_ref() {
+            Some(compression) => compression,
+            None => return (false, None),
+        };
+
+        // Compressing partial content would break the requested ranges.
+        if status.is_informational()
+            | (status == http::StatusCode::NO_CONTENT)
+            | (status == http::StatusCode::NOT_MODIFIED)
+            | (status == http::StatusCode::PARTIAL_CONTENT)
+        {
+            return (false, None);
+        }
+
+        let mut compressible = false;
+        for (name, value) in resp_headers {
+            if name.eq_ignore_ascii_case(b"content-encoding") {
+                return (false, None);
+            } else if name.eq_ignore_ascii_case(b"content-type") {
+                compressible = compression::is_compressible(value);
+            } else if name.eq_ignore_ascii_case(b"content-length") {
+                let len = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                if len.map(|len| len < compression.min_size).unwrap_or(false) {
+                    return (false, None);
+                }
+            }
+        }
+
+        (compressible, self.encoding.filter(|_| compressible))
+    }
+
+    /// Allows the request to be upgraded to a websocket.
+    pub(crate) fn set_websocket(&mut self, acceptor: WebSocketAcceptor) {
+        self.websocket = Some(acceptor);
+    }
+
+    /// Marks the request as waiting on a `100 Continue`, if the response
+    /// is started before the body is received the connection is closed
+    /// once it completes as the body is never read.
+    pub(crate) fn set_expect_continue(&mut self, expect_continue: ExpectContinue) {
+        self.expect_continue = Some(expect_continue);
+    }
+
+    /// Allows the response to be started as an event stream.
+    pub(crate) fn set_event_stream(&mut self, event_stream: EventStream) {
+        self.event_stream = Some(event_stream);
+    }
+
+    /// Sets the range of the body the request asked for, used by ranged
+    /// responses.
+    pub(crate) fn set_range(&mut self, range: RangeRequest) {
+        self.range = Some(range);
+    }
+
+    /// The amount of the response which can be waiting to be written to
+    /// the socket before no more is taken from the queue.
+    pub(crate) fn write_high_water(&self) -> usize {
+        self.settings.write_high_water
+    }
+
+    /// If the connection has been lost or the response aborted, anything
+    /// sent is then ignored.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// If the connection must be closed after the response, as the client
+    /// asked for it, the server is draining or the client is still holding
+    /// back the request body.
+    fn must_close(&self) -> bool {
+        let body_withheld = self
+            .expect_continue
+            .as_ref()
+            .map(|c| c.is_pending())
+            .unwrap_or(false);
+
+        !self.connection.keep_alive | self.settings.is_draining() | body_withheld
+    }
+
+    /// Queues a region of a file as a chunk of the main body, see
+    /// `send_file`.
+    fn queue_file(
+        &mut self,
+        py: Python,
+        file: FileBody,
+        more_body: bool,
+        on_written: Option<PyObject>,
+    ) -> PyResult<()> {
+        let len = file.remaining();
+
+        let has_body =
+            self.chunked_encoding.is_some() | (self.expected_content_length > 0);
+        if (len == 0) | !has_body {
+            return self.send_body(py, more_body, Vec::new(), on_written);
+        }
+
+        // A compressed file has to pass through the encoder.
+        if self.encoder.is_some() {
+            let body = file.read_to_end()?;
+            return self.send_body(py, more_body, body, on_written);
+        }
+
+        let (more_body, prefix, suffix) = match self.chunked_encoding {
+            Some(true) => {
+                let mut suffix = LINE_SEPARATOR.to_vec();
+                suffix.extend(frame_chunk(
+                    more_body,
+                    !self.expects_trailers,
+                    Vec::new(),
+                ));
+
+                let prefix = format!("{:X}\r\n", len).into_bytes();
+                (more_body | self.expects_trailers, prefix, suffix)
+            },
+            _ => (more_body, Vec::new(), Vec::new()),
+        };
+
+        let body = Body::File {
+            file,
+            prefix,
+            suffix,
+        };
+        self.queue((more_body, true, body, on_written))
+    }
 }
 
 #[pymethods]
 impl DataSender {
     /// Sends the a chunk of the main body to the handler.
     ///
+    /// If the response is using chunked encoding the chunk is framed
+    /// accordingly before being sent, otherwise it is sent as is.
+    ///
     /// This raises a `BlockingIoError` if the queue / buffer is full, the
     /// invoker should wait till the queue / buffer is no longer full.
     ///
@@ -56,25 +345,228 @@ impl DataSender {
     ///         A boolean to determine if the server should expect any more
     ///         chunks of body being sent or if the request is regarded as
     ///         being 'complete'.
-    ///
     ///     body:
     ///         A chunk of bytes to be written to the socket.
-    fn send_body(&self, more_body: bool, body: Vec<u8>) -> PyResult<()> {
-        if self.expected_content_length == 0 {
+    ///     on_written:
+    ///         An optional callback invoked with no arguments once the chunk
+    ///         has been fully written to the socket rather than just buffered.
+    #[args(on_written = "None")]
+    fn send_body(
+        &mut self,
+        py: Python,
+        more_body: bool,
+        body: Vec<u8>,
+        on_written: Option<PyObject>,
+    ) -> PyResult<()> {
+        if self.errored {
             return Ok(());
         }
 
-        let payload = (more_body, true, body);
-        if let Err(e) = self.tx.try_send(payload) {
-            if let TrySendError::Full(_) = e {
-                return Err(PyBlockingIOError::new_err(()));
+        // Nothing is fed to the encoder unless it can be queued, otherwise
+        // the chunk would be compressed twice once it's sent again.
+        if self.encoder.is_some() & self.tx.is_full() {
+            return Err(PyBlockingIOError::new_err(()));
+        }
+
+        let body = match self.encoder.take() {
+            Some(mut encoder) if more_body => {
+                let out = encoder.compress(&body)?;
+                self.encoder = Some(encoder);
+                out
+            },
+            Some(encoder) => encoder.finish(&body)?,
+            None => body,
+        };
+
+        let (more_body, body) = match self.chunked_encoding {
+            // The response is only complete once the trailers are sent.
+            Some(true) if self.expects_trailers => {
+                (true, frame_chunk(more_body, false, body))
+            },
+            Some(true) => (more_body, frame_chunk(more_body, true, body)),
+            Some(false) => (more_body, body),
+            // The response has no body so only its completion matters.
+            _ if self.expected_content_length == 0 => (more_body, Vec::new()),
+            _ => (more_body, body),
+        };
+
+        // An empty chunk can only be sent to terminate the body, in which
+        // case there is nothing to wait on being written.
+        let on_written = if body.is_empty() {
+            if let Some(cb) = on_written {
+                invoke_callback(py, &cb, "write");
             }
 
-            // The connection has been dropped, ignore.
+            // The protocol still needs to know the response is complete.
+            if more_body {
+                return Ok(());
+            }
+
+            None
+        } else {
+            on_written
+        };
+
+        self.queue((more_body, true, body.into(), on_written))
+    }
+
+    /// Sends a region of a file as a chunk of the main body to the handler.
+    ///
+    /// The file is written to the socket by the server itself so it never
+    /// passes through Python, using `sendfile(2)` where supported and
+    /// otherwise reading and writing it in chunks. The response must have
+    /// been started first.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     file:
+    ///         Either the path of the file or an open file descriptor, the
+    ///         descriptor is duplicated so the caller keeps ownership of it.
+    ///     offset:
+    ///         The offset in the file to start sending from.
+    ///     count:
+    ///         The max amount of bytes to send, defaults to the rest of the
+    ///         file.
+    ///     more_body:
+    ///         A boolean to determine if the server should expect any more
+    ///         chunks of body being sent after the file.
+    ///     on_written:
+    ///         An optional callback invoked with no arguments once the file
+    ///         has been fully written to the socket.
+    #[args(offset = "0", count = "None", more_body = "false", on_written = "None")]
+    fn send_file(
+        &mut self,
+        py: Python,
+        file: &PyAny,
+        offset: u64,
+        count: Option<u64>,
+        more_body: bool,
+        on_written: Option<PyObject>,
+    ) -> PyResult<()> {
+        if self.errored {
             return Ok(());
         }
 
-        Ok(())
+        if !self.started {
+            return Err(PyRuntimeError::new_err(
+                "response must be started before sending a file",
+            ));
+        }
+
+        let file = FileBody::new(open_file(file)?, offset, count)?;
+        self.queue_file(py, file, more_body, on_written)
+    }
+
+    /// Sends a complete response whose body is a file or bytes, answering
+    /// a `Range` request for a single range of it with only those bytes.
+    ///
+    /// The response is a `200 OK` with the whole body, a `206 Partial
+    /// Content` with the requested range or a `416 Range Not Satisfiable`
+    /// if the range is outside the body. The `content-length`,
+    /// `accept-ranges` and `content-range` headers are set by the server
+    /// and the response is never compressed. An `If-Range` is checked
+    /// against the `etag` or `last-modified` among the given headers, the
+    /// whole body is sent if it doesn't match.
+    ///
+    /// This raises a `BlockingIoError` without sending anything if the
+    /// queue / buffer doesn't have room for the response, the invoker
+    /// should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     body:
+    ///         The body as bytes, or a file given by its path or an open
+    ///         file descriptor which is duplicated so the caller keeps
+    ///         ownership of it.
+    ///     resp_headers:
+    ///         Any headers to send along with the range's own.
+    ///     on_written:
+    ///         An optional callback invoked with no arguments once the body
+    ///         has been fully written to the socket.
+    #[args(resp_headers = "None", on_written = "None")]
+    fn send_ranged(
+        &mut self,
+        py: Python,
+        body: &PyAny,
+        resp_headers: Option<Vec<(&[u8], &[u8])>>,
+        on_written: Option<PyObject>,
+    ) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
+        if self.started {
+            return Err(PyRuntimeError::new_err("response has already been started"));
+        }
+
+        // Both the head and the body have to fit or nothing is sent.
+        let capacity = self.tx.capacity().unwrap_or(usize::MAX);
+        if self.tx.len() + capacity.min(2) > capacity {
+            return Err(PyBlockingIOError::new_err(()));
+        }
+
+        let (bytes, file) = match body.downcast::<PyBytes>() {
+            Ok(bytes) => (Some(bytes.as_bytes()), None),
+            Err(_) => (None, Some(open_file(body)?)),
+        };
+
+        let len = match (bytes, file.as_ref()) {
+            (Some(bytes), _) => bytes.len() as u64,
+            (_, Some(file)) => file.metadata()?.len(),
+            _ => 0,
+        };
+
+        let resp_headers = resp_headers.unwrap_or_default();
+        let find = |name: &[u8]| {
+            resp_headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| std::str::from_utf8(value).ok())
+        };
+
+        let etag = find(b"etag");
+        let last_modified = find(b"last-modified").and_then(range::parse_date);
+        let range = self
+            .range
+            .as_ref()
+            .and_then(|range| range.resolve(len, etag, last_modified));
+
+        let (status, offset, count) = match range {
+            Some(ByteRange::Satisfiable(start, end)) => (206, start, end - start + 1),
+            Some(ByteRange::Unsatisfiable) => (416, 0, 0),
+            None => (200, 0, len),
+        };
+
+        let content_length = count.to_string();
+        let content_range = range.map(|range| range.content_range(len));
+        let mut headers: Vec<(&[u8], &[u8])> = resp_headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case(b"content-length"))
+            .copied()
+            .collect();
+        headers.push((b"content-length", content_length.as_bytes()));
+        headers.push((b"accept-ranges", b"bytes"));
+        if let Some(content_range) = content_range.as_ref() {
+            headers.push((b"content-range", content_range.as_bytes()));
+        }
+
+        // The ranges refer to the body as is.
+        self.encoding = None;
+        self.send_start(status, headers, None)?;
+
+        match (bytes, file) {
+            (Some(bytes), _) => {
+                let start = offset as usize;
+                let body = bytes[start..start + count as usize].to_vec();
+                self.send_body(py, false, body, on_written)
+            },
+            (_, Some(file)) => {
+                let file = FileBody::new(file, offset, Some(count))?;
+                self.queue_file(py, file, false, on_written)
+            },
+            _ => Ok(()),
+        }
     }
 
     /// Sends the start of the response body to the handler.
@@ -89,16 +581,28 @@ impl DataSender {
     ///         A boolean to determine if the server should expect any more
     ///         chunks of body being sent or if the request is regarded as
     ///         being 'complete'.
-    ///
     ///     body:
     ///         A chunk of bytes to be written to the socket.
+    ///     trailers:
+    ///         An optional list of trailer header names to advertise, these
+    ///         are only allowed on chunked responses to clients sending
+    ///         `TE: trailers` and are ignored otherwise.
+    #[args(trailers = "None")]
     fn send_start(
         &mut self,
         status_code: u16,
         resp_headers: Vec<(&[u8], &[u8])>,
+        trailers: Option<Vec<&[u8]>>,
     ) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
         let mut keep_alive = true;
-        let mut out = Vec::with_capacity(resp_headers.len() + 4);
+        let mut has_connection = false;
+        let mut has_content_length = false;
+        let mut out: Vec<Cow<'static, [u8]>> =
+            Vec::with_capacity(resp_headers.len() + 4);
 
         let status = match http::StatusCode::from_u16(status_code) {
             Ok(s) => s,
@@ -109,23 +613,36 @@ impl DataSender {
             status.as_str(),
             status.canonical_reason().unwrap_or_else(|| ""),
         )
-        .as_bytes()
-        .to_vec();
-        out.push(status_block);
+        .into_bytes();
+        out.push(status_block.into());
 
-        for (name, value) in resp_headers {
+        let (vary, encoding) = self.select_encoding(status, &resp_headers);
+        let mut has_vary = false;
+
+        for &(name, value) in resp_headers.iter() {
             let name = match headers::HeaderName::from_bytes(name) {
                 Ok(s) => s,
                 Err(_) => panic!("invalid header name given"),
             };
 
-            let value = match headers::HeaderValue::from_bytes(value) {
+            let mut value = match headers::HeaderValue::from_bytes(value) {
                 Ok(s) => s,
                 Err(_) => panic!("invalid status code given"),
             };
 
+            // The compressed length isn't known up front so it's chunked.
+            if encoding.is_some() & (name == http::header::CONTENT_LENGTH) {
+                continue;
+            }
+
+            if vary & (name == http::header::VARY) {
+                has_vary = true;
+                value = merge_vary(value);
+            }
+
             match &name {
                 &http::header::CONTENT_LENGTH => {
+                    has_content_length = true;
                     self.expected_content_length = value
                         .to_str()
                         .expect("content length header is not ASCII encodable")
@@ -152,17 +669,9 @@ impl DataSender {
                     };
                 },
                 &http::header::CONNECTION => {
-                    let temp_val = value.as_ref();
-                    if temp_val.len() == 5 {
-                        if (temp_val[0] == 99) &   // c
-                        (temp_val[1] == 108) &  // l
-                        (temp_val[2] == 111) &  // o
-                        (temp_val[3] == 115) &  // s
-                        (temp_val[4] == 101)
-                        // e
-                        {
-                            keep_alive = false;
-                        }
+                    has_connection = true;
+                    if has_token(value.as_bytes(), b"close") {
+                        keep_alive = false;
                     }
                 },
                 _ => {},
@@ -170,26 +679,464 @@ impl DataSender {
 
             let res = [name.as_ref(), value.as_bytes()].join(HEADER_SEPARATOR);
 
-            out.push(res);
+            out.push(res.into());
         }
 
-        let formatted_date_header = format!(
-            "date: {}",
-            httpdate::fmt_http_date(std::time::SystemTime::now()),
-        )
-        .as_bytes()
-        .to_vec();
-        out.push(formatted_date_header); // Date
-        out.push(SERVER_HEADER.to_vec()); // Server
-        out.push(LINE_SEPARATOR.to_vec()); // End of Headers
+        if vary & !has_vary {
+            out.push(VARY_HEADER.into());
+        }
+
+        self.encoder = match (encoding, self.settings.compression.as_ref()) {
+            (Some(encoding), Some(compression)) => {
+                let header = format!("content-encoding: {}", encoding.as_str());
+                out.push(header.into_bytes().into());
+                Some(Encoder::new(encoding, compression.level))
+            },
+            _ => None,
+        };
+
+        // Without a known length the body is streamed using chunked encoding,
+        // responses that cannot contain a body are left as they are.
+        let can_have_body = !status.is_informational()
+            && (status != http::StatusCode::NO_CONTENT)
+            && (status != http::StatusCode::NOT_MODIFIED);
+        // HTTP/1.0 clients don't understand chunked encoding so the end of
+        // the body is signalled by closing the connection instead.
+        let unknown_length =
+            self.chunked_encoding.is_none() & !has_content_length & can_have_body;
+        let close_delimited = unknown_length & self.connection.http_10;
+
+        let must_close = self.must_close() | close_delimited;
+        if keep_alive & must_close {
+            keep_alive = false;
+            out.push(CONNECTION_CLOSE_HEADER.into());
+        } else if keep_alive & self.connection.http_10 & !has_connection {
+            // HTTP/1.0 clients assume the connection closes unless told.
+            out.push(CONNECTION_KEEP_ALIVE_HEADER.into());
+        }
+
+        if close_delimited {
+            self.chunked_encoding = Some(false);
+        } else if unknown_length {
+            self.chunked_encoding = Some(true);
+            out.push(CHUNKED_HEADER.into());
+        }
+
+        if let Some(names) = trailers.filter(|names| !names.is_empty()) {
+            if !self.connection.accepts_trailers {
+                debug!("ignoring trailers the client has not accepted with TE");
+            } else if self.chunked_encoding == Some(true) {
+                for name in names.iter() {
+                    validate_header_name(name)?;
+                }
+
+                let value = names.join(", ".as_bytes());
+                let header = [TRAILER_HEADER, value.as_ref()].join(HEADER_SEPARATOR);
+                out.push(header.into());
+                self.expects_trailers = true;
+            } else {
+                debug!("ignoring trailers on a response that is not chunked");
+            }
+        }
+
+        let has_header = |name: &str| {
+            resp_headers
+                .iter()
+                .any(|(given, _)| given.eq_ignore_ascii_case(name.as_bytes()))
+        };
+        push_server_headers(
+            &mut out,
+            &self.settings,
+            self.request_id.as_deref(),
+            has_header,
+        );
+        if let Some(alt_svc) = self.settings.alt_svc.as_ref() {
+            out.push(format!("alt-svc: {}", alt_svc).into_bytes().into());
+        }
 
         // Joins all separate lines into a single block with \r\n joining them.
-        let start_block = out.join(LINE_SEPARATOR);
+        let mut start_block = out.join(LINE_SEPARATOR);
+        start_block.extend_from_slice(LINE_SEPARATOR);
+        date::extend_date_header(&mut start_block);
+        start_block.extend_from_slice(LINE_SEPARATOR); // End of Headers
 
-        return match self.tx.try_send((true, keep_alive, start_block)) {
-            Err(TrySendError::Full(_)) => Err(PyBlockingIOError::new_err(())),
-            _ => Ok(()),
+        self.queue((true, keep_alive, start_block.into(), None))?;
+        self.started = true;
+
+        // The final response has been started so the client is rejected.
+        if let Some(c) = self.expect_continue.take() {
+            c.cancel();
+        }
+
+        Ok(())
+    }
+
+    /// Starts a `text/event-stream` response, events are then sent with
+    /// `send_event` and the stream is ended by sending the last chunk of the
+    /// body with `send_body`.
+    ///
+    /// The response is never compressed so each event reaches the client as
+    /// soon as it's sent. While the stream is open the server keeps it alive
+    /// by sending a comment whenever it has been quiet for the heartbeat
+    /// interval, and it isn't closed for being idle.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     status_code:
+    ///         The status code of the response.
+    ///     resp_headers:
+    ///         Any headers to send along with the stream's own, the content
+    ///         type and framing of the body are set by the server. A
+    ///         `cache-control: no-cache` header is added unless given.
+    #[args(status_code = "200", resp_headers = "None")]
+    fn start_event_stream(
+        &mut self,
+        status_code: u16,
+        resp_headers: Option<Vec<(&[u8], &[u8])>>,
+    ) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
+        let stream = match self.event_stream.clone() {
+            Some(stream) => stream,
+            None => {
+                return Err(PyRuntimeError::new_err(
+                    "event streams are not supported on this connection",
+                ))
+            },
+        };
+
+        if stream.is_closed() {
+            return Err(PyConnectionResetError::new_err(
+                "the client has disconnected",
+            ));
+        }
+
+        let mut headers = vec![(CONTENT_TYPE_HEADER, EVENT_STREAM_CONTENT_TYPE)];
+        let mut has_cache_control = false;
+        for (name, value) in resp_headers.unwrap_or_default() {
+            if name.eq_ignore_ascii_case(CONTENT_TYPE_HEADER)
+                | name.eq_ignore_ascii_case(b"content-length")
+                | name.eq_ignore_ascii_case(b"transfer-encoding")
+            {
+                continue;
+            }
+
+            has_cache_control |= name.eq_ignore_ascii_case(CACHE_CONTROL_HEADER);
+            headers.push((name, value));
+        }
+
+        if !has_cache_control {
+            headers.push((CACHE_CONTROL_HEADER, b"no-cache"));
+        }
+
+        // The encoder would hold events back until it has enough to compress.
+        self.encoding = None;
+        self.send_start(status_code, headers, None)?;
+        stream.open();
+
+        Ok(())
+    }
+
+    /// Sends an event on a stream started with `start_event_stream`.
+    ///
+    /// Each line of the data is sent as its own `data` field, the client
+    /// joins them back together with line breaks.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     data:
+    ///         The data of the event.
+    ///     event:
+    ///         The type of the event, clients dispatch it to listeners of
+    ///         this type rather than `message` if given.
+    ///     id:
+    ///         The id of the event, clients send the last id they received
+    ///         in the `Last-Event-ID` header when reconnecting.
+    ///     retry:
+    ///         The time in milliseconds clients should wait before
+    ///         reconnecting if the stream is lost.
+    ///
+    /// Raises:
+    ///     ConnectionResetError:
+    ///         The client has disconnected, no more events can be sent.
+    #[args(event = "None", id = "None", retry = "None")]
+    fn send_event(
+        &mut self,
+        py: Python,
+        data: &str,
+        event: Option<String>,
+        id: Option<String>,
+        retry: Option<u64>,
+    ) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
+        match self.event_stream.as_ref() {
+            Some(stream) if stream.is_closed() => {
+                return Err(PyConnectionResetError::new_err(
+                    "the client has disconnected",
+                ))
+            },
+            Some(stream) if stream.is_open() => {},
+            _ => {
+                return Err(PyRuntimeError::new_err(
+                    "the event stream has not been started",
+                ))
+            },
+        }
+
+        let body = frame_event(data, event.as_deref(), id.as_deref(), retry)?;
+        self.send_body(py, true, body, None)
+    }
+
+    /// Sends an informational response ahead of the final response, e.g.
+    /// `103 Early Hints` with `Link` headers for the client to preload.
+    /// Any number can be sent until the response is started.
+    ///
+    /// HTTP/1.0 clients don't understand informational responses so they're
+    /// skipped for them, a `100 Continue` is sent by the server itself.
+    ///
+    /// This raises a `RuntimeError` if the response has already been
+    /// started.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     status_code:
+    ///         The status of the response, from 102 to 199.
+    ///     headers:
+    ///         A list of `(name, value)` headers.
+    fn send_informational(
+        &mut self,
+        status_code: u16,
+        headers: Vec<(&[u8], &[u8])>,
+    ) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
+        if !(102..200).contains(&status_code) {
+            return Err(PyValueError::new_err(
+                "informational responses must have a status from 102 to 199",
+            ));
+        }
+
+        if self.started {
+            return Err(PyRuntimeError::new_err(
+                "informational responses cannot be sent once the response has started",
+            ));
+        }
+
+        if self.connection.http_10 {
+            return Ok(());
+        }
+
+        let reason = match status_code {
+            102 => "Processing",
+            103 => "Early Hints",
+            _ => "",
+        };
+        let mut out = Vec::with_capacity(headers.len() + 2);
+        out.push(format!("HTTP/1.1 {} {}", status_code, reason).into_bytes());
+
+        for (name, value) in headers {
+            validate_header_name(name)?;
+            if headers::HeaderValue::from_bytes(value).is_err() {
+                return Err(PyValueError::new_err("invalid header value given"));
+            }
+
+            out.push([name, value].join(HEADER_SEPARATOR));
+        }
+        out.push(LINE_SEPARATOR.to_vec()); // End of headers
+
+        let block = out.join(LINE_SEPARATOR);
+        self.queue((true, true, Body::Interim(block), None))
+    }
+
+    /// Sends the trailer headers of the response to the handler, completing
+    /// the response.
+    ///
+    /// Trailers can only be sent after the last chunk of the body and only
+    /// if they were advertised when the response was started, any trailers
+    /// sent on a response that is not chunked or to a client which didn't
+    /// accept them are ignored.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     trailers:
+    ///         A list of `(name, value)` trailer headers.
+    fn send_trailers(&mut self, trailers: Vec<(&[u8], &[u8])>) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
+        if !self.expects_trailers {
+            debug!("ignoring trailers on a response that did not advertise them");
+            return Ok(());
+        }
+
+        let mut out = Vec::with_capacity(trailers.len() + 1);
+        for (name, value) in trailers {
+            validate_header_name(name)?;
+            if headers::HeaderValue::from_bytes(value).is_err() {
+                return Err(PyValueError::new_err("invalid trailer value given"));
+            }
+
+            out.push([name, value].join(HEADER_SEPARATOR));
+        }
+        out.push(LINE_SEPARATOR.to_vec()); // End of trailers
+
+        let block = out.join(LINE_SEPARATOR);
+        self.queue((false, true, block.into(), None))?;
+
+        self.expects_trailers = false;
+
+        Ok(())
+    }
+
+    /// Accepts a request asking to upgrade to a websocket, sending the
+    /// `101 Switching Protocols` response.
+    ///
+    /// The connection switches to the websocket protocol once the response
+    /// has been written, after which `on_message` is invoked with each
+    /// message received as either `str` or `bytes`, and with `None` once
+    /// the connection is closed with the code given by the returned
+    /// `WebSocket`'s `close_code`.
+    ///
+    /// This raises a `RuntimeError` if the request did not ask to upgrade
+    /// to a websocket or the response has already been started.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     on_message:
+    ///         The callback invoked with each received message.
+    ///     subprotocol:
+    ///         An optional subprotocol selected from those the client offered.
+    ///
+    /// Returns:
+    ///     The `WebSocket` used to send messages to the client.
+    #[args(subprotocol = "None")]
+    fn accept_websocket(
+        &mut self,
+        on_message: PyObject,
+        subprotocol: Option<String>,
+    ) -> PyResult<WebSocket> {
+        let acceptor = match self.websocket.as_ref() {
+            Some(acceptor) => acceptor,
+            None => {
+                return Err(PyRuntimeError::new_err(
+                    "request did not ask to upgrade to a websocket",
+                ))
+            },
         };
+
+        if self.started {
+            return Err(PyRuntimeError::new_err("response has already started"));
+        }
+
+        let accept = format!("sec-websocket-accept: {}", acceptor.accept_key);
+        let mut out: Vec<Cow<'static, [u8]>> = vec![
+            WEBSOCKET_STATUS_LINE.into(),
+            WEBSOCKET_UPGRADE_HEADER.into(),
+            CONNECTION_UPGRADE_HEADER.into(),
+            accept.into_bytes().into(),
+        ];
+
+        if let Some(subprotocol) = subprotocol {
+            if headers::HeaderValue::from_str(&subprotocol).is_err() {
+                return Err(PyValueError::new_err("invalid subprotocol given"));
+            }
+            let header = format!("sec-websocket-protocol: {}", subprotocol);
+            out.push(header.into_bytes().into());
+        }
+
+        push_server_headers(
+            &mut out,
+            &self.settings,
+            self.request_id.as_deref(),
+            |_| false,
+        );
+        out.push(LINE_SEPARATOR.into());
+
+        self.queue((false, true, out.join(LINE_SEPARATOR).into(), None))?;
+
+        self.started = true;
+
+        Ok(acceptor.accept(on_message))
+    }
+
+    /// Signals that the application raised an exception while handling
+    /// the request.
+    ///
+    /// The exception is passed to the server's error callback, if nothing
+    /// has been sent yet the configured error response is sent, by default
+    /// a `500 Internal Server Error`, otherwise the connection is aborted
+    /// as the already started response cannot be recovered. The connection
+    /// is kept alive after the error response if it otherwise would be.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     error:
+    ///         The exception raised by the application.
+    fn send_error(&mut self, py: Python, error: &PyAny) -> PyResult<()> {
+        if self.errored {
+            return Ok(());
+        }
+
+        let (response, keep_alive) = if self.started {
+            (Vec::new(), false)
+        } else {
+            let keep_alive = !self.must_close();
+            let error = &self.settings.error_response;
+            let headers = [(http::header::CONTENT_TYPE.as_str(), "text/plain")];
+            let response = render_static_response(
+                error.status,
+                &headers,
+                &error.body,
+                keep_alive,
+                self.connection.http_10,
+                &self.settings,
+                self.request_id.as_deref(),
+            );
+            (response, keep_alive)
+        };
+
+        self.queue((false, keep_alive, response.into(), None))?;
+
+        self.errored = true;
+        self.callback.report_error(py, PyErr::from_instance(error));
+
+        Ok(())
+    }
+
+    /// Resets the connection immediately with a TCP RST rather than
+    /// finishing the response, e.g. to drop a client the application has
+    /// found to be misbehaving. Anything not yet written is discarded and
+    /// the client sees the connection fail rather than end.
+    ///
+    /// Nothing sent afterwards is written, the `on_disconnect()` callbacks
+    /// are invoked once the connection has been closed.
+    fn abort(&mut self) -> PyResult<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+
+        self.errored = true;
+        self.transport.abort()
     }
 
     /// Submits a given callback to the waiter queue.
@@ -207,9 +1154,282 @@ impl DataSender {
     ///     waker:
     ///         A callback to be invoked when data can be written to the socket
     ///         without blocking.
-    fn subscribe(&self, waker: PyObject) {
+    fn subscribe(&self, py: Python, waker: PyObject) {
+        // Nothing will drain the queue once the connection has been lost,
+        // the waker finds out by sending again.
+        if self.is_closed() {
+            invoke_callback(py, &waker, "waker");
+            return;
+        }
+
         self.waiter_queue.push(waker);
     }
+
+    /// Registers a callback invoked with no arguments once the client
+    /// disconnects or the response is aborted, e.g. by the response
+    /// timeout, letting the application stop working on the request.
+    ///
+    /// The callback is invoked straight away if that has already happened.
+    /// Errors raised by the callback are logged.
+    ///
+    /// Args:
+    ///     callback:
+    ///         The callback to be invoked on disconnect.
+    fn on_disconnect(&self, py: Python, callback: PyObject) {
+        if self.is_closed() {
+            invoke_callback(py, &callback, "disconnect");
+            return;
+        }
+
+        self.disconnect_waiters.push(callback);
+    }
+
+    /// If the client has disconnected or the response has been aborted,
+    /// anything sent is ignored.
+    #[getter]
+    fn disconnected(&self) -> bool {
+        self.is_closed()
+    }
+
+    /// Gets the time left before the response timeout aborts the request,
+    /// the deadline is pushed back each time the application makes progress
+    /// on the response and is on hold while the body is being received.
+    ///
+    /// Returns:
+    ///     The remaining time in seconds or `None` if there is no response
+    ///     timeout or the response is no longer outstanding.
+    fn remaining_time(&self) -> PyResult<Option<f64>> {
+        let timeout = match self.settings.response_timeout {
+            Some(timeout) if !self.is_closed() => timeout,
+            _ => return Ok(None),
+        };
+
+        let last = match self.activity.load(Ordering::Relaxed) {
+            0 => return Ok(None),
+            micros => Duration::from_micros(micros - 1),
+        };
+
+        let elapsed = self.transport.now()?.saturating_sub(last);
+        Ok(Some(timeout.saturating_sub(elapsed).as_secs_f64()))
+    }
+}
+
+/// Renders a complete response generated by the server itself rather than
+/// the application.
+#[allow(clippy::too_many_arguments)]
+fn render_static_response(
+    status: http::StatusCode,
+    resp_headers: &[(&str, &str)],
+    body: &[u8],
+    keep_alive: bool,
+    http_10: bool,
+    settings: &ServerSettings,
+    request_id: Option<&str>,
+) -> Vec<u8> {
+    let mut out = render_static_head(
+        status,
+        resp_headers,
+        body.len() as u64,
+        keep_alive,
+        http_10,
+        settings,
+        request_id,
+    );
+    out.extend_from_slice(body);
+    out
+}
+
+/// Renders the head of a complete response generated by the server with
+/// a body of the given length.
+fn render_static_head(
+    status: http::StatusCode,
+    resp_headers: &[(&str, &str)],
+    content_length: u64,
+    keep_alive: bool,
+    http_10: bool,
+    settings: &ServerSettings,
+    request_id: Option<&str>,
+) -> Vec<u8> {
+    let mut out: Vec<Cow<'static, [u8]>> = Vec::with_capacity(resp_headers.len() + 4);
+    let status_line = format!(
+        "HTTP/1.1 {} {}",
+        status.as_str(),
+        status.canonical_reason().unwrap_or(""),
+    );
+    out.push(status_line.into_bytes().into());
+
+    for (name, value) in resp_headers {
+        out.push(format!("{}: {}", name, value).into_bytes().into());
+    }
+
+    if !keep_alive {
+        out.push(CONNECTION_CLOSE_HEADER.into());
+    } else if http_10 {
+        out.push(CONNECTION_KEEP_ALIVE_HEADER.into());
+    }
+
+    let has_length = !status.is_informational()
+        && (status != http::StatusCode::NO_CONTENT)
+        && (status != http::StatusCode::NOT_MODIFIED);
+    if has_length {
+        let header = format!("content-length: {}", content_length);
+        out.push(header.into_bytes().into());
+    }
+
+    let has_header = |name: &str| {
+        resp_headers
+            .iter()
+            .any(|(given, _)| given.eq_ignore_ascii_case(name))
+    };
+    push_server_headers(&mut out, settings, request_id, has_header);
+
+    let mut out = out.join(LINE_SEPARATOR);
+    out.extend_from_slice(LINE_SEPARATOR);
+    date::extend_date_header(&mut out);
+    out.extend_from_slice(LINE_SEPARATOR);
+    out
+}
+
+/// Adds the headers the server puts on every response along with the id
+/// of the request, leaving out any the response already has.
+fn push_server_headers(
+    out: &mut Vec<Cow<'static, [u8]>>,
+    settings: &ServerSettings,
+    request_id: Option<&str>,
+    has_header: impl Fn(&str) -> bool,
+) {
+    if let Some(id) = request_id.filter(|_| !has_header(REQUEST_ID_HEADER)) {
+        out.push(format!("{}: {}", REQUEST_ID_HEADER, id).into_bytes().into());
+    }
+
+    for line in settings.response_headers.lines(has_header) {
+        out.push(line.to_vec().into());
+    }
+}
+
+/// Opens the file at the given path or duplicates the given file descriptor.
+fn open_file(file: &PyAny) -> PyResult<File> {
+    if let Ok(fd) = file.extract::<i32>() {
+        #[cfg(unix)]
+        {
+            let fd = unsafe { libc::dup(fd) };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            return Ok(unsafe { File::from_raw_fd(fd) });
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = fd;
+            return Err(PyValueError::new_err(
+                "file descriptors are only supported on unix, pass a path instead",
+            ));
+        }
+    }
+
+    let path: PathBuf = file.extract()?;
+    Ok(File::open(path)?)
+}
+
+/// If a comma separated header value, e.g. of `Connection`, lists the
+/// given token ignoring case.
+pub(crate) fn has_token(value: &[u8], token: &[u8]) -> bool {
+    value
+        .split(|b| *b == b',')
+        .any(|v| v.trim_ascii().eq_ignore_ascii_case(token))
+}
+
+/// Adds `Accept-Encoding` to the response's own `Vary` header.
+fn merge_vary(value: headers::HeaderValue) -> headers::HeaderValue {
+    let covered = value
+        .as_bytes()
+        .split(|b| *b == b',')
+        .map(|v| v.trim_ascii())
+        .any(|v| (v == b"*") | v.eq_ignore_ascii_case(b"accept-encoding"));
+
+    if covered {
+        return value;
+    }
+
+    let merged = [value.as_bytes(), b", accept-encoding"].concat();
+    headers::HeaderValue::from_bytes(&merged).unwrap_or(value)
+}
+
+/// Validates the given header name returning a `ValueError` if invalid.
+fn validate_header_name(name: &[u8]) -> PyResult<()> {
+    match headers::HeaderName::from_bytes(name) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(PyValueError::new_err("invalid header name given")),
+    }
+}
+
+/// Frames a chunk of the body using chunked transfer-encoding.
+///
+/// The terminating zero-length chunk is appended if no more body
+/// is expected, empty chunks are otherwise skipped entirely as they
+/// would prematurely end the body. If `finish` is false the terminating
+/// chunk is left open for trailers to follow.
+fn frame_chunk(more_body: bool, finish: bool, body: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 16);
+
+    if !body.is_empty() {
+        out.extend_from_slice(format!("{:X}", body.len()).as_bytes());
+        out.extend_from_slice(LINE_SEPARATOR);
+        out.extend(body);
+        out.extend_from_slice(LINE_SEPARATOR);
+    }
+
+    if !more_body {
+        out.extend_from_slice(LAST_CHUNK);
+
+        if finish {
+            out.extend_from_slice(LINE_SEPARATOR);
+        }
+    }
+
+    out
+}
+
+/// Frames an event of a `text/event-stream`.
+///
+/// The data is split into a `data` field per line, the event type and id
+/// can't contain line breaks as they would end the field early.
+fn frame_event(
+    data: &str,
+    event: Option<&str>,
+    id: Option<&str>,
+    retry: Option<u64>,
+) -> PyResult<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() + 32);
+
+    for (name, value) in [("event", event), ("id", id)].iter() {
+        if let Some(value) = value {
+            if value.contains(|c| (c == '\n') | (c == '\r')) {
+                return Err(PyValueError::new_err(format!(
+                    "the event {} cannot contain line breaks",
+                    name
+                )));
+            }
+
+            out.extend_from_slice(format!("{}: {}\n", name, value).as_bytes());
+        }
+    }
+
+    if let Some(retry) = retry {
+        out.extend_from_slice(format!("retry: {}\n", retry).as_bytes());
+    }
+
+    let data = data.replace("\r\n", "\n");
+    for line in data.split(|c| (c == '\n') | (c == '\r')) {
+        out.extend_from_slice(b"data: ");
+        out.extend_from_slice(line.as_bytes());
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+
+    Ok(out)
 }
 
 pub struct SenderFactory {
@@ -222,24 +1442,177 @@ pub struct SenderFactory {
     /// A queue of waiting events to invoke before the body
     /// can be written to again.
     waiter_queue: WakerQueue,
+
+    /// The callback handler used to report application errors.
+    callback: CallbackHandler,
+
+    /// The server settings.
+    settings: Settings,
+
+    /// How the current request asked for the connection to be handled.
+    connection: RequestConnection,
+
+    /// The id of the current request if request ids are enabled.
+    request_id: Option<Arc<str>>,
+
+    /// Set once the connection has been lost or the response aborted,
+    /// shared with every handle.
+    closed: Arc<AtomicBool>,
+
+    /// The callbacks invoked once the connection is lost or the response
+    /// aborted.
+    disconnect_waiters: WakerQueue,
+
+    /// The last time the application made progress on the response in
+    /// microseconds plus one, 0 if no response is outstanding.
+    activity: Arc<AtomicU64>,
 }
 
 impl SenderFactory {
     /// Constructs a new factory.
-    pub fn new() -> Self {
-        let (tx, rx) = bounded(2);
+    ///
+    /// At most `settings.max_buffered_chunks` messages can be queued at once
+    /// before any handles raise a `BlockingIoError` applying backpressure to
+    /// the application.
+    pub(crate) fn new(callback: CallbackHandler, settings: Settings) -> Self {
+        let (tx, rx) = bounded(settings.max_buffered_chunks.max(1));
         let queue = Arc::new(SegQueue::new());
 
         Self {
             sender_tx: tx,
             sender_rx: rx,
             waiter_queue: queue,
+            callback,
+            settings,
+            connection: RequestConnection::default(),
+            request_id: None,
+            closed: Arc::new(AtomicBool::new(false)),
+            disconnect_waiters: Arc::new(SegQueue::new()),
+            activity: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    /// Makes a new sending handle with the given factory channels and queue.
-    pub fn make_handle(&self) -> DataSender {
-        DataSender::new(self.sender_tx.clone(), self.waiter_queue.clone())
+    /// Sets how the current request asked for the connection to be
+    /// handled, applied to any handles made and static responses sent
+    /// for it.
+    pub(crate) fn set_request_connection(&mut self, connection: RequestConnection) {
+        self.connection = connection;
+    }
+
+    /// Sets the id of the current request, sent back with the response by
+    /// any handles made and static responses sent for it.
+    pub(crate) fn set_request_id(&mut self, request_id: Option<Arc<str>>) {
+        self.request_id = request_id;
+    }
+
+    /// The id of the current request if request ids are enabled.
+    pub(crate) fn request_id(&self) -> Option<&Arc<str>> {
+        self.request_id.as_ref()
+    }
+
+    /// Makes a new sending handle with the given factory channels and queue,
+    /// the transport's writer is woken whenever the handle queues anything.
+    pub(crate) fn make_handle(&self, transport: Transport) -> DataSender {
+        DataSender::new(
+            self.sender_tx.clone(),
+            self.waiter_queue.clone(),
+            self.callback.clone(),
+            self.settings.clone(),
+            transport,
+            self.connection,
+            self.request_id.clone(),
+            self.closed.clone(),
+            self.disconnect_waiters.clone(),
+            self.activity.clone(),
+        )
+    }
+
+    /// Records the last time the application made progress on the response
+    /// to the current request, `None` once no response is outstanding.
+    pub(crate) fn set_activity(&self, activity: Option<Duration>) {
+        let micros = activity.map_or(0, |last| last.as_micros() as u64 + 1);
+        self.activity.store(micros, Ordering::Relaxed);
+    }
+
+    /// Sends a complete response with no body to the handler, used for
+    /// responses generated by the server itself rather than the application.
+    pub(crate) fn send_empty_response(
+        &self,
+        status: http::StatusCode,
+        resp_headers: &[(&str, &str)],
+        keep_alive: bool,
+    ) {
+        self.send_static_response(status, resp_headers, b"", keep_alive)
+    }
+
+    /// Sends a complete response with a fixed body to the handler, used for
+    /// responses generated by the server itself rather than the application.
+    pub(crate) fn send_static_response(
+        &self,
+        status: http::StatusCode,
+        resp_headers: &[(&str, &str)],
+        body: &[u8],
+        keep_alive: bool,
+    ) {
+        let out = render_static_response(
+            status,
+            resp_headers,
+            body,
+            keep_alive,
+            self.connection.http_10,
+            &self.settings,
+            self.request_id.as_deref(),
+        );
+
+        let _ = self
+            .sender_tx
+            .try_send((false, keep_alive, out.into(), None));
+    }
+
+    /// Sends a complete response with a body of the given length to the
+    /// handler, the body being a region of a file written directly to the
+    /// socket if any, e.g. a `HEAD` response only gets the length.
+    pub(crate) fn send_file_response(
+        &self,
+        status: http::StatusCode,
+        resp_headers: &[(&str, &str)],
+        content_length: u64,
+        file: Option<FileBody>,
+        keep_alive: bool,
+    ) {
+        let head = render_static_head(
+            status,
+            resp_headers,
+            content_length,
+            keep_alive,
+            self.connection.http_10,
+            &self.settings,
+            self.request_id.as_deref(),
+        );
+
+        let body = match file {
+            Some(file) if file.remaining() > 0 => Body::File {
+                file,
+                prefix: head,
+                suffix: Vec::new(),
+            },
+            _ => head.into(),
+        };
+
+        let _ = self.sender_tx.try_send((false, keep_alive, body, None));
+    }
+
+    /// Reports an error raised while invoking the application and sends
+    /// the configured error response.
+    ///
+    /// This should only be used before any handles have sent data.
+    pub(crate) fn send_error(&self, err: PyErr) {
+        Python::with_gil(|py| self.callback.report_error(py, err));
+
+        let keep_alive = self.connection.keep_alive & !self.settings.is_draining();
+        let error = &self.settings.error_response;
+        let headers = [(http::header::CONTENT_TYPE.as_str(), "text/plain")];
+        self.send_static_response(error.status, &headers, &error.body, keep_alive);
     }
 
     /// Receives data from any DataSenders that have submitted
@@ -248,14 +1621,174 @@ impl SenderFactory {
     /// This also implicitly wakes up any waiters waiting on a notifying them
     /// that they can send to the handler again.
     pub fn recv(&self) -> Result<SenderPayload, TryRecvError> {
+        self.wake_waiters();
+        self.sender_rx.try_recv()
+    }
+
+    /// If nothing has been sent to the handler that's yet to be received.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sender_rx.is_empty()
+    }
+
+    /// Closes every handle as the connection has been lost or the response
+    /// aborted, anything they send is ignored from then on.
+    ///
+    /// Any waiters are woken so they find out and the disconnect callbacks
+    /// are invoked.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.wake_waiters();
+
+        if !self.disconnect_waiters.is_empty() {
+            Python::with_gil(|py| {
+                while let Some(callback) = self.disconnect_waiters.pop() {
+                    invoke_callback(py, &callback, "disconnect");
+                }
+            });
+        }
+    }
+
+    /// Wakes up any waiters waiting to send to the handler.
+    pub(crate) fn wake_waiters(&self) {
         if self.waiter_queue.len() > 0 {
             Python::with_gil(|py| {
                 while let Some(waker) = self.waiter_queue.pop() {
                     // The waker should not affect the reader
-                    let _ = waker.call0(py);
+                    invoke_callback(py, &waker, "waker");
                 }
             });
         }
-        self.sender_rx.try_recv()
+    }
+}
+
+/// The `100 Continue` interim response owed to a request sent with
+/// `Expect: 100-continue`, shared by the protocol and the request's
+/// sender and receiver.
+///
+/// Whichever comes first decides the outcome, receiving the body requests
+/// the interim response while starting the response rejects the body.
+#[derive(Clone)]
+pub(crate) struct ExpectContinue {
+    /// One of `CONTINUE_WAITING`, `CONTINUE_REQUESTED` or `CONTINUE_SETTLED`.
+    state: Arc<AtomicU8>,
+
+    /// The transport used to wake the writer once the response is requested.
+    transport: Transport,
+}
+
+impl ExpectContinue {
+    /// Creates a new interim response waiting on the application.
+    pub(crate) fn new(transport: Transport) -> Self {
+        Self {
+            state: Arc::new(AtomicU8::new(CONTINUE_WAITING)),
+            transport,
+        }
+    }
+
+    /// If the interim response is yet to be requested or rejected.
+    fn is_pending(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == CONTINUE_WAITING
+    }
+
+    /// Gives up on the interim response as the final response has been
+    /// started.
+    fn cancel(&self) {
+        self.transition(CONTINUE_WAITING, CONTINUE_SETTLED);
+    }
+
+    /// Requests the interim response be written if it is still owed.
+    pub(crate) fn request(&self) -> PyResult<()> {
+        if self.transition(CONTINUE_WAITING, CONTINUE_REQUESTED) {
+            self.transport.resume_writing()?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the interim response to be written if it has been requested,
+    /// this is written ahead of anything the application has queued.
+    pub(crate) fn take_requested(&self) -> Option<&'static [u8]> {
+        if self.transition(CONTINUE_REQUESTED, CONTINUE_SETTLED) {
+            Some(CONTINUE_RESPONSE)
+        } else {
+            None
+        }
+    }
+
+    fn transition(&self, from: u8, to: u8) -> bool {
+        self.state
+            .compare_exchange(from, to, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+/// The state of a `text/event-stream` response, shared by the protocol and
+/// the request's sender.
+///
+/// The protocol keeps an open stream alive with heartbeats and closes it
+/// once the connection is lost, after which the application can't send any
+/// more events.
+#[derive(Clone)]
+pub(crate) struct EventStream {
+    /// One of `STREAM_PENDING`, `STREAM_OPEN` or `STREAM_CLOSED`.
+    state: Arc<AtomicU8>,
+}
+
+impl EventStream {
+    /// Creates a new event stream yet to be started by the application.
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Arc::new(AtomicU8::new(STREAM_PENDING)),
+        }
+    }
+
+    /// Marks the stream as started unless it has been closed.
+    fn open(&self) {
+        let _ = self.state.compare_exchange(
+            STREAM_PENDING,
+            STREAM_OPEN,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// If the application has started the stream and it's not been closed.
+    pub(crate) fn is_open(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == STREAM_OPEN
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == STREAM_CLOSED
+    }
+
+    /// Closes the stream as the connection has been lost.
+    pub(crate) fn close(&self) {
+        self.state.store(STREAM_CLOSED, Ordering::Relaxed);
+    }
+}
+
+/// How a request asked for its connection to be handled, which decides
+/// the connection headers and framing of the response.
+#[derive(Copy, Clone)]
+pub(crate) struct RequestConnection {
+    /// If the request was made with HTTP/1.0.
+    pub(crate) http_10: bool,
+
+    /// If the connection can be kept alive after the response, from the
+    /// request's version and `Connection` header.
+    pub(crate) keep_alive: bool,
+
+    /// If the client accepts trailers after a chunked response, from the
+    /// request's `TE` header.
+    pub(crate) accepts_trailers: bool,
+}
+
+impl Default for RequestConnection {
+    fn default() -> Self {
+        Self {
+            http_10: false,
+            keep_alive: true,
+            accepts_trailers: true,
+        }
     }
 }
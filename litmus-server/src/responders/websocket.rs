@@ -0,0 +1,241 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError, TrySendError};
+use crossbeam::queue::SegQueue;
+use pyo3::exceptions::{PyBlockingIOError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+
+use super::{invoke_callback, WakerQueue};
+use crate::traits::BaseTransport;
+use crate::transport::Transport;
+
+/// The close code given once the connection is lost without a close frame.
+pub(crate) const CLOSE_ABNORMAL: u16 = 1006;
+
+/// A message queued by the application to be sent to the client.
+pub(crate) enum Outgoing {
+    Text(String),
+    Binary(Vec<u8>),
+    Close(u16, String),
+}
+
+/// The callable class used to send messages to a websocket client.
+#[pyclass]
+pub struct WebSocket {
+    /// The sending half of the channel used for sending messages to the
+    /// websocket protocol.
+    tx: Sender<Outgoing>,
+
+    /// A queue of waiting events to invoke before messages can be
+    /// sent again.
+    waiter_queue: WakerQueue,
+
+    /// The transport used to wake the writer once a message is queued.
+    transport: Transport,
+
+    /// The code the connection was closed with.
+    close_code: Arc<AtomicU16>,
+}
+
+impl WebSocket {
+    fn queue(&self, message: Outgoing) -> PyResult<()> {
+        if let Err(e) = self.tx.try_send(message) {
+            if let TrySendError::Full(_) = e {
+                return Err(PyBlockingIOError::new_err(()));
+            }
+
+            // The connection has been dropped, ignore.
+            return Ok(());
+        }
+
+        self.transport.resume_writing()
+    }
+}
+
+#[pymethods]
+impl WebSocket {
+    /// Sends a message to the client.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     data:
+    ///         The message, a `str` is sent as a text message and `bytes`
+    ///         as a binary message.
+    fn send(&self, data: &PyAny) -> PyResult<()> {
+        let message = if let Ok(text) = data.downcast::<PyString>() {
+            Outgoing::Text(text.to_str()?.to_string())
+        } else if let Ok(bytes) = data.downcast::<PyBytes>() {
+            Outgoing::Binary(bytes.as_bytes().to_vec())
+        } else {
+            return Err(PyTypeError::new_err("message must be either str or bytes"));
+        };
+
+        self.queue(message)
+    }
+
+    /// Starts the closing handshake, no more messages can be sent after
+    /// this and the connection is closed once the client replies.
+    ///
+    /// This raises a `BlockingIoError` if the queue / buffer is full, the
+    /// invoker should wait till the queue / buffer is no longer full.
+    ///
+    /// Args:
+    ///     code:
+    ///         The close status code sent to the client.
+    ///     reason:
+    ///         An optional reason sent along with the code, this must be
+    ///         no longer than 123 bytes.
+    #[args(code = "1000", reason = "\"\"")]
+    fn close(&self, code: u16, reason: &str) -> PyResult<()> {
+        if reason.len() > 123 {
+            return Err(PyValueError::new_err(
+                "close reason must be no longer than 123 bytes",
+            ));
+        }
+
+        self.queue(Outgoing::Close(code, reason.to_string()))
+    }
+
+    /// The code the connection was closed with once `on_message` has been
+    /// invoked with `None`.
+    ///
+    /// This is the code of the client's close frame, `1005` if it had none,
+    /// the code the server failed the connection with if the client broke
+    /// the protocol or `1006` if the connection was lost without either.
+    #[getter]
+    fn close_code(&self) -> u16 {
+        self.close_code.load(Ordering::Relaxed)
+    }
+
+    /// Submits a given callback to the waiter queue.
+    ///
+    /// Any waiters in the queue when messages can be sent again will be
+    /// taken out of the queue and invoked, see `DataSender.subscribe`.
+    ///
+    /// Args:
+    ///     waker:
+    ///         A callback to be invoked when messages can be sent again.
+    fn subscribe(&self, waker: PyObject) {
+        self.waiter_queue.push(waker);
+    }
+}
+
+/// Accepts a request asking to upgrade to a websocket, held by the
+/// `DataSender` of the request.
+pub(crate) struct WebSocketAcceptor {
+    /// The value of the `Sec-WebSocket-Accept` header to respond with.
+    pub(crate) accept_key: String,
+
+    tx: Sender<Outgoing>,
+    waiter_queue: WakerQueue,
+    transport: Transport,
+
+    /// The queue the message callback is pushed to once accepted.
+    accepted: Arc<SegQueue<PyObject>>,
+
+    close_code: Arc<AtomicU16>,
+}
+
+impl WebSocketAcceptor {
+    /// Marks the websocket as accepted returning the handle used to send
+    /// messages, `on_message` is invoked with every received message.
+    pub(crate) fn accept(&self, on_message: PyObject) -> WebSocket {
+        self.accepted.push(on_message);
+
+        WebSocket {
+            tx: self.tx.clone(),
+            waiter_queue: self.waiter_queue.clone(),
+            transport: self.transport.clone(),
+            close_code: self.close_code.clone(),
+        }
+    }
+}
+
+pub(crate) struct WebSocketFactory {
+    /// The sender half for sending messages.
+    tx: Sender<Outgoing>,
+
+    /// The receiver half for receiving messages.
+    rx: Receiver<Outgoing>,
+
+    /// A queue of waiting events to invoke before messages can be
+    /// sent again.
+    waiter_queue: WakerQueue,
+
+    /// The transport of the connection being upgraded.
+    transport: Transport,
+
+    /// The computed `Sec-WebSocket-Accept` header value.
+    accept_key: String,
+
+    /// Holds the message callback once the application accepts.
+    accepted: Arc<SegQueue<PyObject>>,
+
+    /// The code the connection was closed with, shared with the handles.
+    close_code: Arc<AtomicU16>,
+}
+
+impl WebSocketFactory {
+    /// Constructs a new factory for a request with the given
+    /// `Sec-WebSocket-Accept` value.
+    ///
+    /// At most `max_messages` can be queued at once before any handles
+    /// raise a `BlockingIoError` applying backpressure to the application.
+    pub(crate) fn new(
+        transport: Transport,
+        accept_key: String,
+        max_messages: usize,
+    ) -> Self {
+        let (tx, rx) = bounded(max_messages.max(1));
+
+        Self {
+            tx,
+            rx,
+            waiter_queue: Arc::new(SegQueue::new()),
+            transport,
+            accept_key,
+            accepted: Arc::new(SegQueue::new()),
+            close_code: Arc::new(AtomicU16::new(CLOSE_ABNORMAL)),
+        }
+    }
+
+    /// Makes a new acceptor to be given to the request's `DataSender`.
+    pub(crate) fn make_acceptor(&self) -> WebSocketAcceptor {
+        WebSocketAcceptor {
+            accept_key: self.accept_key.clone(),
+            tx: self.tx.clone(),
+            waiter_queue: self.waiter_queue.clone(),
+            transport: self.transport.clone(),
+            accepted: self.accepted.clone(),
+            close_code: self.close_code.clone(),
+        }
+    }
+
+    /// Sets the code the connection was closed with, given to the
+    /// application by the handles.
+    pub(crate) fn set_close_code(&self, code: u16) {
+        self.close_code.store(code, Ordering::Relaxed);
+    }
+
+    /// Takes the message callback if the application has accepted.
+    pub(crate) fn take_accepted(&self) -> Option<PyObject> {
+        self.accepted.pop()
+    }
+
+    /// Receives the next message queued by the application waking
+    /// any waiters.
+    pub(crate) fn recv(&self) -> Result<Outgoing, TryRecvError> {
+        if !self.waiter_queue.is_empty() {
+            Python::with_gil(|py| {
+                while let Some(waker) = self.waiter_queue.pop() {
+                    invoke_callback(py, &waker, "waker");
+                }
+            });
+        }
+        self.rx.try_recv()
+    }
+}
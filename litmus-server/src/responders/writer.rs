@@ -0,0 +1,395 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use pyo3::exceptions::{PyBlockingIOError, PyConnectionResetError, PyRuntimeError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyTuple};
+use pyo3::PyNativeType;
+
+use super::DataSender;
+
+/// A chunk of the body waiting for room in the sender's queue along with
+/// the future resolved once it has been accepted.
+struct Chunk {
+    more_body: bool,
+    body: Py<PyBytes>,
+    future: Option<PyObject>,
+}
+
+/// Writes the body of a response using a `DataSender`, each write returns
+/// an awaitable so the application waits on the client to keep up rather
+/// than the response being buffered in memory.
+///
+/// A write resolves once its chunk has been accepted by the sender and no
+/// more than `high_water` bytes of the body are waiting to be written to
+/// the socket, otherwise it waits on enough of the body being written.
+/// Writes are sent in order and don't have to be awaited one at a time.
+///
+/// If the connection is lost or the response aborted any outstanding
+/// awaitables raise a `ConnectionResetError`, as do any later writes.
+///
+/// Args:
+///     sender:
+///         The sender of the response, the response must be started with it
+///         before anything is written.
+///     loop:
+///         The event loop the awaitables are created with.
+///     high_water:
+///         The amount of the body which can be waiting to be written before
+///         writes wait on it draining, defaults to the server's
+///         `write_high_water`.
+#[pyclass]
+pub struct ResponseWriter {
+    sender: Py<DataSender>,
+
+    /// The `create_future` and `call_soon` methods of the event loop.
+    create_future: PyObject,
+    call_soon: PyObject,
+
+    high_water: usize,
+
+    /// The amount of the body accepted by the sender that's yet to be
+    /// written to the socket.
+    buffered: usize,
+
+    /// The chunks waiting for room in the sender's queue, in order.
+    pending: VecDeque<Chunk>,
+
+    /// The writes waiting on the buffered body falling to the high water
+    /// mark.
+    draining: Vec<PyObject>,
+
+    /// The flushes waiting on the entire body being written.
+    flushing: Vec<PyObject>,
+
+    /// If a waker is subscribed to the sender.
+    subscribed: bool,
+
+    /// If the end of the body has been written.
+    finished: bool,
+}
+
+impl ResponseWriter {
+    /// Queues a chunk and sends anything that can be sent.
+    fn push(
+        slf: &PyCell<Self>,
+        more_body: bool,
+        body: Py<PyBytes>,
+        future: Option<PyObject>,
+    ) -> PyResult<()> {
+        {
+            let mut writer = slf.try_borrow_mut()?;
+            writer.ensure_open()?;
+            writer.finished = !more_body;
+            writer.pending.push_back(Chunk {
+                more_body,
+                body,
+                future,
+            });
+        }
+
+        Self::resume(slf)
+    }
+
+    /// Raises a `RuntimeError` if the end of the body has been written.
+    fn ensure_open(&self) -> PyResult<()> {
+        if self.finished {
+            return Err(PyRuntimeError::new_err(
+                "the response body has already been finished",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sends the pending chunks until the sender's queue is full, then
+    /// resolves any awaitables which are done waiting.
+    ///
+    /// The writer is never borrowed while calling into the sender as the
+    /// sender can invoke its callbacks straight away.
+    fn resume(slf: &PyCell<Self>) -> PyResult<()> {
+        let py = slf.py();
+        let sender = slf.try_borrow()?.sender.clone_ref(py);
+
+        if sender.try_borrow(py)?.is_closed() {
+            return Self::fail(slf);
+        }
+
+        loop {
+            let chunk = match slf.try_borrow_mut()?.pending.pop_front() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+
+            // Counted first in case the sender has nothing to write and
+            // invokes the callback straight away.
+            let len = chunk.body.as_ref(py).as_bytes().len();
+            slf.try_borrow_mut()?.buffered += len;
+
+            let on_written = WriterCallback {
+                writer: slf.into(),
+                event: WriterEvent::Written(len),
+            };
+            let args = (chunk.more_body, chunk.body.clone_ref(py), on_written);
+
+            match sender.call_method1(py, "send_body", args) {
+                Ok(_) => {
+                    let mut writer = slf.try_borrow_mut()?;
+                    if let Some(future) = chunk.future {
+                        writer.draining.push(future);
+                    }
+                },
+                Err(e) if e.is_instance::<PyBlockingIOError>(py) => {
+                    let mut writer = slf.try_borrow_mut()?;
+                    writer.buffered -= len;
+                    writer.pending.push_front(chunk);
+                    break;
+                },
+                Err(e) => {
+                    slf.try_borrow_mut()?.buffered -= len;
+                    match chunk.future {
+                        Some(future) => {
+                            future.call_method1(
+                                py,
+                                "set_exception",
+                                (e.instance(py),),
+                            )?;
+                        },
+                        None => return Err(e),
+                    }
+                },
+            }
+        }
+
+        Self::settle(slf)?;
+
+        // The waker also lets anything waiting on the body being written
+        // find out if the connection is lost.
+        let subscribe = {
+            let mut writer = slf.try_borrow_mut()?;
+            let outstanding = !writer.pending.is_empty()
+                | !writer.draining.is_empty()
+                | !writer.flushing.is_empty();
+            let subscribe = outstanding & !writer.subscribed;
+            writer.subscribed |= subscribe;
+            subscribe
+        };
+
+        if subscribe {
+            let waker = WriterCallback {
+                writer: slf.into(),
+                event: WriterEvent::Woken,
+            };
+            sender.call_method1(py, "subscribe", (waker,))?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the writes waiting on the body draining if it's at or below
+    /// the high water mark and the flushes if everything has been written.
+    fn settle(slf: &PyCell<Self>) -> PyResult<()> {
+        let py = slf.py();
+        let (draining, flushing) = {
+            let mut writer = slf.try_borrow_mut()?;
+            let draining = if writer.buffered <= writer.high_water {
+                mem::take(&mut writer.draining)
+            } else {
+                Vec::new()
+            };
+
+            let flushing = if (writer.buffered == 0) & writer.pending.is_empty() {
+                mem::take(&mut writer.flushing)
+            } else {
+                Vec::new()
+            };
+
+            (draining, flushing)
+        };
+
+        for future in draining.into_iter().chain(flushing) {
+            if !future.call_method0(py, "done")?.is_true(py)? {
+                future.call_method1(py, "set_result", (py.None(),))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Raises a `ConnectionResetError` from everything outstanding as the
+    /// connection has been lost.
+    fn fail(slf: &PyCell<Self>) -> PyResult<()> {
+        let py = slf.py();
+        let futures: Vec<PyObject> = {
+            let mut writer = slf.try_borrow_mut()?;
+            let pending = mem::take(&mut writer.pending);
+            let draining = mem::take(&mut writer.draining);
+            let flushing = mem::take(&mut writer.flushing);
+            writer.buffered = 0;
+
+            pending
+                .into_iter()
+                .filter_map(|chunk| chunk.future)
+                .chain(draining)
+                .chain(flushing)
+                .collect()
+        };
+
+        for future in futures {
+            if !future.call_method0(py, "done")?.is_true(py)? {
+                let err = PyConnectionResetError::new_err("the connection was lost");
+                future.call_method1(py, "set_exception", (err.instance(py),))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl ResponseWriter {
+    #[new]
+    #[args(high_water = "None")]
+    fn new(
+        py: Python,
+        sender: Py<DataSender>,
+        event_loop: &PyAny,
+        high_water: Option<usize>,
+    ) -> PyResult<Self> {
+        let high_water =
+            high_water.unwrap_or_else(|| sender.borrow(py).write_high_water());
+
+        Ok(Self {
+            sender,
+            create_future: event_loop.getattr("create_future")?.into(),
+            call_soon: event_loop.getattr("call_soon")?.into(),
+            high_water,
+            buffered: 0,
+            pending: VecDeque::new(),
+            draining: Vec::new(),
+            flushing: Vec::new(),
+            subscribed: false,
+            finished: false,
+        })
+    }
+
+    /// The amount of the body which can be waiting to be written before
+    /// writes wait on it draining.
+    #[getter]
+    fn high_water(&self) -> usize {
+        self.high_water
+    }
+
+    /// The amount of the body accepted by the sender that's yet to be
+    /// written to the socket, not counting any writes waiting on the
+    /// sender's queue.
+    #[getter]
+    fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Writes a chunk of the body.
+    ///
+    /// Returns an awaitable resolved once the chunk has been accepted and
+    /// the body waiting to be written is at or below the high water mark.
+    ///
+    /// Args:
+    ///     data:
+    ///         The chunk of bytes to write.
+    fn write(slf: &PyCell<Self>, data: Py<PyBytes>) -> PyResult<PyObject> {
+        let future = slf.try_borrow()?.create_future.call0(slf.py())?;
+        Self::push(slf, true, data, Some(future.clone_ref(slf.py())))?;
+        Ok(future)
+    }
+
+    /// Waits on everything written so far being written to the socket.
+    ///
+    /// Returns an awaitable resolved once nothing is left to write.
+    fn flush(slf: &PyCell<Self>) -> PyResult<PyObject> {
+        let future = slf.try_borrow()?.create_future.call0(slf.py())?;
+        slf.try_borrow_mut()?
+            .flushing
+            .push(future.clone_ref(slf.py()));
+        Self::resume(slf)?;
+        Ok(future)
+    }
+
+    /// Finishes the body with an optional last chunk, nothing can be
+    /// written after this.
+    ///
+    /// Returns an awaitable resolved once the entire body has been written
+    /// to the socket.
+    ///
+    /// Args:
+    ///     data:
+    ///         The last chunk of bytes to write if any.
+    #[args(data = "None")]
+    fn close(slf: &PyCell<Self>, data: Option<Py<PyBytes>>) -> PyResult<PyObject> {
+        let py = slf.py();
+        let future = slf.try_borrow()?.create_future.call0(py)?;
+        let data = data.unwrap_or_else(|| PyBytes::new(py, b"").into());
+
+        {
+            let mut writer = slf.try_borrow_mut()?;
+            writer.ensure_open()?;
+            writer.flushing.push(future.clone_ref(py));
+        }
+        Self::push(slf, false, data, None)?;
+        Ok(future)
+    }
+}
+
+/// What a `WriterCallback` was invoked for.
+enum WriterEvent {
+    /// A chunk of the given length has been written to the socket.
+    Written(usize),
+
+    /// The sender's waker has been invoked.
+    Woken,
+
+    /// The writer is resumed after being woken.
+    Resume,
+}
+
+/// The callbacks a `ResponseWriter` hands to its sender.
+#[pyclass]
+struct WriterCallback {
+    writer: Py<ResponseWriter>,
+    event: WriterEvent,
+}
+
+#[pymethods]
+impl WriterCallback {
+    #[call]
+    #[args(_args = "*")]
+    fn __call__(&self, py: Python, _args: &PyTuple) -> PyResult<()> {
+        let writer = self.writer.as_ref(py);
+
+        match self.event {
+            WriterEvent::Written(len) => {
+                {
+                    let mut writer = writer.try_borrow_mut()?;
+                    writer.buffered = writer.buffered.saturating_sub(len);
+                }
+                ResponseWriter::settle(writer)
+            },
+            // Wakers are invoked while the sender is draining its waiters so
+            // resuming, which can subscribe again, waits for the next tick.
+            WriterEvent::Woken => {
+                let call_soon = {
+                    let mut writer = writer.try_borrow_mut()?;
+                    writer.subscribed = false;
+                    writer.call_soon.clone_ref(py)
+                };
+
+                let resume = WriterCallback {
+                    writer: self.writer.clone_ref(py),
+                    event: WriterEvent::Resume,
+                };
+                call_soon.call1(py, (resume,))?;
+                Ok(())
+            },
+            WriterEvent::Resume => ResponseWriter::resume(writer),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{BTreeSet, HashMap};
+
+use http::Method;
+
+/// A `/` separated segment of a route's path.
+enum Segment {
+    /// Matches the segment exactly.
+    Literal(String),
+
+    /// A `{name}` segment matching any single segment.
+    Param,
+
+    /// A trailing `{name:path}` segment matching the rest of the path,
+    /// including no segments at all.
+    Rest,
+}
+
+/// A path pattern along with the methods it allows.
+struct Route {
+    segments: Vec<Segment>,
+    methods: Vec<Method>,
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> bool {
+        let mut parts = split(path);
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Rest => return true,
+                Segment::Param => {
+                    if matches!(parts.next(), None | Some("")) {
+                        return false;
+                    }
+                },
+                Segment::Literal(literal) => {
+                    if parts.next() != Some(literal.as_str()) {
+                        return false;
+                    }
+                },
+            }
+        }
+
+        parts.next().is_none()
+    }
+}
+
+/// The routes registered by the application along with the methods each
+/// allows, used to answer `OPTIONS` requests on the application's behalf.
+///
+/// A `{name}` segment matches any single segment of a path and a trailing
+/// `{name:path}` segment matches whatever is left of it, a trailing slash
+/// is ignored. Every route matching a path adds to the methods allowed.
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// Creates the router from a map of path patterns to the methods
+    /// allowed at them, erroring if a pattern or method is invalid.
+    pub fn new(routes: HashMap<String, Vec<String>>) -> Result<Self, String> {
+        let routes = routes
+            .into_iter()
+            .map(|(pattern, methods)| {
+                let segments = parse_pattern(&pattern)?;
+                let methods = methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                            .map_err(|_| {
+                                format!(
+                                    "invalid method {:?} of route {:?}",
+                                    method, pattern
+                                )
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(Route { segments, methods })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self { routes })
+    }
+
+    /// The value of the `Allow` header for the given path, `None` if no
+    /// route matches it.
+    ///
+    /// `OPTIONS` is always allowed as is `HEAD` wherever `GET` is.
+    pub(crate) fn allow(&self, path: &str) -> Option<String> {
+        let mut allowed = BTreeSet::new();
+        for route in self.routes.iter().filter(|route| route.matches(path)) {
+            allowed.extend(route.methods.iter().map(Method::as_str));
+        }
+
+        if allowed.is_empty() {
+            return None;
+        }
+
+        if allowed.contains(Method::GET.as_str()) {
+            allowed.insert(Method::HEAD.as_str());
+        }
+        allowed.insert(Method::OPTIONS.as_str());
+
+        Some(allowed.into_iter().collect::<Vec<_>>().join(", "))
+    }
+}
+
+/// Splits a path into its segments ignoring the leading and any trailing
+/// slash.
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let path = path.strip_suffix('/').unwrap_or(path);
+    path.split('/').filter(move |_| !path.is_empty())
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, String> {
+    if !pattern.starts_with('/') {
+        return Err(format!("route {:?} must start with a slash", pattern));
+    }
+
+    let parts: Vec<&str> = split(pattern).collect();
+    let mut segments = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let param = part.strip_prefix('{').and_then(|p| p.strip_suffix('}'));
+        let segment = match param {
+            Some(param) if param.ends_with(":path") => {
+                if i + 1 != parts.len() {
+                    return Err(format!(
+                        "route {:?} can only match the rest of the path in its last segment",
+                        pattern
+                    ));
+                }
+                Segment::Rest
+            },
+            Some(_) => Segment::Param,
+            None if part.contains(['{', '}']) => {
+                return Err(format!(
+                    "invalid segment {:?} of route {:?}",
+                    part, pattern
+                ));
+            },
+            None => Segment::Literal(part.to_string()),
+        };
+
+        segments.push(segment);
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+
+    fn router(routes: &[(&str, &[&str])]) -> Router {
+        let routes = routes
+            .iter()
+            .map(|(pattern, methods)| {
+                let methods = methods.iter().map(|m| m.to_string()).collect();
+                (pattern.to_string(), methods)
+            })
+            .collect();
+        Router::new(routes).unwrap()
+    }
+
+    #[test]
+    fn allow_lists_the_methods_of_every_matching_route() {
+        let router = router(&[
+            ("/users", &["GET", "POST"]),
+            ("/users/{id}", &["GET", "put"]),
+            ("/users/me", &["DELETE"]),
+            ("/files/{rest:path}", &["GET"]),
+        ]);
+
+        assert_eq!(router.allow("/users").unwrap(), "GET, HEAD, OPTIONS, POST");
+        assert_eq!(router.allow("/users/").unwrap(), "GET, HEAD, OPTIONS, POST");
+        assert_eq!(router.allow("/users/1").unwrap(), "GET, HEAD, OPTIONS, PUT");
+        assert_eq!(
+            router.allow("/users/me").unwrap(),
+            "DELETE, GET, HEAD, OPTIONS, PUT",
+        );
+        assert_eq!(router.allow("/files").unwrap(), "GET, HEAD, OPTIONS");
+        assert_eq!(
+            router.allow("/files/a/b.txt").unwrap(),
+            "GET, HEAD, OPTIONS"
+        );
+        assert_eq!(router.allow("/users/1/posts"), None);
+        assert_eq!(router.allow("/"), None);
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        let invalid: [(&str, &[&str]); 4] = [
+            ("users", &["GET"]),
+            ("/users/{id", &["GET"]),
+            ("/files/{rest:path}/edit", &["GET"]),
+            ("/users", &["G ET"]),
+        ];
+
+        for (pattern, methods) in invalid {
+            let methods = methods.iter().map(|m| m.to_string()).collect();
+            let routes = vec![(pattern.to_string(), methods)].into_iter().collect();
+            assert!(Router::new(routes).is_err(), "{} was accepted", pattern);
+        }
+    }
+}
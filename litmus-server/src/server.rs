@@ -1,27 +1,72 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use pyo3::prelude::*;
-use pyo3::types::PyTuple;
+use pyo3::types::{PyBytes, PyDict, PyTuple};
 
 use crate::client::ClientHandler;
+use crate::clock::Clock;
 use crate::event_loop::EventLoop;
 use crate::manager::ClientManager;
-use crate::net::{NoneBlockingListener, Status};
-use crate::settings::{ServerSettings, Settings};
+use crate::migration::ConnectionSnapshot;
+use crate::net::{CompletionSocket, NoneBlockingListener, Status, StreamHandle};
+use crate::plugin::ProtocolFactory;
+#[cfg(unix)]
+use crate::poller::{Event, Poller, Token};
+#[cfg(feature = "http3")]
+use crate::quic::Quic;
+use crate::settings::{ConnectionLimitPolicy, IpLimitPolicy, ServerSettings, Settings};
 use crate::traits::RawPollHandler;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+use crate::uring::{Completion, Uring};
+
+pub use crate::event_loop::SocketFd;
+
+/// The response sent to connections turned away by the connection limit.
+const CONNECTION_LIMIT_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+    content-length: 0\r\n\
+    connection: close\r\n\r\n";
+
+/// The response sent to connections turned away by the per-address limit.
+const IP_LIMIT_RESPONSE: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\n\
+    content-length: 0\r\n\
+    connection: close\r\n\r\n";
 
 /// A cheaply cloneable helper function that wraps a python callback.
 #[derive(Clone)]
 pub(crate) struct CallbackHandler {
     /// The python callback itself.
     cb: Arc<PyObject>,
+
+    /// An optional user installed callback which is invoked with any
+    /// exceptions raised by the application.
+    on_error: Option<Arc<PyObject>>,
 }
 
 impl CallbackHandler {
     /// Creates a new instance of this struct wrapping the PyObject in a
     /// arc to make for cheap clones.
-    pub(crate) fn new(cb: PyObject) -> Self {
-        Self { cb: Arc::new(cb) }
+    pub(crate) fn new(cb: PyObject, on_error: Option<PyObject>) -> Self {
+        Self {
+            cb: Arc::new(cb),
+            on_error: on_error.map(Arc::new),
+        }
+    }
+
+    /// Reports an error raised by the application.
+    ///
+    /// The error is passed to the error callback if one is installed
+    /// otherwise the traceback is printed, if the error callback itself
+    /// raises an error it is logged and otherwise silenced.
+    pub(crate) fn report_error(&self, py: Python, err: PyErr) {
+        let cb = match self.on_error.as_ref() {
+            Some(cb) => cb,
+            None => return err.print(py),
+        };
+
+        if let Err(e) = cb.call1(py, (err.instance(py),)) {
+            error!("error callback raised an exception: {}", e);
+        }
     }
 
     /// Invokes the callback by acquiring the gil internally.
@@ -43,29 +88,116 @@ pub struct Server {
 
     listeners: Vec<NoneBlockingListener>,
 
+    /// The callback registering a listener with the event loop, kept so
+    /// the listeners can be registered again once accepting resumes.
+    accept_callback: Option<PyObject>,
+
+    /// If the listeners have been removed from the event loop because
+    /// the connection limit was reached.
+    accept_paused: bool,
+
     manager: Option<ClientManager<ClientHandler>>,
+
+    /// The HTTP/3 listeners if any were bound.
+    #[cfg(feature = "http3")]
+    quic: Option<Quic>,
 }
 
 impl Server {
     #[timed::timed(duration(printer = "trace!"))]
+    #[allow(clippy::too_many_arguments)]
     pub fn connect(
         settings: ServerSettings,
         callback: PyObject,
+        error_callback: Option<PyObject>,
         binders: Vec<&str>,
+        http3_binders: Vec<&str>,
+        protocol_binders: Vec<(&str, Arc<dyn ProtocolFactory>)>,
+        reuse_port: bool,
+        listener_fds: Vec<SocketFd>,
+        unix_socket_mode: Option<u32>,
+        socket_activation: bool,
     ) -> PyResult<Self> {
-        let mut listeners = Vec::new();
+        let mut listeners = if socket_activation {
+            NoneBlockingListener::systemd_listeners()?
+        } else {
+            Vec::new()
+        };
+        for listener in listeners.iter() {
+            info!("activated listener on {}", listener.location());
+        }
+
+        // The sockets systemd passed replace the ones we'd bind.
+        let binders = if listeners.is_empty() {
+            binders
+        } else {
+            Vec::new()
+        };
+
+        // An IPv6 listener sharing a port with an IPv4 listener only takes
+        // IPv6 connections, otherwise both can't be bound at once.
+        let v4_ports: Vec<u16> = binders
+            .iter()
+            .filter_map(|bind| bind.parse::<SocketAddr>().ok())
+            .filter(|addr| addr.is_ipv4())
+            .map(|addr| addr.port())
+            .collect();
+
         for bind in binders {
             info!("binding to {}", bind);
-            let listener = NoneBlockingListener::bind(bind)?;
+            let v6_only = bind
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| v4_ports.contains(&addr.port()));
+            let listener =
+                NoneBlockingListener::bind(bind, reuse_port, v6_only, unix_socket_mode)?;
             listeners.push(listener);
         }
 
+        for (bind, protocol) in protocol_binders {
+            info!("binding custom protocol to {}", bind);
+            let listener =
+                NoneBlockingListener::bind(bind, reuse_port, false, unix_socket_mode)?;
+            listeners.push(listener.with_protocol(protocol));
+        }
+
+        for fd in listener_fds {
+            let listener = unsafe { NoneBlockingListener::from_fd(fd)? };
+            info!("inherited listener on {}", listener.location());
+            listeners.push(listener);
+        }
+
+        let settings: Settings = Arc::from(settings);
+
+        #[cfg(feature = "http3")]
+        let quic = match (http3_binders.is_empty(), settings.tls.as_ref()) {
+            (true, _) => None,
+            (false, Some(tls)) => {
+                Some(Quic::bind(&http3_binders, tls, settings.clone())?)
+            },
+            (false, None) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "HTTP/3 requires TLS to be enabled",
+                ))
+            },
+        };
+
+        #[cfg(not(feature = "http3"))]
+        if !http3_binders.is_empty() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "HTTP/3 requires building with the http3 feature",
+            ));
+        }
+
         Ok(Self {
-            settings: Arc::from(settings),
-            callback: CallbackHandler::new(callback),
+            settings,
+            callback: CallbackHandler::new(callback, error_callback),
             listeners,
+            accept_callback: None,
+            accept_paused: false,
             event_loop: None,
             manager: None,
+            #[cfg(feature = "http3")]
+            quic,
         })
     }
 
@@ -79,23 +211,374 @@ impl Server {
         }
     }
 
+    /// Installs the event loop creating the client manager with it, the
+    /// clients of HTTP/3 requests register with the QUIC driver instead.
+    fn install(&mut self, event_loop: EventLoop) {
+        #[cfg(feature = "http3")]
+        let event_loop = match self.quic.as_mut() {
+            Some(quic) => {
+                quic.set_clock(event_loop.clock().clone());
+                event_loop.with_quic(quic.signals())
+            },
+            None => event_loop,
+        };
+
+        self.manager.replace(ClientManager::new(
+            self.callback.clone(),
+            event_loop.clone(),
+            self.settings.clone(),
+        ));
+        self.event_loop.replace(event_loop);
+    }
+
     #[inline]
     fn manager(&mut self) -> &mut ClientManager<ClientHandler> {
         self.manager.as_mut().expect("initialised")
     }
+
+    /// Runs `f` batching the registrations it makes with the event loop,
+    /// applying them all once it returns whether or not it succeeded.
+    fn batched<T>(&mut self, f: impl FnOnce(&mut Self) -> PyResult<T>) -> PyResult<T> {
+        let event_loop = self.event_loop().clone();
+        event_loop.begin_batch();
+        let result = f(self);
+        let applied = event_loop.end_batch();
+
+        let value = result?;
+        applied.map(|_| value)
+    }
+
+    /// The number of connections which can be accepted before reaching
+    /// the connection limit, `None` if there is no limit.
+    fn available_connections(&mut self) -> Option<usize> {
+        let limit = self.settings.connection_limit?;
+        Some(limit.max.saturating_sub(self.manager().len_active()))
+    }
+
+    /// Counts a new connection against its address if there's a
+    /// per-address limit, returning the policy to turn the connection
+    /// away with if the address is over the limit.
+    fn limit_ip(&self, conn: &mut StreamHandle) -> PyResult<Option<IpLimitPolicy>> {
+        let limiter = match self.settings.ip_limiter.as_ref() {
+            Some(limiter) => limiter,
+            None => return Ok(None),
+        };
+
+        match limiter.acquire(conn.addr, self.event_loop().now()?) {
+            Ok(slot) => {
+                conn.set_ip_slot(slot);
+                Ok(None)
+            },
+            Err(reason) => {
+                debug!(
+                    "{} from {}, turning away connection",
+                    reason,
+                    conn.addr.ip()
+                );
+                Ok(Some(limiter.limit().policy))
+            },
+        }
+    }
+
+    /// Stops accepting new connections by removing the listeners from
+    /// the event loop, new connections wait in the listen backlog.
+    fn pause_accepting(&mut self) -> PyResult<()> {
+        if self.accept_paused {
+            return Ok(());
+        }
+
+        warn!("connection limit reached, pausing accepting connections");
+        for listener in self.listeners.iter() {
+            self.event_loop().remove_reader(listener.fd())?;
+        }
+        self.accept_paused = true;
+
+        Ok(())
+    }
+
+    /// Hands the client at the given index data received for it, `None`
+    /// marks the EOF, see `poll_received()`.
+    fn received(
+        &mut self,
+        py: Python,
+        index: usize,
+        data: Option<&[u8]>,
+    ) -> PyResult<()> {
+        self.manager().poll_received(index, data)?;
+        self.poll_connection_limit(py)
+    }
+
+    /// Resumes accepting connections if accepting was paused by the
+    /// connection limit and enough connections have since closed.
+    fn poll_connection_limit(&mut self, py: Python) -> PyResult<()> {
+        if !self.accept_paused
+            | self.settings.is_draining()
+            | (self.available_connections() == Some(0))
+        {
+            return Ok(());
+        }
+
+        self.accept_paused = false;
+        info!("resuming accepting connections");
+        self.register_listeners(py)
+    }
+
+    /// Registers the listeners with the event loop, either with the native
+    /// poller or through the accept callback given when ignited.
+    fn register_listeners(&self, py: Python) -> PyResult<()> {
+        #[cfg(unix)]
+        if let Some(poller) = self.event_loop().poller() {
+            for (index, listener) in self.listeners.iter().enumerate() {
+                poller.add_reader(listener.fd(), Token::Listener(index))?;
+            }
+
+            return Ok(());
+        }
+
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        if let Some(uring) = self.event_loop().ring() {
+            for listener in self.listeners.iter() {
+                uring.add_listener(listener.fd());
+            }
+
+            return Ok(());
+        }
+
+        let callback = match self.accept_callback.as_ref() {
+            Some(cb) => cb,
+            None => return Ok(()),
+        };
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let _ = callback.call1(py, (listener.fd(), index))?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads ahead from every client in the batch of events which is ready
+    /// to be read from, spread over the io threads with the GIL released.
+    #[cfg(unix)]
+    fn prefetch(&mut self, py: Python, poller: &Poller, events: &[Event]) {
+        let mut indexes: Vec<usize> = events
+            .iter()
+            .filter_map(|event| match event.token {
+                Token::Client(index)
+                    if event.readable & poller.interest(event.fd, event.token).0 =>
+                {
+                    Some(index)
+                },
+                _ => None,
+            })
+            .collect();
+
+        // A lone connection is read quicker on this thread.
+        if indexes.len() < 2 {
+            return;
+        }
+
+        indexes.sort_unstable();
+        let threads = self.settings.io_threads;
+        let manager = self.manager();
+        py.allow_threads(|| manager.prefetch(&indexes, threads));
+    }
+
+    /// Handles a single event of the native poller, skipping any readiness
+    /// the handler is no longer listening for.
+    #[cfg(unix)]
+    fn dispatch(&mut self, py: Python, poller: &Poller, event: Event) -> PyResult<()> {
+        let (read, _) = poller.interest(event.fd, event.token);
+
+        match event.token {
+            Token::Listener(index) => {
+                if event.readable & read {
+                    self.poll_accept(index)?;
+                }
+            },
+            Token::Client(index) => {
+                if event.readable & read {
+                    self.poll_read(py, index)?;
+                }
+
+                // Reading may have removed the writer, e.g. once closed.
+                let (_, write) = poller.interest(event.fd, event.token);
+                if event.writable & write {
+                    self.poll_write(py, index)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Adopts a connection whose I/O is performed by whatever accepted it,
+    /// see `adopt()`, the handle is only created once the connection is
+    /// known not to be turned away.
+    fn adopt_socket(
+        &mut self,
+        fd: SocketFd,
+        handle: impl FnOnce() -> StreamHandle,
+    ) -> PyResult<Option<usize>> {
+        if self.settings.is_draining() {
+            return Ok(None);
+        }
+
+        // The connection has already been accepted so it's turned away
+        // regardless of the policy, the listeners are paused as well though
+        // with the pause policy.
+        if self.available_connections() == Some(0) {
+            debug!("connection limit reached, rejecting connection");
+            match self.settings.connection_limit.map(|limit| limit.policy) {
+                Some(ConnectionLimitPolicy::Pause) => self.pause_accepting()?,
+                _ => {
+                    #[cfg(feature = "tls")]
+                    let response = match self.settings.tls {
+                        Some(_) => &b""[..],
+                        None => CONNECTION_LIMIT_RESPONSE,
+                    };
+                    #[cfg(not(feature = "tls"))]
+                    let response = CONNECTION_LIMIT_RESPONSE;
+
+                    CompletionSocket::reject(fd, response);
+                },
+            }
+
+            return Ok(None);
+        }
+
+        let mut conn = handle();
+        if let Some(policy) = self.limit_ip(&mut conn)? {
+            CompletionSocket::reject(fd, ip_limit_response(&self.settings, policy));
+            return Ok(None);
+        }
+
+        #[cfg(feature = "tls")]
+        let conn = match self.settings.tls.as_ref() {
+            Some(tls) => conn.with_tls(tls)?,
+            None => conn,
+        };
+
+        self.manager().handle_connection(conn).map(Some)
+    }
+
+    /// Adopts a connection accepted by the io_uring driver, closing the
+    /// socket if it's turned away.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    fn adopt_ring(&mut self, uring: &Arc<Uring>, fd: SocketFd) -> PyResult<()> {
+        // Registered first as the client starts reading while adopted.
+        let token = uring.register(fd);
+        let hook = uring.shutdown_hook(token);
+
+        match self.adopt_socket(fd, || StreamHandle::from_ring(fd, hook)) {
+            Ok(Some(index)) => {
+                uring.bind(token, index);
+                Ok(())
+            },
+            Ok(None) => {
+                uring.discard(token);
+                Ok(())
+            },
+            Err(e) => {
+                uring.discard(token);
+                Err(e)
+            },
+        }
+    }
+
+    /// Handles a single completion of the io_uring driver.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    fn complete(
+        &mut self,
+        py: Python,
+        uring: &Arc<Uring>,
+        completion: Completion,
+    ) -> PyResult<()> {
+        match completion {
+            Completion::Accepted(fd) => self.adopt_ring(uring, fd),
+            Completion::Received(index, Some(buffer)) => {
+                let result = self
+                    .manager()
+                    .poll_received(index, Some(uring.buffer(&buffer)));
+                uring.release_buffer(buffer);
+                result?;
+                self.poll_connection_limit(py)
+            },
+            Completion::Received(index, None) => self.received(py, index, None),
+            Completion::Failed(index) => self.poll_close(py, index),
+        }
+    }
+
+    /// Drives a connection of the io_uring driver, the same way as the
+    /// completion event loop in Python, sending anything queued and
+    /// telling the client it can read or write.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    fn pump(
+        &mut self,
+        py: Python,
+        uring: &Uring,
+        token: usize,
+        index: usize,
+    ) -> PyResult<()> {
+        loop {
+            let outbound = match self.manager().take_outbound(index)? {
+                Some(outbound) => outbound,
+                None => {
+                    uring.detach(token)?;
+                    return Ok(());
+                },
+            };
+
+            if !outbound.is_empty() {
+                uring.send(token, outbound)?;
+                continue;
+            }
+
+            if uring.take_pending_read(token) {
+                self.received(py, index, Some(b""))?;
+
+                // The client yielded with more left to read, it's read again
+                // on the next wakeup once the other connections are driven.
+                if uring.has_pending_read(token) {
+                    uring.mark_dirty(token);
+                    break;
+                }
+                continue;
+            }
+
+            // Everything queued has been sent so the socket is writable,
+            // the client is polled again on the next wakeup if it still
+            // wants to write without having queued anything.
+            if uring.is_writable(token) {
+                self.poll_write(py, index)?;
+                if uring.is_writable(token) {
+                    uring.mark_dirty(token);
+                    break;
+                }
+                continue;
+            }
+
+            break;
+        }
+
+        Ok(uring.poll_connection(token)?)
+    }
 }
 
 #[pymethods]
 impl Server {
     /// Run litmus
+    ///
+    /// The accept callback is ignored when using the native backend as the
+    /// listeners are registered with the server's own poller.
     fn ignite(&mut self, py: Python, accept_callback: PyObject) -> PyResult<()> {
         let start = std::time::Instant::now();
-        for (index, listener) in self.listeners.iter().enumerate() {
-            let fd = listener.fd();
-            let _ = accept_callback.call1(py, (fd, index))?;
+        self.accept_callback = Some(accept_callback);
+        self.register_listeners(py)?;
+
+        for listener in self.listeners.iter() {
             info!(
-                "listener on http://{} ready to accept connection",
-                &listener.addr
+                "listener on {} ready to accept connection",
+                listener.location()
             );
         }
 
@@ -107,6 +590,18 @@ impl Server {
         Ok(())
     }
 
+    /// Initialises the server with the event loop callbacks.
+    ///
+    /// If `loop_time` is given it is used as the clock for any timeouts,
+    /// otherwise timeouts are measured with a monotonic clock.
+    ///
+    /// Readers and writers added or removed while the server handles an
+    /// event are applied together once it's done, if `apply_registrations`
+    /// is given it's called once with a list of `(op, fd, index)` where op
+    /// is 0 to add a reader, 1 to remove one, 2 to add a writer and 3 to
+    /// remove one.
+    #[args(loop_time = "None", apply_registrations = "None")]
+    #[allow(clippy::too_many_arguments)]
     fn init(
         &mut self,
         add_reader: PyObject,
@@ -114,6 +609,8 @@ impl Server {
         add_writer: PyObject,
         remove_writer: PyObject,
         close_socket: PyObject,
+        loop_time: Option<PyObject>,
+        apply_registrations: Option<PyObject>,
     ) {
         let event_loop = EventLoop::new(
             add_reader,
@@ -121,23 +618,486 @@ impl Server {
             add_writer,
             remove_writer,
             close_socket,
+            apply_registrations,
+            Clock::new(loop_time),
         );
 
-        self.event_loop.replace(event_loop);
+        self.install(event_loop);
+    }
 
-        self.manager.replace(ClientManager::new(
-            self.callback.clone(),
-            self.event_loop().clone(),
-            self.settings.clone(),
-        ));
+    /// Initialises the server with a native epoll / kqueue poller rather
+    /// than the asyncio event loop's `add_reader` and `add_writer`.
+    ///
+    /// The poller's file descriptor given by `poller_fd()` should be watched
+    /// for read readiness, calling `poll_native()` each time it's readable.
+    #[args(loop_time = "None")]
+    fn init_native(&mut self, loop_time: Option<PyObject>) -> PyResult<()> {
+        #[cfg(unix)]
+        {
+            let poller = Arc::new(Poller::new()?);
+            self.install(EventLoop::native(poller, Clock::new(loop_time)));
+
+            Ok(())
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = loop_time;
+            Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "the native backend is not supported on this platform",
+            ))
+        }
+    }
+
+    /// Initialises the server with an io_uring driver performing all
+    /// socket I/O rather than the asyncio event loop, this is only
+    /// available on Linux when built with the `io_uring` feature.
+    ///
+    /// The driver's file descriptor given by `poller_fd()` should be
+    /// watched for read readiness, calling `poll_uring()` each time it's
+    /// readable.
+    #[args(loop_time = "None")]
+    fn init_uring(&mut self, loop_time: Option<PyObject>) -> PyResult<()> {
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        {
+            if self.listeners.iter().any(|l| l.protocol().is_some()) {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "custom protocols are not supported by the io_uring backend",
+                ));
+            }
+
+            let uring = Arc::new(Uring::new()?);
+            self.install(EventLoop::uring(uring, Clock::new(loop_time)));
+
+            Ok(())
+        }
+
+        #[cfg(not(all(target_os = "linux", feature = "io_uring")))]
+        {
+            let _ = loop_time;
+            Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "the io_uring backend requires Linux and building with the io_uring feature",
+            ))
+        }
+    }
+
+    /// The file descriptor of the native poller or io_uring driver if the
+    /// server was initialised with `init_native()` or `init_uring()`.
+    fn poller_fd(&self) -> Option<SocketFd> {
+        #[cfg(unix)]
+        {
+            let event_loop = self.event_loop.as_ref()?;
+
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            if let Some(uring) = event_loop.ring() {
+                return Some(uring.fd());
+            }
+
+            event_loop.poller().map(|poller| poller.fd())
+        }
+
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Handles every event that is ready on the native poller along with
+    /// any sockets scheduled to be closed.
+    ///
+    /// An error handling one connection is logged rather than raised so
+    /// the rest of the events still get handled.
+    #[cfg(unix)]
+    #[timed::timed(duration(printer = "trace!"))]
+    fn poll_native(&mut self, py: Python) -> PyResult<()> {
+        let poller = match self.event_loop().poller() {
+            Some(poller) => poller.clone(),
+            None => {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "server was not initialised with the native backend",
+                ))
+            },
+        };
+
+        let mut events = Vec::new();
+        poller.poll(&mut events)?;
+
+        if self.settings.io_threads > 0 {
+            self.prefetch(py, &poller, &events);
+        }
+
+        for event in events {
+            if let Err(e) = self.dispatch(py, &poller, event) {
+                error!("failed to handle event {:?}: {}", event, e);
+            }
+        }
+
+        for index in poller.take_closing() {
+            if let Err(e) = self.poll_close(py, index) {
+                error!("failed to close client {}: {}", index, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles every completion posted to the io_uring driver and drives
+    /// any connection with something to do.
+    ///
+    /// As with `poll_native()` an error handling one connection is logged
+    /// rather than raised.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    #[timed::timed(duration(printer = "trace!"))]
+    fn poll_uring(&mut self, py: Python) -> PyResult<()> {
+        let uring = match self.event_loop().ring() {
+            Some(uring) => uring.clone(),
+            None => {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "server was not initialised with the io_uring backend",
+                ))
+            },
+        };
+
+        uring.start_polling();
+        for completion in uring.reap() {
+            if let Err(e) = self.complete(py, &uring, completion) {
+                error!("failed to handle io_uring completion: {}", e);
+            }
+        }
+
+        for index in uring.take_closing() {
+            if let Err(e) = self.poll_close(py, index) {
+                error!("failed to close client {}: {}", index, e);
+            }
+        }
+
+        for (token, index) in uring.take_dirty()? {
+            if let Err(e) = self.pump(py, &uring, token, index) {
+                error!("failed to drive client {}: {}", index, e);
+            }
+        }
+
+        Ok(uring.finish_polling()?)
+    }
+
+    /// Sets the callback scheduling `poll_quic()` to be called, this must
+    /// not call it directly as it's invoked while the server is borrowed.
+    ///
+    /// The sockets given by `quic_fds()` should be watched for read
+    /// readiness calling `poll_quic()` each time one is readable, along
+    /// with once the time it returns has passed.
+    fn init_quic(&mut self, wake: PyObject) -> PyResult<()> {
+        #[cfg(feature = "http3")]
+        {
+            match self.quic.as_ref() {
+                Some(quic) => {
+                    quic.set_wake(Some(wake));
+                    Ok(())
+                },
+                None => Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "server has no HTTP/3 listeners",
+                )),
+            }
+        }
+
+        #[cfg(not(feature = "http3"))]
+        {
+            let _ = wake;
+            Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "HTTP/3 requires building with the http3 feature",
+            ))
+        }
+    }
+
+    /// The file descriptors of the HTTP/3 listeners' UDP sockets.
+    fn quic_fds(&self) -> Vec<SocketFd> {
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic.as_ref() {
+            return quic.fds();
+        }
+
+        Vec::new()
+    }
+
+    /// Handles everything the HTTP/3 listeners have to do, returning the
+    /// number of seconds until this should be called again if nothing
+    /// else wakes it before then.
+    #[cfg(feature = "http3")]
+    #[timed::timed(duration(printer = "trace!"))]
+    fn poll_quic(&mut self, py: Python) -> PyResult<Option<f64>> {
+        let (quic, manager) = match (self.quic.as_mut(), self.manager.as_mut()) {
+            (Some(quic), Some(manager)) => (quic, manager),
+            _ => {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "server has no HTTP/3 listeners or was not initialised",
+                ))
+            },
+        };
+
+        let timeout = quic.poll(manager)?;
+        self.poll_connection_limit(py)?;
+
+        Ok(timeout.map(|timeout| timeout.as_secs_f64()))
     }
 
     fn len_clients(&mut self) -> usize {
         self.manager().len_clients()
     }
 
+    /// The number of clients with an open connection.
+    fn len_active(&mut self) -> usize {
+        self.manager().len_active()
+    }
+
+    /// Starts a graceful shutdown of the server, e.g. upon receiving a
+    /// `SIGTERM`.
+    ///
+    /// The listeners are removed from the event loop so no new connections
+    /// are accepted, idle keep-alive connections are closed and every other
+    /// connection is closed once its current response completes.
+    /// Calling this again while already shutting down does nothing more.
+    ///
+    /// Returns the number of connections still open, once this reaches
+    /// zero the server can be shutdown without interrupting any requests.
+    /// Calling `shutdown()` before then forcibly closes them.
+    fn initiate_shutdown(&mut self) -> PyResult<usize> {
+        if !self.settings.is_draining() {
+            self.settings.start_draining();
+
+            for listener in self.listeners.iter() {
+                self.event_loop().remove_reader(listener.fd())?;
+            }
+
+            info!("shutting down, waiting on open connections to finish");
+            self.manager().drain()?;
+
+            #[cfg(feature = "http3")]
+            if let Some(quic) = self.quic.as_mut() {
+                quic.drain()?;
+            }
+        }
+
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic.as_ref() {
+            return Ok(self.manager.as_mut().map_or(0, |m| m.len_active())
+                + quic.len_connections());
+        }
+
+        Ok(self.manager().len_active())
+    }
+
+    fn len_pooled(&mut self) -> usize {
+        self.manager().len_pooled()
+    }
+
+    /// Takes a snapshot of the client pool's counters as a dict, the number
+    /// of clients `created`, the number of connections bound to a `reused`
+    /// client, the number of `active` connections and the number of idle
+    /// clients `pooled`.
+    fn client_pool_stats(&mut self, py: Python) -> PyResult<PyObject> {
+        let active = self.manager().len_active();
+        let stats = self.manager().pool_stats();
+
+        let dict = PyDict::new(py);
+        dict.set_item("created", stats.created)?;
+        dict.set_item("reused", stats.reused)?;
+        dict.set_item("active", active)?;
+        dict.set_item("pooled", stats.pooled)?;
+
+        Ok(dict.into())
+    }
+
+    /// Takes a snapshot of the server's metrics as a dict.
+    ///
+    /// The status code counts map each status sent to the number of
+    /// responses with it and the latency buckets are cumulative pairs of
+    /// the bucket's upper bound in seconds and the number of requests
+    /// taking at most that long, as with a Prometheus histogram.
+    fn metrics(&mut self, py: Python) -> PyResult<PyObject> {
+        let active = self.manager().len_active();
+        self.settings.metrics.set_active_connections(active);
+        let snapshot = self.settings.metrics.snapshot();
+
+        let statuses = PyDict::new(py);
+        for (status, count) in snapshot.statuses {
+            statuses.set_item(status, count)?;
+        }
+
+        let latency = PyDict::new(py);
+        latency.set_item("buckets", snapshot.latency_buckets)?;
+        latency.set_item("count", snapshot.latency_count)?;
+        latency.set_item("sum", snapshot.latency_sum)?;
+
+        let metrics = PyDict::new(py);
+        metrics.set_item("active_connections", snapshot.active_connections)?;
+        metrics.set_item("connections_total", snapshot.connections_total)?;
+        metrics.set_item("requests_total", snapshot.requests_total)?;
+        metrics.set_item("requests_per_second", snapshot.requests_per_second)?;
+        metrics.set_item("status_codes", statuses)?;
+        metrics.set_item("bytes_received", snapshot.bytes_received)?;
+        metrics.set_item("bytes_sent", snapshot.bytes_sent)?;
+        metrics.set_item("latency", latency)?;
+
+        Ok(metrics.into())
+    }
+
+    /// The number of free read and write buffers held by the buffer pool.
+    fn len_pooled_buffers(&self) -> usize {
+        self.settings.buffers.len()
+    }
+
+    /// Turns maintenance mode on or off, while on requests are answered
+    /// with the configured static response without invoking the application.
+    fn set_maintenance(&self, enabled: bool) {
+        self.settings.maintenance.set_enabled(enabled);
+    }
+
+    /// If the server is currently in maintenance mode.
+    fn maintenance(&self) -> bool {
+        self.settings.maintenance.is_enabled()
+    }
+
+    /// The max number of open connections if a limit is set.
+    fn max_connections(&self) -> Option<usize> {
+        self.settings.connection_limit.map(|limit| limit.max)
+    }
+
+    /// If the listeners are paused because the connection limit was
+    /// reached.
+    fn accept_paused(&self) -> bool {
+        self.accept_paused
+    }
+
+    /// Takes a snapshot of the connection at the given index so it can be
+    /// migrated to another process, detaching the server from it.
+    ///
+    /// The connection must be between requests, the socket itself is left
+    /// open and should be handed off along with the returned snapshot.
+    fn snapshot(&mut self, py: Python, index: usize) -> PyResult<Py<PyBytes>> {
+        let snapshot = self.manager().snapshot(index)?;
+        Ok(Py::from(PyBytes::new(py, &snapshot.encode())))
+    }
+
+    /// Restores a migrated connection from its socket's file descriptor
+    /// and the snapshot taken by the previous server, returning the index
+    /// of the new client.
+    ///
+    /// The server takes ownership of the file descriptor.
+    fn restore(&mut self, fd: SocketFd, snapshot: &[u8]) -> PyResult<usize> {
+        #[cfg(feature = "tls")]
+        if self.settings.tls.is_some() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "connections cannot be migrated while TLS is enabled",
+            ));
+        }
+
+        let snapshot = ConnectionSnapshot::decode(snapshot)?;
+        let mut conn = unsafe { StreamHandle::from_fd(fd)? };
+        if let Some(limiter) = self.settings.ip_limiter.as_ref() {
+            conn.set_ip_slot(limiter.register(conn.addr));
+        }
+
+        self.manager().restore(conn, snapshot)
+    }
+
+    /// Adopts a connection accepted by a completion based event loop, e.g.
+    /// the proactor on Windows, returning the index of the new client.
+    ///
+    /// The socket stays owned by the event loop which performs all I/O with
+    /// it, anything it receives should be passed to `poll_received()` and
+    /// anything queued to be sent taken with `take_outbound()`. The add and
+    /// remove reader and writer callbacks mark when the client wants to be
+    /// told it can read or write, `poll_read()` and `poll_write()` should be
+    /// called accordingly. `on_shutdown` is called once the client shuts the
+    /// socket down, it should be closed once everything queued is sent.
+    ///
+    /// Returns `None` if the connection was turned away as the server is
+    /// draining or the connection limit was reached, either server-wide or
+    /// for its address, the socket should then be closed.
+    ///
+    /// `listener` is the index of the listener the connection was accepted
+    /// on, given so any custom protocol of the listener is served on it.
+    #[args(listener = "None")]
+    fn adopt(
+        &mut self,
+        fd: SocketFd,
+        on_shutdown: PyObject,
+        listener: Option<usize>,
+    ) -> PyResult<Option<usize>> {
+        let protocol = listener
+            .and_then(|index| self.listeners.get(index))
+            .and_then(|listener| listener.protocol().cloned());
+
+        self.adopt_socket(fd, || {
+            StreamHandle::from_completion(fd, on_shutdown).with_protocol(protocol)
+        })
+    }
+
+    /// Hands the client at the given index data received by the event loop
+    /// for connections adopted with `adopt()`, `None` marks the EOF.
+    ///
+    /// An empty buffer hands over no new data but reads anything held
+    /// since the client stopped reading, e.g. once the client starts
+    /// reading again.
+    #[timed::timed(duration(printer = "trace!"))]
+    fn poll_received(
+        &mut self,
+        py: Python,
+        index: usize,
+        data: Option<Py<PyBytes>>,
+    ) -> PyResult<()> {
+        let data = data.as_ref().map(|data| data.as_ref(py).as_bytes());
+        self.received(py, index, data)
+    }
+
+    /// Takes the data queued to be sent by the event loop for the client at
+    /// the given index, `None` once the client has shut the socket down and
+    /// everything queued has been taken.
+    fn take_outbound(
+        &mut self,
+        py: Python,
+        index: usize,
+    ) -> PyResult<Option<Py<PyBytes>>> {
+        let outbound = self.manager().take_outbound(index)?;
+        Ok(outbound.map(|data| Py::from(PyBytes::new(py, &data))))
+    }
+
+    /// The file descriptors of the server's listeners, these can be
+    /// inherited by other workers to accept on the same sockets.
+    fn listener_fds(&self) -> Vec<SocketFd> {
+        self.listeners.iter().map(|l| l.fd()).collect()
+    }
+
+    /// Prepares the listeners to be handed off to another process,
+    /// returning their file descriptors to be duplicated for it.
+    ///
+    /// Any unix socket files are left in place once this server shuts
+    /// down as the other process keeps accepting on them.
+    fn prepare_handoff(&mut self) -> Vec<SocketFd> {
+        for listener in self.listeners.iter_mut() {
+            listener.disown();
+        }
+
+        self.listener_fds()
+    }
+
+    /// Gets the last error that occurred on the connection at the given
+    /// index, this is cleared once the client is reused.
+    fn last_error(&mut self, index: usize) -> PyResult<Option<String>> {
+        self.manager().last_error(index)
+    }
+
     #[timed::timed(duration(printer = "trace!"))]
     fn poll_accept(&mut self, index: usize) -> PyResult<()> {
+        if self.settings.is_draining() {
+            return Ok(());
+        }
+
+        let mut available = self.available_connections();
+        let policy = self.settings.connection_limit.map(|limit| limit.policy);
+        let pause = matches!(policy, Some(ConnectionLimitPolicy::Pause));
+        if pause & (available == Some(0)) {
+            return self.pause_accepting();
+        }
+
         let listener = &self.listeners[index];
 
         let mut accepted = Vec::new();
@@ -145,40 +1105,122 @@ impl Server {
         for _ in 0..backlog {
             let maybe_handle = listener.accept()?;
 
-            match maybe_handle {
-                Status::Successful(conn) => accepted.push(conn),
+            let mut conn = match maybe_handle {
+                Status::Successful(conn) => conn,
                 Status::ShouldPause => break,
+            };
+
+            if available == Some(0) {
+                debug!("connection limit reached, rejecting {:?}", conn.addr);
+                reject_connection(&self.settings, conn);
+            } else if let Some(policy) = self.limit_ip(&mut conn)? {
+                conn.reject(ip_limit_response(&self.settings, policy));
+            } else {
+                if let Some(n) = available.as_mut() {
+                    *n -= 1;
+                }
+                accepted.push(conn);
+            }
+
+            if pause & (available == Some(0)) {
+                break;
             }
         }
 
-        let manager = self.manager();
-        for conn in accepted {
-            manager.handle_connection(conn)?;
+        #[cfg(feature = "tls")]
+        let accepted = match self.settings.tls.as_ref() {
+            Some(tls) => accepted
+                .into_iter()
+                .map(|conn| conn.with_tls(tls))
+                .collect::<PyResult<Vec<_>>>()?,
+            None => accepted,
+        };
+
+        self.batched(|server| {
+            let manager = server.manager();
+            for conn in accepted {
+                manager.handle_connection(conn)?;
+            }
+
+            Ok(())
+        })?;
+
+        if pause & (available == Some(0)) {
+            self.pause_accepting()?;
         }
 
         Ok(())
     }
 
     #[timed::timed(duration(printer = "trace!"))]
-    fn poll_read(&mut self, index: usize) -> PyResult<()> {
-        self.manager().poll_read(index)
+    fn poll_read(&mut self, py: Python, index: usize) -> PyResult<()> {
+        self.batched(|server| server.manager().poll_read(index))?;
+        self.poll_connection_limit(py)
     }
 
     #[timed::timed(duration(printer = "trace!"))]
-    fn poll_write(&mut self, index: usize) -> PyResult<()> {
-        self.manager().poll_write(index)
+    fn poll_write(&mut self, py: Python, index: usize) -> PyResult<()> {
+        self.batched(|server| server.manager().poll_write(index))?;
+        self.poll_connection_limit(py)
     }
 
     #[timed::timed(duration(printer = "trace!"))]
-    fn poll_close(&mut self, index: usize) -> PyResult<()> {
-        self.manager().poll_close(index)
+    fn poll_close(&mut self, py: Python, index: usize) -> PyResult<()> {
+        self.batched(|server| server.manager().poll_close(index))?;
+        self.poll_connection_limit(py)
     }
 
-    fn poll_keep_alive(&mut self) -> PyResult<()> {
-        self.manager().poll_keep_alive()
+    fn poll_keep_alive(&mut self, py: Python) -> PyResult<()> {
+        self.batched(|server| server.manager().poll_keep_alive())?;
+        self.poll_connection_limit(py)
     }
 
     fn shutdown(&mut self) -> PyResult<()> {
+        // The ring must be done with the listeners before they're closed.
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        if let Some(uring) = self.event_loop.as_ref().and_then(|el| el.ring()) {
+            uring.shutdown();
+        }
+
+        #[cfg(feature = "http3")]
+        if let Some(quic) = self.quic.as_mut() {
+            quic.shutdown()?;
+        }
+
+        // Closes the listeners removing any unix socket files.
+        self.listeners.clear();
         self.manager().shutdown()
     }
 }
+
+/// Turns away a connection accepted over the connection limit.
+///
+/// TLS connections are closed without a response as the handshake
+/// would have to be completed first.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn reject_connection(settings: &ServerSettings, conn: StreamHandle) {
+    #[cfg(feature = "tls")]
+    if settings.tls.is_some() {
+        return conn.reject(b"");
+    }
+
+    conn.reject(CONNECTION_LIMIT_RESPONSE)
+}
+
+/// The response sent to a connection turned away by the per-address limit
+/// with the given policy.
+///
+/// TLS connections are closed without a response as the handshake
+/// would have to be completed first.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn ip_limit_response(settings: &ServerSettings, policy: IpLimitPolicy) -> &'static [u8] {
+    #[cfg(feature = "tls")]
+    if settings.tls.is_some() {
+        return b"";
+    }
+
+    match policy {
+        IpLimitPolicy::Close => b"",
+        IpLimitPolicy::Reject => IP_LIMIT_RESPONSE,
+    }
+}
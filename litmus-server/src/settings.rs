@@ -1,9 +1,473 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+pub use crate::access_log::{AccessLog, AccessLogFormat};
+pub use crate::forwarded::TrustedProxies;
+pub use crate::hooks::Tracer;
+pub use crate::metrics::{Metrics, MetricsSnapshot};
+#[cfg(feature = "tls")]
+pub use crate::net::{ClientAuthPolicy, SniConfig, TlsConfig};
+pub use crate::net::{SocketOptions, TcpKeepalive};
+pub use crate::pool::BufferPool;
+pub use crate::rate_limit::IpLimiter;
+pub use crate::request_id::RequestIdFormat;
+pub use crate::router::Router;
+pub use crate::static_files::StaticFiles;
+use http::header::{HeaderName, HeaderValue};
+use http::status::InvalidStatusCode;
+use http::StatusCode;
+
 pub type Settings = Arc<ServerSettings>;
 
+/// The most `max_headers_count` can be set to, headers are parsed into a
+/// fixed size array of this many headers.
+pub const MAX_HEADERS_LIMIT: usize = 256;
+
 pub struct ServerSettings {
     pub backlog: usize,
+
+    /// The max number of idle clients kept around to be recycled for
+    /// new connections.
+    pub max_pooled_clients: usize,
+
+    /// The number of idle clients kept once the pool shrinks after a spike
+    /// in connections, room for this many is reserved up front.
+    pub min_pooled_clients: usize,
     pub keep_alive: Duration,
+
+    /// The read and write buffers shared by the server's connections.
+    pub buffers: BufferPool,
+
+    /// The maximum number of reads performed on a single connection each
+    /// time it is woken up by the event loop.
+    pub max_reads_per_wakeup: usize,
+
+    /// The maximum number of bytes read from a single connection each time
+    /// it is woken up, once either limit is reached the connection yields
+    /// to the others and is read again on a later wakeup. `None` only
+    /// limits the number of reads.
+    pub max_bytes_per_wakeup: Option<usize>,
+
+    /// The number of threads ready connections are read from in parallel
+    /// by the native backend, `0` reads them on the event loop's thread.
+    pub io_threads: usize,
+
+    /// The maximum number of response chunks queued by the application
+    /// before it is told to wait for them to drain.
+    pub max_buffered_chunks: usize,
+
+    /// The amount of a response buffered waiting to be written to the
+    /// socket above which no more chunks are taken from the application,
+    /// leaving them queued so it's told to wait.
+    pub write_high_water: usize,
+
+    /// The maximum amount of time to wait on the application to progress
+    /// a response before the request is aborted, `None` disables this.
+    pub response_timeout: Option<Duration>,
+
+    /// The maximum amount of time a client can take to send a complete
+    /// request head once it has started, `None` disables this.
+    pub header_timeout: Option<Duration>,
+
+    /// The maximum amount of time a client can go without sending any of
+    /// an incomplete request body, `None` disables this.
+    pub body_timeout: Option<Duration>,
+
+    /// The max size of a request line and headers, larger request heads
+    /// are rejected with a `431 Request Header Fields Too Large`.
+    pub max_header_size: usize,
+
+    /// The max number of headers in a request, requests with more are
+    /// rejected with a `431 Request Header Fields Too Large`.
+    pub max_headers_count: usize,
+
+    /// If requests with a head other servers could read differently, e.g.
+    /// with a folded header, are rejected with a `400 Bad Request`. Requests
+    /// with an ambiguous body framing are rejected either way.
+    pub strict_parsing: bool,
+
+    /// The max size of a request body, `None` allows bodies of any size.
+    pub max_body_size: Option<usize>,
+
+    /// The max number of requests served on a single connection, the
+    /// response to the last one closes it. `None` allows any number.
+    pub max_requests_per_connection: Option<usize>,
+
+    /// If HTTP/2 is served, with TLS it's negotiated using ALPN while
+    /// cleartext connections can upgrade using `Upgrade: h2c` or start with
+    /// the preface of a client with prior knowledge.
+    ///
+    /// Otherwise `h2` is never offered using ALPN and clients with prior
+    /// knowledge are asked to retry using HTTP/1.1.
+    pub http2: bool,
+
+    /// How long an event stream can go without sending anything before
+    /// the server sends a heartbeat, `None` disables heartbeats.
+    pub event_stream_heartbeat: Option<Duration>,
+
+    /// The compression applied to response bodies if any.
+    pub compression: Option<Compression>,
+
+    /// The routes `OPTIONS` requests are automatically answered for with
+    /// the methods each path allows, `None` passes every `OPTIONS` request
+    /// to the application.
+    pub auto_options: Option<Router>,
+
+    /// The request rate limit applied to each connection if any.
+    pub rate_limit: Option<RateLimit>,
+
+    /// What to do with requests pipelined behind a request asking to
+    /// upgrade the connection.
+    pub pipelined_upgrade: PipelinedUpgradePolicy,
+
+    /// When to send a `100 Continue` to requests sent with
+    /// `Expect: 100-continue`.
+    pub expect_continue: ExpectContinuePolicy,
+
+    /// The guard against slow readers hoarding buffered responses if any.
+    pub write_stall: Option<WriteStallGuard>,
+
+    /// The limit on the number of open connections if any.
+    pub connection_limit: Option<ConnectionLimit>,
+
+    /// The limiter of the connections accepted from each client address
+    /// if any.
+    pub ip_limiter: Option<Arc<IpLimiter>>,
+
+    /// The options set on each accepted tcp socket.
+    pub socket_options: SocketOptions,
+
+    /// If accepted connections start with a PROXY protocol header giving
+    /// the address of the client behind a load balancer.
+    pub proxy_protocol: bool,
+
+    /// The proxies whose `Forwarded` and `X-Forwarded-*` headers are
+    /// trusted to give the client and scheme of a request, `None` ignores
+    /// the headers.
+    pub trusted_proxies: Option<TrustedProxies>,
+
+    /// The log each completed request is recorded in if any.
+    pub access_log: Option<AccessLog>,
+
+    /// The counters tracking the server's connections and requests.
+    pub metrics: Metrics,
+
+    /// The hooks invoked through the life of each connection and request
+    /// if any.
+    pub tracer: Option<Tracer>,
+
+    /// The path the metrics are served at in the Prometheus text format
+    /// if any.
+    pub metrics_path: Option<String>,
+
+    /// The directories served directly by the server at URL prefixes if
+    /// any.
+    pub static_files: Option<StaticFiles>,
+
+    /// The static response served instead of invoking the application
+    /// while the server is in maintenance mode.
+    pub maintenance: Maintenance,
+
+    /// If requests are answered by the server's own benchmark endpoints
+    /// instead of the application.
+    pub bench: bool,
+
+    /// The static response sent when the application fails before it
+    /// starts its response.
+    pub error_response: ErrorResponse,
+
+    /// The `alt-svc` header value advertising the HTTP/3 listeners to
+    /// clients, added to every response started by the application.
+    pub alt_svc: Option<String>,
+
+    /// The `server` header and fixed headers added to every response.
+    pub response_headers: ResponseHeaders,
+
+    /// How the id of each request is generated, passed to the application,
+    /// logged and sent back as `x-request-id`. `None` disables request ids.
+    pub request_id: Option<RequestIdFormat>,
+
+    /// The TLS config used to terminate TLS on accepted connections.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+
+    /// If the server is draining connections ahead of shutting down.
+    pub draining: AtomicBool,
+}
+
+impl ServerSettings {
+    /// If the server is draining connections, any responses should close
+    /// the connection once complete.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Starts draining connections.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A content-coding responses can be compressed with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The name of the coding as used by `Accept-Encoding` and
+    /// `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+}
+
+/// Compresses response bodies with a coding negotiated with the client.
+pub struct Compression {
+    /// The codings the server supports in order of preference, used to
+    /// break ties between codings the client values equally.
+    pub encodings: Vec<Encoding>,
+
+    /// Responses with a known length below this size are left as is.
+    pub min_size: usize,
+
+    /// The compression level from 0 to 9 used by gzip and deflate, trading
+    /// speed for size.
+    pub level: u32,
+}
+
+/// The policy applied to a connection exceeding the request rate limit.
+#[derive(Copy, Clone)]
+pub enum RateLimitPolicy {
+    /// Requests over the limit are held back, and reading from the
+    /// connection paused, until the rate drops.
+    Throttle,
+
+    /// The request is rejected with `429 Too Many Requests` and the
+    /// connection is closed.
+    Reject,
+}
+
+/// A per-connection request rate limit.
+#[derive(Copy, Clone)]
+pub struct RateLimit {
+    /// The number of requests allowed per second.
+    pub requests_per_second: f64,
+
+    /// The number of requests allowed in a single burst.
+    pub burst: usize,
+
+    /// What to do once the limit is exceeded.
+    pub policy: RateLimitPolicy,
+}
+
+/// The policy applied to requests pipelined behind an upgrade request.
+#[derive(Copy, Clone)]
+pub enum PipelinedUpgradePolicy {
+    /// The pipelined requests are silently discarded.
+    Discard,
+
+    /// The connection is closed with a `400 Bad Request`.
+    Reject,
+}
+
+/// When to tell a client waiting on a `100 Continue` to send its body.
+#[derive(Copy, Clone)]
+pub enum ExpectContinuePolicy {
+    /// The interim response is sent as soon as the request is dispatched
+    /// to the application.
+    Auto,
+
+    /// The interim response is sent once the application starts receiving
+    /// the body, if the application responds first the body is rejected
+    /// and the connection is closed after the response.
+    OnReceive,
+}
+
+/// The policy applied to connections once the connection limit is reached.
+#[derive(Copy, Clone)]
+pub enum ConnectionLimitPolicy {
+    /// The listeners stop accepting until enough connections close, new
+    /// connections wait in the listen backlog.
+    Pause,
+
+    /// New connections are accepted and immediately sent a
+    /// `503 Service Unavailable` before being closed.
+    Reject,
+}
+
+/// A server-wide limit on the number of open connections.
+#[derive(Copy, Clone)]
+pub struct ConnectionLimit {
+    /// The max number of open connections.
+    pub max: usize,
+
+    /// What to do with new connections once the limit is reached.
+    pub policy: ConnectionLimitPolicy,
+}
+
+/// The policy applied to connections over a per-address limit.
+#[derive(Copy, Clone)]
+pub enum IpLimitPolicy {
+    /// The connection is closed as soon as it's accepted.
+    Close,
+
+    /// The connection is sent a `429 Too Many Requests` before being
+    /// closed.
+    Reject,
+}
+
+/// A limit on the connections accepted from each client address.
+#[derive(Copy, Clone)]
+pub struct IpLimit {
+    /// The number of new connections allowed per second, `None` doesn't
+    /// limit the rate.
+    pub connections_per_second: Option<f64>,
+
+    /// The number of new connections allowed in a single burst.
+    pub burst: usize,
+
+    /// The max number of open connections, `None` doesn't limit them.
+    pub max_connections: Option<usize>,
+
+    /// What to do with connections over the limit.
+    pub policy: IpLimitPolicy,
+}
+
+/// Force-closes connections whose write buffer has stalled while holding
+/// onto a large amount of memory.
+#[derive(Copy, Clone)]
+pub struct WriteStallGuard {
+    /// How long the write buffer can go without any progress.
+    pub timeout: Duration,
+
+    /// The number of buffered bytes above which a stalled connection
+    /// is closed.
+    pub max_buffered: usize,
+}
+
+/// The headers the server adds to every response, any the response already
+/// has are left out so the application can override them.
+pub struct ResponseHeaders {
+    /// The rendered `server` header line if it's sent.
+    server: Option<Vec<u8>>,
+
+    /// The lowercase names of the fixed headers and their rendered lines.
+    fixed: Vec<(String, Vec<u8>)>,
+}
+
+impl ResponseHeaders {
+    /// Creates the headers from the value of the `server` header, `None`
+    /// to leave it out, and the fixed `(name, value)` headers, erroring with
+    /// the first name or value which isn't valid.
+    pub fn new(
+        server: Option<&str>,
+        fixed: Vec<(String, String)>,
+    ) -> Result<Self, String> {
+        let render = |name: &str, value: &str| -> Result<Vec<u8>, String> {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {:?}", name))?;
+            HeaderValue::from_str(value).map_err(|_| {
+                format!("invalid value {:?} of header {:?}", value, name)
+            })?;
+            Ok(format!("{}: {}", name, value).into_bytes())
+        };
+
+        let server = server.map(|value| render("server", value)).transpose()?;
+        let fixed = fixed
+            .iter()
+            .map(|(name, value)| Ok((name.to_ascii_lowercase(), render(name, value)?)))
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self { server, fixed })
+    }
+
+    /// The rendered lines of the headers a response doesn't already have,
+    /// `has_header` is given each lowercase name.
+    pub(crate) fn lines<'a>(
+        &'a self,
+        has_header: impl Fn(&str) -> bool + 'a,
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let server = self.server.as_deref().filter(|_| !has_header("server"));
+        let fixed = self
+            .fixed
+            .iter()
+            .filter(move |(name, _)| !has_header(name))
+            .map(|(_, line)| line.as_slice());
+
+        server.into_iter().chain(fixed)
+    }
+}
+
+/// The response sent in place of one the application failed to start.
+pub struct ErrorResponse {
+    /// The status of the static response.
+    pub status: StatusCode,
+
+    /// The body of the static response, sent as `text/plain`.
+    pub body: Vec<u8>,
+}
+
+impl ErrorResponse {
+    /// Creates a new error response with the given status and body.
+    pub fn new(status: u16, body: Vec<u8>) -> Result<Self, InvalidStatusCode> {
+        Ok(Self {
+            status: StatusCode::from_u16(status)?,
+            body,
+        })
+    }
+}
+
+/// The server-wide maintenance mode, this can be toggled at runtime.
+pub struct Maintenance {
+    enabled: AtomicBool,
+
+    /// The status of the static response.
+    pub status: StatusCode,
+
+    /// The body of the static response, sent as `text/plain`.
+    pub body: Vec<u8>,
+
+    /// The path prefixes which are still passed to the application while
+    /// in maintenance mode, e.g. health checks or ACME challenges.
+    pub exclude: Vec<String>,
+}
+
+impl Maintenance {
+    /// Creates a new maintenance mode, initially off, responding with
+    /// the given status and body.
+    pub fn new(
+        status: u16,
+        body: Vec<u8>,
+        exclude: Vec<String>,
+    ) -> Result<Self, InvalidStatusCode> {
+        Ok(Self {
+            enabled: AtomicBool::new(false),
+            status: StatusCode::from_u16(status)?,
+            body,
+            exclude,
+        })
+    }
+
+    /// Turns maintenance mode on or off.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// If maintenance mode is currently on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// If a request for the given path should get the static response.
+    pub fn applies_to(&self, path: &str) -> bool {
+        self.is_enabled() && !self.exclude.iter().any(|prefix| path.starts_with(prefix))
+    }
 }
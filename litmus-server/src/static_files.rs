@@ -0,0 +1,427 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use http::header::{
+    ACCEPT_RANGES, ALLOW, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED,
+};
+use http::StatusCode;
+use httparse::Header;
+
+use crate::lsgi::percent_decode;
+use crate::net::FileBody;
+use crate::range::{parse_date, ByteRange, RangeRequest};
+
+/// The file served for requests of a directory.
+const INDEX_FILE: &str = "index.html";
+
+/// The content type of files with an unknown extension.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The content types of files by their lowercase extension.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// A directory served at a URL prefix.
+struct Mount {
+    /// The prefix without a trailing slash, empty for the root.
+    prefix: String,
+
+    /// The canonical path of the directory.
+    root: PathBuf,
+}
+
+/// Serves the files of directories mounted at URL prefixes directly from
+/// the server, requests under a prefix never reach the application.
+///
+/// Only `GET` and `HEAD` are allowed. Responses carry a `Content-Type`
+/// looked up from the file's extension along with an `ETag` and
+/// `Last-Modified` used to answer conditional requests, and single byte
+/// ranges are supported. Paths can't escape their directory, including
+/// by following symlinks.
+pub struct StaticFiles {
+    /// The mounts from the longest prefix to the shortest so the most
+    /// specific mount wins.
+    mounts: Vec<Mount>,
+}
+
+impl StaticFiles {
+    /// Creates the static files from a map of URL prefixes to the
+    /// directories served at them, erroring if a directory doesn't exist.
+    pub fn new(mounts: HashMap<String, String>) -> io::Result<Self> {
+        let mut mounts = mounts
+            .into_iter()
+            .map(|(prefix, dir)| {
+                let root = fs::canonicalize(&dir).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("static files directory {:?}: {}", dir, e),
+                    )
+                })?;
+                if !root.is_dir() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("static files directory {:?} is not a directory", dir),
+                    ));
+                }
+
+                let prefix = format!("/{}", prefix.trim_matches('/'));
+                let prefix = prefix.trim_end_matches('/').to_string();
+                Ok(Mount { prefix, root })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        mounts.sort_by_key(|m| Reverse(m.prefix.len()));
+        Ok(Self { mounts })
+    }
+
+    /// The response to a request for the given path, `None` if the path
+    /// isn't under any mount and the request is left to the application.
+    pub(crate) fn respond(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Option<StaticResponse> {
+        let mount = self.mounts.iter().find(|mount| {
+            path.strip_prefix(mount.prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() | rest.starts_with('/'))
+        })?;
+
+        if (method != "GET") & (method != "HEAD") {
+            return Some(
+                StaticResponse::error(StatusCode::METHOD_NOT_ALLOWED)
+                    .with_header(ALLOW.as_str(), "GET, HEAD".to_string()),
+            );
+        }
+
+        let rest = &path[mount.prefix.len()..];
+        let response = resolve(&mount.root, rest).and_then(|path| {
+            let file = File::open(&path).ok()?;
+            respond_with_file(&path, file, method == "HEAD", headers)
+        });
+
+        Some(response.unwrap_or_else(|| StaticResponse::error(StatusCode::NOT_FOUND)))
+    }
+}
+
+/// A response to a request for a static file.
+pub(crate) struct StaticResponse {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: StaticBody,
+}
+
+/// The body of a response to a request for a static file.
+pub(crate) enum StaticBody {
+    /// A region of the file of the given length, the file is `None` for
+    /// `HEAD` requests which only get the length.
+    File(u64, Option<FileBody>),
+
+    /// A fixed body sent as `text/plain`.
+    Text(&'static [u8]),
+
+    /// No body at all.
+    Empty,
+}
+
+impl StaticResponse {
+    /// A response with no file giving the status's reason as its body.
+    fn error(status: StatusCode) -> Self {
+        let reason = status.canonical_reason().unwrap_or("");
+        Self {
+            status,
+            headers: vec![(CONTENT_TYPE.as_str(), "text/plain".to_string())],
+            body: StaticBody::Text(reason.as_bytes()),
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// Resolves the file a request path refers to under the root, `None` if
+/// it doesn't exist or would escape the root.
+///
+/// Each segment is percent-decoded on its own so an encoded slash can't
+/// introduce a separator, and any segment that isn't a plain name, e.g.
+/// `..`, is rejected.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for segment in path.split('/') {
+        let segment = percent_decode(segment);
+        if segment.is_empty() | (segment == ".") {
+            continue;
+        }
+
+        let mut components = Path::new(&segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => resolved.push(name),
+            _ => return None,
+        }
+    }
+
+    // Symlinks could still point outside of the root.
+    let mut resolved = fs::canonicalize(resolved).ok()?;
+    if resolved.is_dir() {
+        resolved = fs::canonicalize(resolved.join(INDEX_FILE)).ok()?;
+    }
+
+    if resolved.starts_with(root) & resolved.is_file() {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+/// Builds the response serving the file opened from the given path,
+/// answering any conditional or range request.
+fn respond_with_file(
+    path: &Path,
+    file: File,
+    head_only: bool,
+    headers: &[Header],
+) -> Option<StaticResponse> {
+    let metadata = file.metadata().ok()?;
+    let len = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+
+    let etag = format!("\"{:x}-{:x}\"", modified.unwrap_or(0), len);
+    let last_modified = modified
+        .map(|secs| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs)));
+
+    let find = |name: &http::header::HeaderName| {
+        headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name.as_str()))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+
+    let mut validators = vec![(ETAG.as_str(), etag.clone())];
+    if let Some(last_modified) = last_modified.as_ref() {
+        validators.push((LAST_MODIFIED.as_str(), last_modified.clone()));
+    }
+
+    let not_modified = match find(&IF_NONE_MATCH) {
+        Some(tags) => etag_matches(tags, &etag),
+        None => match (find(&IF_MODIFIED_SINCE).and_then(parse_date), modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        },
+    };
+
+    if not_modified {
+        return Some(StaticResponse {
+            status: StatusCode::NOT_MODIFIED,
+            headers: validators,
+            body: StaticBody::Empty,
+        });
+    }
+
+    let mut response = StaticResponse {
+        status: StatusCode::OK,
+        headers: vec![(CONTENT_TYPE.as_str(), content_type(path))],
+        body: StaticBody::Empty,
+    };
+    response.headers.extend(validators);
+    response
+        .headers
+        .push((ACCEPT_RANGES.as_str(), "bytes".to_string()));
+
+    // A stale `If-Range` gets the whole file.
+    let range = RangeRequest::from_headers(headers)
+        .and_then(|range| range.resolve(len, Some(&etag), modified));
+
+    let (offset, count) = match range {
+        Some(range @ ByteRange::Satisfiable(start, end)) => {
+            response.status = StatusCode::PARTIAL_CONTENT;
+            response
+                .headers
+                .push((CONTENT_RANGE.as_str(), range.content_range(len)));
+            (start, end - start + 1)
+        },
+        Some(range @ ByteRange::Unsatisfiable) => {
+            return Some(
+                StaticResponse::error(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .with_header(CONTENT_RANGE.as_str(), range.content_range(len)),
+            );
+        },
+        None => (0, len),
+    };
+
+    let file = if head_only {
+        None
+    } else {
+        Some(FileBody::new(file, offset, Some(count)).ok()?)
+    };
+
+    response.body = StaticBody::File(count, file);
+    Some(response)
+}
+
+/// The content type of a file from its extension.
+fn content_type(path: &Path) -> String {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    extension
+        .and_then(|ext| CONTENT_TYPES.iter().find(|(known, _)| *known == ext))
+        .map_or(DEFAULT_CONTENT_TYPE, |(_, content_type)| content_type)
+        .to_string()
+}
+
+/// If a file's entity tag is in an `If-None-Match` list, entity tags are
+/// compared weakly as the header is only used for caching.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| (tag == "*") | (tag.trim_start_matches("W/") == etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use super::*;
+
+    /// A directory of files for a test, removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "litmus-static-{}-{}",
+                process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("public/docs")).unwrap();
+            fs::create_dir_all(path.join("public/empty")).unwrap();
+            fs::write(path.join("public/app.js"), "js").unwrap();
+            fs::write(path.join("public/a b.txt"), "text").unwrap();
+            fs::write(path.join("public/docs/index.html"), "index").unwrap();
+            fs::write(path.join("secret.txt"), "secret").unwrap();
+            symlink(path.join("secret.txt"), path.join("public/link.txt")).unwrap();
+
+            // Canonical so resolved paths can be compared with it.
+            Self(fs::canonicalize(path).unwrap())
+        }
+
+        fn public(&self) -> PathBuf {
+            self.0.join("public")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn paths_resolve_under_the_root() {
+        let dir = TempDir::new("resolve");
+        let root = dir.public();
+
+        assert_eq!(resolve(&root, "/app.js"), Some(root.join("app.js")));
+        assert_eq!(resolve(&root, "//./app.js"), Some(root.join("app.js")));
+        assert_eq!(resolve(&root, "/a%20b.txt"), Some(root.join("a b.txt")));
+        assert_eq!(resolve(&root, "/docs"), Some(root.join("docs/index.html")));
+        assert_eq!(resolve(&root, "/docs/"), Some(root.join("docs/index.html")));
+        assert_eq!(resolve(&root, "/missing.js"), None);
+        assert_eq!(resolve(&root, "/empty"), None);
+    }
+
+    #[test]
+    fn paths_cannot_escape_the_root() {
+        let dir = TempDir::new("escape");
+        let root = dir.public();
+
+        for path in [
+            "/../secret.txt",
+            "/docs/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/docs%2F..%2F..%2Fsecret.txt",
+            "/link.txt",
+        ] {
+            assert_eq!(resolve(&root, path), None, "{}", path);
+        }
+    }
+
+    fn static_files(mounts: &[(&str, &Path)]) -> StaticFiles {
+        let mounts = mounts
+            .iter()
+            .map(|(prefix, dir)| {
+                (prefix.to_string(), dir.to_string_lossy().into_owned())
+            })
+            .collect();
+        StaticFiles::new(mounts).unwrap()
+    }
+
+    #[test]
+    fn the_longest_prefix_is_used() {
+        let dir = TempDir::new("mounts");
+        let files = static_files(&[("/", &dir.0), ("/static/", &dir.public())]);
+
+        let status = |method, path| files.respond(method, path, &[]).map(|r| r.status);
+        assert_eq!(status("GET", "/static/app.js"), Some(StatusCode::OK));
+        assert_eq!(status("GET", "/secret.txt"), Some(StatusCode::OK));
+        assert_eq!(
+            status("GET", "/static/secret.txt"),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            status("POST", "/static/app.js"),
+            Some(StatusCode::METHOD_NOT_ALLOWED)
+        );
+
+        let files = static_files(&[("/static", &dir.public())]);
+        assert!(files.respond("GET", "/staticfile", &[]).is_none());
+        assert!(files.respond("GET", "/static", &[]).is_some());
+    }
+}
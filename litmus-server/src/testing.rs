@@ -0,0 +1,445 @@
+//! Helpers for the unit tests, connections are driven by hand over an
+//! in-memory socket and a no-op event loop with a Python application
+//! recording each request it's called with.
+
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use pyo3::exceptions::PyBlockingIOError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyModule, PyTuple};
+
+use crate::client::ClientHandler;
+use crate::clock::{Clock, ManualTime};
+use crate::event_loop::{EventLoop, PreSetEventLoop};
+use crate::metrics::Metrics;
+use crate::migration::ConnectionSnapshot;
+use crate::net::{MemoryHandle, StreamHandle, NO_FD};
+use crate::server::CallbackHandler;
+use crate::settings::{
+    BufferPool, ErrorResponse, ExpectContinuePolicy, Maintenance,
+    PipelinedUpgradePolicy, ResponseHeaders, ServerSettings, Settings, SocketOptions,
+};
+use crate::traits::{Migratable, PollHandler, Reusable};
+
+/// The most times the connection is polled by `TestClient::run()` before
+/// it's assumed to be stuck.
+const MAX_POLLS: usize = 1000;
+
+/// The application every test client is served by, requests are recorded
+/// for the test to answer.
+const APP: &str = r#"
+class App:
+    def __init__(self):
+        self.requests = []
+
+    def __call__(self, scope, send, receive):
+        self.requests.append((scope, send, receive))
+"#;
+
+/// The settings the server is started with by default, without the server
+/// header so responses only have the headers a test expects.
+pub(crate) fn settings() -> ServerSettings {
+    ServerSettings {
+        backlog: 1024,
+        max_pooled_clients: 0,
+        min_pooled_clients: 0,
+        keep_alive: Duration::from_secs(5),
+        buffers: BufferPool::new(0),
+        max_reads_per_wakeup: 16,
+        max_bytes_per_wakeup: Some(256 * 1024),
+        io_threads: 0,
+        max_buffered_chunks: 16,
+        write_high_water: 64 * 1024,
+        response_timeout: Some(Duration::from_secs(30)),
+        header_timeout: None,
+        body_timeout: None,
+        max_header_size: 64 * 1024,
+        max_headers_count: 100,
+        strict_parsing: false,
+        max_body_size: None,
+        max_requests_per_connection: None,
+        http2: false,
+        event_stream_heartbeat: None,
+        compression: None,
+        auto_options: None,
+        rate_limit: None,
+        pipelined_upgrade: PipelinedUpgradePolicy::Discard,
+        expect_continue: ExpectContinuePolicy::Auto,
+        write_stall: None,
+        connection_limit: None,
+        ip_limiter: None,
+        socket_options: SocketOptions::default(),
+        proxy_protocol: false,
+        trusted_proxies: None,
+        access_log: None,
+        metrics: Metrics::default(),
+        tracer: None,
+        metrics_path: None,
+        static_files: None,
+        maintenance: Maintenance::new(503, Vec::new(), Vec::new()).unwrap(),
+        bench: false,
+        error_response: ErrorResponse::new(500, Vec::new()).unwrap(),
+        alt_svc: None,
+        response_headers: ResponseHeaders::new(None, Vec::new()).unwrap(),
+        request_id: None,
+        #[cfg(feature = "tls")]
+        tls: None,
+        draining: AtomicBool::new(false),
+    }
+}
+
+/// Creates the server's side of a connection from the given address over
+/// the in-memory socket, terminating TLS if the settings have a config.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn connect(
+    peer: &MemoryHandle,
+    addr: SocketAddr,
+    settings: &ServerSettings,
+) -> StreamHandle {
+    let server: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let connection = StreamHandle::from_memory(peer.clone(), addr, server);
+
+    #[cfg(feature = "tls")]
+    if let Some(tls) = settings.tls.as_ref() {
+        return connection.with_tls(tls).unwrap();
+    }
+
+    connection
+}
+
+/// A connection to the server over an in-memory socket.
+///
+/// Anything sent is read and answered straight away, the requests given
+/// to the application are answered by the test with `respond()`.
+pub(crate) struct TestClient {
+    client: ClientHandler,
+    peer: MemoryHandle,
+    event_loop: EventLoop,
+    handle: PreSetEventLoop,
+    app: PyObject,
+
+    /// The settings shared with the connection, e.g. to toggle the server
+    /// wide state at runtime.
+    settings: Settings,
+
+    /// If the server has closed the connection.
+    closed: bool,
+}
+
+impl TestClient {
+    pub(crate) fn new(settings: ServerSettings) -> Self {
+        Self::with_event_loop(settings, EventLoop::noop())
+    }
+
+    /// Creates a client whose timeouts are measured with the returned time,
+    /// which only moves when advanced by the test.
+    pub(crate) fn with_manual_time(settings: ServerSettings) -> (Self, ManualTime) {
+        let time = ManualTime::default();
+        let event_loop = EventLoop::noop_with_clock(Clock::from_source(time.clone()));
+        (Self::with_event_loop(settings, event_loop), time)
+    }
+
+    /// Creates a client whose connection is driven by the given no-op event
+    /// loop, e.g. one with a manually advanced clock.
+    pub(crate) fn with_event_loop(
+        settings: ServerSettings,
+        event_loop: EventLoop,
+    ) -> Self {
+        pyo3::prepare_freethreaded_python();
+
+        let app = Python::with_gil(|py| -> PyResult<PyObject> {
+            let module = PyModule::from_code(py, APP, "app.py", "app")?;
+            Ok(module.getattr("App")?.call0()?.into())
+        })
+        .unwrap();
+
+        let peer = MemoryHandle::new();
+        let addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let connection = connect(&peer, addr, &settings);
+
+        let handle = PreSetEventLoop::new(event_loop.clone(), NO_FD, 0);
+        let callback =
+            Python::with_gil(|py| CallbackHandler::new(app.clone_ref(py), None));
+        let settings: Settings = settings.into();
+        let client =
+            ClientHandler::new(callback, handle.clone(), connection, settings.clone())
+                .unwrap();
+
+        Self {
+            client,
+            peer,
+            event_loop,
+            handle,
+            app,
+            settings,
+            closed: false,
+        }
+    }
+
+    /// The settings the connection is served with.
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Sends data to the server, running the connection until it has
+    /// nothing left to do.
+    pub(crate) fn send(&mut self, data: &[u8]) {
+        self.peer.feed(data);
+        self.run();
+    }
+
+    /// Queues data for the server to read without polling the connection.
+    pub(crate) fn feed(&self, data: &[u8]) {
+        self.peer.feed(data);
+    }
+
+    /// Limits how much of the data sent the server gets per read, `None`
+    /// removes the limit.
+    pub(crate) fn set_read_limit(&self, limit: Option<usize>) {
+        self.peer.set_read_limit(limit);
+    }
+
+    /// The number of bytes sent which the server is yet to read.
+    pub(crate) fn unread(&self) -> usize {
+        self.peer.unread()
+    }
+
+    /// Selects the protocol as if the client negotiated it using ALPN.
+    pub(crate) fn select_alpn(&mut self, protocol: &[u8]) {
+        self.client.select_alpn(protocol);
+    }
+
+    /// Limits how much the server can write before its writes block until
+    /// the written data is taken, `None` removes the limit.
+    pub(crate) fn set_write_limit(&self, limit: Option<usize>) {
+        self.peer.set_write_limit(limit);
+    }
+
+    /// Makes the server's next read of the connection fail with the given
+    /// error.
+    pub(crate) fn fail_next_read(&self, error: std::io::Error) {
+        self.peer.fail_next_read(error);
+    }
+
+    /// Polls the connection for reading once, returning the result rather
+    /// than expecting it to succeed.
+    pub(crate) fn try_poll_read(&mut self) -> PyResult<()> {
+        self.client.poll_read()
+    }
+
+    /// Rebinds the client to a new connection over a fresh in-memory
+    /// socket, as the server does when reusing a pooled client.
+    pub(crate) fn reconnect(&mut self) -> PyResult<()> {
+        let peer = MemoryHandle::new();
+        let addr: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let connection = connect(&peer, addr, &self.settings);
+
+        self.client.rebind(connection, 0)?;
+        self.handle = self.client.event_loop().clone();
+        self.peer = peer;
+        self.closed = false;
+        Ok(())
+    }
+
+    /// Takes a snapshot of the connection, detaching the client from it as
+    /// if it was handed off to another process.
+    pub(crate) fn snapshot(&mut self) -> PyResult<ConnectionSnapshot> {
+        self.client.snapshot()
+    }
+
+    /// Restores the state of a connection handed off by another process,
+    /// running the connection with any data it had yet to handle.
+    pub(crate) fn restore(&mut self, snapshot: ConnectionSnapshot) -> PyResult<()> {
+        self.client.restore(snapshot)?;
+        self.run();
+        Ok(())
+    }
+
+    /// The last error of the connection, kept until the client is rebound.
+    pub(crate) fn last_error(&self) -> Option<String> {
+        self.client.last_error().map(String::from)
+    }
+
+    /// Shuts down the client's side of the connection.
+    pub(crate) fn send_eof(&mut self) {
+        self.peer.feed_eof();
+        self.run();
+    }
+
+    /// Polls the connection the same as an event loop would until it has
+    /// nothing left to do.
+    pub(crate) fn run(&mut self) {
+        for _ in 0..MAX_POLLS {
+            if !self.event_loop.take_closed().is_empty() & !self.client.is_idle() {
+                self.closed = true;
+                self.client.poll_close().unwrap();
+            }
+
+            if self.client.is_idle() {
+                self.closed = true;
+                return;
+            }
+
+            let mut probe = [0; 1];
+            let reading = self.handle.is_reading() & self.peer.peek(&mut probe).is_ok();
+            let writing = self.handle.is_writing() & self.peer.is_writable();
+            if !reading & !writing {
+                return;
+            }
+
+            if reading {
+                self.client.poll_read().unwrap();
+            }
+
+            if writing & !self.client.is_idle() {
+                self.client.poll_write().unwrap();
+            }
+        }
+
+        panic!("connection still running after {} polls", MAX_POLLS);
+    }
+
+    /// Runs the connection's timers, as the server does each keep alive
+    /// interval.
+    pub(crate) fn poll_timers(&mut self) {
+        self.client.poll_keep_alive().unwrap();
+        self.run();
+    }
+
+    /// Takes everything the server has written so far.
+    pub(crate) fn take_written(&self) -> String {
+        String::from_utf8_lossy(&self.peer.take_written()).into_owned()
+    }
+
+    /// Takes everything the server has written so far as raw bytes, e.g.
+    /// for binary protocols.
+    pub(crate) fn take_written_bytes(&self) -> Vec<u8> {
+        self.peer.take_written().to_vec()
+    }
+
+    /// If the server has closed the connection or shut down its side.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed | self.peer.is_shutdown()
+    }
+
+    /// The number of requests passed to the application so far.
+    pub(crate) fn requests(&self) -> usize {
+        Python::with_gil(|py| {
+            self.app
+                .getattr(py, "requests")
+                .and_then(|r| r.as_ref(py).len())
+                .unwrap()
+        })
+    }
+
+    /// Gets the given attribute of the scope of the nth request.
+    pub(crate) fn scope<T>(&self, index: usize, attr: &str) -> T
+    where
+        T: for<'p> FromPyObject<'p>,
+    {
+        Python::with_gil(|py| {
+            let scope = self.request(py, index).get_item(0)?;
+            scope.getattr(attr)?.extract()
+        })
+        .unwrap()
+    }
+
+    /// Answers the nth request with the given status and body.
+    pub(crate) fn respond(&mut self, index: usize, status: u16, body: &[u8]) {
+        self.start_response(index, status, body.len());
+        Python::with_gil(|py| -> PyResult<()> {
+            let send = self.request(py, index).get_item(1)?;
+            send.call_method1("send_body", (false, PyBytes::new(py, body)))?;
+            Ok(())
+        })
+        .unwrap();
+        self.run();
+    }
+
+    /// Starts the response to the nth request with the given status and a
+    /// body of the given length, which is yet to be sent.
+    pub(crate) fn start_response(&mut self, index: usize, status: u16, length: usize) {
+        Python::with_gil(|py| -> PyResult<()> {
+            let send = self.request(py, index).get_item(1)?;
+            let length = PyBytes::new(py, length.to_string().as_bytes());
+            let headers = vec![(PyBytes::new(py, b"content-length"), length)];
+            send.call_method1("send_start", (status, headers))?;
+            Ok(())
+        })
+        .unwrap();
+        self.run();
+    }
+
+    /// Calls the given method of the nth request's sender, e.g. to send
+    /// a response other than a plain one.
+    pub(crate) fn call(
+        &mut self,
+        index: usize,
+        method: &str,
+        args: impl IntoPy<Py<PyTuple>>,
+    ) {
+        self.try_call(index, method, args).unwrap();
+    }
+
+    /// Calls the given method of the nth request's sender, returning the
+    /// error it raises if any, e.g. when the application has to wait.
+    pub(crate) fn try_call(
+        &mut self,
+        index: usize,
+        method: &str,
+        args: impl IntoPy<Py<PyTuple>>,
+    ) -> PyResult<()> {
+        Python::with_gil(|py| -> PyResult<()> {
+            let send = self.request(py, index).get_item(1)?;
+            send.call_method1(method, args)?;
+            Ok(())
+        })?;
+        self.run();
+        Ok(())
+    }
+
+    /// Takes the body of the nth request received so far, along with if
+    /// more of it is yet to be received.
+    pub(crate) fn receive(&mut self, index: usize) -> (Vec<u8>, bool) {
+        let mut body = Vec::new();
+        let mut more_body = true;
+
+        Python::with_gil(|py| -> PyResult<()> {
+            let receive = self.request(py, index).get_item(2)?;
+            while more_body {
+                match receive.call0() {
+                    Ok(chunk) => {
+                        let (more, data): (bool, &[u8]) = chunk.extract()?;
+                        body.extend_from_slice(data);
+                        more_body = more;
+                    },
+                    Err(e) if e.is_instance::<PyBlockingIOError>(py) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        self.run();
+        (body, more_body)
+    }
+
+    /// The trailers sent after the body of the nth request.
+    pub(crate) fn trailers(&self, index: usize) -> Vec<(String, Vec<u8>)> {
+        Python::with_gil(|py| {
+            let receive = self.request(py, index).get_item(2)?;
+            receive.call_method0("trailers")?.extract()
+        })
+        .unwrap()
+    }
+
+    fn request<'p>(&self, py: Python<'p>, index: usize) -> &'p PyAny {
+        self.app
+            .getattr(py, "requests")
+            .and_then(|r| r.into_ref(py).get_item(index))
+            .unwrap()
+    }
+}
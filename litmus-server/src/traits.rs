@@ -1,7 +1,10 @@
+use std::time::Duration;
+
 use bytes::BytesMut;
 use pyo3::PyResult;
 
 use crate::event_loop::PreSetEventLoop;
+use crate::migration::ConnectionSnapshot;
 use crate::net::StreamHandle;
 use crate::server::CallbackHandler;
 use crate::settings::Settings;
@@ -15,18 +18,67 @@ pub(crate) trait Reusable: Sized {
         settings: Settings,
     ) -> PyResult<Self>;
 
-    fn set_connection(&mut self, conn: StreamHandle) -> PyResult<()>;
+    /// Rebinds the handler to a new connection at the given index,
+    /// recycling the allocation.
+    ///
+    /// The handler must have reached idle before being rebound, that is to
+    /// say the previous connection must have been fully closed, otherwise an
+    /// error is returned and the handler is left untouched.
+    /// Any event loop listeners still registered against the outgoing
+    /// connection are removed before the new connection is swapped in.
+    fn rebind(&mut self, conn: StreamHandle, index: usize) -> PyResult<()>;
 }
 
 pub trait PollHandler {
     fn poll_read(&mut self) -> PyResult<()>;
+
+    /// Reads from the socket ahead of the next `poll_read` without calling
+    /// into Python, so it can be done off the event loop's thread.
+    fn prefetch(&mut self);
+
     fn poll_write(&mut self) -> PyResult<()>;
     fn poll_close(&mut self) -> PyResult<()>;
     fn poll_keep_alive(&mut self) -> PyResult<()>;
     fn shutdown(&mut self) -> PyResult<()>;
+
+    /// Starts draining the connection ahead of the server shutting down,
+    /// closing it now if no request is in flight.
+    fn poll_drain(&mut self) -> PyResult<()>;
+
     fn is_idle(&self) -> bool;
     fn is_free(&self) -> bool;
     fn set_free(&mut self);
+
+    /// The last error that occurred on the connection if any.
+    fn last_error(&self) -> Option<&str>;
+}
+
+/// Defines the methods for migrating a connection between processes.
+pub(crate) trait Migratable {
+    /// Takes a snapshot of the connection's state and detaches from it.
+    ///
+    /// The connection must be at a request boundary, that is no request or
+    /// response is part way through being handled, otherwise an error is
+    /// returned. Once detached the socket is no longer polled but is left
+    /// open so it can be handed off along with the snapshot.
+    fn snapshot(&mut self) -> PyResult<ConnectionSnapshot>;
+
+    /// Restores the state of a migrated connection, handling any pending
+    /// data immediately.
+    fn restore(&mut self, snapshot: ConnectionSnapshot) -> PyResult<()>;
+}
+
+/// Defines the methods for connections whose I/O is performed by a
+/// completion based event loop rather than being polled for readiness.
+pub(crate) trait CompletionHandler {
+    /// Hands the connection data received by the event loop, `None` marks
+    /// the EOF. Anything received is read straight away if the connection
+    /// is being read from, otherwise it's held until reading resumes.
+    fn poll_received(&mut self, data: Option<&[u8]>) -> PyResult<()>;
+
+    /// Takes the data queued to be sent by the event loop, `None` once the
+    /// connection has been shut down and everything queued has been taken.
+    fn take_outbound(&mut self) -> PyResult<Option<BytesMut>>;
 }
 
 pub trait RawPollHandler {
@@ -44,8 +96,9 @@ pub trait SocketState {
     /// The connection has been lost with the client.
     fn connection_lost(&mut self) -> PyResult<()>;
 
-    /// The EOF has been sent by the socket.
-    fn eof_received(&mut self) -> PyResult<()>;
+    /// The EOF has been sent by the socket, returning `true` if the
+    /// connection is kept half open to finish the response in progress.
+    fn eof_received(&mut self) -> PyResult<bool>;
 }
 
 /// Defined the necessary buffer handling methods.
@@ -85,6 +138,10 @@ pub trait BaseTransport {
     /// Closes the connection to the socket.
     fn close(&self) -> PyResult<()>;
 
+    /// Resets the connection to the socket, anything not yet written is
+    /// discarded.
+    fn abort(&self) -> PyResult<()>;
+
     /// Pauses reading of the set connection.
     fn pause_reading(&self) -> PyResult<()>;
 
@@ -97,3 +154,10 @@ pub trait BaseTransport {
     /// Resumes writing of the set connection.
     fn resume_writing(&self) -> PyResult<()>;
 }
+
+/// A source of time used to calculate timeouts.
+pub trait TimeSource {
+    /// Gets the current time, only meaningful when compared to other times
+    /// produced by the same source.
+    fn now(&self) -> PyResult<Duration>;
+}
@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use pyo3::PyResult;
 
-use crate::event_loop::PreSetEventLoop;
+use crate::event_loop::{EventLoop, PreSetEventLoop};
 use crate::traits::BaseTransport;
 
 #[derive(Clone)]
@@ -10,6 +11,10 @@ pub struct Transport {
     pub client: SocketAddr,
     pub server: SocketAddr,
     pub tls: bool,
+
+    /// The id of the connection given to trace hooks.
+    pub connection_id: u64,
+
     event_loop: PreSetEventLoop,
 }
 
@@ -19,13 +24,35 @@ impl Transport {
         client: SocketAddr,
         server: SocketAddr,
         tls: bool,
+        connection_id: u64,
         event_loop: PreSetEventLoop,
     ) -> Self {
         Self {
             client,
             server,
             tls,
+            connection_id,
+            event_loop,
+        }
+    }
+
+    /// Gets the current time of the event loop's clock.
+    pub fn now(&self) -> PyResult<Duration> {
+        self.event_loop.now()
+    }
+
+    /// Creates the event loop the streams multiplexed over the connection
+    /// are registered with, see `EventLoop::multiplexed()`.
+    pub(crate) fn multiplexed_loop(&self) -> EventLoop {
+        EventLoop::multiplexed(self.event_loop.clone())
+    }
+
+    /// Creates the transport of a stream multiplexed over the connection,
+    /// bound to the given event loop in place of the connection's.
+    pub(crate) fn for_stream(&self, event_loop: PreSetEventLoop) -> Self {
+        Self {
             event_loop,
+            ..self.clone()
         }
     }
 }
@@ -39,6 +66,15 @@ impl BaseTransport for Transport {
         self.event_loop.close_socket()
     }
 
+    /// Resets the connection with a TCP RST rather than closing it with a
+    /// FIN, the peer is told the connection failed instead of seeing the
+    /// end of the stream.
+    ///
+    /// Like `close()` the reset itself is not guaranteed to be instant.
+    fn abort(&self) -> PyResult<()> {
+        self.event_loop.abort_socket()
+    }
+
     /// Removes the file descriptor listener from the event loop
     /// therefore pausing reading callbacks.
     fn pause_reading(&self) -> PyResult<()> {
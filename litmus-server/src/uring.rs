@@ -0,0 +1,1322 @@
+//! An experimental io_uring driver used in place of the asyncio event loop
+//! on Linux.
+//!
+//! Rather than waiting for sockets to become ready and then performing
+//! nonblocking syscalls, accepts, receives and sends are submitted to a ring
+//! shared with the kernel which completes them asynchronously. Receives are
+//! read into a fixed set of buffers provided to the kernel up front, the
+//! kernel only picks one once data arrives so idle connections hold none,
+//! and each buffer is handed back once the connection has been fed from it.
+//!
+//! Connections are driven the same way as by a completion based event loop,
+//! what's received is fed to the connection and anything it queues is taken
+//! to be sent. The ring's eventfd is watched by the asyncio event loop, once
+//! it becomes readable `Server::poll_uring()` handles the completions in a
+//! single batch.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{mem, ptr};
+
+use bytes::{Buf, BytesMut};
+use slab::Slab;
+
+use crate::event_loop::SocketFd;
+use crate::net::ShutdownHook;
+
+/// The number of submission queue entries, the completion queue holds
+/// twice as many.
+const RING_ENTRIES: u32 = 1024;
+
+/// The number of buffers provided to the kernel for receives.
+const BUFFER_COUNT: u16 = 256;
+
+/// The size of each provided buffer, the most received at once.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// The group the provided buffers are registered under.
+const BUFFER_GROUP: u16 = 0;
+
+/// The operations submitted to the ring, see `linux/io_uring.h`.
+const IORING_OP_ACCEPT: u8 = 13;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+const IORING_OP_PROVIDE_BUFFERS: u8 = 31;
+
+const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+const IORING_CQE_F_BUFFER: u32 = 1 << 0;
+const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_FEAT_FAST_POLL: u32 = 1 << 5;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_REGISTER_EVENTFD: u32 = 4;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// A submission queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A region of the ring shared with the kernel, unmapped when dropped.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// A pointer to the value at the given byte offset.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// The submission and completion queues shared with the kernel.
+struct Ring {
+    // The maps must be dropped before the ring's file descriptor is closed.
+    _sq_map: Mmap,
+    _cq_map: Option<Mmap>,
+    _sqe_map: Mmap,
+    fd: OwnedFd,
+
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+
+    /// The number of entries pushed which are yet to be submitted.
+    unsubmitted: u32,
+}
+
+// The pointers are into the maps owned by the ring.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        if params.features & IORING_FEAT_FAST_POLL == 0 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the io_uring backend requires Linux 5.7 or later",
+            ));
+        }
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * mem::size_of::<Cqe>();
+
+        // Both queues share a single map since Linux 5.4.
+        let single_mmap = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let sq_map = if single_mmap {
+            Mmap::new(fd.as_raw_fd(), sq_len.max(cq_len), IORING_OFF_SQ_RING)?
+        } else {
+            Mmap::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?
+        };
+        let cq_map = if single_mmap {
+            None
+        } else {
+            Some(Mmap::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?)
+        };
+        let sqe_map = Mmap::new(
+            fd.as_raw_fd(),
+            params.sq_entries as usize * mem::size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        let cq = cq_map.as_ref().unwrap_or(&sq_map);
+        let ring = Self {
+            sq_head: sq_map.at(params.sq_off.head),
+            sq_tail: sq_map.at(params.sq_off.tail),
+            sq_mask: unsafe { *sq_map.at::<u32>(params.sq_off.ring_mask) },
+            sq_entries: unsafe { *sq_map.at::<u32>(params.sq_off.ring_entries) },
+            sq_array: sq_map.at(params.sq_off.array),
+            sqes: sqe_map.at(0),
+            cq_head: cq.at(params.cq_off.head),
+            cq_tail: cq.at(params.cq_off.tail),
+            cq_mask: unsafe { *cq.at::<u32>(params.cq_off.ring_mask) },
+            cqes: cq.at(params.cq_off.cqes),
+            unsubmitted: 0,
+            _sq_map: sq_map,
+            _cq_map: cq_map,
+            _sqe_map: sqe_map,
+            fd,
+        };
+
+        Ok(ring)
+    }
+
+    /// Signals the eventfd whenever a completion is posted.
+    fn register_eventfd(&self, eventfd: RawFd) -> io::Result<()> {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &eventfd as *const RawFd,
+                1,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Pushes an entry onto the submission queue, submitting everything
+    /// pushed so far first if the queue is full.
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        if self.is_full() {
+            self.enter(0)?;
+        }
+        if self.is_full() {
+            return Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                "the io_uring submission queue is full",
+            ));
+        }
+
+        let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+
+        let index = tail & self.sq_mask;
+        unsafe {
+            *self.sqes.add(index as usize) = sqe;
+            *self.sq_array.add(index as usize) = index;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.unsubmitted += 1;
+
+        Ok(())
+    }
+
+    fn is_full(&self) -> bool {
+        let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+        let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+        tail.wrapping_sub(head) >= self.sq_entries
+    }
+
+    /// Submits everything pushed, waiting for at least `min_complete`
+    /// completions to be posted.
+    fn enter(&mut self, min_complete: u32) -> io::Result<()> {
+        loop {
+            let flags = if min_complete > 0 {
+                IORING_ENTER_GETEVENTS
+            } else {
+                0
+            };
+
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.unsubmitted,
+                    min_complete,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // The completion queue is full, the entries are
+                    // submitted once the completions have been reaped.
+                    Some(libc::EBUSY) | Some(libc::EAGAIN) => return Ok(()),
+                    _ => return Err(e),
+                }
+            }
+
+            self.unsubmitted -= (res as u32).min(self.unsubmitted);
+            return Ok(());
+        }
+    }
+
+    /// Takes every completion which has been posted.
+    fn reap(&mut self, completions: &mut Vec<Cqe>) {
+        let mut head = unsafe { (*self.cq_head).load(Ordering::Relaxed) };
+        let tail = unsafe { (*self.cq_tail).load(Ordering::Acquire) };
+
+        while head != tail {
+            let index = head & self.cq_mask;
+            completions.push(unsafe { *self.cqes.add(index as usize) });
+            head = head.wrapping_add(1);
+        }
+
+        unsafe { (*self.cq_head).store(head, Ordering::Release) };
+    }
+}
+
+/// What an operation was submitted for, encoded in the top byte of its
+/// user data with the token of its listener or connection below.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Accept = 1,
+    Recv,
+    Send,
+    ProvideBuffers,
+    Cancel,
+}
+
+impl Op {
+    fn user_data(self, token: usize) -> u64 {
+        ((self as u64) << 56) | token as u64
+    }
+
+    fn decode(user_data: u64) -> Option<(Self, usize)> {
+        let op = match user_data >> 56 {
+            1 => Self::Accept,
+            2 => Self::Recv,
+            3 => Self::Send,
+            4 => Self::ProvideBuffers,
+            5 => Self::Cancel,
+            _ => return None,
+        };
+
+        Some((op, (user_data & ((1 << 56) - 1)) as usize))
+    }
+}
+
+/// A provided buffer the kernel received data into, it must be handed
+/// back with `Uring::release_buffer()` once read.
+pub(crate) struct RecvBuffer {
+    id: u16,
+    len: usize,
+}
+
+/// The completion of an operation which the server needs to handle.
+pub(crate) enum Completion {
+    /// A connection was accepted by one of the listeners.
+    Accepted(SocketFd),
+
+    /// The client at the given index received data, `None` marks the EOF.
+    Received(usize, Option<RecvBuffer>),
+
+    /// Sending to the client at the given index failed.
+    Failed(usize),
+}
+
+/// A listener the ring accepts connections on.
+struct Listener {
+    fd: SocketFd,
+
+    /// If an accept is in flight.
+    accepting: bool,
+
+    /// If accepting has been paused, e.g. by the connection limit.
+    paused: bool,
+}
+
+/// A connection whose socket is owned by the ring.
+struct Connection {
+    fd: SocketFd,
+
+    /// The index of the client bound to the connection.
+    index: Option<usize>,
+
+    /// If the client wants to be told the socket is readable or writable.
+    reading: bool,
+    writing: bool,
+
+    /// If the client should read whatever it was fed while it wasn't
+    /// reading, as it has started reading again.
+    pending_read: bool,
+
+    /// If a receive is in flight.
+    receiving: bool,
+
+    /// The data of the send in flight, left untouched until it completes.
+    sending: Option<BytesMut>,
+
+    /// The data waiting for the send in flight to complete.
+    queued: BytesMut,
+
+    /// If the EOF has been received.
+    eof: bool,
+
+    /// If the client has shut the socket down, it's closed once everything
+    /// queued has been sent.
+    shutdown: bool,
+
+    /// If the receive in flight has been cancelled.
+    cancelled: bool,
+
+    /// If the connection is queued to be pumped by the server.
+    dirty: bool,
+}
+
+struct State {
+    /// The ring, `None` once shut down.
+    ring: Option<Ring>,
+
+    /// The address of the buffers provided to the kernel.
+    buffers: u64,
+
+    listeners: Slab<Listener>,
+    connections: Slab<Connection>,
+
+    /// The tokens of the connections by their file descriptor.
+    tokens: HashMap<SocketFd, usize>,
+
+    /// The connections with something to do, see `Server::pump_ring()`.
+    dirty: Vec<usize>,
+
+    /// The connections waiting for a buffer to be handed back.
+    starved: Vec<usize>,
+
+    /// The buffers to provide to the kernel again.
+    released: Vec<u16>,
+
+    /// The clients whose socket should be closed on the next wakeup.
+    closing: Vec<usize>,
+
+    /// The number of operations in flight which reference a socket or
+    /// buffer, these must complete before either is freed.
+    in_flight: usize,
+
+    /// If the server is handling completions, wakeups are unnecessary as
+    /// anything dirty is handled before it's done.
+    polling: bool,
+}
+
+/// Drives sockets with an io_uring instance, see the module docs.
+pub(crate) struct Uring {
+    state: Mutex<State>,
+
+    /// The memory of the buffers provided to the kernel.
+    buffers: *mut u8,
+
+    /// Signalled by the kernel as completions are posted and by the driver
+    /// when a connection has something to do.
+    eventfd: OwnedFd,
+}
+
+// The buffers are only written by the kernel while provided to it and
+// only read by the server once it's handed one.
+unsafe impl Send for Uring {}
+unsafe impl Sync for Uring {}
+
+impl Uring {
+    /// Creates the ring, this fails if io_uring isn't supported by the
+    /// kernel or is disabled.
+    pub(crate) fn new() -> io::Result<Self> {
+        let ring = Ring::new(RING_ENTRIES)?;
+
+        let eventfd =
+            unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if eventfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let eventfd = unsafe { OwnedFd::from_raw_fd(eventfd) };
+        ring.register_eventfd(eventfd.as_raw_fd())?;
+
+        let buffers = vec![0u8; BUFFER_COUNT as usize * BUFFER_SIZE].into_boxed_slice();
+        let buffers = Box::into_raw(buffers) as *mut u8;
+
+        let uring = Self {
+            state: Mutex::new(State {
+                ring: Some(ring),
+                buffers: buffers as u64,
+                listeners: Slab::new(),
+                connections: Slab::new(),
+                tokens: HashMap::new(),
+                dirty: Vec::new(),
+                starved: Vec::new(),
+                released: Vec::new(),
+                closing: Vec::new(),
+                in_flight: 0,
+                polling: false,
+            }),
+            buffers,
+            eventfd,
+        };
+
+        let mut state = uring.state();
+        let sqe = Sqe {
+            opcode: IORING_OP_PROVIDE_BUFFERS,
+            fd: BUFFER_COUNT as i32,
+            addr: buffers as u64,
+            len: BUFFER_SIZE as u32,
+            off: 0,
+            buf_index: BUFFER_GROUP,
+            user_data: Op::ProvideBuffers.user_data(0),
+            ..Sqe::default()
+        };
+        state.push(sqe)?;
+        state.submit()?;
+        drop(state);
+
+        Ok(uring)
+    }
+
+    /// The file descriptor of the ring's eventfd, this becomes readable
+    /// once there are completions to handle.
+    pub(crate) fn fd(&self) -> SocketFd {
+        self.eventfd.as_raw_fd()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts accepting on the listener with the given file descriptor.
+    pub(crate) fn add_listener(&self, fd: SocketFd) {
+        let mut state = self.state();
+        match state.listeners.iter_mut().find(|(_, l)| l.fd == fd) {
+            Some((_, listener)) => listener.paused = false,
+            None => {
+                state.listeners.insert(Listener {
+                    fd,
+                    accepting: false,
+                    paused: false,
+                });
+            },
+        }
+
+        self.wake(&state);
+    }
+
+    /// Registers a socket accepted by the ring returning its token, the
+    /// socket is owned by the ring from then on.
+    pub(crate) fn register(&self, fd: SocketFd) -> usize {
+        let mut state = self.state();
+        let token = state.connections.insert(Connection {
+            fd,
+            index: None,
+            reading: false,
+            writing: false,
+            pending_read: false,
+            receiving: false,
+            sending: None,
+            queued: BytesMut::new(),
+            eof: false,
+            shutdown: false,
+            cancelled: false,
+            dirty: false,
+        });
+        state.tokens.insert(fd, token);
+
+        token
+    }
+
+    /// Binds the connection with the given token to the client at the
+    /// given index.
+    pub(crate) fn bind(&self, token: usize, index: usize) {
+        if let Some(conn) = self.state().connections.get_mut(token) {
+            conn.index = Some(index);
+        }
+    }
+
+    /// The hook shutting down the connection with the given token once
+    /// its client shuts the socket down.
+    pub(crate) fn shutdown_hook(self: &Arc<Self>, token: usize) -> ShutdownHook {
+        let uring = Arc::downgrade(self);
+        Arc::new(move || {
+            if let Some(uring) = uring.upgrade() {
+                uring.shutdown_connection(token);
+            }
+        })
+    }
+
+    /// Marks the connection as shut down, its socket is closed once
+    /// everything queued has been sent.
+    pub(crate) fn shutdown_connection(&self, token: usize) {
+        let mut state = self.state();
+        if let Some(conn) = state.connections.get_mut(token) {
+            conn.shutdown = true;
+            state.mark_dirty(token);
+            self.wake(&state);
+        }
+    }
+
+    /// Start telling the client of the socket once it's readable, or
+    /// resumes accepting if the socket is a listener.
+    pub(crate) fn add_reader(&self, fd: SocketFd, index: usize) {
+        let mut state = self.state();
+        if let Some(token) = state.tokens.get(&fd).copied() {
+            let conn = &mut state.connections[token];
+            conn.index = Some(index);
+            conn.reading = true;
+            conn.pending_read = true;
+            state.mark_dirty(token);
+        } else if let Some((_, listener)) =
+            state.listeners.iter_mut().find(|(_, l)| l.fd == fd)
+        {
+            listener.paused = false;
+        }
+
+        self.wake(&state);
+    }
+
+    /// Stop telling the client of the socket once it's readable, or pauses
+    /// accepting if the socket is a listener.
+    pub(crate) fn remove_reader(&self, fd: SocketFd) {
+        let mut state = self.state();
+        if let Some(token) = state.tokens.get(&fd).copied() {
+            state.connections[token].reading = false;
+        } else if let Some((_, listener)) =
+            state.listeners.iter_mut().find(|(_, l)| l.fd == fd)
+        {
+            listener.paused = true;
+        }
+    }
+
+    /// Start telling the client of the socket once everything queued has
+    /// been sent.
+    pub(crate) fn add_writer(&self, fd: SocketFd, index: usize) {
+        let mut state = self.state();
+        if let Some(token) = state.tokens.get(&fd).copied() {
+            let conn = &mut state.connections[token];
+            conn.index = Some(index);
+            conn.writing = true;
+            state.mark_dirty(token);
+            self.wake(&state);
+        }
+    }
+
+    /// Stop telling the client of the socket once everything queued has
+    /// been sent.
+    pub(crate) fn remove_writer(&self, fd: SocketFd) {
+        let mut state = self.state();
+        if let Some(token) = state.tokens.get(&fd).copied() {
+            state.connections[token].writing = false;
+        }
+    }
+
+    /// Schedules the client at the given index to be closed on the next
+    /// wakeup.
+    pub(crate) fn close_socket(&self, index: usize) {
+        let mut state = self.state();
+        state.closing.push(index);
+        self.wake(&state);
+    }
+
+    /// Takes the clients whose socket is scheduled to be closed.
+    pub(crate) fn take_closing(&self) -> Vec<usize> {
+        mem::take(&mut self.state().closing)
+    }
+
+    /// Starts handling completions, any wakeups until `finish_polling()`
+    /// are left to it.
+    pub(crate) fn start_polling(&self) {
+        self.clear_wakeups();
+        self.state().polling = true;
+    }
+
+    /// Submits everything pushed while handling completions, waking the
+    /// event loop again if any connection is left with something to do.
+    pub(crate) fn finish_polling(&self) -> io::Result<()> {
+        let mut state = self.state();
+        state.polling = false;
+        state.arm_listeners()?;
+        state.provide_released()?;
+        state.submit()?;
+
+        if !state.dirty.is_empty() {
+            self.wake(&state);
+        }
+
+        Ok(())
+    }
+
+    /// Takes the completions the server needs to handle, the completions
+    /// of sends are handled by the driver itself.
+    pub(crate) fn reap(&self) -> Vec<Completion> {
+        let mut state = self.state();
+        let mut cqes = Vec::new();
+        match state.ring.as_mut() {
+            Some(ring) => ring.reap(&mut cqes),
+            None => return Vec::new(),
+        }
+
+        let mut completions = Vec::new();
+        for cqe in cqes {
+            if let Err(e) = state.complete(cqe, &mut completions) {
+                error!("failed to handle io_uring completion: {}", e);
+            }
+        }
+
+        completions
+    }
+
+    /// The data received into the given buffer.
+    pub(crate) fn buffer(&self, buffer: &RecvBuffer) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self.buffers.add(buffer.id as usize * BUFFER_SIZE),
+                buffer.len,
+            )
+        }
+    }
+
+    /// Hands the buffer back to be provided to the kernel again.
+    pub(crate) fn release_buffer(&self, buffer: RecvBuffer) {
+        let mut state = self.state();
+        state.released.push(buffer.id);
+
+        // Anything waiting for a buffer can receive again.
+        let starved = mem::take(&mut state.starved);
+        for token in starved {
+            state.mark_dirty(token);
+        }
+    }
+
+    /// Takes the connections with something to do along with the index of
+    /// their client, connections detached from their client are closed
+    /// once nothing is left in flight.
+    pub(crate) fn take_dirty(&self) -> io::Result<Vec<(usize, usize)>> {
+        let mut state = self.state();
+        let dirty = mem::take(&mut state.dirty);
+
+        let mut bound = Vec::with_capacity(dirty.len());
+        for token in dirty {
+            let conn = match state.connections.get_mut(token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            conn.dirty = false;
+
+            match conn.index {
+                Some(index) => bound.push((token, index)),
+                None => state.maybe_close(token)?,
+            }
+        }
+
+        Ok(bound)
+    }
+
+    /// Detaches the connection from its client once everything the client
+    /// queued has been taken, as the client may be reused once closed.
+    pub(crate) fn detach(&self, token: usize) -> io::Result<()> {
+        let mut state = self.state();
+        if let Some(conn) = state.connections.get_mut(token) {
+            conn.index = None;
+            conn.reading = false;
+            conn.writing = false;
+        }
+
+        state.maybe_close(token)
+    }
+
+    /// Queues the connection to be pumped again.
+    pub(crate) fn mark_dirty(&self, token: usize) {
+        self.state().mark_dirty(token);
+    }
+
+    /// Queues data taken from the client to be sent.
+    pub(crate) fn send(&self, token: usize, data: BytesMut) -> io::Result<()> {
+        let mut state = self.state();
+        if let Some(conn) = state.connections.get_mut(token) {
+            conn.queued.unsplit(data);
+            state.start_send(token)?;
+        }
+
+        Ok(())
+    }
+
+    /// If the client should read what it was fed while it wasn't reading,
+    /// clearing the flag.
+    pub(crate) fn take_pending_read(&self, token: usize) -> bool {
+        match self.state().connections.get_mut(token) {
+            Some(conn) => mem::take(&mut conn.pending_read) & conn.reading,
+            None => false,
+        }
+    }
+
+    /// If the client should read what it was fed, without clearing the
+    /// flag, e.g. after it yielded with some of it left unread.
+    pub(crate) fn has_pending_read(&self, token: usize) -> bool {
+        match self.state().connections.get(token) {
+            Some(conn) => conn.pending_read & conn.reading,
+            None => false,
+        }
+    }
+
+    /// If the client wants to write and everything queued has been sent,
+    /// the equivalent of the socket being writable.
+    pub(crate) fn is_writable(&self, token: usize) -> bool {
+        match self.state().connections.get(token) {
+            Some(conn) => {
+                conn.writing
+                    & !conn.shutdown
+                    & conn.sending.is_none()
+                    & conn.queued.is_empty()
+            },
+            None => false,
+        }
+    }
+
+    /// Starts receiving on the connection if its client is reading,
+    /// closing the socket instead if the client has shut it down and
+    /// everything has been sent.
+    pub(crate) fn poll_connection(&self, token: usize) -> io::Result<()> {
+        let mut state = self.state();
+        let conn = match state.connections.get(token) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+
+        if conn.shutdown {
+            return state.maybe_close(token);
+        }
+
+        if conn.reading & !conn.receiving & !conn.eof {
+            state.start_recv(token)?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the socket of a connection which was turned away without
+    /// being bound to a client.
+    pub(crate) fn discard(&self, token: usize) {
+        let mut state = self.state();
+        if let Some(conn) = state.connections.get_mut(token) {
+            conn.shutdown = true;
+        }
+        let _ = state.maybe_close(token);
+    }
+
+    /// Cancels everything in flight and closes every socket owned by the
+    /// ring, waiting for the kernel to be done with them.
+    pub(crate) fn shutdown(&self) {
+        let mut state = self.state();
+        if state.ring.is_none() {
+            return;
+        }
+
+        if let Err(e) = state.cancel_all() {
+            error!("failed to cancel io_uring operations: {}", e);
+        }
+
+        for (_, conn) in state.connections.iter() {
+            unsafe { libc::close(conn.fd) };
+        }
+        state.connections.clear();
+        state.tokens.clear();
+        state.listeners.clear();
+
+        if state.in_flight > 0 {
+            // The kernel may still write into the buffers.
+            warn!("io_uring operations still in flight, leaking the receive buffers");
+            if let Some(ring) = state.ring.take() {
+                mem::forget(ring);
+            }
+        } else {
+            state.ring = None;
+        }
+    }
+
+    fn wake(&self, state: &State) {
+        if state.polling {
+            return;
+        }
+
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.eventfd.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+
+    fn clear_wakeups(&self) {
+        let mut count: u64 = 0;
+        unsafe {
+            libc::read(
+                self.eventfd.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        self.shutdown();
+
+        // The ring is only left behind if the buffers couldn't be freed.
+        if self.state().in_flight == 0 {
+            let len = BUFFER_COUNT as usize * BUFFER_SIZE;
+            let buffers = ptr::slice_from_raw_parts_mut(self.buffers, len);
+            drop(unsafe { Box::from_raw(buffers) });
+        }
+    }
+}
+
+impl State {
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        let ring = match self.ring.as_mut() {
+            Some(ring) => ring,
+            None => return Err(ErrorKind::NotConnected.into()),
+        };
+
+        ring.push(sqe)?;
+        if !matches!(Op::decode(sqe.user_data), Some((Op::Cancel, _))) {
+            self.in_flight += 1;
+        }
+
+        Ok(())
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        match self.ring.as_mut() {
+            Some(ring) if ring.unsubmitted > 0 => ring.enter(0),
+            _ => Ok(()),
+        }
+    }
+
+    fn mark_dirty(&mut self, token: usize) {
+        if let Some(conn) = self.connections.get_mut(token) {
+            if !conn.dirty {
+                conn.dirty = true;
+                self.dirty.push(token);
+            }
+        }
+    }
+
+    /// Submits an accept on every listener which isn't paused.
+    fn arm_listeners(&mut self) -> io::Result<()> {
+        let ready: Vec<(usize, SocketFd)> = self
+            .listeners
+            .iter()
+            .filter(|(_, l)| !l.accepting & !l.paused)
+            .map(|(token, l)| (token, l.fd))
+            .collect();
+
+        for (token, fd) in ready {
+            self.push(Sqe {
+                opcode: IORING_OP_ACCEPT,
+                fd,
+                op_flags: (libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) as u32,
+                user_data: Op::Accept.user_data(token),
+                ..Sqe::default()
+            })?;
+            self.listeners[token].accepting = true;
+        }
+
+        Ok(())
+    }
+
+    /// Provides the buffers handed back to the kernel again.
+    fn provide_released(&mut self) -> io::Result<()> {
+        let released = mem::take(&mut self.released);
+        for id in released {
+            let addr = self.buffers + (id as usize * BUFFER_SIZE) as u64;
+            self.push(Sqe {
+                opcode: IORING_OP_PROVIDE_BUFFERS,
+                fd: 1,
+                addr,
+                len: BUFFER_SIZE as u32,
+                off: id as u64,
+                buf_index: BUFFER_GROUP,
+                user_data: Op::ProvideBuffers.user_data(id as usize),
+                ..Sqe::default()
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn start_recv(&mut self, token: usize) -> io::Result<()> {
+        let fd = self.connections[token].fd;
+        self.push(Sqe {
+            opcode: IORING_OP_RECV,
+            flags: IOSQE_BUFFER_SELECT,
+            fd,
+            len: BUFFER_SIZE as u32,
+            buf_index: BUFFER_GROUP,
+            user_data: Op::Recv.user_data(token),
+            ..Sqe::default()
+        })?;
+        self.connections[token].receiving = true;
+
+        Ok(())
+    }
+
+    /// Sends everything queued if no send is already in flight.
+    fn start_send(&mut self, token: usize) -> io::Result<()> {
+        let conn = &mut self.connections[token];
+        if conn.queued.is_empty() {
+            return Ok(());
+        }
+
+        let data = match conn.sending.as_mut() {
+            Some(_) => return Ok(()),
+            None => conn.sending.insert(conn.queued.split()),
+        };
+
+        let sqe = Sqe {
+            opcode: IORING_OP_SEND,
+            fd: conn.fd,
+            addr: data.as_ptr() as u64,
+            len: data.len() as u32,
+            op_flags: libc::MSG_NOSIGNAL as u32,
+            user_data: Op::Send.user_data(token),
+            ..Sqe::default()
+        };
+        self.push(sqe)
+    }
+
+    /// Handles a single completion, anything the server needs to handle is
+    /// added to `completions`.
+    fn complete(
+        &mut self,
+        cqe: Cqe,
+        completions: &mut Vec<Completion>,
+    ) -> io::Result<()> {
+        let (op, token) = match Op::decode(cqe.user_data) {
+            Some(decoded) => decoded,
+            None => return Ok(()),
+        };
+
+        if op == Op::Cancel {
+            return Ok(());
+        }
+        self.in_flight -= 1;
+
+        match op {
+            Op::Accept => {
+                let listener = match self.listeners.get_mut(token) {
+                    Some(listener) => listener,
+                    None => return Ok(()),
+                };
+                listener.accepting = false;
+
+                match cqe.res {
+                    fd if fd >= 0 => completions.push(Completion::Accepted(fd)),
+                    e if (-e == libc::ECANCELED) | (-e == libc::ECONNABORTED) => {},
+                    e => error!(
+                        "failed to accept connection: {}",
+                        io::Error::from_raw_os_error(-e)
+                    ),
+                }
+            },
+            Op::Recv => self.complete_recv(token, cqe, completions)?,
+            Op::Send => self.complete_send(token, cqe, completions)?,
+            Op::ProvideBuffers if cqe.res < 0 => {
+                error!(
+                    "failed to provide receive buffers: {}",
+                    io::Error::from_raw_os_error(-cqe.res)
+                );
+            },
+            Op::ProvideBuffers | Op::Cancel => {},
+        }
+
+        Ok(())
+    }
+
+    fn complete_recv(
+        &mut self,
+        token: usize,
+        cqe: Cqe,
+        completions: &mut Vec<Completion>,
+    ) -> io::Result<()> {
+        let buffer = if cqe.flags & IORING_CQE_F_BUFFER != 0 {
+            Some((cqe.flags >> IORING_CQE_BUFFER_SHIFT) as u16)
+        } else {
+            None
+        };
+
+        let conn = match self.connections.get_mut(token) {
+            Some(conn) => conn,
+            None => {
+                self.released.extend(buffer);
+                return Ok(());
+            },
+        };
+        conn.receiving = false;
+
+        if conn.shutdown {
+            self.released.extend(buffer);
+            return self.maybe_close(token);
+        }
+
+        let index = match conn.index {
+            Some(index) => index,
+            None => {
+                self.released.extend(buffer);
+                return Ok(());
+            },
+        };
+
+        match cqe.res {
+            len if len > 0 => match buffer {
+                Some(id) => completions.push(Completion::Received(
+                    index,
+                    Some(RecvBuffer {
+                        id,
+                        len: len as usize,
+                    }),
+                )),
+                None => error!("received data without a buffer"),
+            },
+            e if -e == libc::ENOBUFS => {
+                // Every buffer is in use, receiving resumes once one is
+                // handed back.
+                self.starved.push(token);
+                return Ok(());
+            },
+            e if -e == libc::ECANCELED => return Ok(()),
+            _ => {
+                conn.eof = true;
+                self.released.extend(buffer);
+                completions.push(Completion::Received(index, None));
+            },
+        }
+
+        self.mark_dirty(token);
+        Ok(())
+    }
+
+    fn complete_send(
+        &mut self,
+        token: usize,
+        cqe: Cqe,
+        completions: &mut Vec<Completion>,
+    ) -> io::Result<()> {
+        let conn = match self.connections.get_mut(token) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+
+        let mut data = match conn.sending.take() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+
+        if cqe.res < 0 {
+            conn.queued.clear();
+            if let Some(index) = conn.index.filter(|_| !conn.shutdown) {
+                completions.push(Completion::Failed(index));
+            }
+            return self.maybe_close(token);
+        }
+
+        // Whatever wasn't sent goes out ahead of anything queued since.
+        data.advance(cqe.res as usize);
+        if !data.is_empty() {
+            data.unsplit(conn.queued.split());
+            conn.queued = data;
+        }
+
+        self.start_send(token)?;
+        self.mark_dirty(token);
+        Ok(())
+    }
+
+    /// Closes the socket of a shut down connection once nothing is in
+    /// flight, cancelling any receive still waiting for data.
+    fn maybe_close(&mut self, token: usize) -> io::Result<()> {
+        let conn = match self.connections.get_mut(token) {
+            Some(conn) if conn.shutdown => conn,
+            _ => return Ok(()),
+        };
+
+        if conn.sending.is_some() | !conn.queued.is_empty() {
+            return Ok(());
+        }
+
+        if conn.receiving {
+            if !conn.cancelled {
+                conn.cancelled = true;
+                self.push(Sqe {
+                    opcode: IORING_OP_ASYNC_CANCEL,
+                    fd: -1,
+                    addr: Op::Recv.user_data(token),
+                    user_data: Op::Cancel.user_data(token),
+                    ..Sqe::default()
+                })?;
+            }
+
+            return Ok(());
+        }
+
+        let conn = self.connections.remove(token);
+        self.tokens.remove(&conn.fd);
+        unsafe { libc::close(conn.fd) };
+
+        Ok(())
+    }
+
+    /// Cancels every operation in flight and waits for them to complete.
+    fn cancel_all(&mut self) -> io::Result<()> {
+        let listeners: Vec<usize> = self
+            .listeners
+            .iter()
+            .filter(|(_, l)| l.accepting)
+            .map(|(token, _)| token)
+            .collect();
+        let connections: Vec<(usize, bool, bool)> = self
+            .connections
+            .iter()
+            .map(|(token, c)| (token, c.receiving, c.sending.is_some()))
+            .collect();
+
+        let cancel = |state: &mut Self, target: u64| {
+            state.push(Sqe {
+                opcode: IORING_OP_ASYNC_CANCEL,
+                fd: -1,
+                addr: target,
+                user_data: Op::Cancel.user_data(0),
+                ..Sqe::default()
+            })
+        };
+
+        for token in listeners {
+            cancel(self, Op::Accept.user_data(token))?;
+        }
+        for (token, receiving, sending) in connections {
+            if receiving {
+                cancel(self, Op::Recv.user_data(token))?;
+            }
+            if sending {
+                cancel(self, Op::Send.user_data(token))?;
+            }
+        }
+
+        let mut cqes = Vec::new();
+        while self.in_flight > 0 {
+            let ring = match self.ring.as_mut() {
+                Some(ring) => ring,
+                None => return Ok(()),
+            };
+            ring.enter(1)?;
+
+            cqes.clear();
+            ring.reap(&mut cqes);
+            for cqe in cqes.iter() {
+                if !matches!(Op::decode(cqe.user_data), Some((Op::Cancel, _))) {
+                    self.in_flight -= 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,5 +1,10 @@
 from .overrides import *
 from .litmus import *  # Overriding import
 from .adapters import LSGIToASGIAdapter
-from .shared import Server
+from .wsgi import LSGIToWSGIAdapter
+from .events import ServerSentEvent, stream_events
+from .multipart import stream_multipart
+from .protocol import Protocol
+from .shared import Server, inherited_fds
+from .tracing import TraceConfig
 
@@ -1,10 +1,42 @@
+import asyncio
+import ssl
 from asyncio import get_running_loop
+from typing import Optional
+
+ASGI_VERSION = {'spec_version': '2.3', 'version': '3.0'}
 
 
 class LSGIToASGIAdapter:
+    """
+    Runs an ASGI 3.0 application (e.g. Starlette or FastAPI) on top of
+    the LSGI (Litmus Server Gateway Interface) callbacks.
+
+    Both `http` and `websocket` scopes are supported along with the
+    `http.request.trailers`, `http.response.trailers`,
+    `http.response.zerocopysend` and `http.response.early_hint`
+    extensions, the trailers of a chunked request body are given as the
+    `trailers` of the last `http.request` message. Requests over TLS have
+    the `tls` extension giving the client's certificate if it verified
+    itself with one, the TLS version and cipher suite aren't known. When
+    the server gives requests ids they're in the `request_id` extension as
+    its `id`. The `lifespan` protocol is driven
+    by `Server.start()` and `Server.shutdown()`, or by awaiting `startup()`
+    before igniting the server and `shutdown()` once it has stopped.
+
+    The adapter can be pickled as long as the application can be, e.g. to
+    be handed to worker processes.
+    """
+
     def __init__(self, app):
-        self._loop = get_running_loop()
         self._app = app
+        self._lifespan: Optional[_Lifespan] = None
+
+    def __reduce__(self):
+        return self.__class__, (self._app,)
+
+    @property
+    def _loop(self):
+        return get_running_loop()
 
     async def __call__(self, scope, send, receive):
         """
@@ -21,63 +53,388 @@ class LSGIToASGIAdapter:
                 The raw LSGI receiver callback that needs to be wrapped.
         """
 
-        scope['query_string'] = scope['query'].encode()
-        scope['raw_path'] = scope['path'].encode()
-        scope['asgi'] = {'spec_version': '2.1', 'version': '3.0'}
-        scope['headers'] = list(map(
-            lambda item: (item[0].encode(), item[1]),
-            scope['headers'],
-        ))
-
-        async def send_wrapper(result: dict):
-            type_ = result['type']
-            if type_ == "http.response.start":
-                try:
-                    send.send_start(
-                        result['status'],
-                        result['headers'],
-                    )
-                except BlockingIOError:  # should never happen on start.
-                    fut = self._loop.create_future()
-                    send.subscribe(fut.set_result)
-                    await fut
-
-                    send.send_start(
-                        result['status'],
-                        result['headers'],
-                    )
-                return
+        headers = scope.headers
+
+        upgrade = next((v for n, v in headers if n == b'upgrade'), b'')
+        is_websocket = upgrade.lower() == b'websocket'
+
+        asgi_scope = {
+            'type': 'websocket' if is_websocket else 'http',
+            'asgi': ASGI_VERSION,
+            'http_version': scope.http_version,
+            'method': scope.method,
+            'scheme': scope.scheme,
+            'path': scope.path,
+            'raw_path': scope.raw_path,
+            'query_string': scope.query_string,
+            'root_path': scope.root_path,
+            'headers': headers,
+            'client': scope.client,
+            'server': scope.server,
+            'extensions': {
+                'http.request.trailers': {},
+                'http.response.trailers': {},
+                'http.response.zerocopysend': {},
+                'http.response.early_hint': {},
+            },
+        }
+
+        if scope.scheme == 'https':
+            asgi_scope['extensions']['tls'] = _tls_extension(scope.client_cert)
+
+        if scope.request_id is not None:
+            asgi_scope['extensions']['request_id'] = {'id': scope.request_id}
+
+        if is_websocket:
+            asgi_scope['scheme'] = 'wss' if scope.scheme == 'https' else 'ws'
+            asgi_scope['subprotocols'] = [
+                p.strip().decode()
+                for n, v in headers if n == b'sec-websocket-protocol'
+                for p in v.split(b',')
+            ]
+            handler = _WebSocketCycle(self._loop, send)
+        else:
+            handler = _HTTPCycle(self._loop, send, receive)
+
+        try:
+            await self._app(asgi_scope, handler.receive, handler.send)
+        except Exception as e:
+            await _retry(self._loop, send, send.send_error, e)
+
+    async def startup(self):
+        """
+        Runs the application's lifespan startup, raising a `RuntimeError`
+        if it fails. Applications not supporting lifespan are ignored.
+        """
+        self._lifespan = _Lifespan(self._loop, self._app)
+        await self._lifespan.startup()
+
+    async def shutdown(self):
+        """ Runs the application's lifespan shutdown if started. """
+        if self._lifespan is not None:
+            await self._lifespan.shutdown()
+            self._lifespan = None
+
+
+async def _retry(loop, waitable, fn, *args):
+    """
+    Invokes `fn` waiting on the waitable to be woken and retrying
+    whenever it raises a `BlockingIOError` due to backpressure.
+    """
+    while True:
+        try:
+            return fn(*args)
+        except BlockingIOError:
+            fut = loop.create_future()
+            waitable.subscribe(lambda *_: fut.done() or fut.set_result(None))
+            await fut
+
+
+def _set_done(fut: asyncio.Future, result=None):
+    if not fut.done():
+        fut.set_result(result)
+
+
+def _tls_extension(client_cert) -> dict:
+    """ The ASGI `tls` scope extension given the LSGI `client_cert`. """
+    chain = []
+    name = None
+    if client_cert is not None:
+        der, subject = client_cert
+        chain.append(ssl.DER_cert_to_PEM_cert(der))
+
+        # RFC 4514 lists the most specific attribute first.
+        name = ','.join(
+            f'{attr}={_escape_dn_value(value)}'
+            for attr, value in reversed(subject)
+        )
+
+    return {
+        'server_cert': None,
+        'client_cert_chain': chain,
+        'client_cert_name': name,
+        'client_cert_error': None,
+        'tls_version': None,
+        'cipher_suite': None,
+    }
+
+
+def _escape_dn_value(value: str) -> str:
+    """ Escapes an attribute value of a distinguished name, see RFC 4514. """
+    escaped = ''.join(f'\\{c}' if c in ',+"\\<>;' else c for c in value)
+    if escaped[:1] in ('#', ' '):
+        escaped = '\\' + escaped
+    if escaped.endswith(' '):
+        escaped = escaped[:-1] + '\\ '
+    return escaped
+
+
+class _HTTPCycle:
+    """ Maps a single HTTP request / response onto the LSGI callbacks. """
+
+    def __init__(self, loop, send, receive):
+        self._loop = loop
+        self._send = send
+        self._receive = receive
+
+        self._body_complete = False
+        self._response_complete = loop.create_future()
+        self._expects_trailers = False
+        self._trailers = []
+
+        self._disconnected = loop.create_future()
+        send.on_disconnect(lambda: _set_done(self._disconnected))
+
+    async def receive(self) -> dict:
+        if self._disconnected.done():
+            return {'type': 'http.disconnect'}
+
+        if self._body_complete:
+            # Nothing is left to read, the application is only told about
+            # the disconnect once it has finished responding or the client
+            # has gone.
+            await asyncio.wait(
+                (self._response_complete, self._disconnected),
+                return_when=asyncio.FIRST_COMPLETED,
+            )
+            return {'type': 'http.disconnect'}
+
+        try:
+            more_body, body = self._receive()
+        except BlockingIOError:
+            # The next chunk is handed straight to the waker rather than
+            # being queued for the receiver.
+            fut = self._loop.create_future()
+            self._receive.subscribe(lambda *chunk: _set_done(fut, chunk))
+            await asyncio.wait(
+                (fut, self._disconnected),
+                return_when=asyncio.FIRST_COMPLETED,
+            )
+            if not fut.done():
+                return {'type': 'http.disconnect'}
+
+            more_body, body = fut.result()
+
+        self._body_complete = not more_body
+        message = {
+            'type': 'http.request',
+            'body': body,
+            'more_body': more_body,
+        }
 
-            elif type_ == "http.response.body":
-                try:
-                    send.send_body(
-                        result.get('more_body', False),
-                        result['body'],
-                    )
-                except BlockingIOError:
-                    fut = self._loop.create_future()
-                    send.subscribe(fut.set_result)
-                    await fut
-
-                    send.send_body(
-                        result.get('more_body', False),
-                        result['body'],
-                    )
+        if not more_body:
+            message['trailers'] = [
+                (name.lower().encode(), value)
+                for name, value in self._receive.trailers()
+            ]
+
+        return message
+
+    async def send(self, message: dict):
+        type_ = message['type']
+
+        if type_ == "http.response.start":
+            headers = message.get('headers', [])
+            trailers = None
+
+            if message.get('trailers', False):
+                # The application advertises the trailer names itself.
+                trailers = [
+                    name.strip()
+                    for n, v in headers if n.lower() == b'trailer'
+                    for name in v.split(b',')
+                ]
+                headers = [(n, v) for n, v in headers if n.lower() != b'trailer']
+                self._expects_trailers = True
+
+            await _retry(
+                self._loop,
+                self._send,
+                self._send.send_start,
+                message['status'],
+                headers,
+                trailers,
+            )
+
+        elif type_ == "http.response.body":
+            more_body = message.get('more_body', False)
+            await _retry(
+                self._loop,
+                self._send,
+                self._send.send_body,
+                more_body,
+                message.get('body', b''),
+            )
+
+            if not more_body and not self._expects_trailers:
+                self._finish()
+
+        elif type_ == "http.response.zerocopysend":
+            file = message['file']
+            offset = message.get('offset')
+            if offset is None:
+                offset = file.tell()
+
+            more_body = message.get('more_body', False)
+            await _retry(
+                self._loop,
+                self._send,
+                self._send.send_file,
+                file.fileno(),
+                offset,
+                message.get('count'),
+                more_body,
+            )
+
+            if not more_body and not self._expects_trailers:
+                self._finish()
+
+        elif type_ == "http.response.early_hint":
+            await _retry(
+                self._loop,
+                self._send,
+                self._send.send_informational,
+                103,
+                [(b'link', link) for link in message.get('links', [])],
+            )
+
+        elif type_ == "http.response.trailers":
+            # Trailers may be split across several messages, they're only
+            # sent once the last one is given.
+            self._trailers.extend(message.get('headers', []))
+            if not message.get('more_trailers', False):
+                await _retry(
+                    self._loop,
+                    self._send,
+                    self._send.send_trailers,
+                    self._trailers,
+                )
+                self._finish()
+
+        else:
+            raise TypeError(f"invalid send type given: {type_!r}")
+
+    def _finish(self):
+        if not self._response_complete.done():
+            self._response_complete.set_result(None)
+
+
+class _WebSocketCycle:
+    """ Maps a single websocket connection onto the LSGI callbacks. """
+
+    def __init__(self, loop, send):
+        self._loop = loop
+        self._send = send
+        self._ws = None
+        self._closed = False
+
+        self._messages = asyncio.Queue()
+        self._messages.put_nowait({'type': 'websocket.connect'})
+
+    def _on_message(self, data):
+        if data is None:
+            self._closed = True
+            message = {'type': 'websocket.disconnect', 'code': self._ws.close_code}
+        elif isinstance(data, str):
+            message = {'type': 'websocket.receive', 'text': data}
+        else:
+            message = {'type': 'websocket.receive', 'bytes': data}
+
+        self._messages.put_nowait(message)
+
+    async def receive(self) -> dict:
+        return await self._messages.get()
+
+    async def send(self, message: dict):
+        type_ = message['type']
+
+        if self._closed:
+            return
+
+        if type_ == "websocket.accept":
+            self._ws = await _retry(
+                self._loop,
+                self._send,
+                self._send.accept_websocket,
+                self._on_message,
+                message.get('subprotocol'),
+            )
+
+        elif type_ == "websocket.send":
+            if self._ws is None:
+                raise RuntimeError("websocket.send sent before websocket.accept")
+
+            data = message.get('text')
+            if data is None:
+                data = message['bytes']
+
+            await _retry(self._loop, self._ws, self._ws.send, data)
+
+        elif type_ == "websocket.close":
+            if self._ws is None:
+                # Closing before accepting rejects the handshake.
+                await _retry(
+                    self._loop,
+                    self._send,
+                    self._send.send_start,
+                    403,
+                    [(b'content-length', b'0')],
+                )
+                await _retry(self._loop, self._send, self._send.send_body, False, b'')
+                self._closed = True
                 return
 
-            raise TypeError("invalid send type given")
+            await _retry(
+                self._loop,
+                self._ws,
+                self._ws.close,
+                message.get('code', 1000),
+                message.get('reason') or "",
+            )
+
+        else:
+            raise TypeError(f"invalid send type given: {type_!r}")
+
+
+class _Lifespan:
+    """ Drives the ASGI lifespan protocol for an application. """
+
+    def __init__(self, loop, app):
+        self._loop = loop
+        self._app = app
+        self._messages = asyncio.Queue()
+        self._events = asyncio.Queue()
+        self._supported = True
+        self._task = None
+
+    async def _run(self):
+        scope = {'type': 'lifespan', 'asgi': ASGI_VERSION}
+        try:
+            await self._app(scope, self._messages.get, self._events.put)
+        except Exception:
+            # Applications that don't support lifespan raise on the scope.
+            self._supported = False
+        finally:
+            # Unblocks anything waiting on an event that will never come.
+            self._events.put_nowait(None)
+
+    async def _send(self, type_: str):
+        if not self._supported:
+            return
+
+        await self._messages.put({'type': type_})
+        event = await self._events.get()
+        if event is None:
+            return
 
-        async def receive_wrapper() -> dict:
-            try:
-                more_body, data = receive()
-            except BlockingIOError:
-                fut = self._loop.create_future()
-                receive.subscribe(fut.set_result)
-                more_body, data = await fut
+        if event['type'].endswith('.failed'):
+            raise RuntimeError(event.get('message', f"{type_} failed"))
 
-            return {
-                'more_body': more_body,
-                'data': data,
-            }
+    async def startup(self):
+        self._task = self._loop.create_task(self._run())
+        await self._send('lifespan.startup')
 
-        await self._app(scope, receive_wrapper, send_wrapper)
+    async def shutdown(self):
+        await self._send('lifespan.shutdown')
+        if self._task is not None:
+            await self._task
@@ -0,0 +1,222 @@
+import asyncio
+import logging
+import socket
+from typing import Dict, Optional
+
+_logger = logging.getLogger("litmus.completion")
+
+#: The max number of bytes received from a socket at once.
+RECV_SIZE = 64 * 1024
+
+
+class _Connection:
+    """ The state of a socket adopted by the server. """
+
+    __slots__ = ("sock", "index", "reading", "writing", "pending_read", "waiter", "task")
+
+    def __init__(self, sock: socket.socket):
+        self.sock = sock
+        self.index: Optional[int] = None
+        self.reading = False
+        self.writing = False
+        self.pending_read = False
+        self.waiter: Optional[asyncio.Future] = None
+        self.task: Optional[asyncio.Task] = None
+
+    def wake(self, *_):
+        _wake(self.waiter)
+
+
+class _Listener:
+    """ A listener owned by the server which the event loop accepts on. """
+
+    __slots__ = ("sock", "index", "paused", "waiter", "task")
+
+    def __init__(self, sock: socket.socket, index: int):
+        self.sock = sock
+        self.index = index
+        self.paused = False
+        self.waiter: Optional[asyncio.Future] = None
+        self.task: Optional[asyncio.Task] = None
+
+
+def _wake(waiter: Optional[asyncio.Future]):
+    if waiter is not None and not waiter.done():
+        waiter.set_result(None)
+
+
+class CompletionLoop:
+    """
+    Drives the server's sockets using the event loop's `sock_accept`,
+    `sock_recv` and `sock_sendall` rather than `add_reader` and
+    `add_writer`, these map onto overlapped I/O with IOCP on the
+    `asyncio.ProactorEventLoop` which has no readiness notifications.
+
+    Accepted sockets are adopted by the server which feeds anything it
+    receives to the connection and sends anything the connection queues.
+    The server's reader and writer callbacks are emulated, a connection is
+    told it can read once data has been received and it can write once
+    everything queued has been sent.
+
+    Args:
+        loop:
+            The event loop performing the I/O.
+        server:
+            The `_Server` instance, initialised with this instance's
+            callbacks.
+    """
+
+    def __init__(self, loop: asyncio.AbstractEventLoop, server):
+        self._loop = loop
+        self._server = server
+        self._connections: Dict[int, _Connection] = {}
+        self._listeners: Dict[int, _Listener] = {}
+
+    def add_listener(self, fd: int, index: int):
+        """ Starts accepting on the listener with the given file descriptor. """
+        listener = self._listeners.get(fd)
+        if listener is None:
+            # The listener is owned by the server, it's detached on close.
+            sock = socket.socket(fileno=fd)
+            sock.setblocking(False)
+            listener = self._listeners[fd] = _Listener(sock, index)
+            listener.task = self._loop.create_task(self._accept(listener))
+
+        listener.paused = False
+        _wake(listener.waiter)
+
+    def add_reader(self, fd: int, index: int):
+        conn = self._connections.get(fd)
+        if conn is not None:
+            conn.index = index
+            conn.reading = True
+            conn.pending_read = True
+            conn.wake()
+        elif fd in self._listeners:
+            self.add_listener(fd, index)
+
+    def remove_reader(self, fd: int):
+        conn = self._connections.get(fd)
+        if conn is not None:
+            conn.reading = False
+            return
+
+        listener = self._listeners.get(fd)
+        if listener is not None:
+            listener.paused = True
+
+    def add_writer(self, fd: int, index: int):
+        conn = self._connections.get(fd)
+        if conn is not None:
+            conn.index = index
+            conn.writing = True
+            conn.wake()
+
+    def remove_writer(self, fd: int):
+        conn = self._connections.get(fd)
+        if conn is not None:
+            conn.writing = False
+
+    def close(self):
+        """
+        Stops accepting, the listeners are detached as they're closed by
+        the server itself.
+        """
+        for listener in self._listeners.values():
+            listener.task.cancel()
+            listener.sock.detach()
+        self._listeners.clear()
+
+    async def _accept(self, listener: _Listener):
+        while True:
+            while listener.paused:
+                listener.waiter = self._loop.create_future()
+                await listener.waiter
+
+            try:
+                sock, _ = await self._loop.sock_accept(listener.sock)
+            except (BlockingIOError, InterruptedError, ConnectionAbortedError):
+                continue
+
+            sock.setblocking(False)
+            fd = sock.fileno()
+            conn = _Connection(sock)
+
+            # The server registers the reader while adopting the socket.
+            self._connections[fd] = conn
+            index = None
+            try:
+                index = self._server.adopt(fd, conn.wake, listener.index)
+            finally:
+                if index is None:
+                    del self._connections[fd]
+                    sock.close()
+
+            if index is not None:
+                conn.index = index
+                conn.task = self._loop.create_task(self._serve(conn))
+
+    async def _serve(self, conn: _Connection):
+        fd = conn.sock.fileno()
+        recv: Optional[asyncio.Task] = None
+        eof = False
+
+        try:
+            while True:
+                data = self._server.take_outbound(conn.index)
+                if data is None:
+                    break
+
+                if data:
+                    try:
+                        await self._loop.sock_sendall(conn.sock, data)
+                    except OSError as e:
+                        _logger.debug("failed to send to fd %d: %s", fd, e)
+                        self._server.poll_close(conn.index)
+                    continue
+
+                if recv is not None and recv.done():
+                    try:
+                        received = recv.result()
+                    except OSError as e:
+                        _logger.debug("failed to receive from fd %d: %s", fd, e)
+                        received = b""
+                    recv = None
+
+                    eof = not received
+                    self._server.poll_received(conn.index, received or None)
+                    continue
+
+                if conn.pending_read:
+                    conn.pending_read = False
+                    self._server.poll_received(conn.index, b"")
+
+                    # The connection yielded with more left to read, the
+                    # other connections get their turn before it's read.
+                    if conn.pending_read:
+                        await asyncio.sleep(0)
+                    continue
+
+                # Everything queued has been sent so the socket is writable.
+                if conn.writing:
+                    self._server.poll_write(conn.index)
+                    if conn.writing:
+                        await asyncio.sleep(0)
+                    continue
+
+                # Only one receive is in flight at a time and none while the
+                # connection isn't reading, applying back pressure.
+                if conn.reading and recv is None and not eof:
+                    recv = self._loop.create_task(
+                        self._loop.sock_recv(conn.sock, RECV_SIZE)
+                    )
+                    recv.add_done_callback(conn.wake)
+
+                conn.waiter = self._loop.create_future()
+                await conn.waiter
+        finally:
+            if recv is not None:
+                recv.cancel()
+
+            self._connections.pop(fd, None)
+            conn.sock.close()
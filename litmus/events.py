@@ -0,0 +1,93 @@
+from asyncio import get_running_loop
+from typing import AsyncIterable, List, Optional, Tuple, Union
+
+from .adapters import _retry
+
+
+class ServerSentEvent:
+    """
+    An event sent on a Server-Sent Events stream.
+
+    Args:
+        data:
+            The data of the event, each line is sent as its own `data`
+            field and joined back together by the client.
+        event:
+            The type of the event, clients dispatch it to listeners of this
+            type rather than `message` if given.
+        id:
+            The id of the event, clients send the last id they received in
+            the `Last-Event-ID` header when reconnecting.
+        retry:
+            The time in milliseconds clients should wait before reconnecting
+            if the stream is lost.
+    """
+
+    __slots__ = ("data", "event", "id", "retry")
+
+    def __init__(
+        self,
+        data: str = "",
+        event: Optional[str] = None,
+        id: Optional[str] = None,
+        retry: Optional[int] = None,
+    ):
+        self.data = data
+        self.event = event
+        self.id = id
+        self.retry = retry
+
+
+async def stream_events(
+    send,
+    events: AsyncIterable[Union[str, ServerSentEvent]],
+    status: int = 200,
+    headers: Optional[List[Tuple[bytes, bytes]]] = None,
+):
+    """
+    Responds with a `text/event-stream`, sending each event yielded by
+    `events` as it's yielded. Strings are sent as the data of an untyped
+    event.
+
+    The server frames the events and keeps the stream alive with heartbeats
+    while `events` is waiting on the next one, sending waits whenever the
+    client falls behind.
+
+    Returns once `events` is exhausted, ending the response, or once the
+    client disconnects, in which case `events` is closed if it's an async
+    generator.
+
+    Args:
+        send:
+            The LSGI sender of the request.
+        events:
+            The events to send.
+        status:
+            The status code of the response.
+        headers:
+            Any headers to send along with the stream's own.
+    """
+    loop = get_running_loop()
+    await _retry(loop, send, send.start_event_stream, status, headers or [])
+
+    try:
+        async for event in events:
+            if not isinstance(event, ServerSentEvent):
+                event = ServerSentEvent(event)
+
+            await _retry(
+                loop,
+                send,
+                send.send_event,
+                event.data,
+                event.event,
+                event.id,
+                event.retry,
+            )
+    except ConnectionResetError:
+        aclose = getattr(events, "aclose", None)
+        if aclose is not None:
+            await aclose()
+        return
+
+    await _retry(loop, send, send.send_body, False, b"")
@@ -0,0 +1,93 @@
+import socket
+from functools import partial
+
+
+def _noop(*_):
+    pass
+
+
+def _probe(loop):
+    """
+    Returns if the loop can watch file descriptors and if it passes any
+    extra positional arguments on to their callbacks.
+    """
+    a, b = socket.socketpair()
+    try:
+        try:
+            loop.add_reader(a.fileno(), _noop, None)
+            loop.remove_reader(a.fileno())
+            return True, True
+        except NotImplementedError:
+            return False, False
+        except TypeError:
+            pass
+
+        try:
+            loop.add_reader(a.fileno(), _noop)
+            loop.remove_reader(a.fileno())
+            return True, False
+        except NotImplementedError:
+            return False, False
+    finally:
+        a.close()
+        b.close()
+
+
+class LoopAdapter:
+    """
+    Registers the server's callbacks with the running event loop, detecting
+    what it supports so the server runs on asyncio's own loops, uvloop and
+    other implementations alike.
+
+    asyncio and uvloop pass any extra positional arguments given to
+    `add_reader`, `add_writer` and `call_soon` on to the callback, these
+    are used as is. Loops that don't take them are given a
+    `functools.partial` bound to the arguments instead.
+
+    Loops that can't watch file descriptors at all, like the
+    `asyncio.ProactorEventLoop`, only support the completion backend.
+
+    Args:
+        loop:
+            The event loop to register callbacks with.
+    """
+
+    __slots__ = (
+        "loop",
+        "watches_fds",
+        "passes_args",
+        "add_reader",
+        "add_writer",
+        "call_soon",
+    )
+
+    def __init__(self, loop):
+        self.loop = loop
+        self.watches_fds, self.passes_args = _probe(loop)
+
+        if self.passes_args:
+            self.add_reader = loop.add_reader
+            self.add_writer = loop.add_writer
+            self.call_soon = loop.call_soon
+        else:
+            self.add_reader = partial(_bind_args, loop.add_reader)
+            self.add_writer = partial(_bind_args, loop.add_writer)
+            self.call_soon = partial(_bind_callback, loop.call_soon)
+
+    @property
+    def name(self) -> str:
+        """ The name of the event loop's type, e.g. `uvloop.Loop`. """
+        cls = type(self.loop)
+        return f"{cls.__module__}.{cls.__qualname__}"
+
+
+def _bind_args(register, fd, callback, *args):
+    if args:
+        callback = partial(callback, *args)
+    return register(fd, callback)
+
+
+def _bind_callback(register, callback, *args):
+    if args:
+        callback = partial(callback, *args)
+    return register(callback)
@@ -0,0 +1,67 @@
+from asyncio import get_running_loop
+from typing import AsyncIterator, Optional, Union
+
+from . import MultipartParser, MultipartPart
+
+
+async def stream_multipart(
+    scope,
+    receive,
+    max_parts: Optional[int] = 1000,
+    max_part_size: Optional[int] = None,
+) -> AsyncIterator[Union[MultipartPart, bytes]]:
+    """
+    Parses a `multipart/form-data` request body as it's received, yielding
+    a `MultipartPart` as each part starts followed by chunks of its content
+    as `bytes`. The body is never held in memory as a whole, so uploads can
+    be written out to disk or object storage as they arrive:
+
+        async for item in stream_multipart(scope, receive):
+            if isinstance(item, MultipartPart):
+                file = open(item.filename, "wb") if item.filename else None
+            elif file is not None:
+                file.write(item)
+
+    Each part gives its `headers`, the form field's `name`, the `filename`
+    of an uploaded file and its `content_type`.
+
+    Args:
+        scope:
+            The LSGI scope of the request.
+        receive:
+            The LSGI receiver of the request.
+        max_parts:
+            The max number of parts in the body, `None` for no limit.
+        max_part_size:
+            The max size in bytes of the content of a single part, `None`
+            for no limit.
+
+    Raises:
+        ValueError:
+            If the request isn't `multipart/form-data`, the body is
+            malformed or it exceeds either limit.
+    """
+    content_type = next(
+        (v for n, v in scope.headers if n == b'content-type'),
+        b'',
+    )
+    parser = MultipartParser(
+        content_type.decode('latin-1'),
+        max_parts,
+        max_part_size,
+    )
+
+    loop = get_running_loop()
+    more_body = True
+    while more_body:
+        try:
+            more_body, body = receive()
+        except BlockingIOError:
+            fut = loop.create_future()
+            receive.subscribe(lambda *chunk: fut.done() or fut.set_result(chunk))
+            more_body, body = await fut
+
+        for item in parser.feed(body):
+            yield item
+
+    parser.finish()
@@ -11,3 +11,22 @@ def init_logger(level: str, log_file: Optional[str], pretty: bool):  # noqa
     """
     ...
 
+
+
+def init_python_logger(level: str, max_errors_per_second: Optional[int] = 10):  # noqa
+    """
+    Forwards the internal server logs to Python's `logging` module instead
+    of printing them, only one of this and `init_logger` can be used.
+
+    Levels: [error, warning, info, debug, trace]
+
+    Each module logs to its own logger under `litmus`, e.g.
+    `litmus.protocols.h1`, and trace records are given level 5. Details
+    such as the `connection_id` and `peer` of a connection are set as
+    attributes of the log record.
+
+    At most `max_errors_per_second` error records are forwarded each
+    second, the number dropped is logged as a warning along with the next
+    error let through. `None` forwards every error.
+    """
+    ...
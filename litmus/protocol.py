@@ -0,0 +1,44 @@
+import abc
+
+
+class Protocol(abc.ABC):
+    """
+    A custom wire protocol served in place of HTTP on the addresses given
+    to the server's `protocols`, modelled after `asyncio.Protocol`. An
+    instance is created for each connection and every method is called on
+    the event loop so they should return quickly.
+
+    The transport given to `connection_made()` is a `ProtocolTransport`:
+
+        write(data):
+            Queues the data to be written to the socket.
+        close():
+            Closes the connection once everything written has been sent.
+        abort():
+            Resets the connection immediately with a TCP RST discarding
+            anything unsent.
+        is_closing():
+            If the transport is closing or has closed.
+        pause_reading() / resume_reading():
+            Stops and restarts `data_received()` being called.
+        client / server:
+            The `(host, port)` of the client and of the listener.
+    """
+
+    def connection_made(self, transport):
+        """ The connection has been accepted. """
+
+    @abc.abstractmethod
+    def data_received(self, data: bytes):
+        """ Data has been read from the connection. """
+
+    def eof_received(self) -> bool:
+        """
+        The client has shut down its side of the connection, returning
+        `True` keeps the connection open to write to until the transport
+        is closed.
+        """
+        return False
+
+    def connection_lost(self):
+        """ The connection has been closed. """
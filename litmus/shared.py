@@ -1,8 +1,21 @@
 import asyncio
-from typing import List
+import os
+import signal
+import multiprocessing
+import socket
+import subprocess
+import sys
+from typing import Callable, Dict, List, Optional, Tuple
 from functools import partial
 
 from . import _Server, create_server
+from .completion import CompletionLoop
+from .loops import LoopAdapter
+from .tracing import TraceConfig
+
+#: The environment variable listeners handed off by `Server.restart()` are
+#: passed to the new process in, as a comma separated list of fds.
+HANDOFF_ENV = "LITMUS_INHERITED_FDS"
 
 
 class FileDescriptorPartial:
@@ -35,74 +48,739 @@ class FileDescriptorPartial:
         self._caller(fd, self._callback, index)
 
 
+def _share_listener(fd: int) -> socket.socket:
+    """ Duplicates a listener owned by the server so it can be pickled. """
+    sock = socket.socket(fileno=fd)
+    try:
+        return sock.dup()
+    finally:
+        sock.detach()
+
+
+def inherited_fds() -> Optional[List[int]]:
+    """
+    The listeners handed off to this process by `Server.restart()` if any,
+    to be passed to the new server as `inherited_fds`.
+    """
+    fds = os.environ.get(HANDOFF_ENV)
+    if not fds:
+        return None
+
+    return [int(fd) for fd in fds.split(",")]
+
+
+def _run_worker(app_callback, options: dict, sockets: Optional[List[socket.socket]]):
+    """ The entrypoint of each spawned worker process. """
+    asyncio.run(_serve_worker(app_callback, options, sockets))
+
+
+async def _serve_worker(app_callback, options: dict, sockets: Optional[List[socket.socket]]):
+    if sockets is not None:
+        options = dict(
+            options,
+            listen_on=[],
+            listener_fds=[sock.detach() for sock in sockets],
+        )
+
+    server = Server(app_callback, **options)
+    await server.start()
+    await server.run_forever()
+
+
 class Server:
+    """
+    The litmus server, accepting connections on each address in
+    `listen_on` and invoking `app_callback` with each request.
+
+    `app_callback` is called with the request's `Scope`, its sender and its
+    receiver. The scope is built once per request by the server, its values
+    such as `method`, `path`, `raw_path`, `query_string` and `headers`, a
+    list of `(name, value)` byte string pairs with lowercase names, are
+    available as attributes or by indexing it like a dict.
+
+    Once more than `write_high_water` bytes of a response are waiting to be
+    written to the client no more chunks are taken from the application,
+    the sender then raises a `BlockingIOError` once `max_buffered_chunks`
+    chunks are queued and `subscribe()` waits for them to drain. Wrapping
+    the sender in a `ResponseWriter` gives awaitable writes instead.
+
+    `binds` can be given instead of `listen_on`, every address is bound
+    at once with each listener feeding the same connections, e.g.
+    `binds=["0.0.0.0:8080", "[::]:8080"]` to serve both IPv4 and IPv6. An
+    IPv6 address sharing its port with an IPv4 address only accepts IPv6
+    connections so both can be bound.
+
+    Addresses of the form `unix:/path/to/socket` bind a unix domain socket
+    instead, with the file's permissions set to `unix_socket_mode` if
+    given, e.g. `0o660`. The socket file is removed on shutdown.
+
+    Setting `workers` above 1 spawns `workers - 1` additional processes
+    when ignited each accepting on the same addresses, allowing more than
+    one core to be used. Where `SO_REUSEPORT` is supported every worker
+    binds its own listeners and the OS balances connections between them,
+    otherwise the listeners are inherited from this process.
+    Workers are started with the `spawn` method so `app_callback` and
+    `error_callback` must be picklable, e.g. module level functions.
+
+    `compression` enables compressing response bodies with the listed
+    codings, out of `"br"`, `"gzip"` and `"deflate"` in order of preference,
+    negotiated using the request's `Accept-Encoding`. Only textual content
+    types are compressed and responses with a `content-length` below
+    `compression_min_size` are left as is, as are responses that already
+    set a `content-encoding`. `compression_level` ranges from 0 to 9
+    trading speed for size, it applies to gzip and deflate.
+
+    `auto_options` registers the application's routes as a mapping of path
+    patterns to the methods each allows, `OPTIONS` requests to a matching
+    path are answered directly with an `Allow` header listing them rather
+    than being passed to the application. A `{name}` segment matches any
+    single segment and a trailing `{name:path}` matches the rest of the
+    path, e.g. `{"/users/{id}": ["GET", "PUT"]}`.
+
+    Exceptions raised by `app_callback` are passed to `error_callback` if
+    given, otherwise their traceback is printed. If the application has yet
+    to start its response the client is sent `error_response` instead, a
+    `(status, body)` tuple with the body sent as `text/plain`, by default a
+    `500 Internal Server Error`, and the connection is kept alive if it
+    otherwise would be. A response that has already started can't be
+    recovered so the connection is closed.
+
+    `trace_config` hooks callbacks into the life of each connection and
+    request, see `TraceConfig`. Each is invoked with a `TraceEvent` giving
+    the `connection_id`, the `client` and `server` addresses, the `time` of
+    the event and the `elapsed` time along with the `bytes_received` and
+    `bytes_sent`, request events also give the `method`, `path` and
+    `status`. As with `app_callback` the config must be picklable when
+    using multiple workers.
+
+    `max_connections` limits the number of open connections per worker.
+    Once reached the `"pause"` policy stops accepting until connections
+    close, leaving new ones waiting in the listen backlog, while the
+    `"reject"` policy turns new connections away with a
+    `503 Service Unavailable`.
+
+    `ip_limit` limits the connections accepted from each client address
+    per worker, a `(connections_per_second, burst, max_connections,
+    policy)` tuple. New connections are accepted at up to
+    `connections_per_second` with bursts of up to `burst`, and at most
+    `max_connections` can be open at once, either can be `None` to leave
+    it unlimited. Connections over the limit are closed as soon as they're
+    accepted with the `"close"` policy or sent a `429 Too Many Requests`
+    with `"reject"`. The address is the socket's peer so with
+    `proxy_protocol` it's the load balancer's, unix domain sockets aren't
+    limited.
+
+    Requests sent with `Expect: 100-continue` are sent a `100 Continue`
+    as soon as they are dispatched when `expect_continue` is `"auto"`.
+    With `"receive"` it is only sent once the application starts receiving
+    the body, responding first rejects the body and the connection is
+    closed after the response.
+
+    `proxy_protocol` expects every connection to start with a PROXY
+    protocol v1 or v2 header, as sent by HAProxy or an ELB, and reports
+    the client and server addresses it gives in the request scope.
+    Connections without a valid header are closed.
+
+    `trusted_proxies` lists the addresses and CIDR ranges of reverse
+    proxies, e.g. `["10.0.0.0/8", "::1"]`, whose `Forwarded` or
+    `X-Forwarded-For` and `X-Forwarded-Proto` headers are trusted. When a
+    request comes from one of them the scope's `client` and `scheme` give
+    the client it was forwarded on behalf of, following the listed
+    addresses back until one isn't a trusted proxy. The client's port is
+    0 unless given by `Forwarded`. The headers are ignored by default.
+
+    `backend` selects how sockets are polled. `"asyncio"` registers each
+    socket with the event loop's `add_reader` and `add_writer`, while
+    `"native"` registers them with the server's own epoll or kqueue poller,
+    avoiding a call into Python every time a socket starts or stops being
+    polled. The event loop then only watches the poller itself. The native
+    backend is only available on Linux, macOS and FreeBSD.
+    `"completion"` instead has the event loop perform all socket I/O with
+    `sock_accept`, `sock_recv` and `sock_sendall`, which use overlapped I/O
+    on the `asyncio.ProactorEventLoop`. This is the only backend supported
+    by loops that can't watch file descriptors, like the proactor, and is
+    used by default with them, `"asyncio"` is the default otherwise.
+    Other event loop implementations such as uvloop are supported, what
+    the running loop supports is detected when the server is created.
+    `"io_uring"` is an experimental backend for Linux 5.7 or later which
+    submits accepts, reads and writes to an io_uring instance, receiving
+    into a fixed set of buffers shared with the kernel. It's only
+    available when litmus is built with the `io_uring` feature.
+
+    With the native backend `io_threads` reads the sockets that are ready
+    together in parallel on up to that many threads with the GIL released,
+    this includes decrypting TLS records. Requests are still parsed and
+    passed to the application on the event loop's thread, so this helps
+    most with TLS and many busy connections on multi-core machines. 0, the
+    default, does all the reading on the event loop's thread.
+
+    Each time a connection is woken up it's read from at most
+    `max_reads_per_wakeup` times and until `max_bytes_per_wakeup` bytes
+    have been read, `None` lifting the byte limit. A connection reaching
+    either limit yields to the other connections and is read again on a
+    later wakeup, so one client pipelining requests or uploading a large
+    body can't hold up the rest.
+
+    Closed connections return their client to a per-worker pool to be
+    reused by the next connection, the most recently closed first. Up to
+    `max_pooled_clients` are kept, clients left unused between keep-alive
+    checks are released until only `min_pooled_clients` remain.
+    `client_pool_stats()` gives the pool's counters for tuning these.
+
+    Connections borrow their read and write buffers from a per-worker pool
+    while they have something to read or write, so idle keep-alive
+    connections hold no buffers. `max_pooled_buffers` caps the number of
+    free buffers kept per size class, free buffers left unused between
+    keep-alive checks are released. The pool is split into shards so the
+    `io_threads` reading into these buffers don't contend over it.
+
+    `access_log` records a line per request in the `"common"` or
+    `"combined"` log format or as `"json"`, which also records the time
+    taken to respond. Lines go to stdout unless `access_log_file` is given,
+    in which case they're appended to it, and are written in batches each
+    keep-alive check.
+
+    Responses are sent with a `Server: Pyre` header, `server_header`
+    replaces its value or `None` leaves it out. `response_headers` are
+    `(name, value)` pairs added to every response, e.g.
+    `[("X-Content-Type-Options", "nosniff")]`, unless the application sets
+    the same header itself. With `request_id` set to `"ulid"` or `"uuid"`
+    each request is given an id of that form, available as the scope's
+    `request_id`, sent back in the `X-Request-ID` response header and
+    recorded in the `"json"` access log. An `X-Request-ID` sent by one of
+    the `trusted_proxies` is used instead of generating a new one.
+
+    Each worker keeps metrics on its connections and requests, `metrics()`
+    returns a snapshot of them and if `metrics_path` is given they're
+    served at that path in the Prometheus text format, e.g. `"/metrics"`.
+
+    `static_files` maps URL prefixes to directories whose files are served
+    by the server itself without invoking the application, e.g.
+    `{"/static": "./public"}`. Only `GET` and `HEAD` are allowed, a
+    directory serves its `index.html` and missing files are sent a
+    `404 Not Found`. Responses get a `Content-Type` from the file's
+    extension, an `ETag` and `Last-Modified` answering conditional requests
+    with a `304 Not Modified`, and a single byte `Range` is supported.
+    Paths that would leave the directory, including through symlinks, are
+    treated as missing. Static files aren't compressed.
+
+    `bench` answers every HTTP/1 request from the server itself without
+    invoking the application, to measure how fast litmus alone can go and
+    so how much of a framework's overhead is its own. `/json` is sent
+    `{"message":"Hello, World!"}`, `/echo` is sent back the request's body,
+    up to 1 MiB, and any other path is sent `Hello, World!` as plain text.
+    The rate limit and `metrics_path` still apply, everything else that
+    would be answered before the application, e.g. `static_files`, is
+    skipped.
+
+    `http3` lists addresses to serve HTTP/3 on over QUIC, e.g.
+    `["0.0.0.0:8443"]`, alongside the tcp listeners. This is experimental
+    and only available when litmus is built with the `http3` feature. It
+    requires `tls`, using the same certificate, and responses advertise the
+    HTTP/3 ports with an `Alt-Svc` header so browsers can switch over.
+    Requests are passed to the application the same as HTTP/1.1 ones and
+    the scope's `http_version` stays `"1.1"`. HTTP/3 isn't supported with
+    the `"completion"` backend or more than one worker.
+
+    `protocols` maps addresses to serve a custom wire protocol on in place
+    of HTTP to a factory called with no arguments for each connection, e.g.
+    `{"127.0.0.1:6379": RedisProtocol}`. The protocol follows
+    `litmus.Protocol` and is given a transport to write to once the
+    connection is made, exceptions it raises are printed and the
+    connection is closed. Custom protocols aren't supported with the
+    `"io_uring"` backend or more than one worker.
+
+    Requests whose line and headers are larger than `max_header_size` bytes
+    or have more than `max_headers_count` headers, at most 256, are sent a
+    `431 Request Header Fields Too Large`. Bodies larger than
+    `max_body_size` bytes are sent a `413 Payload Too Large` before the
+    application sees the request if the `Content-Length` gives it away,
+    chunked bodies have the connection reset once they grow too large.
+    The connection is closed after either response.
+
+    Requests whose body could be framed differently by a proxy in front of
+    the server are always sent a `400 Bad Request` and the connection is
+    closed, this covers requests with both a `Content-Length` and
+    `Transfer-Encoding`, an invalid or conflicting `Content-Length` and
+    `chunked` not being the final transfer coding. `strict_parsing` also
+    rejects headers folded over multiple lines, lines ending in a bare LF
+    and invalid header names the same way.
+
+    Clients taking more than `header_timeout` seconds to send a request's
+    line and headers are sent a `408 Request Timeout`, as are clients that
+    go `body_timeout` seconds without sending any more of a body. An
+    application that goes `response_timeout` seconds without progressing
+    its response has the request answered with a `503 Service Unavailable`
+    if it has yet to start the response. The connection is closed after
+    either response, or straight away if the response has already started.
+    The timeouts are checked every `keep_alive_interval` seconds and 0
+    disables them. The application can check how long it has left with
+    `send.remaining_time()`. Timeouts are measured with a monotonic clock,
+    `loop_clock` measures them with `loop.time()` instead so they line up
+    with the loop's own timers, e.g. a loop whose clock is faked in tests.
+
+    A connection is closed once it goes `keep_alive` seconds without
+    reading or writing anything, a client still reading a response is not
+    idle. `write_stall` is a `(timeout, max_buffered)` pair closing slow
+    readers sooner, once over `max_buffered` bytes of a response are
+    waiting to be written and none have been for `timeout` seconds.
+
+    If the client disconnects or the request is aborted by a timeout before
+    the application has finished with it, the application's task is
+    cancelled unless `cancel_on_disconnect` is `False`. The application can
+    also check `send.disconnected` or register its own callback with
+    `send.on_disconnect()`, the `LSGIToASGIAdapter` tells ASGI applications
+    with an `http.disconnect` message. An application that finds a client
+    misbehaving can drop it with `send.abort()`, which resets the
+    connection with a TCP RST so the client sees it fail rather than end.
+
+    Before starting the response the application can send any number of
+    informational responses with `send.send_informational()`, e.g. a
+    `103 Early Hints` with `Link` headers so browsers can start preloading
+    the page's assets. They're skipped for HTTP/1.0 clients and are passed
+    on as interim heads over HTTP/2 and HTTP/3.
+
+    `max_requests_per_connection` closes a keep-alive connection once it
+    has served that many requests, the last response says so with
+    `Connection: close`. Clients then reconnect, which spreads them more
+    evenly across workers over time.
+
+    The options of accepted tcp sockets are left as the OS defaults
+    unless set. `tcp_nodelay` disables Nagle's algorithm so small responses
+    aren't delayed, `tcp_keepalive` enables keepalive probes given as
+    `(idle, interval, count)` with the times in seconds, `recv_buffer_size`
+    and `send_buffer_size` size the socket buffers in bytes and `linger`
+    is how many seconds closing waits for unsent data, 0 resets the
+    connection instead. On Windows only `tcp_nodelay` is applied.
+
+    With `tls` clients negotiate the protocol using ALPN, `http2` offers
+    HTTP/2 alongside HTTP/1.1. Without TLS `http2` lets clients upgrade
+    with `Upgrade: h2c` or start with the HTTP/2 preface if they have
+    prior knowledge. Requests over HTTP/2 have an `http_version` of `"2"`.
+    Without `http2` clients using prior knowledge are asked to retry
+    using HTTP/1.1.
+
+    `tls_client_ca` turns on mutual TLS, clients are asked for a
+    certificate during the handshake which must be signed by one of the CA
+    certificates in the given PEM file. With `tls_client_auth` as
+    `"required"` clients without one fail the handshake, with `"optional"`
+    they can still connect. The verified certificate is given in the
+    request scope as `client_cert`, a tuple of its DER and its subject as
+    `(name, value)` pairs, e.g. `("CN", "alice")`, or `None`.
+
+    Each request scope's `connection` is a `ConnectionInfo` describing the
+    connection it arrived on, shared by every request made over it: its
+    `connection_id`, the `local` and `remote` addresses of the socket,
+    before any `trusted_proxies` are applied, and with TLS the negotiated
+    `tls_version`, `cipher` and `alpn_protocol` along with the client's
+    `peer_cert`.
+
+    `tls_sni` serves a certificate by the server name clients ask for
+    using SNI, mapping hostnames to `(cert_file, key_file)` PEM files, e.g.
+    `{"*.example.com": ("wild.pem", "wild.key")}`. A wildcard matches any
+    single label and exact hostnames take priority, clients asking for
+    anything else are served the certificate given by `tls`.
+    `tls_sni_callback` is called at the start of every handshake with the
+    server name, or `None`, and returns the `(cert_pem, key_pem)` bytes to
+    serve or `None` to fall back to `tls_sni`. It's called on the event
+    loop so should return quickly, e.g. from a cache kept warm by a task,
+    and the handshake fails if it raises.
+
+    Responses started with `send.start_event_stream()` are Server-Sent
+    Events streams, see `litmus.stream_events`. The server sends a comment
+    on a stream that has been quiet for `sse_heartbeat` seconds so proxies
+    don't time it out, 0 disables this. Event streams are exempt from the
+    keep-alive and response timeouts.
+
+    `inherited_fds` are listeners handed off by a previous server with
+    `prepare_handoff()`, these are accepted on instead of binding
+    `listen_on`. `restart()` uses this to replace the process serving the
+    application without refusing any connections, see `inherited_fds()`.
+
+    With `socket_activation` the server accepts on the sockets passed by
+    systemd through `LISTEN_FDS` and `LISTEN_PID`, as configured by a
+    `.socket` unit, rather than binding `listen_on`. `listen_on` is still
+    bound if no sockets were passed, e.g. when run outside of systemd.
+
+    Servers started with `start()` rather than `ignite()` run the ASGI
+    lifespan protocol of applications wrapped in `LSGIToASGIAdapter`,
+    connections are only accepted once the startup hooks have completed
+    and the shutdown hooks run once `shutdown()` has closed every
+    connection. Each worker runs its own lifespan.
+    """
+
     def __init__(
         self,
         app_callback,
-        listen_on: List[str] = "127.0.0.1:8080",
+        listen_on: Optional[List[str]] = None,
         backlog: int = 1024,
         keep_alive: int = 5,
         gc_interval: int = 60,
         keep_alive_interval: int = 1,
+        *,
+        response_timeout: int = 30,
+        max_reads_per_wakeup: int = 16,
+        max_pooled_clients: int = 128,
+        max_buffered_chunks: int = 2,
+        error_callback=None,
+        compression: Optional[List[str]] = None,
+        compression_min_size: int = 1024,
+        compression_level: int = 6,
+        auto_options: Optional[Dict[str, List[str]]] = None,
+        rate_limit: Optional[Tuple[float, int, str]] = None,
+        pipelined_upgrade: str = "discard",
+        write_stall: Optional[Tuple[int, int]] = None,
+        maintenance_response: Optional[Tuple[int, bytes]] = None,
+        maintenance_exclude: Optional[List[str]] = None,
+        tls: Optional[Tuple[str, str]] = None,
+        header_timeout: int = 10,
+        workers: int = 1,
+        reuse_port: bool = False,
+        listener_fds: Optional[List[int]] = None,
+        unix_socket_mode: Optional[int] = None,
+        max_connections: Optional[int] = None,
+        connection_limit_policy: str = "pause",
+        expect_continue: str = "auto",
+        proxy_protocol: bool = False,
+        backend: Optional[str] = None,
+        max_pooled_buffers: int = 256,
+        access_log: Optional[str] = None,
+        access_log_file: Optional[str] = None,
+        metrics_path: Optional[str] = None,
+        max_header_size: int = 64 * 1024,
+        max_headers_count: int = 100,
+        max_body_size: Optional[int] = None,
+        http2: bool = False,
+        sse_heartbeat: int = 15,
+        inherited_fds: Optional[List[int]] = None,
+        socket_activation: bool = False,
+        max_requests_per_connection: Optional[int] = None,
+        tcp_nodelay: bool = False,
+        tcp_keepalive: Optional[Tuple[int, int, int]] = None,
+        recv_buffer_size: Optional[int] = None,
+        send_buffer_size: Optional[int] = None,
+        linger: Optional[int] = None,
+        binds: Optional[List[str]] = None,
+        io_threads: int = 0,
+        tls_client_ca: Optional[str] = None,
+        tls_client_auth: str = "required",
+        strict_parsing: bool = False,
+        body_timeout: int = 30,
+        error_response: Optional[Tuple[int, bytes]] = None,
+        write_high_water: int = 65536,
+        trace_config: Optional[TraceConfig] = None,
+        trusted_proxies: Optional[List[str]] = None,
+        static_files: Optional[Dict[str, str]] = None,
+        ip_limit: Optional[Tuple[Optional[float], int, Optional[int], str]] = None,
+        http3: Optional[List[str]] = None,
+        min_pooled_clients: int = 0,
+        protocols: Optional[Dict[str, Callable]] = None,
+        cancel_on_disconnect: bool = True,
+        tls_sni: Optional[Dict[str, Tuple[str, str]]] = None,
+        tls_sni_callback: Optional[Callable] = None,
+        server_header: Optional[str] = "Pyre",
+        response_headers: Optional[List[Tuple[str, str]]] = None,
+        request_id: Optional[str] = None,
+        max_bytes_per_wakeup: Optional[int] = 256 * 1024,
+        bench: bool = False,
+        loop_clock: bool = False,
     ):
+        if binds is not None:
+            if listen_on is not None:
+                raise ValueError("only one of listen_on and binds can be given")
+            listen_on = binds
+        elif listen_on is None:
+            listen_on = "127.0.0.1:8080"
+
         if isinstance(listen_on, str):
             listen_on = [listen_on]
 
+        if workers < 1:
+            raise ValueError("workers must be at least 1")
+
+        if backend not in (None, "asyncio", "native", "completion", "io_uring"):
+            raise ValueError(
+                f"unknown backend {backend!r}, expected 'asyncio', 'native', "
+                "'completion' or 'io_uring'"
+            )
+
+        # Unix domain sockets can't share a path, they're always inherited.
+        has_unix = any(addr.startswith("unix:") for addr in listen_on)
+        if workers > 1 and hasattr(socket, "SO_REUSEPORT") and not has_unix:
+            reuse_port = True
+
+        # The listeners are already bound, workers share them.
+        if inherited_fds is not None:
+            listen_on = []
+            listener_fds = (listener_fds or []) + inherited_fds
+            reuse_port = False
+
+        # Workers share the sockets passed by systemd, they can't bind
+        # their own alongside them.
+        if socket_activation:
+            reuse_port = False
+
         self.app = app_callback
         self.loop = asyncio.get_running_loop()
         self.gc_interval = gc_interval
         self.keep_alive_interval = keep_alive_interval
+        self.cancel_on_disconnect = cancel_on_disconnect
+
+        self._loop_adapter = LoopAdapter(self.loop)
+        if backend is None:
+            backend = "asyncio" if self._loop_adapter.watches_fds else "completion"
+        elif not self._loop_adapter.watches_fds and backend != "completion":
+            raise TypeError(
+                f"the {self._loop_adapter.name} event loop can't watch file descriptors, "
+                "it only supports the 'completion' backend"
+            )
+
+        if http3 is not None:
+            if backend == "completion":
+                raise ValueError("http3 isn't supported with the 'completion' backend")
+            if workers > 1:
+                raise ValueError("http3 is only supported with a single worker")
 
-        if hasattr(asyncio, "ProactorEventLoop") and isinstance(self.loop, asyncio.ProactorEventLoop):
-            raise TypeError("the asyncio.ProactorEventLoop event loop is not supported")
+        if protocols and workers > 1:
+            raise ValueError("protocols are only supported with a single worker")
 
         self._waiter = self.loop.create_future()
         self._shutdown = False
+        self._lifespan = False
+        self._draining: Optional[asyncio.Task] = None
+        self._workers = workers
+        self._processes: List[multiprocessing.Process] = []
+
+        # Everything a worker needs to construct an identical server.
+        self._worker_options = {
+            "listen_on": listen_on,
+            "backlog": backlog,
+            "keep_alive": keep_alive,
+            "response_timeout": response_timeout,
+            "max_reads_per_wakeup": max_reads_per_wakeup,
+            "max_pooled_clients": max_pooled_clients,
+            "max_buffered_chunks": max_buffered_chunks,
+            "gc_interval": gc_interval,
+            "keep_alive_interval": keep_alive_interval,
+            "error_callback": error_callback,
+            "compression": compression,
+            "compression_min_size": compression_min_size,
+            "compression_level": compression_level,
+            "auto_options": auto_options,
+            "rate_limit": rate_limit,
+            "pipelined_upgrade": pipelined_upgrade,
+            "write_stall": write_stall,
+            "maintenance_response": maintenance_response,
+            "maintenance_exclude": maintenance_exclude,
+            "tls": tls,
+            "header_timeout": header_timeout,
+            "reuse_port": reuse_port,
+            "unix_socket_mode": unix_socket_mode,
+            "max_connections": max_connections,
+            "connection_limit_policy": connection_limit_policy,
+            "expect_continue": expect_continue,
+            "proxy_protocol": proxy_protocol,
+            "backend": backend,
+            "max_pooled_buffers": max_pooled_buffers,
+            "access_log": access_log,
+            "access_log_file": access_log_file,
+            "metrics_path": metrics_path,
+            "max_header_size": max_header_size,
+            "max_headers_count": max_headers_count,
+            "max_body_size": max_body_size,
+            "http2": http2,
+            "sse_heartbeat": sse_heartbeat,
+            "socket_activation": socket_activation,
+            "max_requests_per_connection": max_requests_per_connection,
+            "tcp_nodelay": tcp_nodelay,
+            "tcp_keepalive": tcp_keepalive,
+            "recv_buffer_size": recv_buffer_size,
+            "send_buffer_size": send_buffer_size,
+            "linger": linger,
+            "io_threads": io_threads,
+            "tls_client_ca": tls_client_ca,
+            "tls_client_auth": tls_client_auth,
+            "strict_parsing": strict_parsing,
+            "body_timeout": body_timeout,
+            "error_response": error_response,
+            "write_high_water": write_high_water,
+            "trace_config": trace_config,
+            "trusted_proxies": trusted_proxies,
+            "static_files": static_files,
+            "ip_limit": ip_limit,
+            "http3": http3,
+            "min_pooled_clients": min_pooled_clients,
+            "protocols": protocols,
+            "cancel_on_disconnect": cancel_on_disconnect,
+            "tls_sni": tls_sni,
+            "tls_sni_callback": tls_sni_callback,
+            "server_header": server_header,
+            "response_headers": response_headers,
+            "request_id": request_id,
+            "max_bytes_per_wakeup": max_bytes_per_wakeup,
+            "bench": bench,
+            "loop_clock": loop_clock,
+        }
 
         self._server = create_server(
             self.__app,
             listen_on,
             backlog,
             keep_alive,
+            response_timeout,
+            max_reads_per_wakeup,
+            max_pooled_clients,
+            max_buffered_chunks,
+            error_callback,
+            compression,
+            compression_min_size,
+            compression_level,
+            auto_options,
+            rate_limit,
+            pipelined_upgrade,
+            write_stall,
+            maintenance_response,
+            maintenance_exclude,
+            tls,
+            header_timeout,
+            reuse_port,
+            listener_fds,
+            unix_socket_mode,
+            max_connections,
+            connection_limit_policy,
+            expect_continue,
+            proxy_protocol,
+            max_pooled_buffers,
+            access_log,
+            access_log_file,
+            metrics_path,
+            max_header_size,
+            max_headers_count,
+            max_body_size,
+            http2,
+            sse_heartbeat,
+            socket_activation,
+            max_requests_per_connection,
+            tcp_nodelay,
+            tcp_keepalive,
+            recv_buffer_size,
+            send_buffer_size,
+            linger,
+            io_threads,
+            tls_client_ca,
+            tls_client_auth,
+            strict_parsing,
+            body_timeout,
+            error_response,
+            write_high_water,
+            trace_config,
+            trusted_proxies,
+            static_files,
+            ip_limit,
+            http3,
+            min_pooled_clients,
+            protocols,
+            tls_sni,
+            tls_sni_callback,
+            server_header,
+            response_headers,
+            request_id,
+            max_bytes_per_wakeup,
+            bench,
         )
-        self._server.init(
-            self._add_reader,
-            self._remove_reader,
-            self._add_writer,
-            self._remove_writer,
-            self._close_socket,
-        )
+
+        # The server removes these from the process' environment but
+        # Python keeps its own copy which is passed to child processes.
+        if socket_activation:
+            for name in ("LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"):
+                os.environ.pop(name, None)
+
+        loop_time = self.loop.time if loop_clock else None
+        self._poller_fd: Optional[int] = None
+        self._completion: Optional[CompletionLoop] = None
+        if backend == "native":
+            self._server.init_native(loop_time)
+            self._poller_fd = self._server.poller_fd()
+            self.loop.add_reader(self._poller_fd, self._server.poll_native)
+        elif backend == "io_uring":
+            self._server.init_uring(loop_time)
+            self._poller_fd = self._server.poller_fd()
+            self.loop.add_reader(self._poller_fd, self._server.poll_uring)
+        elif backend == "completion":
+            self._completion = CompletionLoop(self.loop, self._server)
+            self._server.init(
+                self._completion.add_reader,
+                self._completion.remove_reader,
+                self._completion.add_writer,
+                self._completion.remove_writer,
+                self._close_socket,
+                loop_time,
+            )
+        else:
+            self._server.init(
+                self._add_reader,
+                self._remove_reader,
+                self._add_writer,
+                self._remove_writer,
+                self._close_socket,
+                loop_time,
+                self._apply_registrations,
+            )
+
+        # The QUIC driver is polled whenever a UDP socket is readable, a
+        # connection's timer expires or a client asks to be woken.
+        self._quic_fds: List[int] = []
+        self._quic_timer: Optional[asyncio.TimerHandle] = None
+        if http3 is not None:
+            self._server.init_quic(partial(self.loop.call_soon, self._poll_quic))
+            self._quic_fds = self._server.quic_fds()
+            for fd in self._quic_fds:
+                self.loop.add_reader(fd, self._poll_quic)
+
         self._kai_task = self.loop.call_later(self.keep_alive_interval, self._poll_keep_alive)
 
+    def _poll_quic(self):
+        if self._shutdown:
+            return
+
+        timeout = self._server.poll_quic()
+
+        if self._quic_timer is not None:
+            self._quic_timer.cancel()
+            self._quic_timer = None
+        if timeout is not None:
+            self._quic_timer = self.loop.call_later(timeout, self._poll_quic)
+
     def _poll_keep_alive(self):
         self._server.poll_keep_alive()
 
         if not self._shutdown:
-            self.loop.call_later(
+            self._kai_task = self.loop.call_later(
                 self.keep_alive_interval,
                 self._poll_keep_alive,
             )
 
     def __app(self, scope, send, receive):
-        scope = {
-            "type": scope[0],
-            "http_version": scope[1],
-            "method": scope[2],
-            "scheme": scope[3],
-            "path": scope[4],
-            "query": scope[5],
-            "root_path": scope[6],
-            "headers": scope[7],
-            "client": scope[8],
-            "server": scope[9],
-        }
+        task = self.loop.create_task(self.__run_app(scope, send, receive))
+        if self.cancel_on_disconnect:
+            send.on_disconnect(task.cancel)
 
-        self.loop.create_task(self.app(scope, send, receive))
+    async def __run_app(self, scope, send, receive):
+        try:
+            await self.app(scope, send, receive)
+        except Exception as e:
+            while True:
+                try:
+                    return send.send_error(e)
+                except BlockingIOError:
+                    fut = self.loop.create_future()
+                    send.subscribe(lambda *_: fut.done() or fut.set_result(None))
+                    await fut
 
     @property
     def _add_reader(self):
         return FileDescriptorPartial(
-            self.loop.add_reader,
+            self._loop_adapter.add_reader,
             callback=self._server.poll_read
         )
 
@@ -113,7 +791,7 @@ class Server:
     @property
     def _add_writer(self):
         return FileDescriptorPartial(
-            self.loop.add_writer,
+            self._loop_adapter.add_writer,
             callback=self._server.poll_write
         )
 
@@ -121,22 +799,233 @@ class Server:
     def _remove_writer(self):
         return self.loop.remove_writer
 
+    def _apply_registrations(self, ops: List[Tuple[int, int, int]]):
+        add_reader = self._loop_adapter.add_reader
+        add_writer = self._loop_adapter.add_writer
+        poll_read = self._server.poll_read
+        poll_write = self._server.poll_write
+
+        for op, fd, index in ops:
+            if op == 0:
+                add_reader(fd, poll_read, index)
+            elif op == 1:
+                self.loop.remove_reader(fd)
+            elif op == 2:
+                add_writer(fd, poll_write, index)
+            else:
+                self.loop.remove_writer(fd)
+
     @property
     def _close_socket(self):
-        return partial(self.loop.call_soon, self._server.poll_close)
+        return partial(self._loop_adapter.call_soon, self._server.poll_close)
 
     def _register_listener(self, fd: int, index: int):
-        self.loop.add_reader(fd, self._server.poll_accept, index)
+        if self._completion is not None:
+            self._completion.add_listener(fd, index)
+        else:
+            self._loop_adapter.add_reader(fd, self._server.poll_accept, index)
+
+    async def start(self):
+        """
+        Runs the application's lifespan startup if it has one, e.g. an
+        `LSGIToASGIAdapter`, then ignites the server. Connections are only
+        accepted once startup has completed, a failed startup raises a
+        `RuntimeError` without accepting any.
+
+        The lifespan shutdown is run by `shutdown()` once every connection
+        has been closed.
+        """
+        startup = getattr(self.app, "startup", None)
+        if startup is not None:
+            await startup()
+            self._lifespan = True
+
+        self.ignite()
 
     def ignite(self):
         self._server.ignite(self._register_listener)
 
-    def shutdown(self):
+        if self._workers > 1:
+            self._spawn_workers()
+
+    def install_signal_handlers(self, timeout: float = 30):
+        """
+        Shuts the server down gracefully upon receiving `SIGTERM` or
+        `SIGINT`, this is only supported on unix.
+
+        The first signal starts shutting the server down as with
+        `shutdown(timeout)`, a second signal closes every connection
+        immediately.
+        """
+        for sig in (signal.SIGTERM, signal.SIGINT):
+            self.loop.add_signal_handler(sig, partial(self._on_signal, timeout))
+
+    def _on_signal(self, timeout: float):
+        if self._draining is None:
+            self._draining = self.loop.create_task(self.shutdown(timeout))
+        else:
+            self._draining.cancel()
+            self._close()
+            self._stop()
+
+    def _spawn_workers(self):
+        ctx = multiprocessing.get_context("spawn")
+
+        sockets = None
+        if not self._worker_options["reuse_port"]:
+            sockets = [_share_listener(fd) for fd in self._server.listener_fds()]
+
+        try:
+            for _ in range(self._workers - 1):
+                process = ctx.Process(
+                    target=_run_worker,
+                    args=(self.app, self._worker_options, sockets),
+                    daemon=True,
+                )
+                process.start()
+                self._processes.append(process)
+        finally:
+            for sock in sockets or []:
+                sock.close()
+
+    @property
+    def maintenance(self) -> bool:
+        return self._server.maintenance()
+
+    @maintenance.setter
+    def maintenance(self, enabled: bool):
+        self._server.set_maintenance(enabled)
+
+    @property
+    def connections(self) -> int:
+        """The number of open connections on this worker."""
+        return self._server.len_active()
+
+    def metrics(self) -> dict:
+        """
+        A snapshot of this worker's metrics, the number of active
+        connections, requests answered, responses by status code, bytes
+        received and sent and a histogram of request latency.
+        """
+        return self._server.metrics()
+
+    def client_pool_stats(self) -> dict:
+        """
+        The counters of this worker's client pool, the number of clients
+        `created`, the number of connections which `reused` a pooled client,
+        the number of `active` connections and the number of idle clients
+        `pooled`.
+        """
+        return self._server.client_pool_stats()
+
+    @property
+    def max_connections(self) -> Optional[int]:
+        """The limit on the number of open connections if any."""
+        return self._server.max_connections()
+
+    def prepare_handoff(self) -> List[int]:
+        """
+        Prepares the listeners to be handed off to another process, e.g. a
+        new version of the application, returning inheritable duplicates
+        of their file descriptors which the caller owns.
+
+        The other process passes these as `inherited_fds` and accepts on
+        the same sockets, so connections are never refused while this
+        server drains. Unix socket files are left in place once it shuts
+        down.
+        """
+        fds = []
+        for fd in self._server.prepare_handoff():
+            sock = _share_listener(fd)
+            sock.set_inheritable(True)
+            fds.append(sock.detach())
+        return fds
+
+    async def restart(self, args: Optional[List[str]] = None, timeout: float = 30) -> int:
+        """
+        Restarts the server without downtime, this is only supported on
+        unix.
+
+        A new process is started running `args`, by default this process'
+        own command line, which is handed the listeners in the
+        `LITMUS_INHERITED_FDS` environment variable. This server then shuts
+        down as with `shutdown(timeout)`, connections arriving meanwhile
+        wait in the listen backlog until the new process accepts them.
+
+        Returns the pid of the new process.
+        """
+        if args is None:
+            args = [sys.executable, *sys.argv]
+
+        fds = self.prepare_handoff()
+        try:
+            env = dict(os.environ, **{HANDOFF_ENV: ",".join(map(str, fds))})
+            process = subprocess.Popen(args, env=env, pass_fds=fds)
+        finally:
+            for fd in fds:
+                os.close(fd)
+
+        await self.shutdown(timeout)
+        return process.pid
+
+    async def shutdown(self, timeout: float = 0):
+        """
+        Shuts down the server, new connections are no longer accepted.
+
+        If a timeout is given the server first drains connections, idle
+        keep-alive connections are closed and in-flight requests are given
+        up to `timeout` seconds to finish, with their responses closing the
+        connection. Any connections still open after this are closed.
+
+        The application's lifespan shutdown is then run if `start()` ran
+        its startup. Any worker processes are terminated without draining.
+        """
+        remaining = self._server.initiate_shutdown()
+
+        deadline = self.loop.time() + timeout
+        while remaining > 0 and self.loop.time() < deadline:
+            await asyncio.sleep(0.05)
+            remaining = self._server.initiate_shutdown()
+
+        for process in self._processes:
+            process.terminate()
+        for process in self._processes:
+            await self.loop.run_in_executor(None, process.join)
+        self._processes.clear()
+
+        self._close()
+
+        try:
+            if self._lifespan:
+                self._lifespan = False
+                await self.app.shutdown()
+        finally:
+            self._stop()
+
+    def _close(self):
+        if self._shutdown:
+            return
+
+        for process in self._processes:
+            process.terminate()
+        self._processes.clear()
+
+        if self._poller_fd is not None:
+            self.loop.remove_reader(self._poller_fd)
+        if self._completion is not None:
+            self._completion.close()
+        for fd in self._quic_fds:
+            self.loop.remove_reader(fd)
+        if self._quic_timer is not None:
+            self._quic_timer.cancel()
+
         self._server.shutdown()
         self._shutdown = True
-        self._gci_task.cancel()
         self._kai_task.cancel()
-        self._waiter.set_result(None)
+
+    def _stop(self):
+        if not self._waiter.done():
+            self._waiter.set_result(None)
 
     async def run_forever(self):
         await self._waiter
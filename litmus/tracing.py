@@ -0,0 +1,50 @@
+from typing import Callable, List
+
+
+class Signal(list):
+    """
+    A list of callbacks invoked in order with the `TraceEvent` of a hook.
+    """
+
+    def __call__(self, event):
+        for callback in self:
+            callback(event)
+
+
+class TraceConfig:
+    """
+    The hooks invoked by the server through the life of each connection
+    and request, letting APM tools instrument the server. Each hook is a
+    list of callbacks invoked with a `TraceEvent`, callbacks are run on the
+    event loop so they should return quickly.
+
+    Hooks:
+        on_connection_accepted:
+            A connection has been accepted.
+        on_request_parsed:
+            The head of a request has been parsed, before the application
+            is invoked.
+        on_response_started:
+            The application has started its response.
+        on_response_complete:
+            The response has been queued in full and the request is done
+            with, `elapsed` gives the time since the request was parsed.
+        on_connection_closed:
+            The connection has been closed, `elapsed` gives the time since
+            it was accepted.
+    """
+
+    __slots__ = (
+        "on_connection_accepted",
+        "on_request_parsed",
+        "on_response_started",
+        "on_response_complete",
+        "on_connection_closed",
+    )
+
+    def __init__(self):
+        self.on_connection_accepted: List[Callable] = Signal()
+        self.on_request_parsed: List[Callable] = Signal()
+        self.on_response_started: List[Callable] = Signal()
+        self.on_response_complete: List[Callable] = Signal()
+        self.on_connection_closed: List[Callable] = Signal()
@@ -0,0 +1,418 @@
+import asyncio
+import queue
+import sys
+import threading
+from asyncio import get_running_loop
+from concurrent.futures import ThreadPoolExecutor
+from typing import Optional
+from urllib.parse import unquote_to_bytes
+
+from .adapters import _retry, _set_done
+
+# Sent in place of a body chunk once the request body has ended.
+_EOF = object()
+
+# Sent in place of a body chunk if the client disconnected mid body.
+_DISCONNECTED = object()
+
+# Queued for the response once the application has finished.
+_END = object()
+
+
+class LSGIToWSGIAdapter:
+    """
+    Runs a WSGI (PEP 3333) application (e.g. Flask or Django) on top of
+    the LSGI (Litmus Server Gateway Interface) callbacks.
+
+    The server keeps handling the sockets and HTTP parsing on the event
+    loop while each request calls the application on a thread from a
+    pool of `max_workers` threads, so synchronous applications can be
+    served alongside async ones. The request body and the response are
+    passed between the two in chunks, at most `max_buffered_chunks` are
+    held each way before the side producing them waits for the other to
+    catch up, so neither is ever buffered in full.
+
+    Reading `wsgi.input` once the client has disconnected raises an
+    `OSError` and anything written afterwards is dropped, an application
+    iterating a response stops being iterated. The LSGI scope is given
+    as `litmus.scope` in the environ, and `litmus.request_id` when the
+    server gives requests ids.
+
+    The adapter can be pickled as long as the application can be, e.g. to
+    be handed to worker processes, each process starts its own pool.
+
+    Args:
+        app:
+            The WSGI application.
+        max_workers:
+            The max number of requests handled by the application at once,
+            defaults to that of `concurrent.futures.ThreadPoolExecutor`.
+        max_buffered_chunks:
+            The max number of body chunks held in either direction.
+    """
+
+    def __init__(
+        self,
+        app,
+        max_workers: Optional[int] = None,
+        max_buffered_chunks: int = 4,
+    ):
+        if max_buffered_chunks < 1:
+            raise ValueError("max_buffered_chunks must be at least 1")
+
+        self._app = app
+        self._max_workers = max_workers
+        self._max_buffered_chunks = max_buffered_chunks
+        self._executor: Optional[ThreadPoolExecutor] = None
+
+    def __reduce__(self):
+        return (
+            self.__class__,
+            (self._app, self._max_workers, self._max_buffered_chunks),
+        )
+
+    async def __call__(self, scope, send, receive):
+        """
+        The LSGI (Litmus Server Gateway Interface) callback handler used
+        to interact with the framework.
+
+        Args:
+            scope:
+                The LSGI scope.
+            send:
+                The raw LSGI sender callback that needs to be wrapped.
+
+            receive:
+                The raw LSGI receiver callback that needs to be wrapped.
+        """
+
+        loop = get_running_loop()
+        if self._executor is None:
+            self._executor = ThreadPoolExecutor(
+                self._max_workers,
+                thread_name_prefix="litmus-wsgi",
+            )
+
+        body = _RequestBody(loop, receive, self._max_buffered_chunks)
+        response = _Response(loop, send, self._max_buffered_chunks)
+
+        def on_disconnect():
+            body.close()
+            response.close()
+
+        send.on_disconnect(on_disconnect)
+
+        environ = _environ(scope, body)
+        loop.run_in_executor(self._executor, self._call_app, environ, response)
+        try:
+            await response.pump()
+        finally:
+            # Unblocks the application's thread if this task is cancelled.
+            on_disconnect()
+
+    def _call_app(self, environ: dict, response: "_Response"):
+        """ Calls the application on the pool's thread. """
+        try:
+            result = self._app(environ, response.start_response)
+            try:
+                for chunk in result:
+                    response.write(chunk)
+                    if response.closed:
+                        break
+            finally:
+                close = getattr(result, 'close', None)
+                if close is not None:
+                    close()
+
+            response.finish()
+        except Exception as e:
+            response.fail(e)
+
+
+def _environ(scope, body: "_RequestBody") -> dict:
+    """ The WSGI environ of a request given its LSGI scope. """
+    server_host, server_port = scope.server
+    client_host, client_port = scope.client
+
+    # Paths are given as their bytes decoded as latin-1, see PEP 3333.
+    script_name = scope.root_path
+    path = unquote_to_bytes(scope.raw_path).decode('latin-1')
+    if script_name and path.startswith(script_name):
+        path = path[len(script_name):]
+
+    environ = {
+        'REQUEST_METHOD': scope.method,
+        'SCRIPT_NAME': script_name,
+        'PATH_INFO': path,
+        'QUERY_STRING': scope.query,
+        'SERVER_NAME': server_host,
+        'SERVER_PORT': str(server_port),
+        'SERVER_PROTOCOL': f"HTTP/{scope.http_version}",
+        'REMOTE_ADDR': client_host,
+        'REMOTE_PORT': str(client_port),
+        'wsgi.version': (1, 0),
+        'wsgi.url_scheme': scope.scheme,
+        'wsgi.input': body,
+        'wsgi.input_terminated': True,
+        'wsgi.errors': sys.stderr,
+        'wsgi.multithread': True,
+        'wsgi.multiprocess': False,
+        'wsgi.run_once': False,
+        'litmus.scope': scope,
+    }
+
+    if scope.request_id is not None:
+        environ['litmus.request_id'] = scope.request_id
+
+    for name, value in scope.headers:
+        key = name.decode('latin-1').upper().replace('-', '_')
+        if key not in ('CONTENT_TYPE', 'CONTENT_LENGTH'):
+            key = f"HTTP_{key}"
+
+        value = value.decode('latin-1')
+        if key in environ:
+            separator = '; ' if key == 'HTTP_COOKIE' else ','
+            value = environ[key] + separator + value
+        environ[key] = value
+
+    return environ
+
+
+class _RequestBody:
+    """
+    The `wsgi.input` stream, read on the application's thread while the
+    chunks are received on the event loop.
+
+    Nothing is received until the application first reads from it, after
+    that up to `max_buffered_chunks` are received ahead of it.
+    """
+
+    def __init__(self, loop, receive, max_buffered_chunks: int):
+        self._loop = loop
+        self._receive = receive
+        self._space = asyncio.Semaphore(max_buffered_chunks)
+        self._chunks = queue.SimpleQueue()
+        self._pump = None
+        self._closed = False
+
+        self._buffer = bytearray()
+        self._started = False
+        self._eof = False
+
+    def close(self):
+        """ Ends the body after the client disconnected. """
+        if self._closed:
+            return
+
+        self._closed = True
+        if self._pump is not None:
+            self._pump.cancel()
+        self._chunks.put(_DISCONNECTED)
+
+    def _start(self):
+        if not self._closed:
+            self._pump = self._loop.create_task(self._receive_chunks())
+
+    async def _receive_chunks(self):
+        more_body = True
+        while more_body:
+            await self._space.acquire()
+            try:
+                more_body, body = self._receive()
+            except BlockingIOError:
+                fut = self._loop.create_future()
+                self._receive.subscribe(lambda *chunk: _set_done(fut, chunk))
+                more_body, body = await fut
+
+            if body:
+                self._chunks.put(body)
+            else:
+                self._space.release()
+
+        self._chunks.put(_EOF)
+
+    def _fill(self) -> bool:
+        """
+        Waits for the next chunk to be added to the buffer, returning
+        `False` once the body has ended.
+        """
+        if self._eof:
+            return False
+
+        if not self._started:
+            self._started = True
+            self._loop.call_soon_threadsafe(self._start)
+
+        chunk = self._chunks.get()
+        if chunk is _DISCONNECTED:
+            self._eof = True
+            raise OSError("the client disconnected")
+
+        if chunk is _EOF:
+            self._eof = True
+            return False
+
+        self._buffer += chunk
+        self._loop.call_soon_threadsafe(self._space.release)
+        return True
+
+    def _take(self, size: int) -> bytes:
+        data = bytes(self._buffer[:size])
+        del self._buffer[:size]
+        return data
+
+    def read(self, size: Optional[int] = -1) -> bytes:
+        if size is None or size < 0:
+            while self._fill():
+                pass
+            return self._take(len(self._buffer))
+
+        while len(self._buffer) < size and self._fill():
+            pass
+        return self._take(size)
+
+    def readline(self, size: Optional[int] = -1) -> bytes:
+        searched = 0
+        while True:
+            end = self._buffer.find(b'\n', searched)
+            if end != -1:
+                end += 1
+                break
+
+            searched = len(self._buffer)
+            if (size is not None and 0 <= size <= searched) or not self._fill():
+                end = len(self._buffer)
+                break
+
+        if size is not None and size >= 0:
+            end = min(end, size)
+        return self._take(end)
+
+    def readlines(self, hint: Optional[int] = -1) -> list:
+        lines = []
+        total = 0
+        for line in self:
+            lines.append(line)
+            total += len(line)
+            if hint is not None and 0 < hint <= total:
+                break
+        return lines
+
+    def __iter__(self):
+        return self
+
+    def __next__(self) -> bytes:
+        line = self.readline()
+        if not line:
+            raise StopIteration
+        return line
+
+
+class _Response:
+    """
+    Passes the response written on the application's thread to the event
+    loop where it's sent.
+
+    Writing waits once `max_buffered_chunks` are yet to be sent.
+    """
+
+    def __init__(self, loop, send, max_buffered_chunks: int):
+        self._loop = loop
+        self._send = send
+        self._max_buffered_chunks = max_buffered_chunks
+        self._credits = threading.Semaphore(max_buffered_chunks)
+        self._items = asyncio.Queue()
+        self.closed = False
+
+        self._status: Optional[int] = None
+        self._headers = []
+        self._headers_sent = False
+
+    def close(self):
+        """ Drops anything written after the client disconnected. """
+        if self.closed:
+            return
+
+        self.closed = True
+        for _ in range(self._max_buffered_chunks):
+            self._credits.release()
+
+    def _put(self, item):
+        self._loop.call_soon_threadsafe(self._items.put_nowait, item)
+
+    def start_response(self, status: str, headers: list, exc_info=None):
+        if exc_info is not None:
+            try:
+                if self._headers_sent:
+                    raise exc_info[1].with_traceback(exc_info[2])
+            finally:
+                exc_info = None
+        elif self._status is not None:
+            raise RuntimeError("start_response() has already been called")
+
+        self._status = int(status.split(' ', 1)[0])
+        self._headers = [
+            (name.encode('latin-1'), value.encode('latin-1'))
+            for name, value in headers
+        ]
+        return self.write
+
+    def _send_headers(self):
+        if self._status is None:
+            raise RuntimeError("the application didn't call start_response()")
+
+        if not self._headers_sent:
+            self._headers_sent = True
+            self._put((self._status, self._headers))
+
+    def write(self, data: bytes):
+        # The headers are only sent with the first chunk of the body so an
+        # application can still replace them after an error up to then.
+        if not data or self.closed:
+            return
+
+        self._send_headers()
+
+        self._credits.acquire()
+        if not self.closed:
+            self._put(data)
+
+    def finish(self):
+        self._send_headers()
+        self._put(_END)
+
+    def fail(self, error: Exception):
+        self._put(error)
+
+    async def pump(self):
+        """ Sends the response until the application has finished. """
+        while True:
+            item = await self._items.get()
+
+            if item is _END:
+                await _retry(
+                    self._loop,
+                    self._send,
+                    self._send.send_body,
+                    False,
+                    b'',
+                )
+                return
+
+            if isinstance(item, Exception):
+                await _retry(self._loop, self._send, self._send.send_error, item)
+                return
+
+            if isinstance(item, tuple):
+                status, headers = item
+                await _retry(
+                    self._loop,
+                    self._send,
+                    self._send.send_start,
+                    status,
+                    headers,
+                )
+                continue
+
+            await _retry(self._loop, self._send, self._send.send_body, True, item)
+            self._credits.release()
@@ -1,11 +1,15 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::Duration;
 
 use fern::colors::{Color, ColoredLevelConfig};
 #[cfg(not(target_env = "msvc"))]
 use jemallocator::Jemalloc;
 use log::LevelFilter;
-use pyo3::exceptions::PyValueError;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
 
@@ -13,9 +17,26 @@ use pyo3::wrap_pyfunction;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-use litmus_server::responders::{DataReceiver, DataSender};
-use litmus_server::server::Server;
-use litmus_server::settings::ServerSettings;
+mod logging;
+
+use litmus_server::lsgi::{ConnectionInfo, Scope};
+use litmus_server::multipart::{MultipartParser, Part};
+use litmus_server::plugin::{ProtocolFactory, ProtocolTransport, PyProtocolFactory};
+use litmus_server::responders::{DataReceiver, DataSender, ResponseWriter, WebSocket};
+use litmus_server::server::{Server, SocketFd};
+use litmus_server::settings::{
+    AccessLog, AccessLogFormat, BufferPool, Compression, ConnectionLimit,
+    ConnectionLimitPolicy, Encoding, ErrorResponse, ExpectContinuePolicy, IpLimit,
+    IpLimitPolicy, IpLimiter, Maintenance, Metrics, PipelinedUpgradePolicy, RateLimit,
+    RateLimitPolicy, RequestIdFormat, ResponseHeaders, Router, ServerSettings,
+    SocketOptions, StaticFiles, TcpKeepalive, Tracer, TrustedProxies, WriteStallGuard,
+    MAX_HEADERS_LIMIT,
+};
+#[cfg(feature = "tls")]
+use litmus_server::settings::{ClientAuthPolicy, SniConfig, TlsConfig};
+use litmus_server::TraceEvent;
+
+use crate::logging::{Fields, PythonLogger};
 
 #[pyfunction]
 pub fn init_logger(
@@ -42,11 +63,12 @@ pub fn init_logger(
     let mut builder = fern::Dispatch::new()
         .format(move |out, message, record| {
             out.finish(format_args!(
-                "{} | {} | {:<5} - {}",
+                "{} | {} | {:<5} - {}{}",
                 chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
                 record.target(),
                 colours.color(record.level()),
                 message,
+                Fields(record.key_values()),
             ))
         })
         .level(level)
@@ -62,19 +84,596 @@ pub fn init_logger(
     Ok(())
 }
 
-#[pyfunction]
+/// Forwards the server's logs to Python's `logging` module, each module
+/// of the server logging to its own logger under `litmus`.
+#[pyfunction(max_errors_per_second = "10")]
+pub fn init_python_logger(
+    log_level: &str,
+    max_errors_per_second: Option<u32>,
+) -> PyResult<()> {
+    let level = match LevelFilter::from_str(log_level) {
+        Ok(l) => l,
+        Err(e) => return Err(PyValueError::new_err(e.to_string())),
+    };
+
+    if max_errors_per_second == Some(0) {
+        return Err(PyValueError::new_err(
+            "invalid max errors per second 0, expected at least 1",
+        ));
+    }
+
+    let logger = PythonLogger::new(level, max_errors_per_second);
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        return Err(PyRuntimeError::new_err(
+            "a logger has already been initialised",
+        ));
+    }
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[pyfunction(
+    error_callback = "None",
+    compression = "None",
+    compression_min_size = "1024",
+    compression_level = "6",
+    auto_options = "None",
+    rate_limit = "None",
+    pipelined_upgrade = "\"discard\"",
+    write_stall = "None",
+    maintenance_response = "None",
+    maintenance_exclude = "None",
+    tls = "None",
+    header_timeout = "0",
+    reuse_port = "false",
+    listener_fds = "None",
+    unix_socket_mode = "None",
+    max_connections = "None",
+    connection_limit_policy = "\"pause\"",
+    expect_continue = "\"auto\"",
+    proxy_protocol = "false",
+    max_pooled_buffers = "256",
+    access_log = "None",
+    access_log_file = "None",
+    metrics_path = "None",
+    max_header_size = "65536",
+    max_headers_count = "100",
+    max_body_size = "None",
+    http2 = "false",
+    sse_heartbeat = "15",
+    socket_activation = "false",
+    max_requests_per_connection = "None",
+    tcp_nodelay = "false",
+    tcp_keepalive = "None",
+    recv_buffer_size = "None",
+    send_buffer_size = "None",
+    linger = "None",
+    io_threads = "0",
+    tls_client_ca = "None",
+    tls_client_auth = "\"required\"",
+    strict_parsing = "false",
+    body_timeout = "0",
+    error_response = "None",
+    write_high_water = "65536",
+    trace_config = "None",
+    trusted_proxies = "None",
+    static_files = "None",
+    ip_limit = "None",
+    http3 = "None",
+    min_pooled_clients = "0",
+    protocols = "None",
+    tls_sni = "None",
+    tls_sni_callback = "None",
+    server_header = "\"Pyre\".to_string()",
+    response_headers = "None",
+    request_id = "None",
+    max_bytes_per_wakeup = "262144",
+    bench = "false"
+)]
 pub fn create_server(
     callback: PyObject,
     binders: Vec<&str>,
     backlog: usize,
     keep_alive: u64,
+    response_timeout: u64,
+    max_reads_per_wakeup: usize,
+    max_pooled_clients: usize,
+    max_buffered_chunks: usize,
+    error_callback: Option<PyObject>,
+    compression: Option<Vec<&str>>,
+    compression_min_size: usize,
+    compression_level: u32,
+    auto_options: Option<HashMap<String, Vec<String>>>,
+    rate_limit: Option<(f64, usize, &str)>,
+    pipelined_upgrade: &str,
+    write_stall: Option<(u64, usize)>,
+    maintenance_response: Option<(u16, Vec<u8>)>,
+    maintenance_exclude: Option<Vec<String>>,
+    tls: Option<(String, String)>,
+    header_timeout: u64,
+    reuse_port: bool,
+    listener_fds: Option<Vec<SocketFd>>,
+    unix_socket_mode: Option<u32>,
+    max_connections: Option<usize>,
+    connection_limit_policy: &str,
+    expect_continue: &str,
+    proxy_protocol: bool,
+    max_pooled_buffers: usize,
+    access_log: Option<String>,
+    access_log_file: Option<String>,
+    metrics_path: Option<String>,
+    max_header_size: usize,
+    max_headers_count: usize,
+    max_body_size: Option<usize>,
+    http2: bool,
+    sse_heartbeat: u64,
+    socket_activation: bool,
+    max_requests_per_connection: Option<usize>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<(u64, u64, u32)>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    linger: Option<u64>,
+    io_threads: usize,
+    tls_client_ca: Option<String>,
+    tls_client_auth: &str,
+    strict_parsing: bool,
+    body_timeout: u64,
+    error_response: Option<(u16, Vec<u8>)>,
+    write_high_water: usize,
+    trace_config: Option<PyObject>,
+    trusted_proxies: Option<Vec<String>>,
+    static_files: Option<HashMap<String, String>>,
+    ip_limit: Option<(Option<f64>, usize, Option<usize>, &str)>,
+    http3: Option<Vec<&str>>,
+    min_pooled_clients: usize,
+    protocols: Option<HashMap<String, PyObject>>,
+    tls_sni: Option<HashMap<String, (String, String)>>,
+    tls_sni_callback: Option<PyObject>,
+    server_header: Option<String>,
+    response_headers: Option<Vec<(String, String)>>,
+    request_id: Option<String>,
+    max_bytes_per_wakeup: Option<usize>,
+    bench: bool,
 ) -> PyResult<Server> {
+    if tls_client_ca.is_some() & tls.is_none() {
+        return Err(PyValueError::new_err(
+            "tls_client_ca requires tls to be given",
+        ));
+    }
+
+    if (tls_sni.is_some() | tls_sni_callback.is_some()) & tls.is_none() {
+        return Err(PyValueError::new_err(
+            "tls_sni and tls_sni_callback require tls to be given",
+        ));
+    }
+
+    #[cfg(feature = "tls")]
+    let client_auth = match tls_client_auth {
+        "required" => ClientAuthPolicy::Required,
+        "optional" => ClientAuthPolicy::Optional,
+        other => {
+            return Err(PyValueError::new_err(format!(
+                "unknown tls client auth policy {:?}, expected 'required' or 'optional'",
+                other
+            )))
+        },
+    };
+
+    // The default certificate is served to clients asking for any other
+    // server name.
+    #[cfg(feature = "tls")]
+    let sni = if tls_sni.is_some() | tls_sni_callback.is_some() {
+        Some(SniConfig {
+            certs: tls_sni.unwrap_or_default(),
+            callback: tls_sni_callback,
+        })
+    } else {
+        None
+    };
+
+    #[cfg(feature = "tls")]
+    let tls = tls
+        .map(|(cert, key)| {
+            let client_ca = tls_client_ca.as_deref().map(|ca| (ca, client_auth));
+            TlsConfig::from_pem_files(&cert, &key, http2, client_ca, sni)
+        })
+        .transpose()?;
+
+    #[cfg(not(feature = "tls"))]
+    if tls.is_some() {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "litmus was built without TLS support, enable the 'tls' feature",
+        ));
+    }
+
+    #[cfg(not(feature = "tls"))]
+    let _ = (tls_client_auth, tls_sni, tls_sni_callback);
+
+    #[cfg(not(feature = "http3"))]
+    if http3.is_some() {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "litmus was built without HTTP/3 support, enable the 'http3' feature",
+        ));
+    }
+
+    #[cfg(feature = "http3")]
+    if http3.is_some() & tls.is_none() {
+        return Err(PyValueError::new_err("http3 requires tls to be given"));
+    }
+
+    let http3 = http3.unwrap_or_default();
+
+    // Clients are told of the HTTP/3 listeners by every response, the
+    // advertisement is cached for a day.
+    let mut http3_ports: Vec<u16> = http3
+        .iter()
+        .filter_map(|bind| bind.parse::<SocketAddr>().ok())
+        .map(|addr| addr.port())
+        .collect();
+    http3_ports.sort_unstable();
+    http3_ports.dedup();
+    let alt_svc = if http3_ports.is_empty() {
+        None
+    } else {
+        let services: Vec<String> = http3_ports
+            .iter()
+            .map(|port| format!("h3=\":{}\"; ma=86400", port))
+            .collect();
+        Some(services.join(", "))
+    };
+
+    let response_timeout = if response_timeout == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(response_timeout))
+    };
+
+    if compression_level > 9 {
+        return Err(PyValueError::new_err(format!(
+            "invalid compression level {}, expected 0 to 9",
+            compression_level
+        )));
+    }
+
+    if max_headers_count > MAX_HEADERS_LIMIT {
+        return Err(PyValueError::new_err(format!(
+            "invalid max headers count {}, expected at most {}",
+            max_headers_count, MAX_HEADERS_LIMIT
+        )));
+    }
+
+    if max_bytes_per_wakeup == Some(0) {
+        return Err(PyValueError::new_err(
+            "invalid max bytes per wakeup 0, expected at least 1",
+        ));
+    }
+
+    if max_requests_per_connection == Some(0) {
+        return Err(PyValueError::new_err(
+            "invalid max requests per connection 0, expected at least 1",
+        ));
+    }
+
+    let compression = match compression {
+        Some(names) => {
+            let mut encodings = Vec::with_capacity(names.len());
+            for name in names {
+                let encoding = match name {
+                    "br" => Encoding::Brotli,
+                    "gzip" => Encoding::Gzip,
+                    "deflate" => Encoding::Deflate,
+                    other => {
+                        return Err(PyValueError::new_err(format!(
+                            "unknown compression encoding {:?}, expected 'br', 'gzip' or 'deflate'",
+                            other
+                        )))
+                    },
+                };
+                encodings.push(encoding);
+            }
+
+            Some(Compression {
+                encodings,
+                min_size: compression_min_size,
+                level: compression_level,
+            })
+        },
+        None => None,
+    };
+
+    let header_timeout = if header_timeout == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(header_timeout))
+    };
+
+    let body_timeout = if body_timeout == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(body_timeout))
+    };
+
+    let event_stream_heartbeat = if sse_heartbeat == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(sse_heartbeat))
+    };
+
+    let rate_limit = match rate_limit {
+        Some((requests_per_second, burst, policy)) => {
+            let policy = match policy {
+                "throttle" => RateLimitPolicy::Throttle,
+                "reject" => RateLimitPolicy::Reject,
+                other => {
+                    return Err(PyValueError::new_err(format!(
+                    "unknown rate limit policy {:?}, expected 'throttle' or 'reject'",
+                    other
+                )))
+                },
+            };
+
+            Some(RateLimit {
+                requests_per_second,
+                burst,
+                policy,
+            })
+        },
+        None => None,
+    };
+
+    let pipelined_upgrade = match pipelined_upgrade {
+        "discard" => PipelinedUpgradePolicy::Discard,
+        "reject" => PipelinedUpgradePolicy::Reject,
+        other => {
+            return Err(PyValueError::new_err(format!(
+                "unknown pipelined upgrade policy {:?}, expected 'discard' or 'reject'",
+                other
+            )))
+        },
+    };
+
+    let expect_continue = match expect_continue {
+        "auto" => ExpectContinuePolicy::Auto,
+        "receive" => ExpectContinuePolicy::OnReceive,
+        other => {
+            return Err(PyValueError::new_err(format!(
+                "unknown expect continue policy {:?}, expected 'auto' or 'receive'",
+                other
+            )))
+        },
+    };
+
+    let response_headers = ResponseHeaders::new(
+        server_header.as_deref(),
+        response_headers.unwrap_or_default(),
+    )
+    .map_err(PyValueError::new_err)?;
+
+    let request_id = match request_id.as_deref() {
+        None => None,
+        Some("ulid") => Some(RequestIdFormat::Ulid),
+        Some("uuid") => Some(RequestIdFormat::Uuid),
+        Some(other) => {
+            return Err(PyValueError::new_err(format!(
+                "unknown request id format {:?}, expected 'ulid' or 'uuid'",
+                other
+            )))
+        },
+    };
+
+    let policy = match connection_limit_policy {
+        "pause" => ConnectionLimitPolicy::Pause,
+        "reject" => ConnectionLimitPolicy::Reject,
+        other => {
+            return Err(PyValueError::new_err(format!(
+                "unknown connection limit policy {:?}, expected 'pause' or 'reject'",
+                other
+            )))
+        },
+    };
+
+    let access_log = match access_log.as_deref() {
+        Some(format) => {
+            let format = match format {
+                "common" => AccessLogFormat::Common,
+                "combined" => AccessLogFormat::Combined,
+                "json" => AccessLogFormat::Json,
+                other => {
+                    return Err(PyValueError::new_err(format!(
+                        "unknown access log format {:?}, expected 'common', 'combined' or 'json'",
+                        other
+                    )))
+                },
+            };
+
+            match access_log_file {
+                Some(path) => Some(AccessLog::file(format, &path)?),
+                None => Some(AccessLog::stdout(format)),
+            }
+        },
+        None => None,
+    };
+
+    let connection_limit = max_connections.map(|max| ConnectionLimit { max, policy });
+
+    let ip_limiter = match ip_limit {
+        Some((connections_per_second, burst, max_connections, policy)) => {
+            let policy = match policy {
+                "close" => IpLimitPolicy::Close,
+                "reject" => IpLimitPolicy::Reject,
+                other => {
+                    return Err(PyValueError::new_err(format!(
+                        "unknown ip limit policy {:?}, expected 'close' or 'reject'",
+                        other
+                    )))
+                },
+            };
+
+            let invalid_rate =
+                matches!(connections_per_second, Some(rate) if rate <= 0.0);
+            if invalid_rate | (burst == 0) | (max_connections == Some(0)) {
+                return Err(PyValueError::new_err(
+                    "invalid ip limit, expected the rate, burst and max connections to be above 0",
+                ));
+            }
+
+            Some(Arc::new(IpLimiter::new(IpLimit {
+                connections_per_second,
+                burst,
+                max_connections,
+                policy,
+            })))
+        },
+        None => None,
+    };
+
+    if let Some((idle, interval, count)) = tcp_keepalive {
+        if (idle == 0) | (interval == 0) | (count == 0) {
+            return Err(PyValueError::new_err(format!(
+                "invalid tcp keepalive ({}, {}, {}), expected the idle time, interval and count to be at least 1",
+                idle, interval, count
+            )));
+        }
+    }
+
+    if (recv_buffer_size == Some(0)) | (send_buffer_size == Some(0)) {
+        return Err(PyValueError::new_err(
+            "invalid socket buffer size 0, expected at least 1",
+        ));
+    }
+
+    let socket_options = SocketOptions {
+        nodelay: tcp_nodelay,
+        keepalive: tcp_keepalive.map(|(idle, interval, count)| TcpKeepalive {
+            idle: Duration::from_secs(idle),
+            interval: Duration::from_secs(interval),
+            count,
+        }),
+        recv_buffer_size,
+        send_buffer_size,
+        linger: linger.map(Duration::from_secs),
+    };
+
+    let write_stall = write_stall.map(|(timeout, max_buffered)| WriteStallGuard {
+        timeout: Duration::from_secs(timeout),
+        max_buffered,
+    });
+
+    let (status, body) =
+        maintenance_response.unwrap_or_else(|| (503, b"Service Unavailable".to_vec()));
+    let maintenance =
+        Maintenance::new(status, body, maintenance_exclude.unwrap_or_default())
+            .map_err(|e| {
+                PyValueError::new_err(format!("invalid maintenance status: {}", e))
+            })?;
+
+    let (status, body) =
+        error_response.unwrap_or_else(|| (500, b"Internal Server Error".to_vec()));
+    let error_response = ErrorResponse::new(status, body).map_err(|e| {
+        PyValueError::new_err(format!("invalid error response status: {}", e))
+    })?;
+
+    let trusted_proxies = trusted_proxies
+        .map(|proxies| TrustedProxies::new(&proxies))
+        .transpose()
+        .map_err(|proxy| {
+            PyValueError::new_err(format!(
+                "invalid trusted proxy {:?}, expected an address or CIDR range",
+                proxy
+            ))
+        })?;
+
+    let auto_options = auto_options
+        .map(Router::new)
+        .transpose()
+        .map_err(PyValueError::new_err)?;
+
+    let static_files = static_files.map(StaticFiles::new).transpose()?;
+
+    let tracer = Python::with_gil(|py| {
+        trace_config
+            .map(|config| Tracer::new(config.as_ref(py)))
+            .transpose()
+    })?;
+
     let settings = ServerSettings {
         backlog,
+        max_pooled_clients,
+        min_pooled_clients,
         keep_alive: Duration::from_secs(keep_alive),
+        buffers: BufferPool::new(max_pooled_buffers),
+        max_reads_per_wakeup,
+        max_bytes_per_wakeup,
+        io_threads,
+        max_buffered_chunks,
+        write_high_water,
+        response_timeout,
+        header_timeout,
+        body_timeout,
+        max_header_size,
+        max_headers_count,
+        strict_parsing,
+        max_body_size,
+        max_requests_per_connection,
+        http2,
+        event_stream_heartbeat,
+        compression,
+        auto_options,
+        rate_limit,
+        pipelined_upgrade,
+        expect_continue,
+        write_stall,
+        connection_limit,
+        ip_limiter,
+        socket_options,
+        proxy_protocol,
+        trusted_proxies,
+        access_log,
+        metrics: Metrics::default(),
+        tracer,
+        metrics_path,
+        static_files,
+        maintenance,
+        bench,
+        error_response,
+        alt_svc,
+        response_headers,
+        request_id,
+        #[cfg(feature = "tls")]
+        tls,
+        draining: AtomicBool::new(false),
     };
 
-    let server = Server::connect(settings, callback, binders)?;
+    // Each custom protocol is served on its own listener.
+    let protocols: Vec<(String, Arc<dyn ProtocolFactory>)> = protocols
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(bind, factory)| {
+            let factory: Arc<dyn ProtocolFactory> =
+                Arc::new(PyProtocolFactory::new(factory));
+            (bind, factory)
+        })
+        .collect();
+    let protocol_binders = protocols
+        .iter()
+        .map(|(bind, factory)| (bind.as_str(), factory.clone()))
+        .collect();
+
+    let server = Server::connect(
+        settings,
+        callback,
+        error_callback,
+        binders,
+        http3,
+        protocol_binders,
+        reuse_port,
+        listener_fds.unwrap_or_default(),
+        unix_socket_mode,
+        socket_activation,
+    )?;
 
     Ok(server)
 }
@@ -83,8 +682,17 @@ pub fn create_server(
 fn litmus(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(create_server, m)?)?;
     m.add_function(wrap_pyfunction!(init_logger, m)?)?;
+    m.add_function(wrap_pyfunction!(init_python_logger, m)?)?;
     m.add_class::<Server>()?;
+    m.add_class::<Scope>()?;
+    m.add_class::<ConnectionInfo>()?;
     m.add_class::<DataSender>()?;
     m.add_class::<DataReceiver>()?;
+    m.add_class::<ResponseWriter>()?;
+    m.add_class::<WebSocket>()?;
+    m.add_class::<TraceEvent>()?;
+    m.add_class::<ProtocolTransport>()?;
+    m.add_class::<MultipartParser>()?;
+    m.add_class::<Part>()?;
     Ok(())
 }
@@ -0,0 +1,293 @@
+//! Forwards the server's log records to Python's `logging` module.
+//!
+//! Each record is handed to the logger named after the module it came
+//! from, e.g. `litmus.protocols.h1`, with any fields of the record such as
+//! the `connection_id` set as attributes of the Python `LogRecord`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::kv::{self, Key, Source, Value, VisitSource, VisitValue};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// The period error records are counted over when rate limiting them.
+const ERROR_WINDOW: Duration = Duration::from_secs(1);
+
+/// The level Python gives trace records, it has no level of its own
+/// below `DEBUG`.
+const TRACE: u8 = 5;
+
+pub struct PythonLogger {
+    level: LevelFilter,
+
+    /// The max number of error records forwarded each second, `None`
+    /// forwards every record.
+    max_errors_per_second: Option<u32>,
+
+    errors: Mutex<ErrorWindow>,
+
+    /// The Python loggers by the target of the records they're given.
+    loggers: Mutex<HashMap<String, PyObject>>,
+}
+
+/// The error records seen in the current window.
+struct ErrorWindow {
+    started: Instant,
+    forwarded: u32,
+
+    /// The records dropped since the last one forwarded.
+    suppressed: u64,
+}
+
+impl PythonLogger {
+    pub fn new(level: LevelFilter, max_errors_per_second: Option<u32>) -> Self {
+        Self {
+            level,
+            max_errors_per_second,
+            errors: Mutex::new(ErrorWindow {
+                started: Instant::now(),
+                forwarded: 0,
+                suppressed: 0,
+            }),
+            loggers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts an error record against the rate limit, returning the number
+    /// of records suppressed since the last one let through if this one
+    /// should be forwarded.
+    fn admit_error(&self) -> Option<u64> {
+        let max = match self.max_errors_per_second {
+            Some(max) => max,
+            None => return Some(0),
+        };
+
+        let mut window = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if now.duration_since(window.started) >= ERROR_WINDOW {
+            window.started = now;
+            window.forwarded = 0;
+        }
+
+        if window.forwarded >= max {
+            window.suppressed += 1;
+            return None;
+        }
+
+        window.forwarded += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+
+    /// Gets the Python logger the records of the given target go to.
+    fn logger(&self, py: Python, target: &str) -> PyResult<PyObject> {
+        if let Some(logger) = self.lock_loggers().get(target) {
+            return Ok(logger.clone_ref(py));
+        }
+
+        // The lock isn't held while calling into Python, which may let
+        // another thread take the GIL and log in the meantime.
+        let logger: PyObject = py
+            .import("logging")?
+            .call_method1("getLogger", (logger_name(target),))?
+            .into();
+        self.lock_loggers()
+            .insert(target.to_string(), logger.clone_ref(py));
+
+        Ok(logger)
+    }
+
+    fn lock_loggers(&self) -> MutexGuard<'_, HashMap<String, PyObject>> {
+        self.loggers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn emit(
+        &self,
+        py: Python,
+        target: &str,
+        level: Level,
+        message: &str,
+        fields: &dyn Source,
+    ) -> PyResult<()> {
+        let logger = self.logger(py, target)?;
+        let level = python_level(level);
+        if !logger
+            .call_method1(py, "isEnabledFor", (level,))?
+            .is_true(py)?
+        {
+            return Ok(());
+        }
+
+        let extra = PyDict::new(py);
+        fields
+            .visit(&mut FieldsToDict { py, dict: extra })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("extra", extra)?;
+        logger.call_method(py, "log", (level, message), Some(kwargs))?;
+
+        Ok(())
+    }
+}
+
+impl Log for PythonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let suppressed = match record.level() {
+            Level::Error => match self.admit_error() {
+                Some(suppressed) => suppressed,
+                None => return,
+            },
+            _ => 0,
+        };
+
+        let message = record.args().to_string();
+        Python::with_gil(|py| {
+            if suppressed > 0 {
+                let message = format!(
+                    "suppressed {} error messages exceeding the rate limit",
+                    suppressed,
+                );
+                let fields = None::<(&str, &str)>;
+                if let Err(e) = self.emit(py, "litmus", Level::Warn, &message, &fields) {
+                    e.print(py);
+                }
+            }
+
+            let result = self.emit(
+                py,
+                record.target(),
+                record.level(),
+                &message,
+                record.key_values(),
+            );
+            if let Err(e) = result {
+                e.print(py);
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Formats the fields of a record as ` key=value` pairs.
+pub struct Fields<'a>(pub &'a dyn Source);
+
+impl fmt::Display for Fields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Writer<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl<'kvs> VisitSource<'kvs> for Writer<'_, '_> {
+            fn visit_pair(
+                &mut self,
+                key: Key<'kvs>,
+                value: Value<'kvs>,
+            ) -> Result<(), kv::Error> {
+                write!(self.0, " {}={}", key, value)?;
+                Ok(())
+            }
+        }
+
+        self.0.visit(&mut Writer(f)).map_err(|_| fmt::Error)
+    }
+}
+
+/// Sets each field of a record in the `extra` dict of the Python record.
+struct FieldsToDict<'p> {
+    py: Python<'p>,
+    dict: &'p PyDict,
+}
+
+impl<'kvs> VisitSource<'kvs> for FieldsToDict<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> Result<(), kv::Error> {
+        let mut object = ValueToPython {
+            py: self.py,
+            object: None,
+        };
+        value.visit(&mut object)?;
+
+        let object = object.object.unwrap_or_else(|| self.py.None());
+        self.dict
+            .set_item(key.as_str(), object)
+            .map_err(|e| kv::Error::boxed(e.to_string()))
+    }
+}
+
+/// Converts the value of a field to the closest Python type.
+struct ValueToPython<'p> {
+    py: Python<'p>,
+    object: Option<PyObject>,
+}
+
+impl<'v> VisitValue<'v> for ValueToPython<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.object = Some(value.to_string().into_py(self.py));
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.object = Some(self.py.None());
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.object = Some(value.into_py(self.py));
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.object = Some(value.into_py(self.py));
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.object = Some(value.into_py(self.py));
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.object = Some(value.into_py(self.py));
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.object = Some(value.into_py(self.py));
+        Ok(())
+    }
+}
+
+/// The name of the Python logger for the given target, the server's own
+/// modules are under `litmus` and any other crate's keeps its name.
+fn logger_name(target: &str) -> String {
+    let name = target.replace("::", ".");
+    match name.strip_prefix("litmus_server") {
+        Some(module) => format!("litmus{}", module),
+        None => name,
+    }
+}
+
+fn python_level(level: Level) -> u8 {
+    match level {
+        Level::Error => 40,
+        Level::Warn => 30,
+        Level::Info => 20,
+        Level::Debug => 10,
+        Level::Trace => TRACE,
+    }
+}
@@ -28,7 +28,7 @@ async def main():
     global server
     runner = litmus.LSGIToASGIAdapter(app)
     server = litmus.Server(runner, listen_on="0.0.0.0:8000")
-    server.ignite()
+    await server.start()
     await server.run_forever()
 
 
@@ -0,0 +1,155 @@
+"""
+Tests for the ASGI adapter served by a real server over loopback.
+
+Run with `python -m unittest discover tests` once the extension is built,
+e.g. with `maturin develop`.
+"""
+
+import asyncio
+import socket
+import unittest
+
+import litmus
+
+
+def free_port() -> int:
+    with socket.socket() as sock:
+        sock.bind(("127.0.0.1", 0))
+        return sock.getsockname()[1]
+
+
+async def echo(scope, receive, send):
+    """ Echoes the request body back once it has been received in full. """
+    if scope["type"] != "http":
+        return
+
+    body = b""
+    more_body = True
+    while more_body:
+        message = await receive()
+        body += message.get("body", b"")
+        more_body = message.get("more_body", False)
+
+    await send({
+        "type": "http.response.start",
+        "status": 200,
+        "headers": [(b"content-length", str(len(body)).encode())],
+    })
+    await send({"type": "http.response.body", "body": body})
+
+
+class AdapterTestCase(unittest.IsolatedAsyncioTestCase):
+    app = None
+
+    async def asyncSetUp(self):
+        self.addr = ("127.0.0.1", free_port())
+        self.server = litmus.Server(
+            litmus.LSGIToASGIAdapter(self.app),
+            listen_on=f"{self.addr[0]}:{self.addr[1]}",
+        )
+        self.server.ignite()
+
+    async def asyncTearDown(self):
+        await self.server.shutdown()
+
+    async def connect(self):
+        return await asyncio.open_connection(*self.addr)
+
+
+class HTTPTests(AdapterTestCase):
+    app = staticmethod(echo)
+
+    async def test_body_sent_after_the_app_waits_is_received(self):
+        reader, writer = await self.connect()
+        writer.write(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"
+        )
+        await writer.drain()
+
+        # The app is waiting on the body by now, each chunk is handed
+        # straight to its waker.
+        await asyncio.sleep(0.1)
+        writer.write(b"5\r\nhello\r\n")
+        await writer.drain()
+        await asyncio.sleep(0.1)
+        writer.write(b"6\r\n world\r\n0\r\n\r\n")
+        await writer.drain()
+
+        response = await asyncio.wait_for(reader.readuntil(b"hello world"), 5)
+        self.assertTrue(response.startswith(b"HTTP/1.1 200 OK\r\n"))
+        writer.close()
+
+
+WEBSOCKET_HANDSHAKE = (
+    b"GET / HTTP/1.1\r\n"
+    b"Upgrade: websocket\r\n"
+    b"Connection: Upgrade\r\n"
+    b"Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+    b"Sec-WebSocket-Version: 13\r\n\r\n"
+)
+
+
+def client_frame(opcode: int, payload: bytes) -> bytes:
+    """ A masked frame as sent by a client, the payload must be short. """
+    mask = b"\x01\x02\x03\x04"
+    masked = bytes(b ^ mask[i % 4] for i, b in enumerate(payload))
+    return bytes([0x80 | opcode, 0x80 | len(payload)]) + mask + masked
+
+
+class WebSocketTests(AdapterTestCase):
+    @staticmethod
+    async def app(scope, receive, send):
+        if scope["type"] != "websocket":
+            return
+
+        await receive()
+        try:
+            await send({"type": "websocket.send", "text": "too early"})
+        except RuntimeError as e:
+            WebSocketTests.send_error = e
+
+        await send({"type": "websocket.accept"})
+        WebSocketTests.disconnect = await receive()
+
+    async def asyncSetUp(self):
+        WebSocketTests.send_error = None
+        WebSocketTests.disconnect = None
+        await super().asyncSetUp()
+
+    async def handshake(self):
+        reader, writer = await self.connect()
+        writer.write(WEBSOCKET_HANDSHAKE)
+        response = await asyncio.wait_for(reader.readuntil(b"\r\n\r\n"), 5)
+        self.assertTrue(response.startswith(b"HTTP/1.1 101 "))
+        return reader, writer
+
+    async def test_send_before_accept_raises(self):
+        _, writer = await self.handshake()
+        self.assertIsInstance(self.send_error, RuntimeError)
+        writer.close()
+
+    async def test_disconnect_has_the_close_code(self):
+        reader, writer = await self.handshake()
+        writer.write(client_frame(0x8, (4001).to_bytes(2, "big")))
+        await writer.drain()
+
+        # The close frame is echoed back before the connection closes.
+        reply = await asyncio.wait_for(reader.read(), 5)
+        self.assertEqual(reply, b"\x88\x02" + (4001).to_bytes(2, "big"))
+        await asyncio.sleep(0.1)
+        self.assertEqual(
+            self.disconnect, {"type": "websocket.disconnect", "code": 4001}
+        )
+        writer.close()
+
+    async def test_disconnect_without_a_close_frame_is_abnormal(self):
+        _, writer = await self.handshake()
+        writer.close()
+        await asyncio.sleep(0.1)
+        self.assertEqual(
+            self.disconnect, {"type": "websocket.disconnect", "code": 1006}
+        )
+
+
+if __name__ == "__main__":
+    unittest.main()